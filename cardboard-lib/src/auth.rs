@@ -0,0 +1,121 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const NONCE_SIZE: usize = 16;
+pub const AUTH_TAG_SIZE: usize = 32;
+pub const MAX_AUTH_SECRET_LENGTH: usize = 64;
+
+/// Source of unpredictable bytes for authentication challenges, which the xorshift PRNG in
+/// `random` isn't good enough for.
+pub trait NonceSource {
+	fn fill_nonce(&mut self, nonce: &mut [u8]);
+}
+
+/// Authentication state of the current host session. A session starts with Identify; the host
+/// then asks for a challenge and answers it with `HMAC-SHA256(secret, nonce)`. Each challenge
+/// can only be answered once.
+#[derive(Default)]
+pub struct AuthSession {
+	nonce: Option<[u8; NONCE_SIZE]>,
+	authenticated: bool,
+}
+
+impl AuthSession {
+	pub const fn new() -> Self {
+		Self {
+			nonce: None,
+			authenticated: false,
+		}
+	}
+
+	/// Forgets any authentication, for when a new host session starts.
+	pub fn reset(&mut self) {
+		self.nonce = None;
+		self.authenticated = false;
+	}
+
+	pub fn challenge(&mut self, source: &mut dyn NonceSource) -> [u8; NONCE_SIZE] {
+		let mut nonce = [0; NONCE_SIZE];
+		source.fill_nonce(&mut nonce);
+		self.nonce = Some(nonce);
+		nonce
+	}
+
+	/// Checks the host's answer to the last challenge, which is used up either way.
+	pub fn respond(&mut self, secret: &[u8], tag: &[u8]) -> bool {
+		let Some(nonce) = self.nonce.take() else {
+			return false;
+		};
+		let mut mac =
+			<Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC takes any key size");
+		mac.update(&nonce);
+		self.authenticated = mac.verify_slice(tag).is_ok();
+		self.authenticated
+	}
+
+	pub fn is_authenticated(&self) -> bool {
+		self.authenticated
+	}
+}
+
+pub fn validate_auth_secret(secret: &[u8]) -> Result<(), &'static str> {
+	if secret.len() > MAX_AUTH_SECRET_LENGTH {
+		return Err("Authentication secret is too long");
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	struct CountingNonceSource(u8);
+
+	impl NonceSource for CountingNonceSource {
+		fn fill_nonce(&mut self, nonce: &mut [u8]) {
+			self.0 += 1;
+			nonce.fill(self.0);
+		}
+	}
+
+	fn tag(secret: &[u8], nonce: &[u8]) -> [u8; AUTH_TAG_SIZE] {
+		let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret).unwrap();
+		mac.update(nonce);
+		mac.finalize().into_bytes().into()
+	}
+
+	#[test]
+	fn answered_challenge_authenticates_the_session() {
+		let mut session = AuthSession::new();
+		let mut source = CountingNonceSource(0);
+
+		let nonce = session.challenge(&mut source);
+		assert!(session.respond(b"secret", &tag(b"secret", &nonce)));
+		assert!(session.is_authenticated());
+
+		session.reset();
+		assert!(!session.is_authenticated());
+	}
+
+	#[test]
+	fn challenge_can_only_be_answered_once() {
+		let mut session = AuthSession::new();
+		let mut source = CountingNonceSource(0);
+
+		let nonce = session.challenge(&mut source);
+		assert!(!session.respond(b"secret", &tag(b"wrong", &nonce)));
+		assert!(!session.respond(b"secret", &tag(b"secret", &nonce)));
+		assert!(!session.is_authenticated());
+	}
+
+	#[test]
+	fn answer_to_an_old_challenge_is_rejected() {
+		let mut session = AuthSession::new();
+		let mut source = CountingNonceSource(0);
+
+		let old = session.challenge(&mut source);
+		session.challenge(&mut source);
+		assert!(!session.respond(b"secret", &tag(b"secret", &old)));
+	}
+}
@@ -0,0 +1,124 @@
+use core::cell::Cell;
+use critical_section::Mutex;
+
+use crate::serialize::Writeable;
+use crate::stream::{WriteAsync, WriteAsyncExt};
+
+/// How a board wires its battery to the ADC, through a divider of two resistors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatteryConfig {
+	pub reference_mv: u32,
+	/// The reading at the reference voltage, 4095 for a 12-bit ADC.
+	pub adc_max: u16,
+	/// Between the battery and the ADC pin.
+	pub divider_top_ohms: u32,
+	/// Between the ADC pin and ground.
+	pub divider_bottom_ohms: u32,
+	/// Voltage reported as 0%.
+	pub empty_mv: u16,
+	/// Voltage reported as 100%.
+	pub full_mv: u16,
+}
+
+impl BatteryConfig {
+	pub fn voltage_mv(&self, raw: u16) -> u16 {
+		let pin_mv = raw as u64 * self.reference_mv as u64 / self.adc_max.max(1) as u64;
+		let divider_ohms = self.divider_top_ohms as u64 + self.divider_bottom_ohms as u64;
+		let mv = pin_mv * divider_ohms / self.divider_bottom_ohms.max(1) as u64;
+		mv.min(u16::MAX as u64) as u16
+	}
+
+	/// Charge in percent, linear between the empty and full voltages.
+	pub fn percent(&self, voltage_mv: u16) -> u8 {
+		if voltage_mv <= self.empty_mv {
+			return 0;
+		}
+		if voltage_mv >= self.full_mv {
+			return 100;
+		}
+		((voltage_mv - self.empty_mv) as u32 * 100 / (self.full_mv - self.empty_mv) as u32) as u8
+	}
+
+	pub fn level(&self, raw: u16) -> BatteryLevel {
+		let voltage_mv = self.voltage_mv(raw);
+		BatteryLevel {
+			voltage_mv,
+			percent: self.percent(voltage_mv),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatteryLevel {
+	pub voltage_mv: u16,
+	pub percent: u8,
+}
+
+impl Writeable for BatteryLevel {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_u16(self.voltage_mv).await?;
+		writer.write_u8(self.percent).await
+	}
+}
+
+/// An ADC channel measuring the battery through its divider.
+pub trait BatteryAdc {
+	async fn read(&mut self) -> Result<u16, &'static str>;
+}
+
+/// Latest battery level, set by the battery task and read by `GetStatus` and the USB stack.
+pub struct BatteryStatus {
+	level: Mutex<Cell<Option<BatteryLevel>>>,
+}
+
+impl BatteryStatus {
+	pub const fn new() -> Self {
+		Self {
+			level: Mutex::new(Cell::new(None)),
+		}
+	}
+
+	pub fn set(&self, level: BatteryLevel) {
+		critical_section::with(|cs| self.level.borrow(cs).set(Some(level)));
+	}
+
+	/// None until the first measurement, or on boards without a battery monitor.
+	pub fn get(&self) -> Option<BatteryLevel> {
+		critical_section::with(|cs| self.level.borrow(cs).get())
+	}
+}
+
+impl Default for BatteryStatus {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// the Pico measures VSYS through a 200k/100k divider on GPIO 29
+	const PICO_VSYS: BatteryConfig = BatteryConfig {
+		reference_mv: 3300,
+		adc_max: 4095,
+		divider_top_ohms: 200_000,
+		divider_bottom_ohms: 100_000,
+		empty_mv: 3300,
+		full_mv: 4200,
+	};
+
+	#[test]
+	fn readings_are_scaled_back_up_through_the_divider() {
+		assert_eq!(PICO_VSYS.voltage_mv(0), 0);
+		assert_eq!(PICO_VSYS.voltage_mv(2048), 4950);
+		assert_eq!(PICO_VSYS.voltage_mv(4095), 9900);
+	}
+
+	#[test]
+	fn charge_is_clamped_between_empty_and_full() {
+		assert_eq!(PICO_VSYS.percent(3000), 0);
+		assert_eq!(PICO_VSYS.percent(3750), 50);
+		assert_eq!(PICO_VSYS.percent(5000), 100);
+	}
+}
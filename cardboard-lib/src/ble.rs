@@ -0,0 +1,155 @@
+use alloc::vec::Vec;
+
+use crate::hid::HidReport;
+use crate::transport::HidTransport;
+
+/// HID-over-GATT has one input report characteristic per report ID, so each device in the
+/// report map gets its own.
+pub const KEYBOARD_REPORT_ID: u8 = 1;
+pub const MOUSE_REPORT_ID: u8 = 2;
+pub const CONSUMER_REPORT_ID: u8 = 3;
+
+const COLLECTION: u8 = 0xA0;
+const REPORT_ID: u8 = 0x85;
+
+/// Joins USB report descriptors into the single report map a BLE HID service exposes, giving
+/// each a report ID as its application collection opens. The reports themselves are
+/// unchanged, so the same `HidDevice` bytes go out over either transport.
+pub fn report_map(descriptors: &[(u8, &[u8])]) -> Vec<u8> {
+	let mut map = Vec::with_capacity(descriptors.iter().map(|(_, d)| d.len() + 2).sum());
+	for (report_id, descriptor) in descriptors {
+		let mut i = 0;
+		let mut tagged = false;
+		while i < descriptor.len() {
+			let prefix = descriptor[i];
+			let size = [0, 1, 2, 4][(prefix & 0x03) as usize];
+			let end = (i + 1 + size).min(descriptor.len());
+			map.extend_from_slice(&descriptor[i..end]);
+			if !tagged && prefix & 0xFC == COLLECTION {
+				map.extend_from_slice(&[REPORT_ID, *report_id]);
+				tagged = true;
+			}
+			i = end;
+		}
+	}
+	map
+}
+
+/// A BLE connection to the host, with a HID service built from [`report_map`].
+pub trait BleHidTransport {
+	fn is_connected(&self) -> bool;
+
+	/// Notifies the host through the input report characteristic for a report ID.
+	async fn notify(&mut self, report_id: u8, report: &[u8]) -> Result<(), &'static str>;
+}
+
+/// Sends the reports the keypad task produces over BLE, the way the USB HID task writes
+/// them to its endpoints.
+pub struct BleHid<T: BleHidTransport> {
+	transport: T,
+}
+
+impl<T: BleHidTransport> BleHid<T> {
+	pub fn new(transport: T) -> Self {
+		Self { transport }
+	}
+
+	/// Reports made while no host is connected are dropped; each report carries the whole
+	/// state, so the next one after connecting catches the host up.
+	pub async fn send<const SIZE_K: usize, const SIZE_M: usize, const SIZE_C: usize>(
+		&mut self,
+		report: &HidReport<SIZE_K, SIZE_M, SIZE_C>,
+	) -> Result<(), &'static str> {
+		if !self.transport.is_connected() {
+			return Ok(());
+		}
+		if let Some(keyboard) = &report.keyboard {
+			self.transport.notify(KEYBOARD_REPORT_ID, keyboard).await?;
+		}
+		if let Some(mouse) = &report.mouse {
+			self.transport.notify(MOUSE_REPORT_ID, mouse).await?;
+		}
+		if let Some(consumer) = &report.consumer {
+			self.transport.notify(CONSUMER_REPORT_ID, consumer).await?;
+		}
+		Ok(())
+	}
+}
+
+impl<T: BleHidTransport, const SIZE_K: usize, const SIZE_M: usize, const SIZE_C: usize>
+	HidTransport<SIZE_K, SIZE_M, SIZE_C> for BleHid<T>
+{
+	async fn send(
+		&mut self,
+		report: &HidReport<SIZE_K, SIZE_M, SIZE_C>,
+	) -> Result<(), &'static str> {
+		BleHid::send(self, report).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use alloc::vec;
+
+	#[test]
+	fn report_ids_follow_each_application_collection() {
+		let keyboard = [0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, 0x75, 0x01, 0xC0];
+		let consumer = [0x05, 0x0C, 0x09, 0x01, 0xA1, 0x01, 0x2A, 0xFF, 0x00, 0xC0];
+
+		let map = report_map(&[
+			(KEYBOARD_REPORT_ID, &keyboard[..]),
+			(CONSUMER_REPORT_ID, &consumer[..]),
+		]);
+
+		assert_eq!(
+			map,
+			[
+				0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, 0x85, 1, 0x75, 0x01, 0xC0, //
+				0x05, 0x0C, 0x09, 0x01, 0xA1, 0x01, 0x85, 3, 0x2A, 0xFF, 0x00, 0xC0,
+			]
+		);
+	}
+
+	struct MockTransport {
+		connected: bool,
+		sent: Vec<(u8, Vec<u8>)>,
+	}
+
+	impl BleHidTransport for MockTransport {
+		fn is_connected(&self) -> bool {
+			self.connected
+		}
+
+		async fn notify(&mut self, report_id: u8, report: &[u8]) -> Result<(), &'static str> {
+			self.sent.push((report_id, report.to_vec()));
+			Ok(())
+		}
+	}
+
+	#[tokio::test]
+	async fn reports_go_out_under_their_ids_only_while_connected() {
+		let mut ble = BleHid::new(MockTransport {
+			connected: false,
+			sent: Vec::new(),
+		});
+		let report: HidReport<2, 1, 1> = HidReport {
+			keyboard: Some([0x02, 0x04]),
+			mouse: None,
+			consumer: Some([0x10]),
+		};
+
+		ble.send(&report).await.unwrap();
+		assert!(ble.transport.sent.is_empty());
+
+		ble.transport.connected = true;
+		ble.send(&report).await.unwrap();
+		assert_eq!(
+			ble.transport.sent,
+			[
+				(KEYBOARD_REPORT_ID, vec![0x02, 0x04]),
+				(CONSUMER_REPORT_ID, vec![0x10]),
+			]
+		);
+	}
+}
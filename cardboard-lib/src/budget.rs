@@ -0,0 +1,97 @@
+use core::cell::Cell;
+
+use crate::serialize::Writeable;
+use crate::stream::{WriteAsync, WriteAsyncExt};
+use critical_section::Mutex;
+
+/// A ceiling on the heap a single subsystem may use, so one runaway subsystem (say, a huge
+/// profile) can't starve the others. Violations are counted and logged.
+pub struct MemoryBudget {
+	name: &'static str,
+	limit: usize,
+	violations: Mutex<Cell<u32>>,
+}
+
+impl MemoryBudget {
+	pub const fn new(name: &'static str, limit: usize) -> Self {
+		Self {
+			name,
+			limit,
+			violations: Mutex::new(Cell::new(0)),
+		}
+	}
+
+	pub const fn unlimited(name: &'static str) -> Self {
+		Self::new(name, usize::MAX)
+	}
+
+	pub fn limit(&self) -> usize {
+		self.limit
+	}
+
+	pub fn violations(&self) -> u32 {
+		critical_section::with(|cs| self.violations.borrow(cs).get())
+	}
+
+	/// Checks `bytes` against the limit, recording a violation if it's over.
+	pub fn check(&self, bytes: usize) -> Result<(), &'static str> {
+		if bytes <= self.limit {
+			return Ok(());
+		}
+
+		warn!(
+			"{} memory budget exceeded: {} of {} bytes",
+			self.name, bytes, self.limit
+		);
+		critical_section::with(|cs| {
+			let violations = self.violations.borrow(cs);
+			violations.set(violations.get().saturating_add(1));
+		});
+		Err("Memory budget exceeded")
+	}
+}
+
+/// Budgets for the subsystems whose memory use depends on what the host sends.
+pub struct MemoryBudgets {
+	/// Heap held by a parsed profile.
+	pub profile: MemoryBudget,
+	/// Heap held by running macro state.
+	pub macros: MemoryBudget,
+	/// Transient heap used while handling a single serial command.
+	pub serial: MemoryBudget,
+}
+
+impl MemoryBudgets {
+	pub const fn new(profile: usize, macros: usize, serial: usize) -> Self {
+		Self {
+			profile: MemoryBudget::new("Profile", profile),
+			macros: MemoryBudget::new("Macro", macros),
+			serial: MemoryBudget::new("Serial", serial),
+		}
+	}
+}
+
+/// Violation counts per budget, for the status command.
+pub struct BudgetViolations {
+	pub profile: u32,
+	pub macros: u32,
+	pub serial: u32,
+}
+
+impl From<&MemoryBudgets> for BudgetViolations {
+	fn from(budgets: &MemoryBudgets) -> Self {
+		Self {
+			profile: budgets.profile.violations(),
+			macros: budgets.macros.violations(),
+			serial: budgets.serial.violations(),
+		}
+	}
+}
+
+impl Writeable for BudgetViolations {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_u32(self.profile).await?;
+		writer.write_u32(self.macros).await?;
+		writer.write_u32(self.serial).await
+	}
+}
@@ -0,0 +1,70 @@
+/// A note for the buzzer, queued by a macro.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tone {
+	/// Pitch in hertz; 0 is a rest.
+	pub freq_hz: u16,
+	pub duration_ms: u16,
+}
+
+/// A piezo buzzer or speaker that plays one pitch at a time.
+pub trait Buzzer {
+	fn start(&mut self, freq_hz: u16);
+	fn stop(&mut self);
+}
+
+/// Counter settings for a PWM output: the counter runs at the clock divided by `divider` and
+/// wraps after `top`, so the output has a frequency of `clock / divider / (top + 1)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PwmTiming {
+	pub divider: u8,
+	pub top: u16,
+}
+
+impl PwmTiming {
+	/// Picks the smallest divider that lets the counter reach the frequency, for the finest
+	/// pitch.
+	pub fn for_frequency(clock_hz: u32, freq_hz: u16) -> Self {
+		let freq_hz = freq_hz.max(1) as u32;
+		let divider = clock_hz
+			.div_ceil(freq_hz * (u16::MAX as u32 + 1))
+			.clamp(1, u8::MAX as u32);
+		let top = (clock_hz / (divider * freq_hz)).saturating_sub(1);
+		Self {
+			divider: divider as u8,
+			top: top.min(u16::MAX as u32) as u16,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CLOCK_HZ: u32 = 125_000_000;
+
+	#[test]
+	fn pwm_timing_hits_the_requested_pitch() {
+		for freq_hz in [20, 440, 4000, 20_000] {
+			let timing = PwmTiming::for_frequency(CLOCK_HZ, freq_hz);
+			let actual = CLOCK_HZ / (timing.divider as u32 * (timing.top as u32 + 1));
+			assert!(
+				actual.abs_diff(freq_hz as u32) <= 1,
+				"{} Hz came out as {}",
+				freq_hz,
+				actual
+			);
+		}
+	}
+
+	#[test]
+	fn pwm_timing_uses_the_smallest_divider() {
+		assert_eq!(
+			PwmTiming::for_frequency(CLOCK_HZ, 440),
+			PwmTiming {
+				divider: 5,
+				top: 56817
+			}
+		);
+		assert_eq!(PwmTiming::for_frequency(CLOCK_HZ, 20_000).divider, 1);
+	}
+}
@@ -1,34 +1,75 @@
+use crate::HeapFragmentation;
+use crate::auth::{AUTH_TAG_SIZE, validate_auth_secret};
+use crate::battery::BatteryLevel;
+use crate::budget::BudgetViolations;
+use crate::context::ContextAuth;
 use crate::context::ContextClock;
+use crate::context::ContextCrashReportFlash;
 use crate::context::ContextErrorLog;
+use crate::context::ContextFirmwareUpdateFlash;
+use crate::context::ContextLockFlash;
+use crate::context::ContextSafeMode;
 use crate::context::ContextSettingsFlash;
+use crate::crc::crc16;
 use crate::error::Error;
 use crate::error::ErrorLog;
+use crate::event::{Credit, Progress, ProgressStage, send_event};
+use crate::lock::{MAX_UNLOCK_ATTEMPTS, pins_match, validate_lock_pin};
+use crate::serial::{SerialCredit, SerialEventSender, TRANSFER_ABORTED};
+use crate::serialize::Readable;
 use crate::serialize::Writeable;
+use crate::settings::{
+	DeviceSettings, SettingValue, load_settings_or_default, save_settings, validate_device_name,
+};
+use crate::state::{ActiveTags, KeyStats, KeypadStatus};
+use crate::stats::UsbCounters;
 use crate::storage::BlockFlash;
 use crate::storage::BlockFlashExt;
 use crate::storage::PartitionedFlashMemory;
+use crate::storage::{
+	clear_auth_secret_in_flash, clear_crash_report_in_flash, clear_lock_pin_in_flash,
+	load_auth_secret_from_flash, load_crash_report_from_flash, load_lock_pin_from_flash,
+	save_auth_secret_to_flash, save_lock_pin_to_flash, yield_now,
+};
 use crate::time::Clock;
+use crate::update::{
+	DIGEST_SIZE, check_chunk, check_image, commit_update, image_offset, max_image_length,
+	record_update, staged_image, update_erase_length, verify_update,
+};
 use async_trait::async_trait;
+use bitflags::bitflags;
+use core::cell::Cell;
 use core::cmp::Ord;
+use core::marker::PhantomData;
 use core::module_path;
 use core::option_env;
 use core::panic;
 use core::result::Result;
 use core::result::Result::Err;
 use core::result::Result::Ok;
-use defmt::{debug, error};
+use fugit::ExtU64;
+use sha2::{Digest, Sha256};
 
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 use uuid::uuid;
 
-use crate::context::{ContextAllocator, ContextReboot};
 use crate::context::{
-	ContextDeviceInfo, ContextProfileFlash, ContextSerialRx, ContextSerialTx, ContextTags,
-	ContextUpdateProfile, ContextVirtualKeys, UpdateProfileSignalTx,
+	ContextActiveTags, ContextDeviceInfo, ContextInjectKeys, ContextKeyEvents, ContextKeyStats,
+	ContextKeypadStatus, ContextMacroSpeed, ContextMatrixScan, ContextProfileFlash,
+	ContextProfileUpload, ContextSerialRx, ContextSerialTx, ContextTags, ContextUpdateProfile,
+	ContextVirtualKeys, ContextVirtualKeysById, UpdateProfileSignalTx,
+};
+use crate::context::{
+	ContextAllocator, ContextBattery, ContextMemoryBudgets, ContextModules, ContextReboot,
+	ContextSyncMarkers, ContextUsbStats, ContextWallClock,
 };
 use crate::device::{CommandId, DeviceInfo};
-use crate::storage::load_profile_from_flash;
+use crate::input::{KeyId, KeyState, KeyboardAction, RawMatrixScan, VirtualKeyAction};
+use crate::profile::{
+	KeyboardProfile, MacroId, MacroIndex, ProfileRecord, VirtualKeyId, find_record,
+};
+use crate::storage::{load_profile_from_flash, max_profile_size};
 use crate::stream::{ReadAsync, ReadAsyncExt, WriteAsync, WriteAsyncExt};
 
 const CHUNK_SIZE: usize = 64; // TODO: parameterize this. for now, we hack it to the USB packet size we currently use
@@ -44,11 +85,15 @@ pub trait Command<Context> {
 pub struct IdentifyCommand;
 
 #[async_trait(?Send)]
-impl<Context: ContextDeviceInfo + ContextSerialTx> Command<Context> for IdentifyCommand {
+impl<Context: ContextDeviceInfo + ContextSerialRx + ContextSerialTx + ContextAuth> Command<Context>
+	for IdentifyCommand
+{
 	fn info(&self) -> CommandInfo {
 		CommandInfo {
 			id: CommandId(uuid!("ffffffff-ffff-ffff-ffff-ffffffffffff")),
 			name: "Identify",
+			flags: CommandFlags::READ_ONLY,
+			schema: 1,
 		}
 	}
 
@@ -56,6 +101,11 @@ impl<Context: ContextDeviceInfo + ContextSerialTx> Command<Context> for Identify
 	where
 		Context: 'async_trait,
 	{
+		// every Identify starts a new session, which has to authenticate again and gets the
+		// whole receive window back
+		ctx.auth_session().reset();
+		ctx.serial_rx().take_credit();
+
 		let response = IdentifyResponse {
 			info: ctx.device_info(),
 		};
@@ -65,14 +115,62 @@ impl<Context: ContextDeviceInfo + ContextSerialTx> Command<Context> for Identify
 
 const SIZEOF_PROFILE_LENGTH: usize = 2; // size of u16
 
+pub struct GetBuildInfoCommand;
+
+#[async_trait(?Send)]
+impl<Context: ContextDeviceInfo + ContextSerialTx> Command<Context> for GetBuildInfoCommand {
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("72105da0-ba91-5301-b877-d0d8d3031265")),
+			name: "Get Build Info",
+			flags: CommandFlags::READ_ONLY,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str>
+	where
+		Context: 'async_trait,
+	{
+		let build = &ctx.device_info().build;
+		build.write_to(ctx.serial_tx()).await
+	}
+}
+
 pub struct UpdateProfileCommand;
 
+/// A profile upload that was cut off before it finished, so the host can resume it with
+/// `ResumeProfileUploadCommand` rather than sending the whole profile again. It's only kept in
+/// RAM, so a reboot forgets it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProfileUpload {
+	pub length: usize,
+	/// The first chunk that hasn't been written to flash yet.
+	pub next_chunk: usize,
+}
+
+impl ProfileUpload {
+	/// How many bytes of the profile are already in flash.
+	pub fn committed(&self) -> usize {
+		(self.next_chunk * CHUNK_SIZE).min(self.length)
+	}
+}
+
 impl UpdateProfileCommand {
 	async fn try_execute<
-		Context: ContextSerialRx + ContextSerialTx + ContextProfileFlash + ContextUpdateProfile,
+		Context: ContextSerialRx
+			+ ContextSerialTx
+			+ ContextProfileFlash
+			+ ContextProfileUpload
+			+ ContextUpdateProfile
+			+ ContextAllocator
+			+ ContextMemoryBudgets,
 	>(
 		ctx: &mut Context,
-	) -> Result<(), (u8, &'static str)> {
+	) -> Result<(), (u8, &'static str)>
+	where
+		Context::SerialTx: SerialEventSender,
+	{
 		let len = ctx.serial_rx().read_u16().await.ok_or_else(|| {
 			error!("Failed to read profile length");
 			(0x10u8, "Failed to read profile length")
@@ -80,41 +178,116 @@ impl UpdateProfileCommand {
 
 		debug!("Profile length: {}", len);
 
+		// whatever was left of an earlier upload is about to be erased
+		*ctx.profile_upload() = None;
+
 		// clear profile flash storage
-		ctx.profile_flash().erase_at_least(len).or_else(|e| {
-			error!("Failed to erase profile flash storage: {:?}", e);
-			Err((0x20u8, e))
+		erase_with_progress(ctx, |c| c.profile_flash(), SIZEOF_PROFILE_LENGTH + len)
+			.await
+			.or_else(|e| {
+				error!("Failed to erase profile flash storage: {:?}", e);
+				Err((0x20u8, e))
+			})?;
+
+		*ctx.profile_upload() = Some(ProfileUpload {
+			length: len,
+			next_chunk: 0,
+		});
+		Self::finish_upload(ctx).await
+	}
+
+	/// Receives the rest of the upload in `ctx.profile_upload()` and loads the profile.
+	async fn finish_upload<
+		Context: ContextSerialRx
+			+ ContextSerialTx
+			+ ContextProfileFlash
+			+ ContextProfileUpload
+			+ ContextUpdateProfile
+			+ ContextAllocator
+			+ ContextMemoryBudgets,
+	>(
+		ctx: &mut Context,
+	) -> Result<(), (u8, &'static str)>
+	where
+		Context::SerialTx: SerialEventSender,
+	{
+		let upload = (*ctx.profile_upload()).ok_or((0x11u8, "No profile upload to resume"))?;
+		let len = upload.length;
+
+		let mut next_chunk = upload.next_chunk;
+		let copied = copy_serial_to_flash_acked(
+			ctx,
+			|c| c.profile_flash(),
+			SIZEOF_PROFILE_LENGTH,
+			len,
+			&mut next_chunk,
+		)
+		.await;
+
+		// a dropped connection can be resumed from the last chunk that made it to flash; an
+		// abort or a flash failure can't
+		*ctx.profile_upload() = match copied {
+			Err(CopySerialToFlashError::SerialReadError(_))
+			| Err(CopySerialToFlashError::SerialWriteError(_))
+			| Err(CopySerialToFlashError::TooManyRetries) => Some(ProfileUpload {
+				next_chunk,
+				..upload
+			}),
+			_ => None,
+		};
+
+		copied.map_err(|e| match e {
+			CopySerialToFlashError::Aborted => {
+				info!("Profile upload aborted");
+				(STATUS_ABORTED, TRANSFER_ABORTED)
+			}
+			CopySerialToFlashError::SerialReadError(e) => {
+				error!("Failed to read profile chunk from serial port: {:?}", e);
+				(0x14u8, "Failed to read profile chunk from serial port")
+			}
+			CopySerialToFlashError::SerialWriteError(e) => {
+				error!("Failed to acknowledge profile chunk: {:?}", e);
+				(0x16u8, "Failed to acknowledge profile chunk")
+			}
+			CopySerialToFlashError::TooManyRetries => {
+				error!("Too many failed profile chunk transfers");
+				(0x18u8, "Too many failed profile chunk transfers")
+			}
+			CopySerialToFlashError::FlashWriteError(e) => {
+				error!("Failed to write profile to flash storage: {:?}", e);
+				(0x28u8, "Failed to write profile to flash storage")
+			}
 		})?;
 
-		// write profile length to flash storage
+		// deserialize the profile from flash storage before its length is written
+		let heap_before = ctx.allocator().current();
+		let mut data = ctx
+			.profile_flash()
+			.as_slice()
+			.get(SIZEOF_PROFILE_LENGTH..SIZEOF_PROFILE_LENGTH + len)
+			.ok_or((0x2Cu8, "Profile length exceeds flash storage"))?;
+		let profile = KeyboardProfile::read_from(&mut data).await.map_err(|e| {
+			error!("Failed to load profile from flash storage: {:?}", e);
+			(0x2Cu8, "Failed to load profile from flash storage")
+		})?;
+
+		// the profile is dropped here if it's over budget, leaving the old one running
+		let heap_used = ctx.allocator().current().saturating_sub(heap_before);
+		ctx.budgets()
+			.profile
+			.check(heap_used)
+			.map_err(|_| (0x30u8, "Profile exceeds memory budget"))?;
+
+		// the length goes in last, so an aborted upload, or a profile that doesn't load or is
+		// over budget, reads as no profile at all at the next boot rather than a bad one
 		ctx.profile_flash()
 			.write(0, &(len as u16).to_le_bytes())
+			.await
 			.or_else(|e| {
 				error!("Failed to write profile length to flash storage: {:?}", e);
 				Err((0x24u8, "Failed to write profile length to flash storage"))
 			})?;
 
-		copy_serial_to_flash(ctx, |c| c.profile_flash(), SIZEOF_PROFILE_LENGTH, len)
-			.await
-			.map_err(|e| match e {
-				CopySerialToFlashError::SerialReadError(e) => {
-					error!("Failed to read profile chunk from serial port: {:?}", e);
-					(0x14u8, "Failed to read profile chunk from serial port")
-				}
-				CopySerialToFlashError::FlashWriteError(e) => {
-					error!("Failed to write profile to flash storage: {:?}", e);
-					(0x28u8, "Failed to write profile to flash storage")
-				}
-			})?;
-
-		// deserialize profile from flash storage
-		let profile = load_profile_from_flash(&mut ctx.profile_flash())
-			.await
-			.map_err(|e| {
-				error!("Failed to load profile from flash storage: {:?}", e);
-				(0x2Cu8, "Failed to load profile from flash storage")
-			})?;
-
 		// signal profile changed
 		ctx.profile_signal().update_profile(profile);
 
@@ -123,34 +296,109 @@ impl UpdateProfileCommand {
 }
 
 #[async_trait(?Send)]
-impl<Context: ContextSerialRx + ContextSerialTx + ContextProfileFlash + ContextUpdateProfile>
-	Command<Context> for UpdateProfileCommand
+impl<Context> Command<Context> for UpdateProfileCommand
+where
+	Context: ContextSerialRx
+		+ ContextSerialTx
+		+ ContextProfileFlash
+		+ ContextProfileUpload
+		+ ContextUpdateProfile
+		+ ContextAllocator
+		+ ContextMemoryBudgets,
+	Context::SerialTx: SerialEventSender,
 {
 	fn info(&self) -> CommandInfo {
 		CommandInfo {
 			id: CommandId(uuid!("45963fd8-73e2-50a0-ba69-69c3333dd8af")),
 			name: "Set Keyboard Profile",
+			flags: CommandFlags::MUTATING | CommandFlags::PRIVILEGED | CommandFlags::LONG_RUNNING,
+			schema: 1,
 		}
 	}
 
 	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
 		let result = Self::try_execute(ctx).await;
+		write_result(ctx, result).await
+	}
+}
 
-		let response = match result {
-			Ok(_) => 0xFF,
-			Err((code, _)) => code,
-		};
+/// Reports the profile upload that was cut off, if any: its length, how much of it is already
+/// in flash, and the CRC-16 of that part so the host can check it's resuming the same profile.
+pub struct GetProfileUploadStatusCommand;
 
-		ctx.serial_tx().write_u8(response).await.or_else(|e| {
-			error!("Failed to write response to serial port: {:?}", e);
-			Err("Failed to write response")
-		})?;
+struct ProfileUploadStatus {
+	length: u32,
+	committed: u32,
+	crc: u16,
+}
 
-		match result {
-			Ok(_) => Ok(()),
-			Err((_, msg)) => Err(msg),
+impl Writeable for ProfileUploadStatus {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_u32(self.length).await?;
+		writer.write_u32(self.committed).await?;
+		writer.write_u16(self.crc).await?;
+		Ok(())
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialTx + ContextProfileFlash + ContextProfileUpload> Command<Context>
+	for GetProfileUploadStatusCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("d69a5080-2e3e-5a99-9ef3-25fb763303b0")),
+			name: "Get Profile Upload Status",
+			flags: CommandFlags::READ_ONLY,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let upload = *ctx.profile_upload();
+		let status = upload.map(|upload| {
+			let committed = upload.committed();
+			let data = ctx.profile_flash().as_slice();
+			ProfileUploadStatus {
+				length: upload.length as u32,
+				committed: committed as u32,
+				crc: crc16(&data[SIZEOF_PROFILE_LENGTH..SIZEOF_PROFILE_LENGTH + committed]),
+			}
+		});
+		ctx.serial_tx().write_option(status).await
+	}
+}
+
+/// Carries on with the profile upload that was cut off, from the first chunk that didn't make
+/// it to flash. The host sends chunks as it would for `UpdateProfileCommand`; one sent from the
+/// wrong place is answered with the sequence number to send instead.
+pub struct ResumeProfileUploadCommand;
+
+#[async_trait(?Send)]
+impl<Context> Command<Context> for ResumeProfileUploadCommand
+where
+	Context: ContextSerialRx
+		+ ContextSerialTx
+		+ ContextProfileFlash
+		+ ContextProfileUpload
+		+ ContextUpdateProfile
+		+ ContextAllocator
+		+ ContextMemoryBudgets,
+	Context::SerialTx: SerialEventSender,
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("35a70434-dcbd-5d8b-ae4e-e07a57892ab7")),
+			name: "Resume Profile Upload",
+			flags: CommandFlags::MUTATING | CommandFlags::PRIVILEGED | CommandFlags::LONG_RUNNING,
+			schema: 1,
 		}
 	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let result = UpdateProfileCommand::finish_upload(ctx).await;
+		write_result(ctx, result).await
+	}
 }
 
 pub struct GetProfileCommand;
@@ -161,6 +409,8 @@ impl<Context: ContextSerialTx + ContextProfileFlash> Command<Context> for GetPro
 		CommandInfo {
 			id: CommandId(uuid!("e8dfdb54-f01c-5f79-9bb7-7d8d0c0c82d1")),
 			name: "Get Keyboard Profile",
+			flags: CommandFlags::READ_ONLY | CommandFlags::LONG_RUNNING,
+			schema: 1,
 		}
 	}
 
@@ -189,6 +439,164 @@ impl<Context: ContextSerialTx + ContextProfileFlash> Command<Context> for GetPro
 	}
 }
 
+/// Rebinds one key without uploading the whole profile: the host sends the key's ID, then the
+/// length and bytes of its new layers in the stored profile's format, and the key's layers are
+/// replaced in flash and in the running profile.
+pub struct SetKeyBindingCommand;
+
+impl SetKeyBindingCommand {
+	async fn try_execute<
+		Context: ContextSerialRx
+			+ ContextSerialTx
+			+ ContextProfileFlash
+			+ ContextProfileUpload
+			+ ContextUpdateProfile
+			+ ContextAllocator
+			+ ContextMemoryBudgets,
+	>(
+		ctx: &mut Context,
+	) -> Result<(), (u8, &'static str)>
+	where
+		Context::SerialTx: SerialEventSender,
+	{
+		let key_id = KeyId::read_from(ctx.serial_rx())
+			.await
+			.map_err(|e| (0x10u8, e))?;
+		let layers = read_replacement_record(ctx).await?;
+
+		replace_profile_record(ctx, ProfileRecord::KeyLayers(key_id), &layers).await
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context> Command<Context> for SetKeyBindingCommand
+where
+	Context: ContextSerialRx
+		+ ContextSerialTx
+		+ ContextProfileFlash
+		+ ContextProfileUpload
+		+ ContextUpdateProfile
+		+ ContextAllocator
+		+ ContextMemoryBudgets,
+	Context::SerialTx: SerialEventSender,
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("9b3e27c4-61d8-5f0a-a4c2-7e15d8b09f36")),
+			name: "Set Key Binding",
+			flags: CommandFlags::MUTATING | CommandFlags::PRIVILEGED | CommandFlags::LONG_RUNNING,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let result = Self::try_execute(ctx).await;
+		write_result(ctx, result).await
+	}
+}
+
+/// Replaces one macro without uploading the whole profile, so a long macro can be tweaked and
+/// tried again quickly. The host picks the macro by index (`0x00`, then a u16) or by ID (`0x01`,
+/// then a UUID), then sends the length and bytes of the new macro in the stored profile's
+/// format.
+pub struct SetMacroCommand;
+
+impl SetMacroCommand {
+	const BY_INDEX: u8 = 0x00;
+	const BY_ID: u8 = 0x01;
+
+	async fn try_execute<
+		Context: ContextSerialRx
+			+ ContextSerialTx
+			+ ContextProfileFlash
+			+ ContextProfileUpload
+			+ ContextUpdateProfile
+			+ ContextAllocator
+			+ ContextMemoryBudgets,
+	>(
+		ctx: &mut Context,
+	) -> Result<(), (u8, &'static str)>
+	where
+		Context::SerialTx: SerialEventSender,
+	{
+		let selector = ctx
+			.serial_rx()
+			.read_u8()
+			.await
+			.ok_or((0x10u8, "Failed to read macro selector"))?;
+		let record = match selector {
+			Self::BY_INDEX => MacroIndex::read_from(ctx.serial_rx())
+				.await
+				.map(ProfileRecord::Macro),
+			Self::BY_ID => MacroId::read_from(ctx.serial_rx())
+				.await
+				.map(ProfileRecord::MacroById),
+			_ => return Err((0x10u8, "Invalid macro selector")),
+		}
+		.map_err(|e| (0x10u8, e))?;
+		let m = read_replacement_record(ctx).await?;
+
+		replace_profile_record(ctx, record, &m).await
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context> Command<Context> for SetMacroCommand
+where
+	Context: ContextSerialRx
+		+ ContextSerialTx
+		+ ContextProfileFlash
+		+ ContextProfileUpload
+		+ ContextUpdateProfile
+		+ ContextAllocator
+		+ ContextMemoryBudgets,
+	Context::SerialTx: SerialEventSender,
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("c4a81f5e-2d79-5b36-9e0c-63f7a2d5b814")),
+			name: "Set Macro",
+			flags: CommandFlags::MUTATING | CommandFlags::PRIVILEGED | CommandFlags::LONG_RUNNING,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let result = Self::try_execute(ctx).await;
+		write_result(ctx, result).await
+	}
+}
+
+/// Returns the length and SHA-256 of the stored profile, so a host can check whether the
+/// device already has its copy before syncing.
+pub struct GetProfileHashCommand;
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialTx + ContextProfileFlash> Command<Context> for GetProfileHashCommand {
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("7f2d94b0-c85e-5a13-b6d1-08e4c37a95f2")),
+			name: "Get Profile Hash",
+			flags: CommandFlags::READ_ONLY,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let data = ctx.profile_flash().as_slice();
+		let (Some(profile), Some(digest)) =
+			(stored_profile(data), stored_profile_digest(data).await)
+		else {
+			ctx.serial_tx().write_u8(0x12).await?;
+			return Err("No profile stored");
+		};
+
+		ctx.serial_tx().write_u8(0xFF).await?;
+		ctx.serial_tx().write_u16(profile.len() as u16).await?;
+		ctx.serial_tx().write_exact(&digest).await
+	}
+}
+
 pub struct SetExternalTagsCommand;
 
 #[async_trait(?Send)]
@@ -199,6 +607,8 @@ impl<Context: ContextSerialRx + ContextSerialTx + ContextTags> Command<Context>
 		CommandInfo {
 			id: CommandId(uuid!("6d84630b-03ec-57f7-806e-b1c5dee4974d")),
 			name: "Set External Tags",
+			flags: CommandFlags::empty(),
+			schema: 1,
 		}
 	}
 
@@ -225,6 +635,8 @@ impl<Context: ContextReboot + ContextSerialRx + ContextSerialTx> Command<Context
 		CommandInfo {
 			id: CommandId(uuid!("6dce0823-d199-5abb-a56f-a85cdba61842")),
 			name: "Enter Bootloader",
+			flags: CommandFlags::MUTATING | CommandFlags::PRIVILEGED,
+			schema: 1,
 		}
 	}
 
@@ -249,31 +661,191 @@ impl<Context: ContextReboot + ContextSerialRx + ContextSerialTx> Command<Context
 pub struct GetStatusCommand;
 
 #[async_trait(?Send)]
-impl<Context: ContextSerialTx + ContextAllocator + ContextClock + ContextErrorLog> Command<Context>
-	for GetStatusCommand
+impl<Context> Command<Context> for GetStatusCommand
+where
+	Context: ContextSerialTx
+		+ ContextAllocator
+		+ ContextClock
+		+ ContextErrorLog
+		+ ContextDeviceInfo
+		+ ContextProfileFlash
+		+ ContextKeypadStatus
+		+ ContextUsbStats
+		+ ContextBattery
+		+ ContextMemoryBudgets
+		+ ContextWallClock,
 {
 	fn info(&self) -> CommandInfo {
 		CommandInfo {
 			id: CommandId(uuid!("b14aadb5-53a2-5e69-b463-603efce7c199")),
 			name: "Get Status",
+			flags: CommandFlags::READ_ONLY,
+			schema: 2,
 		}
 	}
 
 	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
 		let allocator_current = ctx.allocator().current();
 		let allocator_max = ctx.allocator().max();
+		let heap = ctx.allocator().fragmentation();
+
+		// a stalled keypad task shouldn't stop the host from seeing the rest of the status
+		ctx.request_keypad_status();
+		let keypad = wait_for_keypad(ctx, |ctx| ctx.try_take_keypad_status()).await;
 
+		let now = ctx.clock().now();
 		let response = StatusResponse {
-			now: ctx.clock().now().ticks(),
+			now: now.ticks(),
 			allocator_current,
 			allocator_max,
 			errors: ctx.errors().get_errors().cloned().collect(),
+			mouse_enabled: ctx.device_info().mouse_enabled,
+			uptime_ms: now.duration_since_epoch().to_millis(),
+			profile_hash: stored_profile_hash(ctx.profile_flash().as_slice()),
+			keypad,
+			usb: ctx.usb_stats().get(),
+			heap,
+			budget_violations: ctx.budgets().into(),
+			battery: ctx.battery().get(),
+			unix_ms: ctx.wall_clock().unix_ms(now),
 		};
 
 		response.write_to(ctx.serial_tx()).await
 	}
 }
 
+pub struct ResetAllocatorStatsCommand;
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialTx + ContextAllocator> Command<Context> for ResetAllocatorStatsCommand {
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("fa80829a-ec2f-5063-ae6b-4b1f265d5a7a")),
+			name: "Reset Allocator Stats",
+			flags: CommandFlags::empty(),
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		// reply with the new baseline so the host can diff the max reported by GetStatus later
+		ctx.allocator().reset_stats();
+		let current = ctx.allocator().current();
+
+		ctx.serial_tx().write_u8(0xFF).await?;
+		ctx.serial_tx().write_u32(current as u32).await
+	}
+}
+
+pub struct ClearErrorsCommand;
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialTx + ContextErrorLog> Command<Context> for ClearErrorsCommand {
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("cc402f99-57e1-5adc-b8b0-8628a07c782b")),
+			name: "Clear Errors",
+			flags: CommandFlags::empty(),
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		ctx.errors().clear();
+		ctx.serial_tx().write_u8(0xFF).await?;
+
+		Ok(())
+	}
+}
+
+/// Echoes a host-chosen nonce along with the device clock so the host can measure round-trip
+/// latency and check the link before starting a large transfer.
+pub struct PingCommand;
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialRx + ContextSerialTx + ContextClock> Command<Context> for PingCommand {
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("f9a17f82-010f-51c6-995d-a1ad1b4ea3ce")),
+			name: "Ping",
+			flags: CommandFlags::READ_ONLY,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let nonce = ctx
+			.serial_rx()
+			.read_u32()
+			.await
+			.ok_or("Failed to read ping nonce")?;
+		let now = ctx.clock().now().ticks();
+
+		ctx.serial_tx().write_u32(nonce).await?;
+		ctx.serial_tx().write_u64(now).await?;
+
+		Ok(())
+	}
+}
+
+/// Turns sync markers on or off for the rest of the session. While they're on, every command
+/// starts with `SYNC_MARKER`, and after a failed command the device skips to the next marker.
+/// Only changes how this host's commands are read, so it's allowed while locked.
+pub struct SetSyncMarkersCommand;
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialRx + ContextSerialTx + ContextSyncMarkers> Command<Context>
+	for SetSyncMarkersCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("3b6e0f41-9d2a-5c87-a4f3-61c8e25d07b9")),
+			name: "Set Sync Markers",
+			flags: CommandFlags::empty(),
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let Some(enabled) = ctx.serial_rx().read_bool().await else {
+			ctx.serial_tx().write_u8(0x10).await?;
+			return Err("Failed to read sync marker setting");
+		};
+		// this response is the last one read without a marker in front of the next command
+		*ctx.sync_markers() = enabled;
+		ctx.serial_tx().write_u8(0xFF).await
+	}
+}
+
+/// Tells the device the real time, as milliseconds since the Unix epoch, so `GetStatus` can
+/// report it. Hosts send it on connect; it's forgotten on reboot.
+pub struct SetTimeCommand;
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialRx + ContextSerialTx + ContextClock + ContextWallClock> Command<Context>
+	for SetTimeCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("acf7aa57-eede-5c3f-a333-d297a33ca75f")),
+			name: "Set Time",
+			flags: CommandFlags::MUTATING | CommandFlags::PRIVILEGED,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let unix_ms = ctx
+			.serial_rx()
+			.read_u64()
+			.await
+			.ok_or("Failed to read time")?;
+		ctx.wall_clock().sync(ctx.clock().now(), unix_ms);
+
+		ctx.serial_tx().write_u8(0xFF).await
+	}
+}
+
 pub struct SetVirtualKeysCommand<const VIRTUAL_KEY_BITFIELD_BYTES: usize>
 where
 	[(); VIRTUAL_KEY_BITFIELD_BYTES]:;
@@ -304,6 +876,8 @@ where
 		CommandInfo {
 			id: CommandId(uuid!("162d99cc-5e8f-5879-97fc-c37fdb0f22a9")),
 			name: "Set Virtual Key (8 keys)",
+			flags: CommandFlags::empty(),
+			schema: 1,
 		}
 	}
 
@@ -321,6 +895,27 @@ where
 		CommandInfo {
 			id: CommandId(uuid!("c1b2d3e4-f5a6-7b8c-9d0e-f1a2b3c4d5e6")),
 			name: "Set Virtual Key (32 keys)",
+			flags: CommandFlags::empty(),
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		self.execute(ctx).await
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context> Command<Context> for SetVirtualKeysCommand<16>
+where
+	Context: ContextSerialRx + ContextSerialTx + ContextVirtualKeys<16>,
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("75ab1f01-add0-5026-9954-8f332ac893ce")),
+			name: "Set Virtual Key (128 keys)",
+			flags: CommandFlags::empty(),
+			schema: 1,
 		}
 	}
 
@@ -334,7 +929,10 @@ pub struct UpdateSettingsCommand;
 impl UpdateSettingsCommand {
 	async fn try_execute<Context: ContextSerialRx + ContextSerialTx + ContextSettingsFlash>(
 		ctx: &mut Context,
-	) -> Result<(), (u8, &'static str)> {
+	) -> Result<(), (u8, &'static str)>
+	where
+		Context::SerialTx: SerialEventSender,
+	{
 		let len = ctx.serial_rx().read_u16().await.ok_or_else(|| {
 			error!("Failed to read settings length");
 			(0x10u8, "Failed to read settings length")
@@ -342,31 +940,65 @@ impl UpdateSettingsCommand {
 
 		debug!("Settings length: {}", len);
 
-		// clear settings flash storage
-		ctx.settings_flash().erase_at_least(len).or_else(|e| {
-			error!("Failed to erase settings flash storage: {:?}", e);
-			Err((0x20u8, "Failed to erase settings flash storage"))
-		})?;
+		// kept so an aborted upload can put the old settings back
+		let previous = stored_settings_record(ctx.settings_flash().as_slice()).map(|r| r.to_vec());
 
-		// write settings length to flash storage
+		// clear settings flash storage
+		let record_length = SIZEOF_SETTINGS_LENGTH + len;
 		ctx.settings_flash()
-			.write(0, &(len as u16).to_le_bytes())
+			.erase_at_least(record_length)
+			.await
 			.or_else(|e| {
-				error!("Failed to write settings length to flash storage: {:?}", e);
-				Err((0x24u8, "Failed to write settings length to flash storage"))
+				error!("Failed to erase settings flash storage: {:?}", e);
+				Err((0x20u8, "Failed to erase settings flash storage"))
 			})?;
 
-		copy_serial_to_flash(ctx, |c| c.settings_flash(), SIZEOF_SETTINGS_LENGTH, len)
-			.await
-			.map_err(|e| match e {
-				CopySerialToFlashError::SerialReadError(e) => {
-					error!("Failed to read settings chunk from serial port: {:?}", e);
-					(0x14u8, "Failed to read settings chunk from serial port")
-				}
-				CopySerialToFlashError::FlashWriteError(e) => {
-					error!("Failed to write settings to flash storage: {:?}", e);
-					(0x28u8, "Failed to write settings to flash storage")
+		let copied =
+			copy_serial_to_flash(ctx, |c| c.settings_flash(), SIZEOF_SETTINGS_LENGTH, len).await;
+		if let Err(CopySerialToFlashError::Aborted) = copied {
+			info!("Settings upload aborted");
+			if let Some(previous) = previous {
+				let mut flash = ctx.settings_flash();
+				let erase_length = record_length.max(previous.len());
+				let restored = match flash.erase_at_least(erase_length).await {
+					Ok(_) => flash.write(0, &previous).await,
+					Err(e) => Err(e),
+				};
+				if let Err(e) = restored {
+					error!("Failed to restore settings in flash storage: {:?}", e);
 				}
+			}
+			return Err((STATUS_ABORTED, TRANSFER_ABORTED));
+		}
+		copied.map_err(|e| match e {
+			CopySerialToFlashError::SerialReadError(e) => {
+				error!("Failed to read settings chunk from serial port: {:?}", e);
+				(0x14u8, "Failed to read settings chunk from serial port")
+			}
+			CopySerialToFlashError::SerialWriteError(e) => {
+				error!("Failed to acknowledge settings chunk: {:?}", e);
+				(0x16u8, "Failed to acknowledge settings chunk")
+			}
+			CopySerialToFlashError::TooManyRetries => {
+				error!("Too many failed settings chunk transfers");
+				(0x18u8, "Too many failed settings chunk transfers")
+			}
+			CopySerialToFlashError::FlashWriteError(e) => {
+				error!("Failed to write settings to flash storage: {:?}", e);
+				(0x28u8, "Failed to write settings to flash storage")
+			}
+			// handled above, once the old settings are back
+			CopySerialToFlashError::Aborted => (STATUS_ABORTED, TRANSFER_ABORTED),
+		})?;
+
+		// the length goes in last, so a failed upload reads as no settings rather than part of
+		// them
+		ctx.settings_flash()
+			.write(0, &(len as u16).to_le_bytes())
+			.await
+			.or_else(|e| {
+				error!("Failed to write settings length to flash storage: {:?}", e);
+				Err((0x24u8, "Failed to write settings length to flash storage"))
 			})?;
 
 		Ok(())
@@ -376,11 +1008,70 @@ impl UpdateSettingsCommand {
 #[async_trait(?Send)]
 impl<Context: ContextSerialRx + ContextSerialTx + ContextSettingsFlash> Command<Context>
 	for UpdateSettingsCommand
+where
+	Context::SerialTx: SerialEventSender,
 {
 	fn info(&self) -> CommandInfo {
 		CommandInfo {
 			id: CommandId(uuid!("a2460f18-32a8-5e57-b8c7-7adac7a096bd")),
 			name: "Update Settings",
+			flags: CommandFlags::MUTATING | CommandFlags::PRIVILEGED,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let result = Self::try_execute(ctx).await;
+		write_result(ctx, result).await
+	}
+}
+
+pub struct SetDeviceNameCommand<Settings: DeviceSettings> {
+	_marker: PhantomData<Settings>,
+}
+
+impl<Settings: DeviceSettings> SetDeviceNameCommand<Settings> {
+	pub const fn new() -> Self {
+		Self {
+			_marker: PhantomData,
+		}
+	}
+
+	async fn try_execute<Context: ContextSerialRx + ContextSettingsFlash>(
+		ctx: &mut Context,
+	) -> Result<(), (u8, &'static str)> {
+		let name = ctx
+			.serial_rx()
+			.read_string_u8()
+			.await
+			.ok_or((0x10u8, "Failed to read device name"))?;
+
+		validate_device_name(&name).map_err(|e| (0x11u8, e))?;
+
+		let mut settings: Settings = load_settings_or_default(&mut ctx.settings_flash()).await;
+		settings.set_device_name(name);
+
+		save_settings(&mut ctx.settings_flash(), &settings)
+			.await
+			.map_err(|e| {
+				error!("Failed to save settings: {:?}", e);
+				(0x20u8, "Failed to save settings")
+			})
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context, Settings> Command<Context> for SetDeviceNameCommand<Settings>
+where
+	Context: ContextSerialRx + ContextSerialTx + ContextSettingsFlash,
+	Settings: DeviceSettings,
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("b0eaba58-0ac9-5b6c-a5a2-1cc05aaeb95e")),
+			name: "Set Device Name",
+			flags: CommandFlags::MUTATING | CommandFlags::PRIVILEGED,
+			schema: 1,
 		}
 	}
 
@@ -392,10 +1083,75 @@ impl<Context: ContextSerialRx + ContextSerialTx + ContextSettingsFlash> Command<
 			Err((code, _)) => code,
 		};
 
-		ctx.serial_tx().write_u8(response).await.or_else(|e| {
-			error!("Failed to write response to serial port: {:?}", e);
-			Err("Failed to write response")
-		})?;
+		ctx.serial_tx().write_u8(response).await?;
+
+		match result {
+			Ok(_) => Ok(()),
+			Err((_, msg)) => Err(msg),
+		}
+	}
+}
+
+pub struct SetSettingCommand<Settings: DeviceSettings> {
+	_marker: PhantomData<Settings>,
+}
+
+impl<Settings: DeviceSettings> SetSettingCommand<Settings> {
+	pub const fn new() -> Self {
+		Self {
+			_marker: PhantomData,
+		}
+	}
+
+	async fn try_execute<Context: ContextSerialRx + ContextSettingsFlash>(
+		ctx: &mut Context,
+	) -> Result<(), (u8, &'static str)> {
+		let key = ctx
+			.serial_rx()
+			.read_u8()
+			.await
+			.ok_or((0x10u8, "Failed to read setting key"))?;
+
+		let value = SettingValue::read_from(ctx.serial_rx())
+			.await
+			.map_err(|e| (0x11u8, e))?;
+
+		let mut settings: Settings = load_settings_or_default(&mut ctx.settings_flash()).await;
+		settings.set_setting(key, value).map_err(|e| (0x12u8, e))?;
+
+		save_settings(&mut ctx.settings_flash(), &settings)
+			.await
+			.map_err(|e| {
+				error!("Failed to save settings: {:?}", e);
+				(0x20u8, "Failed to save settings")
+			})
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context, Settings> Command<Context> for SetSettingCommand<Settings>
+where
+	Context: ContextSerialRx + ContextSerialTx + ContextSettingsFlash,
+	Settings: DeviceSettings,
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("749bf25b-190b-5cdc-a231-47f6f626e3de")),
+			name: "Set Setting",
+			flags: CommandFlags::MUTATING | CommandFlags::PRIVILEGED,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let result = Self::try_execute(ctx).await;
+
+		let response = match result {
+			Ok(_) => 0xFF,
+			Err((code, _)) => code,
+		};
+
+		ctx.serial_tx().write_u8(response).await?;
 
 		match result {
 			Ok(_) => Ok(()),
@@ -413,6 +1169,8 @@ impl<Context: ContextSerialTx + ContextSettingsFlash> Command<Context> for GetSe
 		CommandInfo {
 			id: CommandId(uuid!("0062d411-70a5-55a5-a333-16706d62069f")),
 			name: "Get Device Settings",
+			flags: CommandFlags::READ_ONLY,
+			schema: 1,
 		}
 	}
 
@@ -434,39 +1192,1159 @@ impl<Context: ContextSerialTx + ContextSettingsFlash> Command<Context> for GetSe
 	}
 }
 
-pub struct IdentifyResponse<'a> {
-	info: &'a DeviceInfo,
+pub struct SubscribeKeyEventsCommand;
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialRx + ContextSerialTx + ContextKeyEvents> Command<Context>
+	for SubscribeKeyEventsCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("354abcdd-566f-5288-9d7a-21a5760d0cb8")),
+			name: "Subscribe Key Events",
+			flags: CommandFlags::empty(),
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let subscribed = ctx
+			.serial_rx()
+			.read_u8()
+			.await
+			.ok_or("Failed to read subscription state")?;
+		ctx.subscribe_key_events(subscribed != 0);
+		ctx.serial_tx().write_u8(0xFF).await?;
+
+		Ok(())
+	}
 }
 
-impl Writeable for IdentifyResponse<'_> {
-	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
-		const VERSION: u32 = 1;
-		writer.write_u32(VERSION).await?;
-		self.info.write_to(writer).await
+/// How long to wait for the keypad task to answer a request; it checks once per tick.
+const KEYPAD_RESPONSE_TIMEOUT_MS: u64 = 100;
+
+async fn wait_for_keypad<Context: ContextClock, T>(
+	ctx: &mut Context,
+	mut try_take: impl FnMut(&mut Context) -> Option<T>,
+) -> Option<T> {
+	for _ in 0..KEYPAD_RESPONSE_TIMEOUT_MS {
+		if let Some(value) = try_take(ctx) {
+			return Some(value);
+		}
+		ctx.clock().after(1.millis()).await;
 	}
+
+	None
 }
 
-#[derive(Clone)]
-pub struct CommandInfo {
-	pub id: CommandId,
-	pub name: &'static str,
-	// TODO: add fingerprint boolean (if true, command must write id after cmd index to confirm command execution)
+pub struct GetRawMatrixCommand;
+
+impl GetRawMatrixCommand {
+	async fn try_execute<Context: ContextSerialTx + ContextMatrixScan + ContextClock>(
+		ctx: &mut Context,
+	) -> Result<RawMatrixScan, (u8, &'static str)> {
+		ctx.request_matrix_scan();
+		wait_for_keypad(ctx, |ctx| ctx.try_take_matrix_scan())
+			.await
+			.ok_or((0x10u8, "Timed out waiting for matrix scan"))
+	}
 }
 
-impl Writeable for CommandInfo {
-	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
-		writer.write_uuid(self.id.0).await?;
-		writer.write_string_u8(self.name).await?;
-		Ok(())
+#[async_trait(?Send)]
+impl<Context: ContextSerialTx + ContextMatrixScan + ContextClock> Command<Context>
+	for GetRawMatrixCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("31a5f443-747d-5696-99f4-6630bea9eecf")),
+			name: "Get Raw Matrix",
+			flags: CommandFlags::READ_ONLY,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		match Self::try_execute(ctx).await {
+			Ok(scan) => {
+				ctx.serial_tx().write_u8(0xFF).await?;
+				scan.write_to(ctx.serial_tx()).await
+			}
+			Err((code, msg)) => {
+				ctx.serial_tx().write_u8(code).await?;
+				Err(msg)
+			}
+		}
+	}
+}
+
+pub struct GetActiveTagsCommand;
+
+impl GetActiveTagsCommand {
+	async fn try_execute<Context: ContextSerialTx + ContextActiveTags + ContextClock>(
+		ctx: &mut Context,
+	) -> Result<ActiveTags, (u8, &'static str)> {
+		ctx.request_active_tags();
+		wait_for_keypad(ctx, |ctx| ctx.try_take_active_tags())
+			.await
+			.ok_or((0x10u8, "Timed out waiting for active tags"))
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialTx + ContextActiveTags + ContextClock> Command<Context>
+	for GetActiveTagsCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("00e3f6e2-f997-5f37-988f-012df01989b1")),
+			name: "Get Active Tags",
+			flags: CommandFlags::READ_ONLY,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		match Self::try_execute(ctx).await {
+			Ok(tags) => {
+				ctx.serial_tx().write_u8(0xFF).await?;
+				tags.write_to(ctx.serial_tx()).await
+			}
+			Err((code, msg)) => {
+				ctx.serial_tx().write_u8(code).await?;
+				Err(msg)
+			}
+		}
+	}
+}
+
+pub struct GetKeyStatsCommand;
+
+impl GetKeyStatsCommand {
+	async fn try_execute<Context: ContextSerialTx + ContextKeyStats + ContextClock>(
+		ctx: &mut Context,
+	) -> Result<KeyStats, (u8, &'static str)> {
+		ctx.request_key_stats();
+		wait_for_keypad(ctx, |ctx| ctx.try_take_key_stats())
+			.await
+			.ok_or((0x10u8, "Timed out waiting for key stats"))
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialTx + ContextKeyStats + ContextClock> Command<Context>
+	for GetKeyStatsCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("c81b8d3d-8316-5cc7-94b6-2509c3c58c29")),
+			name: "Get Key Stats",
+			flags: CommandFlags::READ_ONLY,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		match Self::try_execute(ctx).await {
+			Ok(stats) => {
+				ctx.serial_tx().write_u8(0xFF).await?;
+				stats.write_to(ctx.serial_tx()).await
+			}
+			Err((code, msg)) => {
+				ctx.serial_tx().write_u8(code).await?;
+				Err(msg)
+			}
+		}
+	}
+}
+
+/// Lists the key module ports and whether a module is plugged into each.
+pub struct GetModulesCommand;
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialTx + ContextModules> Command<Context> for GetModulesCommand {
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("5d0f6b1e-93c4-5a27-b8e1-2f6c40d9a713")),
+			name: "Get Modules",
+			flags: CommandFlags::READ_ONLY,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let modules = ctx.modules().get();
+		ctx.serial_tx().write_u8(0xFF).await?;
+		ctx.serial_tx().write_collection_u8(&modules).await
+	}
+}
+
+/// Returns the crash report saved after the last panic, if there is one.
+pub struct GetCrashReportCommand;
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialTx + ContextCrashReportFlash> Command<Context>
+	for GetCrashReportCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("49e918e5-6d04-5665-8bd2-43edecb4007e")),
+			name: "Get Crash Report",
+			flags: CommandFlags::READ_ONLY,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		match load_crash_report_from_flash(&mut ctx.crash_report_flash()).await {
+			Ok(report) => {
+				ctx.serial_tx().write_u8(0xFF).await?;
+				ctx.serial_tx().write_option(report).await
+			}
+			Err(e) => {
+				ctx.serial_tx().write_u8(0x20).await?;
+				Err(e)
+			}
+		}
+	}
+}
+
+pub struct ClearCrashReportCommand;
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialTx + ContextCrashReportFlash> Command<Context>
+	for ClearCrashReportCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("f98952a2-8c11-5b0c-9995-0c1d33d436e4")),
+			name: "Clear Crash Report",
+			flags: CommandFlags::MUTATING | CommandFlags::PRIVILEGED,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		if let Err(e) = clear_crash_report_in_flash(&mut ctx.crash_report_flash()).await {
+			ctx.serial_tx().write_u8(0x20).await?;
+			return Err(e);
+		}
+		ctx.serial_tx().write_u8(0xFF).await
+	}
+}
+
+/// Status for firmware update commands on a build that can't install the update.
+const STATUS_UPDATES_UNSUPPORTED: (u8, &str) = (0x2E, "This build can't install firmware updates");
+
+/// Starts a firmware update: erases the staging partition for an image of the given length
+/// and records the SHA-256 digest it should have. The image is then sent with
+/// `WriteFirmwareChunkCommand`, checked with `VerifyFirmwareUpdateCommand`, marked for install
+/// with `CommitFirmwareUpdateCommand`, and installed by the flasher on the next reboot.
+pub struct BeginFirmwareUpdateCommand;
+
+impl BeginFirmwareUpdateCommand {
+	async fn try_execute<Context: ContextSerialRx + ContextSerialTx + ContextFirmwareUpdateFlash>(
+		ctx: &mut Context,
+	) -> Result<(), (u8, &'static str)>
+	where
+		Context::SerialTx: SerialEventSender,
+	{
+		let length = ctx
+			.serial_rx()
+			.read_u32()
+			.await
+			.ok_or((0x10u8, "Failed to read firmware image length"))? as usize;
+		let mut digest = [0u8; DIGEST_SIZE];
+		ctx.serial_rx()
+			.read_exact(&mut digest)
+			.await
+			.map_err(|_| (0x10u8, "Failed to read firmware image digest"))?;
+
+		if ctx.firmware_image_layout().is_none() {
+			return Err(STATUS_UPDATES_UNSUPPORTED);
+		}
+		if length == 0 || length > max_image_length(&ctx.firmware_update_flash()) {
+			return Err((0x11u8, "Firmware image doesn't fit in the update partition"));
+		}
+
+		let erase_length = update_erase_length::<
+			PartitionedFlashMemory<<Context as ContextFirmwareUpdateFlash>::Flash>,
+		>(length);
+		erase_with_progress(ctx, |c| c.firmware_update_flash(), erase_length)
+			.await
+			.map_err(|e| {
+				error!("Failed to erase firmware update flash storage: {:?}", e);
+				(0x20u8, e)
+			})?;
+		record_update(&mut ctx.firmware_update_flash(), length, &digest)
+			.await
+			.map_err(|e| {
+				error!("Failed to prepare firmware update flash storage: {:?}", e);
+				(0x20u8, e)
+			})
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialRx + ContextSerialTx + ContextFirmwareUpdateFlash> Command<Context>
+	for BeginFirmwareUpdateCommand
+where
+	Context::SerialTx: SerialEventSender,
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("eb4aab8b-3d4d-5f20-ba4b-d7a56e94b2b8")),
+			name: "Begin Firmware Update",
+			flags: CommandFlags::MUTATING | CommandFlags::PRIVILEGED | CommandFlags::LONG_RUNNING,
+			schema: 2,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let result = Self::try_execute(ctx).await;
+		write_result(ctx, result).await
 	}
 }
 
+/// Writes `length` bytes of the firmware image at `offset`, using the same acknowledged chunk
+/// transfer as profile updates.
+pub struct WriteFirmwareChunkCommand;
+
+impl WriteFirmwareChunkCommand {
+	async fn try_execute<Context: ContextSerialRx + ContextSerialTx + ContextFirmwareUpdateFlash>(
+		ctx: &mut Context,
+	) -> Result<(), (u8, &'static str)>
+	where
+		Context::SerialTx: SerialEventSender,
+	{
+		let offset = ctx
+			.serial_rx()
+			.read_u32()
+			.await
+			.ok_or((0x10u8, "Failed to read firmware chunk offset"))? as usize;
+		let length = ctx
+			.serial_rx()
+			.read_u16()
+			.await
+			.ok_or((0x10u8, "Failed to read firmware chunk length"))? as usize;
+
+		check_chunk(&ctx.firmware_update_flash(), offset, length).map_err(|e| (0x11u8, e))?;
+
+		let start = image_offset::<
+			PartitionedFlashMemory<<Context as ContextFirmwareUpdateFlash>::Flash>,
+		>() + offset;
+		copy_serial_to_flash_acked(ctx, |c| c.firmware_update_flash(), start, length, &mut 0)
+			.await
+			.map_err(|e| match e {
+				CopySerialToFlashError::SerialReadError(e) => {
+					error!("Failed to read firmware chunk from serial port: {:?}", e);
+					(0x14u8, "Failed to read firmware chunk from serial port")
+				}
+				CopySerialToFlashError::SerialWriteError(e) => {
+					error!("Failed to acknowledge firmware chunk: {:?}", e);
+					(0x16u8, "Failed to acknowledge firmware chunk")
+				}
+				CopySerialToFlashError::Aborted => {
+					info!("Firmware chunk transfer aborted");
+					(STATUS_ABORTED, TRANSFER_ABORTED)
+				}
+				CopySerialToFlashError::TooManyRetries => {
+					error!("Too many failed firmware chunk transfers");
+					(0x18u8, "Too many failed firmware chunk transfers")
+				}
+				CopySerialToFlashError::FlashWriteError(e) => {
+					error!("Failed to write firmware to flash storage: {:?}", e);
+					(0x28u8, "Failed to write firmware to flash storage")
+				}
+			})
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialRx + ContextSerialTx + ContextFirmwareUpdateFlash> Command<Context>
+	for WriteFirmwareChunkCommand
+where
+	Context::SerialTx: SerialEventSender,
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("f9b6ff56-7c0c-5e1f-a962-7406a143c809")),
+			name: "Write Firmware Chunk",
+			flags: CommandFlags::MUTATING | CommandFlags::PRIVILEGED | CommandFlags::LONG_RUNNING,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let result = Self::try_execute(ctx).await;
+		write_result(ctx, result).await
+	}
+}
+
+/// Checks the staged firmware image against the digest it was started with.
+pub struct VerifyFirmwareUpdateCommand;
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialTx + ContextFirmwareUpdateFlash> Command<Context>
+	for VerifyFirmwareUpdateCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("e222e405-189b-594c-a55d-049904006e77")),
+			name: "Verify Firmware Update",
+			flags: CommandFlags::READ_ONLY,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let result = verify_update(&ctx.firmware_update_flash())
+			.map(|_| ())
+			.map_err(|e| (0x2Cu8, e));
+		write_result(ctx, result).await
+	}
+}
+
+/// Marks the staged firmware image for the flasher to install on the next reboot. The image
+/// is verified again first, and checked for the boot2 stage and vector table this chip
+/// expects, so neither a corrupted image nor one built for something else is ever installed.
+pub struct CommitFirmwareUpdateCommand;
+
+impl CommitFirmwareUpdateCommand {
+	async fn try_execute<Context: ContextFirmwareUpdateFlash>(
+		ctx: &mut Context,
+	) -> Result<(), (u8, &'static str)> {
+		let layout = ctx
+			.firmware_image_layout()
+			.ok_or(STATUS_UPDATES_UNSUPPORTED)?
+			.clone();
+		let flash = ctx.firmware_update_flash();
+		let update = verify_update(&flash).map_err(|e| (0x2Cu8, e))?;
+		let image = staged_image(&flash, &update).map_err(|e| (0x2Cu8, e))?;
+		check_image(image, &layout).map_err(|e| (0x2Du8, e))?;
+		commit_update(&mut ctx.firmware_update_flash(), &layout)
+			.await
+			.map_err(|e| {
+				error!("Failed to commit firmware update: {:?}", e);
+				(0x24u8, e)
+			})
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialTx + ContextFirmwareUpdateFlash> Command<Context>
+	for CommitFirmwareUpdateCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("216c812e-e6f1-5ceb-af26-084b89971d3e")),
+			name: "Commit Firmware Update",
+			flags: CommandFlags::MUTATING | CommandFlags::PRIVILEGED,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let result = Self::try_execute(ctx).await;
+		write_result(ctx, result).await
+	}
+}
+
+/// Locks the device with a PIN. While it's locked, commands that change the profile, settings
+/// or firmware, or reboot the device, fail with `0x40` until `UnlockDeviceCommand` is sent the
+/// same PIN.
+pub struct LockDeviceCommand;
+
+impl LockDeviceCommand {
+	async fn try_execute<Context: ContextSerialRx + ContextLockFlash>(
+		ctx: &mut Context,
+	) -> Result<(), (u8, &'static str)> {
+		let pin: Vec<u8> = ctx
+			.serial_rx()
+			.read_collection_u8()
+			.await
+			.ok_or((0x10u8, "Failed to read lock PIN"))?;
+
+		validate_lock_pin(&pin).map_err(|e| (0x11u8, e))?;
+		save_lock_pin_to_flash(&mut ctx.lock_flash(), &pin)
+			.await
+			.map_err(|e| {
+				error!("Failed to save lock PIN: {:?}", e);
+				(0x20u8, "Failed to save lock PIN")
+			})
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialRx + ContextSerialTx + ContextLockFlash> Command<Context>
+	for LockDeviceCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("dcee43bd-9b01-567c-bd29-a712fdf2c83a")),
+			name: "Lock Device",
+			flags: CommandFlags::MUTATING | CommandFlags::PRIVILEGED,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let result = Self::try_execute(ctx).await;
+		write_result(ctx, result).await
+	}
+}
+
+/// Unlocks the device given the PIN it was locked with. After `MAX_UNLOCK_ATTEMPTS` wrong PINs
+/// it refuses to try again until it's power cycled, which a host can't do by itself. A lock
+/// record that fails its CRC can't be matched, so it fails with `0x43` unless the device was
+/// started in safe mode, where any PIN clears it.
+pub struct UnlockDeviceCommand {
+	failed_attempts: Cell<u8>,
+}
+
+impl UnlockDeviceCommand {
+	pub const fn new() -> Self {
+		Self {
+			failed_attempts: Cell::new(0),
+		}
+	}
+
+	async fn try_execute<Context: ContextSerialRx + ContextLockFlash + ContextSafeMode>(
+		&self,
+		ctx: &mut Context,
+	) -> Result<(), (u8, &'static str)> {
+		let pin: Vec<u8> = ctx
+			.serial_rx()
+			.read_collection_u8()
+			.await
+			.ok_or((0x10u8, "Failed to read lock PIN"))?;
+
+		let expected = match load_lock_pin_from_flash(&ctx.lock_flash()) {
+			Ok(Some(expected)) => expected,
+			Ok(None) => return Ok(()),
+			Err(e) if ctx.started_in_safe_mode() => {
+				warn!("Clearing corrupt lock record from safe mode: {:?}", e);
+				return clear_lock_pin_in_flash(&mut ctx.lock_flash())
+					.await
+					.map_err(|e| {
+						error!("Failed to clear lock PIN: {:?}", e);
+						(0x20u8, "Failed to clear lock PIN")
+					});
+			}
+			Err(e) => {
+				error!("Lock record is corrupt: {:?}", e);
+				return Err((0x43u8, "Lock record is corrupt"));
+			}
+		};
+		if self.failed_attempts.get() >= MAX_UNLOCK_ATTEMPTS {
+			return Err((0x41u8, "Too many failed unlock attempts"));
+		}
+		if !pins_match(expected, &pin) {
+			self.failed_attempts.set(self.failed_attempts.get() + 1);
+			return Err((0x11u8, "Wrong lock PIN"));
+		}
+
+		self.failed_attempts.set(0);
+		clear_lock_pin_in_flash(&mut ctx.lock_flash())
+			.await
+			.map_err(|e| {
+				error!("Failed to clear lock PIN: {:?}", e);
+				(0x20u8, "Failed to clear lock PIN")
+			})
+	}
+}
+
+impl Default for UnlockDeviceCommand {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialRx + ContextSerialTx + ContextLockFlash + ContextSafeMode>
+	Command<Context> for UnlockDeviceCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("927d3416-0e48-5065-8c38-0ee64f8ab953")),
+			name: "Unlock Device",
+			flags: CommandFlags::empty(),
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let result = self.try_execute(ctx).await;
+		write_result(ctx, result).await
+	}
+}
+
+/// Starts authenticating the session: answers with a fresh nonce for the host to sign.
+pub struct GetAuthChallengeCommand;
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialTx + ContextAuth> Command<Context> for GetAuthChallengeCommand {
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("3cc558e5-fd89-571a-9da3-4a8910b6be11")),
+			name: "Get Authentication Challenge",
+			flags: CommandFlags::empty(),
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let nonce = ctx.auth_challenge();
+		ctx.serial_tx().write_u8(0xFF).await?;
+		ctx.serial_tx().write_exact(&nonce).await
+	}
+}
+
+/// Authenticates the session with `HMAC-SHA256(secret, nonce)` for the last challenge.
+pub struct AuthenticateCommand;
+
+impl AuthenticateCommand {
+	async fn try_execute<Context: ContextSerialRx + ContextAuth>(
+		ctx: &mut Context,
+	) -> Result<(), (u8, &'static str)> {
+		let mut tag = [0; AUTH_TAG_SIZE];
+		ctx.serial_rx()
+			.read_exact(&mut tag)
+			.await
+			.map_err(|_| (0x10u8, "Failed to read authentication tag"))?;
+
+		let Some(secret) = load_auth_secret_from_flash(&ctx.auth_flash()) else {
+			return Err((0x12u8, "No authentication secret is set"));
+		};
+		if !ctx.auth_session().respond(secret, &tag) {
+			return Err((0x11u8, "Authentication failed"));
+		}
+		Ok(())
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialRx + ContextSerialTx + ContextAuth> Command<Context>
+	for AuthenticateCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("991bd828-bf3f-5a64-a218-f7f484cd6639")),
+			name: "Authenticate",
+			flags: CommandFlags::empty(),
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let result = Self::try_execute(ctx).await;
+		write_result(ctx, result).await
+	}
+}
+
+/// Sets the secret hosts have to authenticate with before running privileged commands, or
+/// turns authentication off when sent an empty secret. Changing an existing secret is itself
+/// privileged, so only an authenticated host can do it.
+pub struct SetAuthSecretCommand;
+
+impl SetAuthSecretCommand {
+	async fn try_execute<Context: ContextSerialRx + ContextAuth>(
+		ctx: &mut Context,
+	) -> Result<(), (u8, &'static str)> {
+		let secret: Vec<u8> = ctx
+			.serial_rx()
+			.read_collection_u8()
+			.await
+			.ok_or((0x10u8, "Failed to read authentication secret"))?;
+
+		validate_auth_secret(&secret).map_err(|e| (0x11u8, e))?;
+		let result = if secret.is_empty() {
+			clear_auth_secret_in_flash(&mut ctx.auth_flash()).await
+		} else {
+			save_auth_secret_to_flash(&mut ctx.auth_flash(), &secret).await
+		};
+		result.map_err(|e| {
+			error!("Failed to save authentication secret: {:?}", e);
+			(0x20u8, "Failed to save authentication secret")
+		})
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialRx + ContextSerialTx + ContextAuth> Command<Context>
+	for SetAuthSecretCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("0b323e85-bfec-56eb-a49d-dab989c0ed65")),
+			name: "Set Authentication Secret",
+			flags: CommandFlags::MUTATING | CommandFlags::PRIVILEGED,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let result = Self::try_execute(ctx).await;
+		write_result(ctx, result).await
+	}
+}
+
+/// Checks a command's flags against the session before the dispatcher runs it: privileged
+/// commands fail with `0x42` until the session is authorized, and mutating ones with `0x40`
+/// while the device is locked.
+pub fn check_policy<Context: ContextAuth + ContextLockFlash>(
+	ctx: &mut Context,
+	info: &CommandInfo,
+) -> Result<(), (u8, &'static str)> {
+	if info.flags.contains(CommandFlags::PRIVILEGED) && !is_authorized(ctx) {
+		return Err((0x42u8, "Command requires authentication"));
+	}
+	if info.flags.contains(CommandFlags::MUTATING) {
+		check_unlocked(ctx)?;
+	}
+	Ok(())
+}
+
+/// Whether the session may run privileged commands: always, until a secret is set.
+fn is_authorized<Context: ContextAuth>(ctx: &mut Context) -> bool {
+	load_auth_secret_from_flash(&ctx.auth_flash()).is_none()
+		|| ctx.auth_session().is_authenticated()
+}
+
+/// Fails with `0x40` while the device is locked, which it is too when its lock record is
+/// corrupt.
+fn check_unlocked<Context: ContextLockFlash>(ctx: &mut Context) -> Result<(), (u8, &'static str)> {
+	match load_lock_pin_from_flash(&ctx.lock_flash()) {
+		Ok(None) => Ok(()),
+		Ok(Some(_)) | Err(_) => Err((0x40u8, "Device is locked")),
+	}
+}
+
+/// Writes `0xFF` for success or the error code, and passes the error on.
+async fn write_result<Context: ContextSerialTx>(
+	ctx: &mut Context,
+	result: Result<(), (u8, &'static str)>,
+) -> Result<(), &'static str> {
+	let response = match result {
+		Ok(_) => 0xFF,
+		Err((code, _)) => code,
+	};
+
+	ctx.serial_tx().write_u8(response).await.or_else(|e| {
+		error!("Failed to write response to serial port: {:?}", e);
+		Err("Failed to write response")
+	})?;
+
+	match result {
+		Err((STATUS_ABORTED, _)) => Ok(()),
+		result => result.map_err(|(_, msg)| msg),
+	}
+}
+
+pub struct InjectKeyCommand;
+
+impl InjectKeyCommand {
+	async fn try_execute<Context: ContextSerialRx + ContextInjectKeys + ContextClock>(
+		ctx: &mut Context,
+	) -> Result<(), (u8, &'static str)> {
+		const STATE_RELEASED: u8 = 0x00;
+		const STATE_PRESSED: u8 = 0x01;
+
+		let key_id = KeyId::read_from(ctx.serial_rx())
+			.await
+			.map_err(|e| (0x10u8, e))?;
+		let state = ctx
+			.serial_rx()
+			.read_u8()
+			.await
+			.ok_or((0x10u8, "Failed to read key state"))?;
+
+		// stamped now, so the keypad task plays it from when it arrived rather than when it's
+		// taken off the queue
+		let now = ctx.clock().now();
+		let action = match state {
+			STATE_PRESSED => KeyboardAction::pressed(key_id, now),
+			STATE_RELEASED => KeyboardAction::released(key_id, now),
+			_ => return Err((0x11u8, "Invalid key state")),
+		};
+
+		if !ctx.inject_key(action) {
+			return Err((0x12u8, "Injected key queue is full"));
+		}
+
+		Ok(())
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialRx + ContextSerialTx + ContextInjectKeys + ContextClock> Command<Context>
+	for InjectKeyCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("56e43cf0-0770-5aa0-8673-5e6fd9785970")),
+			name: "Inject Key",
+			flags: CommandFlags::MUTATING | CommandFlags::PRIVILEGED,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let result = Self::try_execute(ctx).await;
+
+		let response = match result {
+			Ok(_) => 0xFF,
+			Err((code, _)) => code,
+		};
+
+		ctx.serial_tx().write_u8(response).await?;
+
+		match result {
+			Ok(_) => Ok(()),
+			Err((_, msg)) => Err(msg),
+		}
+	}
+}
+
+pub struct SetVirtualKeysByIdCommand;
+
+impl SetVirtualKeysByIdCommand {
+	async fn try_execute<Context: ContextSerialRx + ContextVirtualKeysById>(
+		ctx: &mut Context,
+	) -> Result<(), (u8, &'static str)> {
+		const STATE_RELEASED: u8 = 0x00;
+		const STATE_PRESSED: u8 = 0x01;
+
+		let count = ctx
+			.serial_rx()
+			.read_u8()
+			.await
+			.ok_or((0x10u8, "Failed to read virtual key count"))?;
+
+		for _ in 0..count {
+			let id = VirtualKeyId::read_from(ctx.serial_rx())
+				.await
+				.map_err(|e| (0x10u8, e))?;
+			let state = ctx
+				.serial_rx()
+				.read_u8()
+				.await
+				.ok_or((0x10u8, "Failed to read virtual key state"))?;
+
+			let action = match state {
+				STATE_PRESSED => KeyState::Pressed,
+				STATE_RELEASED => KeyState::Released,
+				_ => return Err((0x11u8, "Invalid virtual key state")),
+			};
+
+			if !ctx.set_virtual_key(VirtualKeyAction { action, id }) {
+				return Err((0x12u8, "Virtual key queue is full"));
+			}
+		}
+
+		Ok(())
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialRx + ContextSerialTx + ContextVirtualKeysById> Command<Context>
+	for SetVirtualKeysByIdCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("d7a81080-8b5f-53c8-b38a-d7f513b2dac6")),
+			name: "Set Virtual Keys By ID",
+			flags: CommandFlags::empty(),
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let result = Self::try_execute(ctx).await;
+
+		let response = match result {
+			Ok(_) => 0xFF,
+			Err((code, _)) => code,
+		};
+
+		ctx.serial_tx().write_u8(response).await?;
+
+		match result {
+			Ok(_) => Ok(()),
+			Err((_, msg)) => Err(msg),
+		}
+	}
+}
+
+pub struct SetMacroSpeedCommand;
+
+impl SetMacroSpeedCommand {
+	const SPEED_RANGE: core::ops::RangeInclusive<u16> = 10..=1000;
+
+	async fn try_execute<Context: ContextSerialRx + ContextMacroSpeed>(
+		ctx: &mut Context,
+	) -> Result<(), (u8, &'static str)> {
+		let percent = ctx
+			.serial_rx()
+			.read_u16()
+			.await
+			.ok_or((0x10u8, "Failed to read macro speed"))?;
+
+		if !Self::SPEED_RANGE.contains(&percent) {
+			return Err((0x11u8, "Macro speed out of range"));
+		}
+
+		ctx.set_macro_speed(percent);
+
+		Ok(())
+	}
+}
+
+#[async_trait(?Send)]
+impl<Context: ContextSerialRx + ContextSerialTx + ContextMacroSpeed> Command<Context>
+	for SetMacroSpeedCommand
+{
+	fn info(&self) -> CommandInfo {
+		CommandInfo {
+			id: CommandId(uuid!("0c87ff31-581c-58f0-aab2-763e5c2dae4e")),
+			name: "Set Macro Speed",
+			flags: CommandFlags::MUTATING | CommandFlags::PRIVILEGED,
+			schema: 1,
+		}
+	}
+
+	async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+		let result = Self::try_execute(ctx).await;
+
+		let response = match result {
+			Ok(_) => 0xFF,
+			Err((code, _)) => code,
+		};
+
+		ctx.serial_tx().write_u8(response).await?;
+
+		match result {
+			Ok(_) => Ok(()),
+			Err((_, msg)) => Err(msg),
+		}
+	}
+}
+
+pub struct IdentifyResponse<'a> {
+	info: &'a DeviceInfo,
+}
+
+impl Writeable for IdentifyResponse<'_> {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		// v2 added the virtual key count, v3 the command flags, v4 the rest of the capabilities,
+		// v5 the command schema versions, v6 the receive window
+		const VERSION: u32 = 6;
+		writer.write_u32(VERSION).await?;
+		self.info.write_to(writer).await
+	}
+}
+
+bitflags! {
+	/// What a command does, sent to the host in Identify so apps can tell which commands are
+	/// safe to poll and which need confirmation or a progress bar.
+	#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+	pub struct CommandFlags: u8 {
+		/// Only reports on the device; safe to send at any time.
+		const READ_ONLY = 0b00000001;
+		/// Changes the stored profile, settings, lock or firmware, or reboots. Refused by the
+		/// dispatcher while the device is locked.
+		const MUTATING = 0b00000010;
+		/// Once an authentication secret is set, refused by the dispatcher until the session has
+		/// answered an authentication challenge.
+		const PRIVILEGED = 0b00000100;
+		/// Can take seconds, e.g. to erase flash; hosts should allow a longer response timeout.
+		const LONG_RUNNING = 0b00001000;
+	}
+}
+
+#[derive(Clone)]
+pub struct CommandInfo {
+	pub id: CommandId,
+	pub name: &'static str,
+	pub flags: CommandFlags,
+	/// Version of the command's request and response layout, bumped whenever either changes so
+	/// hosts can tell a firmware they don't know how to talk to before sending it anything.
+	pub schema: u16,
+}
+
+impl Writeable for CommandInfo {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_uuid(self.id.0).await?;
+		writer.write_string_u8(self.name).await?;
+		writer.write_u8(self.flags.bits()).await?;
+		writer.write_u16(self.schema).await?;
+		Ok(())
+	}
+}
+
+/// The `[length u16][settings]` record stored in flash, or `None` if there isn't a valid one.
+fn stored_settings_record(data: &[u8]) -> Option<&[u8]> {
+	let len = u16::from_le_bytes([*data.first()?, *data.get(1)?]) as usize;
+	data.get(..SIZEOF_SETTINGS_LENGTH + len)
+}
+
+/// The profile in a `[length u16][profile]` partition, or `None` if the stored length is
+/// invalid.
+fn stored_profile(data: &[u8]) -> Option<&[u8]> {
+	let len = u16::from_le_bytes([*data.first()?, *data.get(1)?]) as usize;
+	data.get(SIZEOF_PROFILE_LENGTH..SIZEOF_PROFILE_LENGTH + len)
+}
+
+/// CRC-16 of the profile stored in flash, or `None` if the stored length is invalid.
+fn stored_profile_hash(data: &[u8]) -> Option<u16> {
+	stored_profile(data).map(crc16)
+}
+
+/// Bytes of the profile hashed between yields.
+const PROFILE_HASH_BLOCK_SIZE: usize = 4096;
+
+/// SHA-256 of the profile stored in flash, or `None` if the stored length is invalid. It's
+/// hashed a block at a time, yielding in between so a large profile doesn't hold up the other
+/// tasks on this core.
+async fn stored_profile_digest(data: &[u8]) -> Option<[u8; 32]> {
+	let profile = stored_profile(data)?;
+	let mut hasher = Sha256::new();
+	for block in profile.chunks(PROFILE_HASH_BLOCK_SIZE) {
+		hasher.update(block);
+		yield_now().await;
+	}
+	Some(hasher.finalize().into())
+}
+
+/// Refuses a buffer of `bytes` the host asked for when it would take the command over the
+/// serial memory budget, before any of it is allocated.
+fn reserve_serial_budget<Context: ContextAllocator + ContextMemoryBudgets>(
+	ctx: &mut Context,
+	bytes: usize,
+) -> Result<(), (u8, &'static str)> {
+	let used = ctx.allocator().window_used();
+	ctx.budgets()
+		.serial
+		.check(used + bytes)
+		.map_err(|_| (0x31u8, "Command exceeds serial memory budget"))
+}
+
+/// Reads a `[length u16][data]` record the host sends to replace one in the stored profile.
+async fn read_replacement_record<
+	Context: ContextSerialRx + ContextAllocator + ContextMemoryBudgets,
+>(
+	ctx: &mut Context,
+) -> Result<Vec<u8>, (u8, &'static str)> {
+	let len = ctx
+		.serial_rx()
+		.read_u16()
+		.await
+		.ok_or((0x11u8, "Failed to read record length"))? as usize;
+
+	reserve_serial_budget(ctx, len)?;
+	let mut record = Vec::new();
+	record
+		.try_reserve_exact(len)
+		.map_err(|_| (0x13u8, "Not enough memory for record"))?;
+	record.resize(len, 0);
+	ctx.serial_rx()
+		.read_exact(&mut record)
+		.await
+		.map_err(|e| (0x11u8, e))?;
+	Ok(record)
+}
+
+/// Swaps the bytes of `record` in the stored profile for `replacement`, writes the result back
+/// to flash and loads it. The new profile is loaded from RAM first, so one that doesn't load or
+/// is over budget leaves the stored profile as it was.
+async fn replace_profile_record<
+	Context: ContextSerialTx
+		+ ContextProfileFlash
+		+ ContextProfileUpload
+		+ ContextUpdateProfile
+		+ ContextAllocator
+		+ ContextMemoryBudgets,
+>(
+	ctx: &mut Context,
+	record: ProfileRecord,
+	replacement: &[u8],
+) -> Result<(), (u8, &'static str)>
+where
+	Context::SerialTx: SerialEventSender,
+{
+	let stored =
+		stored_profile(ctx.profile_flash().as_slice()).ok_or((0x12u8, "No profile stored"))?;
+	let range = find_record(stored, record).await.map_err(|e| (0x12u8, e))?;
+
+	let len = stored.len() - range.len() + replacement.len();
+	if len > max_profile_size(ctx.profile_flash().length()) {
+		return Err((0x14u8, "Profile too large for flash storage"));
+	}
+	let mut data = Vec::new();
+	data.try_reserve_exact(len)
+		.map_err(|_| (0x13u8, "Not enough memory for profile"))?;
+	data.extend_from_slice(&stored[..range.start]);
+	data.extend_from_slice(replacement);
+	data.extend_from_slice(&stored[range.end..]);
+
+	let heap_before = ctx.allocator().current();
+	let profile = KeyboardProfile::read_from(&mut data.as_slice())
+		.await
+		.map_err(|e| {
+			error!("Updated profile failed to load: {:?}", e);
+			(0x2Cu8, e)
+		})?;
+	let heap_used = ctx.allocator().current().saturating_sub(heap_before);
+	ctx.budgets()
+		.profile
+		.check(heap_used)
+		.map_err(|_| (0x30u8, "Profile exceeds memory budget"))?;
+
+	// whatever was left of a cut off upload is about to be erased
+	*ctx.profile_upload() = None;
+
+	erase_with_progress(ctx, |c| c.profile_flash(), SIZEOF_PROFILE_LENGTH + len)
+		.await
+		.map_err(|e| {
+			error!("Failed to erase profile flash storage: {:?}", e);
+			(0x20u8, e)
+		})?;
+	ctx.profile_flash()
+		.write(SIZEOF_PROFILE_LENGTH, &data)
+		.await
+		.map_err(|e| {
+			error!("Failed to write profile to flash storage: {:?}", e);
+			(0x28u8, "Failed to write profile to flash storage")
+		})?;
+	// the length goes in last, as for an upload
+	ctx.profile_flash()
+		.write(0, &(len as u16).to_le_bytes())
+		.await
+		.map_err(|e| {
+			error!("Failed to write profile length to flash storage: {:?}", e);
+			(0x24u8, "Failed to write profile length to flash storage")
+		})?;
+
+	ctx.profile_signal().update_profile(profile);
+
+	Ok(())
+}
+
 struct StatusResponse {
 	pub now: u64,
 	pub allocator_current: usize,
 	pub allocator_max: usize,
-	// WISH: pub mouse_enabled: bool,
 	pub errors: Vec<Error>,
+	// fields below were added later; they follow the errors so older hosts still parse the rest
+	pub mouse_enabled: bool,
+	pub uptime_ms: u64,
+	pub profile_hash: Option<u16>,
+	pub keypad: Option<KeypadStatus>,
+	pub usb: UsbCounters,
+	pub heap: HeapFragmentation,
+	pub budget_violations: BudgetViolations,
+	pub battery: Option<BatteryLevel>,
+	/// Real time at `now`, for the host to turn device timestamps into; None until synced.
+	pub unix_ms: Option<u64>,
 }
 
 impl Writeable for StatusResponse {
@@ -474,8 +2352,31 @@ impl Writeable for StatusResponse {
 		writer.write_u64(self.now).await?;
 		writer.write_u32(self.allocator_current as u32).await?;
 		writer.write_u32(self.allocator_max as u32).await?;
-		// WISH: writer.write_bool(self.mouse_enabled).await?;
 		writer.write_collection_u8(&self.errors).await?;
+		writer.write_bool(self.mouse_enabled).await?;
+		writer.write_u64(self.uptime_ms).await?;
+		match self.profile_hash {
+			Some(hash) => {
+				writer.write_bool(true).await?;
+				writer.write_u16(hash).await?;
+			}
+			None => writer.write_bool(false).await?,
+		}
+		writer.write_option(self.keypad.clone()).await?;
+		self.usb.write_to(writer).await?;
+		writer.write_u32(self.heap.free_blocks as u32).await?;
+		writer
+			.write_u32(self.heap.largest_free_block as u32)
+			.await?;
+		self.budget_violations.write_to(writer).await?;
+		writer.write_option(self.battery).await?;
+		match self.unix_ms {
+			Some(unix_ms) => {
+				writer.write_bool(true).await?;
+				writer.write_u64(unix_ms).await?;
+			}
+			None => writer.write_bool(false).await?,
+		}
 		Ok(())
 	}
 }
@@ -489,7 +2390,10 @@ async fn copy_serial_to_flash<
 	get_flash: GetFlash,
 	offset: usize,
 	length: usize,
-) -> Result<(), CopySerialToFlashError> {
+) -> Result<(), CopySerialToFlashError>
+where
+	Context::SerialTx: SerialEventSender,
+{
 	let mut total_read = 0;
 	let mut buf = [0; CHUNK_SIZE];
 	while total_read < length {
@@ -499,34 +2403,271 @@ async fn copy_serial_to_flash<
 		ctx.serial_rx()
 			.read_exact(chunk)
 			.await
-			.map_err(|e| CopySerialToFlashError::SerialReadError(e))?;
+			.map_err(CopySerialToFlashError::from_read_error)?;
 
 		debug!("Writing chunk: {} bytes", size);
 		let mut flash = get_flash(ctx);
 		flash
 			.write(offset + total_read, chunk)
+			.await
 			.map_err(|e| CopySerialToFlashError::FlashWriteError(e))?;
 		total_read += size;
+		return_credit(ctx).await;
+	}
+
+	Ok(())
+}
+
+const CHUNK_ACK_OK: u8 = 0xFF;
+const CHUNK_ACK_SEQUENCE_MISMATCH: u8 = 0x01;
+const CHUNK_ACK_CHECKSUM_MISMATCH: u8 = 0x02;
+const CHUNK_ACK_FLASH_WRITE_FAILED: u8 = 0x03;
+const MAX_CHUNK_RETRIES: u8 = 5;
+
+/// Copies `length` bytes from serial to flash, acknowledging every chunk.
+///
+/// Each chunk is sent by the host as `[seq: u16][data][crc16(data): u16]`, where `data`
+/// is `CHUNK_SIZE` bytes (or whatever remains for the last chunk). The device answers
+/// each chunk with `[seq: u16][status: u8]`, where `seq` is the next chunk it expects.
+/// On any non-OK status the host resends starting from the returned sequence number.
+///
+/// `expected_seq` is the chunk to start from, and is left at the first chunk not yet written,
+/// even if the copy fails.
+async fn copy_serial_to_flash_acked<
+	Context: ContextSerialRx + ContextSerialTx,
+	Flash: BlockFlash,
+	GetFlash: Fn(&mut Context) -> PartitionedFlashMemory<Flash>,
+>(
+	ctx: &mut Context,
+	get_flash: GetFlash,
+	offset: usize,
+	length: usize,
+	expected_seq: &mut usize,
+) -> Result<(), CopySerialToFlashError>
+where
+	Context::SerialTx: SerialEventSender,
+{
+	let num_chunks = length.div_ceil(CHUNK_SIZE);
+	let mut retries = 0u8;
+	let mut buf = [0; CHUNK_SIZE];
+	let mut progress = ProgressReporter::new(ProgressStage::Writing, length);
+	progress
+		.report(ctx.serial_tx(), (*expected_seq * CHUNK_SIZE).min(length))
+		.await;
+
+	while *expected_seq < num_chunks {
+		let seq = read_chunk_u16(ctx).await? as usize;
+
+		if seq >= num_chunks {
+			return Err(CopySerialToFlashError::SerialReadError(
+				"Chunk sequence number out of range",
+			));
+		}
+
+		let chunk_offset = seq * CHUNK_SIZE;
+		let size = (length - chunk_offset).min(CHUNK_SIZE);
+		let chunk = &mut buf[..size];
+		ctx.serial_rx()
+			.read_exact(chunk)
+			.await
+			.map_err(CopySerialToFlashError::from_read_error)?;
+		let checksum = read_chunk_u16(ctx).await?;
+		return_credit(ctx).await;
+
+		let status = if seq < *expected_seq {
+			// already written; the host probably missed our ack
+			CHUNK_ACK_OK
+		} else if seq > *expected_seq {
+			CHUNK_ACK_SEQUENCE_MISMATCH
+		} else if crc16(chunk) != checksum {
+			CHUNK_ACK_CHECKSUM_MISMATCH
+		} else {
+			debug!("Writing chunk {}: {} bytes", seq, size);
+			let mut flash = get_flash(ctx);
+			match flash.write(offset + chunk_offset, chunk).await {
+				Ok(_) => {
+					*expected_seq += 1;
+					CHUNK_ACK_OK
+				}
+				Err(e) => {
+					error!("Failed to write chunk {}: {:?}", seq, e);
+					CHUNK_ACK_FLASH_WRITE_FAILED
+				}
+			}
+		};
+
+		ctx.serial_tx()
+			.write_u16(*expected_seq as u16)
+			.await
+			.map_err(CopySerialToFlashError::SerialWriteError)?;
+		ctx.serial_tx()
+			.write_u8(status)
+			.await
+			.map_err(CopySerialToFlashError::SerialWriteError)?;
+
+		match status {
+			CHUNK_ACK_OK => {
+				retries = 0;
+				let written = (*expected_seq * CHUNK_SIZE).min(length);
+				progress.report(ctx.serial_tx(), written).await;
+			}
+			CHUNK_ACK_FLASH_WRITE_FAILED => {
+				// flash can't be rewritten without an erase, so retrying won't help
+				return Err(CopySerialToFlashError::FlashWriteError(
+					"Failed to write chunk to flash",
+				));
+			}
+			_ => {
+				retries += 1;
+				if retries > MAX_CHUNK_RETRIES {
+					return Err(CopySerialToFlashError::TooManyRetries);
+				}
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// Sends a `Credit` event for whatever has been read since the last one, so a host sending
+/// a lot of data can keep going without overrunning the device's buffers.
+pub(crate) async fn return_credit<Context: ContextSerialRx + ContextSerialTx>(ctx: &mut Context)
+where
+	Context::SerialTx: SerialEventSender,
+{
+	let bytes = ctx.serial_rx().take_credit();
+	if bytes == 0 {
+		return;
+	}
+
+	// the host gets its whole window back at the next Identify, so a lost credit isn't fatal
+	if let Err(e) = send_event(ctx.serial_tx(), &Credit { bytes }).await {
+		warn!("Failed to send credit: {}", e);
+	}
+}
+
+/// Sends `Progress` events for one stage of a long-running command, at most one per
+/// `PROGRESS_STEP_PERCENT` so they don't crowd out the command's own traffic.
+struct ProgressReporter {
+	stage: ProgressStage,
+	total: usize,
+	reported: Option<u8>,
+}
+
+const PROGRESS_STEP_PERCENT: u8 = 5;
+
+impl ProgressReporter {
+	fn new(stage: ProgressStage, total: usize) -> Self {
+		Self {
+			stage,
+			total,
+			reported: None,
+		}
+	}
+
+	async fn report<Tx: SerialEventSender>(&mut self, tx: &mut Tx, done: usize) {
+		let progress = Progress {
+			stage: self.stage,
+			done: done as u32,
+			total: self.total as u32,
+		};
+		let percent = progress.percent();
+		if let Some(reported) = self.reported {
+			if percent <= reported || (percent < reported + PROGRESS_STEP_PERCENT && percent < 100)
+			{
+				return;
+			}
+		}
+
+		// progress is only a courtesy to the host; the command carries on if it can't be sent
+		if let Err(e) = send_event(tx, &progress).await {
+			warn!("Failed to send progress: {}", e);
+		}
+		self.reported = Some(percent);
 	}
+}
 
+/// Erases the blocks covering the first `length` bytes of a partition one at a time, sending
+/// `Progress` events as it goes and yielding in between so other tasks keep running.
+async fn erase_with_progress<
+	Context: ContextSerialTx,
+	Flash: BlockFlash,
+	GetFlash: Fn(&mut Context) -> PartitionedFlashMemory<Flash>,
+>(
+	ctx: &mut Context,
+	get_flash: GetFlash,
+	length: usize,
+) -> Result<(), &'static str>
+where
+	Context::SerialTx: SerialEventSender,
+{
+	let block_size = Flash::ERASE_BLOCK_SIZE;
+	let erase_length = length.div_ceil(block_size) * block_size;
+	let mut progress = ProgressReporter::new(ProgressStage::Erasing, erase_length);
+	progress.report(ctx.serial_tx(), 0).await;
+
+	let mut erased = 0;
+	while erased < erase_length {
+		get_flash(ctx).erase(erased, block_size).await?;
+		erased += block_size;
+		progress.report(ctx.serial_tx(), erased).await;
+		yield_now().await;
+	}
 	Ok(())
 }
 
+// unlike `read_u16`, keeps the read error so an abort can be told apart from a bad chunk
+async fn read_chunk_u16<Context: ContextSerialRx>(
+	ctx: &mut Context,
+) -> Result<u16, CopySerialToFlashError> {
+	let mut buf = [0u8; 2];
+	ctx.serial_rx()
+		.read_exact(&mut buf)
+		.await
+		.map_err(CopySerialToFlashError::from_read_error)?;
+	Ok(u16::from_le_bytes(buf))
+}
+
 enum CopySerialToFlashError {
 	SerialReadError(&'static str),
+	SerialWriteError(&'static str),
 	FlashWriteError(&'static str),
+	TooManyRetries,
+	/// The host sent an abort frame.
+	Aborted,
+}
+
+impl CopySerialToFlashError {
+	fn from_read_error(e: &'static str) -> Self {
+		if e == TRANSFER_ABORTED {
+			CopySerialToFlashError::Aborted
+		} else {
+			CopySerialToFlashError::SerialReadError(e)
+		}
+	}
 }
 
+/// Answered to an upload the host aborted. It isn't an error, so nothing is logged.
+const STATUS_ABORTED: u8 = 0x1A;
+
 #[cfg(test)]
 mod tests {
+	use std::collections::VecDeque;
+
+	use crate::auth::{AuthSession, NONCE_SIZE, NonceSource};
+	use crate::event::{EVENT_KIND_CREDIT, EVENT_KIND_PROGRESS};
+	use crate::serial::{SerialDrain, SerialSession};
+	use crate::sim::SimClock;
 	use crate::storage::FlashPartition;
-	use crate::test::test::*;
+	use crate::testing::*;
+	use uuid::Uuid;
 
 	use super::*;
 
 	struct FakeContext {
 		flash: FakeFlashMemory,
 		partition: FlashPartition<FakeFlashMemory>,
+		serial_rx: FakeSerialRx,
 		serial_tx: FakeContextSerialTx,
 	}
 
@@ -549,14 +2690,11 @@ mod tests {
 		}
 	}
 
-	struct FakeSerialTx {
-		written: Vec<u8>,
-	}
+	impl ContextSerialRx for FakeContext {
+		type SerialRx = FakeSerialRx;
 
-	impl WriteAsync for FakeSerialTx {
-		async fn write_exact(&mut self, _data: &[u8]) -> Result<(), &'static str> {
-			self.written.extend_from_slice(_data);
-			Ok(())
+		fn serial_rx(&mut self) -> &mut Self::SerialRx {
+			&mut self.serial_rx
 		}
 	}
 
@@ -568,10 +2706,9 @@ mod tests {
 		let mut ctx = FakeContext {
 			flash: FakeFlashMemory::new(Some(cranky_profile_data), None),
 			partition: FlashPartition::new(0, cranky_profile_data.len()),
+			serial_rx: FakeSerialRx::new(VecDeque::new()),
 			serial_tx: FakeContextSerialTx {
-				serial_tx: FakeSerialTx {
-					written: Vec::new(),
-				},
+				serial_tx: FakeSerialTx::new(),
 			},
 		};
 
@@ -595,4 +2732,550 @@ mod tests {
 		let length = u16::from_le_bytes([length_bytes[0], length_bytes[1]]) as usize;
 		assert_eq!(length, cranky_profile_data.len() - 2);
 	}
+
+	fn new_chunk_context(input: Vec<u8>, flash_size: usize) -> FakeContext {
+		let write_buf = Box::leak(alloc::vec![0u8; flash_size].into_boxed_slice());
+		FakeContext {
+			flash: FakeFlashMemory::new(None, Some(write_buf)),
+			partition: FlashPartition::new(0, flash_size),
+			serial_rx: FakeSerialRx::new(input),
+			serial_tx: FakeContextSerialTx {
+				serial_tx: FakeSerialTx::new(),
+			},
+		}
+	}
+
+	fn push_chunk(input: &mut Vec<u8>, seq: u16, data: &[u8], checksum: u16) {
+		input.extend_from_slice(&seq.to_le_bytes());
+		input.extend_from_slice(data);
+		input.extend_from_slice(&checksum.to_le_bytes());
+	}
+
+	#[tokio::test]
+	async fn acked_copy_retries_corrupted_chunk() {
+		let chunk0 = [0x11u8; CHUNK_SIZE];
+		let chunk1 = [0x22u8; 10];
+		let length = chunk0.len() + chunk1.len();
+
+		let mut input = Vec::new();
+		push_chunk(&mut input, 0, &chunk0, crc16(&chunk0) ^ 0xFFFF);
+		push_chunk(&mut input, 0, &chunk0, crc16(&chunk0));
+		push_chunk(&mut input, 1, &chunk1, crc16(&chunk1));
+
+		let mut ctx = new_chunk_context(input, length + 2);
+		let result =
+			copy_serial_to_flash_acked(&mut ctx, |c| c.profile_flash(), 2, length, &mut 0).await;
+		assert!(result.is_ok());
+
+		assert_eq!(
+			ctx.serial_tx.serial_tx.written,
+			[
+				0x00,
+				0x00,
+				CHUNK_ACK_CHECKSUM_MISMATCH,
+				0x01,
+				0x00,
+				CHUNK_ACK_OK,
+				0x02,
+				0x00,
+				CHUNK_ACK_OK
+			]
+		);
+		assert_eq!(&ctx.flash.write_buf[2..2 + CHUNK_SIZE], &chunk0);
+		assert_eq!(&ctx.flash.write_buf[2 + CHUNK_SIZE..], &chunk1);
+	}
+
+	#[tokio::test]
+	async fn acked_copy_rejects_out_of_order_chunk() {
+		let chunk0 = [0x11u8; CHUNK_SIZE];
+		let chunk1 = [0x22u8; 10];
+		let length = chunk0.len() + chunk1.len();
+
+		let mut input = Vec::new();
+		push_chunk(&mut input, 1, &chunk1, crc16(&chunk1));
+		push_chunk(&mut input, 0, &chunk0, crc16(&chunk0));
+		push_chunk(&mut input, 1, &chunk1, crc16(&chunk1));
+
+		let mut ctx = new_chunk_context(input, length + 2);
+		let result =
+			copy_serial_to_flash_acked(&mut ctx, |c| c.profile_flash(), 2, length, &mut 0).await;
+		assert!(result.is_ok());
+
+		assert_eq!(
+			&ctx.serial_tx.serial_tx.written[..3],
+			&[0x00, 0x00, CHUNK_ACK_SEQUENCE_MISMATCH]
+		);
+	}
+
+	#[tokio::test]
+	async fn acked_copy_resumes_from_the_first_unwritten_chunk() {
+		let chunk0 = [0x11u8; CHUNK_SIZE];
+		let chunk1 = [0x22u8; 10];
+		let length = chunk0.len() + chunk1.len();
+
+		// the connection drops after the first chunk
+		let mut input = Vec::new();
+		push_chunk(&mut input, 0, &chunk0, crc16(&chunk0));
+		let mut ctx = new_chunk_context(input, length + 2);
+		let mut next_chunk = 0;
+		let result =
+			copy_serial_to_flash_acked(&mut ctx, |c| c.profile_flash(), 2, length, &mut next_chunk)
+				.await;
+		assert!(result.is_err());
+		assert_eq!(next_chunk, 1);
+		let upload = ProfileUpload { length, next_chunk };
+		assert_eq!(upload.committed(), CHUNK_SIZE);
+
+		let mut input = Vec::new();
+		push_chunk(&mut input, 1, &chunk1, crc16(&chunk1));
+		ctx.serial_rx.data.extend(input);
+		let result =
+			copy_serial_to_flash_acked(&mut ctx, |c| c.profile_flash(), 2, length, &mut next_chunk)
+				.await;
+		assert!(result.is_ok());
+		assert_eq!(next_chunk, 2);
+		assert_eq!(&ctx.flash.write_buf[2..2 + CHUNK_SIZE], &chunk0);
+		assert_eq!(&ctx.flash.write_buf[2 + CHUNK_SIZE..], &chunk1);
+	}
+
+	#[tokio::test]
+	async fn acked_copy_reports_progress() {
+		let chunk0 = [0x11u8; CHUNK_SIZE];
+		let chunk1 = [0x22u8; 10];
+		let length = chunk0.len() + chunk1.len();
+
+		let mut input = Vec::new();
+		push_chunk(&mut input, 0, &chunk0, crc16(&chunk0));
+		push_chunk(&mut input, 0, &chunk0, crc16(&chunk0) ^ 0xFFFF);
+		push_chunk(&mut input, 1, &chunk1, crc16(&chunk1));
+
+		let mut ctx = new_chunk_context(input, length + 2);
+		let result =
+			copy_serial_to_flash_acked(&mut ctx, |c| c.profile_flash(), 2, length, &mut 0).await;
+		assert!(result.is_ok());
+
+		// a resent chunk doesn't repeat the progress it already reported
+		let events: Vec<&Vec<u8>> = ctx
+			.serial_tx
+			.serial_tx
+			.events
+			.iter()
+			.filter(|event| event[0] != EVENT_KIND_CREDIT)
+			.collect();
+		let percents: Vec<u8> = events.iter().map(|event| event[2]).collect();
+		let expected = (CHUNK_SIZE * 100 / length) as u8;
+		assert_eq!(percents, [0, expected, 100]);
+		assert!(
+			events
+				.iter()
+				.all(|event| event[..2] == [EVENT_KIND_PROGRESS, ProgressStage::Writing as u8])
+		);
+	}
+
+	#[tokio::test]
+	async fn acked_copy_returns_credit_for_each_chunk() {
+		let chunk0 = [0x11u8; CHUNK_SIZE];
+		let chunk1 = [0x22u8; 10];
+		let length = chunk0.len() + chunk1.len();
+
+		let mut input = Vec::new();
+		push_chunk(&mut input, 0, &chunk0, crc16(&chunk0));
+		push_chunk(&mut input, 1, &chunk1, crc16(&chunk1));
+
+		let mut ctx = new_chunk_context(input, length + 2);
+		let result =
+			copy_serial_to_flash_acked(&mut ctx, |c| c.profile_flash(), 2, length, &mut 0).await;
+		assert!(result.is_ok());
+
+		let credits: Vec<u16> = ctx
+			.serial_tx
+			.serial_tx
+			.events
+			.iter()
+			.filter(|event| event[0] == EVENT_KIND_CREDIT)
+			.map(|event| u16::from_le_bytes([event[1], event[2]]))
+			.collect();
+		assert_eq!(credits, [2 + CHUNK_SIZE as u16 + 2, 2 + 10 + 2]);
+	}
+
+	#[tokio::test]
+	async fn erase_reports_progress_per_block() {
+		let mut ctx = new_chunk_context(Vec::new(), 4096);
+		erase_with_progress(&mut ctx, |c| c.profile_flash(), 4096)
+			.await
+			.unwrap();
+
+		let events = &ctx.serial_tx.serial_tx.events;
+		assert_eq!(events.first().unwrap()[2], 0);
+		assert_eq!(events.last().unwrap()[2], 100);
+		assert_eq!(events.last().unwrap()[3..7], 4096u32.to_le_bytes());
+		assert!(ctx.serial_tx.serial_tx.written.is_empty());
+	}
+
+	#[test]
+	fn stored_profile_hash_covers_length_prefixed_data() {
+		let data = [3, 0, b'a', b'b', b'c', 0xFF, 0xFF];
+		assert_eq!(stored_profile_hash(&data), Some(crc16(b"abc")));
+
+		// erased flash reads as a length that runs past the partition
+		assert_eq!(stored_profile_hash(&[0xFF; 8]), None);
+	}
+
+	#[tokio::test]
+	async fn stored_profile_digest_is_sha256_of_the_profile() {
+		let data = [3, 0, b'a', b'b', b'c', 0xFF, 0xFF];
+		let expected = [
+			0xBA, 0x78, 0x16, 0xBF, 0x8F, 0x01, 0xCF, 0xEA, 0x41, 0x41, 0x40, 0xDE, 0x5D, 0xAE,
+			0x22, 0x23, 0xB0, 0x03, 0x61, 0xA3, 0x96, 0x17, 0x7A, 0x9C, 0xB4, 0x10, 0xFF, 0x61,
+			0xF2, 0x00, 0x15, 0xAD,
+		];
+		assert_eq!(stored_profile_digest(&data).await, Some(expected));
+
+		assert_eq!(stored_profile_digest(&[0xFF; 8]).await, None);
+	}
+
+	struct LockContext {
+		flash: FakeNorFlash,
+		partition: FlashPartition<FakeNorFlash>,
+		auth_partition: FlashPartition<FakeNorFlash>,
+		auth_session: AuthSession,
+		safe_mode: bool,
+		serial_rx: FakeSerialRx,
+		serial_tx: FakeSerialTx,
+	}
+
+	impl LockContext {
+		fn new() -> Self {
+			Self {
+				flash: FakeNorFlash::new(128),
+				partition: FlashPartition::new(0, 64),
+				auth_partition: FlashPartition::new(64, 64),
+				auth_session: AuthSession::new(),
+				safe_mode: false,
+				serial_rx: FakeSerialRx::new(VecDeque::new()),
+				serial_tx: FakeSerialTx::new(),
+			}
+		}
+
+		fn send_pin(&mut self, pin: &[u8]) {
+			self.serial_rx.data.push_back(pin.len() as u8);
+			self.serial_rx.data.extend(pin);
+		}
+	}
+
+	impl ContextLockFlash for LockContext {
+		type Flash = FakeNorFlash;
+		fn lock_flash(&mut self) -> PartitionedFlashMemory<Self::Flash> {
+			PartitionedFlashMemory::new(&mut self.flash, &self.partition)
+		}
+	}
+
+	impl ContextSafeMode for LockContext {
+		fn started_in_safe_mode(&self) -> bool {
+			self.safe_mode
+		}
+	}
+
+	struct ZeroNonceSource;
+
+	impl NonceSource for ZeroNonceSource {
+		fn fill_nonce(&mut self, nonce: &mut [u8]) {
+			nonce.fill(0);
+		}
+	}
+
+	impl ContextAuth for LockContext {
+		type Flash = FakeNorFlash;
+		fn auth_flash(&mut self) -> PartitionedFlashMemory<Self::Flash> {
+			PartitionedFlashMemory::new(&mut self.flash, &self.auth_partition)
+		}
+
+		fn auth_session(&mut self) -> &mut AuthSession {
+			&mut self.auth_session
+		}
+
+		fn auth_challenge(&mut self) -> [u8; NONCE_SIZE] {
+			self.auth_session.challenge(&mut ZeroNonceSource)
+		}
+	}
+
+	impl ContextSerialTx for LockContext {
+		type SerialTx = FakeSerialTx;
+
+		fn serial_tx(&mut self) -> &mut Self::SerialTx {
+			&mut self.serial_tx
+		}
+	}
+
+	impl ContextSerialRx for LockContext {
+		type SerialRx = FakeSerialRx;
+
+		fn serial_rx(&mut self) -> &mut Self::SerialRx {
+			&mut self.serial_rx
+		}
+	}
+
+	#[tokio::test]
+	async fn device_unlocks_with_the_pin_it_was_locked_with() {
+		let mut ctx = LockContext::new();
+		let unlock = UnlockDeviceCommand::new();
+
+		ctx.send_pin(b"1234");
+		LockDeviceCommand.execute(&mut ctx).await.unwrap();
+		assert_eq!(check_unlocked(&mut ctx), Err((0x40, "Device is locked")));
+
+		ctx.send_pin(b"9999");
+		assert!(unlock.execute(&mut ctx).await.is_err());
+		assert_eq!(check_unlocked(&mut ctx), Err((0x40, "Device is locked")));
+
+		ctx.send_pin(b"1234");
+		unlock.execute(&mut ctx).await.unwrap();
+		assert_eq!(check_unlocked(&mut ctx), Ok(()));
+		assert_eq!(ctx.serial_tx.written, [0xFF, 0x11, 0xFF]);
+	}
+
+	#[tokio::test]
+	async fn corrupt_lock_record_stays_locked_until_cleared_from_safe_mode() {
+		let mut ctx = LockContext::new();
+		let unlock = UnlockDeviceCommand::new();
+		ctx.send_pin(b"1234");
+		LockDeviceCommand.execute(&mut ctx).await.unwrap();
+		ctx.flash.data[5] = 0;
+		assert_eq!(check_unlocked(&mut ctx), Err((0x40, "Device is locked")));
+
+		ctx.send_pin(b"1234");
+		assert!(unlock.execute(&mut ctx).await.is_err());
+		assert_eq!(check_unlocked(&mut ctx), Err((0x40, "Device is locked")));
+
+		ctx.safe_mode = true;
+		ctx.send_pin(b"0000");
+		unlock.execute(&mut ctx).await.unwrap();
+		assert_eq!(check_unlocked(&mut ctx), Ok(()));
+		assert_eq!(ctx.serial_tx.written, [0xFF, 0x43, 0xFF]);
+	}
+
+	#[tokio::test]
+	async fn unlock_gives_up_after_too_many_wrong_pins() {
+		let mut ctx = LockContext::new();
+		let unlock = UnlockDeviceCommand::new();
+		ctx.send_pin(b"1234");
+		LockDeviceCommand.execute(&mut ctx).await.unwrap();
+
+		for _ in 0..MAX_UNLOCK_ATTEMPTS {
+			ctx.send_pin(b"0000");
+			assert!(unlock.execute(&mut ctx).await.is_err());
+		}
+		ctx.serial_tx.written.clear();
+
+		ctx.send_pin(b"1234");
+		assert!(unlock.execute(&mut ctx).await.is_err());
+		assert_eq!(ctx.serial_tx.written, [0x41]);
+		assert_eq!(check_unlocked(&mut ctx), Err((0x40, "Device is locked")));
+	}
+	#[tokio::test]
+	async fn dispatcher_enforces_command_flags() {
+		let mut ctx = LockContext::new();
+		let lock = Command::<LockContext>::info(&LockDeviceCommand);
+		let unlock = Command::<LockContext>::info(&UnlockDeviceCommand::new());
+		assert_eq!(check_policy(&mut ctx, &lock), Ok(()));
+
+		ctx.send_pin(b"1234");
+		LockDeviceCommand.execute(&mut ctx).await.unwrap();
+		assert_eq!(
+			check_policy(&mut ctx, &lock),
+			Err((0x40, "Device is locked"))
+		);
+		assert_eq!(check_policy(&mut ctx, &unlock), Ok(()));
+
+		clear_lock_pin_in_flash(&mut ctx.lock_flash())
+			.await
+			.unwrap();
+		save_auth_secret_to_flash(&mut ctx.auth_flash(), b"secret")
+			.await
+			.unwrap();
+		assert_eq!(
+			check_policy(&mut ctx, &lock),
+			Err((0x42, "Command requires authentication"))
+		);
+		assert_eq!(check_policy(&mut ctx, &unlock), Ok(()));
+	}
+
+	#[tokio::test]
+	async fn command_info_ends_with_the_schema_version() {
+		let mut info = Command::<LockContext>::info(&LockDeviceCommand);
+		info.schema = 0x0102;
+
+		let mut data = Vec::new();
+		info.write_to(&mut data).await.unwrap();
+
+		let flags = 16 + 1 + info.name.len();
+		assert_eq!(data[flags], info.flags.bits());
+		assert_eq!(data[flags + 1..], [0x02, 0x01]);
+	}
+
+	/// Serial input that reads as aborted by the host once its data runs out.
+	struct AbortingSerialRx {
+		data: VecDeque<u8>,
+	}
+
+	impl ReadAsync for AbortingSerialRx {
+		async fn read_exact(&mut self, to_fill: &mut [u8]) -> Result<(), &'static str> {
+			if to_fill.len() > self.data.len() {
+				return Err(TRANSFER_ABORTED);
+			}
+			for byte in to_fill.iter_mut() {
+				*byte = self.data.pop_front().unwrap();
+			}
+			Ok(())
+		}
+	}
+
+	impl SerialDrain for AbortingSerialRx {
+		fn drop_buffered(&mut self) {}
+	}
+
+	impl SerialCredit for AbortingSerialRx {
+		fn take_credit(&mut self) -> u16 {
+			0
+		}
+	}
+
+	impl SerialSession for AbortingSerialRx {}
+
+	struct SettingsContext {
+		flash: FakeNorFlash,
+		partition: FlashPartition<FakeNorFlash>,
+		serial_rx: AbortingSerialRx,
+		serial_tx: FakeSerialTx,
+	}
+
+	impl ContextSettingsFlash for SettingsContext {
+		type Flash = FakeNorFlash;
+		fn settings_flash(&mut self) -> PartitionedFlashMemory<Self::Flash> {
+			PartitionedFlashMemory::new(&mut self.flash, &self.partition)
+		}
+	}
+
+	impl ContextSerialTx for SettingsContext {
+		type SerialTx = FakeSerialTx;
+
+		fn serial_tx(&mut self) -> &mut Self::SerialTx {
+			&mut self.serial_tx
+		}
+	}
+
+	impl ContextSerialRx for SettingsContext {
+		type SerialRx = AbortingSerialRx;
+
+		fn serial_rx(&mut self) -> &mut Self::SerialRx {
+			&mut self.serial_rx
+		}
+	}
+
+	#[tokio::test]
+	async fn aborted_settings_upload_restores_the_old_settings() {
+		let mut flash = FakeNorFlash::new(64);
+		flash.data[..5].copy_from_slice(&[3, 0, 0x0A, 0x0B, 0x0C]);
+
+		// a length of 10, then only 4 bytes before the host aborts
+		let mut ctx = SettingsContext {
+			flash,
+			partition: FlashPartition::new(0, 64),
+			serial_rx: AbortingSerialRx {
+				data: VecDeque::from([10, 0, 1, 2, 3, 4]),
+			},
+			serial_tx: FakeSerialTx::new(),
+		};
+
+		assert_eq!(UpdateSettingsCommand.execute(&mut ctx).await, Ok(()));
+		assert_eq!(ctx.serial_tx.written, [STATUS_ABORTED]);
+		assert_eq!(ctx.flash.data[..5], [3, 0, 0x0A, 0x0B, 0x0C]);
+		assert!(ctx.flash.data[5..].iter().all(|byte| *byte == 0xFF));
+	}
+
+	const INJECTED_KEY_UUID: Uuid = uuid!("d1472104-1c37-560f-a39b-1737983559fc");
+	const INJECTED_KEY_ID: KeyId = KeyId::new(INJECTED_KEY_UUID);
+
+	struct InjectContext {
+		serial_rx: FakeSerialRx,
+		serial_tx: FakeSerialTx,
+		clock: SimClock,
+		injected: Vec<KeyboardAction>,
+		queue_size: usize,
+	}
+
+	impl InjectContext {
+		fn new(state: u8) -> Self {
+			let mut input = Vec::from(INJECTED_KEY_UUID.to_bytes_le());
+			input.push(state);
+			Self {
+				serial_rx: FakeSerialRx::new(input),
+				serial_tx: FakeSerialTx::new(),
+				clock: SimClock::new(),
+				injected: Vec::new(),
+				queue_size: 1,
+			}
+		}
+	}
+
+	impl ContextInjectKeys for InjectContext {
+		fn inject_key(&mut self, action: KeyboardAction) -> bool {
+			if self.injected.len() == self.queue_size {
+				return false;
+			}
+			self.injected.push(action);
+			true
+		}
+	}
+
+	impl ContextClock for InjectContext {
+		fn clock(&self) -> &impl crate::time::Clock {
+			&self.clock
+		}
+	}
+
+	impl ContextSerialTx for InjectContext {
+		type SerialTx = FakeSerialTx;
+
+		fn serial_tx(&mut self) -> &mut Self::SerialTx {
+			&mut self.serial_tx
+		}
+	}
+
+	impl ContextSerialRx for InjectContext {
+		type SerialRx = FakeSerialRx;
+
+		fn serial_rx(&mut self) -> &mut Self::SerialRx {
+			&mut self.serial_rx
+		}
+	}
+
+	#[tokio::test]
+	async fn inject_key_queues_the_key_change() {
+		let mut ctx = InjectContext::new(0x01);
+		InjectKeyCommand.execute(&mut ctx).await.unwrap();
+		assert_eq!(ctx.serial_tx.written, [0xFF]);
+		assert_eq!(ctx.injected.len(), 1);
+		assert_eq!(ctx.injected[0].key_id, INJECTED_KEY_ID);
+		assert_eq!(ctx.injected[0].action, KeyState::Pressed);
+
+		let mut ctx = InjectContext::new(0x00);
+		InjectKeyCommand.execute(&mut ctx).await.unwrap();
+		assert_eq!(ctx.injected[0].action, KeyState::Released);
+	}
+
+	#[tokio::test]
+	async fn inject_key_rejects_bad_states_and_a_full_queue() {
+		let mut ctx = InjectContext::new(0x02);
+		let result = InjectKeyCommand.execute(&mut ctx).await;
+		assert_eq!(result, Err("Invalid key state"));
+		assert_eq!(ctx.serial_tx.written, [0x11]);
+		assert!(ctx.injected.is_empty());
+
+		let mut ctx = InjectContext::new(0x01);
+		ctx.queue_size = 0;
+		let result = InjectKeyCommand.execute(&mut ctx).await;
+		assert_eq!(result, Err("Injected key queue is full"));
+		assert_eq!(ctx.serial_tx.written, [0x12]);
+	}
 }
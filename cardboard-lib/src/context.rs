@@ -2,12 +2,28 @@ use core::alloc::GlobalAlloc;
 
 use crate::{
 	TrackingAllocator,
+	auth::{AuthSession, NONCE_SIZE, NonceSource},
+	battery::BatteryStatus,
+	budget::MemoryBudgets,
+	buzzer::Tone,
+	command::ProfileUpload,
 	device::DeviceInfo,
-	error::ErrorLog,
+	display::DisplayStatus,
+	error::{Error, ErrorLog},
+	event::{HostEvents, KeyEvent},
+	haptic::HapticPattern,
+	input::{KeyboardAction, RawMatrixScan, VirtualKeyAction},
+	lighting::LightingEvent,
+	lock::SafeMode,
+	module::ModuleStatus,
 	profile::{KeyboardProfile, LayerTag},
-	serial::SerialDrain,
+	serial::{SerialCredit, SerialDrain, SerialSession},
+	state::{ActiveTags, KeyStats, KeypadStatus},
+	stats::UsbStats,
 	storage::{BlockFlash, BlockFlashExt, FlashPartition, PartitionedFlashMemory},
 	stream::{ReadAsync, WriteAsync},
+	time::WallClock,
+	update::ImageLayout,
 };
 use alloc::vec::Vec;
 
@@ -32,16 +48,40 @@ pub struct Context<
 	pub flash: Flash,
 	pub settings_partition: FlashPartition<Flash>,
 	pub profile_partition: FlashPartition<Flash>,
+	pub key_stats_partition: FlashPartition<Flash>,
+	pub crash_report_partition: FlashPartition<Flash>,
+	pub firmware_update_partition: FlashPartition<Flash>,
+	pub firmware_image_layout: Option<ImageLayout>,
+	pub lock_partition: FlashPartition<Flash>,
+	pub auth_partition: FlashPartition<Flash>,
 	pub update_profile_signal: &'static dyn UpdateProfileSignalTx,
 	pub serial_rx: SerialRx,
 	pub serial_tx: SerialTx,
 	pub external_tags_signal: &'static dyn ExternalTagsSignalTx,
 	pub virtual_keys_signal: &'static dyn VirtualKeySignalTx<VIRTUAL_KEY_BITFIELD_BYTES>,
+	pub virtual_keys_by_id: &'static dyn VirtualKeyIdSignalTx,
+	pub macro_speed_signal: &'static dyn MacroSpeedSignalTx,
+	pub key_events: &'static dyn KeyEventSignalRx,
+	pub matrix_scan: &'static dyn MatrixScanSignalRx,
+	pub injected_keys: &'static dyn InjectKeySignalTx,
+	pub active_tags: &'static dyn ActiveTagsSignalRx,
+	pub keypad_status: &'static dyn KeypadStatusSignalRx,
+	pub key_stats: &'static dyn KeyStatsSignalRx,
 	pub allocator: &'static TrackingAllocator<Allocator>,
+	pub usb_stats: &'static UsbStats,
+	pub battery: &'static BatteryStatus,
+	pub modules: &'static ModuleStatus,
+	pub safe_mode: &'static SafeMode,
+	pub budgets: &'static MemoryBudgets,
 	pub reboot: &'static mut dyn Reboot,
 	pub bootloader: &'static dyn RebootToBootloader,
+	pub nonce_source: &'static mut dyn NonceSource,
+	pub auth_session: AuthSession,
+	pub sync_markers: bool,
+	pub profile_upload: Option<ProfileUpload>,
 	pub errors: Errors,
 	pub clock: &'static Clock,
+	pub wall_clock: &'static WallClock,
 }
 
 impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
@@ -60,32 +100,77 @@ where
 		flash: Flash,
 		settings_partition: FlashPartition<Flash>,
 		profile_partition: FlashPartition<Flash>,
+		key_stats_partition: FlashPartition<Flash>,
+		crash_report_partition: FlashPartition<Flash>,
+		firmware_update_partition: FlashPartition<Flash>,
+		firmware_image_layout: Option<ImageLayout>,
+		lock_partition: FlashPartition<Flash>,
+		auth_partition: FlashPartition<Flash>,
 		update_profile_signal: &'static dyn UpdateProfileSignalTx,
 		serial_rx: SerialRx,
 		serial_tx: SerialTx,
 		external_tags_signal: &'static dyn ExternalTagsSignalTx,
 		virtual_keys_signal: &'static dyn VirtualKeySignalTx<VIRTUAL_KEY_BITFIELD_BYTES>,
+		virtual_keys_by_id: &'static dyn VirtualKeyIdSignalTx,
+		macro_speed_signal: &'static dyn MacroSpeedSignalTx,
+		key_events: &'static dyn KeyEventSignalRx,
+		matrix_scan: &'static dyn MatrixScanSignalRx,
+		injected_keys: &'static dyn InjectKeySignalTx,
+		active_tags: &'static dyn ActiveTagsSignalRx,
+		keypad_status: &'static dyn KeypadStatusSignalRx,
+		key_stats: &'static dyn KeyStatsSignalRx,
 		allocator: &'static TrackingAllocator<Allocator>,
+		usb_stats: &'static UsbStats,
+		battery: &'static BatteryStatus,
+		modules: &'static ModuleStatus,
+		safe_mode: &'static SafeMode,
+		budgets: &'static MemoryBudgets,
 		reboot: &'static mut dyn Reboot,
 		bootloader: &'static dyn RebootToBootloader,
+		nonce_source: &'static mut dyn NonceSource,
 		errors: Errors,
 		clock: &'static Clock,
+		wall_clock: &'static WallClock,
 	) -> Self {
 		Self {
 			device_info,
 			flash,
 			settings_partition,
 			profile_partition,
+			key_stats_partition,
+			crash_report_partition,
+			firmware_update_partition,
+			firmware_image_layout,
+			lock_partition,
+			auth_partition,
 			update_profile_signal,
 			serial_rx,
 			serial_tx,
 			external_tags_signal,
 			virtual_keys_signal,
+			virtual_keys_by_id,
+			macro_speed_signal,
+			key_events,
+			matrix_scan,
+			injected_keys,
+			active_tags,
+			keypad_status,
+			key_stats,
 			allocator,
+			usb_stats,
+			battery,
+			modules,
+			safe_mode,
+			budgets,
 			reboot,
 			bootloader,
+			nonce_source,
+			auth_session: AuthSession::new(),
+			sync_markers: false,
+			profile_upload: None,
 			errors,
 			clock,
+			wall_clock,
 		}
 	}
 }
@@ -98,7 +183,7 @@ pub trait ContextDeviceInfo {
 }
 
 pub trait ContextSerialRx {
-	type SerialRx: ReadAsync + SerialDrain;
+	type SerialRx: ReadAsync + SerialDrain + SerialCredit + SerialSession;
 	fn serial_rx(&mut self) -> &mut Self::SerialRx;
 }
 
@@ -117,6 +202,50 @@ pub trait ContextProfileFlash {
 	fn profile_flash(&mut self) -> PartitionedFlashMemory<Self::Flash>;
 }
 
+pub trait ContextKeyStatsFlash {
+	type Flash: BlockFlash;
+	fn key_stats_flash(&mut self) -> PartitionedFlashMemory<Self::Flash>;
+}
+
+pub trait ContextCrashReportFlash {
+	type Flash: BlockFlash;
+	fn crash_report_flash(&mut self) -> PartitionedFlashMemory<Self::Flash>;
+}
+
+pub trait ContextFirmwareUpdateFlash {
+	type Flash: BlockFlash;
+	fn firmware_update_flash(&mut self) -> PartitionedFlashMemory<Self::Flash>;
+	/// What an image for this chip starts with, or `None` if this build can't install updates.
+	fn firmware_image_layout(&self) -> Option<&ImageLayout>;
+}
+
+pub trait ContextLockFlash {
+	type Flash: BlockFlash;
+	fn lock_flash(&mut self) -> PartitionedFlashMemory<Self::Flash>;
+}
+
+pub trait ContextSafeMode {
+	/// Whether the safe mode key was held at power-up.
+	fn started_in_safe_mode(&self) -> bool;
+}
+
+pub trait ContextAuth {
+	type Flash: BlockFlash;
+	fn auth_flash(&mut self) -> PartitionedFlashMemory<Self::Flash>;
+	fn auth_session(&mut self) -> &mut AuthSession;
+	fn auth_challenge(&mut self) -> [u8; NONCE_SIZE];
+}
+
+pub trait ContextProfileUpload {
+	/// The profile upload that was cut off, if there is one to resume.
+	fn profile_upload(&mut self) -> &mut Option<ProfileUpload>;
+}
+
+pub trait ContextSyncMarkers {
+	/// Whether this session's commands each start with `SYNC_MARKER`.
+	fn sync_markers(&mut self) -> &mut bool;
+}
+
 pub trait ContextUpdateProfile {
 	type UpdateProfileSignal: UpdateProfileSignalTx + ?Sized;
 	fn profile_signal(&mut self) -> &Self::UpdateProfileSignal;
@@ -130,11 +259,62 @@ pub trait ContextVirtualKeys<const VIRTUAL_KEY_BITFIELD_BYTES: usize> {
 	fn set_virtual_keys(&mut self, state: [u8; VIRTUAL_KEY_BITFIELD_BYTES]);
 }
 
+pub trait ContextVirtualKeysById {
+	fn set_virtual_key(&mut self, action: VirtualKeyAction) -> bool;
+}
+
+pub trait ContextMacroSpeed {
+	fn set_macro_speed(&mut self, percent: u16);
+}
+
+pub trait ContextKeyEvents {
+	fn subscribe_key_events(&mut self, subscribed: bool);
+	fn try_take_key_event(&mut self) -> Option<KeyEvent>;
+}
+
+pub trait ContextMatrixScan {
+	fn request_matrix_scan(&mut self);
+	fn try_take_matrix_scan(&mut self) -> Option<RawMatrixScan>;
+}
+
+pub trait ContextInjectKeys {
+	fn inject_key(&mut self, action: KeyboardAction) -> bool;
+}
+
+pub trait ContextActiveTags {
+	fn request_active_tags(&mut self);
+	fn try_take_active_tags(&mut self) -> Option<ActiveTags>;
+}
+
+pub trait ContextKeypadStatus {
+	fn request_keypad_status(&mut self);
+	fn try_take_keypad_status(&mut self) -> Option<KeypadStatus>;
+}
+
+pub trait ContextKeyStats {
+	fn request_key_stats(&mut self);
+	fn try_take_key_stats(&mut self) -> Option<KeyStats>;
+	/// Stats the keypad task wants written to flash, if it has any.
+	fn try_take_key_stats_to_save(&mut self) -> Option<KeyStats>;
+}
+
 pub trait ContextAllocator {
 	fn allocator(&self) -> &TrackingAllocator<Self::A>;
 	type A: GlobalAlloc;
 }
 
+pub trait ContextUsbStats {
+	fn usb_stats(&self) -> &UsbStats;
+}
+
+pub trait ContextBattery {
+	fn battery(&self) -> &BatteryStatus;
+}
+
+pub trait ContextMemoryBudgets {
+	fn budgets(&self) -> &'static MemoryBudgets;
+}
+
 pub trait ContextReboot {
 	fn reboot(&mut self) -> !;
 	fn reboot_to_bootloader(&mut self) -> !;
@@ -149,6 +329,14 @@ pub trait ContextClock {
 	fn clock(&self) -> &impl crate::time::Clock;
 }
 
+pub trait ContextWallClock {
+	fn wall_clock(&self) -> &'static WallClock;
+}
+
+pub trait ContextModules {
+	fn modules(&self) -> &'static ModuleStatus;
+}
+
 // Trait implementations for Context
 
 impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
@@ -156,7 +344,7 @@ impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocat
 	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
 where
 	Flash: BlockFlash,
-	SerialRx: ReadAsync + SerialDrain,
+	SerialRx: ReadAsync + SerialDrain + SerialCredit,
 	SerialTx: WriteAsync,
 	Allocator: GlobalAlloc + 'static,
 	Errors: ErrorLog,
@@ -172,7 +360,7 @@ impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocat
 	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
 where
 	Flash: BlockFlash,
-	SerialRx: ReadAsync + SerialDrain,
+	SerialRx: ReadAsync + SerialDrain + SerialCredit,
 	SerialTx: WriteAsync,
 	Allocator: GlobalAlloc + 'static,
 	Errors: ErrorLog,
@@ -237,6 +425,156 @@ where
 	}
 }
 
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextKeyStatsFlash
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	type Flash = Flash;
+
+	fn key_stats_flash(&mut self) -> PartitionedFlashMemory<Flash> {
+		self.flash.partition(&self.key_stats_partition)
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextCrashReportFlash
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	type Flash = Flash;
+
+	fn crash_report_flash(&mut self) -> PartitionedFlashMemory<Flash> {
+		self.flash.partition(&self.crash_report_partition)
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextFirmwareUpdateFlash
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	type Flash = Flash;
+
+	fn firmware_update_flash(&mut self) -> PartitionedFlashMemory<Flash> {
+		self.flash.partition(&self.firmware_update_partition)
+	}
+
+	fn firmware_image_layout(&self) -> Option<&ImageLayout> {
+		self.firmware_image_layout.as_ref()
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextLockFlash
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	type Flash = Flash;
+
+	fn lock_flash(&mut self) -> PartitionedFlashMemory<Flash> {
+		self.flash.partition(&self.lock_partition)
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextSafeMode
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn started_in_safe_mode(&self) -> bool {
+		self.safe_mode.is_set()
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextAuth
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	type Flash = Flash;
+
+	fn auth_flash(&mut self) -> PartitionedFlashMemory<Flash> {
+		self.flash.partition(&self.auth_partition)
+	}
+
+	fn auth_session(&mut self) -> &mut AuthSession {
+		&mut self.auth_session
+	}
+
+	fn auth_challenge(&mut self) -> [u8; NONCE_SIZE] {
+		self.auth_session.challenge(self.nonce_source)
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextSyncMarkers
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn sync_markers(&mut self) -> &mut bool {
+		&mut self.sync_markers
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextProfileUpload
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn profile_upload(&mut self) -> &mut Option<ProfileUpload> {
+		&mut self.profile_upload
+	}
+}
+
 impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
 	ContextUpdateProfile
 	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
@@ -286,6 +624,195 @@ where
 	}
 }
 
+/// A context with room for 128 virtual keys also takes the 8 and 32 key commands, so a firmware
+/// can pick the one that fits its board without another signal type. The keys the shorter
+/// bitfield doesn't cover are released.
+impl<Flash, SerialRx, SerialTx, Allocator, Errors, Clock> ContextVirtualKeys<1>
+	for Context<Flash, SerialRx, SerialTx, 16, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn set_virtual_keys(&mut self, state: [u8; 1]) {
+		let mut keys = [0; 16];
+		keys[..1].copy_from_slice(&state);
+		self.virtual_keys_signal.set_virtual_keys(keys);
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, Allocator, Errors, Clock> ContextVirtualKeys<4>
+	for Context<Flash, SerialRx, SerialTx, 16, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn set_virtual_keys(&mut self, state: [u8; 4]) {
+		let mut keys = [0; 16];
+		keys[..4].copy_from_slice(&state);
+		self.virtual_keys_signal.set_virtual_keys(keys);
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextVirtualKeysById
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn set_virtual_key(&mut self, action: VirtualKeyAction) -> bool {
+		self.virtual_keys_by_id.set_virtual_key(action)
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextMacroSpeed
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn set_macro_speed(&mut self, percent: u16) {
+		self.macro_speed_signal.set_macro_speed(percent);
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextKeyEvents
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn subscribe_key_events(&mut self, subscribed: bool) {
+		self.key_events.set_key_events_subscribed(subscribed);
+	}
+
+	fn try_take_key_event(&mut self) -> Option<KeyEvent> {
+		self.key_events.try_take_key_event()
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextMatrixScan
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn request_matrix_scan(&mut self) {
+		self.matrix_scan.request_matrix_scan();
+	}
+
+	fn try_take_matrix_scan(&mut self) -> Option<RawMatrixScan> {
+		self.matrix_scan.try_take_matrix_scan()
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextInjectKeys
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn inject_key(&mut self, action: KeyboardAction) -> bool {
+		self.injected_keys.inject_key(action)
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextActiveTags
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn request_active_tags(&mut self) {
+		self.active_tags.request_active_tags();
+	}
+
+	fn try_take_active_tags(&mut self) -> Option<ActiveTags> {
+		self.active_tags.try_take_active_tags()
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextKeypadStatus
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn request_keypad_status(&mut self) {
+		self.keypad_status.request_keypad_status();
+	}
+
+	fn try_take_keypad_status(&mut self) -> Option<KeypadStatus> {
+		self.keypad_status.try_take_keypad_status()
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextKeyStats
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn request_key_stats(&mut self) {
+		self.key_stats.request_key_stats();
+	}
+
+	fn try_take_key_stats(&mut self) -> Option<KeyStats> {
+		self.key_stats.try_take_key_stats()
+	}
+
+	fn try_take_key_stats_to_save(&mut self) -> Option<KeyStats> {
+		self.key_stats.try_take_key_stats_to_save()
+	}
+}
+
 impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
 	ContextAllocator
 	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
@@ -303,6 +830,86 @@ where
 	}
 }
 
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextUsbStats
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn usb_stats(&self) -> &UsbStats {
+		self.usb_stats
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextBattery
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn battery(&self) -> &BatteryStatus {
+		self.battery
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextModules
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn modules(&self) -> &'static ModuleStatus {
+		self.modules
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextWallClock
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn wall_clock(&self) -> &'static WallClock {
+		self.wall_clock
+	}
+}
+
+impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
+	ContextMemoryBudgets
+	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
+where
+	Flash: BlockFlash,
+	SerialRx: ReadAsync,
+	SerialTx: WriteAsync,
+	Allocator: GlobalAlloc + 'static,
+	Errors: ErrorLog,
+	Clock: crate::time::Clock + 'static,
+{
+	fn budgets(&self) -> &'static MemoryBudgets {
+		self.budgets
+	}
+}
+
 impl<Flash, SerialRx, SerialTx, const VIRTUAL_KEY_BITFIELD_BYTES: usize, Allocator, Errors, Clock>
 	ContextReboot
 	for Context<Flash, SerialRx, SerialTx, VIRTUAL_KEY_BITFIELD_BYTES, Allocator, Errors, Clock>
@@ -382,6 +989,140 @@ pub trait VirtualKeySignalRx<const SIZE: usize> {
 	fn try_get_virtual_keys(&self) -> Option<[u8; SIZE]>;
 }
 
+pub trait VirtualKeyIdSignalTx {
+	/// Queues a virtual key change. Returns false if the queue is full.
+	fn set_virtual_key(&self, action: VirtualKeyAction) -> bool;
+}
+
+pub trait VirtualKeyIdSignalRx {
+	fn try_take_virtual_key(&self) -> Option<VirtualKeyAction>;
+}
+
+/// Global macro playback speed in percent.
+pub trait MacroSpeedSignalTx {
+	fn set_macro_speed(&self, percent: u16);
+}
+
+pub trait MacroSpeedSignalRx {
+	fn try_get_macro_speed(&self) -> Option<u16>;
+}
+
+pub trait HostEventSignalTx {
+	fn notify_host(&self, events: HostEvents);
+}
+
+pub trait HostEventSignalRx {
+	fn try_take_host_events(&self) -> Option<HostEvents>;
+}
+
+pub trait KeyEventSignalTx {
+	fn key_events_subscribed(&self) -> bool;
+	fn send_key_event(&self, event: KeyEvent);
+}
+
+pub trait KeyEventSignalRx {
+	fn set_key_events_subscribed(&self, subscribed: bool);
+	fn try_take_key_event(&self) -> Option<KeyEvent>;
+}
+
+pub trait MatrixScanSignalTx {
+	fn matrix_scan_requested(&self) -> bool;
+	fn send_matrix_scan(&self, scan: RawMatrixScan);
+}
+
+pub trait MatrixScanSignalRx {
+	fn request_matrix_scan(&self);
+	fn try_take_matrix_scan(&self) -> Option<RawMatrixScan>;
+}
+
+pub trait InjectKeySignalTx {
+	/// Queues a synthetic key action. Returns false if the queue is full.
+	fn inject_key(&self, action: KeyboardAction) -> bool;
+}
+
+pub trait InjectKeySignalRx {
+	fn try_take_injected_key(&self) -> Option<KeyboardAction>;
+}
+
+pub trait ActiveTagsSignalTx {
+	fn active_tags_requested(&self) -> bool;
+	fn send_active_tags(&self, tags: ActiveTags);
+}
+
+pub trait ActiveTagsSignalRx {
+	fn request_active_tags(&self);
+	fn try_take_active_tags(&self) -> Option<ActiveTags>;
+}
+
+pub trait KeypadStatusSignalTx {
+	fn keypad_status_requested(&self) -> bool;
+	fn send_keypad_status(&self, status: KeypadStatus);
+}
+
+pub trait KeypadStatusSignalRx {
+	fn request_keypad_status(&self);
+	fn try_take_keypad_status(&self) -> Option<KeypadStatus>;
+}
+
+/// Key stats snapshots, either requested by the host or pushed by the keypad task when they're
+/// due to be saved.
+pub trait KeyStatsSignalTx {
+	fn key_stats_requested(&self) -> bool;
+	fn send_key_stats(&self, stats: KeyStats);
+	fn save_key_stats(&self, stats: KeyStats);
+}
+
+pub trait KeyStatsSignalRx {
+	fn request_key_stats(&self);
+	fn try_take_key_stats(&self) -> Option<KeyStats>;
+	fn try_take_key_stats_to_save(&self) -> Option<KeyStats>;
+}
+
+/// Lighting events from macros, resolved to LEDs by the keypad task.
+pub trait LightingSignalTx {
+	fn send_lighting_event(&self, event: LightingEvent);
+}
+
+pub trait LightingSignalRx {
+	fn try_take_lighting_event(&self) -> Option<LightingEvent>;
+}
+
+/// Tones from macros, played in order by the buzzer task.
+pub trait ToneSignalTx {
+	fn send_tone(&self, tone: Tone);
+}
+
+pub trait ToneSignalRx {
+	fn try_take_tone(&self) -> Option<Tone>;
+}
+
+/// Vibration patterns from macros, played in order by the haptic task.
+pub trait HapticSignalTx {
+	fn send_haptic(&self, pattern: HapticPattern);
+}
+
+pub trait HapticSignalRx {
+	fn try_take_haptic(&self) -> Option<HapticPattern>;
+}
+
+/// What the status display shows, sent by the keypad task when it changes.
+pub trait DisplaySignalTx {
+	fn send_display_status(&self, status: DisplayStatus);
+}
+
+pub trait DisplaySignalRx {
+	fn try_take_display_status(&self) -> Option<DisplayStatus>;
+}
+
+/// Errors raised by the keypad task, handed to the command task for the error log.
+pub trait KeypadErrorSignalTx {
+	fn report_error(&self, error: Error);
+}
+
+pub trait KeypadErrorSignalRx {
+	fn try_take_keypad_error(&self) -> Option<Error>;
+}
+
 pub trait Reboot {
 	fn reboot(&mut self) -> !;
 }
@@ -389,3 +1130,9 @@ pub trait Reboot {
 pub trait RebootToBootloader {
 	fn reboot_to_bootloader(&self) -> !;
 }
+
+/// Lets the keypad task save power while it's asleep.
+pub trait LowPower {
+	fn enter_low_power(&self);
+	fn exit_low_power(&self);
+}
@@ -0,0 +1,190 @@
+use alloc::string::String;
+use core::fmt::{self, Write};
+
+use crate::serialize::{Readable, Writeable};
+use crate::stream::{ReadAsync, ReadAsyncExt, WriteAsync, WriteAsyncExt};
+
+/// What the last panic left behind, saved to flash so the host can fetch it after the reset.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrashReport {
+	pub uptime_ms: u64,
+	pub core: u8,
+	pub stack_pointer: u32,
+	pub message: String,
+}
+
+impl Readable for CrashReport {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str> {
+		Ok(Self {
+			uptime_ms: reader
+				.read_u64()
+				.await
+				.ok_or("Failed to read crash uptime")?,
+			core: reader.read_u8().await.ok_or("Failed to read crash core")?,
+			stack_pointer: reader
+				.read_u32()
+				.await
+				.ok_or("Failed to read crash stack pointer")?,
+			message: reader
+				.read_string_u16()
+				.await
+				.ok_or("Failed to read crash message")?,
+		})
+	}
+}
+
+impl Writeable for CrashReport {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_u64(self.uptime_ms).await?;
+		writer.write_u8(self.core).await?;
+		writer.write_u32(self.stack_pointer).await?;
+		writer.write_string_u16(&self.message).await
+	}
+}
+
+pub const CRASH_MESSAGE_SIZE: usize = 256;
+const CRASH_RECORD_MAGIC: u32 = 0xC4A5_4ED0;
+
+/// Fixed-size crash record for the panic handler to fill in without allocating. It's meant to
+/// live in RAM that isn't cleared on reset, so the next boot can turn it into a `CrashReport`.
+#[repr(C)]
+pub struct CrashRecord {
+	magic: u32,
+	uptime_ms: u64,
+	core: u8,
+	stack_pointer: u32,
+	length: u16,
+	message: [u8; CRASH_MESSAGE_SIZE],
+}
+
+impl CrashRecord {
+	pub const fn new() -> Self {
+		Self {
+			magic: 0,
+			uptime_ms: 0,
+			core: 0,
+			stack_pointer: 0,
+			length: 0,
+			message: [0; CRASH_MESSAGE_SIZE],
+		}
+	}
+
+	/// Records a crash, truncating the message to fit.
+	pub fn capture(
+		&mut self,
+		message: fmt::Arguments,
+		uptime_ms: u64,
+		core: u8,
+		stack_pointer: u32,
+	) {
+		let mut writer = TruncatingWriter {
+			buffer: &mut self.message,
+			length: 0,
+		};
+		let _ = writer.write_fmt(message);
+		self.length = writer.length as u16;
+		self.uptime_ms = uptime_ms;
+		self.core = core;
+		self.stack_pointer = stack_pointer;
+		self.magic = CRASH_RECORD_MAGIC;
+	}
+
+	/// Returns the recorded crash, if any, and clears the record. Anything without the magic
+	/// is leftover RAM contents from a power-on rather than a crash.
+	pub fn take(&mut self) -> Option<CrashReport> {
+		if self.magic != CRASH_RECORD_MAGIC {
+			return None;
+		}
+		self.magic = 0;
+
+		let length = (self.length as usize).min(CRASH_MESSAGE_SIZE);
+		Some(CrashReport {
+			uptime_ms: self.uptime_ms,
+			core: self.core,
+			stack_pointer: self.stack_pointer,
+			message: String::from_utf8_lossy(&self.message[..length]).into(),
+		})
+	}
+}
+
+impl Default for CrashRecord {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+struct TruncatingWriter<'a> {
+	buffer: &'a mut [u8],
+	length: usize,
+}
+
+impl Write for TruncatingWriter<'_> {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		let space = self.buffer.len() - self.length;
+		let mut count = s.len().min(space);
+		while !s.is_char_boundary(count) {
+			count -= 1;
+		}
+		self.buffer[self.length..self.length + count].copy_from_slice(&s.as_bytes()[..count]);
+		self.length += count;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn captured_crash_is_taken_once() {
+		let mut record = CrashRecord::new();
+		assert_eq!(record.take(), None);
+
+		record.capture(
+			format_args!("panicked at {}:{}", "main.rs", 42),
+			1234,
+			1,
+			0x2004_1000,
+		);
+
+		assert_eq!(
+			record.take(),
+			Some(CrashReport {
+				uptime_ms: 1234,
+				core: 1,
+				stack_pointer: 0x2004_1000,
+				message: "panicked at main.rs:42".into(),
+			})
+		);
+		assert_eq!(record.take(), None);
+	}
+
+	#[test]
+	fn long_messages_are_truncated_on_a_char_boundary() {
+		let mut record = CrashRecord::new();
+		let message = "é".repeat(CRASH_MESSAGE_SIZE);
+
+		record.capture(format_args!("{}", message), 0, 0, 0);
+
+		let report = record.take().unwrap();
+		assert_eq!(report.message.len(), CRASH_MESSAGE_SIZE);
+		assert!(report.message.chars().all(|c| c == 'é'));
+	}
+
+	#[tokio::test]
+	async fn crash_report_round_trips() {
+		let report = CrashReport {
+			uptime_ms: 99,
+			core: 0,
+			stack_pointer: 0x2003_FF00,
+			message: "oops".into(),
+		};
+		let mut data = alloc::vec::Vec::new();
+		report.write_to(&mut data).await.unwrap();
+
+		assert_eq!(
+			CrashReport::read_from(&mut data.as_slice()).await.unwrap(),
+			report
+		);
+	}
+}
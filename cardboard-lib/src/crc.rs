@@ -0,0 +1,84 @@
+/// CRC-16/CCITT-FALSE (poly 0x1021, init 0xFFFF, no reflection, no final xor).
+pub struct Crc16 {
+	value: u16,
+}
+
+impl Crc16 {
+	pub const fn new() -> Self {
+		Self { value: 0xFFFF }
+	}
+
+	pub fn update(&mut self, data: &[u8]) {
+		for byte in data {
+			self.value ^= (*byte as u16) << 8;
+			for _ in 0..8 {
+				if self.value & 0x8000 != 0 {
+					self.value = (self.value << 1) ^ 0x1021;
+				} else {
+					self.value <<= 1;
+				}
+			}
+		}
+	}
+
+	pub fn finish(&self) -> u16 {
+		self.value
+	}
+}
+
+impl Default for Crc16 {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+pub fn crc16(data: &[u8]) -> u16 {
+	let mut crc = Crc16::new();
+	crc.update(data);
+	crc.finish()
+}
+
+/// CRC-32/MPEG-2 (poly 0x04C11DB7, init 0xFFFFFFFF, no reflection, no final xor), which the
+/// RP2040 boot ROM checks boot2 with.
+pub fn crc32_mpeg2(data: &[u8]) -> u32 {
+	let mut value = 0xFFFF_FFFFu32;
+	for byte in data {
+		value ^= (*byte as u32) << 24;
+		for _ in 0..8 {
+			if value & 0x8000_0000 != 0 {
+				value = (value << 1) ^ 0x04C1_1DB7;
+			} else {
+				value <<= 1;
+			}
+		}
+	}
+	value
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn crc16_matches_check_value() {
+		assert_eq!(crc16(b"123456789"), 0x29B1);
+	}
+
+	#[test]
+	fn crc32_mpeg2_matches_check_value() {
+		assert_eq!(crc32_mpeg2(b"123456789"), 0x0376_E6E7);
+	}
+
+	#[test]
+	fn crc16_of_empty_data_is_init_value() {
+		assert_eq!(crc16(&[]), 0xFFFF);
+	}
+
+	#[test]
+	fn crc16_incremental_matches_one_shot() {
+		let mut crc = Crc16::new();
+		crc.update(b"1234");
+		crc.update(b"56789");
+		assert_eq!(crc.finish(), crc16(b"123456789"));
+	}
+}
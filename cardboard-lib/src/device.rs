@@ -1,7 +1,7 @@
 use core::fmt::Display;
 
 use alloc::{string::String, string::ToString, vec::Vec};
-use defmt::Format;
+use bitflags::bitflags;
 use uuid::Uuid;
 
 use crate::{
@@ -85,9 +85,10 @@ impl Display for CommandId {
 	}
 }
 
-impl Format for CommandId {
+#[cfg(feature = "defmt")]
+impl defmt::Format for CommandId {
 	fn format(&self, fmt: defmt::Formatter) {
-		self.0.to_string().format(fmt);
+		defmt::Format::format(&self.0.to_string(), fmt);
 	}
 }
 
@@ -99,6 +100,9 @@ pub struct DeviceInfo {
 	pub variant: Option<DeviceVariant>,
 	pub version: DeviceVersion,
 	pub commands: Vec<CommandInfo>,
+	pub build: BuildInfo,
+	pub mouse_enabled: bool,
+	pub capabilities: DeviceCapabilities,
 }
 
 impl Writeable for DeviceInfo {
@@ -110,6 +114,94 @@ impl Writeable for DeviceInfo {
 		writer.write_option(self.variant).await?;
 		self.version.write_to(writer).await?;
 		writer.write_collection_u8(&self.commands).await?;
+		self.capabilities.write_to(writer).await?;
+		Ok(())
+	}
+}
+
+bitflags! {
+	/// The HID interfaces the device presents to the host.
+	#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+	pub struct HidInterfaces: u8 {
+		const KEYBOARD = 0b00000001;
+		const MOUSE = 0b00000010;
+		const CONSUMER_CONTROL = 0b00000100;
+		/// Reports the battery level to the host.
+		const BATTERY = 0b00001000;
+	}
+}
+
+/// Sizes of the flash partitions, in bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PartitionSizes {
+	pub firmware_update: u32,
+	pub settings: u32,
+	pub profile: u32,
+	pub auth: u32,
+	pub lock: u32,
+	pub crash_report: u32,
+	pub key_stats: u32,
+}
+
+impl Writeable for PartitionSizes {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_u32(self.firmware_update).await?;
+		writer.write_u32(self.settings).await?;
+		writer.write_u32(self.profile).await?;
+		writer.write_u32(self.auth).await?;
+		writer.write_u32(self.lock).await?;
+		writer.write_u32(self.crash_report).await?;
+		writer.write_u32(self.key_stats).await?;
+		Ok(())
+	}
+}
+
+/// What the device has, sent in Identify so host apps don't have to assume it per device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeviceCapabilities {
+	/// How many virtual keys the Set Virtual Keys command covers.
+	pub virtual_keys: u16,
+	pub matrix_rows: u8,
+	pub matrix_cols: u8,
+	pub hid: HidInterfaces,
+	pub partitions: PartitionSizes,
+	/// Largest profile Update Profile accepts, in bytes.
+	pub max_profile_size: u32,
+	/// How many bytes the host may send before the device hands some back as credit.
+	pub receive_window: u16,
+}
+
+impl Writeable for DeviceCapabilities {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		// the virtual key count came first, so older hosts still read it in the same place
+		writer.write_u16(self.virtual_keys).await?;
+		writer.write_u8(self.matrix_rows).await?;
+		writer.write_u8(self.matrix_cols).await?;
+		writer.write_u8(self.hid.bits()).await?;
+		self.partitions.write_to(writer).await?;
+		writer.write_u32(self.max_profile_size).await?;
+		writer.write_u16(self.receive_window).await?;
+		Ok(())
+	}
+}
+
+/// Details of the firmware build, filled in at compile time.
+pub struct BuildInfo {
+	pub version: &'static str,
+	pub git_hash: &'static str,
+	pub timestamp: u64, // seconds since the unix epoch
+	pub features: &'static [&'static str],
+}
+
+impl Writeable for BuildInfo {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_string_u8(self.version).await?;
+		writer.write_string_u8(self.git_hash).await?;
+		writer.write_u64(self.timestamp).await?;
+		writer.write_u8(self.features.len() as u8).await?;
+		for feature in self.features {
+			writer.write_string_u8(feature).await?;
+		}
 		Ok(())
 	}
 }
@@ -135,3 +227,34 @@ impl Writeable for DeviceOptions {
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn capabilities_start_with_the_virtual_key_count() {
+		let capabilities = DeviceCapabilities {
+			virtual_keys: 128,
+			matrix_rows: 5,
+			matrix_cols: 6,
+			hid: HidInterfaces::KEYBOARD | HidInterfaces::CONSUMER_CONTROL,
+			partitions: PartitionSizes {
+				profile: 0x1000,
+				..Default::default()
+			},
+			max_profile_size: 0x0FFE,
+			receive_window: 0x0140,
+		};
+
+		let mut data = Vec::new();
+		capabilities.write_to(&mut data).await.unwrap();
+
+		assert_eq!(data[..5], [128, 0, 5, 6, 0b101]);
+		// the profile partition is the third of seven
+		assert_eq!(data[13..17], 0x1000u32.to_le_bytes());
+		assert_eq!(data.len(), 5 + 7 * 4 + 4 + 2);
+		assert_eq!(data[33..37], 0x0FFEu32.to_le_bytes());
+		assert_eq!(data[37..], 0x0140u16.to_le_bytes());
+	}
+}
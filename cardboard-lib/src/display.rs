@@ -0,0 +1,568 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::hid::LockState;
+use crate::input::KeyId;
+use crate::profile::LayerTag;
+use crate::serialize::Readable;
+use crate::stream::{ReadAsync, ReadAsyncExt};
+
+pub const WIDTH: usize = 128;
+pub const HEIGHT: usize = 64;
+/// Rows of 8 pixels, the unit both controllers address memory in.
+pub const PAGES: usize = HEIGHT / 8;
+
+/// Each glyph is 3 columns wide with a blank column after it, and sits in one page.
+const GLYPH_WIDTH: usize = 4;
+pub const COLUMNS: usize = WIDTH / GLYPH_WIDTH;
+
+/// 3x5 glyphs for ASCII 0x20 to 0x5F, a byte per column with the top row in bit 0. Lowercase
+/// letters are drawn as uppercase and anything else as `?`.
+const FONT: [[u8; 3]; 64] = [
+	[0x00, 0x00, 0x00], // ' '
+	[0x00, 0x17, 0x00], // '!'
+	[0x03, 0x00, 0x03], // '"'
+	[0x1f, 0x0a, 0x1f], // '#'
+	[0x12, 0x1f, 0x09], // '$'
+	[0x09, 0x04, 0x12], // '%'
+	[0x0a, 0x15, 0x1a], // '&'
+	[0x00, 0x03, 0x00], // '\''
+	[0x00, 0x0e, 0x11], // '('
+	[0x11, 0x0e, 0x00], // ')'
+	[0x0a, 0x04, 0x0a], // '*'
+	[0x04, 0x0e, 0x04], // '+'
+	[0x10, 0x08, 0x00], // ','
+	[0x04, 0x04, 0x04], // '-'
+	[0x00, 0x10, 0x00], // '.'
+	[0x18, 0x04, 0x03], // '/'
+	[0x1f, 0x11, 0x1f], // '0'
+	[0x12, 0x1f, 0x10], // '1'
+	[0x1d, 0x15, 0x17], // '2'
+	[0x11, 0x15, 0x1f], // '3'
+	[0x07, 0x04, 0x1f], // '4'
+	[0x17, 0x15, 0x1d], // '5'
+	[0x1f, 0x15, 0x1d], // '6'
+	[0x01, 0x19, 0x07], // '7'
+	[0x1f, 0x15, 0x1f], // '8'
+	[0x17, 0x15, 0x1f], // '9'
+	[0x00, 0x0a, 0x00], // ':'
+	[0x10, 0x0a, 0x00], // ';'
+	[0x04, 0x0a, 0x11], // '<'
+	[0x0a, 0x0a, 0x0a], // '='
+	[0x11, 0x0a, 0x04], // '>'
+	[0x01, 0x15, 0x07], // '?'
+	[0x0e, 0x15, 0x16], // '@'
+	[0x1e, 0x05, 0x1e], // 'A'
+	[0x1f, 0x15, 0x0a], // 'B'
+	[0x0e, 0x11, 0x11], // 'C'
+	[0x1f, 0x11, 0x0e], // 'D'
+	[0x1f, 0x15, 0x15], // 'E'
+	[0x1f, 0x05, 0x05], // 'F'
+	[0x0e, 0x11, 0x1d], // 'G'
+	[0x1f, 0x04, 0x1f], // 'H'
+	[0x11, 0x1f, 0x11], // 'I'
+	[0x08, 0x10, 0x0f], // 'J'
+	[0x1f, 0x04, 0x1b], // 'K'
+	[0x1f, 0x10, 0x10], // 'L'
+	[0x1f, 0x06, 0x1f], // 'M'
+	[0x1f, 0x01, 0x1e], // 'N'
+	[0x0e, 0x11, 0x0e], // 'O'
+	[0x1f, 0x05, 0x02], // 'P'
+	[0x0e, 0x19, 0x16], // 'Q'
+	[0x1f, 0x05, 0x1a], // 'R'
+	[0x12, 0x15, 0x09], // 'S'
+	[0x01, 0x1f, 0x01], // 'T'
+	[0x1f, 0x10, 0x1f], // 'U'
+	[0x0f, 0x10, 0x0f], // 'V'
+	[0x1f, 0x0c, 0x1f], // 'W'
+	[0x1b, 0x04, 0x1b], // 'X'
+	[0x03, 0x1c, 0x03], // 'Y'
+	[0x19, 0x15, 0x13], // 'Z'
+	[0x1f, 0x11, 0x00], // '['
+	[0x03, 0x04, 0x18], // '\\'
+	[0x00, 0x11, 0x1f], // ']'
+	[0x02, 0x01, 0x02], // '^'
+	[0x10, 0x10, 0x10], // '_'
+];
+
+/// A monochrome frame laid out the way the controllers expect: a byte per column of each page,
+/// with the top row in bit 0.
+pub struct FrameBuffer {
+	pixels: [u8; WIDTH * PAGES],
+}
+
+impl FrameBuffer {
+	pub const fn new() -> Self {
+		Self {
+			pixels: [0; WIDTH * PAGES],
+		}
+	}
+
+	pub fn clear(&mut self) {
+		self.pixels.fill(0);
+	}
+
+	/// Draws text on one page, starting at a character column. Text past the right edge is
+	/// cut off. Returns the column after the last character drawn.
+	pub fn draw_text(&mut self, page: usize, column: usize, text: &str) -> usize {
+		if page >= PAGES {
+			return column;
+		}
+		let mut column = column;
+		for c in text.chars() {
+			if column >= COLUMNS {
+				break;
+			}
+			let start = page * WIDTH + column * GLYPH_WIDTH;
+			for (pixel, bits) in self.pixels[start..].iter_mut().zip(glyph(c)) {
+				// a blank row above the glyph keeps lines apart
+				*pixel = bits << 1;
+			}
+			column += 1;
+		}
+		column
+	}
+
+	pub fn page(&self, page: usize) -> &[u8] {
+		&self.pixels[page * WIDTH..(page + 1) * WIDTH]
+	}
+}
+
+impl Default for FrameBuffer {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+fn glyph(c: char) -> [u8; 3] {
+	let c = c.to_ascii_uppercase();
+	let index = match c as u32 {
+		code @ 0x20..=0x5F => code - 0x20,
+		_ => '?' as u32 - 0x20,
+	};
+	FONT[index as usize]
+}
+
+/// Writes to an I2C bus.
+pub trait I2cBus {
+	async fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), &'static str>;
+}
+
+/// Which chip drives the panel. Both take the same commands, but the SH1106 has a 132 column
+/// memory with the panel in the middle, and only page addressing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayController {
+	Ssd1306,
+	Sh1106,
+}
+
+const CONTROL_COMMAND: u8 = 0x00;
+const CONTROL_DATA: u8 = 0x40;
+/// Bytes of pixel data sent per I2C write.
+const DATA_CHUNK: usize = 32;
+
+/// A 128x64 SSD1306 or SH1106 OLED panel on I2C.
+pub struct OledDisplay<I: I2cBus> {
+	i2c: I,
+	address: u8,
+	controller: DisplayController,
+}
+
+impl<I: I2cBus> OledDisplay<I> {
+	/// Panels usually sit at address 0x3C, or 0x3D with the address jumper changed.
+	pub fn new(i2c: I, address: u8, controller: DisplayController) -> Self {
+		Self {
+			i2c,
+			address,
+			controller,
+		}
+	}
+
+	pub async fn init(&mut self) -> Result<(), &'static str> {
+		// display off while it's set up
+		self.command(&[0xAE]).await?;
+		// clock divider, multiplex for 64 rows, no offset, start at line 0
+		self.command(&[0xD5, 0x80, 0xA8, 0x3F, 0xD3, 0x00, 0x40])
+			.await?;
+		match self.controller {
+			// charge pump on, horizontal addressing
+			DisplayController::Ssd1306 => self.command(&[0x8D, 0x14, 0x20, 0x00]).await?,
+			// DC-DC converter on
+			DisplayController::Sh1106 => self.command(&[0xAD, 0x8B]).await?,
+		}
+		// flip to the usual orientation, COM pin layout, contrast, precharge and VCOMH level
+		self.command(&[0xA1, 0xC8, 0xDA, 0x12, 0x81, 0xCF, 0xD9, 0xF1, 0xDB, 0x40])
+			.await?;
+		// show memory contents, not inverted, display on
+		self.command(&[0xA4, 0xA6, 0xAF]).await
+	}
+
+	pub async fn draw(&mut self, frame: &FrameBuffer) -> Result<(), &'static str> {
+		match self.controller {
+			DisplayController::Ssd1306 => {
+				// whole screen as one run, wrapping from page to page
+				self.command(&[0x21, 0, (WIDTH - 1) as u8, 0x22, 0, (PAGES - 1) as u8])
+					.await?;
+				for page in 0..PAGES {
+					self.data(frame.page(page)).await?;
+				}
+			}
+			DisplayController::Sh1106 => {
+				for page in 0..PAGES {
+					// the panel starts at column 2 of the controller's memory
+					self.command(&[0xB0 + page as u8, 0x02, 0x10]).await?;
+					self.data(frame.page(page)).await?;
+				}
+			}
+		}
+		Ok(())
+	}
+
+	async fn command(&mut self, commands: &[u8]) -> Result<(), &'static str> {
+		let mut buffer = [0u8; 16];
+		buffer[0] = CONTROL_COMMAND;
+		buffer[1..=commands.len()].copy_from_slice(commands);
+		self.i2c
+			.write(self.address, &buffer[..=commands.len()])
+			.await
+	}
+
+	async fn data(&mut self, data: &[u8]) -> Result<(), &'static str> {
+		let mut buffer = [0u8; DATA_CHUNK + 1];
+		buffer[0] = CONTROL_DATA;
+		for chunk in data.chunks(DATA_CHUNK) {
+			buffer[1..=chunk.len()].copy_from_slice(chunk);
+			self.i2c
+				.write(self.address, &buffer[..=chunk.len()])
+				.await?;
+		}
+		Ok(())
+	}
+}
+
+/// Something drawn on the display, as set in the profile. Pages and columns count in
+/// characters; anything past the edge of the screen is cut off.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub enum DisplayWidget {
+	Text {
+		page: u8,
+		column: u8,
+		text: String,
+	},
+	/// Shows the label while a tag is active.
+	TagIndicator {
+		page: u8,
+		column: u8,
+		tag: LayerTag,
+		label: String,
+	},
+	/// Shows the label followed by how often a key was pressed, or all keys without one.
+	KeyCounter {
+		page: u8,
+		column: u8,
+		key: Option<KeyId>,
+		label: String,
+	},
+	ProfileName {
+		page: u8,
+		column: u8,
+	},
+	/// Lists the active tags, wrapping over as many pages as given.
+	ActiveTags {
+		page: u8,
+		pages: u8,
+	},
+	/// Names the lock LEDs the host has on.
+	LockState {
+		page: u8,
+		column: u8,
+	},
+}
+
+impl DisplayWidget {
+	/// What the display shows for profiles that don't set any widgets.
+	pub fn default_layout() -> Vec<DisplayWidget> {
+		vec![
+			DisplayWidget::ProfileName { page: 0, column: 0 },
+			DisplayWidget::ActiveTags {
+				page: 2,
+				pages: PAGES as u8 - 3,
+			},
+			DisplayWidget::LockState {
+				page: PAGES as u8 - 1,
+				column: 0,
+			},
+		]
+	}
+
+	fn draw(&self, status: &DisplayStatus, frame: &mut FrameBuffer) {
+		match self {
+			DisplayWidget::Text { page, column, text } => {
+				frame.draw_text(*page as usize, *column as usize, text);
+			}
+			DisplayWidget::TagIndicator {
+				page,
+				column,
+				tag,
+				label,
+			} => {
+				if status.tags.contains(tag) {
+					frame.draw_text(*page as usize, *column as usize, label);
+				}
+			}
+			DisplayWidget::KeyCounter {
+				page,
+				column,
+				key,
+				label,
+			} => {
+				let count = status
+					.key_counts
+					.iter()
+					.find(|(counted, _)| counted == key)
+					.map_or(0, |(_, count)| *count);
+				let column = frame.draw_text(*page as usize, *column as usize, label);
+				frame.draw_text(*page as usize, column, &format!("{}", count));
+			}
+			DisplayWidget::ProfileName { page, column } => {
+				let name = if status.profile_name.is_empty() {
+					"NO PROFILE"
+				} else {
+					status.profile_name.as_str()
+				};
+				frame.draw_text(*page as usize, *column as usize, name);
+			}
+			DisplayWidget::ActiveTags { page, pages } => {
+				// tags wrap onto the next page rather than splitting, unless one is a page long
+				// anyway
+				let end = *page as usize + *pages as usize;
+				let mut page = *page as usize;
+				let mut column = 0;
+				for tag in status.tags.iter().map(LayerTag::as_str) {
+					let len = tag.chars().count();
+					if column > 0 && column + len > COLUMNS {
+						page += 1;
+						column = 0;
+					}
+					if page >= end {
+						break;
+					}
+					column = frame.draw_text(page, column, tag) + 1;
+				}
+			}
+			DisplayWidget::LockState { page, column } => {
+				let mut column = *column as usize;
+				for (lock, name) in [
+					(LockState::NUM_LOCK, "NUM"),
+					(LockState::CAPS_LOCK, "CAPS"),
+					(LockState::SCROLL_LOCK, "SCROLL"),
+				] {
+					if status.locks.contains(lock) {
+						column = frame.draw_text(*page as usize, column, name) + 1;
+					}
+				}
+			}
+		}
+	}
+}
+
+impl Readable for DisplayWidget {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let discriminator = reader
+			.read_u8()
+			.await
+			.ok_or("Failed to read display widget")?;
+		// every widget starts with its page, then a column or page count
+		let page = reader
+			.read_u8()
+			.await
+			.ok_or("Failed to read widget position")?;
+		let column = reader
+			.read_u8()
+			.await
+			.ok_or("Failed to read widget position")?;
+		let widget = match discriminator {
+			0 => DisplayWidget::Text {
+				page,
+				column,
+				text: read_widget_text(reader).await?,
+			},
+			1 => DisplayWidget::TagIndicator {
+				page,
+				column,
+				tag: LayerTag::read_from(reader).await?,
+				label: read_widget_text(reader).await?,
+			},
+			2 => DisplayWidget::KeyCounter {
+				page,
+				column,
+				key: reader
+					.read_option()
+					.await
+					.ok_or("Failed to read counted key")?,
+				label: read_widget_text(reader).await?,
+			},
+			3 => DisplayWidget::ProfileName { page, column },
+			4 => DisplayWidget::ActiveTags {
+				page,
+				pages: column,
+			},
+			5 => DisplayWidget::LockState { page, column },
+			_ => return Err("Invalid display widget"),
+		};
+		Ok(widget)
+	}
+}
+
+async fn read_widget_text<R: ReadAsync>(reader: &mut R) -> Result<String, &'static str> {
+	reader
+		.read_string_u8()
+		.await
+		.ok_or("Failed to read widget text")
+}
+
+/// What the display shows, sent by the keypad task whenever it changes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DisplayStatus {
+	pub profile_name: String,
+	pub tags: Vec<LayerTag>,
+	pub locks: LockState,
+	pub widgets: Vec<DisplayWidget>,
+	/// Press counts for the key counter widgets, with `None` for all keys.
+	pub key_counts: Vec<(Option<KeyId>, u32)>,
+}
+
+impl DisplayStatus {
+	pub fn render(&self, frame: &mut FrameBuffer) {
+		frame.clear();
+		for widget in self.widgets.iter() {
+			widget.draw(self, frame);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use alloc::string::ToString;
+	use uuid::Uuid;
+
+	#[derive(Default)]
+	struct MockI2c {
+		writes: Vec<(u8, Vec<u8>)>,
+	}
+
+	impl I2cBus for MockI2c {
+		async fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), &'static str> {
+			self.writes.push((address, bytes.to_vec()));
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn text_is_drawn_uppercase_and_clipped_at_the_edge() {
+		let mut frame = FrameBuffer::new();
+
+		assert_eq!(frame.draw_text(1, 0, "i"), 1);
+		assert_eq!(&frame.page(1)[..4], &[0x22, 0x3e, 0x22, 0x00]);
+
+		let long = "X".repeat(COLUMNS + 4);
+		assert_eq!(frame.draw_text(2, 0, &long), COLUMNS);
+	}
+
+	#[test]
+	fn status_shows_profile_tags_and_locks() {
+		let status = DisplayStatus {
+			profile_name: "".to_string(),
+			tags: vec![LayerTag::new("fn".to_string())],
+			locks: LockState::CAPS_LOCK,
+			widgets: DisplayWidget::default_layout(),
+			key_counts: vec![],
+		};
+		let mut frame = FrameBuffer::new();
+		status.render(&mut frame);
+
+		let mut expected = FrameBuffer::new();
+		expected.draw_text(0, 0, "NO PROFILE");
+		expected.draw_text(2, 0, "FN");
+		expected.draw_text(PAGES - 1, 0, "CAPS");
+		assert_eq!(frame.pixels, expected.pixels);
+	}
+
+	#[test]
+	fn widgets_draw_tag_indicators_and_key_counts() {
+		let key = KeyId::new(Uuid::from_u128(1));
+		let caps = LayerTag::new("caps".to_string());
+		let status = DisplayStatus {
+			tags: vec![caps.clone()],
+			widgets: vec![
+				DisplayWidget::TagIndicator {
+					page: 0,
+					column: 0,
+					tag: caps,
+					label: "CAPS".to_string(),
+				},
+				DisplayWidget::TagIndicator {
+					page: 0,
+					column: 8,
+					tag: LayerTag::new("fn".to_string()),
+					label: "FN".to_string(),
+				},
+				DisplayWidget::KeyCounter {
+					page: 1,
+					column: 0,
+					key: Some(key),
+					label: "A:".to_string(),
+				},
+			],
+			key_counts: vec![(Some(key), 42)],
+			..DisplayStatus::default()
+		};
+		let mut frame = FrameBuffer::new();
+		status.render(&mut frame);
+
+		let mut expected = FrameBuffer::new();
+		expected.draw_text(0, 0, "CAPS");
+		expected.draw_text(1, 0, "A:42");
+		assert_eq!(frame.pixels, expected.pixels);
+	}
+
+	#[tokio::test]
+	async fn widgets_read_their_position_then_their_fields() {
+		let bytes = [2, 3, 4, 0, 2, b'N', b':'];
+		let widget = DisplayWidget::read_from(&mut &bytes[..]).await.unwrap();
+
+		assert_eq!(
+			widget,
+			DisplayWidget::KeyCounter {
+				page: 3,
+				column: 4,
+				key: None,
+				label: "N:".to_string(),
+			}
+		);
+	}
+
+	#[tokio::test]
+	async fn sh1106_frames_are_drawn_page_by_page_from_column_two() {
+		let mut display = OledDisplay::new(MockI2c::default(), 0x3C, DisplayController::Sh1106);
+		display.draw(&FrameBuffer::new()).await.unwrap();
+
+		let writes = &display.i2c.writes;
+		// a page address and four chunks of data per page
+		assert_eq!(writes.len(), PAGES * (1 + WIDTH / DATA_CHUNK));
+		assert_eq!(writes[0], (0x3C, vec![CONTROL_COMMAND, 0xB0, 0x02, 0x10]));
+		assert!(
+			writes[1..5]
+				.iter()
+				.all(|(_, bytes)| bytes[0] == CONTROL_DATA)
+		);
+		assert_eq!(writes[5].1, vec![CONTROL_COMMAND, 0xB1, 0x02, 0x10]);
+	}
+}
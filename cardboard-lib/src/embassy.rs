@@ -1,27 +1,42 @@
 use alloc::vec::Vec;
-use defmt::error;
-use embassy_futures::select::{Either, select};
-use embassy_rp::gpio::{Input, Output};
-use embassy_rp::peripherals::USB;
-use embassy_rp::usb::Driver;
-use embassy_rp::{
-	flash::{Async, ERASE_SIZE, Flash, WRITE_SIZE},
-	peripherals::FLASH,
-};
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use embassy_sync::{blocking_mutex::raw::RawMutex, signal::Signal};
+use embassy_sync::{
+	blocking_mutex::raw::RawMutex,
+	channel::{Channel, TrySendError},
+	signal::Signal,
+};
 use embassy_time::Timer;
 use embassy_usb::class::cdc_acm::{Receiver, Sender};
-
-use crate::context::{ExternalTagsSignalRx, VirtualKeySignalTx};
+use embassy_usb::class::hid::HidWriter;
+use embassy_usb::driver::{Driver, EndpointError};
+use fugit::ExtU64;
+
+use crate::buzzer::Tone;
+use crate::context::{
+	ActiveTagsSignalRx, ActiveTagsSignalTx, DisplaySignalRx, DisplaySignalTx, ExternalTagsSignalRx,
+	HapticSignalRx, HapticSignalTx, InjectKeySignalRx, InjectKeySignalTx, KeyEventSignalRx,
+	KeyEventSignalTx, KeyStatsSignalRx, KeyStatsSignalTx, KeypadErrorSignalRx, KeypadErrorSignalTx,
+	KeypadStatusSignalRx, KeypadStatusSignalTx, LightingSignalRx, LightingSignalTx,
+	MacroSpeedSignalRx, MacroSpeedSignalTx, MatrixScanSignalRx, MatrixScanSignalTx, ToneSignalRx,
+	ToneSignalTx, VirtualKeyIdSignalRx, VirtualKeyIdSignalTx, VirtualKeySignalTx,
+};
+use crate::display::DisplayStatus;
+use crate::error::Error;
+use crate::event::KeyEvent;
+use crate::haptic::HapticPattern;
 use crate::hid::{HidDevice, HidReport, ReportHid};
+use crate::input::{KeyboardAction, RawMatrixScan, VirtualKeyAction};
+use crate::lighting::LightingEvent;
 use crate::profile::{ConsumerControlEvent, KeyboardEvent, MouseEvent};
-use crate::serial::{SerialDrain, SerialPacketReader, SerialPacketSender};
-use crate::storage::{BlockFlash, FlashPartition, PartitionedFlashMemory};
+use crate::serial::{SerialPacketReader, SerialPacketSender, SerialSession};
+use crate::state::{ActiveTags, KeyStats, KeypadStatus};
+use crate::stats::UsbStats;
+use crate::supervisor::Heartbeat;
 use crate::time::{Clock, Duration};
+use crate::transport::HidTransport;
 use crate::{
 	context::{ExternalTagsSignalTx, UpdateProfileSignalRx, UpdateProfileSignalTx},
-	input::{ColPin, RowPin},
 	profile::{KeyboardProfile, LayerTag},
 };
 
@@ -63,181 +78,371 @@ impl<M: RawMutex, const SIZE: usize> crate::context::VirtualKeySignalRx<SIZE>
 	}
 }
 
-impl RowPin for Output<'_> {
-	fn set_high(&mut self) {
-		self.set_high();
+impl<M: RawMutex> MacroSpeedSignalTx for Signal<M, u16> {
+	fn set_macro_speed(&self, percent: u16) {
+		self.signal(percent);
+	}
+}
+
+impl<M: RawMutex> MacroSpeedSignalRx for Signal<M, u16> {
+	fn try_get_macro_speed(&self) -> Option<u16> {
+		self.try_take()
+	}
+}
+
+impl<M: RawMutex, const N: usize> KeypadErrorSignalTx for Channel<M, Error, N> {
+	fn report_error(&self, error: Error) {
+		// the keypad can't wait on the command task; if the queue is full the oldest error makes
+		// room, so the latest always reaches the log and the host hears about it
+		if let Err(TrySendError::Full(error)) = self.try_send(error) {
+			let _ = self.try_receive();
+			let _ = self.try_send(error);
+		}
+	}
+}
+
+impl<M: RawMutex, const N: usize> KeypadErrorSignalRx for Channel<M, Error, N> {
+	fn try_take_keypad_error(&self) -> Option<Error> {
+		self.try_receive().ok()
+	}
+}
+
+impl<M: RawMutex, const N: usize> LightingSignalTx for Channel<M, LightingEvent, N> {
+	fn send_lighting_event(&self, event: LightingEvent) {
+		// lighting is cosmetic, so the keypad drops events rather than wait for the LEDs
+		let _ = self.try_send(event);
+	}
+}
+
+impl<M: RawMutex, const N: usize> LightingSignalRx for Channel<M, LightingEvent, N> {
+	fn try_take_lighting_event(&self) -> Option<LightingEvent> {
+		self.try_receive().ok()
+	}
+}
+
+impl<M: RawMutex, const N: usize> ToneSignalTx for Channel<M, Tone, N> {
+	fn send_tone(&self, tone: Tone) {
+		// a full queue means a macro is beeping faster than the buzzer plays; drop the tone
+		let _ = self.try_send(tone);
+	}
+}
+
+impl<M: RawMutex, const N: usize> ToneSignalRx for Channel<M, Tone, N> {
+	fn try_take_tone(&self) -> Option<Tone> {
+		self.try_receive().ok()
+	}
+}
+
+impl<M: RawMutex, const N: usize> HapticSignalTx for Channel<M, HapticPattern, N> {
+	fn send_haptic(&self, pattern: HapticPattern) {
+		// patterns are short, so dropping one when the queue is full loses little
+		let _ = self.try_send(pattern);
+	}
+}
+
+impl<M: RawMutex, const N: usize> HapticSignalRx for Channel<M, HapticPattern, N> {
+	fn try_take_haptic(&self) -> Option<HapticPattern> {
+		self.try_receive().ok()
+	}
+}
+
+impl<M: RawMutex> DisplaySignalTx for Signal<M, DisplayStatus> {
+	fn send_display_status(&self, status: DisplayStatus) {
+		// only the latest status matters
+		self.signal(status);
+	}
+}
+
+impl<M: RawMutex> DisplaySignalRx for Signal<M, DisplayStatus> {
+	fn try_take_display_status(&self) -> Option<DisplayStatus> {
+		self.try_take()
+	}
+}
+
+impl<M: RawMutex, const N: usize> InjectKeySignalTx for Channel<M, KeyboardAction, N> {
+	fn inject_key(&self, action: KeyboardAction) -> bool {
+		self.try_send(action).is_ok()
 	}
+}
 
-	fn set_low(&mut self) {
-		self.set_low();
+impl<M: RawMutex, const N: usize> InjectKeySignalRx for Channel<M, KeyboardAction, N> {
+	fn try_take_injected_key(&self) -> Option<KeyboardAction> {
+		self.try_receive().ok()
 	}
 }
 
-impl ColPin for Input<'_> {
-	fn is_high(&self) -> bool {
-		self.is_high()
+impl<M: RawMutex, const N: usize> VirtualKeyIdSignalTx for Channel<M, VirtualKeyAction, N> {
+	fn set_virtual_key(&self, action: VirtualKeyAction) -> bool {
+		self.try_send(action).is_ok()
 	}
 }
 
-pub struct EmbassySerialPacketReader<'d, const SIZE: usize> {
-	receiver: Receiver<'d, Driver<'d, USB>>,
-	timeout: embassy_time::Duration,
+impl<M: RawMutex, const N: usize> VirtualKeyIdSignalRx for Channel<M, VirtualKeyAction, N> {
+	fn try_take_virtual_key(&self) -> Option<VirtualKeyAction> {
+		self.try_receive().ok()
+	}
 }
 
-pub struct EmbassySerialPacketWriter<'d, const SIZE: usize> {
-	sender: Sender<'d, Driver<'d, USB>>,
-	timeout: embassy_time::Duration,
+/// Buffers key events for the command task while the host is subscribed to them.
+pub struct EmbassyKeyEventChannel<M: RawMutex, const N: usize> {
+	subscribed: AtomicBool,
+	channel: Channel<M, KeyEvent, N>,
 }
 
-impl<'d, const SIZE: usize> EmbassySerialPacketReader<'d, SIZE> {
-	pub fn new(receiver: Receiver<'d, Driver<'d, USB>>, timeout: crate::time::Duration) -> Self {
+impl<M: RawMutex, const N: usize> EmbassyKeyEventChannel<M, N> {
+	pub const fn new() -> Self {
 		Self {
-			receiver,
-			timeout: embassy_time::Duration::from_millis(timeout.to_millis() as u64),
+			subscribed: AtomicBool::new(false),
+			channel: Channel::new(),
 		}
 	}
 }
 
-impl<'d, const SIZE: usize> EmbassySerialPacketWriter<'d, SIZE> {
-	pub fn new(sender: Sender<'d, Driver<'d, USB>>, timeout: crate::time::Duration) -> Self {
+impl<M: RawMutex, const N: usize> KeyEventSignalTx for EmbassyKeyEventChannel<M, N> {
+	fn key_events_subscribed(&self) -> bool {
+		self.subscribed.load(Ordering::Relaxed)
+	}
+
+	fn send_key_event(&self, event: KeyEvent) {
+		// if the host isn't keeping up, drop the event rather than stall the keypad
+		let _ = self.channel.try_send(event);
+	}
+}
+
+impl<M: RawMutex, const N: usize> KeyEventSignalRx for EmbassyKeyEventChannel<M, N> {
+	fn set_key_events_subscribed(&self, subscribed: bool) {
+		self.subscribed.store(subscribed, Ordering::Relaxed);
+		if !subscribed {
+			self.channel.clear();
+		}
+	}
+
+	fn try_take_key_event(&self) -> Option<KeyEvent> {
+		self.channel.try_receive().ok()
+	}
+}
+
+/// Hands a one-off request to the keypad task and its result back to the command task.
+pub struct EmbassyRequestSignal<M: RawMutex, T> {
+	requested: AtomicBool,
+	result: Signal<M, T>,
+}
+
+impl<M: RawMutex, T> EmbassyRequestSignal<M, T> {
+	pub const fn new() -> Self {
 		Self {
-			sender,
-			timeout: embassy_time::Duration::from_millis(timeout.to_millis() as u64),
+			requested: AtomicBool::new(false),
+			result: Signal::new(),
 		}
 	}
+
+	fn is_requested(&self) -> bool {
+		self.requested.load(Ordering::Relaxed)
+	}
+
+	fn respond(&self, value: T) {
+		self.requested.store(false, Ordering::Relaxed);
+		self.result.signal(value);
+	}
+
+	fn request(&self) {
+		// throw away any result left over from a request that timed out
+		self.result.reset();
+		self.requested.store(true, Ordering::Relaxed);
+	}
+
+	fn try_take(&self) -> Option<T> {
+		self.result.try_take()
+	}
 }
 
-impl<'d, const SIZE: usize> SerialPacketReader for EmbassySerialPacketReader<'d, SIZE> {
-	async fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
-		let timer = Timer::after(self.timeout);
+impl<M: RawMutex> MatrixScanSignalTx for EmbassyRequestSignal<M, RawMatrixScan> {
+	fn matrix_scan_requested(&self) -> bool {
+		self.is_requested()
+	}
 
-		let result = select(self.receiver.read_packet(buf), async { timer.await }).await;
+	fn send_matrix_scan(&self, scan: RawMatrixScan) {
+		self.respond(scan);
+	}
+}
 
-		match result {
-			Either::First(result) => result.map_err(|_| "Endpoint error"),
-			Either::Second(_) => Err("Read timeout"),
-		}
+impl<M: RawMutex> MatrixScanSignalRx for EmbassyRequestSignal<M, RawMatrixScan> {
+	fn request_matrix_scan(&self) {
+		self.request();
 	}
 
-	const SIZE: usize = SIZE;
+	fn try_take_matrix_scan(&self) -> Option<RawMatrixScan> {
+		self.try_take()
+	}
 }
 
-impl<'d, const SIZE: usize> SerialDrain for EmbassySerialPacketReader<'d, SIZE> {
-	async fn drop_packet(&mut self) -> bool {
-		let mut buf = [0u8; SIZE];
-		self.read_packet(&mut buf).await.is_ok()
+impl<M: RawMutex> ActiveTagsSignalTx for EmbassyRequestSignal<M, ActiveTags> {
+	fn active_tags_requested(&self) -> bool {
+		self.is_requested()
+	}
+
+	fn send_active_tags(&self, tags: ActiveTags) {
+		self.respond(tags);
 	}
 }
 
-impl<'d, const SIZE: usize> SerialPacketSender for EmbassySerialPacketWriter<'d, SIZE> {
-	async fn write_packet(&mut self, data: &[u8]) -> Result<(), &'static str> {
-		let timer = Timer::after(self.timeout);
-		let result =
-			embassy_futures::select::select(self.sender.write_packet(data), async { timer.await })
-				.await;
+impl<M: RawMutex> ActiveTagsSignalRx for EmbassyRequestSignal<M, ActiveTags> {
+	fn request_active_tags(&self) {
+		self.request();
+	}
 
-		match result {
-			embassy_futures::select::Either::First(result) => result.map_err(|_| "Endpoint error"),
-			embassy_futures::select::Either::Second(_) => Err("Write timeout"),
-		}
+	fn try_take_active_tags(&self) -> Option<ActiveTags> {
+		self.try_take()
 	}
-	const SIZE: usize = SIZE;
 }
 
-pub struct EmbassyFlashMemory<'d, const SIZE: usize> {
-	flash_addr: *const u8,
-	storage_addr: *const u8,
-	length: usize,
-	flash: Flash<'d, FLASH, Async, SIZE>,
+impl<M: RawMutex> KeypadStatusSignalTx for EmbassyRequestSignal<M, KeypadStatus> {
+	fn keypad_status_requested(&self) -> bool {
+		self.is_requested()
+	}
+
+	fn send_keypad_status(&self, status: KeypadStatus) {
+		self.respond(status);
+	}
 }
 
-impl<'d, const SIZE: usize> EmbassyFlashMemory<'d, SIZE> {
-	pub fn new(
-		flash_addr: *const u8,
-		storage_addr: *const u8,
-		length: usize,
-		flash: Flash<'d, FLASH, Async, SIZE>,
-	) -> Self {
-		if storage_addr as usize % WRITE_SIZE != 0 {
-			error!(
-				"Base address is not write block aligned: {}",
-				storage_addr as usize
-			);
-			panic!("Base address is not write block aligned");
-		}
+impl<M: RawMutex> KeypadStatusSignalRx for EmbassyRequestSignal<M, KeypadStatus> {
+	fn request_keypad_status(&self) {
+		self.request();
+	}
 
-		if storage_addr as usize % ERASE_SIZE != 0 {
-			error!(
-				"Base address is not erase block aligned: {}",
-				storage_addr as usize
-			);
-			panic!("Base address is not erase block aligned");
-		}
+	fn try_take_keypad_status(&self) -> Option<KeypadStatus> {
+		self.try_take()
+	}
+}
 
-		if length % WRITE_SIZE != 0 {
-			error!("Length is not block aligned: {}", length);
-			panic!("Length is not block aligned");
-		}
+/// Answers key stats requests and separately carries the stats the keypad task wants saved, so
+/// a save never gets taken as a host request's answer.
+pub struct EmbassyKeyStatsSignal<M: RawMutex> {
+	request: EmbassyRequestSignal<M, KeyStats>,
+	save: Signal<M, KeyStats>,
+}
 
-		if length % ERASE_SIZE != 0 {
-			error!("Length is not erase block aligned: {}", length);
-			panic!("Length is not erase block aligned");
+impl<M: RawMutex> EmbassyKeyStatsSignal<M> {
+	pub const fn new() -> Self {
+		Self {
+			request: EmbassyRequestSignal::new(),
+			save: Signal::new(),
 		}
+	}
+}
 
-		EmbassyFlashMemory {
-			flash_addr,
-			storage_addr,
-			length,
-			flash,
-		}
+impl<M: RawMutex> KeyStatsSignalTx for EmbassyKeyStatsSignal<M> {
+	fn key_stats_requested(&self) -> bool {
+		self.request.is_requested()
+	}
+
+	fn send_key_stats(&self, stats: KeyStats) {
+		self.request.respond(stats);
 	}
 
-	fn get_flash_offset(&self) -> usize {
-		self.storage_addr as usize - self.flash_addr as usize
+	fn save_key_stats(&self, stats: KeyStats) {
+		self.save.signal(stats);
 	}
 }
 
-impl<'a, const SIZE: usize> BlockFlash for EmbassyFlashMemory<'a, SIZE> {
-	fn as_slice(&self) -> &'static [u8] {
-		unsafe { core::slice::from_raw_parts(self.storage_addr, self.length) }
+impl<M: RawMutex> KeyStatsSignalRx for EmbassyKeyStatsSignal<M> {
+	fn request_key_stats(&self) {
+		self.request.request();
+	}
+
+	fn try_take_key_stats(&self) -> Option<KeyStats> {
+		self.request.try_take()
 	}
 
-	fn erase(&mut self, offset: usize, length: usize) -> Result<(), &'static str> {
-		let start = offset + self.get_flash_offset();
-		let end = start + length;
+	fn try_take_key_stats_to_save(&self) -> Option<KeyStats> {
+		self.save.try_take()
+	}
+}
+
+pub struct EmbassySerialPacketReader<'d, D: Driver<'d>, const SIZE: usize> {
+	receiver: Receiver<'d, D>,
+	timeout: Duration,
+	/// Whether the host had the port open (DTR set) at the last read.
+	open: bool,
+	session_ended: bool,
+}
+
+pub struct EmbassySerialPacketWriter<'d, D: Driver<'d>, const SIZE: usize> {
+	sender: Sender<'d, D>,
+	timeout: Duration,
+}
 
-		self.flash
-			.blocking_erase(start as u32, end as u32)
-			.map_err(|e| {
-				error!("Error erasing flash memory: {:?}", e);
-				match e {
-					embassy_rp::flash::Error::OutOfBounds => "Erase out of bounds",
-					embassy_rp::flash::Error::Unaligned => "Erase not block aligned",
-					_ => "Error erasing flash memory",
-				}
-			})
+impl<'d, D: Driver<'d>, const SIZE: usize> EmbassySerialPacketReader<'d, D, SIZE> {
+	pub fn new(receiver: Receiver<'d, D>, timeout: crate::time::Duration) -> Self {
+		Self {
+			receiver,
+			timeout,
+			open: false,
+			session_ended: false,
+		}
 	}
 
-	fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), &'static str> {
-		self.flash
-			.blocking_write((self.get_flash_offset() + offset) as u32, data)
-			.map_err(|e| {
-				error!("Error writing to flash memory: {:?}", e);
-				match e {
-					embassy_rp::flash::Error::OutOfBounds => "Write out of bounds",
-					embassy_rp::flash::Error::Unaligned => "Write not block aligned",
-					_ => "Error writing to flash memory",
-				}
-			})
+	// reads time out regularly, so a host closing the port is noticed even while it's quiet
+	fn check_open(&mut self) {
+		let open = self.receiver.dtr();
+		if self.open && !open {
+			self.session_ended = true;
+		}
+		self.open = open;
 	}
+}
 
-	fn length(&self) -> usize {
-		self.length
+impl<'d, D: Driver<'d>, const SIZE: usize> EmbassySerialPacketWriter<'d, D, SIZE> {
+	pub fn new(sender: Sender<'d, D>, timeout: crate::time::Duration) -> Self {
+		Self { sender, timeout }
 	}
+}
+
+impl<'d, D: Driver<'d>, const SIZE: usize> SerialPacketReader
+	for EmbassySerialPacketReader<'d, D, SIZE>
+{
+	async fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+		let result = EmbassyTickClock {}
+			.timeout(self.timeout, self.receiver.read_packet(buf))
+			.await;
+		self.check_open();
+
+		match result {
+			Ok(Ok(length)) => Ok(length),
+			Ok(Err(EndpointError::Disabled)) => {
+				// unplugged, or reset by the host
+				self.session_ended = true;
+				Err("Endpoint disabled")
+			}
+			Ok(Err(_)) => Err("Endpoint error"),
+			Err(_) => Err("Read timeout"),
+		}
+	}
+
+	const SIZE: usize = SIZE;
+}
 
-	const ERASE_BLOCK_SIZE: usize = ERASE_SIZE;
+impl<'d, D: Driver<'d>, const SIZE: usize> SerialSession
+	for EmbassySerialPacketReader<'d, D, SIZE>
+{
+	fn take_session_ended(&mut self) -> bool {
+		self.check_open();
+		core::mem::take(&mut self.session_ended)
+	}
+}
 
-	const WRITE_BLOCK_SIZE: usize = WRITE_SIZE;
+impl<'d, D: Driver<'d>, const SIZE: usize> SerialPacketSender
+	for EmbassySerialPacketWriter<'d, D, SIZE>
+{
+	async fn write_packet(&mut self, data: &[u8]) -> Result<(), &'static str> {
+		EmbassyTickClock {}
+			.timeout(self.timeout, self.sender.write_packet(data))
+			.await
+			.map_err(|_| "Write timeout")?
+			.map_err(|_| "Endpoint error")
+	}
+	const SIZE: usize = SIZE;
 }
 
 pub struct EmbassyTickClock {}
@@ -306,6 +511,10 @@ where
 		self.mouse.input(report);
 	}
 
+	fn report_motion(&mut self, x: i32, y: i32) {
+		self.mouse.add_motion(x, y);
+	}
+
 	fn report_consumer(&mut self, report: &crate::profile::ConsumerControlEvent) {
 		self.consumer.input(report);
 	}
@@ -334,3 +543,145 @@ where
 		});
 	}
 }
+
+/// How often the HID task beats while no reports come in.
+const HID_HEARTBEAT_INTERVAL_MS: u64 = 500;
+
+/// A report the host won't take within this long is dropped, so a host that has stopped
+/// reading doesn't look like a hung task.
+const HID_SEND_TIMEOUT_MS: u64 = 5000;
+
+/// Delivers the reports [`EmbassyKeypadHid`] signals through a transport, so each firmware
+/// target only builds its transport instead of its own task.
+pub async fn hid_task<
+	M: RawMutex,
+	Transport: HidTransport<SIZE_K, SIZE_M, SIZE_C>,
+	const SIZE_K: usize,
+	const SIZE_M: usize,
+	const SIZE_C: usize,
+>(
+	signal: &'static Signal<M, HidReport<SIZE_K, SIZE_M, SIZE_C>>,
+	mut transport: Transport,
+	heartbeat: &'static Heartbeat,
+) {
+	info!("HID task started.");
+
+	let clock = EmbassyTickClock {};
+
+	loop {
+		heartbeat.beat(clock.now());
+		let report = match clock
+			.timeout(HID_HEARTBEAT_INTERVAL_MS.millis(), signal.wait())
+			.await
+		{
+			Ok(report) => report,
+			Err(_) => continue,
+		};
+
+		// also bounds the wait for the host to set up the endpoints
+		match clock
+			.timeout(HID_SEND_TIMEOUT_MS.millis(), transport.send(&report))
+			.await
+		{
+			Ok(Ok(())) => {}
+			Ok(Err(e)) => warn!("Error sending HID report: {}", e),
+			Err(_) => warn!("Timed out sending HID report"),
+		}
+	}
+}
+
+/// The USB HID endpoints, as a transport for the HID task. The mouse is optional, for
+/// devices with it turned off in settings.
+pub struct UsbHidTransport<
+	D: Driver<'static>,
+	const KEYBOARD_PACKET_SIZE: usize,
+	const MOUSE_PACKET_SIZE: usize,
+	const CONSUMER_PACKET_SIZE: usize,
+> {
+	keyboard: HidWriter<'static, D, KEYBOARD_PACKET_SIZE>,
+	mouse: Option<HidWriter<'static, D, MOUSE_PACKET_SIZE>>,
+	consumer: HidWriter<'static, D, CONSUMER_PACKET_SIZE>,
+	stats: &'static UsbStats,
+	ready: bool,
+}
+
+impl<
+	D: Driver<'static>,
+	const KEYBOARD_PACKET_SIZE: usize,
+	const MOUSE_PACKET_SIZE: usize,
+	const CONSUMER_PACKET_SIZE: usize,
+> UsbHidTransport<D, KEYBOARD_PACKET_SIZE, MOUSE_PACKET_SIZE, CONSUMER_PACKET_SIZE>
+{
+	pub fn new(
+		keyboard: HidWriter<'static, D, KEYBOARD_PACKET_SIZE>,
+		mouse: Option<HidWriter<'static, D, MOUSE_PACKET_SIZE>>,
+		consumer: HidWriter<'static, D, CONSUMER_PACKET_SIZE>,
+		stats: &'static UsbStats,
+	) -> Self {
+		Self {
+			keyboard,
+			mouse,
+			consumer,
+			stats,
+			ready: false,
+		}
+	}
+
+	async fn wait_ready(&mut self) {
+		Timer::after_secs(1).await;
+		self.keyboard.ready().await;
+		if let Some(mouse) = &mut self.mouse {
+			mouse.ready().await;
+		}
+		self.consumer.ready().await;
+
+		info!("HID ready.");
+		self.ready = true;
+	}
+}
+
+impl<
+	D: Driver<'static>,
+	const KEYBOARD_PACKET_SIZE: usize,
+	const MOUSE_PACKET_SIZE: usize,
+	const CONSUMER_PACKET_SIZE: usize,
+> HidTransport<KEYBOARD_PACKET_SIZE, MOUSE_PACKET_SIZE, CONSUMER_PACKET_SIZE>
+	for UsbHidTransport<D, KEYBOARD_PACKET_SIZE, MOUSE_PACKET_SIZE, CONSUMER_PACKET_SIZE>
+{
+	async fn send(
+		&mut self,
+		report: &HidReport<KEYBOARD_PACKET_SIZE, MOUSE_PACKET_SIZE, CONSUMER_PACKET_SIZE>,
+	) -> Result<(), &'static str> {
+		if !self.ready {
+			self.wait_ready().await;
+		}
+
+		// every endpoint gets its report even if an earlier one fails
+		let mut result = Ok(());
+		if let Some(keyboard_report) = &report.keyboard {
+			let written = self.keyboard.write(&keyboard_report[..]).await;
+			self.stats.hid_report_written(written.is_ok());
+			if let Err(e) = written {
+				warn!("Error writing keyboard report: {:?}", e);
+				result = Err("Keyboard report not written");
+			}
+		}
+		if let (Some(mouse), Some(mouse_report)) = (&mut self.mouse, &report.mouse) {
+			let written = mouse.write(&mouse_report[..]).await;
+			self.stats.hid_report_written(written.is_ok());
+			if let Err(e) = written {
+				warn!("Error writing mouse report: {:?}", e);
+				result = Err("Mouse report not written");
+			}
+		}
+		if let Some(consumer_report) = &report.consumer {
+			let written = self.consumer.write(&consumer_report[..]).await;
+			self.stats.hid_report_written(written.is_ok());
+			if let Err(e) = written {
+				warn!("Error writing consumer report: {:?}", e);
+				result = Err("Consumer report not written");
+			}
+		}
+		result
+	}
+}
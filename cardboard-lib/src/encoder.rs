@@ -0,0 +1,323 @@
+use crate::profile::{LayerIndex, LayerMask, MouseScroll};
+use crate::serialize::Readable;
+use crate::stream::{ReadAsync, ReadAsyncExt};
+use crate::time::Duration;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The two phase pins of a quadrature rotary encoder.
+pub trait EncoderPins {
+	/// Levels of the A and B phases.
+	fn read(&self) -> (bool, bool);
+}
+
+/// Quarter steps for a move from the previous A/B state (high bits) to the next. Moves that
+/// skipped a state can't be told apart and count as nothing.
+const TRANSITIONS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+/// Turning faster than this many detents a second speeds scrolling up.
+const ACCELERATION_THRESHOLD: u64 = 4;
+
+/// Acceleration never scales scrolling past this.
+const MAX_SCALE_PERCENT: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub enum ScrollAxis {
+	Vertical,
+	Horizontal,
+}
+
+impl ScrollAxis {
+	/// `steps` of scrolling along this axis.
+	pub fn scroll(&self, steps: i32) -> MouseScroll {
+		match self {
+			ScrollAxis::Vertical => MouseScroll { x: 0, y: steps },
+			ScrollAxis::Horizontal => MouseScroll { x: steps, y: 0 },
+		}
+	}
+}
+
+impl Readable for ScrollAxis {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		match reader.read_u8().await.ok_or("Failed to read scroll axis")? {
+			0 => Ok(ScrollAxis::Vertical),
+			1 => Ok(ScrollAxis::Horizontal),
+			_ => Err("Invalid scroll axis"),
+		}
+	}
+}
+
+/// Turns an encoder's detents straight into scrolling while a layer is active, without going
+/// through a macro.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub struct EncoderScroll {
+	/// The encoder's name on the board.
+	pub encoder: String,
+	/// `None` applies when no mapping for an active layer does.
+	pub layer: Option<LayerIndex>,
+	pub axis: ScrollAxis,
+	/// Scroll steps per detent when turning slowly; negative reverses the direction.
+	pub speed: i8,
+	/// Percent added to each detent's scroll for every detent a second above 4.
+	pub acceleration: u8,
+}
+
+impl Readable for EncoderScroll {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let encoder = reader
+			.read_string_u8()
+			.await
+			.ok_or("Failed to read encoder name")?;
+		let layer = reader
+			.read_option()
+			.await
+			.ok_or("Failed to read encoder layer")?;
+		let axis = ScrollAxis::read_from(reader).await?;
+		let speed = reader
+			.read_i8()
+			.await
+			.ok_or("Failed to read scroll speed")?;
+		let acceleration = reader
+			.read_u8()
+			.await
+			.ok_or("Failed to read scroll acceleration")?;
+
+		Ok(Self {
+			encoder,
+			layer,
+			axis,
+			speed,
+			acceleration,
+		})
+	}
+}
+
+impl EncoderScroll {
+	/// The mapping for an encoder on the active layers: the first one for an active layer, or
+	/// else the first without a layer.
+	pub fn find<'m>(
+		mappings: &'m [EncoderScroll],
+		encoder: &str,
+		active_layers: LayerMask,
+	) -> Option<&'m EncoderScroll> {
+		let for_encoder = || mappings.iter().filter(move |m| m.encoder == encoder);
+		for_encoder()
+			.find(|m| {
+				m.layer
+					.is_some_and(|layer| active_layers & (1 << layer.get_index()) != 0)
+			})
+			.or_else(|| for_encoder().find(|m| m.layer.is_none()))
+	}
+
+	/// Percent to scale each detent's scroll by when turning at `detents_per_s`.
+	fn scale_percent(&self, detents_per_s: u64) -> u64 {
+		let extra = self.acceleration as u64 * detents_per_s.saturating_sub(ACCELERATION_THRESHOLD);
+		(100 + extra).min(MAX_SCALE_PERCENT)
+	}
+}
+
+/// A named encoder, sampled every keypad tick.
+pub struct Encoder<P: EncoderPins> {
+	name: String,
+	pins: P,
+	state: u8,
+	steps: i8,
+	steps_per_detent: i8,
+	since_detent: Duration,
+	/// Scroll not yet sent, in hundredths of a step.
+	remainder: i32,
+}
+
+impl<P: EncoderPins> Encoder<P> {
+	/// `steps_per_detent` is how many quadrature transitions there are between clicks, usually
+	/// 4.
+	pub fn new(name: &str, pins: P, steps_per_detent: u8) -> Self {
+		assert!((1..=i8::MAX as u8).contains(&steps_per_detent));
+		let state = Self::sample(&pins);
+		Self {
+			name: name.into(),
+			pins,
+			state,
+			steps: 0,
+			steps_per_detent: steps_per_detent as i8,
+			since_detent: Duration::from_ticks(0),
+			remainder: 0,
+		}
+	}
+
+	fn sample(pins: &P) -> u8 {
+		let (a, b) = pins.read();
+		((a as u8) << 1) | b as u8
+	}
+
+	/// Detents turned since the last call, positive when A leads B, which is clockwise on most
+	/// encoders.
+	fn take_detents(&mut self) -> i32 {
+		let state = Self::sample(&self.pins);
+		self.steps += TRANSITIONS[((self.state << 2) | state) as usize];
+		self.state = state;
+
+		let detents = self.steps / self.steps_per_detent;
+		self.steps %= self.steps_per_detent;
+		detents as i32
+	}
+}
+
+/// The board's encoders, driven by the keypad task.
+pub struct Encoders<P: EncoderPins> {
+	encoders: Vec<Encoder<P>>,
+}
+
+impl<P: EncoderPins> Encoders<P> {
+	pub fn new(encoders: Vec<Encoder<P>>) -> Self {
+		Self { encoders }
+	}
+
+	/// Samples every encoder and reports the scroll its detents make under whichever of
+	/// `mappings` applies. Returns true if any encoder turned.
+	pub fn scroll(
+		&mut self,
+		dt: Duration,
+		mappings: &[EncoderScroll],
+		active_layers: LayerMask,
+		mut report: impl FnMut(MouseScroll),
+	) -> bool {
+		let mut turned = false;
+		for encoder in self.encoders.iter_mut() {
+			encoder.since_detent += dt;
+			let detents = encoder.take_detents();
+			if detents == 0 {
+				continue;
+			}
+			turned = true;
+
+			let since_detent_ms = encoder.since_detent.to_millis().max(1);
+			encoder.since_detent = Duration::from_ticks(0);
+			let Some(mapping) = EncoderScroll::find(mappings, &encoder.name, active_layers) else {
+				encoder.remainder = 0;
+				continue;
+			};
+
+			let detents_per_s = detents.unsigned_abs() as u64 * 1000 / since_detent_ms;
+			let scale = mapping.scale_percent(detents_per_s) as i32;
+			let hundredths = encoder.remainder + detents * mapping.speed as i32 * scale;
+			encoder.remainder = hundredths % 100;
+
+			let steps = hundredths / 100;
+			if steps != 0 {
+				report(mapping.axis.scroll(steps));
+			}
+		}
+		turned
+	}
+
+	/// Drops scroll still owed from the last mappings, e.g. when the profile changes.
+	pub fn reset(&mut self) {
+		for encoder in self.encoders.iter_mut() {
+			encoder.remainder = 0;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use alloc::rc::Rc;
+	use alloc::vec;
+	use core::cell::Cell;
+	use fugit::ExtU64;
+
+	#[derive(Clone, Default)]
+	struct MockPins {
+		levels: Rc<Cell<(bool, bool)>>,
+	}
+
+	impl EncoderPins for MockPins {
+		fn read(&self) -> (bool, bool) {
+			self.levels.get()
+		}
+	}
+
+	fn wheel(layer: Option<u8>, speed: i8, acceleration: u8) -> EncoderScroll {
+		EncoderScroll {
+			encoder: "wheel".into(),
+			layer: layer.map(LayerIndex::new),
+			axis: ScrollAxis::Vertical,
+			speed,
+			acceleration,
+		}
+	}
+
+	/// Steps the pins through one detent, sampling after each transition.
+	fn turn(
+		encoders: &mut Encoders<MockPins>,
+		pins: &MockPins,
+		clockwise: bool,
+		dt: Duration,
+		mappings: &[EncoderScroll],
+		active_layers: LayerMask,
+	) -> Vec<i32> {
+		let mut sequence = [(true, false), (true, true), (false, true), (false, false)];
+		if !clockwise {
+			sequence = [(false, true), (true, true), (true, false), (false, false)];
+		}
+
+		let mut scrolled = Vec::new();
+		for levels in sequence {
+			pins.levels.set(levels);
+			encoders.scroll(dt, mappings, active_layers, |scroll| {
+				scrolled.push(scroll.y)
+			});
+		}
+		scrolled
+	}
+
+	#[test]
+	fn detents_scroll_in_the_turned_direction() {
+		let pins = MockPins::default();
+		let mut encoders = Encoders::new(vec![Encoder::new("wheel", pins.clone(), 4)]);
+		let mappings = [wheel(None, -1, 0)];
+
+		let slow = 100.millis();
+		assert_eq!(turn(&mut encoders, &pins, true, slow, &mappings, 0), [-1]);
+		assert_eq!(turn(&mut encoders, &pins, false, slow, &mappings, 0), [1]);
+	}
+
+	#[test]
+	fn turning_fast_accelerates_scrolling() {
+		let pins = MockPins::default();
+		let mut encoders = Encoders::new(vec![Encoder::new("wheel", pins.clone(), 4)]);
+		let mappings = [wheel(None, 1, 50)];
+
+		// a detent every 250ms is below the threshold
+		assert_eq!(
+			turn(&mut encoders, &pins, true, 62.millis(), &mappings, 0),
+			[1]
+		);
+		// 20 detents a second is 16 over, so 100% + 16 * 50%
+		assert_eq!(
+			turn(&mut encoders, &pins, true, 12.millis(), &mappings, 0),
+			[9]
+		);
+	}
+
+	#[test]
+	fn the_active_layer_picks_the_mapping() {
+		let pins = MockPins::default();
+		let mut encoders = Encoders::new(vec![Encoder::new("wheel", pins.clone(), 4)]);
+		let mappings = [wheel(None, 1, 0), wheel(Some(1), 3, 0)];
+
+		let slow = 100.millis();
+		assert_eq!(turn(&mut encoders, &pins, true, slow, &mappings, 0b01), [1]);
+		assert_eq!(turn(&mut encoders, &pins, true, slow, &mappings, 0b10), [3]);
+		assert!(turn(&mut encoders, &pins, true, slow, &[], 0b10).is_empty());
+	}
+}
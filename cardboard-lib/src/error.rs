@@ -23,6 +23,7 @@ impl Writeable for Error {
 pub trait ErrorLog {
 	fn push(&mut self, error: Error);
 	fn get_errors(&self) -> Self::Iter<'_>;
+	fn clear(&mut self);
 
 	type Iter<'a>: Iterator<Item = &'a Error>
 	where
@@ -54,5 +55,31 @@ impl<const N: usize> ErrorLog for HeaplessSpscErrorLog<N> {
 		self.queue.iter()
 	}
 
+	fn clear(&mut self) {
+		while self.queue.dequeue().is_some() {}
+	}
+
 	type Iter<'a> = Iter<'a, Error>;
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn clear_empties_log() {
+		let mut log = HeaplessSpscErrorLog::<4>::new();
+		log.push(Error {
+			timestamp: Instant::from_ticks(1),
+			message: "first",
+		});
+		log.push(Error {
+			timestamp: Instant::from_ticks(2),
+			message: "second",
+		});
+
+		log.clear();
+
+		assert_eq!(log.get_errors().count(), 0);
+	}
+}
@@ -0,0 +1,249 @@
+use core::cell::Cell;
+
+use bitflags::bitflags;
+use critical_section::Mutex;
+
+use crate::{
+	context::{HostEventSignalRx, HostEventSignalTx},
+	input::{KeyId, KeyState},
+	serial::SerialEventSender,
+	serialize::Writeable,
+	stream::{WriteAsync, WriteAsyncExt},
+	time::Instant,
+};
+
+/// First byte of every event frame, identifying what follows.
+pub const EVENT_KIND_NOTIFICATION: u8 = 0x01;
+pub const EVENT_KIND_KEY: u8 = 0x02;
+pub const EVENT_KIND_PROGRESS: u8 = 0x03;
+pub const EVENT_KIND_CREDIT: u8 = 0x04;
+
+/// Upper bound on the serialized size of any event.
+pub const MAX_EVENT_SIZE: usize = 32;
+
+bitflags! {
+	/// Notifications pushed to the host over the serial link. Events raised before the
+	/// command task gets a chance to send them are coalesced into a single frame.
+	#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+	pub struct HostEvents: u8 {
+		const PROFILE_CHANGED = 0b00000001;
+		const ERROR_LOGGED = 0b00000010;
+		const TAGS_CHANGED = 0b00000100;
+		const MODULES_CHANGED = 0b00001000;
+	}
+}
+
+/// Host events raised since the command task last took them. Raising one merges it in under a
+/// critical section, so an event raised while the command task takes the rest isn't lost.
+pub struct PendingHostEvents {
+	pending: Mutex<Cell<HostEvents>>,
+}
+
+impl PendingHostEvents {
+	pub const fn new() -> Self {
+		Self {
+			pending: Mutex::new(Cell::new(HostEvents::empty())),
+		}
+	}
+}
+
+impl Default for PendingHostEvents {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl HostEventSignalTx for PendingHostEvents {
+	fn notify_host(&self, events: HostEvents) {
+		critical_section::with(|cs| {
+			let pending = self.pending.borrow(cs);
+			pending.set(pending.get() | events);
+		});
+	}
+}
+
+impl HostEventSignalRx for PendingHostEvents {
+	fn try_take_host_events(&self) -> Option<HostEvents> {
+		let events =
+			critical_section::with(|cs| self.pending.borrow(cs).replace(HostEvents::empty()));
+		(!events.is_empty()).then_some(events)
+	}
+}
+
+impl Writeable for HostEvents {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_u8(EVENT_KIND_NOTIFICATION).await?;
+		writer.write_u8(self.bits()).await
+	}
+}
+
+/// A physical key press or release, streamed to the host while it's subscribed.
+#[derive(Clone, Copy, Debug)]
+pub struct KeyEvent {
+	pub key_id: KeyId,
+	pub state: KeyState,
+	pub timestamp: Instant,
+}
+
+impl Writeable for KeyEvent {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_u8(EVENT_KIND_KEY).await?;
+		self.key_id.write_to(writer).await?;
+		writer
+			.write_u8(match self.state {
+				KeyState::Pressed => 0x01,
+				KeyState::Released => 0x00,
+			})
+			.await?;
+		writer.write_u64(self.timestamp.ticks()).await
+	}
+}
+
+/// The part of a long-running command a `Progress` event is about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressStage {
+	Erasing = 0x01,
+	Writing = 0x02,
+}
+
+/// How far a long-running command has got, sent while it works so hosts can show a progress
+/// bar and tell a slow command from a stalled one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+	pub stage: ProgressStage,
+	pub done: u32,
+	pub total: u32,
+}
+
+impl Progress {
+	pub fn percent(&self) -> u8 {
+		if self.total == 0 {
+			return 100;
+		}
+		(self.done.min(self.total) as u64 * 100 / self.total as u64) as u8
+	}
+}
+
+impl Writeable for Progress {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_u8(EVENT_KIND_PROGRESS).await?;
+		writer.write_u8(self.stage as u8).await?;
+		writer.write_u8(self.percent()).await?;
+		writer.write_u32(self.done).await?;
+		writer.write_u32(self.total).await
+	}
+}
+
+/// Hands part of the receive window back to the host once the device has taken the bytes off
+/// its buffers, so the host can send that many more.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Credit {
+	pub bytes: u16,
+}
+
+impl Writeable for Credit {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_u8(EVENT_KIND_CREDIT).await?;
+		writer.write_u16(self.bytes).await
+	}
+}
+
+/// Serializes `event` and sends it as an event frame.
+pub(crate) async fn send_event<Tx: SerialEventSender, Event: Writeable>(
+	tx: &mut Tx,
+	event: &Event,
+) -> Result<(), &'static str> {
+	let mut buffer = [0u8; MAX_EVENT_SIZE];
+	let mut writer = &mut buffer[..];
+	event.write_to(&mut writer).await?;
+	let length = MAX_EVENT_SIZE - writer.len();
+
+	tx.send_event(&buffer[..length]).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use uuid::uuid;
+
+	#[tokio::test]
+	async fn key_event_layout() {
+		let event = KeyEvent {
+			key_id: KeyId::new(uuid!("0661ee85-348b-5d93-b5e2-ac11cfa5344b")),
+			state: KeyState::Pressed,
+			timestamp: Instant::from_ticks(0x0102),
+		};
+
+		let mut buffer = [0u8; MAX_EVENT_SIZE];
+		let mut writer = &mut buffer[..];
+		event.write_to(&mut writer).await.unwrap();
+		let written = MAX_EVENT_SIZE - writer.len();
+
+		assert_eq!(written, 26);
+		assert_eq!(buffer[0], EVENT_KIND_KEY);
+		assert_eq!(
+			buffer[1..17],
+			uuid!("0661ee85-348b-5d93-b5e2-ac11cfa5344b").to_bytes_le()
+		);
+		assert_eq!(buffer[17], 0x01);
+		assert_eq!(buffer[18..26], 0x0102u64.to_le_bytes());
+	}
+
+	#[tokio::test]
+	async fn progress_layout() {
+		let progress = Progress {
+			stage: ProgressStage::Writing,
+			done: 0x0300,
+			total: 0x0400,
+		};
+
+		let mut buffer = [0u8; MAX_EVENT_SIZE];
+		let mut writer = &mut buffer[..];
+		progress.write_to(&mut writer).await.unwrap();
+		let written = MAX_EVENT_SIZE - writer.len();
+
+		assert_eq!(written, 11);
+		assert_eq!(buffer[..3], [EVENT_KIND_PROGRESS, 0x02, 75]);
+		assert_eq!(buffer[3..7], 0x0300u32.to_le_bytes());
+		assert_eq!(buffer[7..11], 0x0400u32.to_le_bytes());
+	}
+
+	#[tokio::test]
+	async fn credit_layout() {
+		let mut buffer = [0u8; MAX_EVENT_SIZE];
+		let mut writer = &mut buffer[..];
+		Credit { bytes: 0x0140 }
+			.write_to(&mut writer)
+			.await
+			.unwrap();
+		let written = MAX_EVENT_SIZE - writer.len();
+
+		assert_eq!(buffer[..written], [EVENT_KIND_CREDIT, 0x40, 0x01]);
+	}
+
+	#[test]
+	fn pending_host_events_merge_until_taken() {
+		let pending = PendingHostEvents::new();
+		assert_eq!(pending.try_take_host_events(), None);
+
+		pending.notify_host(HostEvents::PROFILE_CHANGED);
+		pending.notify_host(HostEvents::ERROR_LOGGED);
+		assert_eq!(
+			pending.try_take_host_events(),
+			Some(HostEvents::PROFILE_CHANGED | HostEvents::ERROR_LOGGED)
+		);
+		assert_eq!(pending.try_take_host_events(), None);
+	}
+
+	#[test]
+	fn progress_percent_is_clamped() {
+		let progress = |done, total| Progress {
+			stage: ProgressStage::Erasing,
+			done,
+			total,
+		};
+		assert_eq!(progress(0, 0).percent(), 100);
+		assert_eq!(progress(5, 4).percent(), 100);
+		assert_eq!(progress(u32::MAX / 2, u32::MAX).percent(), 49);
+	}
+}
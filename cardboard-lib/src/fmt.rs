@@ -0,0 +1,49 @@
+#![macro_use]
+#![allow(unused_macros)]
+
+// Logging macros used throughout the crate. They forward to defmt when the `defmt` feature is
+// on and compile to nothing otherwise, so host tools can use the crate without a defmt logger.
+
+macro_rules! debug {
+	($s:literal $(, $x:expr)* $(,)?) => {
+		{
+			#[cfg(feature = "defmt")]
+			::defmt::debug!($s $(, $x)*);
+			#[cfg(not(feature = "defmt"))]
+			let _ = ($( & $x ),*);
+		}
+	};
+}
+
+macro_rules! info {
+	($s:literal $(, $x:expr)* $(,)?) => {
+		{
+			#[cfg(feature = "defmt")]
+			::defmt::info!($s $(, $x)*);
+			#[cfg(not(feature = "defmt"))]
+			let _ = ($( & $x ),*);
+		}
+	};
+}
+
+macro_rules! warn {
+	($s:literal $(, $x:expr)* $(,)?) => {
+		{
+			#[cfg(feature = "defmt")]
+			::defmt::warn!($s $(, $x)*);
+			#[cfg(not(feature = "defmt"))]
+			let _ = ($( & $x ),*);
+		}
+	};
+}
+
+macro_rules! error {
+	($s:literal $(, $x:expr)* $(,)?) => {
+		{
+			#[cfg(feature = "defmt")]
+			::defmt::error!($s $(, $x)*);
+			#[cfg(not(feature = "defmt"))]
+			let _ = ($( & $x ),*);
+		}
+	};
+}
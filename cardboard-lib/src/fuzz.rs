@@ -0,0 +1,69 @@
+use alloc::vec;
+use core::future::Future;
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+
+use crate::profile::KeyboardProfile;
+use crate::storage::read_profile_record;
+
+/// Size of the profile partition the fuzzer loads from: the most a profile's u16 length
+/// prefix can cover.
+pub const FUZZ_FLASH_SIZE: usize = 2 + u16::MAX as usize;
+
+/// Loads `data` as the contents of the profile partition, the way the keypad loads an uploaded
+/// profile. Data past the partition is cut off and the rest reads as erased flash.
+///
+/// Malformed input has to come back as an error; a panic or an allocation the size of a bogus
+/// length prefix is a bug.
+pub fn fuzz_profile(data: &[u8]) -> Result<KeyboardProfile, &'static str> {
+	let mut flash = vec![0xFF; FUZZ_FLASH_SIZE];
+	let length = data.len().min(FUZZ_FLASH_SIZE);
+	flash[..length].copy_from_slice(&data[..length]);
+
+	block_on(read_profile_record(&flash))
+}
+
+// reading from memory never waits on anything, so polling until done is enough
+fn block_on<F: Future>(future: F) -> F::Output {
+	let mut future = pin!(future);
+	let mut cx = Context::from_waker(Waker::noop());
+	loop {
+		if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+			return output;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::random::Rng;
+	use crate::testing::get_cranky_profile_data;
+
+	#[test]
+	fn loads_a_valid_profile() {
+		assert!(fuzz_profile(get_cranky_profile_data()).is_ok());
+	}
+
+	#[test]
+	fn rejects_bogus_lengths() {
+		assert!(fuzz_profile(&[]).is_err());
+		// a length prefix that only covers the version
+		assert!(fuzz_profile(&[4, 0, 11, 0, 0, 0, 0]).is_err());
+	}
+
+	#[test]
+	fn survives_corrupted_profiles() {
+		let profile = get_cranky_profile_data();
+		let mut rng = Rng::new(0x5EED);
+		for _ in 0..2000 {
+			let mut data = profile.to_vec();
+			for _ in 0..rng.range(1, 8) {
+				let index = rng.range(0, data.len() as u64 - 1) as usize;
+				data[index] = rng.next_u32() as u8;
+			}
+			data.truncate(rng.range(0, data.len() as u64) as usize);
+			let _ = fuzz_profile(&data);
+		}
+	}
+}
@@ -0,0 +1,98 @@
+use num_enum::TryFromPrimitive;
+
+use crate::serialize::Readable;
+use crate::stream::{ReadAsync, ReadAsyncExt};
+
+/// A vibration pattern, played by the haptic task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub enum HapticPattern {
+	/// A short, sharp tap, for confirming a key press.
+	Click = 0,
+	/// Two taps, for confirming a mode change.
+	DoubleClick = 1,
+	/// A long buzz, for something that needs attention.
+	Buzz = 2,
+	/// A soft tap, for macro milestones that shouldn't distract.
+	Tick = 3,
+}
+
+/// Part of a pattern: the motor runs at `strength_percent` for `duration_ms`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HapticStep {
+	pub strength_percent: u8,
+	pub duration_ms: u16,
+}
+
+const fn step(strength_percent: u8, duration_ms: u16) -> HapticStep {
+	HapticStep {
+		strength_percent,
+		duration_ms,
+	}
+}
+
+impl HapticPattern {
+	/// The steps to play. The motor is stopped after the last one.
+	pub fn steps(&self) -> &'static [HapticStep] {
+		match self {
+			HapticPattern::Click => &[step(100, 20)],
+			HapticPattern::DoubleClick => &[step(100, 20), step(0, 60), step(100, 20)],
+			HapticPattern::Buzz => &[step(80, 300)],
+			HapticPattern::Tick => &[step(40, 10)],
+		}
+	}
+}
+
+impl Readable for HapticPattern {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let value = reader
+			.read_u8()
+			.await
+			.ok_or("Failed to read haptic pattern")?;
+		HapticPattern::try_from(value).or(Err("Invalid haptic pattern"))
+	}
+}
+
+/// A vibration motor. Strength is in percent, where 0 stops the motor.
+pub trait HapticMotor {
+	fn set(&mut self, strength_percent: u8);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn patterns_are_short_and_start_with_the_motor_on() {
+		for value in 0..=u8::MAX {
+			let Ok(pattern) = HapticPattern::try_from(value) else {
+				continue;
+			};
+			let steps = pattern.steps();
+			let total_ms: u32 = steps.iter().map(|step| step.duration_ms as u32).sum();
+
+			assert!(steps[0].strength_percent > 0, "{:?}", pattern);
+			assert!(steps.iter().all(|step| step.strength_percent <= 100));
+			assert!(total_ms <= 500, "{:?} runs for {} ms", pattern, total_ms);
+		}
+	}
+
+	#[tokio::test]
+	async fn unknown_patterns_are_rejected() {
+		let bytes = [1, 4];
+		let mut reader = &bytes[..];
+
+		assert_eq!(
+			HapticPattern::read_from(&mut reader).await,
+			Ok(HapticPattern::DoubleClick)
+		);
+		assert_eq!(
+			HapticPattern::read_from(&mut reader).await,
+			Err("Invalid haptic pattern")
+		);
+	}
+}
@@ -1,7 +1,8 @@
 use crate::input::KeyState;
 use crate::profile::{ConsumerControlEvent, KeyboardEvent, KeyboardKey, MouseButton, MouseEvent};
 use bitflags::bitflags;
-use defmt::Format;
+use core::cell::Cell;
+use critical_section::Mutex;
 
 pub struct HidReport<const SIZE_K: usize, const SIZE_M: usize, const SIZE_C: usize> {
 	pub keyboard: Option<[u8; SIZE_K]>,
@@ -12,11 +13,71 @@ pub struct HidReport<const SIZE_K: usize, const SIZE_M: usize, const SIZE_C: usi
 pub trait ReportHid {
 	fn report_keyboard(&mut self, report: &KeyboardEvent);
 	fn report_mouse(&mut self, report: &MouseEvent);
+	/// Pointer motion for the next report only, unlike a `MouseEvent::Move`, which holds until
+	/// changed.
+	fn report_motion(&mut self, x: i32, y: i32);
 	fn report_consumer(&mut self, report: &ConsumerControlEvent);
 	fn flush(&mut self);
 	fn reset(&mut self);
 }
 
+bitflags! {
+	/// Lock LEDs the host sets through the keyboard's output report.
+	#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+	pub struct LockState: u8 {
+		const NUM_LOCK = 0b00000001;
+		const CAPS_LOCK = 0b00000010;
+		const SCROLL_LOCK = 0b00000100;
+		const COMPOSE = 0b00001000;
+		const KANA = 0b00010000;
+	}
+}
+
+/// Latest lock state from the host, set by the USB stack and read by the keypad task.
+pub struct HostLocks {
+	state: Mutex<Cell<LockState>>,
+}
+
+impl HostLocks {
+	pub const fn new() -> Self {
+		Self {
+			state: Mutex::new(Cell::new(LockState::empty())),
+		}
+	}
+
+	/// Takes the keyboard output report as sent by the host.
+	pub fn set_report(&self, report: u8) {
+		let locks = LockState::from_bits_truncate(report);
+		critical_section::with(|cs| self.state.borrow(cs).set(locks));
+	}
+
+	pub fn get(&self) -> LockState {
+		critical_section::with(|cs| self.state.borrow(cs).get())
+	}
+}
+
+impl Default for HostLocks {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// A one byte feature report with the battery charge in percent, which the host reads to
+/// show a battery icon. Kept in its own interface so boards without a battery don't show one.
+pub const BATTERY_REPORT_DESCRIPTOR: &[u8] = &[
+	0x05, 0x0C, // Usage Page (Consumer)
+	0x09, 0x01, // Usage (Consumer Control)
+	0xA1, 0x01, // Collection (Application)
+	0x05, 0x06, // Usage Page (Generic Device Controls)
+	0x09, 0x20, // Usage (Battery Strength)
+	0x15, 0x00, // Logical Minimum (0)
+	0x25, 0x64, // Logical Maximum (100)
+	0x75, 0x08, // Report Size (8)
+	0x95, 0x01, // Report Count (1)
+	0xB1, 0x02, // Feature (Data, Variable, Absolute)
+	0xC0, // End Collection
+];
+
 pub trait HidKeyboard {
 	fn report(&mut self, event: &KeyboardEvent);
 }
@@ -34,6 +95,9 @@ pub trait HidDevice<I> {
 
 	fn input(&mut self, input: &I);
 
+	/// Pointer motion for the next report; devices without a pointer ignore it.
+	fn add_motion(&mut self, _x: i32, _y: i32) {}
+
 	fn reset(&mut self);
 
 	fn report_descriptor() -> &'static [u8];
@@ -155,6 +219,8 @@ impl HidDevice<KeyboardEvent> for NKROKeyboard {
 pub struct Mouse {
 	buttons: HidMouseButtons,
 	cursor: (i32, i32),
+	/// From a pointing sensor, sent once rather than held like `cursor`.
+	motion: (i32, i32),
 	scroll: (i32, i32),
 }
 
@@ -165,6 +231,7 @@ impl Mouse {
 		Mouse {
 			buttons: HidMouseButtons::empty(),
 			cursor: (0, 0),
+			motion: (0, 0),
 			scroll: (0, 0),
 		}
 	}
@@ -191,8 +258,8 @@ impl Mouse {
 impl HidDevice<MouseEvent> for Mouse {
 	fn create_report(&mut self) -> Option<[u8; Mouse::REPORT_SIZE]> {
 		let buttons = self.buttons.bits();
-		let x = self.cursor.0.clamp(-128, 127) as i8;
-		let y = self.cursor.1.clamp(-128, 127) as i8;
+		let x = take_motion(self.cursor.0, &mut self.motion.0);
+		let y = take_motion(self.cursor.1, &mut self.motion.1);
 		let scroll_x = self.scroll.0.clamp(-128, 127) as i8;
 		let scroll_y = self.scroll.1.clamp(-128, 127) as i8;
 
@@ -208,6 +275,11 @@ impl HidDevice<MouseEvent> for Mouse {
 		}
 	}
 
+	fn add_motion(&mut self, x: i32, y: i32) {
+		self.motion.0 = self.motion.0.saturating_add(x);
+		self.motion.1 = self.motion.1.saturating_add(y);
+	}
+
 	fn reset(&mut self) {
 		*self = Mouse::new();
 	}
@@ -264,6 +336,15 @@ impl HidDevice<MouseEvent> for Mouse {
 	// const SIZE: usize = Mouse::REPORT_SIZE;
 }
 
+/// One axis of a report: the held cursor movement plus as much sensor motion as still fits,
+/// leaving the rest for the next report.
+fn take_motion(cursor: i32, motion: &mut i32) -> i8 {
+	let cursor = cursor.clamp(-128, 127);
+	let sent = (*motion).clamp(-128 - cursor, 127 - cursor);
+	*motion -= sent;
+	(cursor + sent) as i8
+}
+
 pub struct Scroll {
 	buttons: HidMouseButtons,
 	scroll: (i32, i32),
@@ -460,7 +541,8 @@ pub(crate) fn map_cc(key: &ConsumerControlEvent) -> Consumer {
 	}
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Format)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u16)]
 pub(crate) enum Consumer {
 	Unassigned = 0x00,
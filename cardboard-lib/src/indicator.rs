@@ -0,0 +1,133 @@
+use core::cell::Cell;
+use critical_section::Mutex;
+
+/// What lights an indicator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndicatorCondition {
+	/// A layer tag is active, whether set by a macro or the host.
+	TagActive(&'static str),
+	/// The host has suspended the USB bus.
+	UsbSuspended,
+	/// The error log has errors the host hasn't cleared.
+	ErrorPresent,
+}
+
+/// A plain single-color LED, for boards without addressable LEDs.
+pub trait Indicator {
+	fn set(&mut self, on: bool);
+}
+
+/// An indicator and the condition that lights it. Only touches the LED when the condition
+/// changes.
+pub struct BoundIndicator<I: Indicator> {
+	pub condition: IndicatorCondition,
+	indicator: I,
+	on: bool,
+}
+
+impl<I: Indicator> BoundIndicator<I> {
+	pub fn new(condition: IndicatorCondition, mut indicator: I) -> Self {
+		indicator.set(false);
+		Self {
+			condition,
+			indicator,
+			on: false,
+		}
+	}
+
+	pub fn update(&mut self, on: bool) {
+		if on != self.on {
+			self.indicator.set(on);
+			self.on = on;
+		}
+	}
+}
+
+/// Conditions tracked outside the keypad task, which drives the indicators.
+///
+/// Shared the same way as `UsbStats`, since the USB stack and the command task both set it.
+pub struct IndicatorStatus {
+	usb_suspended: Mutex<Cell<bool>>,
+	error_present: Mutex<Cell<bool>>,
+}
+
+impl IndicatorStatus {
+	pub const fn new() -> Self {
+		Self {
+			usb_suspended: Mutex::new(Cell::new(false)),
+			error_present: Mutex::new(Cell::new(false)),
+		}
+	}
+
+	pub fn set_usb_suspended(&self, suspended: bool) {
+		critical_section::with(|cs| self.usb_suspended.borrow(cs).set(suspended));
+	}
+
+	pub fn set_error_present(&self, present: bool) {
+		critical_section::with(|cs| self.error_present.borrow(cs).set(present));
+	}
+
+	/// Checks a condition, with tags looked up through `has_tag`.
+	pub fn is_met(&self, condition: IndicatorCondition, has_tag: impl Fn(&str) -> bool) -> bool {
+		match condition {
+			IndicatorCondition::TagActive(tag) => has_tag(tag),
+			IndicatorCondition::UsbSuspended => {
+				critical_section::with(|cs| self.usb_suspended.borrow(cs).get())
+			}
+			IndicatorCondition::ErrorPresent => {
+				critical_section::with(|cs| self.error_present.borrow(cs).get())
+			}
+		}
+	}
+}
+
+impl Default for IndicatorStatus {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use alloc::vec::Vec;
+
+	struct MockIndicator<'a> {
+		writes: &'a mut Vec<bool>,
+	}
+
+	impl Indicator for MockIndicator<'_> {
+		fn set(&mut self, on: bool) {
+			self.writes.push(on);
+		}
+	}
+
+	#[test]
+	fn indicators_are_only_written_when_their_condition_changes() {
+		let status = IndicatorStatus::new();
+		let mut writes = Vec::new();
+		let mut indicator = BoundIndicator::new(
+			IndicatorCondition::UsbSuspended,
+			MockIndicator {
+				writes: &mut writes,
+			},
+		);
+
+		for suspended in [false, true, true, false] {
+			status.set_usb_suspended(suspended);
+			indicator.update(status.is_met(indicator.condition, |_| false));
+		}
+		drop(indicator);
+
+		assert_eq!(writes, [false, true, false]);
+	}
+
+	#[test]
+	fn tag_conditions_are_looked_up_by_name() {
+		let status = IndicatorStatus::new();
+		let condition = IndicatorCondition::TagActive("caps");
+
+		assert!(status.is_met(condition, |tag| tag == "caps"));
+		assert!(!status.is_met(condition, |tag| tag == "fn"));
+	}
+}
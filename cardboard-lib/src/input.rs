@@ -1,14 +1,17 @@
-use crate::serialize::Readable;
-use crate::stream::{ReadAsync, ReadAsyncExt};
-use crate::time::Duration;
+use crate::profile::VirtualKeyId;
+use crate::serialize::{Readable, Writeable};
+use crate::stream::{ReadAsync, ReadAsyncExt, WriteAsync, WriteAsyncExt};
+use crate::time::{Duration, Instant};
 use alloc::boxed::Box;
+use alloc::vec;
 use alloc::vec::Vec;
+use core::future::{Future, poll_fn};
+use core::pin::Pin;
+use core::task::Poll;
 use uuid::Uuid;
 
-#[cfg(not(test))]
+#[cfg(all(not(test), feature = "defmt"))]
 use crate::alloc::string::ToString;
-#[cfg(not(test))]
-use defmt::Format;
 
 pub trait RowPin {
 	fn set_high(&mut self);
@@ -17,19 +20,50 @@ pub trait RowPin {
 
 pub trait ColPin {
 	fn is_high(&self) -> bool;
+	/// Resolves once the pin goes high, without polling it.
+	fn wait_for_high(&mut self) -> Pin<Box<dyn Future<Output = ()> + '_>>;
+}
+
+/// Samples every column at once. Bit `c` of the result is set when column `c` is high.
+pub trait ColPort {
+	fn read(&self) -> u32;
 }
 
 pub trait UpdateMatrix {
-	fn update(&mut self, dt: Duration, output: &mut Vec<KeyboardAction>);
+	/// Scans the matrix at `now`, `dt` after the last scan, adding a stamped action for each key
+	/// that changed.
+	fn update(&mut self, now: Instant, dt: Duration, output: &mut Vec<KeyboardAction>);
+	fn scan_raw(&mut self) -> RawMatrixScan;
+	/// Waits for any switch to close, so the matrix doesn't need scanning while nothing is
+	/// happening.
+	async fn wait_for_key(&mut self);
 	const SIZE: usize;
 }
 
+/// Switch states read straight off the pins, bypassing debounce. Bit `r * cols + c` of
+/// `bitmap` is set when the switch at row `r`, column `c` is closed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawMatrixScan {
+	pub rows: u8,
+	pub cols: u8,
+	pub bitmap: Vec<u8>,
+}
+
+impl Writeable for RawMatrixScan {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_u8(self.rows).await?;
+		writer.write_u8(self.cols).await?;
+		writer.write_exact(&self.bitmap).await
+	}
+}
+
 pub struct KeyMatrix<const ROWS: usize, const COLS: usize>
 where
 	[(); ROWS * COLS]:,
 {
 	rows: [Box<dyn RowPin>; ROWS],
 	cols: [Box<dyn ColPin>; COLS],
+	col_port: Option<Box<dyn ColPort>>,
 	keys: [InputKey; ROWS * COLS],
 }
 
@@ -47,6 +81,7 @@ where
 		Self {
 			rows,
 			cols,
+			col_port: None,
 			keys: key_ids.map(|key_id| InputKey {
 				id: key_id,
 				prev_actual_state: KeyState::Released,
@@ -57,12 +92,21 @@ where
 		}
 	}
 
-	pub fn update(&mut self, dt: Duration, output: &mut Vec<KeyboardAction>) {
+	/// Scans the columns through `port`, one read per row instead of one per key. The column
+	/// pins are still used to wait for a key.
+	pub fn with_col_port(mut self, port: Box<dyn ColPort>) -> Self {
+		assert!(COLS <= 32);
+		self.col_port = Some(port);
+		self
+	}
+
+	pub fn update(&mut self, now: Instant, dt: Duration, output: &mut Vec<KeyboardAction>) {
 		for (r, row_pin) in self.rows.iter_mut().enumerate() {
 			row_pin.set_high();
+			let port_bits = self.col_port.as_ref().map(|port| port.read());
 
-			for (c, col_pin) in self.cols.iter_mut().enumerate() {
-				let state = match col_pin.is_high() {
+			for (c, col_pin) in self.cols.iter().enumerate() {
+				let state = match Self::col_is_high(port_bits, c, col_pin.as_ref()) {
 					true => KeyState::Pressed,
 					false => KeyState::Released,
 				};
@@ -73,6 +117,7 @@ where
 					output.push(KeyboardAction {
 						action: event,
 						key_id: key.id,
+						timestamp: now,
 					});
 				}
 			}
@@ -81,18 +126,103 @@ where
 		}
 	}
 
+	pub fn scan_raw(&mut self) -> RawMatrixScan {
+		let mut bitmap = vec![0u8; (ROWS * COLS).div_ceil(8)];
+
+		for (r, row_pin) in self.rows.iter_mut().enumerate() {
+			row_pin.set_high();
+			let port_bits = self.col_port.as_ref().map(|port| port.read());
+
+			for (c, col_pin) in self.cols.iter().enumerate() {
+				if Self::col_is_high(port_bits, c, col_pin.as_ref()) {
+					let index = Self::get_key_index(r, c);
+					bitmap[index / 8] |= 1 << (index % 8);
+				}
+			}
+
+			row_pin.set_low();
+		}
+
+		RawMatrixScan {
+			rows: ROWS as u8,
+			cols: COLS as u8,
+			bitmap,
+		}
+	}
+
+	pub async fn wait_for_key(&mut self) {
+		// with every row driven, any closed switch pulls its column high. The guard sets them low
+		// again however the wait ends, including being cancelled by a timeout.
+		let _driven = DrivenRows::new(&mut self.rows);
+
+		let mut waits: Vec<_> = self
+			.cols
+			.iter_mut()
+			.map(|pin| pin.wait_for_high())
+			.collect();
+		poll_fn(|cx| {
+			if waits
+				.iter_mut()
+				.any(|wait| wait.as_mut().poll(cx).is_ready())
+			{
+				Poll::Ready(())
+			} else {
+				Poll::Pending
+			}
+		})
+		.await;
+	}
+
 	fn get_key_index(r: usize, c: usize) -> usize {
 		r * COLS + c
 	}
+
+	fn col_is_high(port_bits: Option<u32>, c: usize, col_pin: &dyn ColPin) -> bool {
+		match port_bits {
+			Some(bits) => bits & (1 << c) != 0,
+			None => col_pin.is_high(),
+		}
+	}
+}
+
+/// Drives every row high for as long as it's alive.
+struct DrivenRows<'a> {
+	rows: &'a mut [Box<dyn RowPin>],
+}
+
+impl<'a> DrivenRows<'a> {
+	fn new(rows: &'a mut [Box<dyn RowPin>]) -> Self {
+		for row_pin in rows.iter_mut() {
+			row_pin.set_high();
+		}
+		Self { rows }
+	}
+}
+
+impl Drop for DrivenRows<'_> {
+	fn drop(&mut self) {
+		for row_pin in self.rows.iter_mut() {
+			row_pin.set_low();
+		}
+	}
 }
 
 impl<const ROWS: usize, const COLS: usize> UpdateMatrix for KeyMatrix<ROWS, COLS>
 where
 	[(); ROWS * COLS]:,
 {
-	fn update(&mut self, dt: Duration, output: &mut Vec<KeyboardAction>) {
-		self.update(dt, output);
+	fn update(&mut self, now: Instant, dt: Duration, output: &mut Vec<KeyboardAction>) {
+		self.update(now, dt, output);
 	}
+
+	fn scan_raw(&mut self) -> RawMatrixScan {
+		self.scan_raw()
+	}
+
+	async fn wait_for_key(&mut self) {
+		self.wait_for_key().await;
+	}
+
 	const SIZE: usize = ROWS * COLS;
 }
 
@@ -150,6 +280,7 @@ impl InputKey {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub struct KeyId(Uuid);
 
 impl KeyId {
@@ -168,10 +299,16 @@ impl Readable for KeyId {
 	}
 }
 
-#[cfg(not(test))]
-impl Format for KeyId {
+impl Writeable for KeyId {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_uuid(self.0).await
+	}
+}
+
+#[cfg(all(not(test), feature = "defmt"))]
+impl defmt::Format for KeyId {
 	fn format(&self, fmt: defmt::Formatter) {
-		self.0.to_string().format(fmt);
+		defmt::Format::format(&self.0.to_string(), fmt);
 	}
 }
 
@@ -179,46 +316,115 @@ impl Format for KeyId {
 pub struct KeyboardAction {
 	pub action: KeyState,
 	pub key_id: KeyId,
+	/// When the change was captured, which can be before the tick that handles it.
+	pub timestamp: Instant,
 }
 
 impl KeyboardAction {
-	pub fn pressed(key_id: KeyId) -> Self {
+	pub fn pressed(key_id: KeyId, timestamp: Instant) -> Self {
 		Self {
 			action: KeyState::Pressed,
 			key_id,
+			timestamp,
 		}
 	}
 
-	pub fn released(key_id: KeyId) -> Self {
+	pub fn released(key_id: KeyId, timestamp: Instant) -> Self {
 		Self {
 			action: KeyState::Released,
 			key_id,
+			timestamp,
 		}
 	}
 }
 
+/// A virtual key set by ID rather than by its place in the bitfield.
+#[derive(Debug, Clone, Copy)]
+pub struct VirtualKeyAction {
+	pub action: KeyState,
+	pub id: VirtualKeyId,
+}
+
 impl Default for KeyboardAction {
 	fn default() -> Self {
 		Self {
 			action: KeyState::Released,
 			key_id: KeyId(Uuid::nil()),
+			timestamp: Instant::from_ticks(0),
 		}
 	}
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
-#[cfg_attr(not(test), derive(Format))]
+#[cfg_attr(all(not(test), feature = "defmt"), derive(defmt::Format))]
 pub enum KeyState {
 	Pressed,
 	Released,
 }
 
+/// Watches for a set of keys being held together for a while.
+pub struct Chord {
+	keys: Vec<KeyId>,
+	hold: Duration,
+	/// Bit n is set while `keys[n]` is held.
+	held: u8,
+	held_for: Duration,
+}
+
+impl Chord {
+	pub const MAX_KEYS: usize = u8::BITS as usize;
+
+	pub fn new(keys: Vec<KeyId>, hold: Duration) -> Result<Self, &'static str> {
+		if keys.is_empty() {
+			return Err("Chord has no keys");
+		}
+		if keys.len() > Self::MAX_KEYS {
+			return Err("Chord has too many keys");
+		}
+
+		Ok(Self {
+			keys,
+			hold,
+			held: 0,
+			held_for: Duration::from_ticks(0),
+		})
+	}
+
+	/// Feeds one tick's key changes. Returns true once every key has been held for the hold
+	/// time, and keeps returning true until one is released.
+	pub fn update(&mut self, actions: &[KeyboardAction], elapsed: Duration) -> bool {
+		let all = u8::MAX >> (Self::MAX_KEYS - self.keys.len());
+		let was_complete = self.held == all;
+
+		for action in actions {
+			if let Some(i) = self.keys.iter().position(|k| *k == action.key_id) {
+				match action.action {
+					KeyState::Pressed => self.held |= 1 << i,
+					KeyState::Released => self.held &= !(1 << i),
+				}
+			}
+		}
+
+		if self.held != all {
+			return false;
+		}
+		// the tick that completes the chord doesn't count towards holding it
+		if was_complete {
+			self.held_for += elapsed;
+		} else {
+			self.held_for = Duration::from_ticks(0);
+		}
+		self.held_for >= self.hold
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use alloc::rc::Rc;
 	use core::cell::RefCell;
 
 	use super::*;
+	use crate::testing::create_mock_matrix;
 
 	#[test]
 	fn key_same_state_returns_none() {
@@ -389,120 +595,6 @@ mod tests {
 		assert_eq!(result, Some(KeyState::Released));
 	}
 
-	pub struct MockKeyMatrixState<const ROWS: usize, const COLS: usize> {
-		// the physical state of keys: true = pressed, false = released
-		key_states: [[bool; COLS]; ROWS],
-		// current state of row pins: true = high, false = low
-		row_states: [bool; ROWS],
-	}
-
-	impl<const ROWS: usize, const COLS: usize> MockKeyMatrixState<ROWS, COLS> {
-		pub fn new() -> Self {
-			Self {
-				key_states: [[false; COLS]; ROWS],
-				row_states: [false; ROWS],
-			}
-		}
-
-		pub fn set_key_states(&mut self, states: [[bool; COLS]; ROWS]) {
-			self.key_states = states;
-		}
-
-		pub fn set_key(&mut self, row: usize, col: usize, pressed: bool) {
-			if row < ROWS && col < COLS {
-				self.key_states[row][col] = pressed;
-			}
-		}
-
-		pub fn get_key(&self, row: usize, col: usize) -> bool {
-			if row < ROWS && col < COLS {
-				self.key_states[row][col]
-			} else {
-				false
-			}
-		}
-
-		fn set_row_state(&mut self, row: usize, high: bool) {
-			if row < ROWS {
-				self.row_states[row] = high;
-			}
-		}
-
-		fn get_row_state(&self, row: usize) -> bool {
-			if row < ROWS {
-				self.row_states[row]
-			} else {
-				false
-			}
-		}
-	}
-
-	pub struct MockRowPin<const ROWS: usize, const COLS: usize> {
-		row_index: usize,
-		state: Rc<RefCell<MockKeyMatrixState<ROWS, COLS>>>,
-	}
-
-	impl<const ROWS: usize, const COLS: usize> MockRowPin<ROWS, COLS> {
-		pub fn new(row_index: usize, state: Rc<RefCell<MockKeyMatrixState<ROWS, COLS>>>) -> Self {
-			Self { row_index, state }
-		}
-	}
-
-	impl<const ROWS: usize, const COLS: usize> RowPin for MockRowPin<ROWS, COLS> {
-		fn set_high(&mut self) {
-			self.state.borrow_mut().set_row_state(self.row_index, true);
-		}
-
-		fn set_low(&mut self) {
-			self.state.borrow_mut().set_row_state(self.row_index, false);
-		}
-	}
-
-	pub struct MockColPin<const ROWS: usize, const COLS: usize> {
-		col_index: usize,
-		state: Rc<RefCell<MockKeyMatrixState<ROWS, COLS>>>,
-	}
-
-	impl<const ROWS: usize, const COLS: usize> MockColPin<ROWS, COLS> {
-		pub fn new(col_index: usize, state: Rc<RefCell<MockKeyMatrixState<ROWS, COLS>>>) -> Self {
-			Self { col_index, state }
-		}
-	}
-
-	impl<const ROWS: usize, const COLS: usize> ColPin for MockColPin<ROWS, COLS> {
-		fn is_high(&self) -> bool {
-			let state = self.state.borrow();
-
-			// Check if any row that is currently high has a pressed key in this column
-			for row in 0..ROWS {
-				if state.get_row_state(row) && state.get_key(row, self.col_index) {
-					return true;
-				}
-			}
-			false
-		}
-	}
-
-	pub fn create_mock_matrix<const ROWS: usize, const COLS: usize>() -> (
-		Rc<RefCell<MockKeyMatrixState<ROWS, COLS>>>,
-		[Box<dyn RowPin>; ROWS],
-		[Box<dyn ColPin>; COLS],
-	) {
-		let state = Rc::new(RefCell::new(MockKeyMatrixState::new()));
-
-		// Create row pins
-		let rows: [Box<dyn RowPin>; ROWS] = std::array::from_fn(|i| {
-			Box::new(MockRowPin::new(i, Rc::clone(&state))) as Box<dyn RowPin>
-		});
-
-		// Create column pins
-		let cols: [Box<dyn ColPin>; COLS] = std::array::from_fn(|i| {
-			Box::new(MockColPin::new(i, Rc::clone(&state))) as Box<dyn ColPin>
-		});
-
-		(state, rows, cols)
-	}
-
 	struct OldMockRowPin {}
 
 	impl RowPin for OldMockRowPin {
@@ -519,6 +611,10 @@ mod tests {
 		fn is_high(&self) -> bool {
 			*self.state.borrow()
 		}
+
+		fn wait_for_high(&mut self) -> Pin<Box<dyn Future<Output = ()> + '_>> {
+			Box::pin(core::future::pending())
+		}
 	}
 
 	#[test]
@@ -536,7 +632,7 @@ mod tests {
 		let dt = Duration::from_ticks(1);
 		let output = &mut Vec::new();
 
-		matrix.update(dt, output);
+		matrix.update(Instant::from_ticks(0), dt, output);
 
 		assert_eq!(output.len(), 0);
 	}
@@ -555,10 +651,49 @@ mod tests {
 
 		let dt = Duration::from_ticks(1);
 		let output = &mut Vec::new();
-		matrix.update(dt, output);
+		matrix.update(Instant::from_ticks(20), dt, output);
 
 		assert_eq!(output.len(), 1);
 		assert_eq!(output[0].action, KeyState::Pressed);
+		assert_eq!(output[0].timestamp, Instant::from_ticks(20));
+	}
+
+	struct MockColPort {
+		bits: u32,
+	}
+
+	impl ColPort for MockColPort {
+		fn read(&self) -> u32 {
+			self.bits
+		}
+	}
+
+	#[test]
+	fn col_port_replaces_per_pin_reads() {
+		let key_ids = [
+			KeyId::new(Uuid::from_u128(0)),
+			KeyId::new(Uuid::from_u128(1)),
+		];
+		let row_pin: Box<dyn RowPin> = Box::new(OldMockRowPin {});
+		let state = Rc::new(RefCell::new(false));
+		let col_pins: [Box<dyn ColPin>; 2] = [
+			Box::new(OldMockColPin {
+				state: state.clone(),
+			}),
+			Box::new(OldMockColPin {
+				state: state.clone(),
+			}),
+		];
+
+		let mut matrix = KeyMatrix::new(key_ids, [row_pin], col_pins, Duration::from_ticks(0))
+			.with_col_port(Box::new(MockColPort { bits: 0b10 }));
+
+		let output = &mut Vec::new();
+		matrix.update(Instant::from_ticks(0), Duration::from_ticks(1), output);
+
+		assert_eq!(output.len(), 1);
+		assert_eq!(output[0].key_id, key_ids[1]);
+		assert_eq!(matrix.scan_raw().bitmap, vec![0b10]);
 	}
 
 	#[test]
@@ -576,9 +711,9 @@ mod tests {
 		let dt = Duration::from_ticks(1);
 		let output = &mut Vec::new();
 
-		matrix.update(dt, output);
+		matrix.update(Instant::from_ticks(0), dt, output);
 		output.clear();
-		matrix.update(dt, output);
+		matrix.update(Instant::from_ticks(0), dt, output);
 
 		assert_eq!(
 			output.len(),
@@ -603,9 +738,9 @@ mod tests {
 		let dt = Duration::from_ticks(1);
 		let output = &mut Vec::new();
 
-		matrix.update(dt, output);
+		matrix.update(Instant::from_ticks(0), dt, output);
 		output.clear();
-		matrix.update(dt, output);
+		matrix.update(Instant::from_ticks(0), dt, output);
 
 		assert_eq!(output.len(), 0);
 	}
@@ -625,10 +760,10 @@ mod tests {
 		let dt = Duration::from_ticks(1);
 		let output = &mut Vec::new();
 
-		matrix.update(dt, output);
+		matrix.update(Instant::from_ticks(0), dt, output);
 		output.clear();
 		*state.borrow_mut() = false;
-		matrix.update(dt, output);
+		matrix.update(Instant::from_ticks(0), dt, output);
 
 		assert_eq!(output.len(), 1);
 		assert_eq!(output[0].action, KeyState::Released);
@@ -649,10 +784,10 @@ mod tests {
 		let dt = Duration::from_ticks(1);
 		let output = &mut Vec::new();
 
-		matrix.update(dt, output);
+		matrix.update(Instant::from_ticks(0), dt, output);
 		output.clear();
 		*state.borrow_mut() = false;
-		matrix.update(dt, output);
+		matrix.update(Instant::from_ticks(0), dt, output);
 
 		assert_eq!(output.len(), 0);
 	}
@@ -713,7 +848,7 @@ mod tests {
 
 		let dt = Duration::from_ticks(1);
 		let output = &mut Vec::new();
-		matrix.update(dt, output);
+		matrix.update(Instant::from_ticks(0), dt, output);
 
 		assert_eq!(output.len(), 2);
 
@@ -724,4 +859,81 @@ mod tests {
 			action.key_id == KeyId::new(Uuid::from_u128(3)) && action.action == KeyState::Pressed
 		}));
 	}
+
+	#[test]
+	fn raw_scan_reports_closed_switches_without_debounce() {
+		let (state, rows, cols) = create_mock_matrix::<2, 5>();
+		state.borrow_mut().set_key(0, 1, true);
+		state.borrow_mut().set_key(1, 4, true);
+
+		let key_ids = [KeyId::new(Uuid::from_u128(0)); 10];
+		let mut matrix = KeyMatrix::<2, 5>::new(key_ids, rows, cols, Duration::from_ticks(1000));
+
+		let scan = matrix.scan_raw();
+
+		assert_eq!(scan.rows, 2);
+		assert_eq!(scan.cols, 5);
+		assert_eq!(scan.bitmap, vec![0b0000_0010, 0b0000_0010]);
+	}
+
+	#[tokio::test]
+	async fn waiting_for_a_key_drives_every_row_then_releases_them() {
+		let (state, rows, cols) = create_mock_matrix::<2, 5>();
+		state.borrow_mut().set_key(1, 3, true);
+
+		let key_ids = [KeyId::new(Uuid::from_u128(0)); 10];
+		let mut matrix = KeyMatrix::<2, 5>::new(key_ids, rows, cols, Duration::from_ticks(1000));
+
+		// only resolves if row 1 was driven while waiting
+		matrix.wait_for_key().await;
+
+		assert!(!state.borrow().get_row_state(0));
+		assert!(!state.borrow().get_row_state(1));
+	}
+
+	#[test]
+	fn cancelling_a_wait_for_a_key_releases_the_rows() {
+		let (state, rows, cols) = create_mock_matrix::<2, 5>();
+		let key_ids = [KeyId::new(Uuid::from_u128(0)); 10];
+		let mut matrix = KeyMatrix::<2, 5>::new(key_ids, rows, cols, Duration::from_ticks(1000));
+
+		{
+			let mut wait = core::pin::pin!(matrix.wait_for_key());
+			let mut cx = core::task::Context::from_waker(core::task::Waker::noop());
+			assert!(wait.as_mut().poll(&mut cx).is_pending());
+			assert!(state.borrow().get_row_state(0));
+			assert!(state.borrow().get_row_state(1));
+		}
+
+		assert!(!state.borrow().get_row_state(0));
+		assert!(!state.borrow().get_row_state(1));
+	}
+
+	#[test]
+	fn chord_fires_once_all_keys_are_held_long_enough() {
+		let a = KeyId::new(Uuid::from_u128(1));
+		let b = KeyId::new(Uuid::from_u128(2));
+		let ms = |ms: u64| Duration::from_ticks(ms * 1000);
+		let at = Instant::from_ticks(0);
+		let mut chord = Chord::new(vec![a, b], ms(100)).unwrap();
+
+		assert!(!chord.update(&[KeyboardAction::pressed(a, at)], ms(1)));
+		assert!(!chord.update(&[KeyboardAction::pressed(b, at)], ms(1)));
+		assert!(!chord.update(&[], ms(60)));
+		assert!(chord.update(&[], ms(40)));
+
+		// letting go of one key starts over
+		assert!(!chord.update(&[KeyboardAction::released(a, at)], ms(1)));
+		assert!(!chord.update(&[KeyboardAction::pressed(a, at)], ms(1)));
+		assert!(!chord.update(&[], ms(99)));
+	}
+
+	#[test]
+	fn chord_needs_between_one_and_eight_keys() {
+		let keys: Vec<KeyId> = (0..9).map(|i| KeyId::new(Uuid::from_u128(i))).collect();
+
+		assert!(Chord::new(vec![], Duration::from_ticks(0)).is_err());
+		assert!(Chord::new(keys[..8].to_vec(), Duration::from_ticks(0)).is_ok());
+		assert!(Chord::new(keys, Duration::from_ticks(0)).is_err());
+	}
 }
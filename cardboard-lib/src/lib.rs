@@ -3,28 +3,73 @@
 #![feature(type_alias_impl_trait)]
 
 extern crate alloc;
+#[cfg(feature = "sim")]
+extern crate std;
+
+// must come first so the logging macros are visible in every module
+mod fmt;
 
 use core::alloc::{GlobalAlloc, Layout};
-use core::cell::Cell;
-use critical_section::Mutex;
+use core::cell::{Cell, RefCell};
+use critical_section::{CriticalSection, Mutex};
 
+pub mod auth;
+pub mod battery;
+pub mod ble;
+pub mod budget;
+pub mod buzzer;
 pub mod command;
 pub mod context;
+pub mod crash;
+pub mod crc;
 pub mod device;
+pub mod display;
+pub mod encoder;
 pub mod error;
+pub mod event;
+#[cfg(any(test, feature = "fuzz"))]
+pub mod fuzz;
+pub mod haptic;
 pub mod hid;
+pub mod indicator;
 pub mod input;
+pub mod lighting;
+pub mod lock;
+pub mod module;
+pub mod output;
+pub mod pointer;
+pub mod power;
 pub mod profile;
+pub mod random;
 pub mod serial;
 pub mod serialize;
+pub mod settings;
+pub mod slider;
 pub mod state;
+pub mod stats;
 pub mod storage;
 pub mod stream;
+pub mod supervisor;
 pub mod tasks;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod text;
 pub mod time;
+pub mod transport;
+pub mod update;
 
 #[cfg(all(not(test), feature = "embassy"))]
 pub mod embassy;
+#[cfg(all(not(test), feature = "nrf52"))]
+pub mod nrf;
+#[cfg(all(not(test), any(feature = "rp2040", feature = "rp2350")))]
+pub mod rp;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+#[cfg(all(not(test), feature = "softdevice"))]
+pub mod softdevice;
+#[cfg(all(not(test), feature = "stm32"))]
+pub mod stm32;
 
 #[cfg(test)]
 mod test;
@@ -32,16 +77,46 @@ mod test;
 pub trait TrackedAllocator {
 	fn current(&self) -> usize;
 	fn max(&self) -> usize;
+	fn fragmentation(&self) -> HeapFragmentation;
+}
+
+/// Free space layout of the heap, as found by `TrackingAllocator::fragmentation`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HeapFragmentation {
+	pub free_blocks: usize,
+	pub largest_free_block: usize,
 }
 
+/// Most free blocks `fragmentation` will count; a heap split further than this is reported
+/// as having exactly this many.
+const MAX_PROBED_BLOCKS: usize = 32;
+/// Free blocks smaller than this aren't counted, they can't hold anything useful anyway.
+const MIN_PROBED_BLOCK_SIZE: usize = 16;
+
 /// Tracking allocator wrapper that monitors heap usage.
 ///
 /// Wraps any `GlobalAlloc` implementation and tracks current and maximum
 /// allocation statistics using interrupt-safe critical sections.
 pub struct TrackingAllocator<A: GlobalAlloc> {
 	pub inner: A,
-	current: Mutex<Cell<usize>>, // Current allocated bytes
-	max: Mutex<Cell<usize>>,     // Maximum allocated bytes ever
+	current: Mutex<Cell<usize>>,         // Current allocated bytes
+	max: Mutex<Cell<usize>>,             // Maximum allocated bytes ever
+	window_max: Mutex<Cell<usize>>,      // Maximum allocated bytes since `start_window`
+	window_start: Mutex<Cell<usize>>,    // Allocated bytes at `start_window`
+	heap_size: Mutex<Cell<usize>>,       // Total heap size, for probing free blocks
+	probe: Mutex<RefCell<ProbedBlocks>>, // Free blocks held by `fragmentation`
+}
+
+/// Free blocks `fragmentation` has found and is holding, as addresses and sizes.
+struct ProbedBlocks {
+	blocks: [(usize, usize); MAX_PROBED_BLOCKS],
+	count: usize,
+	/// Set when an allocation needed the memory back, which ends the probe.
+	released: bool,
+}
+
+fn probe_layout(size: usize) -> Option<Layout> {
+	Layout::from_size_align(size, core::mem::align_of::<usize>()).ok()
 }
 
 impl<A: GlobalAlloc> TrackingAllocator<A> {
@@ -50,9 +125,22 @@ impl<A: GlobalAlloc> TrackingAllocator<A> {
 			inner,
 			current: Mutex::new(Cell::new(0)),
 			max: Mutex::new(Cell::new(0)),
+			window_max: Mutex::new(Cell::new(0)),
+			window_start: Mutex::new(Cell::new(0)),
+			heap_size: Mutex::new(Cell::new(0)),
+			probe: Mutex::new(RefCell::new(ProbedBlocks {
+				blocks: [(0, 0); MAX_PROBED_BLOCKS],
+				count: 0,
+				released: false,
+			})),
 		}
 	}
 
+	/// Set the size of the heap given to the inner allocator, needed by `fragmentation`
+	pub fn set_heap_size(&self, size: usize) {
+		critical_section::with(|cs| self.heap_size.borrow(cs).set(size));
+	}
+
 	/// Get current allocated bytes
 	pub fn current(&self) -> usize {
 		critical_section::with(|cs| self.current.borrow(cs).get())
@@ -70,19 +158,143 @@ impl<A: GlobalAlloc> TrackingAllocator<A> {
 			self.max.borrow(cs).set(current);
 		});
 	}
+
+	/// Start tracking peak usage separately from `max`, returning the current allocated bytes.
+	/// Unlike `reset_stats` this doesn't disturb the high-water mark the host sees.
+	pub fn start_window(&self) -> usize {
+		critical_section::with(|cs| {
+			let current = self.current.borrow(cs).get();
+			self.window_max.borrow(cs).set(current);
+			self.window_start.borrow(cs).set(current);
+			current
+		})
+	}
+
+	/// Get bytes allocated since `start_window` and not freed yet
+	pub fn window_used(&self) -> usize {
+		critical_section::with(|cs| {
+			let current = self.current.borrow(cs).get();
+			current.saturating_sub(self.window_start.borrow(cs).get())
+		})
+	}
+
+	/// Get maximum allocated bytes since `start_window`
+	pub fn window_max(&self) -> usize {
+		critical_section::with(|cs| self.window_max.borrow(cs).get())
+	}
+
+	fn record_current(&self, cs: critical_section::CriticalSection, new_current: usize) {
+		self.current.borrow(cs).set(new_current);
+		self.max
+			.borrow(cs)
+			.set(self.max.borrow(cs).get().max(new_current));
+		self.window_max
+			.borrow(cs)
+			.set(self.window_max.borrow(cs).get().max(new_current));
+	}
+
+	/// Count free blocks and find the largest one.
+	///
+	/// The inner allocator doesn't expose its free list, so this repeatedly allocates the
+	/// largest block it can (found by binary search) until the heap is exhausted, then frees
+	/// everything again. Each block is found in its own critical section, so the other core
+	/// only ever waits for one search. An allocation that fails while the probe holds the heap
+	/// takes the blocks back and retries; the probe then reports only the blocks it had found.
+	/// It's slow; only call it on request.
+	pub fn fragmentation(&self) -> HeapFragmentation {
+		let heap_size = critical_section::with(|cs| {
+			let mut probe = self.probe.borrow_ref_mut(cs);
+			probe.count = 0;
+			probe.released = false;
+			self.heap_size.borrow(cs).get()
+		});
+		let mut result = HeapFragmentation::default();
+
+		while result.free_blocks < MAX_PROBED_BLOCKS {
+			let found = critical_section::with(|cs| {
+				let mut probe = self.probe.borrow_ref_mut(cs);
+				if probe.released {
+					return None;
+				}
+				let (ptr, layout) = self.alloc_largest(heap_size)?;
+				let count = probe.count;
+				probe.blocks[count] = (ptr as usize, layout.size());
+				probe.count += 1;
+				Some(layout.size())
+			});
+			let Some(size) = found else {
+				break;
+			};
+			if result.free_blocks == 0 {
+				result.largest_free_block = size;
+			}
+			result.free_blocks += 1;
+		}
+
+		critical_section::with(|cs| self.release_probe(cs));
+		result
+	}
+
+	/// Frees the blocks `fragmentation` is holding, returning whether there were any.
+	fn release_probe(&self, cs: CriticalSection) -> bool {
+		let mut probe = self.probe.borrow_ref_mut(cs);
+		let held = probe.count;
+		for &(addr, size) in &probe.blocks[..held] {
+			if let Some(layout) = probe_layout(size) {
+				unsafe { self.inner.dealloc(addr as *mut u8, layout) };
+			}
+		}
+		probe.count = 0;
+		probe.released = true;
+		held > 0
+	}
+
+	fn alloc_largest(&self, upper_bound: usize) -> Option<(*mut u8, Layout)> {
+		let fits = |size| {
+			let Some(layout) = probe_layout(size) else {
+				return false;
+			};
+			let ptr = unsafe { self.inner.alloc(layout) };
+			if ptr.is_null() {
+				return false;
+			}
+			unsafe { self.inner.dealloc(ptr, layout) };
+			true
+		};
+
+		if !fits(MIN_PROBED_BLOCK_SIZE) {
+			return None;
+		}
+
+		let mut low = MIN_PROBED_BLOCK_SIZE;
+		let mut high = upper_bound.max(low);
+		while low < high {
+			let mid = low + (high - low).div_ceil(2);
+			if fits(mid) {
+				low = mid;
+			} else {
+				high = mid - 1;
+			}
+		}
+
+		let layout = probe_layout(low)?;
+		let ptr = unsafe { self.inner.alloc(layout) };
+		(!ptr.is_null()).then_some((ptr, layout))
+	}
 }
 
 unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
 	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-		let ptr = unsafe { self.inner.alloc(layout) };
+		let mut ptr = unsafe { self.inner.alloc(layout) };
+		if ptr.is_null() && critical_section::with(|cs| self.release_probe(cs)) {
+			// a fragmentation probe was holding the free blocks
+			ptr = unsafe { self.inner.alloc(layout) };
+		}
 		if !ptr.is_null() {
 			let size = layout.size();
 			critical_section::with(|cs| {
 				let new_current = self.current.borrow(cs).get() + size;
-				self.current.borrow(cs).set(new_current);
-				self.max
-					.borrow(cs)
-					.set(self.max.borrow(cs).get().max(new_current));
+				self.record_current(cs, new_current);
 			});
 		}
 		ptr
@@ -101,14 +313,14 @@ unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
 
 	unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
 		let old_size = layout.size();
-		let new_ptr = unsafe { self.inner.realloc(ptr, layout, new_size) };
+		let mut new_ptr = unsafe { self.inner.realloc(ptr, layout, new_size) };
+		if new_ptr.is_null() && critical_section::with(|cs| self.release_probe(cs)) {
+			new_ptr = unsafe { self.inner.realloc(ptr, layout, new_size) };
+		}
 		if !new_ptr.is_null() && !ptr.is_null() {
 			critical_section::with(|cs| {
 				let new_current = self.current.borrow(cs).get() - old_size + new_size;
-				self.current.borrow(cs).set(new_current);
-				self.max
-					.borrow(cs)
-					.set(self.max.borrow(cs).get().max(new_current));
+				self.record_current(cs, new_current);
 			});
 		}
 		new_ptr
@@ -123,4 +335,8 @@ impl<A: GlobalAlloc> TrackedAllocator for TrackingAllocator<A> {
 	fn max(&self) -> usize {
 		self.max()
 	}
+
+	fn fragmentation(&self) -> HeapFragmentation {
+		self.fragmentation()
+	}
 }
@@ -0,0 +1,438 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use num_enum::TryFromPrimitive;
+
+use crate::input::KeyId;
+use crate::serialize::{Readable, Writeable};
+use crate::stream::{ReadAsync, ReadAsyncExt, WriteAsync, WriteAsyncExt};
+use crate::time::Duration;
+
+/// LEDs are addressed by a u8 index.
+pub const MAX_LEDS: usize = u8::MAX as usize + 1;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rgb {
+	pub r: u8,
+	pub g: u8,
+	pub b: u8,
+}
+
+impl Rgb {
+	pub const OFF: Rgb = Rgb::new(0, 0, 0);
+
+	pub const WHITE: Rgb = Rgb::new(255, 255, 255);
+
+	pub const fn new(r: u8, g: u8, b: u8) -> Self {
+		Self { r, g, b }
+	}
+
+	/// Dims the color, where 255 is full brightness.
+	pub fn scale(self, level: u8) -> Self {
+		let scale = |c: u8| (c as u16 * level as u16 / 255) as u8;
+		Self::new(scale(self.r), scale(self.g), scale(self.b))
+	}
+
+	/// Mixes in another color, where 255 is all the other color.
+	pub fn blend(self, other: Rgb, amount: u8) -> Self {
+		let mix = |a: u8, b: u8| {
+			((a as u16 * (255 - amount as u16) + b as u16 * amount as u16) / 255) as u8
+		};
+		Self::new(
+			mix(self.r, other.r),
+			mix(self.g, other.g),
+			mix(self.b, other.b),
+		)
+	}
+
+	/// A fully saturated color from a hue, going red, green, blue and back to red.
+	pub fn wheel(hue: u8) -> Self {
+		let step = hue % 85 * 3;
+		match hue / 85 {
+			0 => Self::new(255 - step, step, 0),
+			1 => Self::new(0, 255 - step, step),
+			_ => Self::new(step, 0, 255 - step),
+		}
+	}
+}
+
+impl Readable for Rgb {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let mut rgb = [0u8; 3];
+		reader
+			.read_exact(&mut rgb)
+			.await
+			.map_err(|_| "Failed to read color")?;
+		Ok(Self::new(rgb[0], rgb[1], rgb[2]))
+	}
+}
+
+impl Writeable for Rgb {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_exact(&[self.r, self.g, self.b]).await
+	}
+}
+
+/// Which LED in the chain sits under a key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub struct LedMapping {
+	pub key: KeyId,
+	pub led: u8,
+}
+
+impl Readable for LedMapping {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let key = KeyId::read_from(reader).await?;
+		let led = reader.read_u8().await.ok_or("Failed to read LED index")?;
+		Ok(Self { key, led })
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub enum LightingEvent {
+	/// Sets every LED to one color.
+	Fill(Rgb),
+	SetLed {
+		led: u8,
+		color: Rgb,
+	},
+	/// Sets the LEDs the profile maps to a key.
+	SetKey {
+		key: KeyId,
+		color: Rgb,
+	},
+	SetEffect(LightingEffect),
+	/// Effect speed in percent, where 100 is normal speed.
+	SetEffectSpeed(u16),
+	/// Sent by the keypad task for each LED under a pressed key, for reactive effects. Not
+	/// part of the profile format.
+	Pressed {
+		led: u8,
+	},
+}
+
+impl LightingEvent {
+	/// Turns key events into events for the LEDs mapped to that key, so the lighting task
+	/// doesn't need the profile.
+	pub fn resolve(&self, leds: &[LedMapping], mut send: impl FnMut(LightingEvent)) {
+		match *self {
+			LightingEvent::SetKey { key, color } => leds
+				.iter()
+				.filter(|mapping| mapping.key == key)
+				.for_each(|mapping| {
+					send(LightingEvent::SetLed {
+						led: mapping.led,
+						color,
+					})
+				}),
+			event => send(event),
+		}
+	}
+}
+
+impl Readable for LightingEvent {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let discriminator = reader
+			.read_u8()
+			.await
+			.ok_or("Failed to read lighting event")?;
+		match discriminator {
+			0 => Ok(LightingEvent::Fill(Rgb::read_from(reader).await?)),
+			1 => {
+				let led = reader.read_u8().await.ok_or("Failed to read LED index")?;
+				let color = Rgb::read_from(reader).await?;
+				Ok(LightingEvent::SetLed { led, color })
+			}
+			2 => {
+				let key = KeyId::read_from(reader).await?;
+				let color = Rgb::read_from(reader).await?;
+				Ok(LightingEvent::SetKey { key, color })
+			}
+			3 => Ok(LightingEvent::SetEffect(
+				LightingEffect::read_from(reader).await?,
+			)),
+			4 => Ok(LightingEvent::SetEffectSpeed(
+				reader
+					.read_u16()
+					.await
+					.ok_or("Failed to read effect speed")?,
+			)),
+			_ => Err("Invalid lighting event"),
+		}
+	}
+}
+
+/// Colors for every LED in the chain, as last sent to the driver or about to be.
+pub struct LedFrame {
+	leds: Vec<Rgb>,
+}
+
+impl LedFrame {
+	pub fn new(len: usize) -> Self {
+		Self {
+			leds: vec![Rgb::OFF; len.min(MAX_LEDS)],
+		}
+	}
+
+	/// Applies a resolved event. Returns true if any LED changed color.
+	pub fn apply(&mut self, event: &LightingEvent) -> bool {
+		match *event {
+			LightingEvent::Fill(color) => {
+				let changed = self.leds.iter().any(|led| *led != color);
+				self.leds.fill(color);
+				changed
+			}
+			LightingEvent::SetLed { led, color } => match self.leds.get_mut(led as usize) {
+				Some(current) if *current != color => {
+					*current = color;
+					true
+				}
+				_ => false,
+			},
+			// resolved by the keypad task, which has the mapping
+			LightingEvent::SetKey { .. } => false,
+			// handled by the effects
+			LightingEvent::SetEffect(_)
+			| LightingEvent::SetEffectSpeed(_)
+			| LightingEvent::Pressed { .. } => false,
+		}
+	}
+
+	pub fn leds(&self) -> &[Rgb] {
+		&self.leds
+	}
+}
+
+/// Animation drawn over the colors set by macros and key backlights.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub enum LightingEffect {
+	/// Shows the set colors as they are.
+	#[default]
+	Static = 0,
+	/// Fades the set colors in and out.
+	Breathing = 1,
+	/// Cycles every LED through the color wheel, ignoring the set colors.
+	Rainbow = 2,
+	/// Flashes pressed keys white, fading back to their set color.
+	Reactive = 3,
+}
+
+impl Readable for LightingEffect {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let value = reader
+			.read_u8()
+			.await
+			.ok_or("Failed to read lighting effect")?;
+		LightingEffect::try_from(value).or(Err("Invalid lighting effect"))
+	}
+}
+
+/// One breath at normal speed.
+const BREATHING_PERIOD_MS: u64 = 4000;
+/// One trip around the color wheel at normal speed.
+const RAINBOW_PERIOD_MS: u64 = 5000;
+/// How long a pressed key takes to fade back at normal speed.
+const REACTIVE_FADE_MS: u64 = 500;
+
+/// Renders the current effect over an [`LedFrame`].
+pub struct Effects {
+	effect: LightingEffect,
+	speed_percent: u16,
+	/// Effect time, which runs faster or slower than real time with the speed.
+	phase_ms: u64,
+	/// Leftover effect time under a millisecond.
+	phase_us: u64,
+	/// Effect time left before each LED stops showing a press, for the reactive effect.
+	heat_ms: Vec<u64>,
+}
+
+impl Effects {
+	pub fn new(len: usize, effect: LightingEffect, speed_percent: u16) -> Self {
+		Self {
+			effect,
+			speed_percent,
+			phase_ms: 0,
+			phase_us: 0,
+			heat_ms: vec![0; len.min(MAX_LEDS)],
+		}
+	}
+
+	/// Applies a resolved event. Returns true if the output may have changed.
+	pub fn apply(&mut self, event: &LightingEvent) -> bool {
+		match *event {
+			LightingEvent::SetEffect(effect) => {
+				let changed = effect != self.effect;
+				self.effect = effect;
+				self.heat_ms.fill(0);
+				changed
+			}
+			LightingEvent::SetEffectSpeed(speed_percent) => {
+				self.speed_percent = speed_percent;
+				false
+			}
+			LightingEvent::Pressed { led } => match self.heat_ms.get_mut(led as usize) {
+				Some(heat_ms) if self.effect == LightingEffect::Reactive => {
+					*heat_ms = REACTIVE_FADE_MS;
+					true
+				}
+				_ => false,
+			},
+			_ => false,
+		}
+	}
+
+	/// Returns true if the effect changes the output on its own, so it has to be redrawn every
+	/// tick.
+	pub fn is_animated(&self) -> bool {
+		match self.effect {
+			LightingEffect::Static => false,
+			LightingEffect::Reactive => self.heat_ms.iter().any(|heat_ms| *heat_ms > 0),
+			LightingEffect::Breathing | LightingEffect::Rainbow => self.speed_percent > 0,
+		}
+	}
+
+	/// Moves the effect on by `dt`.
+	pub fn advance(&mut self, dt: Duration) {
+		self.phase_us += dt.to_micros() * self.speed_percent as u64 / 100;
+		let elapsed_ms = self.phase_us / 1000;
+		self.phase_us %= 1000;
+		self.phase_ms = self.phase_ms.wrapping_add(elapsed_ms);
+
+		for heat_ms in self.heat_ms.iter_mut() {
+			*heat_ms = heat_ms.saturating_sub(elapsed_ms);
+		}
+	}
+
+	/// Draws the effect over the set colors.
+	pub fn render(&self, frame: &LedFrame, out: &mut Vec<Rgb>) {
+		let leds = frame.leds();
+		out.clear();
+		match self.effect {
+			LightingEffect::Static => out.extend_from_slice(leds),
+			LightingEffect::Breathing => {
+				// a triangle wave, brightest halfway through each breath
+				let t = self.phase_ms % BREATHING_PERIOD_MS;
+				let half = BREATHING_PERIOD_MS / 2;
+				let level = if t < half { t } else { BREATHING_PERIOD_MS - t };
+				let level = (level * u8::MAX as u64 / half) as u8;
+				out.extend(leds.iter().map(|color| color.scale(level)));
+			}
+			LightingEffect::Rainbow => {
+				let offset = self.phase_ms % RAINBOW_PERIOD_MS * 256 / RAINBOW_PERIOD_MS;
+				let len = leds.len().max(1) as u64;
+				out.extend(
+					(0..leds.len() as u64).map(|i| Rgb::wheel((offset + i * 256 / len) as u8)),
+				);
+			}
+			LightingEffect::Reactive => out.extend(leds.iter().zip(self.heat_ms.iter()).map(
+				|(color, heat_ms)| {
+					color.blend(Rgb::WHITE, (heat_ms * 255 / REACTIVE_FADE_MS) as u8)
+				},
+			)),
+		}
+	}
+}
+
+/// Pushes colors out to the LED chain.
+pub trait LedDriver {
+	async fn write(&mut self, colors: &[Rgb]);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use fugit::ExtU64;
+	use uuid::Uuid;
+
+	#[test]
+	fn key_events_resolve_to_every_mapped_led() {
+		let key = KeyId::new(Uuid::from_u128(1));
+		let other = KeyId::new(Uuid::from_u128(2));
+		let leds = [
+			LedMapping { key, led: 3 },
+			LedMapping { key: other, led: 4 },
+			LedMapping { key, led: 7 },
+		];
+		let color = Rgb::new(1, 2, 3);
+
+		let mut resolved = Vec::new();
+		LightingEvent::SetKey { key, color }.resolve(&leds, |event| resolved.push(event));
+
+		assert_eq!(
+			resolved,
+			vec![
+				LightingEvent::SetLed { led: 3, color },
+				LightingEvent::SetLed { led: 7, color },
+			]
+		);
+	}
+
+	#[test]
+	fn frame_reports_only_real_changes() {
+		let mut frame = LedFrame::new(2);
+		let red = Rgb::new(255, 0, 0);
+
+		assert!(!frame.apply(&LightingEvent::Fill(Rgb::OFF)));
+		assert!(frame.apply(&LightingEvent::SetLed { led: 1, color: red }));
+		assert!(!frame.apply(&LightingEvent::SetLed { led: 1, color: red }));
+		assert!(!frame.apply(&LightingEvent::SetLed { led: 2, color: red }));
+		assert_eq!(frame.leds(), &[Rgb::OFF, red]);
+	}
+
+	#[test]
+	fn reactive_presses_fade_back_to_the_set_color() {
+		let mut frame = LedFrame::new(2);
+		let red = Rgb::new(255, 0, 0);
+		frame.apply(&LightingEvent::Fill(red));
+		let mut effects = Effects::new(2, LightingEffect::Reactive, 100);
+		let mut out = Vec::new();
+
+		assert!(!effects.is_animated());
+		assert!(effects.apply(&LightingEvent::Pressed { led: 1 }));
+		effects.render(&frame, &mut out);
+		assert_eq!(out, vec![red, Rgb::WHITE]);
+
+		effects.advance((REACTIVE_FADE_MS / 2).millis());
+		effects.render(&frame, &mut out);
+		assert!(out[1].g > 0 && out[1].g < 255);
+
+		effects.advance((REACTIVE_FADE_MS / 2).millis());
+		effects.render(&frame, &mut out);
+		assert_eq!(out, vec![red, red]);
+		assert!(!effects.is_animated());
+	}
+
+	#[test]
+	fn speed_scales_effect_time() {
+		let mut frame = LedFrame::new(1);
+		frame.apply(&LightingEvent::Fill(Rgb::WHITE));
+		let mut out = Vec::new();
+
+		let mut normal = Effects::new(1, LightingEffect::Breathing, 100);
+		normal.advance((BREATHING_PERIOD_MS / 2).millis());
+		normal.render(&frame, &mut out);
+		assert_eq!(out, vec![Rgb::WHITE]);
+
+		let mut double = Effects::new(1, LightingEffect::Breathing, 100);
+		double.apply(&LightingEvent::SetEffectSpeed(200));
+		double.advance((BREATHING_PERIOD_MS / 2).millis());
+		double.render(&frame, &mut out);
+		assert_eq!(out, vec![Rgb::OFF]);
+	}
+}
@@ -0,0 +1,79 @@
+use core::cell::Cell;
+use critical_section::Mutex;
+
+pub const MAX_LOCK_PIN_LENGTH: usize = 32;
+
+/// Unlock attempts allowed before the device refuses any more until it's power cycled.
+pub const MAX_UNLOCK_ATTEMPTS: u8 = 5;
+
+pub fn validate_lock_pin(pin: &[u8]) -> Result<(), &'static str> {
+	if pin.is_empty() {
+		return Err("Lock PIN is empty");
+	}
+
+	if pin.len() > MAX_LOCK_PIN_LENGTH {
+		return Err("Lock PIN is too long");
+	}
+
+	Ok(())
+}
+
+/// Compares PINs in time that only depends on their lengths, so a host can't find the PIN a
+/// byte at a time by timing failed unlocks.
+pub fn pins_match(expected: &[u8], actual: &[u8]) -> bool {
+	if expected.len() != actual.len() {
+		return false;
+	}
+	expected
+		.iter()
+		.zip(actual)
+		.fold(0, |diff, (a, b)| diff | (a ^ b))
+		== 0
+}
+
+/// Set by the keypad task when the safe mode key was held at power-up. Only someone at the
+/// keypad can do that, so it's what lets a lock record nobody can unlock be cleared.
+pub struct SafeMode {
+	started: Mutex<Cell<bool>>,
+}
+
+impl SafeMode {
+	pub const fn new() -> Self {
+		Self {
+			started: Mutex::new(Cell::new(false)),
+		}
+	}
+
+	pub fn set(&self) {
+		critical_section::with(|cs| self.started.borrow(cs).set(true));
+	}
+
+	pub fn is_set(&self) -> bool {
+		critical_section::with(|cs| self.started.borrow(cs).get())
+	}
+}
+
+impl Default for SafeMode {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn lock_pin_length_is_limited() {
+		assert!(validate_lock_pin(b"").is_err());
+		assert!(validate_lock_pin(&[b'1'; MAX_LOCK_PIN_LENGTH]).is_ok());
+		assert!(validate_lock_pin(&[b'1'; MAX_LOCK_PIN_LENGTH + 1]).is_err());
+	}
+
+	#[test]
+	fn pins_must_match_exactly() {
+		assert!(pins_match(b"1234", b"1234"));
+		assert!(!pins_match(b"1234", b"1235"));
+		assert!(!pins_match(b"1234", b"12345"));
+	}
+}
@@ -0,0 +1,343 @@
+use crate::display::I2cBus;
+use crate::input::{KeyId, KeyState, KeyboardAction, RawMatrixScan, UpdateMatrix};
+use crate::serialize::Writeable;
+use crate::stream::{WriteAsync, WriteAsyncExt};
+use crate::time::{Duration, Instant};
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use critical_section::Mutex;
+
+/// Tells whether a detachable key module is plugged in, such as by a presence pin the module
+/// pulls low or by probing its I2C address.
+pub trait ModuleDetect {
+	async fn is_present(&mut self) -> bool;
+}
+
+/// Finds a module by whether anything acknowledges its I2C address, for modules that carry an
+/// I/O expander or similar chip.
+pub struct I2cProbe<I: I2cBus> {
+	bus: I,
+	address: u8,
+}
+
+impl<I: I2cBus> I2cProbe<I> {
+	pub fn new(bus: I, address: u8) -> Self {
+		Self { bus, address }
+	}
+}
+
+impl<I: I2cBus> ModuleDetect for I2cProbe<I> {
+	async fn is_present(&mut self) -> bool {
+		// a register pointer of 0, which changes nothing on the expanders modules use
+		self.bus.write(self.address, &[0x00]).await.is_ok()
+	}
+}
+
+/// A connector a key module can be plugged into, probed by the module task.
+pub struct ModulePort<D: ModuleDetect> {
+	pub name: &'static str,
+	pub detect: D,
+}
+
+/// Probes in a row that have to agree before a module counts as plugged in or pulled out, so
+/// a connector wiggling on its way in doesn't flap.
+pub const DETECT_CONFIRMATIONS: u8 = 3;
+
+/// Debounces the probes of one port.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ModuleDebounce {
+	attached: bool,
+	disagreements: u8,
+}
+
+impl ModuleDebounce {
+	/// Feeds in a probe, returning the module's new state once it has changed.
+	pub fn update(&mut self, present: bool) -> Option<bool> {
+		if present == self.attached {
+			self.disagreements = 0;
+			return None;
+		}
+		self.disagreements += 1;
+		if self.disagreements < DETECT_CONFIRMATIONS {
+			return None;
+		}
+		self.disagreements = 0;
+		self.attached = present;
+		Some(present)
+	}
+}
+
+/// A key module as reported to the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModuleInfo {
+	pub name: &'static str,
+	pub attached: bool,
+}
+
+impl Writeable for ModuleInfo {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_string_u8(self.name).await?;
+		writer.write_bool(self.attached).await
+	}
+}
+
+/// Which key modules are plugged in, kept by the module task and read by the matrix, the keypad
+/// task and the command task. Modules are indexed in the order their ports were registered.
+pub struct ModuleStatus {
+	modules: Mutex<RefCell<Vec<ModuleInfo>>>,
+	changed: Mutex<Cell<bool>>,
+}
+
+impl ModuleStatus {
+	pub const fn new() -> Self {
+		Self {
+			modules: Mutex::new(RefCell::new(Vec::new())),
+			changed: Mutex::new(Cell::new(false)),
+		}
+	}
+
+	/// Adds a port, detached until its module is found.
+	pub fn register(&self, name: &'static str) {
+		critical_section::with(|cs| {
+			self.modules.borrow_ref_mut(cs).push(ModuleInfo {
+				name,
+				attached: false,
+			})
+		});
+	}
+
+	pub fn set_attached(&self, index: usize, attached: bool) {
+		critical_section::with(|cs| {
+			if let Some(module) = self.modules.borrow_ref_mut(cs).get_mut(index) {
+				module.attached = attached;
+				self.changed.borrow(cs).set(true);
+			}
+		});
+	}
+
+	pub fn is_attached(&self, index: usize) -> bool {
+		critical_section::with(|cs| {
+			self.modules
+				.borrow_ref(cs)
+				.get(index)
+				.is_some_and(|module| module.attached)
+		})
+	}
+
+	pub fn get(&self) -> Vec<ModuleInfo> {
+		critical_section::with(|cs| self.modules.borrow_ref(cs).clone())
+	}
+
+	/// Whether a module was plugged in or pulled out since the last call.
+	pub fn take_changed(&self) -> bool {
+		critical_section::with(|cs| self.changed.borrow(cs).replace(false))
+	}
+}
+
+impl Default for ModuleStatus {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// The keys on one key module, scanned only while it's plugged in.
+pub struct ModuleKeys<K: UpdateMatrix> {
+	matrix: K,
+	attached: bool,
+	/// Keys reported pressed and not yet released, released for the module if it's pulled out.
+	held: Vec<KeyId>,
+	scanned: Vec<KeyboardAction>,
+}
+
+impl<K: UpdateMatrix> ModuleKeys<K> {
+	pub fn new(matrix: K) -> Self {
+		Self {
+			matrix,
+			attached: false,
+			held: Vec::new(),
+			scanned: Vec::with_capacity(K::SIZE),
+		}
+	}
+
+	fn scan(&mut self, now: Instant, dt: Duration, output: &mut Vec<KeyboardAction>) {
+		self.matrix.update(now, dt, &mut self.scanned);
+		// only pass on changes that follow what was last reported, so a key still down when the
+		// module went back in isn't released twice
+		for action in self.scanned.drain(..) {
+			let held = self.held.iter().position(|key| *key == action.key_id);
+			match (action.action, held) {
+				(KeyState::Pressed, None) => self.held.push(action.key_id),
+				(KeyState::Released, Some(index)) => {
+					self.held.swap_remove(index);
+				}
+				_ => continue,
+			}
+			output.push(action);
+		}
+	}
+
+	fn release_held(&mut self, now: Instant, output: &mut Vec<KeyboardAction>) {
+		for key in self.held.drain(..) {
+			output.push(KeyboardAction::released(key, now));
+		}
+	}
+}
+
+/// A keypad's own matrix plus the key modules that can be plugged into it. Each module is
+/// scanned only while `status` has it attached, and any of its keys held when it's pulled out
+/// are released. Modules must be given in the order their ports were registered with `status`.
+pub struct ModularMatrix<M: UpdateMatrix, K: UpdateMatrix> {
+	base: M,
+	modules: Vec<ModuleKeys<K>>,
+	status: &'static ModuleStatus,
+}
+
+impl<M: UpdateMatrix, K: UpdateMatrix> ModularMatrix<M, K> {
+	pub fn new(base: M, modules: Vec<ModuleKeys<K>>, status: &'static ModuleStatus) -> Self {
+		Self {
+			base,
+			modules,
+			status,
+		}
+	}
+}
+
+impl<M: UpdateMatrix, K: UpdateMatrix> UpdateMatrix for ModularMatrix<M, K> {
+	fn update(&mut self, now: Instant, dt: Duration, output: &mut Vec<KeyboardAction>) {
+		self.base.update(now, dt, output);
+
+		for (index, module) in self.modules.iter_mut().enumerate() {
+			let attached = self.status.is_attached(index);
+			if module.attached && !attached {
+				module.release_held(now, output);
+			}
+			module.attached = attached;
+			if attached {
+				module.scan(now, dt, output);
+			}
+		}
+	}
+
+	/// Only the keypad's own matrix; modules vary, so a fixed layout can't describe them.
+	fn scan_raw(&mut self) -> RawMatrixScan {
+		self.base.scan_raw()
+	}
+
+	/// Only wakes for the keypad's own keys, modules aren't watched while asleep.
+	async fn wait_for_key(&mut self) {
+		self.base.wait_for_key().await;
+	}
+
+	const SIZE: usize = M::SIZE + K::SIZE;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use fugit::ExtU64;
+	use uuid::Uuid;
+
+	/// Reports whatever is queued in `next` on each scan.
+	struct QueuedMatrix {
+		next: Vec<KeyboardAction>,
+	}
+
+	impl UpdateMatrix for QueuedMatrix {
+		fn update(&mut self, _now: Instant, _dt: Duration, output: &mut Vec<KeyboardAction>) {
+			output.append(&mut self.next);
+		}
+
+		fn scan_raw(&mut self) -> RawMatrixScan {
+			RawMatrixScan {
+				rows: 0,
+				cols: 0,
+				bitmap: Vec::new(),
+			}
+		}
+
+		async fn wait_for_key(&mut self) {}
+
+		const SIZE: usize = 4;
+	}
+
+	fn key(n: u128) -> KeyId {
+		KeyId::new(Uuid::from_u128(n))
+	}
+
+	fn queued() -> QueuedMatrix {
+		QueuedMatrix { next: Vec::new() }
+	}
+
+	#[test]
+	fn debounce_needs_agreeing_probes() {
+		let mut debounce = ModuleDebounce::default();
+		assert_eq!(debounce.update(true), None);
+		assert_eq!(debounce.update(false), None);
+		assert_eq!(debounce.update(true), None);
+		assert_eq!(debounce.update(true), None);
+		assert_eq!(debounce.update(true), Some(true));
+		assert_eq!(debounce.update(true), None);
+	}
+
+	#[test]
+	fn detached_modules_arent_scanned() {
+		static STATUS: ModuleStatus = ModuleStatus::new();
+		STATUS.register("numpad");
+		let mut module = queued();
+		module
+			.next
+			.push(KeyboardAction::pressed(key(1), Instant::from_ticks(0)));
+		let mut matrix = ModularMatrix::new(queued(), vec![ModuleKeys::new(module)], &STATUS);
+
+		let mut output = Vec::new();
+		matrix.update(Instant::from_ticks(0), 1.millis(), &mut output);
+		assert!(output.is_empty());
+
+		STATUS.set_attached(0, true);
+		assert!(STATUS.take_changed());
+		matrix.update(Instant::from_ticks(0), 1.millis(), &mut output);
+		assert_eq!(output.len(), 1);
+		assert_eq!(output[0].key_id, key(1));
+		assert_eq!(output[0].action, KeyState::Pressed);
+	}
+
+	#[test]
+	fn pulling_a_module_out_releases_its_held_keys() {
+		static STATUS: ModuleStatus = ModuleStatus::new();
+		STATUS.register("numpad");
+		STATUS.set_attached(0, true);
+		let mut module = queued();
+		module
+			.next
+			.push(KeyboardAction::pressed(key(1), Instant::from_ticks(0)));
+		module
+			.next
+			.push(KeyboardAction::pressed(key(2), Instant::from_ticks(0)));
+		module
+			.next
+			.push(KeyboardAction::released(key(2), Instant::from_ticks(0)));
+		let mut matrix = ModularMatrix::new(queued(), vec![ModuleKeys::new(module)], &STATUS);
+
+		let mut output = Vec::new();
+		matrix.update(Instant::from_ticks(0), 1.millis(), &mut output);
+		assert_eq!(output.len(), 3);
+
+		STATUS.set_attached(0, false);
+		output.clear();
+		matrix.update(Instant::from_ticks(10), 1.millis(), &mut output);
+		assert_eq!(output.len(), 1);
+		assert_eq!(output[0].key_id, key(1));
+		assert_eq!(output[0].action, KeyState::Released);
+		assert_eq!(output[0].timestamp, Instant::from_ticks(10));
+
+		// the module's own release once it's back in was already sent
+		STATUS.set_attached(0, true);
+		matrix.modules[0]
+			.matrix
+			.next
+			.push(KeyboardAction::released(key(1), Instant::from_ticks(20)));
+		output.clear();
+		matrix.update(Instant::from_ticks(20), 1.millis(), &mut output);
+		assert!(output.is_empty());
+	}
+}
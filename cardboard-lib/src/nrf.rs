@@ -0,0 +1,163 @@
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use cortex_m::peripheral::SCB;
+use embassy_nrf::gpio::{Input, Output};
+use embassy_nrf::nvmc::{Error, Nvmc, PAGE_SIZE};
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::context::{Reboot, RebootToBootloader};
+use crate::indicator::Indicator;
+use crate::input::{ColPin, RowPin};
+use crate::output::OutputPin;
+use crate::power::PowerSourceSense;
+use crate::storage::BlockFlash;
+
+// the NVMC writes whole words
+const WRITE_SIZE: usize = 4;
+
+// FICR.DEVICEID, 64 bits unique to each chip
+const FICR_DEVICEID: *const u32 = 0x1000_0060 as *const u32;
+
+// POWER.GPREGRET, kept across a soft reset
+const GPREGRET: *mut u32 = 0x4000_051C as *mut u32;
+
+// tells the Adafruit UF2 bootloader to stay in DFU mode
+const GPREGRET_UF2_DFU: u32 = 0x57;
+
+impl RowPin for Output<'_> {
+	fn set_high(&mut self) {
+		self.set_high();
+	}
+
+	fn set_low(&mut self) {
+		self.set_low();
+	}
+}
+
+impl Indicator for Output<'_> {
+	fn set(&mut self, on: bool) {
+		self.set_level(on.into());
+	}
+}
+
+impl OutputPin for Output<'_> {
+	fn set(&mut self, high: bool) {
+		self.set_level(high.into());
+	}
+}
+
+impl PowerSourceSense for Input<'_> {
+	fn on_external_power(&mut self) -> bool {
+		self.is_high()
+	}
+}
+
+impl ColPin for Input<'_> {
+	fn is_high(&self) -> bool {
+		self.is_high()
+	}
+
+	fn wait_for_high(&mut self) -> Pin<Box<dyn Future<Output = ()> + '_>> {
+		Box::pin(Input::wait_for_high(self))
+	}
+}
+
+/// The chip's factory programmed device ID, to derive the `DeviceId` from.
+pub fn chip_id() -> u64 {
+	let low = unsafe { FICR_DEVICEID.read_volatile() };
+	let high = unsafe { FICR_DEVICEID.add(1).read_volatile() };
+	((high as u64) << 32) | low as u64
+}
+
+/// A partition of the internal flash, written through the NVMC.
+pub struct NrfFlashMemory<'d> {
+	storage_addr: *const u8,
+	length: usize,
+	flash: Nvmc<'d>,
+}
+
+impl<'d> NrfFlashMemory<'d> {
+	pub fn new(storage_addr: *const u8, length: usize, flash: Nvmc<'d>) -> Self {
+		if storage_addr as usize % PAGE_SIZE != 0 {
+			error!(
+				"Base address is not erase block aligned: {}",
+				storage_addr as usize
+			);
+			panic!("Base address is not erase block aligned");
+		}
+
+		if length % PAGE_SIZE != 0 {
+			error!("Length is not erase block aligned: {}", length);
+			panic!("Length is not erase block aligned");
+		}
+
+		NrfFlashMemory {
+			storage_addr,
+			length,
+			flash,
+		}
+	}
+
+	// flash starts at address 0 on the nRF52
+	fn get_flash_offset(&self) -> usize {
+		self.storage_addr as usize
+	}
+}
+
+impl<'a> BlockFlash for NrfFlashMemory<'a> {
+	fn as_slice(&self) -> &'static [u8] {
+		unsafe { core::slice::from_raw_parts(self.storage_addr, self.length) }
+	}
+
+	async fn erase(&mut self, offset: usize, length: usize) -> Result<(), &'static str> {
+		let start = offset + self.get_flash_offset();
+		let end = start + length;
+
+		self.flash.erase(start as u32, end as u32).map_err(|e| {
+			error!("Error erasing flash memory: {:?}", e);
+			match e {
+				Error::OutOfBounds => "Erase out of bounds",
+				Error::Unaligned => "Erase not block aligned",
+			}
+		})
+	}
+
+	async fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), &'static str> {
+		self.flash
+			.write((self.get_flash_offset() + offset) as u32, data)
+			.map_err(|e| {
+				error!("Error writing to flash memory: {:?}", e);
+				match e {
+					Error::OutOfBounds => "Write out of bounds",
+					Error::Unaligned => "Write not block aligned",
+				}
+			})
+	}
+
+	fn length(&self) -> usize {
+		self.length
+	}
+
+	const ERASE_BLOCK_SIZE: usize = PAGE_SIZE;
+
+	const WRITE_BLOCK_SIZE: usize = WRITE_SIZE;
+}
+
+pub struct NrfReboot {}
+
+impl Reboot for NrfReboot {
+	fn reboot(&mut self) -> ! {
+		SCB::sys_reset()
+	}
+}
+
+/// Reboots into the UF2 bootloader most nRF52 keyboards ship with.
+pub struct NrfRebootToBootloader {}
+
+impl RebootToBootloader for NrfRebootToBootloader {
+	fn reboot_to_bootloader(&self) -> ! {
+		unsafe { GPREGRET.write_volatile(GPREGRET_UF2_DFU) };
+		SCB::sys_reset()
+	}
+}
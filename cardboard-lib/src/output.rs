@@ -0,0 +1,315 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use fugit::ExtU64;
+
+use crate::serialize::Readable;
+use crate::stream::{ReadAsync, ReadAsyncExt};
+use crate::time::Duration;
+
+/// What to do with an auxiliary output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub enum OutputMode {
+	Low,
+	High,
+	Toggle,
+	/// Drives the output high, then low again after the given time.
+	Pulse {
+		duration_ms: u16,
+	},
+}
+
+impl Readable for OutputMode {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let discriminator = reader.read_u8().await.ok_or("Failed to read output mode")?;
+		match discriminator {
+			0 => Ok(OutputMode::Low),
+			1 => Ok(OutputMode::High),
+			2 => Ok(OutputMode::Toggle),
+			3 => Ok(OutputMode::Pulse {
+				duration_ms: reader
+					.read_u16()
+					.await
+					.ok_or("Failed to read pulse duration")?,
+			}),
+			_ => Err("Invalid output mode"),
+		}
+	}
+}
+
+/// Sets an auxiliary output, named as the board declares it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub struct OutputEvent {
+	pub name: String,
+	pub mode: OutputMode,
+}
+
+impl Readable for OutputEvent {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let name = reader
+			.read_string_u8()
+			.await
+			.ok_or("Failed to read output name")?;
+		let mode = OutputMode::read_from(reader).await?;
+		Ok(OutputEvent { name, mode })
+	}
+}
+
+/// A GPIO pin driving external hardware, such as a relay or a camera shutter.
+pub trait OutputPin {
+	fn set(&mut self, high: bool);
+}
+
+/// A pin the board declares for macros to drive, with any pulse in progress.
+pub struct AuxOutput<P: OutputPin> {
+	pub name: &'static str,
+	pin: P,
+	high: bool,
+	pulse_remaining: Option<Duration>,
+}
+
+impl<P: OutputPin> AuxOutput<P> {
+	pub fn new(name: &'static str, mut pin: P) -> Self {
+		pin.set(false);
+		Self {
+			name,
+			pin,
+			high: false,
+			pulse_remaining: None,
+		}
+	}
+
+	fn write(&mut self, high: bool) {
+		self.pin.set(high);
+		self.high = high;
+	}
+
+	fn apply(&mut self, mode: OutputMode) {
+		self.pulse_remaining = None;
+		match mode {
+			OutputMode::Low => self.write(false),
+			OutputMode::High => self.write(true),
+			OutputMode::Toggle => self.write(!self.high),
+			OutputMode::Pulse { duration_ms } => {
+				self.write(true);
+				self.pulse_remaining = Some((duration_ms as u64).millis());
+			}
+		}
+	}
+
+	fn advance(&mut self, dt: Duration) {
+		if let Some(remaining) = self.pulse_remaining {
+			if remaining <= dt {
+				self.pulse_remaining = None;
+				self.write(false);
+			} else {
+				self.pulse_remaining = Some(remaining - dt);
+			}
+		}
+	}
+}
+
+/// The board's auxiliary outputs, driven by the keypad task.
+pub struct AuxOutputs<P: OutputPin> {
+	outputs: Vec<AuxOutput<P>>,
+}
+
+impl<P: OutputPin> AuxOutputs<P> {
+	pub fn new(outputs: Vec<AuxOutput<P>>) -> Self {
+		Self { outputs }
+	}
+
+	/// Applies an event to the output with its name, if the board has one.
+	pub fn apply(&mut self, event: &OutputEvent) {
+		match self
+			.outputs
+			.iter_mut()
+			.find(|output| output.name == event.name)
+		{
+			Some(output) => output.apply(event.mode),
+			None => warn!("No output named {:?}", event.name.as_str()),
+		}
+	}
+
+	/// Ends pulses that have run their time.
+	pub fn advance(&mut self, dt: Duration) {
+		self.outputs
+			.iter_mut()
+			.for_each(|output| output.advance(dt));
+	}
+
+	/// True while a pulse is running, so the keypad stays awake to end it on time.
+	pub fn is_pulsing(&self) -> bool {
+		self.outputs
+			.iter()
+			.any(|output| output.pulse_remaining.is_some())
+	}
+}
+
+/// Sets the duty cycle of a PWM output, named as the board declares it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub struct PwmEvent {
+	pub name: String,
+	pub duty_percent: u8,
+}
+
+impl Readable for PwmEvent {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let name = reader
+			.read_string_u8()
+			.await
+			.ok_or("Failed to read PWM output name")?;
+		let duty_percent = reader.read_u8().await.ok_or("Failed to read duty cycle")?;
+		if duty_percent > 100 {
+			return Err("Invalid duty cycle");
+		}
+		Ok(PwmEvent { name, duty_percent })
+	}
+}
+
+/// A PWM channel driving external hardware, such as a lamp dimmer or a fan.
+pub trait PwmPin {
+	fn set_duty(&mut self, percent: u8);
+}
+
+/// A PWM channel the board declares for macros to drive.
+pub struct PwmOutput<P: PwmPin> {
+	pub name: &'static str,
+	pin: P,
+}
+
+impl<P: PwmPin> PwmOutput<P> {
+	pub fn new(name: &'static str, mut pin: P) -> Self {
+		pin.set_duty(0);
+		Self { name, pin }
+	}
+}
+
+/// The board's PWM outputs, driven by the keypad task.
+pub struct PwmOutputs<P: PwmPin> {
+	outputs: Vec<PwmOutput<P>>,
+}
+
+impl<P: PwmPin> PwmOutputs<P> {
+	pub fn new(outputs: Vec<PwmOutput<P>>) -> Self {
+		Self { outputs }
+	}
+
+	/// Applies an event to the output with its name, if the board has one.
+	pub fn apply(&mut self, event: &PwmEvent) {
+		match self
+			.outputs
+			.iter_mut()
+			.find(|output| output.name == event.name)
+		{
+			Some(output) => output.pin.set_duty(event.duty_percent),
+			None => warn!("No PWM output named {:?}", event.name.as_str()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use alloc::rc::Rc;
+	use alloc::vec;
+	use core::cell::RefCell;
+
+	#[derive(Clone, Default)]
+	struct MockPin {
+		writes: Rc<RefCell<Vec<bool>>>,
+	}
+
+	impl OutputPin for MockPin {
+		fn set(&mut self, high: bool) {
+			self.writes.borrow_mut().push(high);
+		}
+	}
+
+	fn event(name: &str, mode: OutputMode) -> OutputEvent {
+		OutputEvent {
+			name: name.into(),
+			mode,
+		}
+	}
+
+	#[test]
+	fn pulses_end_after_their_duration() {
+		let pin = MockPin::default();
+		let mut outputs = AuxOutputs::new(vec![AuxOutput::new("shutter", pin.clone())]);
+
+		outputs.apply(&event("shutter", OutputMode::Pulse { duration_ms: 50 }));
+		outputs.advance(30.millis());
+		assert!(outputs.is_pulsing());
+		outputs.advance(30.millis());
+		assert!(!outputs.is_pulsing());
+
+		assert_eq!(*pin.writes.borrow(), [false, true, false]);
+	}
+
+	#[test]
+	fn events_only_drive_the_named_output() {
+		let relay = MockPin::default();
+		let lamp = MockPin::default();
+		let mut outputs = AuxOutputs::new(vec![
+			AuxOutput::new("relay", relay.clone()),
+			AuxOutput::new("lamp", lamp.clone()),
+		]);
+
+		outputs.apply(&event("lamp", OutputMode::Toggle));
+		outputs.apply(&event("lamp", OutputMode::Toggle));
+		outputs.apply(&event("fan", OutputMode::High));
+
+		assert_eq!(*relay.writes.borrow(), [false]);
+		assert_eq!(*lamp.writes.borrow(), [false, true, false]);
+	}
+
+	#[derive(Clone, Default)]
+	struct MockPwmPin {
+		duties: Rc<RefCell<Vec<u8>>>,
+	}
+
+	impl PwmPin for MockPwmPin {
+		fn set_duty(&mut self, percent: u8) {
+			self.duties.borrow_mut().push(percent);
+		}
+	}
+
+	#[test]
+	fn pwm_outputs_start_off_and_take_the_named_duty() {
+		let fan = MockPwmPin::default();
+		let mut outputs = PwmOutputs::new(vec![PwmOutput::new("fan", fan.clone())]);
+
+		outputs.apply(&PwmEvent {
+			name: "fan".into(),
+			duty_percent: 60,
+		});
+		outputs.apply(&PwmEvent {
+			name: "lamp".into(),
+			duty_percent: 100,
+		});
+
+		assert_eq!(*fan.duties.borrow(), [0, 60]);
+	}
+
+	#[tokio::test]
+	async fn duty_cycles_over_100_percent_are_rejected() {
+		let bytes = [3, b'f', b'a', b'n', 101];
+
+		assert_eq!(
+			PwmEvent::read_from(&mut &bytes[..]).await,
+			Err("Invalid duty cycle")
+		);
+	}
+}
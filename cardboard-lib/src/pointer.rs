@@ -0,0 +1,163 @@
+use core::cell::Cell;
+use critical_section::Mutex;
+
+/// A trackball, trackpoint or similar sensor.
+pub trait PointingSensor {
+	/// Brings the sensor up; called once before the first read.
+	async fn init(&mut self) -> Result<(), &'static str> {
+		Ok(())
+	}
+
+	/// Counts moved along each axis since the last read.
+	async fn read_motion(&mut self) -> Result<(i16, i16), &'static str>;
+}
+
+/// How a sensor's motion is adjusted before it reaches the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PointerConfig {
+	/// Percent to scale motion by, where 100 passes counts through unchanged.
+	pub sensitivity_percent: u16,
+	/// Degrees to turn motion counter-clockwise by, for a sensor mounted at an angle.
+	pub rotation_degrees: u16,
+}
+
+impl Default for PointerConfig {
+	fn default() -> Self {
+		Self {
+			sensitivity_percent: 100,
+			rotation_degrees: 0,
+		}
+	}
+}
+
+/// One, as `sin_q14` returns it.
+const ONE_Q14: i64 = 1 << 14;
+/// One count, once motion has been rotated and scaled by a percentage.
+const ONE: i64 = ONE_Q14 * 100;
+
+/// Sine of a whole number of degrees in 1/16384ths, from Bhaskara's approximation, which is
+/// within 0.2% and plenty for a mounting angle.
+fn sin_q14(degrees: u16) -> i64 {
+	let degrees = (degrees % 360) as i64;
+	let (degrees, sign) = match degrees < 180 {
+		true => (degrees, 1),
+		false => (degrees - 180, -1),
+	};
+	let p = degrees * (180 - degrees);
+	sign * 4 * p * ONE_Q14 / (40500 - p)
+}
+
+/// Applies a `PointerConfig` to a stream of sensor reads, carrying the fractions of a count
+/// over to the next read so slow movement isn't lost.
+pub struct PointerTransform {
+	cos: i64,
+	sin: i64,
+	sensitivity: i64,
+	remainder: (i64, i64),
+}
+
+impl PointerTransform {
+	pub fn new(config: PointerConfig) -> Self {
+		Self {
+			cos: sin_q14(config.rotation_degrees % 360 + 90),
+			sin: sin_q14(config.rotation_degrees),
+			sensitivity: config.sensitivity_percent as i64,
+			remainder: (0, 0),
+		}
+	}
+
+	/// Turns and scales one read, in whole counts.
+	pub fn apply(&mut self, x: i16, y: i16) -> (i32, i32) {
+		let (x, y) = (x as i64, y as i64);
+		// y points down, as HID has it, so this turns counter-clockwise on screen
+		let turned_x = self.remainder.0 + (x * self.cos + y * self.sin) * self.sensitivity;
+		let turned_y = self.remainder.1 + (y * self.cos - x * self.sin) * self.sensitivity;
+		self.remainder = (turned_x % ONE, turned_y % ONE);
+		((turned_x / ONE) as i32, (turned_y / ONE) as i32)
+	}
+}
+
+/// Motion waiting for the keypad task to report, added to by the pointer task.
+pub struct PointerMotion {
+	pending: Mutex<Cell<(i32, i32)>>,
+}
+
+impl PointerMotion {
+	pub const fn new() -> Self {
+		Self {
+			pending: Mutex::new(Cell::new((0, 0))),
+		}
+	}
+
+	pub fn add(&self, x: i32, y: i32) {
+		critical_section::with(|cs| {
+			let pending = self.pending.borrow(cs);
+			let (px, py) = pending.get();
+			pending.set((px.saturating_add(x), py.saturating_add(y)));
+		});
+	}
+
+	/// Everything added since the last call.
+	pub fn take(&self) -> (i32, i32) {
+		critical_section::with(|cs| self.pending.borrow(cs).replace((0, 0)))
+	}
+}
+
+impl Default for PointerMotion {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn default_config_passes_counts_through() {
+		let mut transform = PointerTransform::new(PointerConfig::default());
+		assert_eq!(transform.apply(5, -3), (5, -3));
+		assert_eq!(transform.apply(-1000, 1000), (-1000, 1000));
+	}
+
+	#[test]
+	fn rotation_turns_motion_counter_clockwise() {
+		let mut transform = PointerTransform::new(PointerConfig {
+			sensitivity_percent: 100,
+			rotation_degrees: 90,
+		});
+		// right turns into up, and down into right
+		assert_eq!(transform.apply(10, 0), (0, -10));
+		assert_eq!(transform.apply(0, 10), (10, 0));
+	}
+
+	#[test]
+	fn fractions_carry_over_between_reads() {
+		let mut transform = PointerTransform::new(PointerConfig {
+			sensitivity_percent: 50,
+			rotation_degrees: 0,
+		});
+		assert_eq!(transform.apply(1, -1), (0, 0));
+		assert_eq!(transform.apply(1, -1), (1, -1));
+	}
+
+	#[test]
+	fn sensor_motion_is_spread_over_reports() {
+		use crate::hid::{HidDevice, Mouse};
+
+		let mut mouse = Mouse::new();
+		mouse.add_motion(200, -3);
+		assert_eq!(mouse.create_report().unwrap()[1..3], [127, (-3i8) as u8]);
+		assert_eq!(mouse.create_report().unwrap()[1..3], [73, 0]);
+		assert_eq!(mouse.create_report().unwrap()[1..3], [0, 0]);
+	}
+
+	#[test]
+	fn motion_is_taken_once() {
+		let motion = PointerMotion::new();
+		motion.add(3, 4);
+		motion.add(-1, 1);
+		assert_eq!(motion.take(), (2, 5));
+		assert_eq!(motion.take(), (0, 0));
+	}
+}
@@ -0,0 +1,140 @@
+use core::cell::Cell;
+use critical_section::Mutex;
+
+use crate::battery::BatteryLevel;
+use crate::time::Duration;
+
+/// Where the keypad is drawing power from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerSource {
+	/// USB or another supply that won't run out.
+	External,
+	Battery,
+}
+
+/// Detects external power, such as a VBUS sense pin.
+pub trait PowerSourceSense {
+	fn on_external_power(&mut self) -> bool;
+}
+
+/// How hard the keypad works: how often it scans, how bright the LEDs are, and how soon it
+/// sleeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerMode {
+	pub scan_interval: Duration,
+	/// Scales every LED color, where 100 leaves them as they are.
+	pub brightness_percent: u8,
+	/// None never sleeps.
+	pub idle_timeout: Option<Duration>,
+}
+
+/// Picks a power mode from the power source and battery level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerPolicy {
+	pub external: PowerMode,
+	pub battery: PowerMode,
+	/// Used instead of `battery` once the charge drops to `low_battery_percent`.
+	pub low_battery: PowerMode,
+	pub low_battery_percent: u8,
+}
+
+impl PowerPolicy {
+	/// Before the first measurement the battery is assumed to be fine.
+	pub fn mode(&self, source: PowerSource, level: Option<BatteryLevel>) -> PowerMode {
+		match source {
+			PowerSource::External => self.external,
+			PowerSource::Battery
+				if level.is_some_and(|level| level.percent <= self.low_battery_percent) =>
+			{
+				self.low_battery
+			}
+			PowerSource::Battery => self.battery,
+		}
+	}
+}
+
+/// The mode the policy last picked, set by the battery task and followed by the keypad and
+/// lighting tasks. Shared the same way as `UsbStats`.
+pub struct PowerState {
+	mode: Mutex<Cell<Option<PowerMode>>>,
+}
+
+impl PowerState {
+	pub const fn new() -> Self {
+		Self {
+			mode: Mutex::new(Cell::new(None)),
+		}
+	}
+
+	pub fn set(&self, mode: PowerMode) {
+		critical_section::with(|cs| self.mode.borrow(cs).set(Some(mode)));
+	}
+
+	/// None until the policy first runs, or on boards without one; tasks then keep their own
+	/// settings.
+	pub fn get(&self) -> Option<PowerMode> {
+		critical_section::with(|cs| self.mode.borrow(cs).get())
+	}
+}
+
+impl Default for PowerState {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use fugit::ExtU64;
+
+	fn policy() -> PowerPolicy {
+		PowerPolicy {
+			external: PowerMode {
+				scan_interval: 1.millis(),
+				brightness_percent: 100,
+				idle_timeout: Some(300.secs()),
+			},
+			battery: PowerMode {
+				scan_interval: 2.millis(),
+				brightness_percent: 50,
+				idle_timeout: Some(60.secs()),
+			},
+			low_battery: PowerMode {
+				scan_interval: 2.millis(),
+				brightness_percent: 0,
+				idle_timeout: Some(30.secs()),
+			},
+			low_battery_percent: 15,
+		}
+	}
+
+	fn level(percent: u8) -> Option<BatteryLevel> {
+		Some(BatteryLevel {
+			voltage_mv: 3700,
+			percent,
+		})
+	}
+
+	#[test]
+	fn external_power_ignores_the_battery_level() {
+		let policy = policy();
+
+		assert_eq!(
+			policy.mode(PowerSource::External, level(5)),
+			policy.external
+		);
+	}
+
+	#[test]
+	fn battery_power_drops_to_low_battery_at_the_threshold() {
+		let policy = policy();
+
+		assert_eq!(policy.mode(PowerSource::Battery, None), policy.battery);
+		assert_eq!(policy.mode(PowerSource::Battery, level(16)), policy.battery);
+		assert_eq!(
+			policy.mode(PowerSource::Battery, level(15)),
+			policy.low_battery
+		);
+	}
+}
@@ -4,23 +4,191 @@ use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::fmt::Debug;
-use defmt::Format;
+use core::ops::Range;
 use num_enum::TryFromPrimitive;
 use uuid::Uuid;
 
+use crate::display::DisplayWidget;
+use crate::encoder::EncoderScroll;
+use crate::haptic::HapticPattern;
 use crate::input::KeyId;
-use crate::serialize::Readable;
+use crate::lighting::{LedMapping, LightingEvent, MAX_LEDS, Rgb};
+use crate::output::{OutputEvent, PwmEvent};
+use crate::random::Rng;
+use crate::serialize::{Readable, Writeable};
+use crate::slider::SliderMapping;
 use crate::state::TagList;
-use crate::stream::{ReadAsync, ReadAsyncExt};
-
-const VERSION: u32 = 1;
+use crate::stream::{
+	ReadAsync, ReadAsyncExt, VarintLengths, WriteAsync, WriteAsyncExt, collection_capacity,
+	try_vec_with_capacity,
+};
+
+const VERSION: u32 = 14;
+/// Oldest profile format that can still be read. v1 macros have no loop limit, layers before
+/// v3 always trigger on press, actions before v4 have fixed delays and macros before v5 play at
+/// normal speed. Channel priorities came in v6, and v7 moved layer conditions from each key to
+/// the profile. Virtual keys have IDs from v8, v9 maps keys to LEDs, v10 gives key layers a
+/// backlight color and v11 lays out the display. From v12 every collection and string length is
+/// a varint, v13 maps encoders to scrolling and v14 maps the touch slider.
+const MIN_VERSION: u32 = 1;
 
 #[derive(Default)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub struct KeyboardProfile {
 	pub name: String,
+	/// Layers keys can bind macros to, in priority order.
+	pub layers: Vec<Layer>,
 	pub keys: Vec<DeviceKey>,
 	pub virtual_keys: Vec<VirtualKey>,
 	pub macros: Vec<Macro>,
+	#[cfg_attr(feature = "postcard", serde(skip))]
+	pub tags: TagTable,
+	/// LEDs under each key, for lighting events that target keys.
+	pub leds: Vec<LedMapping>,
+	/// What the display shows; the default layout if empty.
+	pub display: Vec<DisplayWidget>,
+	/// Encoders that scroll instead of running macros, per layer.
+	pub encoders: Vec<EncoderScroll>,
+	/// What the touch slider does, per layer.
+	pub sliders: Vec<SliderMapping>,
+}
+
+impl KeyboardProfile {
+	/// Numbers the tags used by layer conditions so layers can be matched by bitmask.
+	pub fn intern_tags(&mut self) {
+		let mut table = TagTable::default();
+
+		for layer in self.layers.iter_mut() {
+			layer.tag_mask = layer
+				.tags
+				.iter()
+				.try_fold(0, |mask, tag| table.intern(tag).map(|id| mask | (1 << id)));
+		}
+
+		self.tags = table;
+	}
+
+	/// Checks the limits reading a profile enforces, for profiles built some other way.
+	pub fn validate(&self) -> Result<(), &'static str> {
+		if self.layers.len() > MAX_LAYERS {
+			return Err("Too many layers");
+		}
+		if self.keys.len() > u8::MAX as usize {
+			return Err("Too many keys");
+		}
+		if self.virtual_keys.len() > MAX_VIRTUAL_KEYS {
+			return Err("Too many virtual keys");
+		}
+		if self.macros.len() > u16::MAX as usize {
+			return Err("Too many macros");
+		}
+		if self.leds.len() > MAX_LEDS {
+			return Err("Too many LED mappings");
+		}
+		if self.display.len() > u8::MAX as usize {
+			return Err("Too many display widgets");
+		}
+		if self.encoders.len() > u8::MAX as usize {
+			return Err("Too many encoder mappings");
+		}
+		if self.sliders.len() > u8::MAX as usize {
+			return Err("Too many slider mappings");
+		}
+		if !mapping_layers_in_range(self.encoders.iter().map(|m| m.layer), self.layers.len())
+			|| !mapping_layers_in_range(self.sliders.iter().map(|m| m.layer), self.layers.len())
+		{
+			return Err("Layer index out of range");
+		}
+
+		let key_layers = self.keys.iter().map(|key| &key.layers);
+		let virtual_key_layers = self.virtual_keys.iter().map(|key| &key.layers);
+		for layers in key_layers.chain(virtual_key_layers) {
+			if layers
+				.layers
+				.iter()
+				.any(|layer| layer.index.get_index() >= self.layers.len())
+			{
+				return Err("Layer index out of range");
+			}
+		}
+
+		for m in self.macros.iter() {
+			if m.speed_percent == 0 {
+				return Err("Invalid macro speed");
+			}
+
+			let sequences = [&m.start_sequence, &m.loop_sequence, &m.end_sequence];
+			for action in sequences
+				.iter()
+				.flat_map(|sequence| sequence.actions.iter())
+			{
+				if action
+					.predelay_max_ms
+					.is_some_and(|max| max < action.predelay_ms)
+				{
+					return Err("Predelay range max is below min");
+				}
+				match &action.action_event {
+					ActionEvent::Text(text) if !crate::text::is_typeable(text) => {
+						return Err("Text has characters that can't be typed");
+					}
+					ActionEvent::Pwm(pwm) if pwm.duty_percent > 100 => {
+						return Err("Invalid duty cycle");
+					}
+					_ => {}
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Reads a profile serialized with postcard, validated like one read from flash.
+	#[cfg(feature = "postcard")]
+	pub fn from_postcard(bytes: &[u8]) -> Result<Self, &'static str> {
+		let mut profile: Self = crate::serialize::from_postcard(bytes)?;
+		profile.validate()?;
+		profile.intern_tags();
+		Ok(profile)
+	}
+}
+
+/// Bitmask of tag IDs from a `TagTable`.
+pub type TagMask = u64;
+
+/// The distinct tags a profile's layer conditions refer to; a tag's ID is its index here.
+/// Only the first 64 get an ID, layers using any others fall back to comparing strings.
+#[derive(Default)]
+pub struct TagTable {
+	tags: Vec<LayerTag>,
+}
+
+impl TagTable {
+	pub const MAX_TAGS: usize = TagMask::BITS as usize;
+
+	pub fn id_of(&self, tag: &LayerTag) -> Option<usize> {
+		self.tags.iter().position(|t| t == tag)
+	}
+
+	/// Mask of the given tags; tags without an ID aren't used by any layer and are ignored.
+	pub fn mask_of<'t>(&self, tags: impl IntoIterator<Item = &'t LayerTag>) -> TagMask {
+		tags.into_iter()
+			.filter_map(|tag| self.id_of(tag))
+			.fold(0, |mask, id| mask | (1 << id))
+	}
+
+	fn intern(&mut self, tag: &LayerTag) -> Option<usize> {
+		if let Some(id) = self.id_of(tag) {
+			return Some(id);
+		}
+
+		if self.tags.len() >= Self::MAX_TAGS {
+			return None;
+		}
+
+		self.tags.push(tag.clone());
+		Some(self.tags.len() - 1)
+	}
 }
 
 impl Readable for KeyboardProfile {
@@ -32,97 +200,350 @@ impl Readable for KeyboardProfile {
 			.read_u32()
 			.await
 			.ok_or("Failed to read profile version")?;
-		if version != VERSION {
+		if !(MIN_VERSION..=VERSION).contains(&version) {
 			return Err("Unsupported profile version");
 		}
 
-		let name = reader
-			.read_string_u8()
+		if version >= 12 {
+			read_profile(&mut VarintLengths(reader), version).await
+		} else {
+			read_profile(reader, version).await
+		}
+	}
+}
+
+async fn read_profile<R: ReadAsync>(
+	reader: &mut R,
+	version: u32,
+) -> Result<KeyboardProfile, &'static str> {
+	let name = reader
+		.read_string_u8()
+		.await
+		.ok_or("Failed to read profile name")?;
+
+	let mut ctx = ReadContext {
+		version,
+		layers: Vec::new(),
+	};
+	let ctx = &mut ctx;
+	if version >= 7 {
+		ctx.layers = reader
+			.read_collection_u8()
+			.await
+			.ok_or("Failed to read layers")?;
+		if ctx.layers.len() > MAX_LAYERS {
+			return Err("Too many layers");
+		}
+	}
+
+	let keys = read_versioned_collection_u8(reader, ctx)
+		.await
+		.ok_or("Failed to read keys")?;
+
+	let virtual_keys = read_versioned_collection_u8(reader, ctx)
+		.await
+		.ok_or("Failed to read virtual_keys")?;
+	if virtual_keys.len() > MAX_VIRTUAL_KEYS {
+		return Err("Too many virtual keys");
+	}
+
+	let macros = read_versioned_collection_u16(reader, ctx)
+		.await
+		.ok_or("Failed to read macros")?;
+
+	let leds: Vec<LedMapping> = if version >= 9 {
+		reader
+			.read_collection_u16()
 			.await
-			.ok_or("Failed to read profile name")?;
-		let keys = reader
+			.ok_or("Failed to read LED mappings")?
+	} else {
+		Vec::new()
+	};
+	if leds.len() > MAX_LEDS {
+		return Err("Too many LED mappings");
+	}
+
+	let display = if version >= 11 {
+		reader
 			.read_collection_u8()
 			.await
-			.ok_or("Failed to read keys")?;
+			.ok_or("Failed to read display widgets")?
+	} else {
+		Vec::new()
+	};
 
-		let virtual_keys = reader
+	let encoders: Vec<EncoderScroll> = if version >= 13 {
+		reader
 			.read_collection_u8()
 			.await
-			.ok_or("Failed to read virtual_keys")?;
-		if virtual_keys.len() > 32 {
-			return Err("Number of virtual keys exceeds 32");
-		}
+			.ok_or("Failed to read encoder mappings")?
+	} else {
+		Vec::new()
+	};
+	if !mapping_layers_in_range(encoders.iter().map(|m| m.layer), ctx.layers.len()) {
+		return Err("Layer index out of range");
+	}
 
-		let macros = reader
-			.read_collection_u16()
+	let sliders: Vec<SliderMapping> = if version >= 14 {
+		reader
+			.read_collection_u8()
 			.await
-			.ok_or("Failed to read macros")?;
+			.ok_or("Failed to read slider mappings")?
+	} else {
+		Vec::new()
+	};
+	if !mapping_layers_in_range(sliders.iter().map(|m| m.layer), ctx.layers.len()) {
+		return Err("Layer index out of range");
+	}
 
-		Ok(KeyboardProfile {
-			name,
-			keys,
-			virtual_keys,
-			macros,
-		})
+	let mut profile = KeyboardProfile {
+		name,
+		layers: core::mem::take(&mut ctx.layers),
+		keys,
+		virtual_keys,
+		macros,
+		tags: TagTable::default(),
+		leds,
+		display,
+		encoders,
+		sliders,
+	};
+	profile.intern_tags();
+
+	Ok(profile)
+}
+
+/// One record in a serialized profile that can be replaced without rewriting the rest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProfileRecord {
+	/// A key's layers, which follow its ID.
+	KeyLayers(KeyId),
+	/// A whole macro, by its place in the profile's macros.
+	Macro(MacroIndex),
+	/// A whole macro, by its ID.
+	MacroById(MacroId),
+}
+
+/// Finds the bytes of `record` in `data`, a profile serialized in any supported version.
+pub async fn find_record(data: &[u8], record: ProfileRecord) -> Result<Range<usize>, &'static str> {
+	let mut reader = data;
+	let version = reader
+		.read_u32()
+		.await
+		.ok_or("Failed to read profile version")?;
+	if !(MIN_VERSION..=VERSION).contains(&version) {
+		return Err("Unsupported profile version");
+	}
+
+	let range = if version >= 12 {
+		find_record_in(&mut VarintLengths(&mut reader), version, record).await?
+	} else {
+		find_record_in(&mut reader, version, record).await?
+	};
+	// the reader only knows how much it has left, which is counted from the end
+	Ok(data.len() - range.start..data.len() - range.end)
+}
+
+/// Reads `reader` up to `record`, returning how many bytes were left before and after it.
+async fn find_record_in<R: ReadAsync>(
+	reader: &mut R,
+	version: u32,
+	record: ProfileRecord,
+) -> Result<Range<usize>, &'static str> {
+	reader
+		.read_string_u8()
+		.await
+		.ok_or("Failed to read profile name")?;
+
+	let mut ctx = ReadContext {
+		version,
+		layers: Vec::new(),
+	};
+	if version >= 7 {
+		ctx.layers = reader
+			.read_collection_u8()
+			.await
+			.ok_or("Failed to read layers")?;
+	}
+
+	let keys = reader.read_length_u8().await.ok_or("Failed to read keys")?;
+	for _ in 0..keys {
+		let key_id = KeyId::read_from(reader).await?;
+		let start = remaining(reader)?;
+		DeviceLayers::read_versioned(reader, &mut ctx).await?;
+		if record == ProfileRecord::KeyLayers(key_id) {
+			return Ok(start..remaining(reader)?);
+		}
+	}
+	if let ProfileRecord::KeyLayers(_) = record {
+		return Err("Key not found in profile");
+	}
+
+	let virtual_keys = reader
+		.read_length_u8()
+		.await
+		.ok_or("Failed to read virtual_keys")?;
+	for _ in 0..virtual_keys {
+		VirtualKey::read_versioned(reader, &mut ctx).await?;
 	}
+
+	let macros = reader
+		.read_length_u16()
+		.await
+		.ok_or("Failed to read macros")?;
+	for index in 0..macros {
+		let start = remaining(reader)?;
+		let m = Macro::read_versioned(reader, &mut ctx).await?;
+		let found = match record {
+			ProfileRecord::Macro(i) => i.get_index() == index,
+			ProfileRecord::MacroById(id) => m.id == id,
+			ProfileRecord::KeyLayers(_) => false,
+		};
+		if found {
+			return Ok(start..remaining(reader)?);
+		}
+	}
+	Err("Macro not found in profile")
+}
+
+/// How many bytes `reader` has left. Record offsets are counted from the end, so a reader
+/// that can't tell can't find records.
+fn remaining<R: ReadAsync>(reader: &R) -> Result<usize, &'static str> {
+	reader
+		.remaining_hint()
+		.ok_or("Profile reader can't tell how much is left")
+}
+
+/// Whether every layer an encoder or slider mapping is tied to exists.
+fn mapping_layers_in_range(
+	mut mapping_layers: impl Iterator<Item = Option<LayerIndex>>,
+	layers: usize,
+) -> bool {
+	mapping_layers.all(|layer| layer.is_none_or(|layer| layer.get_index() < layers))
+}
+
+/// State shared by everything read from one profile.
+struct ReadContext {
+	version: u32,
+	/// The profile's layers. Before v7 these are collected from the keys as they're read.
+	layers: Vec<Layer>,
 }
 
+/// Profile items whose layout depends on the profile format version.
+trait ReadVersioned: Sized {
+	async fn read_versioned<R: ReadAsync>(
+		reader: &mut R,
+		ctx: &mut ReadContext,
+	) -> Result<Self, &'static str>;
+}
+
+async fn read_versioned_collection_u8<T: ReadVersioned, R: ReadAsync>(
+	reader: &mut R,
+	ctx: &mut ReadContext,
+) -> Option<Vec<T>> {
+	let num_items = reader.read_length_u8().await?;
+	read_versioned_items(reader, ctx, num_items).await
+}
+
+async fn read_versioned_collection_u16<T: ReadVersioned, R: ReadAsync>(
+	reader: &mut R,
+	ctx: &mut ReadContext,
+) -> Option<Vec<T>> {
+	let num_items = reader.read_length_u16().await?;
+	read_versioned_items(reader, ctx, num_items).await
+}
+
+async fn read_versioned_items<T: ReadVersioned, R: ReadAsync>(
+	reader: &mut R,
+	ctx: &mut ReadContext,
+	num_items: usize,
+) -> Option<Vec<T>> {
+	let mut items = try_vec_with_capacity(collection_capacity(reader, num_items))?;
+	for _ in 0..num_items {
+		items.push(T::read_versioned(reader, ctx).await.ok()?);
+	}
+	Some(items)
+}
+
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceKey {
 	pub id: KeyId,
 	pub layers: DeviceLayers,
 }
 
-impl Readable for DeviceKey {
-	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
-	where
-		Self: Sized,
-	{
+impl ReadVersioned for DeviceKey {
+	async fn read_versioned<R: ReadAsync>(
+		reader: &mut R,
+		ctx: &mut ReadContext,
+	) -> Result<Self, &'static str> {
 		let id = KeyId::read_from(reader).await?;
-		let layers: DeviceLayers = DeviceLayers::read_from(reader).await?;
+		let layers = DeviceLayers::read_versioned(reader, ctx).await?;
 
 		Ok(DeviceKey { id, layers })
 	}
 }
 
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub struct VirtualKey {
+	/// Lets the host set the key without knowing its index. `None` for keys from profiles
+	/// older than v8, which can only be set by index.
+	pub id: Option<VirtualKeyId>,
 	pub layers: DeviceLayers,
 }
 
-impl Readable for VirtualKey {
-	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
-	where
-		Self: Sized,
-	{
-		let layers: DeviceLayers = DeviceLayers::read_from(reader).await?;
+impl ReadVersioned for VirtualKey {
+	async fn read_versioned<R: ReadAsync>(
+		reader: &mut R,
+		ctx: &mut ReadContext,
+	) -> Result<Self, &'static str> {
+		let id = if ctx.version >= 8 {
+			Some(VirtualKeyId::read_from(reader).await?)
+		} else {
+			None
+		};
+		let layers = DeviceLayers::read_versioned(reader, ctx).await?;
 
-		Ok(VirtualKey { layers })
+		Ok(VirtualKey { id, layers })
 	}
 }
 
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceLayers {
 	pub layers: Vec<TaggedDeviceKeyLayer>,
 	pub default_layer: DeviceKeyLayer,
 }
 
 impl DeviceLayers {
-	pub fn get_active_layer(&self, tags: &TagList) -> &DeviceKeyLayer {
-		match self.layers.iter().find(|layer| layer.is_match(tags)) {
+	pub fn get_active_layer(&self, active_layers: LayerMask) -> &DeviceKeyLayer {
+		match self
+			.layers
+			.iter()
+			.find(|layer| active_layers & (1 << layer.index.get_index()) != 0)
+		{
 			Some(layer) => &layer.layer,
 			None => &self.default_layer,
 		}
 	}
+
+	/// Returns true if any of the key's layers sets a backlight color.
+	pub fn has_backlight(&self) -> bool {
+		self.default_layer.backlight.is_some()
+			|| self
+				.layers
+				.iter()
+				.any(|layer| layer.layer.backlight.is_some())
+	}
 }
 
-impl Readable for DeviceLayers {
-	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
-	where
-		Self: Sized,
-	{
-		let layers = reader
-			.read_collection_u8()
+impl ReadVersioned for DeviceLayers {
+	async fn read_versioned<R: ReadAsync>(
+		reader: &mut R,
+		ctx: &mut ReadContext,
+	) -> Result<Self, &'static str> {
+		let layers = read_versioned_collection_u8(reader, ctx)
 			.await
 			.ok_or("Failed to read layers")?;
-		let default_layer: DeviceKeyLayer = DeviceKeyLayer::read_from(reader).await?;
+		let default_layer = DeviceKeyLayer::read_versioned(reader, ctx).await?;
 
 		Ok(DeviceLayers {
 			layers,
@@ -131,64 +552,228 @@ impl Readable for DeviceLayers {
 	}
 }
 
-pub struct TaggedDeviceKeyLayer {
+/// Most virtual keys a profile can have. Devices may only support setting some of them; see
+/// `DeviceCapabilities::virtual_keys`.
+pub const MAX_VIRTUAL_KEYS: usize = 128;
+
+/// Bitmask of indices into `KeyboardProfile::layers`.
+pub type LayerMask = u64;
+
+pub const MAX_LAYERS: usize = LayerMask::BITS as usize;
+
+/// A named set of tag conditions that keys can bind macros to.
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub struct Layer {
+	pub name: String,
 	pub tags: Vec<LayerTag>,
 	pub match_type: TagMatchType,
-	pub layer: DeviceKeyLayer,
+	/// Set by `KeyboardProfile::intern_tags` when every tag has an ID.
+	#[cfg_attr(feature = "postcard", serde(skip))]
+	pub tag_mask: Option<TagMask>,
 }
 
-impl TaggedDeviceKeyLayer {
-	fn is_match(&self, tags: &TagList) -> bool {
-		tags.matches(self.tags.as_slice(), &self.match_type)
+impl Layer {
+	pub fn is_match(&self, tags: &TagList) -> bool {
+		match self.tag_mask {
+			Some(mask) if tags.has_tag_ids() => tags.matches_mask(mask, &self.match_type),
+			_ => tags.matches(self.tags.as_slice(), &self.match_type),
+		}
 	}
 }
 
-impl Readable for TaggedDeviceKeyLayer {
+impl Readable for Layer {
 	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
 	where
 		Self: Sized,
 	{
+		let name = reader
+			.read_string_u8()
+			.await
+			.ok_or("Failed to read layer name")?;
 		let tags = reader
 			.read_collection_u8()
 			.await
 			.ok_or("Failed to read tags")?;
-
 		let match_type = TagMatchType::read_from(reader).await?;
 
-		let layer: DeviceKeyLayer = DeviceKeyLayer::read_from(reader).await?;
-
-		Ok(TaggedDeviceKeyLayer {
+		Ok(Layer {
+			name,
 			tags,
 			match_type,
+			tag_mask: None,
+		})
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub struct LayerIndex(u8);
+
+impl LayerIndex {
+	pub const fn new(index: u8) -> Self {
+		LayerIndex(index)
+	}
+
+	pub fn get_index(&self) -> usize {
+		self.0 as usize
+	}
+}
+
+impl Readable for LayerIndex {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let index = reader.read_u8().await.ok_or("Failed to read layer index")?;
+		Ok(LayerIndex::new(index))
+	}
+}
+
+/// A key's bindings for when a profile layer is active.
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub struct TaggedDeviceKeyLayer {
+	pub index: LayerIndex,
+	pub layer: DeviceKeyLayer,
+}
+
+impl ReadVersioned for TaggedDeviceKeyLayer {
+	async fn read_versioned<R: ReadAsync>(
+		reader: &mut R,
+		ctx: &mut ReadContext,
+	) -> Result<Self, &'static str> {
+		let index = if ctx.version >= 7 {
+			let index = reader.read_u8().await.ok_or("Failed to read layer index")?;
+			if index as usize >= ctx.layers.len() {
+				return Err("Layer index out of range");
+			}
+			index
+		} else {
+			// keys used to carry their own conditions, which often repeat between keys
+			let tags: Vec<LayerTag> = reader
+				.read_collection_u8()
+				.await
+				.ok_or("Failed to read tags")?;
+			let match_type = TagMatchType::read_from(reader).await?;
+
+			let existing = ctx
+				.layers
+				.iter()
+				.position(|layer| layer.match_type == match_type && layer.tags == tags);
+			match existing {
+				Some(index) => index as u8,
+				None if ctx.layers.len() < MAX_LAYERS => {
+					ctx.layers.push(Layer {
+						name: String::new(),
+						tags,
+						match_type,
+						tag_mask: None,
+					});
+					(ctx.layers.len() - 1) as u8
+				}
+				None => return Err("Too many layers"),
+			}
+		};
+
+		let layer = DeviceKeyLayer::read_versioned(reader, ctx).await?;
+
+		Ok(TaggedDeviceKeyLayer {
+			index: LayerIndex(index),
 			layer,
 		})
 	}
 }
 
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceKeyLayer {
 	// TODO: remove this and modify state to keep track of active layer with something like Option<usize | ()>, where usize is the layer index, or where () is default layer
 	pub id: LayerId,
 	pub macros: Vec<MacroIndex>,
+	pub trigger: MacroTrigger,
+	/// Color of the key's LEDs while this layer is active.
+	pub backlight: Option<Rgb>,
 }
 
-impl Readable for DeviceKeyLayer {
-	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
-	where
-		Self: Sized,
-	{
+impl ReadVersioned for DeviceKeyLayer {
+	async fn read_versioned<R: ReadAsync>(
+		reader: &mut R,
+		ctx: &mut ReadContext,
+	) -> Result<Self, &'static str> {
 		let layer_id = LayerId::read_from(reader).await?;
 		let macros = reader
 			.read_collection_u8()
 			.await
 			.ok_or("Failed to read macro bindings for key")?;
+		let trigger = if ctx.version >= 3 {
+			MacroTrigger::read_from(reader).await?
+		} else {
+			MacroTrigger::Press
+		};
+		let backlight = if ctx.version >= 10 {
+			reader
+				.read_option()
+				.await
+				.ok_or("Failed to read key backlight")?
+		} else {
+			None
+		};
 
 		Ok(DeviceKeyLayer {
 			id: layer_id,
 			macros,
+			trigger,
+			backlight,
 		})
 	}
 }
 
+/// When a layer's macros start, relative to its key going down and up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub enum MacroTrigger {
+	/// Start on press, stop on release.
+	#[default]
+	Press,
+	/// Start on release. There's no key to hold, so the loop sequence is skipped.
+	Release,
+	/// Start once the key has been held this long, stop on release.
+	Hold { threshold_ms: u16 },
+	/// Start on a second press within this long of the first, stop on release.
+	DoublePress { window_ms: u16 },
+}
+
+impl Readable for MacroTrigger {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let discriminator = reader
+			.read_u8()
+			.await
+			.ok_or("Failed to read macro trigger")?;
+		let trigger = match discriminator {
+			0 => MacroTrigger::Press,
+			1 => MacroTrigger::Release,
+			2 => MacroTrigger::Hold {
+				threshold_ms: reader
+					.read_u16()
+					.await
+					.ok_or("Failed to read hold threshold")?,
+			},
+			3 => MacroTrigger::DoublePress {
+				window_ms: reader
+					.read_u16()
+					.await
+					.ok_or("Failed to read double press window")?,
+			},
+			_ => return Err("Invalid macro trigger"),
+		};
+
+		Ok(trigger)
+	}
+}
+
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub struct Macro {
 	pub id: MacroId,
 	pub name: String,
@@ -197,13 +782,52 @@ pub struct Macro {
 	pub start_sequence: Sequence,
 	pub loop_sequence: Sequence,
 	pub end_sequence: Sequence,
+	/// Number of times the loop sequence plays before the macro moves on to its end sequence,
+	/// even if the key is still held. `None` loops until the key is released.
+	pub loop_limit: Option<u16>,
+	/// Playback speed in percent, 100 being the speed the delays were recorded at. Only delays
+	/// are scaled.
+	pub speed_percent: u16,
+	/// Only matters to macros sharing a play channel; see [`ChannelPolicy`].
+	pub priority: u8,
+	pub channel_policy: ChannelPolicy,
+}
+
+/// How a macro treats lower priority macros playing on the same channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChannelPolicy {
+	/// Plays alongside them.
+	#[default]
+	Share,
+	/// Pauses them while it plays. They pick up where they left off once it's done, so keys
+	/// they're holding stay held in the meantime.
+	Preempt,
 }
 
-impl Readable for Macro {
+impl Readable for ChannelPolicy {
 	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
 	where
 		Self: Sized,
 	{
+		let discriminator = reader
+			.read_u8()
+			.await
+			.ok_or("Failed to read channel policy")?;
+
+		match discriminator {
+			0 => Ok(ChannelPolicy::Share),
+			1 => Ok(ChannelPolicy::Preempt),
+			_ => Err("Invalid channel policy"),
+		}
+	}
+}
+
+impl ReadVersioned for Macro {
+	async fn read_versioned<R: ReadAsync>(
+		reader: &mut R,
+		ctx: &mut ReadContext,
+	) -> Result<Self, &'static str> {
 		let id = MacroId::read_from(reader).await?;
 
 		let name = reader
@@ -220,9 +844,42 @@ impl Readable for Macro {
 			.await
 			.ok_or("Failed to read cut channels")?;
 
-		let start_sequence = Sequence::read_from(reader).await?;
-		let loop_sequence = Sequence::read_from(reader).await?;
-		let end_sequence = Sequence::read_from(reader).await?;
+		let start_sequence = Sequence::read_versioned(reader, ctx).await?;
+		let loop_sequence = Sequence::read_versioned(reader, ctx).await?;
+		let end_sequence = Sequence::read_versioned(reader, ctx).await?;
+
+		let has_loop_limit = ctx.version >= 2
+			&& reader
+				.read_bool()
+				.await
+				.ok_or("Failed to read loop limit")?;
+		let loop_limit = if has_loop_limit {
+			Some(reader.read_u16().await.ok_or("Failed to read loop limit")?)
+		} else {
+			None
+		};
+
+		let speed_percent = if ctx.version >= 5 {
+			reader
+				.read_u16()
+				.await
+				.ok_or("Failed to read macro speed")?
+		} else {
+			100
+		};
+		if speed_percent == 0 {
+			return Err("Invalid macro speed");
+		}
+
+		let (priority, channel_policy) = if ctx.version >= 6 {
+			let priority = reader
+				.read_u8()
+				.await
+				.ok_or("Failed to read macro priority")?;
+			(priority, ChannelPolicy::read_from(reader).await?)
+		} else {
+			(0, ChannelPolicy::Share)
+		};
 
 		Ok(Macro {
 			id,
@@ -232,21 +889,25 @@ impl Readable for Macro {
 			start_sequence,
 			loop_sequence,
 			end_sequence,
+			loop_limit,
+			speed_percent,
+			priority,
+			channel_policy,
 		})
 	}
 }
 
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub struct Sequence {
 	pub actions: Vec<Action>,
 }
 
-impl Readable for Sequence {
-	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
-	where
-		Self: Sized,
-	{
-		let actions = reader
-			.read_collection_u8()
+impl ReadVersioned for Sequence {
+	async fn read_versioned<R: ReadAsync>(
+		reader: &mut R,
+		ctx: &mut ReadContext,
+	) -> Result<Self, &'static str> {
+		let actions = read_versioned_collection_u8(reader, ctx)
 			.await
 			.ok_or("Failed to read actions")?;
 		Ok(Sequence { actions })
@@ -259,30 +920,63 @@ impl Default for Sequence {
 	}
 }
 
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub struct Action {
 	pub predelay_ms: u64,
+	/// If set, the delay is picked between `predelay_ms` and this each time the action plays.
+	pub predelay_max_ms: Option<u64>,
 	pub action_event: ActionEvent,
 }
 
-impl Readable for Action {
-	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
-	where
-		Self: Sized,
-	{
+impl Action {
+	pub fn sample_predelay_ms(&self, rng: &mut Rng) -> u64 {
+		match self.predelay_max_ms {
+			Some(max) => rng.range(self.predelay_ms, max),
+			None => self.predelay_ms,
+		}
+	}
+}
+
+impl ReadVersioned for Action {
+	async fn read_versioned<R: ReadAsync>(
+		reader: &mut R,
+		ctx: &mut ReadContext,
+	) -> Result<Self, &'static str> {
 		let predelay_ms = reader
 			.read_u64()
 			.await
 			.ok_or("Failed to read predelay ms")?;
+
+		let has_max = ctx.version >= 4
+			&& reader
+				.read_bool()
+				.await
+				.ok_or("Failed to read predelay range")?;
+		let predelay_max_ms = if has_max {
+			let max = reader
+				.read_u64()
+				.await
+				.ok_or("Failed to read predelay range")?;
+			if max < predelay_ms {
+				return Err("Predelay range max is below min");
+			}
+			Some(max)
+		} else {
+			None
+		};
+
 		let action_event = ActionEvent::read_from(reader).await?;
 
 		Ok(Action {
 			predelay_ms,
+			predelay_max_ms,
 			action_event,
 		})
 	}
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub enum ActionEvent {
 	None,
 	Keyboard(KeyboardEvent),
@@ -290,6 +984,22 @@ pub enum ActionEvent {
 	ConsumerControl(ConsumerControlEvent),
 	Layer(LayerEvent),
 	DebugAction(DebugEvent),
+	/// Typed out as key presses on a US ASCII layout, one press or release per tick.
+	Text(String),
+	/// Plays another macro as a subroutine; the sequence continues once it finishes.
+	RunMacro(MacroIndex),
+	Lighting(LightingEvent),
+	/// Beeps the buzzer. Tones queue up, so a macro can play a tune without waiting on each.
+	Tone {
+		freq_hz: u16,
+		duration_ms: u16,
+	},
+	/// Runs the vibration motor, for tactile confirmation.
+	Haptic(HapticPattern),
+	/// Drives one of the board's auxiliary outputs.
+	Output(OutputEvent),
+	/// Sets the duty cycle of one of the board's PWM outputs.
+	Pwm(PwmEvent),
 }
 
 impl Readable for ActionEvent {
@@ -308,6 +1018,32 @@ impl Readable for ActionEvent {
 			3 => ActionEvent::ConsumerControl(ConsumerControlEvent::read_from(reader).await?),
 			4 => ActionEvent::Layer(LayerEvent::read_from(reader).await?),
 			5 => ActionEvent::DebugAction(DebugEvent::read_from(reader).await?),
+			6 => {
+				let text = reader
+					.read_string_u16()
+					.await
+					.ok_or("Failed to read text")?;
+				if !crate::text::is_typeable(&text) {
+					return Err("Text has characters that can't be typed");
+				}
+				ActionEvent::Text(text)
+			}
+			7 => ActionEvent::RunMacro(MacroIndex::read_from(reader).await?),
+			8 => ActionEvent::Lighting(LightingEvent::read_from(reader).await?),
+			9 => {
+				let freq_hz = reader.read_u16().await.ok_or("Failed to read tone pitch")?;
+				let duration_ms = reader
+					.read_u16()
+					.await
+					.ok_or("Failed to read tone duration")?;
+				ActionEvent::Tone {
+					freq_hz,
+					duration_ms,
+				}
+			}
+			10 => ActionEvent::Haptic(HapticPattern::read_from(reader).await?),
+			11 => ActionEvent::Output(OutputEvent::read_from(reader).await?),
+			12 => ActionEvent::Pwm(PwmEvent::read_from(reader).await?),
 			_ => return Err("Invalid action event discriminator"),
 		};
 
@@ -315,6 +1051,8 @@ impl Readable for ActionEvent {
 	}
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub enum TagMatchType {
 	All,
 	Any,
@@ -335,6 +1073,7 @@ impl Readable for TagMatchType {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub struct LayerId(Uuid);
 
 impl LayerId {
@@ -354,6 +1093,30 @@ impl Readable for LayerId {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub struct VirtualKeyId(Uuid);
+
+impl VirtualKeyId {
+	pub const fn new(id: Uuid) -> Self {
+		VirtualKeyId(id)
+	}
+}
+
+impl Readable for VirtualKeyId {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let uuid = reader
+			.read_uuid()
+			.await
+			.ok_or("Failed to read VirtualKeyId")?;
+		Ok(VirtualKeyId::new(uuid))
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub struct MacroId(Uuid);
 
 impl MacroId {
@@ -372,7 +1135,9 @@ impl Readable for MacroId {
 	}
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Format)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub struct MacroIndex(u16);
 
 impl MacroIndex {
@@ -396,6 +1161,7 @@ impl Readable for MacroIndex {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub struct Channel(u8);
 
 impl Channel {
@@ -417,14 +1183,24 @@ impl Readable for Channel {
 	}
 }
 
-#[derive(Debug, PartialEq, Eq)]
-#[cfg_attr(test, derive(Clone))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub struct LayerTag(String);
 
 impl LayerTag {
 	pub fn new(tag: String) -> Self {
 		LayerTag(tag)
 	}
+
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+}
+
+impl Writeable for LayerTag {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_string_u8(&self.0).await
+	}
 }
 
 impl Readable for LayerTag {
@@ -440,6 +1216,7 @@ impl Readable for LayerTag {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub enum KeyboardEvent {
 	KeyDown(KeyboardKey),
 	KeyUp(KeyboardKey),
@@ -467,6 +1244,7 @@ impl Readable for KeyboardEvent {
 
 #[derive(Debug, Clone, Copy, TryFromPrimitive)]
 #[repr(u8)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub enum KeyboardKey {
 	A = 0x04,
 	B = 0x05,
@@ -604,6 +1382,7 @@ impl Readable for KeyboardKey {
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub enum MouseEvent {
 	ButtonDown(MouseButton),
 	ButtonUp(MouseButton),
@@ -634,6 +1413,7 @@ impl Readable for MouseEvent {
 
 #[derive(Clone, Debug, TryFromPrimitive)]
 #[repr(u8)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub enum MouseButton {
 	Left,
 	Right,
@@ -655,7 +1435,8 @@ impl Readable for MouseButton {
 	}
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub struct MouseScroll {
 	pub x: i32,
 	pub y: i32,
@@ -667,18 +1448,21 @@ impl Readable for MouseScroll {
 		Self: Sized,
 	{
 		let x = reader
-			.read_u16()
+			.read_i16()
 			.await
-			.ok_or("Failed to read mouse scroll x")? as i32;
+			.ok_or("Failed to read mouse scroll x")?
+			.into();
 		let y = reader
-			.read_u16()
+			.read_i16()
 			.await
-			.ok_or("Failed to read mouse scroll y")? as i32;
+			.ok_or("Failed to read mouse scroll y")?
+			.into();
 		Ok(MouseScroll { x, y })
 	}
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub struct MouseMove {
 	pub x: i32,
 	pub y: i32,
@@ -690,19 +1474,20 @@ impl Readable for MouseMove {
 		Self: Sized,
 	{
 		let x = reader
-			.read_u32()
+			.read_i32()
 			.await
-			.ok_or("Failed to read mouse move x")? as i32;
+			.ok_or("Failed to read mouse move x")?;
 		let y = reader
-			.read_u32()
+			.read_i32()
 			.await
-			.ok_or("Failed to read mouse move y")? as i32;
+			.ok_or("Failed to read mouse move y")?;
 		Ok(MouseMove { x, y })
 	}
 }
 
-#[derive(Clone, Debug, TryFromPrimitive)]
+#[derive(Clone, Copy, Debug, PartialEq, TryFromPrimitive)]
 #[repr(u8)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub enum ConsumerControlEvent {
 	RECORD = 0xB2,
 	FAST_FORWARD = 0xB3,
@@ -733,9 +1518,17 @@ impl Readable for ConsumerControlEvent {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub enum LayerEvent {
 	Clear(LayerTag),
 	Set(LayerTag),
+	/// Sets the tag if it isn't set, clears it if it is.
+	Toggle(LayerTag),
+	/// Sets the tag, clearing it again after a while. Setting it again restarts the timer.
+	SetFor {
+		tag: LayerTag,
+		duration_ms: u32,
+	},
 }
 
 impl Readable for LayerEvent {
@@ -743,18 +1536,28 @@ impl Readable for LayerEvent {
 	where
 		Self: Sized,
 	{
-		let value = reader.read_bool().await.ok_or("Failed to read value")?;
+		// used to be a bool for "clear"
+		let discriminator = reader.read_u8().await.ok_or("Failed to read value")?;
 		let tag = LayerTag::read_from(reader).await?;
 
-		if value {
-			Ok(LayerEvent::Clear(tag))
-		} else {
-			Ok(LayerEvent::Set(tag))
+		match discriminator {
+			0 => Ok(LayerEvent::Set(tag)),
+			1 => Ok(LayerEvent::Clear(tag)),
+			2 => Ok(LayerEvent::Toggle(tag)),
+			3 => {
+				let duration_ms = reader
+					.read_u32()
+					.await
+					.ok_or("Failed to read tag duration")?;
+				Ok(LayerEvent::SetFor { tag, duration_ms })
+			}
+			_ => Err("Invalid layer event"),
 		}
 	}
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
 pub enum DebugEvent {
 	Log(String),
 }
@@ -771,3 +1574,388 @@ impl Readable for DebugEvent {
 		Ok(DebugEvent::Log(log))
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::slider::SliderAction;
+
+	#[tokio::test]
+	async fn mouse_deltas_keep_their_sign() {
+		let mut data: &[u8] = &[2, 0xFF, 0xFF, 0x02, 0x00];
+		let MouseEvent::Scroll(scroll) = MouseEvent::read_from(&mut data).await.unwrap() else {
+			panic!("expected a scroll");
+		};
+		assert_eq!((scroll.x, scroll.y), (-1, 2));
+
+		let mut data = vec![3];
+		data.extend_from_slice(&(-40i32).to_le_bytes());
+		data.extend_from_slice(&7i32.to_le_bytes());
+		let MouseEvent::Move(movement) = MouseEvent::read_from(&mut data.as_slice()).await.unwrap()
+		else {
+			panic!("expected a move");
+		};
+		assert_eq!((movement.x, movement.y), (-40, 7));
+	}
+
+	#[tokio::test]
+	async fn v12_reads_lengths_as_varints() {
+		// name, layers, keys, virtual keys, macros, LEDs and display, all empty but the name
+		let mut data = vec![12, 0, 0, 0, 2, b'k', b'p', 0, 0, 0, 0, 0, 0];
+		let profile = KeyboardProfile::read_from(&mut data.as_slice())
+			.await
+			.unwrap();
+		assert_eq!(profile.name, "kp");
+		assert!(profile.macros.is_empty() && profile.leds.is_empty());
+
+		// the same profile in v11, where macros and LEDs have u16 counts
+		data[0] = 11;
+		assert!(
+			KeyboardProfile::read_from(&mut data.as_slice())
+				.await
+				.is_err()
+		);
+		data.extend_from_slice(&[0, 0]);
+		assert!(
+			KeyboardProfile::read_from(&mut data.as_slice())
+				.await
+				.is_ok()
+		);
+	}
+
+	#[tokio::test]
+	async fn v13_reads_encoder_mappings() {
+		// no layers, then one mapping for "w" on layer 0: vertical, speed 2, no acceleration
+		let mut data = vec![13, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, b'w', 1, 0, 0, 2, 0];
+		assert_eq!(
+			KeyboardProfile::read_from(&mut data.as_slice()).await.err(),
+			Some("Layer index out of range")
+		);
+
+		// the same mapping for when no layer matches
+		data.splice(14..16, [0]);
+		let profile = KeyboardProfile::read_from(&mut data.as_slice())
+			.await
+			.unwrap();
+		assert_eq!(profile.encoders[0].encoder, "w");
+		assert_eq!(profile.encoders[0].layer, None);
+		assert_eq!(profile.encoders[0].speed, 2);
+	}
+
+	#[tokio::test]
+	async fn v14_reads_slider_mappings() {
+		// no layers or encoders, then one mapping on layer 0: 16 steps of volume
+		let mut data = vec![14, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 16, 1];
+		assert_eq!(
+			KeyboardProfile::read_from(&mut data.as_slice()).await.err(),
+			Some("Layer index out of range")
+		);
+
+		// the same mapping for when no layer matches
+		data.splice(13..15, [0]);
+		let profile = KeyboardProfile::read_from(&mut data.as_slice())
+			.await
+			.unwrap();
+		assert_eq!(profile.sliders[0].layer, None);
+		assert_eq!(profile.sliders[0].steps, 16);
+		assert_eq!(profile.sliders[0].action, SliderAction::Volume);
+	}
+
+	#[tokio::test]
+	async fn every_supported_version_still_loads() {
+		for version in MIN_VERSION..=VERSION {
+			let mut data = version.to_le_bytes().to_vec();
+			// an empty name, then a zero count for each collection the version has
+			let counts = if version >= 14 {
+				vec![1; 9]
+			} else if version >= 13 {
+				vec![1; 8]
+			} else if version >= 12 {
+				vec![1; 7]
+			} else {
+				let mut counts = vec![1];
+				if version >= 7 {
+					counts.push(1);
+				}
+				counts.extend([1, 1, 2]);
+				if version >= 9 {
+					counts.push(2);
+				}
+				if version >= 11 {
+					counts.push(1);
+				}
+				counts
+			};
+			data.extend(
+				counts
+					.iter()
+					.flat_map(|&width| core::iter::repeat_n(0, width)),
+			);
+
+			let mut reader = data.as_slice();
+			let result = KeyboardProfile::read_from(&mut reader).await;
+			assert!(result.is_ok(), "version {} failed to load", version);
+			assert!(reader.is_empty(), "version {} left data unread", version);
+		}
+	}
+
+	/// One of everything `version` can hold, with the fields each version added set to
+	/// something other than what older versions load as.
+	fn versioned_fixture(version: u32) -> Vec<u8> {
+		let mut data = version.to_le_bytes().to_vec();
+		// every length here fits in one varint byte, so only u16 lengths change width at v12
+		let len_u16 = |data: &mut Vec<u8>, len: u8| {
+			data.push(len);
+			if version < 12 {
+				data.push(0);
+			}
+		};
+		let trigger = |data: &mut Vec<u8>, hold: bool| {
+			if version >= 3 {
+				let bytes: &[u8] = if hold { &[2, 44, 1] } else { &[0] };
+				data.extend_from_slice(bytes);
+			}
+		};
+		let backlight = |data: &mut Vec<u8>, set: bool| {
+			if version >= 10 {
+				let bytes: &[u8] = if set { &[1, 1, 2, 3] } else { &[0] };
+				data.extend_from_slice(bytes);
+			}
+		};
+
+		data.extend_from_slice(&[1, b'p']);
+		if version >= 7 {
+			data.extend_from_slice(&[1, 1, b'L', 1, 1, b't', 0]);
+		}
+
+		// a key with a macro on its tagged layer
+		data.push(1);
+		data.extend_from_slice(&[1; 16]);
+		data.push(1);
+		if version >= 7 {
+			data.push(0);
+		} else {
+			data.extend_from_slice(&[1, 1, b't', 0]);
+		}
+		data.extend_from_slice(&[2; 16]);
+		data.extend_from_slice(&[1, 0, 0]);
+		trigger(&mut data, true);
+		backlight(&mut data, true);
+		data.extend_from_slice(&[3; 16]);
+		data.push(0);
+		trigger(&mut data, false);
+		backlight(&mut data, false);
+
+		data.push(1);
+		if version >= 8 {
+			data.extend_from_slice(&[4; 16]);
+		}
+		data.push(0);
+		data.extend_from_slice(&[5; 16]);
+		data.push(0);
+		trigger(&mut data, false);
+		backlight(&mut data, false);
+
+		// a macro pressing A after a delay, on channel 7
+		len_u16(&mut data, 1);
+		data.extend_from_slice(&[6; 16]);
+		data.extend_from_slice(&[1, b'm', 1, 7, 0, 1]);
+		data.extend_from_slice(&10u64.to_le_bytes());
+		if version >= 4 {
+			data.push(1);
+			data.extend_from_slice(&20u64.to_le_bytes());
+		}
+		data.extend_from_slice(&[1, 1, 0x04, 0, 0]);
+		if version >= 2 {
+			data.extend_from_slice(&[1, 3, 0]);
+		}
+		if version >= 5 {
+			data.extend_from_slice(&[50, 0]);
+		}
+		if version >= 6 {
+			data.extend_from_slice(&[2, 1]);
+		}
+
+		if version >= 9 {
+			len_u16(&mut data, 1);
+			data.extend_from_slice(&[1; 16]);
+			data.push(5);
+		}
+		if version >= 11 {
+			data.extend_from_slice(&[1, 0, 0, 0, 1, b'd']);
+		}
+		if version >= 13 {
+			data.extend_from_slice(&[1, 1, b'e', 1, 0, 0, 1, 0]);
+		}
+		if version >= 14 {
+			data.extend_from_slice(&[1, 0, 4, 1]);
+		}
+		data
+	}
+
+	#[tokio::test]
+	async fn every_supported_version_loads_its_fields() {
+		for version in MIN_VERSION..=VERSION {
+			let data = versioned_fixture(version);
+			let mut reader = data.as_slice();
+			let profile = match KeyboardProfile::read_from(&mut reader).await {
+				Ok(profile) => profile,
+				Err(e) => panic!("version {} failed to load: {}", version, e),
+			};
+			assert!(reader.is_empty(), "version {} left data unread", version);
+
+			assert_eq!(profile.name, "p");
+			assert_eq!(profile.layers.len(), 1);
+			assert_eq!(profile.layers[0].tags, vec![LayerTag::new("t".into())]);
+			let layer_name = if version >= 7 { "L" } else { "" };
+			assert_eq!(profile.layers[0].name, layer_name);
+
+			let layers = &profile.keys[0].layers;
+			assert_eq!(layers.layers[0].index, LayerIndex::new(0));
+			let layer = &layers.layers[0].layer;
+			assert_eq!(layer.macros, vec![MacroIndex::new(0)]);
+			let trigger = if version >= 3 {
+				MacroTrigger::Hold { threshold_ms: 300 }
+			} else {
+				MacroTrigger::Press
+			};
+			assert_eq!(layer.trigger, trigger, "version {}", version);
+			let backlight = (version >= 10).then_some(Rgb::new(1, 2, 3));
+			assert_eq!(layer.backlight, backlight, "version {}", version);
+			assert_eq!(layers.default_layer.trigger, MacroTrigger::Press);
+			assert_eq!(layers.default_layer.backlight, None);
+
+			let has_id = profile.virtual_keys[0].id.is_some();
+			assert_eq!(has_id, version >= 8, "version {}", version);
+
+			let m = &profile.macros[0];
+			assert_eq!(m.play_channel, Some(Channel::new(7)));
+			let action = &m.start_sequence.actions[0];
+			assert_eq!(action.predelay_ms, 10);
+			let predelay_max = (version >= 4).then_some(20);
+			assert_eq!(action.predelay_max_ms, predelay_max, "version {}", version);
+			let loop_limit = (version >= 2).then_some(3);
+			assert_eq!(m.loop_limit, loop_limit, "version {}", version);
+			let speed = if version >= 5 { 50 } else { 100 };
+			assert_eq!(m.speed_percent, speed, "version {}", version);
+			let (priority, policy) = if version >= 6 {
+				(2, ChannelPolicy::Preempt)
+			} else {
+				(0, ChannelPolicy::Share)
+			};
+			assert_eq!(m.priority, priority, "version {}", version);
+			assert_eq!(m.channel_policy, policy, "version {}", version);
+
+			assert_eq!(profile.leds.len(), (version >= 9) as usize);
+			assert_eq!(profile.display.len(), (version >= 11) as usize);
+			assert_eq!(profile.encoders.len(), (version >= 13) as usize);
+			assert_eq!(profile.sliders.len(), (version >= 14) as usize);
+		}
+	}
+
+	/// A key with no tagged layers and one macro on its default layer.
+	fn key_record(id: u8, macro_index: u8) -> Vec<u8> {
+		let mut data = vec![id; 16];
+		// no tagged layers, then the default layer's ID, macro, trigger and backlight
+		data.push(0);
+		data.extend_from_slice(&[0; 16]);
+		data.extend_from_slice(&[1, macro_index, 0, 0, 0]);
+		data
+	}
+
+	#[tokio::test]
+	async fn find_record_locates_a_keys_layers() {
+		let mut data = vec![14, 0, 0, 0, 0, 0, 2];
+		data.extend(key_record(1, 0));
+		data.extend(key_record(2, 0));
+		data.extend_from_slice(&[0; 6]);
+
+		let second = KeyId::new(Uuid::from_bytes([2; 16]));
+		let range = find_record(&data, ProfileRecord::KeyLayers(second))
+			.await
+			.unwrap();
+		assert_eq!(range, 61..83);
+
+		// swapping in other layers leaves a profile that still loads, with the new binding
+		data.splice(range, key_record(2, 1).split_off(16));
+		let profile = KeyboardProfile::read_from(&mut data.as_slice())
+			.await
+			.unwrap();
+		assert_eq!(
+			profile.keys[0].layers.default_layer.macros,
+			vec![MacroIndex::new(0)]
+		);
+		assert_eq!(
+			profile.keys[1].layers.default_layer.macros,
+			vec![MacroIndex::new(1)]
+		);
+
+		let missing = KeyId::new(Uuid::from_bytes([3; 16]));
+		assert_eq!(
+			find_record(&data, ProfileRecord::KeyLayers(missing)).await,
+			Err("Key not found in profile")
+		);
+	}
+
+	#[tokio::test]
+	async fn find_record_locates_a_macro_by_index_or_id() {
+		// no keys or virtual keys, then two macros with empty names and sequences
+		let mut data = vec![14, 0, 0, 0, 0, 0, 0, 0, 2];
+		for id in [1, 2] {
+			data.extend_from_slice(&[id; 16]);
+			data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 100, 0, 0, 0]);
+		}
+		data.extend_from_slice(&[0; 4]);
+
+		let range = 36..63;
+		let by_index = find_record(&data, ProfileRecord::Macro(MacroIndex::new(1))).await;
+		assert_eq!(by_index, Ok(range.clone()));
+		let id = MacroId::new(Uuid::from_bytes([2; 16]));
+		let by_id = find_record(&data, ProfileRecord::MacroById(id)).await;
+		assert_eq!(by_id, Ok(range.clone()));
+
+		// the same macro at half speed
+		let mut replacement = data[range.clone()].to_vec();
+		replacement[23] = 50;
+		data.splice(range, replacement);
+		let profile = KeyboardProfile::read_from(&mut data.as_slice())
+			.await
+			.unwrap();
+		assert_eq!(profile.macros[0].speed_percent, 100);
+		assert_eq!(profile.macros[1].speed_percent, 50);
+
+		assert_eq!(
+			find_record(&data, ProfileRecord::Macro(MacroIndex::new(2))).await,
+			Err("Macro not found in profile")
+		);
+	}
+
+	#[test]
+	fn validate_rejects_a_layer_index_past_the_layers() {
+		use crate::testing::{new_test_layer, new_test_layered_profile, new_test_tagged_key};
+
+		let key = KeyId::new(Uuid::from_u128(1));
+		let layers = vec![new_test_layer(vec![LayerTag::new("fn".into())])];
+		let profile = new_test_layered_profile(layers, vec![new_test_tagged_key(key, 0)], vec![]);
+		assert!(profile.validate().is_ok());
+
+		let profile = new_test_layered_profile(vec![], vec![new_test_tagged_key(key, 0)], vec![]);
+		assert_eq!(profile.validate(), Err("Layer index out of range"));
+	}
+
+	#[cfg(feature = "postcard")]
+	#[test]
+	fn postcard_round_trip_interns_tags() {
+		use crate::serialize::to_postcard;
+		use crate::testing::{new_test_layer, new_test_layered_profile};
+
+		let layers = vec![new_test_layer(vec![LayerTag::new("fn".into())])];
+		let mut profile = new_test_layered_profile(layers, vec![], vec![]);
+		profile.name = "desk".into();
+
+		let bytes = to_postcard(&profile).unwrap();
+		let read = KeyboardProfile::from_postcard(&bytes).unwrap();
+		assert_eq!(read.name, "desk");
+		assert_eq!(read.layers[0].tag_mask, Some(1));
+	}
+}
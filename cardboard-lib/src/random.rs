@@ -0,0 +1,71 @@
+/// Small xorshift PRNG for humanizing macro timing. Cheap and good enough to keep delays from
+/// looking robotic; not suitable for anything security related.
+#[derive(Clone)]
+pub struct Rng {
+	state: u32,
+}
+
+impl Rng {
+	const DEFAULT_SEED: u32 = 0x2545_F491;
+
+	pub const fn new(seed: u32) -> Self {
+		// xorshift gets stuck at zero
+		let state = if seed == 0 { Self::DEFAULT_SEED } else { seed };
+		Self { state }
+	}
+
+	pub fn next_u32(&mut self) -> u32 {
+		let mut x = self.state;
+		x ^= x << 13;
+		x ^= x >> 17;
+		x ^= x << 5;
+		self.state = x;
+		x
+	}
+
+	/// A value between `min` and `max`, inclusive. Returns `min` if the range is empty.
+	pub fn range(&mut self, min: u64, max: u64) -> u64 {
+		if max <= min {
+			return min;
+		}
+
+		let span = (max - min).saturating_add(1);
+		min + self.next_u32() as u64 % span
+	}
+}
+
+impl Default for Rng {
+	fn default() -> Self {
+		Self::new(Self::DEFAULT_SEED)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn range_stays_within_bounds() {
+		let mut rng = Rng::new(1);
+		let mut seen = [false; 5];
+		for _ in 0..1000 {
+			let value = rng.range(10, 14);
+			assert!((10..=14).contains(&value));
+			seen[(value - 10) as usize] = true;
+		}
+		assert!(seen.iter().all(|s| *s));
+	}
+
+	#[test]
+	fn empty_range_returns_min() {
+		let mut rng = Rng::default();
+		assert_eq!(rng.range(20, 20), 20);
+		assert_eq!(rng.range(20, 10), 20);
+	}
+
+	#[test]
+	fn zero_seed_doesnt_get_stuck() {
+		let mut rng = Rng::new(0);
+		assert_ne!(rng.next_u32(), 0);
+	}
+}
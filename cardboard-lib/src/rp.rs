@@ -0,0 +1,248 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use embassy_futures::yield_now;
+use embassy_rp::gpio::{Input, Output};
+use embassy_rp::watchdog::Watchdog;
+use embassy_rp::{
+	flash::{Async, ERASE_SIZE, Flash, PAGE_SIZE, WRITE_SIZE},
+	peripherals::FLASH,
+};
+
+use crate::auth::NonceSource;
+use crate::encoder::EncoderPins;
+use crate::indicator::Indicator;
+use crate::input::{ColPin, ColPort, RowPin};
+use crate::output::OutputPin;
+use crate::power::PowerSourceSense;
+use crate::storage::BlockFlash;
+use crate::supervisor::HardwareWatchdog;
+
+impl RowPin for Output<'_> {
+	fn set_high(&mut self) {
+		self.set_high();
+	}
+
+	fn set_low(&mut self) {
+		self.set_low();
+	}
+}
+
+impl Indicator for Output<'_> {
+	fn set(&mut self, on: bool) {
+		self.set_level(on.into());
+	}
+}
+
+impl OutputPin for Output<'_> {
+	fn set(&mut self, high: bool) {
+		self.set_level(high.into());
+	}
+}
+
+impl PowerSourceSense for Input<'_> {
+	fn on_external_power(&mut self) -> bool {
+		self.is_high()
+	}
+}
+
+impl HardwareWatchdog for Watchdog {
+	fn feed(&mut self) {
+		Watchdog::feed(self);
+	}
+}
+
+/// Nonces from the ring oscillator's random bit. Each bit is folded from several samples,
+/// since consecutive reads are correlated.
+pub struct RoscNonceSource;
+
+impl NonceSource for RoscNonceSource {
+	fn fill_nonce(&mut self, nonce: &mut [u8]) {
+		let rosc = embassy_rp::pac::ROSC;
+		for byte in nonce.iter_mut() {
+			let mut value = 0u8;
+			for _ in 0..8 {
+				let mut bit = false;
+				for _ in 0..16 {
+					bit ^= rosc.randombit().read().randombit();
+				}
+				value = (value << 1) | bit as u8;
+			}
+			*byte = value;
+		}
+	}
+}
+
+impl ColPin for Input<'_> {
+	fn is_high(&self) -> bool {
+		self.is_high()
+	}
+
+	fn wait_for_high(&mut self) -> Pin<Box<dyn Future<Output = ()> + '_>> {
+		Box::pin(Input::wait_for_high(self))
+	}
+}
+
+/// Reads a matrix's columns from the SIO input register in one go. Takes the columns' GPIO
+/// numbers, which must all be in bank 0; the pins themselves still need to be configured as
+/// inputs elsewhere.
+pub struct Rp2040ColPort {
+	pins: Vec<u8>,
+	// the first pin, when the columns sit on consecutive GPIOs and need only a shift and mask
+	first_consecutive: Option<u8>,
+}
+
+impl Rp2040ColPort {
+	pub fn new(pins: &[u8]) -> Self {
+		assert!(pins.len() <= 32);
+		assert!(pins.iter().all(|&pin| pin < 32));
+		let first_consecutive = match pins.first() {
+			Some(&first)
+				if pins
+					.iter()
+					.enumerate()
+					.all(|(c, &pin)| pin as usize == first as usize + c) =>
+			{
+				Some(first)
+			}
+			_ => None,
+		};
+		Self {
+			pins: pins.to_vec(),
+			first_consecutive,
+		}
+	}
+}
+
+impl ColPort for Rp2040ColPort {
+	fn read(&self) -> u32 {
+		let input = embassy_rp::pac::SIO.gpio_in(0).read();
+		match self.first_consecutive {
+			Some(first) => {
+				let mask = u32::MAX >> (32 - self.pins.len());
+				(input >> first) & mask
+			}
+			None => self
+				.pins
+				.iter()
+				.enumerate()
+				.fold(0, |bits, (c, &pin)| bits | (((input >> pin) & 1) << c)),
+		}
+	}
+}
+
+impl EncoderPins for (Input<'_>, Input<'_>) {
+	fn read(&self) -> (bool, bool) {
+		(self.0.is_high(), self.1.is_high())
+	}
+}
+
+pub struct EmbassyFlashMemory<'d, const SIZE: usize> {
+	flash_addr: *const u8,
+	storage_addr: *const u8,
+	length: usize,
+	flash: Flash<'d, FLASH, Async, SIZE>,
+}
+
+impl<'d, const SIZE: usize> EmbassyFlashMemory<'d, SIZE> {
+	pub fn new(
+		flash_addr: *const u8,
+		storage_addr: *const u8,
+		length: usize,
+		flash: Flash<'d, FLASH, Async, SIZE>,
+	) -> Self {
+		if storage_addr as usize % WRITE_SIZE != 0 {
+			error!(
+				"Base address is not write block aligned: {}",
+				storage_addr as usize
+			);
+			panic!("Base address is not write block aligned");
+		}
+
+		if storage_addr as usize % ERASE_SIZE != 0 {
+			error!(
+				"Base address is not erase block aligned: {}",
+				storage_addr as usize
+			);
+			panic!("Base address is not erase block aligned");
+		}
+
+		if length % WRITE_SIZE != 0 {
+			error!("Length is not block aligned: {}", length);
+			panic!("Length is not block aligned");
+		}
+
+		if length % ERASE_SIZE != 0 {
+			error!("Length is not erase block aligned: {}", length);
+			panic!("Length is not erase block aligned");
+		}
+
+		EmbassyFlashMemory {
+			flash_addr,
+			storage_addr,
+			length,
+			flash,
+		}
+	}
+
+	fn get_flash_offset(&self) -> usize {
+		self.storage_addr as usize - self.flash_addr as usize
+	}
+}
+
+impl<'a, const SIZE: usize> BlockFlash for EmbassyFlashMemory<'a, SIZE> {
+	fn as_slice(&self) -> &'static [u8] {
+		unsafe { core::slice::from_raw_parts(self.storage_addr, self.length) }
+	}
+
+	async fn erase(&mut self, offset: usize, length: usize) -> Result<(), &'static str> {
+		let start = offset + self.get_flash_offset();
+		let end = start + length;
+
+		self.flash
+			.blocking_erase(start as u32, end as u32)
+			.map_err(|e| {
+				error!("Error erasing flash memory: {:?}", e);
+				match e {
+					embassy_rp::flash::Error::OutOfBounds => "Erase out of bounds",
+					embassy_rp::flash::Error::Unaligned => "Erase not block aligned",
+					_ => "Error erasing flash memory",
+				}
+			})
+	}
+
+	// Flash can't be read while it's being programmed, so every write runs with interrupts off
+	// and the CPU away from XIP. Writing a page at a time and yielding in between keeps each of
+	// those stalls short enough for USB and the keypad scan to keep up.
+	async fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), &'static str> {
+		let mut address = self.get_flash_offset() + offset;
+		let mut remaining = data;
+		while !remaining.is_empty() {
+			let page_length = (PAGE_SIZE - address % PAGE_SIZE).min(remaining.len());
+			let (page, rest) = remaining.split_at(page_length);
+			self.flash
+				.blocking_write(address as u32, page)
+				.map_err(|e| {
+					error!("Error writing to flash memory: {:?}", e);
+					match e {
+						embassy_rp::flash::Error::OutOfBounds => "Write out of bounds",
+						embassy_rp::flash::Error::Unaligned => "Write not block aligned",
+						_ => "Error writing to flash memory",
+					}
+				})?;
+			address += page_length;
+			remaining = rest;
+			yield_now().await;
+		}
+		Ok(())
+	}
+
+	fn length(&self) -> usize {
+		self.length
+	}
+
+	const ERASE_BLOCK_SIZE: usize = ERASE_SIZE;
+
+	const WRITE_BLOCK_SIZE: usize = WRITE_SIZE;
+}
@@ -1,7 +1,51 @@
+use crate::crc::{Crc16, crc16};
+use crate::stats::UsbStats;
 use crate::stream::{ReadAsync, WriteAsync};
 
+/// Throws away input that has been read from the source but not consumed yet.
 pub trait SerialDrain {
-	async fn drop_packet(&mut self) -> bool;
+	fn drop_buffered(&mut self);
+}
+
+/// Counts the bytes taken off the wire that haven't been handed back to the host as credit.
+pub trait SerialCredit {
+	/// Returns the bytes read since the last call, up to what fits in a `Credit` event.
+	fn take_credit(&mut self) -> u16;
+}
+
+/// Tells when the host at the other end of the link has gone away, so whoever connects next
+/// starts a session of their own. Links with no notion of a connection, like a UART, never end
+/// one.
+pub trait SerialSession {
+	/// Whether the host closed the port or the link went down since the last call.
+	fn take_session_ended(&mut self) -> bool {
+		false
+	}
+}
+
+/// Sent by the host before every command, so the device can find the next one after an error.
+pub const SYNC_MARKER: [u8; 2] = [0xA5, 0x5A];
+
+/// Reads from `source` up to and including the next `SYNC_MARKER` and returns how many bytes
+/// before it were skipped.
+pub async fn read_sync_marker<R: ReadAsync>(source: &mut R) -> Result<usize, &'static str> {
+	let mut matched = 0;
+	let mut skipped = 0;
+
+	while matched < SYNC_MARKER.len() {
+		let mut byte = [0u8];
+		source.read_exact(&mut byte).await?;
+
+		if byte[0] == SYNC_MARKER[matched] {
+			matched += 1;
+		} else {
+			skipped += matched;
+			matched = usize::from(byte[0] == SYNC_MARKER[0]);
+			skipped += 1 - matched;
+		}
+	}
+
+	Ok(skipped)
 }
 
 pub trait SerialPacketReader {
@@ -38,6 +82,7 @@ where
 {
 	buffer: SerialBuffer<{ S::SIZE }>,
 	source: S,
+	received: usize,
 }
 
 impl<S: SerialPacketReader> BufferedReader<S>
@@ -48,6 +93,7 @@ where
 		Self {
 			buffer: SerialBuffer::new(),
 			source,
+			received: 0,
 		}
 	}
 
@@ -55,6 +101,7 @@ where
 		let bytes_read = self.source.read_packet(&mut self.buffer.buffer).await?;
 		self.buffer.skip = 0;
 		self.buffer.length = bytes_read;
+		self.received += bytes_read;
 		Ok(())
 	}
 }
@@ -109,18 +156,398 @@ where
 
 		Ok(())
 	}
+
+	async fn read_up_to(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+		if buf.is_empty() {
+			return Ok(0);
+		}
+		while self.buffer.length == 0 {
+			self.read_packet().await?;
+		}
+		Ok(self.buffer.read_up_to(buf))
+	}
 }
 
-impl<S: SerialPacketReader + SerialDrain> SerialDrain for BufferedReader<S>
+impl<S: SerialPacketReader> SerialDrain for BufferedReader<S>
 where
 	[(); S::SIZE]:,
 {
-	async fn drop_packet(&mut self) -> bool {
+	fn drop_buffered(&mut self) {
 		self.buffer.drop();
-		self.source.drop_packet().await
 	}
 }
 
+impl<S: SerialPacketReader + SerialSession> SerialSession for BufferedReader<S>
+where
+	[(); S::SIZE]:,
+{
+	fn take_session_ended(&mut self) -> bool {
+		self.source.take_session_ended()
+	}
+}
+
+impl<S: SerialPacketReader> SerialCredit for BufferedReader<S>
+where
+	[(); S::SIZE]:,
+{
+	// a packet's worth of buffer is freed for the next packet once it has been read through
+	fn take_credit(&mut self) -> u16 {
+		let consumed = self.received - self.buffer.length;
+		let credit = consumed.min(u16::MAX as usize);
+		self.received -= credit;
+		credit as u16
+	}
+}
+
+pub const FRAME_DELIMITER: u8 = 0x00;
+pub const FRAME_FLAG_START: u8 = 0x01;
+pub const FRAME_FLAG_EVENT: u8 = 0x02;
+pub const FRAME_FLAG_ABORT: u8 = 0x04;
+/// Read error for a frame carrying `FRAME_FLAG_ABORT`.
+pub const TRANSFER_ABORTED: &str = "Transfer aborted by host";
+const FRAME_HEADER_SIZE: usize = 1; // flags
+const FRAME_CRC_SIZE: usize = 2;
+
+/// Reads COBS-encoded frames from `source` and exposes their payloads as a single byte stream.
+///
+/// A frame on the wire is `COBS([flags: u8][payload][crc16: u16])` followed by a `0x00`
+/// delimiter. `N` is the largest decoded frame (flags + payload + crc) that will be accepted.
+/// Frames with a bad checksum are reported as read errors. After `drop_buffered`, frames are
+/// discarded until one carrying `FRAME_FLAG_START` arrives, so the host can resynchronize
+/// by starting its next command in a fresh frame. A frame carrying `FRAME_FLAG_ABORT` fails the
+/// read in progress with `TRANSFER_ABORTED` and drops everything after it in the same way.
+pub struct FramedReader<R: ReadAsync, const N: usize> {
+	source: R,
+	frame: [u8; N],
+	skip: usize,
+	length: usize,
+	desynced: bool,
+	awaiting_start: bool,
+	consumed: usize,
+	stats: Option<&'static UsbStats>,
+}
+
+impl<R: ReadAsync, const N: usize> FramedReader<R, N> {
+	pub fn new(source: R) -> Self {
+		Self {
+			source,
+			frame: [0; N],
+			skip: 0,
+			length: 0,
+			desynced: false,
+			awaiting_start: false,
+			consumed: 0,
+			stats: None,
+		}
+	}
+
+	/// Counts frames read and dropped in `stats`.
+	pub fn with_stats(mut self, stats: &'static UsbStats) -> Self {
+		self.stats = Some(stats);
+		self
+	}
+
+	fn count_dropped(&self) {
+		if let Some(stats) = self.stats {
+			stats.serial_packet_dropped();
+		}
+	}
+
+	async fn read_byte(&mut self) -> Result<u8, &'static str> {
+		let mut buf = [0u8];
+		self.source.read_exact(&mut buf).await?;
+		self.consumed += 1;
+		Ok(buf[0])
+	}
+
+	fn push(&mut self, length: &mut usize, byte: u8) -> bool {
+		if *length >= N {
+			return false;
+		}
+		self.frame[*length] = byte;
+		*length += 1;
+		true
+	}
+
+	/// Decodes the next frame into `self.frame` and returns its length.
+	async fn decode_frame(&mut self) -> Result<usize, &'static str> {
+		if self.desynced {
+			// the previous frame was interrupted; throw away the rest of it
+			while self.read_byte().await? != FRAME_DELIMITER {}
+			self.desynced = false;
+		}
+
+		let mut length = 0;
+		let mut overflow = false;
+		let mut pending_zero = false;
+		let mut started = false;
+
+		loop {
+			let code = match self.read_byte().await {
+				Ok(code) => code,
+				Err(e) => {
+					self.desynced = started;
+					return Err(e);
+				}
+			};
+
+			if code == FRAME_DELIMITER {
+				break;
+			}
+			started = true;
+
+			if pending_zero {
+				overflow |= !self.push(&mut length, 0);
+			}
+
+			for _ in 1..code {
+				let byte = match self.read_byte().await {
+					Ok(byte) => byte,
+					Err(e) => {
+						self.desynced = true;
+						return Err(e);
+					}
+				};
+
+				if byte == FRAME_DELIMITER {
+					self.count_dropped();
+					return Err("Truncated frame");
+				}
+
+				overflow |= !self.push(&mut length, byte);
+			}
+
+			pending_zero = code != 0xFF;
+		}
+
+		if overflow {
+			self.count_dropped();
+			return Err("Frame too large");
+		}
+
+		Ok(length)
+	}
+
+	async fn read_frame(&mut self) -> Result<(), &'static str> {
+		loop {
+			let length = self.decode_frame().await?;
+
+			if length == 0 {
+				// back-to-back delimiters; hosts may send these to flush a partial frame
+				continue;
+			}
+
+			if length < FRAME_HEADER_SIZE + FRAME_CRC_SIZE {
+				self.count_dropped();
+				return Err("Frame too short");
+			}
+
+			let crc_offset = length - FRAME_CRC_SIZE;
+			let checksum = u16::from_le_bytes([self.frame[crc_offset], self.frame[crc_offset + 1]]);
+			if crc16(&self.frame[..crc_offset]) != checksum {
+				self.count_dropped();
+				return Err("Frame checksum mismatch");
+			}
+
+			let flags = self.frame[0];
+			if flags & FRAME_FLAG_ABORT != 0 {
+				// the rest of the aborted transfer may already be on its way
+				self.skip = 0;
+				self.length = 0;
+				self.awaiting_start = true;
+				return Err(TRANSFER_ABORTED);
+			}
+			if self.awaiting_start && flags & FRAME_FLAG_START == 0 {
+				self.count_dropped();
+				continue;
+			}
+			self.awaiting_start = false;
+
+			if let Some(stats) = self.stats {
+				stats.serial_packet_read();
+			}
+
+			self.skip = FRAME_HEADER_SIZE;
+			self.length = crc_offset - FRAME_HEADER_SIZE;
+			return Ok(());
+		}
+	}
+}
+
+impl<R: ReadAsync, const N: usize> ReadAsync for FramedReader<R, N> {
+	async fn read_exact(&mut self, to_fill: &mut [u8]) -> Result<(), &'static str> {
+		let mut total_read = 0usize;
+
+		while total_read < to_fill.len() {
+			if self.length == 0 {
+				self.read_frame().await?;
+				continue;
+			}
+
+			let size = self.length.min(to_fill.len() - total_read);
+			to_fill[total_read..total_read + size]
+				.copy_from_slice(&self.frame[self.skip..self.skip + size]);
+
+			self.skip += size;
+			self.length -= size;
+			total_read += size;
+		}
+
+		Ok(())
+	}
+
+	async fn read_up_to(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+		if buf.is_empty() {
+			return Ok(0);
+		}
+		while self.length == 0 {
+			self.read_frame().await?;
+		}
+
+		let size = self.length.min(buf.len());
+		buf[..size].copy_from_slice(&self.frame[self.skip..self.skip + size]);
+		self.skip += size;
+		self.length -= size;
+		Ok(size)
+	}
+}
+
+impl<R: ReadAsync, const N: usize> SerialDrain for FramedReader<R, N> {
+	// frames are self-delimiting, so drop what's buffered and wait for the host to start a new
+	// command
+	fn drop_buffered(&mut self) {
+		self.skip = 0;
+		self.length = 0;
+		self.awaiting_start = true;
+	}
+}
+
+impl<R: ReadAsync + SerialSession, const N: usize> SerialSession for FramedReader<R, N> {
+	fn take_session_ended(&mut self) -> bool {
+		self.source.take_session_ended()
+	}
+}
+
+impl<R: ReadAsync, const N: usize> SerialCredit for FramedReader<R, N> {
+	// counts encoded bytes, delimiters included, since that's what the host sends
+	fn take_credit(&mut self) -> u16 {
+		let credit = self.consumed.min(u16::MAX as usize);
+		self.consumed -= credit;
+		credit as u16
+	}
+}
+
+/// Writes data to `sink` as COBS-encoded, CRC-checked frames (see `FramedReader`).
+///
+/// Every `write_exact` call is sent immediately as one or more frames, so responses never
+/// sit in a buffer waiting for a flush. `N` is the size of the encoded frame buffer.
+pub struct FramedWriter<W: WriteAsync, const N: usize> {
+	sink: W,
+	frame: [u8; N],
+	flags: u8,
+}
+
+impl<W: WriteAsync, const N: usize> FramedWriter<W, N> {
+	// worst case COBS overhead is one byte per 254, plus the leading code byte and the delimiter
+	const MAX_PAYLOAD: usize = N - FRAME_HEADER_SIZE - FRAME_CRC_SIZE - 2 - N / 254 - 1;
+
+	pub fn new(sink: W) -> Self {
+		Self {
+			sink,
+			frame: [0; N],
+			flags: 0,
+		}
+	}
+
+	pub fn set_flags(&mut self, flags: u8) {
+		self.flags = flags;
+	}
+
+	async fn write_frame(&mut self, payload: &[u8]) -> Result<(), &'static str> {
+		let mut crc = Crc16::new();
+		crc.update(&[self.flags]);
+		crc.update(payload);
+		let checksum = crc.finish().to_le_bytes();
+
+		let bytes = [self.flags]
+			.into_iter()
+			.chain(payload.iter().copied())
+			.chain(checksum);
+		let length = cobs_encode(bytes, &mut self.frame)?;
+
+		self.sink.write_exact(&self.frame[..length]).await
+	}
+}
+
+impl<W: WriteAsync, const N: usize> WriteAsync for FramedWriter<W, N> {
+	async fn write_exact(&mut self, data: &[u8]) -> Result<(), &'static str> {
+		for chunk in data.chunks(Self::MAX_PAYLOAD) {
+			self.write_frame(chunk).await?;
+		}
+		Ok(())
+	}
+}
+
+/// Sends unsolicited device-to-host messages that the host can tell apart from command responses.
+pub trait SerialEventSender {
+	async fn send_event(&mut self, data: &[u8]) -> Result<(), &'static str>;
+}
+
+impl<W: WriteAsync, const N: usize> SerialEventSender for FramedWriter<W, N> {
+	async fn send_event(&mut self, data: &[u8]) -> Result<(), &'static str> {
+		let flags = self.flags;
+		self.flags = flags | FRAME_FLAG_EVENT;
+		let result = self.write_exact(data).await;
+		self.flags = flags;
+		result
+	}
+}
+
+/// COBS-encodes `input` into `output`, appending the frame delimiter. Returns the encoded length.
+fn cobs_encode(input: impl Iterator<Item = u8>, output: &mut [u8]) -> Result<usize, &'static str> {
+	const TOO_SMALL: &str = "Frame buffer too small";
+
+	let mut code_index = 0;
+	let mut write_index = 1;
+	let mut code = 1u8;
+
+	for byte in input {
+		if write_index >= output.len() {
+			return Err(TOO_SMALL);
+		}
+
+		if byte == 0 {
+			output[code_index] = code;
+			code_index = write_index;
+			write_index += 1;
+			code = 1;
+			continue;
+		}
+
+		output[write_index] = byte;
+		write_index += 1;
+		code += 1;
+
+		if code == 0xFF {
+			if write_index >= output.len() {
+				return Err(TOO_SMALL);
+			}
+			output[code_index] = code;
+			code_index = write_index;
+			write_index += 1;
+			code = 1;
+		}
+	}
+
+	if write_index >= output.len() {
+		return Err(TOO_SMALL);
+	}
+	output[code_index] = code;
+	output[write_index] = FRAME_DELIMITER;
+
+	Ok(write_index + 1)
+}
+
 #[cfg(test)]
 mod tests {
 	use std::collections::VecDeque;
@@ -149,6 +576,13 @@ mod tests {
 		const SIZE: usize = SIZE;
 	}
 
+	// the link goes down once every packet has been read
+	impl<const SIZE: usize> SerialSession for DummySerialPacketReader<'_, SIZE> {
+		fn take_session_ended(&mut self) -> bool {
+			self.packets.is_empty()
+		}
+	}
+
 	#[tokio::test]
 	async fn read_single_packet() {
 		const PACKET_SIZE: usize = 2;
@@ -206,4 +640,155 @@ mod tests {
 		serial_reader.read_exact(&mut buffer).await.unwrap();
 		assert_eq!(buffer, [0x03, 0x04]);
 	}
+
+	struct VecWriter {
+		written: Vec<u8>,
+	}
+
+	impl WriteAsync for VecWriter {
+		async fn write_exact(&mut self, data: &[u8]) -> Result<(), &'static str> {
+			self.written.extend_from_slice(data);
+			Ok(())
+		}
+	}
+
+	async fn encode_frames<const N: usize>(flags: u8, data: &[u8]) -> Vec<u8> {
+		let mut writer = FramedWriter::<_, N>::new(VecWriter {
+			written: Vec::new(),
+		});
+		writer.set_flags(flags);
+		writer.write_exact(data).await.unwrap();
+		writer.sink.written
+	}
+
+	#[tokio::test]
+	async fn framed_round_trip_with_zeros() {
+		let data = [0x00, 0x11, 0x00, 0x00, 0x22, 0x33, 0x00];
+		let encoded = encode_frames::<64>(0, &data).await;
+		assert_eq!(encoded.iter().filter(|b| **b == 0).count(), 1);
+
+		let mut reader = FramedReader::<_, 64>::new(encoded.as_slice());
+		let mut buffer = [0xAAu8; 7];
+		reader.read_exact(&mut buffer).await.unwrap();
+		assert_eq!(buffer, data);
+	}
+
+	#[tokio::test]
+	async fn framed_round_trip_spanning_multiple_frames() {
+		let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+		let encoded = encode_frames::<300>(0, &data).await;
+		assert!(encoded.iter().filter(|b| **b == 0).count() > 1);
+
+		let mut reader = FramedReader::<_, 300>::new(encoded.as_slice());
+		let mut buffer = vec![0u8; data.len()];
+		reader.read_exact(&mut buffer).await.unwrap();
+		assert_eq!(buffer, data);
+	}
+
+	#[tokio::test]
+	async fn framed_reader_detects_corruption() {
+		let mut encoded = encode_frames::<64>(0, &[0x01, 0x02, 0x03]).await;
+		encoded[2] ^= 0x40;
+
+		let mut reader = FramedReader::<_, 64>::new(encoded.as_slice());
+		let mut buffer = [0u8; 3];
+		assert_eq!(
+			reader.read_exact(&mut buffer).await,
+			Err("Frame checksum mismatch")
+		);
+	}
+
+	#[tokio::test]
+	async fn framed_reader_resyncs_on_start_frame() {
+		let mut encoded = encode_frames::<64>(0, &[0x01, 0x02]).await;
+		encoded.extend(encode_frames::<64>(0, &[0x03, 0x04]).await);
+		encoded.extend(encode_frames::<64>(FRAME_FLAG_START, &[0x05, 0x06]).await);
+
+		let mut reader = FramedReader::<_, 64>::new(encoded.as_slice());
+		let mut buffer = [0u8; 1];
+		reader.read_exact(&mut buffer).await.unwrap();
+		assert_eq!(buffer, [0x01]);
+
+		reader.drop_buffered();
+
+		let mut buffer = [0u8; 2];
+		reader.read_exact(&mut buffer).await.unwrap();
+		assert_eq!(buffer, [0x05, 0x06]);
+	}
+
+	#[tokio::test]
+	async fn framed_reader_credits_encoded_bytes() {
+		let encoded = encode_frames::<64>(FRAME_FLAG_START, &[0x00, 0x01, 0x02]).await;
+
+		let mut reader = FramedReader::<_, 64>::new(encoded.as_slice());
+		assert_eq!(reader.take_credit(), 0);
+
+		let mut buffer = [0u8; 3];
+		reader.read_exact(&mut buffer).await.unwrap();
+		assert_eq!(reader.take_credit() as usize, encoded.len());
+		assert_eq!(reader.take_credit(), 0);
+	}
+
+	#[tokio::test]
+	async fn sync_marker_skips_leading_garbage() {
+		let data = [0x01, 0xA5, 0xA5, 0x02, 0xA5, 0xA5, 0x5A, 0x07];
+		let mut reader = data.as_slice();
+
+		assert_eq!(read_sync_marker(&mut reader).await, Ok(5));
+		assert_eq!(reader, [0x07]);
+	}
+
+	#[tokio::test]
+	async fn sync_marker_read_fails_when_input_runs_out() {
+		let data = [0x01, 0xA5];
+		let mut reader = data.as_slice();
+
+		assert!(read_sync_marker(&mut reader).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn event_frames_are_flagged() {
+		let mut writer = FramedWriter::<_, 64>::new(VecWriter {
+			written: Vec::new(),
+		});
+		writer.send_event(&[0x01]).await.unwrap();
+		writer.write_exact(&[0x02]).await.unwrap();
+
+		let frames: Vec<&[u8]> = writer.sink.written.split(|b| *b == 0).collect();
+		// each frame starts with a COBS code byte; the flags come right after it
+		assert_eq!(frames[0][1], FRAME_FLAG_EVENT);
+		// zero flags encode as an empty block
+		assert_eq!(frames[1][0], 0x01);
+	}
+
+	#[tokio::test]
+	async fn abort_frame_fails_the_read_and_skips_to_the_next_command() {
+		let mut encoded = encode_frames::<64>(0, &[0x01]).await;
+		// the payload of an abort frame is ignored
+		encoded.extend(encode_frames::<64>(FRAME_FLAG_ABORT, &[0x00]).await);
+		encoded.extend(encode_frames::<64>(0, &[0x02]).await);
+		encoded.extend(encode_frames::<64>(FRAME_FLAG_START, &[0x03]).await);
+		let mut reader = FramedReader::<_, 64>::new(encoded.as_slice());
+
+		let mut buffer = [0u8; 2];
+		assert_eq!(reader.read_exact(&mut buffer).await, Err(TRANSFER_ABORTED));
+
+		let mut buffer = [0u8; 1];
+		reader.read_exact(&mut buffer).await.unwrap();
+		assert_eq!(buffer, [0x03]);
+	}
+
+	#[tokio::test]
+	async fn framed_reader_passes_on_the_end_of_a_session() {
+		let encoded = encode_frames::<64>(FRAME_FLAG_START, &[0x01]).await;
+		let source = DummySerialPacketReader::<64> {
+			packets: VecDeque::from(vec![encoded.as_slice()]),
+		};
+		let mut reader = FramedReader::<_, 64>::new(BufferedReader::new(source));
+		assert!(!reader.take_session_ended());
+
+		let mut buffer = [0u8; 1];
+		reader.read_exact(&mut buffer).await.unwrap();
+		assert!(reader.take_session_ended());
+	}
 }
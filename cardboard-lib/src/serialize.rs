@@ -9,3 +9,15 @@ pub trait Readable {
 pub trait Writeable {
 	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str>;
 }
+
+/// Deserializes a value written by `to_postcard`, for host apps sharing the profile and
+/// settings types rather than reimplementing their byte layout.
+#[cfg(feature = "postcard")]
+pub fn from_postcard<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T, &'static str> {
+	postcard::from_bytes(bytes).map_err(|_| "Failed to deserialize postcard data")
+}
+
+#[cfg(feature = "postcard")]
+pub fn to_postcard<T: serde::Serialize>(value: &T) -> Result<alloc::vec::Vec<u8>, &'static str> {
+	postcard::to_allocvec(value).map_err(|_| "Failed to serialize postcard data")
+}
@@ -0,0 +1,172 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::input::KeyId;
+use crate::serialize::{Readable, Writeable};
+use crate::storage::{BlockFlash, load_settings_from_flash, save_settings_to_flash};
+use crate::stream::{ReadAsync, ReadAsyncExt};
+
+pub const MAX_DEVICE_NAME_LENGTH: usize = 32;
+
+/// Settings stored in the settings partition. The firmware owns the layout; commands that
+/// change a single value load the settings, modify them and save them back.
+pub trait DeviceSettings: Readable + Writeable + Default {
+	fn set_device_name(&mut self, name: String);
+
+	/// Sets a single setting by key. Implementations must reject unknown keys and values of the
+	/// wrong type.
+	fn set_setting(&mut self, key: u8, value: SettingValue) -> Result<(), &'static str>;
+}
+
+const SETTING_TYPE_BOOL: u8 = 0x00;
+const SETTING_TYPE_U32: u8 = 0x01;
+const SETTING_TYPE_STRING: u8 = 0x02;
+const SETTING_TYPE_KEYS: u8 = 0x03;
+
+/// A typed value for a single setting, sent as a type byte followed by the value.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub enum SettingValue {
+	Bool(bool),
+	U32(u32),
+	String(String),
+	Keys(Vec<KeyId>),
+}
+
+impl Readable for SettingValue {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let value_type = reader
+			.read_u8()
+			.await
+			.ok_or("Failed to read setting type")?;
+
+		match value_type {
+			SETTING_TYPE_BOOL => reader
+				.read_bool()
+				.await
+				.map(SettingValue::Bool)
+				.ok_or("Failed to read bool setting"),
+			SETTING_TYPE_U32 => reader
+				.read_u32()
+				.await
+				.map(SettingValue::U32)
+				.ok_or("Failed to read u32 setting"),
+			SETTING_TYPE_STRING => reader
+				.read_string_u8()
+				.await
+				.map(SettingValue::String)
+				.ok_or("Failed to read string setting"),
+			SETTING_TYPE_KEYS => reader
+				.read_collection_u8()
+				.await
+				.map(SettingValue::Keys)
+				.ok_or("Failed to read keys setting"),
+			_ => Err("Unknown setting type"),
+		}
+	}
+}
+
+pub fn validate_device_name(name: &str) -> Result<(), &'static str> {
+	if name.is_empty() {
+		return Err("Device name is empty");
+	}
+
+	if name.len() > MAX_DEVICE_NAME_LENGTH {
+		return Err("Device name is too long");
+	}
+
+	if name.chars().any(char::is_control) {
+		return Err("Device name contains control characters");
+	}
+
+	Ok(())
+}
+
+/// Loads settings from flash, falling back to the defaults if none have been saved yet.
+pub async fn load_settings_or_default<F: BlockFlash, Settings: DeviceSettings>(
+	flash: &mut F,
+) -> Settings {
+	load_settings_from_flash(flash).await.unwrap_or_default()
+}
+
+pub async fn save_settings<F: BlockFlash, Settings: Writeable>(
+	flash: &mut F,
+	settings: &Settings,
+) -> Result<(), &'static str> {
+	let mut data = Vec::new();
+	settings.write_to(&mut data).await?;
+	save_settings_to_flash(flash, &data).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn device_name_must_not_be_empty() {
+		assert!(validate_device_name("").is_err());
+	}
+
+	#[test]
+	fn device_name_length_is_limited() {
+		assert!(validate_device_name(&"a".repeat(MAX_DEVICE_NAME_LENGTH)).is_ok());
+		assert!(validate_device_name(&"a".repeat(MAX_DEVICE_NAME_LENGTH + 1)).is_err());
+	}
+
+	#[tokio::test]
+	async fn setting_values_are_read_by_type() {
+		let mut bool_data: &[u8] = &[SETTING_TYPE_BOOL, 1];
+		let mut u32_data: &[u8] = &[SETTING_TYPE_U32, 0x78, 0x56, 0x34, 0x12];
+		let mut string_data: &[u8] = &[SETTING_TYPE_STRING, 2, b'h', b'i'];
+		let mut keys_data: &[u8] = &[
+			SETTING_TYPE_KEYS,
+			1,
+			0,
+			0,
+			0,
+			0,
+			0,
+			0,
+			0,
+			0,
+			0,
+			0,
+			0,
+			0,
+			0,
+			0,
+			0,
+			7,
+		];
+		let mut unknown_data: &[u8] = &[0x7F, 0];
+
+		assert_eq!(
+			SettingValue::read_from(&mut bool_data).await,
+			Ok(SettingValue::Bool(true))
+		);
+		assert_eq!(
+			SettingValue::read_from(&mut u32_data).await,
+			Ok(SettingValue::U32(0x12345678))
+		);
+		assert_eq!(
+			SettingValue::read_from(&mut string_data).await,
+			Ok(SettingValue::String(String::from("hi")))
+		);
+		assert_eq!(
+			SettingValue::read_from(&mut keys_data).await,
+			Ok(SettingValue::Keys(alloc::vec![KeyId::new(
+				uuid::Uuid::from_u128(7)
+			)]))
+		);
+		assert!(SettingValue::read_from(&mut unknown_data).await.is_err());
+	}
+
+	#[test]
+	fn device_name_rejects_control_characters() {
+		assert!(validate_device_name("left\nhalf").is_err());
+		assert!(validate_device_name("Left Half").is_ok());
+	}
+}
@@ -0,0 +1,409 @@
+use alloc::{boxed::Box, collections::VecDeque, vec, vec::Vec};
+use core::future::{Future, poll_fn};
+use core::pin::{Pin, pin};
+use core::task::{Context, Poll, Waker};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::hid::{ConsumerControl, HidDevice, HidReport, Mouse, NKROKeyboard, ReportHid};
+use crate::profile::{ConsumerControlEvent, KeyboardEvent, MouseEvent};
+use crate::serial::{SerialPacketReader, SerialPacketSender, SerialSession};
+use crate::storage::BlockFlash;
+use crate::time::{Clock, Duration, Instant};
+
+/// Packet size of the simulated serial port, the same as the USB CDC endpoints.
+pub const SIM_SERIAL_PACKET_SIZE: usize = 64;
+
+/// Clock counting from when it was created, on the host's monotonic clock.
+pub struct SimClock {
+	start: std::time::Instant,
+}
+
+impl SimClock {
+	pub fn new() -> Self {
+		Self {
+			start: std::time::Instant::now(),
+		}
+	}
+}
+
+impl Default for SimClock {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Clock for SimClock {
+	fn now(&self) -> Instant {
+		Instant::from_ticks(self.start.elapsed().as_micros() as u64)
+	}
+
+	async fn after(&self, duration: Duration) {
+		Sleep::new(duration).await;
+	}
+
+	async fn at(&self, instant: Instant) {
+		let duration = instant
+			.checked_duration_since(self.now())
+			.unwrap_or(Duration::from_ticks(0));
+		Sleep::new(duration).await;
+	}
+}
+
+/// Resolves once `deadline` has passed. A thread wakes the task then, so it works on any
+/// executor without a timer driver.
+struct Sleep {
+	deadline: std::time::Instant,
+	waker: Option<Arc<Mutex<Waker>>>,
+}
+
+impl Sleep {
+	fn new(duration: Duration) -> Self {
+		Self {
+			deadline: std::time::Instant::now()
+				+ std::time::Duration::from_micros(duration.to_micros()),
+			waker: None,
+		}
+	}
+}
+
+impl Future for Sleep {
+	type Output = ();
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+		if std::time::Instant::now() >= self.deadline {
+			return Poll::Ready(());
+		}
+
+		match &self.waker {
+			Some(waker) => waker.lock().unwrap().clone_from(cx.waker()),
+			None => {
+				let waker = Arc::new(Mutex::new(cx.waker().clone()));
+				let deadline = self.deadline;
+				let thread_waker = waker.clone();
+				std::thread::spawn(move || {
+					std::thread::sleep(
+						deadline.saturating_duration_since(std::time::Instant::now()),
+					);
+					thread_waker.lock().unwrap().wake_by_ref();
+				});
+				self.waker = Some(waker);
+			}
+		}
+		Poll::Pending
+	}
+}
+
+/// NOR flash held in memory: erased bytes read back as 0xFF, writes can only clear bits and
+/// erases must cover whole 4 KB blocks, as on the RP2040. Given a path it's also saved to a
+/// file after every change, so profiles and settings survive restarting the simulator.
+pub struct SimFlash {
+	data: &'static mut [u8],
+	path: Option<PathBuf>,
+}
+
+impl SimFlash {
+	pub fn new(length: usize) -> Self {
+		Self {
+			data: Box::leak(vec![0xFF; length].into_boxed_slice()),
+			path: None,
+		}
+	}
+
+	/// Loads the flash from `path` if it exists, erased otherwise.
+	pub fn open(path: impl Into<PathBuf>, length: usize) -> std::io::Result<Self> {
+		let path = path.into();
+		let mut flash = Self::new(length);
+		match std::fs::read(&path) {
+			Ok(contents) => {
+				let loaded = contents.len().min(length);
+				flash.data[..loaded].copy_from_slice(&contents[..loaded]);
+			}
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+			Err(err) => return Err(err),
+		}
+		flash.path = Some(path);
+		Ok(flash)
+	}
+
+	fn save(&self) -> Result<(), &'static str> {
+		match &self.path {
+			Some(path) => {
+				std::fs::write(path, &*self.data).map_err(|_| "Could not save flash file")
+			}
+			None => Ok(()),
+		}
+	}
+}
+
+impl BlockFlash for SimFlash {
+	// like the memory mapped flash on the device, reads see later writes
+	fn as_slice(&self) -> &'static [u8] {
+		unsafe { core::slice::from_raw_parts(self.data.as_ptr(), self.data.len()) }
+	}
+
+	async fn erase(&mut self, offset: usize, length: usize) -> Result<(), &'static str> {
+		if offset + length > self.data.len() {
+			return Err("Erase out of bounds");
+		}
+		if offset % Self::ERASE_BLOCK_SIZE != 0 || length % Self::ERASE_BLOCK_SIZE != 0 {
+			return Err("Erase not block aligned");
+		}
+		self.data[offset..offset + length].fill(0xFF);
+		self.save()
+	}
+
+	async fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), &'static str> {
+		if offset + data.len() > self.data.len() {
+			return Err("Write out of bounds");
+		}
+		for (byte, new) in self.data[offset..offset + data.len()].iter_mut().zip(data) {
+			*byte &= new;
+		}
+		self.save()
+	}
+
+	fn length(&self) -> usize {
+		self.data.len()
+	}
+
+	const ERASE_BLOCK_SIZE: usize = 4096;
+
+	const WRITE_BLOCK_SIZE: usize = 1;
+}
+
+struct Pipe {
+	packets: VecDeque<Vec<u8>>,
+	waker: Option<Waker>,
+}
+
+/// Creates one direction of a simulated serial port. The device takes the reader of one pipe
+/// and the sender of another, and the host side the opposite ends, so both can use the same
+/// framing over it.
+pub fn sim_serial_pipe(read_timeout: Duration) -> (SimSerialSender, SimSerialReader) {
+	let pipe = Arc::new(Mutex::new(Pipe {
+		packets: VecDeque::new(),
+		waker: None,
+	}));
+	(
+		SimSerialSender { pipe: pipe.clone() },
+		SimSerialReader {
+			pipe,
+			timeout: read_timeout,
+		},
+	)
+}
+
+pub struct SimSerialReader {
+	pipe: Arc<Mutex<Pipe>>,
+	timeout: Duration,
+}
+
+pub struct SimSerialSender {
+	pipe: Arc<Mutex<Pipe>>,
+}
+
+impl SerialPacketReader for SimSerialReader {
+	async fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+		let mut timeout = pin!(Sleep::new(self.timeout));
+		poll_fn(|cx| {
+			let mut pipe = self.pipe.lock().unwrap();
+			if let Some(packet) = pipe.packets.pop_front() {
+				let length = packet.len().min(buf.len());
+				buf[..length].copy_from_slice(&packet[..length]);
+				return Poll::Ready(Ok(length));
+			}
+			pipe.waker = Some(cx.waker().clone());
+			drop(pipe);
+
+			timeout.as_mut().poll(cx).map(|_| Err("Read timeout"))
+		})
+		.await
+	}
+
+	const SIZE: usize = SIM_SERIAL_PACKET_SIZE;
+}
+
+// the simulated link stays up for as long as the simulator runs
+impl SerialSession for SimSerialReader {}
+
+impl SerialPacketSender for SimSerialSender {
+	async fn write_packet(&mut self, data: &[u8]) -> Result<(), &'static str> {
+		let mut pipe = self.pipe.lock().unwrap();
+		pipe.packets.push_back(data.to_vec());
+		if let Some(waker) = pipe.waker.take() {
+			waker.wake();
+		}
+		Ok(())
+	}
+
+	const SIZE: usize = SIM_SERIAL_PACKET_SIZE;
+}
+
+pub type SimHidReport = HidReport<
+	{ <NKROKeyboard as HidDevice<KeyboardEvent>>::SIZE },
+	{ <Mouse as HidDevice<MouseEvent>>::SIZE },
+	{ <ConsumerControl as HidDevice<ConsumerControlEvent>>::SIZE },
+>;
+
+/// Builds the same reports as the USB keypad and queues them for the test or host app to
+/// inspect, instead of sending them to a host.
+pub struct SimHid {
+	keyboard: NKROKeyboard,
+	mouse: Mouse,
+	consumer: ConsumerControl,
+	reports: Arc<Mutex<VecDeque<SimHidReport>>>,
+}
+
+/// The receiving end of a `SimHid`.
+#[derive(Clone)]
+pub struct SimHidReports {
+	reports: Arc<Mutex<VecDeque<SimHidReport>>>,
+}
+
+impl SimHid {
+	pub fn new() -> (Self, SimHidReports) {
+		let reports = Arc::new(Mutex::new(VecDeque::new()));
+		(
+			Self {
+				keyboard: NKROKeyboard::new(),
+				mouse: Mouse::new(),
+				consumer: ConsumerControl::new(),
+				reports: reports.clone(),
+			},
+			SimHidReports { reports },
+		)
+	}
+}
+
+impl SimHidReports {
+	/// Takes the oldest report flushed by the keypad, if any.
+	pub fn try_take(&self) -> Option<SimHidReport> {
+		self.reports.lock().unwrap().pop_front()
+	}
+
+	/// Takes every report flushed so far, oldest first.
+	pub fn take_all(&self) -> Vec<SimHidReport> {
+		self.reports.lock().unwrap().drain(..).collect()
+	}
+}
+
+impl ReportHid for SimHid {
+	fn report_keyboard(&mut self, report: &KeyboardEvent) {
+		self.keyboard.input(report);
+	}
+
+	fn report_mouse(&mut self, report: &MouseEvent) {
+		self.mouse.input(report);
+	}
+
+	fn report_motion(&mut self, x: i32, y: i32) {
+		self.mouse.add_motion(x, y);
+	}
+
+	fn report_consumer(&mut self, report: &ConsumerControlEvent) {
+		self.consumer.input(report);
+	}
+
+	fn flush(&mut self) {
+		let report = HidReport {
+			keyboard: self.keyboard.create_report(),
+			mouse: self.mouse.create_report(),
+			consumer: self.consumer.create_report(),
+		};
+		self.reports.lock().unwrap().push_back(report);
+	}
+
+	fn reset(&mut self) {
+		self.keyboard.reset();
+		self.mouse.reset();
+		self.consumer.reset();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::profile::KeyboardKey;
+	use crate::serial::{BufferedReader, FramedReader, FramedWriter};
+	use crate::stream::{ReadAsync, WriteAsync};
+
+	#[tokio::test]
+	async fn flash_behaves_like_nor() {
+		let mut flash = SimFlash::new(8192);
+		let data = flash.as_slice();
+		assert!(data.iter().all(|byte| *byte == 0xFF));
+
+		flash.write(10, &[0x0F, 0xAA]).await.unwrap();
+		flash.write(10, &[0xF0, 0xFF]).await.unwrap();
+		assert_eq!(&data[10..12], &[0x00, 0xAA]);
+
+		assert_eq!(flash.erase(100, 4096).await, Err("Erase not block aligned"));
+		assert_eq!(flash.erase(4096, 8192).await, Err("Erase out of bounds"));
+		assert_eq!(flash.write(8191, &[0, 0]).await, Err("Write out of bounds"));
+
+		flash.erase(0, 4096).await.unwrap();
+		assert_eq!(&data[10..12], &[0xFF, 0xFF]);
+	}
+
+	#[tokio::test]
+	async fn flash_persists_to_file() {
+		let path = std::env::temp_dir().join(std::format!(
+			"cardboard-sim-flash-{}.bin",
+			std::process::id()
+		));
+		let _ = std::fs::remove_file(&path);
+
+		let mut flash = SimFlash::open(&path, 4096).unwrap();
+		flash.write(0, b"keep").await.unwrap();
+
+		let reopened = SimFlash::open(&path, 4096).unwrap();
+		assert_eq!(&reopened.as_slice()[..4], b"keep");
+		assert_eq!(reopened.as_slice()[4], 0xFF);
+
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn serial_pipe_carries_frames() {
+		let (tx, rx) = sim_serial_pipe(Duration::millis(100));
+		let mut writer = FramedWriter::<_, 256>::new(tx);
+		let mut reader = FramedReader::<_, 256>::new(BufferedReader::new(rx));
+
+		let message: Vec<u8> = (0..150).collect();
+		writer.write_exact(&message).await.unwrap();
+
+		let mut received = vec![0u8; message.len()];
+		reader.read_exact(&mut received).await.unwrap();
+		assert_eq!(received, message);
+	}
+
+	#[tokio::test]
+	async fn serial_read_times_out() {
+		let (_tx, mut rx) = sim_serial_pipe(Duration::millis(10));
+		let mut buf = [0u8; SIM_SERIAL_PACKET_SIZE];
+		assert_eq!(rx.read_packet(&mut buf).await, Err("Read timeout"));
+	}
+
+	#[tokio::test]
+	async fn clock_waits() {
+		let clock = SimClock::new();
+		let start = clock.now();
+		clock.after(Duration::millis(20)).await;
+		assert!(clock.now() - start >= Duration::millis(20));
+	}
+
+	#[test]
+	fn hid_queues_reports() {
+		let (mut hid, reports) = SimHid::new();
+		hid.report_keyboard(&KeyboardEvent::KeyDown(KeyboardKey::A));
+		hid.flush();
+		hid.report_keyboard(&KeyboardEvent::KeyUp(KeyboardKey::A));
+		hid.flush();
+
+		let reports = reports.take_all();
+		assert_eq!(reports.len(), 2);
+		assert_ne!(reports[0].keyboard, reports[1].keyboard);
+		assert!(reports[1].keyboard.unwrap().iter().all(|byte| *byte == 0));
+	}
+}
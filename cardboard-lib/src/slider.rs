@@ -0,0 +1,400 @@
+use crate::encoder::ScrollAxis;
+use crate::profile::{ConsumerControlEvent, LayerIndex, LayerMask, MouseScroll, VirtualKeyId};
+use crate::serialize::Readable;
+use crate::stream::{ReadAsync, ReadAsyncExt};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use critical_section::Mutex;
+
+/// A touch strip, read for where along it a finger is.
+pub trait SliderSensor {
+	/// Brings the sensor up; called once before the first read.
+	async fn init(&mut self) -> Result<(), &'static str> {
+		Ok(())
+	}
+
+	/// Position along the strip from 0 to 255, or `None` while nothing touches it.
+	async fn read_position(&mut self) -> Result<Option<u8>, &'static str>;
+}
+
+/// A row of capacitive pads, measured as counts that rise when a finger is near, such as how
+/// long each pad takes to charge.
+pub trait TouchPads {
+	fn len(&self) -> usize;
+	async fn measure(&mut self, counts: &mut [u16]) -> Result<(), &'static str>;
+}
+
+/// Measurements averaged for each pad's untouched count when the slider starts.
+const CALIBRATION_SAMPLES: u32 = 16;
+
+/// A slider built from a row of pads. Each pad's untouched count is learned at start and
+/// follows slow drift while nothing touches the strip; a finger's position is the centroid of
+/// how far each pad has risen above it.
+pub struct PadSlider<P: TouchPads> {
+	pads: P,
+	/// Rise over the untouched count that means a finger is on a pad.
+	threshold: u16,
+	baseline: Vec<u16>,
+	counts: Vec<u16>,
+}
+
+impl<P: TouchPads> PadSlider<P> {
+	pub fn new(pads: P, threshold: u16) -> Self {
+		let len = pads.len();
+		assert!(len >= 2);
+		Self {
+			pads,
+			// a finger has to raise a pad at least a little, or an untouched strip would read
+			threshold: threshold.max(1),
+			baseline: vec![0; len],
+			counts: vec![0; len],
+		}
+	}
+
+	fn position(&self) -> Option<u8> {
+		let rises: Vec<u32> = self
+			.counts
+			.iter()
+			.zip(self.baseline.iter())
+			.map(|(count, baseline)| count.saturating_sub(*baseline) as u32)
+			.collect();
+		if rises.iter().all(|rise| *rise < self.threshold as u32) {
+			return None;
+		}
+
+		let total: u32 = rises.iter().sum();
+		let weighted: u32 = rises
+			.iter()
+			.enumerate()
+			.map(|(i, rise)| rise * i as u32 * 255 / (rises.len() as u32 - 1))
+			.sum();
+		Some((weighted / total) as u8)
+	}
+}
+
+impl<P: TouchPads> SliderSensor for PadSlider<P> {
+	async fn init(&mut self) -> Result<(), &'static str> {
+		let mut totals = vec![0u32; self.baseline.len()];
+		for _ in 0..CALIBRATION_SAMPLES {
+			self.pads.measure(&mut self.counts).await?;
+			for (total, count) in totals.iter_mut().zip(self.counts.iter()) {
+				*total += *count as u32;
+			}
+		}
+		for (baseline, total) in self.baseline.iter_mut().zip(totals) {
+			*baseline = (total / CALIBRATION_SAMPLES) as u16;
+		}
+		Ok(())
+	}
+
+	async fn read_position(&mut self) -> Result<Option<u8>, &'static str> {
+		self.pads.measure(&mut self.counts).await?;
+		let position = self.position();
+		if position.is_none() {
+			for (baseline, count) in self.baseline.iter_mut().zip(self.counts.iter()) {
+				*baseline = match (*count).cmp(baseline) {
+					core::cmp::Ordering::Greater => *baseline + 1,
+					core::cmp::Ordering::Less => *baseline - 1,
+					core::cmp::Ordering::Equal => *baseline,
+				};
+			}
+		}
+		Ok(position)
+	}
+}
+
+/// Where the slider was last touched, set by the slider task and read by the keypad task.
+pub struct SliderPosition {
+	position: Mutex<Cell<Option<u8>>>,
+}
+
+impl SliderPosition {
+	pub const fn new() -> Self {
+		Self {
+			position: Mutex::new(Cell::new(None)),
+		}
+	}
+
+	pub fn set(&self, position: Option<u8>) {
+		critical_section::with(|cs| self.position.borrow(cs).set(position));
+	}
+
+	pub fn get(&self) -> Option<u8> {
+		critical_section::with(|cs| self.position.borrow(cs).get())
+	}
+}
+
+impl Default for SliderPosition {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub enum SliderAction {
+	Scroll {
+		axis: ScrollAxis,
+		/// Scroll steps per slider step; negative reverses the direction.
+		speed: i8,
+	},
+	/// One volume up or down per step.
+	Volume,
+	/// Taps a virtual key for each step, running whatever macros it has on the active layer.
+	VirtualKeys {
+		decrease: VirtualKeyId,
+		increase: VirtualKeyId,
+	},
+}
+
+impl Readable for SliderAction {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		match reader
+			.read_u8()
+			.await
+			.ok_or("Failed to read slider action")?
+		{
+			0 => Ok(SliderAction::Scroll {
+				axis: ScrollAxis::read_from(reader).await?,
+				speed: reader
+					.read_i8()
+					.await
+					.ok_or("Failed to read scroll speed")?,
+			}),
+			1 => Ok(SliderAction::Volume),
+			2 => Ok(SliderAction::VirtualKeys {
+				decrease: VirtualKeyId::read_from(reader).await?,
+				increase: VirtualKeyId::read_from(reader).await?,
+			}),
+			_ => Err("Unknown slider action"),
+		}
+	}
+}
+
+/// What sliding along the touch strip does while a layer is active.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "postcard", derive(serde::Serialize, serde::Deserialize))]
+pub struct SliderMapping {
+	/// `None` applies when no mapping for an active layer does.
+	pub layer: Option<LayerIndex>,
+	/// How many steps the whole length of the strip is divided into.
+	pub steps: u8,
+	pub action: SliderAction,
+}
+
+impl Readable for SliderMapping {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let layer = reader
+			.read_option()
+			.await
+			.ok_or("Failed to read slider layer")?;
+		let steps = reader
+			.read_u8()
+			.await
+			.ok_or("Failed to read slider steps")?;
+		if steps == 0 {
+			return Err("Invalid slider steps");
+		}
+		let action = SliderAction::read_from(reader).await?;
+
+		Ok(Self {
+			layer,
+			steps,
+			action,
+		})
+	}
+}
+
+impl SliderMapping {
+	/// The first mapping for an active layer, or else the first without a layer.
+	pub fn find(mappings: &[SliderMapping], active_layers: LayerMask) -> Option<&SliderMapping> {
+		mappings
+			.iter()
+			.find(|m| {
+				m.layer
+					.is_some_and(|layer| active_layers & (1 << layer.get_index()) != 0)
+			})
+			.or_else(|| mappings.iter().find(|m| m.layer.is_none()))
+	}
+}
+
+/// One step's worth of output from the slider.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SliderStep {
+	Scroll(MouseScroll),
+	Consumer(ConsumerControlEvent),
+	/// Press and release the virtual key.
+	TapVirtualKey(VirtualKeyId),
+}
+
+/// Turns slider positions into steps under the profile's mappings, driven by the keypad task.
+#[derive(Default)]
+pub struct SliderSteps {
+	/// Where the finger was at the last step, while it's on the strip.
+	anchor: Option<u8>,
+	/// Volume steps still to send, one a tick so the host sees each one.
+	volume: i32,
+}
+
+impl SliderSteps {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Follows the finger to `position`, reporting a step for each step length it has moved
+	/// since the last one. Returns true while the strip is touched or steps are still queued.
+	pub fn update(
+		&mut self,
+		position: Option<u8>,
+		mappings: &[SliderMapping],
+		active_layers: LayerMask,
+		mut report: impl FnMut(SliderStep),
+	) -> bool {
+		if self.volume != 0 {
+			let (event, step) = match self.volume > 0 {
+				true => (ConsumerControlEvent::VOLUME_INCREMENT, 1),
+				false => (ConsumerControlEvent::VOLUME_DECREMENT, -1),
+			};
+			report(SliderStep::Consumer(event));
+			self.volume -= step;
+		}
+
+		let (Some(position), Some(anchor)) = (position, self.anchor) else {
+			self.anchor = position;
+			return position.is_some() || self.volume != 0;
+		};
+		let Some(mapping) = SliderMapping::find(mappings, active_layers) else {
+			return true;
+		};
+
+		let step_length = 256 / mapping.steps as i32;
+		let steps = (position as i32 - anchor as i32) / step_length;
+		if steps == 0 {
+			return true;
+		}
+		self.anchor = Some((anchor as i32 + steps * step_length) as u8);
+
+		match &mapping.action {
+			SliderAction::Scroll { axis, speed } => {
+				report(SliderStep::Scroll(axis.scroll(steps * *speed as i32)))
+			}
+			SliderAction::Volume => self.volume += steps,
+			SliderAction::VirtualKeys { decrease, increase } => {
+				let key = if steps > 0 { *increase } else { *decrease };
+				for _ in 0..steps.unsigned_abs() {
+					report(SliderStep::TapVirtualKey(key));
+				}
+			}
+		}
+		true
+	}
+
+	/// Forgets the finger and any queued steps, e.g. when the profile changes.
+	pub fn reset(&mut self) {
+		*self = Self::default();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use uuid::Uuid;
+
+	struct MockPads {
+		counts: Vec<u16>,
+	}
+
+	impl TouchPads for MockPads {
+		fn len(&self) -> usize {
+			self.counts.len()
+		}
+
+		async fn measure(&mut self, counts: &mut [u16]) -> Result<(), &'static str> {
+			counts.copy_from_slice(&self.counts);
+			Ok(())
+		}
+	}
+
+	#[tokio::test]
+	async fn pad_slider_finds_the_finger_between_pads() {
+		let mut slider = PadSlider::new(
+			MockPads {
+				counts: vec![100, 100, 100],
+			},
+			20,
+		);
+		slider.init().await.unwrap();
+		assert_eq!(slider.read_position().await, Ok(None));
+
+		// evenly over the last two pads
+		slider.pads.counts = vec![100, 150, 150];
+		assert_eq!(slider.read_position().await, Ok(Some(191)));
+	}
+
+	fn steps_for(mapping: SliderMapping, positions: &[Option<u8>]) -> Vec<SliderStep> {
+		let mut slider = SliderSteps::new();
+		let mut steps = Vec::new();
+		for position in positions {
+			slider.update(*position, &[mapping.clone()], 0, |step| steps.push(step));
+		}
+		steps
+	}
+
+	#[test]
+	fn sliding_scrolls_a_step_at_a_time() {
+		let mapping = SliderMapping {
+			layer: None,
+			steps: 8,
+			action: SliderAction::Scroll {
+				axis: ScrollAxis::Vertical,
+				speed: 2,
+			},
+		};
+
+		// 32 positions a step; touching down alone does nothing
+		let steps = steps_for(mapping, &[Some(100), Some(120), Some(140), Some(40), None]);
+		assert_eq!(
+			steps,
+			[
+				SliderStep::Scroll(MouseScroll { x: 0, y: 2 }),
+				SliderStep::Scroll(MouseScroll { x: 0, y: -4 }),
+			]
+		);
+	}
+
+	#[test]
+	fn volume_steps_go_out_one_a_tick() {
+		let mapping = SliderMapping {
+			layer: None,
+			steps: 4,
+			action: SliderAction::Volume,
+		};
+
+		let steps = steps_for(mapping, &[Some(0), Some(200), None, None, None]);
+		assert_eq!(
+			steps,
+			[SliderStep::Consumer(ConsumerControlEvent::VOLUME_INCREMENT); 3]
+		);
+	}
+
+	#[test]
+	fn virtual_keys_are_tapped_per_step() {
+		let decrease = VirtualKeyId::new(Uuid::from_u128(1));
+		let increase = VirtualKeyId::new(Uuid::from_u128(2));
+		let mapping = SliderMapping {
+			layer: None,
+			steps: 2,
+			action: SliderAction::VirtualKeys { decrease, increase },
+		};
+
+		let steps = steps_for(mapping, &[Some(255), Some(0)]);
+		assert_eq!(steps, [SliderStep::TapVirtualKey(decrease)]);
+	}
+}
@@ -0,0 +1,267 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use nrf_softdevice::ble::gatt_server::builder::ServiceBuilder;
+use nrf_softdevice::ble::gatt_server::characteristic::{Attribute, Metadata, Properties};
+use nrf_softdevice::ble::gatt_server::{self, NotifyValueError, RegisterError, WriteOp};
+use nrf_softdevice::ble::security::SecurityHandler;
+use nrf_softdevice::ble::{Connection, SecurityMode, Uuid, peripheral};
+use nrf_softdevice::{RawError, Softdevice, raw};
+
+use crate::ble::BleHidTransport;
+
+const HID_SERVICE: Uuid = Uuid::new_16(0x1812);
+const HID_INFORMATION: Uuid = Uuid::new_16(0x2A4A);
+const REPORT_MAP: Uuid = Uuid::new_16(0x2A4B);
+const HID_CONTROL_POINT: Uuid = Uuid::new_16(0x2A4C);
+const REPORT: Uuid = Uuid::new_16(0x2A4D);
+const PROTOCOL_MODE: Uuid = Uuid::new_16(0x2A4E);
+const REPORT_REFERENCE: Uuid = Uuid::new_16(0x2908);
+
+// HID 1.11, not localized, remote wake and normally connectable
+const HID_INFO: [u8; 4] = [0x11, 0x01, 0x00, 0x03];
+// report protocol; there's no boot protocol keyboard to fall back to
+const PROTOCOL_MODE_REPORT: u8 = 0x01;
+const INPUT_REPORT: u8 = 0x01;
+
+// legacy advertising packets are 31 bytes
+const MAX_ADV_DATA: usize = 31;
+const APPEARANCE_KEYBOARD: u16 = 0x03C1;
+
+/// The HID-over-GATT service, registered with the SoftDevice before it starts running. It
+/// exposes the [`crate::ble::report_map`] and one input report characteristic per report ID,
+/// tagged with that ID in its report reference descriptor.
+pub struct HidService {
+	reports: Vec<(u8, u16)>,
+}
+
+impl HidService {
+	/// `reports` lists each report ID in the map with the length of its report.
+	pub fn new(
+		sd: &mut Softdevice,
+		report_map: Vec<u8>,
+		reports: &[(u8, usize)],
+	) -> Result<Self, RegisterError> {
+		let mut service = ServiceBuilder::new(sd, HID_SERVICE)?;
+
+		service
+			.add_characteristic(
+				HID_INFORMATION,
+				Attribute::new(HID_INFO).security(SecurityMode::JustWorks),
+				Metadata::new(Properties::new().read()),
+			)?
+			.build();
+		service
+			.add_characteristic(
+				REPORT_MAP,
+				Attribute::new(report_map).security(SecurityMode::JustWorks),
+				Metadata::new(Properties::new().read()),
+			)?
+			.build();
+		service
+			.add_characteristic(
+				HID_CONTROL_POINT,
+				Attribute::new([0u8]).security(SecurityMode::JustWorks),
+				Metadata::new(Properties::new().write_without_response()),
+			)?
+			.build();
+		service
+			.add_characteristic(
+				PROTOCOL_MODE,
+				Attribute::new([PROTOCOL_MODE_REPORT]).security(SecurityMode::JustWorks),
+				Metadata::new(Properties::new().read().write_without_response()),
+			)?
+			.build();
+
+		let mut handles = Vec::with_capacity(reports.len());
+		for (report_id, length) in reports {
+			let mut report = service.add_characteristic(
+				REPORT,
+				Attribute::new(vec![0u8; *length]).security(SecurityMode::JustWorks),
+				Metadata::new(Properties::new().read().notify()),
+			)?;
+			report.add_descriptor(
+				REPORT_REFERENCE,
+				Attribute::new([*report_id, INPUT_REPORT]).security(SecurityMode::JustWorks),
+			)?;
+			handles.push((*report_id, report.build().value_handle));
+		}
+		service.build();
+
+		Ok(Self { reports: handles })
+	}
+
+	fn report_handle(&self, report_id: u8) -> Option<u16> {
+		self.reports
+			.iter()
+			.find(|(id, _)| *id == report_id)
+			.map(|(_, handle)| *handle)
+	}
+}
+
+impl gatt_server::Server for HidService {
+	type Event = ();
+
+	// suspend and protocol mode writes are accepted, but the keypad carries on the same
+	fn on_write(
+		&self,
+		_conn: &Connection,
+		_handle: u16,
+		_op: WriteOp,
+		_offset: usize,
+		_data: &[u8],
+	) -> Option<Self::Event> {
+		None
+	}
+}
+
+/// The host connection, kept by [`serve`] while a host is connected for the transport to
+/// notify through. Shared the same way as `BatteryStatus`.
+pub struct BleConnection {
+	connection: Mutex<RefCell<Option<Connection>>>,
+}
+
+impl BleConnection {
+	pub const fn new() -> Self {
+		Self {
+			connection: Mutex::new(RefCell::new(None)),
+		}
+	}
+
+	fn set(&self, connection: Option<Connection>) {
+		critical_section::with(|cs| *self.connection.borrow(cs).borrow_mut() = connection);
+	}
+
+	fn get(&self) -> Option<Connection> {
+		critical_section::with(|cs| self.connection.borrow(cs).borrow().clone())
+	}
+}
+
+impl Default for BleConnection {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// [`BleHidTransport`] through the SoftDevice, for [`crate::ble::BleHid`] to send reports
+/// over.
+pub struct SoftdeviceHidTransport {
+	service: &'static HidService,
+	connection: &'static BleConnection,
+}
+
+impl SoftdeviceHidTransport {
+	pub fn new(service: &'static HidService, connection: &'static BleConnection) -> Self {
+		Self {
+			service,
+			connection,
+		}
+	}
+}
+
+impl BleHidTransport for SoftdeviceHidTransport {
+	fn is_connected(&self) -> bool {
+		self.connection.get().is_some()
+	}
+
+	async fn notify(&mut self, report_id: u8, report: &[u8]) -> Result<(), &'static str> {
+		let handle = self
+			.service
+			.report_handle(report_id)
+			.ok_or("No input report characteristic for the report ID")?;
+		loop {
+			let Some(connection) = self.connection.get() else {
+				return Ok(());
+			};
+			match gatt_server::notify_value(&connection, handle, report) {
+				Ok(()) => return Ok(()),
+				// the outgoing queue is full; a dropped key up would leave the key held
+				Err(NotifyValueError::Raw(RawError::Resources)) => {
+					embassy_futures::yield_now().await
+				}
+				// gone, or the host hasn't subscribed to this report yet
+				Err(NotifyValueError::Disconnected)
+				| Err(NotifyValueError::Raw(RawError::InvalidState))
+				| Err(NotifyValueError::Raw(RawError::BleGattsSysAttrMissing)) => return Ok(()),
+				Err(_) => {
+					error!("Failed to notify BLE input report {}", report_id);
+					return Err("Failed to notify BLE input report");
+				}
+			}
+		}
+	}
+}
+
+// hosts pair without a passkey, since the keypad has no display; bonds aren't kept, so a
+// host pairs again after the keypad restarts
+struct JustWorks;
+
+impl SecurityHandler for JustWorks {}
+
+static JUST_WORKS: JustWorks = JustWorks;
+
+fn advertising_data(name: &str) -> Vec<u8> {
+	let [appearance_low, appearance_high] = APPEARANCE_KEYBOARD.to_le_bytes();
+	let mut data = vec![
+		0x02,
+		raw::BLE_GAP_AD_TYPE_FLAGS as u8,
+		raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8,
+		0x03,
+		raw::BLE_GAP_AD_TYPE_16BIT_SERVICE_UUID_COMPLETE as u8,
+		0x12,
+		0x18,
+		0x03,
+		raw::BLE_GAP_AD_TYPE_APPEARANCE as u8,
+		appearance_low,
+		appearance_high,
+	];
+
+	// a name that doesn't fit is cut short and advertised as such
+	let room = MAX_ADV_DATA - data.len() - 2;
+	let (name, name_type) = if name.len() > room {
+		(
+			&name.as_bytes()[..room],
+			raw::BLE_GAP_AD_TYPE_SHORT_LOCAL_NAME,
+		)
+	} else {
+		(name.as_bytes(), raw::BLE_GAP_AD_TYPE_COMPLETE_LOCAL_NAME)
+	};
+	data.extend_from_slice(&[name.len() as u8 + 1, name_type as u8]);
+	data.extend_from_slice(name);
+	data
+}
+
+/// Advertises the HID service as a keyboard called `name` until a host connects, serves it
+/// until that host disconnects, then advertises again. `connection` follows along for
+/// [`SoftdeviceHidTransport`]. The SoftDevice itself has to be running in a task of its own.
+pub async fn serve(
+	sd: &'static Softdevice,
+	service: &'static HidService,
+	connection: &'static BleConnection,
+	name: &str,
+) -> ! {
+	let adv_data = advertising_data(name);
+	let config = peripheral::Config::default();
+	loop {
+		let advertisement = peripheral::ConnectableAdvertisement::ScannableUndirected {
+			adv_data: &adv_data,
+			scan_data: &[],
+		};
+		let conn =
+			match peripheral::advertise_pairable(sd, advertisement, &config, &JUST_WORKS).await {
+				Ok(conn) => conn,
+				Err(_) => {
+					warn!("BLE advertising failed");
+					continue;
+				}
+			};
+
+		info!("BLE host connected");
+		connection.set(Some(conn.clone()));
+		gatt_server::run(&conn, service, |_| {}).await;
+		connection.set(None);
+		info!("BLE host disconnected");
+	}
+}
@@ -3,20 +3,77 @@ extern crate alloc;
 use core::fmt;
 use core::slice::IterMut;
 
+use crate::budget::MemoryBudget;
 use crate::input::KeyId;
+use crate::lighting::Rgb;
 use crate::profile::*;
+use crate::random::Rng;
+use crate::serialize::{Readable, Writeable};
+use crate::stats::LoopTiming;
+use crate::stream::{ReadAsync, ReadAsyncExt, WriteAsync, WriteAsyncExt};
+use crate::text::TextTyping;
 use crate::time::Duration;
+use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::vec::Vec;
 use bitset_core::BitSet;
-use defmt::warn;
 use fugit::ExtU64;
 
 pub struct KeyboardState<'a> {
 	keys: Vec<PhysicalKeyState<'a>>,
 	virtual_keys: Vec<VirtualKeyState<'a>>,
 	tags: TagList<'a>,
+	layers: &'a [Layer],
+	active_layers: LayerMask,
 	running: Vec<MacroState<'a>>,
 	macros: &'a Vec<Macro>,
+	macro_budget: Option<&'static MemoryBudget>,
+	macro_limit: MacroLimit,
+	macro_overflowed: bool,
+	rng: Rng,
+	speed_percent: u16,
+	/// Internal tags that clear themselves, with the time they have left.
+	tag_deadlines: Vec<(&'a LayerTag, Duration)>,
+	/// Layer events macros sent this tick, applied once they've all played. Kept between ticks
+	/// so it only allocates when a tick sends more than any before it.
+	layer_events: Vec<&'a LayerEvent>,
+	/// Set when a layer event or an expiring tag changes the internal tags.
+	tags_changed: bool,
+	key_stats: KeyStats,
+	/// Set when a key's layer changes, so its backlight needs redrawing.
+	backlight_changed: bool,
+	/// Keys armed since the last tick with a known capture time, and how long before the next
+	/// tick ends they were pressed.
+	armed_late: Vec<(KeyIndex, Duration)>,
+	/// Keyboard keys held by macros the macro limit cut, waiting to be released.
+	cut_keys: HeldKeys,
+}
+
+/// What to do when a key press would start more macros than [`MacroLimit::max_running`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MacroOverflowPolicy {
+	/// Macros that don't fit don't start.
+	RejectNewest,
+	/// The oldest running macros are dropped to make room. They don't get to play their end
+	/// sequence; keys they were holding are released instead.
+	CutOldest,
+}
+
+/// Caps how many macros can run at once, so a pathological profile or a stuck key can't grow
+/// the running list until the heap runs out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MacroLimit {
+	pub max_running: usize,
+	pub overflow: MacroOverflowPolicy,
+}
+
+impl Default for MacroLimit {
+	fn default() -> Self {
+		Self {
+			max_running: 32,
+			overflow: MacroOverflowPolicy::RejectNewest,
+		}
+	}
 }
 
 impl<'a> KeyboardState<'a> {
@@ -29,9 +86,23 @@ impl<'a> KeyboardState<'a> {
 				.enumerate()
 				.map(|(i, vk)| VirtualKeyState::from(vk, i))
 				.collect(),
-			tags: TagList::new(),
+			tags: TagList::with_tag_ids(&profile.tags),
+			layers: &profile.layers,
+			active_layers: 0,
 			running: Vec::with_capacity(8),
 			macros: &profile.macros,
+			macro_budget: None,
+			macro_limit: MacroLimit::default(),
+			macro_overflowed: false,
+			rng: Rng::default(),
+			speed_percent: 100,
+			tag_deadlines: Vec::new(),
+			layer_events: Vec::new(),
+			tags_changed: false,
+			key_stats: KeyStats::default(),
+			backlight_changed: true,
+			armed_late: Vec::new(),
+			cut_keys: HeldKeys::default(),
 		};
 
 		state.update_layers();
@@ -40,18 +111,152 @@ impl<'a> KeyboardState<'a> {
 	}
 
 	pub fn press_key(&mut self, key_id: KeyId) {
-		if let Some(key) = self.get_key(key_id) {
-			let macros = Self::get_macros_from_key(self.macros, key);
-			Self::run_macros(&mut self.running, macros);
+		self.key_stats.count_press(key_id);
+		if let Some(i) = self.keys.iter().position(|ks| ks.key.id == key_id) {
+			self.key_down(KeyIndex::Physical(i), None);
+		};
+	}
+
+	/// Presses a key captured `age` before the end of the tick about to run, so its macros and
+	/// hold timer start from when it was captured rather than from the last tick. A late tick
+	/// then doesn't eat into the first predelay.
+	pub fn press_key_captured(&mut self, key_id: KeyId, age: Duration) {
+		self.key_stats.count_press(key_id);
+		if let Some(i) = self.keys.iter().position(|ks| ks.key.id == key_id) {
+			self.key_down(KeyIndex::Physical(i), Some(age));
 		};
 	}
 
-	fn get_key(&self, key_id: KeyId) -> Option<&PhysicalKeyState<'a>> {
-		self.keys.iter().find(|ks| ks.key.id == key_id)
+	/// Presses a key the host injected; see [`Self::press_key_captured`]. Only keys pressed on
+	/// the keypad count towards the key stats, so the press isn't counted.
+	pub fn press_injected_key(&mut self, key_id: KeyId, age: Duration) {
+		if let Some(i) = self.keys.iter().position(|ks| ks.key.id == key_id) {
+			self.key_down(KeyIndex::Physical(i), Some(age));
+		};
 	}
 
 	pub fn release_key(&mut self, key_id: KeyId) {
-		Self::release_key_source(self.running.iter_mut(), MacroSourceKey::PhysicalKey(key_id));
+		if let Some(i) = self.keys.iter().position(|ks| ks.key.id == key_id) {
+			self.key_up(KeyIndex::Physical(i), None);
+		};
+	}
+
+	/// Releases a key captured `age` before the end of the tick about to run; see
+	/// [`Self::press_key_captured`].
+	pub fn release_key_captured(&mut self, key_id: KeyId, age: Duration) {
+		if let Some(i) = self.keys.iter().position(|ks| ks.key.id == key_id) {
+			self.key_up(KeyIndex::Physical(i), Some(age));
+		};
+	}
+
+	// `age` is how long before the end of the next tick the key changed, if known; without it the
+	// change counts from the last tick
+	fn key_down(&mut self, index: KeyIndex, age: Option<Duration>) {
+		let key = Self::key_state(&mut self.keys, &mut self.virtual_keys, index);
+		let start = match key.current_layer().trigger {
+			MacroTrigger::Press => true,
+			MacroTrigger::Release => false,
+			MacroTrigger::Hold { .. } => {
+				*key.armed() = Some(0.millis());
+				if let Some(age) = age {
+					self.armed_late.push((index, age));
+				}
+				false
+			}
+			MacroTrigger::DoublePress { window_ms } => {
+				let second_press = key
+					.armed()
+					.is_some_and(|since| since <= (window_ms as u64).millis());
+				// the second press consumes the first; a third starts a new pair
+				*key.armed() = if second_press { None } else { Some(0.millis()) };
+				if let (false, Some(age)) = (second_press, age) {
+					self.armed_late.push((index, age));
+				}
+				second_press
+			}
+		};
+
+		if start {
+			self.macro_overflowed |= Self::run_macros(
+				&mut self.running,
+				&mut self.cut_keys,
+				self.macros,
+				key,
+				self.macro_budget,
+				self.macro_limit,
+				age,
+			);
+		}
+	}
+
+	fn key_up(&mut self, index: KeyIndex, age: Option<Duration>) {
+		let key = Self::key_state(&mut self.keys, &mut self.virtual_keys, index);
+		match key.current_layer().trigger {
+			MacroTrigger::Release => {
+				// started and released at once, so these play their start and end sequences
+				self.macro_overflowed |= Self::run_macros(
+					&mut self.running,
+					&mut self.cut_keys,
+					self.macros,
+					key,
+					self.macro_budget,
+					self.macro_limit,
+					age,
+				);
+			}
+			MacroTrigger::Hold { .. } => *key.armed() = None,
+			MacroTrigger::Press | MacroTrigger::DoublePress { .. } => {}
+		}
+
+		Self::release_key_source(self.running.iter_mut(), key.key());
+	}
+
+	/// Advances hold and double press timers, starting any held macros that are due.
+	fn tick_triggers(&mut self, elapsed: Duration) {
+		let indices = (0..self.keys.len())
+			.map(KeyIndex::Physical)
+			.chain((0..self.virtual_keys.len()).map(KeyIndex::Virtual));
+		for index in indices {
+			let key = Self::key_state(&mut self.keys, &mut self.virtual_keys, index);
+			let Some(since) = *key.armed() else {
+				continue;
+			};
+			let since = match self.armed_late.iter().find(|(i, _)| *i == index) {
+				Some((_, age)) => (*age).min(since + elapsed),
+				None => since + elapsed,
+			};
+
+			match key.current_layer().trigger {
+				MacroTrigger::Hold { threshold_ms } if since >= (threshold_ms as u64).millis() => {
+					*key.armed() = None;
+					self.macro_overflowed |= Self::run_macros(
+						&mut self.running,
+						&mut self.cut_keys,
+						self.macros,
+						key,
+						self.macro_budget,
+						self.macro_limit,
+						None,
+					);
+				}
+				MacroTrigger::DoublePress { window_ms } if since > (window_ms as u64).millis() => {
+					*key.armed() = None;
+				}
+				_ => *key.armed() = Some(since),
+			}
+		}
+		self.armed_late.clear();
+	}
+
+	fn key_state<'k>(
+		keys: &'k mut [PhysicalKeyState<'a>],
+		virtual_keys: &'k mut [VirtualKeyState<'a>],
+		index: KeyIndex,
+	) -> &'k mut dyn KeyState<'a> {
+		match index {
+			KeyIndex::Physical(i) => &mut keys[i],
+			KeyIndex::Virtual(i) => &mut virtual_keys[i],
+		}
 	}
 
 	fn release_key_source(running: IterMut<MacroState<'a>>, source_key: MacroSourceKey) {
@@ -66,59 +271,218 @@ impl<'a> KeyboardState<'a> {
 		let num_bits = bits.len() * 8;
 		let num_keys = self.virtual_keys.len().min(num_bits);
 		for i in 0..num_keys {
-			let key = &mut self.virtual_keys[i];
 			let Some(bit_index) = to_bitset_index(i, num_bits) else {
 				continue;
 			};
 			let state = bits.bit_test(bit_index);
-			match key.update(state) {
-				Some(true) => {
-					let macros = Self::get_macros_from_key(self.macros, key);
-					Self::run_macros(&mut self.running, macros);
-				}
-				Some(false) => {
-					Self::release_key_source(
-						self.running.iter_mut(),
-						MacroSourceKey::VirtualKey(key.id),
-					);
-				}
+			match self.virtual_keys[i].update(state) {
+				Some(true) => self.key_down(KeyIndex::Virtual(i), None),
+				Some(false) => self.key_up(KeyIndex::Virtual(i), None),
 				_ => {}
 			};
 		}
 	}
 
-	fn get_macros_from_key<K: KeyState<'a>>(
-		macros: &'a Vec<Macro>,
+	/// Sets one virtual key by ID. Returns false if the profile has no key with that ID.
+	pub fn set_virtual_key_by_id(&mut self, id: VirtualKeyId, pressed: bool) -> bool {
+		let Some(i) = self
+			.virtual_keys
+			.iter()
+			.position(|vk| vk.key.id == Some(id))
+		else {
+			return false;
+		};
+
+		match self.virtual_keys[i].update(pressed) {
+			Some(true) => self.key_down(KeyIndex::Virtual(i), None),
+			Some(false) => self.key_up(KeyIndex::Virtual(i), None),
+			_ => {}
+		};
+		true
+	}
+
+	fn get_macros_from_key<K: KeyState<'a> + ?Sized>(
+		macros: &'a [Macro],
 		key: &K,
-	) -> Vec<MacroState<'a>> {
+	) -> impl Iterator<Item = &'a Macro> + Clone {
 		key.current_layer()
 			.macros
 			.iter()
-			.filter_map(|i| match macros.get(i.get_index()) {
-				Some(macro_) => Some(MacroState::from(macro_, key)),
-				None => {
-					warn!("Macro index {:?} not found in profile macros.", i);
-					None
-				}
-			})
-			.collect()
+			.filter_map(|i| macros.get(i.get_index()))
 	}
 
-	fn run_macros(running: &mut Vec<MacroState<'a>>, macros: Vec<MacroState<'a>>) {
-		let channels_to_cut: Vec<Channel> = macros
-			.iter()
-			.flat_map(|m| m.macro_.cut_channels.iter().copied())
-			.collect();
-		Self::cut_channels(running.iter_mut(), &channels_to_cut);
-		running.extend(macros);
+	/// Limits the heap held by running macros; macros that would exceed it don't start.
+	pub fn set_macro_budget(&mut self, budget: &'static MemoryBudget) {
+		self.macro_budget = Some(budget);
+	}
+
+	pub fn set_macro_limit(&mut self, limit: MacroLimit) {
+		self.macro_limit = limit;
+	}
+
+	/// Seeds the generator behind randomized action delays.
+	pub fn seed_rng(&mut self, seed: u32) {
+		self.rng = Rng::new(seed);
+	}
+
+	/// Global playback speed in percent, applied on top of each macro's own speed.
+	pub fn set_speed_percent(&mut self, percent: u16) {
+		self.speed_percent = percent;
 	}
 
+	/// Returns true if the macro limit was hit since the last call.
+	pub fn take_macro_overflow(&mut self) -> bool {
+		core::mem::take(&mut self.macro_overflowed)
+	}
+
+	/// Reports the backlight color of every key with one on any of its layers, if a layer
+	/// change may have changed them since the last call. Keys without one on their current
+	/// layer are reported as off.
+	pub fn take_backlight(&mut self, mut set: impl FnMut(KeyId, Rgb)) {
+		if !core::mem::take(&mut self.backlight_changed) {
+			return;
+		}
+		for ks in self.keys.iter().filter(|ks| ks.key.layers.has_backlight()) {
+			set(ks.key.id, ks.current_layer.backlight.unwrap_or(Rgb::OFF));
+		}
+	}
+
+	/// Releases the keyboard keys that macros cut by the macro limit were holding, unless a
+	/// macro still running holds them too.
+	pub fn take_cut_keys(&mut self, mut release: impl FnMut(KeyboardKey)) {
+		let mut keys = core::mem::take(&mut self.cut_keys);
+		for macro_ in self.running.iter() {
+			keys.remove(&macro_.held);
+		}
+		keys.for_each(&mut release);
+	}
+
+	/// Returns true while no macros are running.
+	pub fn is_idle(&self) -> bool {
+		self.running.is_empty()
+	}
+
+	// runs on every key press, so this walks the profile instead of collecting into Vecs.
+	// Returns true if the macro limit was hit.
+	fn run_macros<K: KeyState<'a> + ?Sized>(
+		running: &mut Vec<MacroState<'a>>,
+		cut_keys: &mut HeldKeys,
+		macros: &'a [Macro],
+		key: &K,
+		budget: Option<&MemoryBudget>,
+		limit: MacroLimit,
+		age: Option<Duration>,
+	) -> bool {
+		let mut count = 0;
+		for i in key.current_layer().macros.iter() {
+			if macros.get(i.get_index()).is_some() {
+				count += 1;
+			} else {
+				warn!("Macro index {:?} not found in profile macros.", i);
+			}
+		}
+
+		let mut room = count;
+		if let Some(budget) = budget {
+			// what the macros actually hold on the heap: the running list as allocated, plus
+			// the macros each one is partway through calling
+			let size = core::mem::size_of::<MacroState>();
+			let calls: usize = running.iter().map(MacroState::heap_size).sum();
+			let list = |len: usize| len.max(running.capacity()) * size;
+			if budget.check(list(running.len() + count) + calls).is_err() {
+				let free = budget.limit().saturating_sub(calls);
+				room = if list(running.len()) > free {
+					0
+				} else {
+					(free / size).saturating_sub(running.len())
+				};
+			}
+		}
+
+		let overflowed = running.len() + room > limit.max_running;
+		if overflowed {
+			warn!("Macro limit of {} reached", limit.max_running);
+			match limit.overflow {
+				MacroOverflowPolicy::RejectNewest => {
+					room = limit.max_running.saturating_sub(running.len());
+				}
+				MacroOverflowPolicy::CutOldest => {
+					room = room.min(limit.max_running);
+					let excess = (running.len() + room).saturating_sub(limit.max_running);
+					for cut in running.drain(..excess) {
+						cut_keys.add(&cut.held);
+					}
+				}
+			}
+		}
+
+		// cut before starting anything so macros started together don't cut each other
+		let layer_macros = Self::get_macros_from_key(macros, key).take(room);
+		for macro_ in layer_macros.clone() {
+			Self::cut_channels(running.iter_mut(), &macro_.cut_channels);
+		}
+		// grown by exactly what's starting, so the list stays the size the budget was checked for
+		running.reserve_exact(room);
+		running.extend(layer_macros.map(|macro_| {
+			let mut state = MacroState::from(macro_, key).with_calls(macros);
+			state.first_tick = age;
+			state
+		}));
+
+		overflowed
+	}
+
+	/// Plays the running macros for `elapsed`, passing their events to `on_event`. Layer events
+	/// are applied here instead, after every macro has played.
 	pub fn tick(&mut self, elapsed: Duration, mut on_event: impl FnMut(&'a ActionEvent)) {
-		for macro_ in self.running.iter_mut() {
-			macro_.tick(elapsed, &mut on_event);
+		self.tick_triggers(elapsed);
+		self.tick_tag_deadlines(elapsed);
+
+		let layer_events = &mut self.layer_events;
+		let mut on_event = |event: &'a ActionEvent| match event {
+			ActionEvent::Layer(event) => layer_events.push(event),
+			event => on_event(event),
+		};
+
+		for i in 0..self.running.len() {
+			if Self::is_preempted(&self.running, i) {
+				continue;
+			}
+
+			let macro_ = &mut self.running[i];
+			// a macro started by a key with a known capture time only plays from then
+			let played = macro_
+				.first_tick
+				.take()
+				.map_or(elapsed, |age| age.min(elapsed));
+			// only playback time is scaled; hold and double press timing stays real time.
+			// Called macros play at the speed of their caller
+			let speed = macro_.macro_.speed_percent as u64 * self.speed_percent as u64;
+			let scaled = (played.ticks() * speed / 10_000).micros();
+			let mut held = macro_.held;
+			macro_.tick(scaled, &mut self.rng, &mut |event: &'a ActionEvent| {
+				held.track(event);
+				on_event(event)
+			});
+			macro_.held = held;
 		}
 
 		self.running.retain(|macro_| !macro_.is_finished());
+
+		for i in 0..self.layer_events.len() {
+			match self.layer_events[i] {
+				LayerEvent::Clear(tag) => self.remove_internal_tag(tag),
+				LayerEvent::Set(tag) => self.add_internal_tag(tag),
+				LayerEvent::Toggle(tag) => self.toggle_internal_tag(tag),
+				LayerEvent::SetFor { tag, duration_ms } => {
+					self.add_internal_tag_for(tag, (*duration_ms as u64).millis())
+				}
+			}
+		}
+		if !self.layer_events.is_empty() {
+			self.tags_changed = true;
+			self.layer_events.clear();
+		}
 	}
 
 	pub fn add_internal_tag(&mut self, tag: &'a LayerTag) {
@@ -126,15 +490,93 @@ impl<'a> KeyboardState<'a> {
 		self.update_layers();
 	}
 
+	/// Sets an internal tag that clears itself after `duration`. Setting it again before then
+	/// restarts the timer rather than setting it twice.
+	pub fn add_internal_tag_for(&mut self, tag: &'a LayerTag, duration: Duration) {
+		match self.tag_deadlines.iter_mut().find(|(t, _)| *t == tag) {
+			Some((_, remaining)) => *remaining = duration,
+			None => {
+				self.tag_deadlines.push((tag, duration));
+				self.add_internal_tag(tag);
+			}
+		}
+	}
+
+	// clearing or toggling a timed tag by hand cancels its timer
 	pub fn remove_internal_tag(&mut self, tag: &'a LayerTag) {
+		self.tag_deadlines.retain(|(t, _)| *t != tag);
 		self.tags.remove_internal(tag);
 		self.update_layers();
 	}
 
+	pub fn toggle_internal_tag(&mut self, tag: &'a LayerTag) {
+		self.tag_deadlines.retain(|(t, _)| *t != tag);
+		self.tags.toggle_internal(tag);
+		self.update_layers();
+	}
+
+	/// Returns true if a macro's layer event or a timed tag clearing itself changed the
+	/// internal tags since the last call.
+	pub fn take_tags_changed(&mut self) -> bool {
+		core::mem::take(&mut self.tags_changed)
+	}
+
+	fn tick_tag_deadlines(&mut self, elapsed: Duration) {
+		let mut expired = false;
+		for (_, remaining) in self.tag_deadlines.iter_mut() {
+			*remaining = remaining.checked_sub(elapsed).unwrap_or(0.millis());
+			expired |= remaining.is_zero();
+		}
+		if !expired {
+			return;
+		}
+
+		for &(tag, _) in self.tag_deadlines.iter().filter(|(_, r)| r.is_zero()) {
+			self.tags.remove_internal(tag);
+		}
+		self.tag_deadlines
+			.retain(|(_, remaining)| !remaining.is_zero());
+		self.tags_changed = true;
+		self.update_layers();
+	}
+
+	pub fn active_layers(&self) -> LayerMask {
+		self.active_layers
+	}
+
+	pub fn active_tags(&self) -> ActiveTags {
+		ActiveTags {
+			internal: self
+				.tags
+				.internal
+				.iter()
+				.map(|tag| (*tag).clone())
+				.collect(),
+			external: self.tags.external.clone(),
+			layers: self.active_layers,
+		}
+	}
+
+	pub fn key_stats(&self) -> &KeyStats {
+		&self.key_stats
+	}
+
+	pub fn take_key_stats(&mut self) -> KeyStats {
+		core::mem::take(&mut self.key_stats)
+	}
+
+	pub fn set_key_stats(&mut self, stats: KeyStats) {
+		self.key_stats = stats;
+	}
+
 	pub fn get_external_tags(&self) -> &[LayerTag] {
 		&self.tags.external
 	}
 
+	pub fn has_tag(&self, name: &str) -> bool {
+		self.tags.contains_name(name)
+	}
+
 	pub fn to_external_tags(self) -> Vec<LayerTag> {
 		self.tags.external
 	}
@@ -145,6 +587,13 @@ impl<'a> KeyboardState<'a> {
 	}
 
 	fn update_layers(&mut self) {
+		self.active_layers = self
+			.layers
+			.iter()
+			.enumerate()
+			.filter(|(_, layer)| layer.is_match(&self.tags))
+			.fold(0, |mask, (i, _)| mask | (1 << i));
+
 		for ks in self
 			.keys
 			.iter_mut()
@@ -154,9 +603,13 @@ impl<'a> KeyboardState<'a> {
 					.iter_mut()
 					.map(|vk| vk as &mut dyn KeyState),
 			) {
-			let new_layer = ks.update_current_layer(&self.tags);
+			let new_layer = ks.update_current_layer(self.active_layers);
 
 			if let Some(new_layer) = new_layer {
+				self.backlight_changed |= ks.layers().has_backlight();
+				// a pending hold or double press was for the old layer's macros
+				*ks.armed() = None;
+
 				// release macros that no longer have a valid source
 				for macro_ in self
 					.running
@@ -169,6 +622,23 @@ impl<'a> KeyboardState<'a> {
 		}
 	}
 
+	// checked every tick rather than paused and resumed, so a lower priority macro started
+	// while the channel is taken waits its turn too
+	fn is_preempted(running: &[MacroState<'a>], index: usize) -> bool {
+		let macro_ = running[index].macro_;
+		let Some(channel) = macro_.play_channel else {
+			return false;
+		};
+
+		running.iter().enumerate().any(|(i, other)| {
+			i != index
+				&& !other.is_finished()
+				&& other.macro_.channel_policy == ChannelPolicy::Preempt
+				&& other.macro_.play_channel == Some(channel)
+				&& other.macro_.priority > macro_.priority
+		})
+	}
+
 	fn cut_channels(running: IterMut<MacroState<'a>>, channels: &[Channel]) {
 		for macro_ in running.filter(|m| match m.macro_.play_channel {
 			Some(channel) => channels.contains(&channel),
@@ -179,9 +649,16 @@ impl<'a> KeyboardState<'a> {
 	}
 }
 
+#[derive(Clone, Copy, PartialEq)]
+enum KeyIndex {
+	Physical(usize),
+	Virtual(usize),
+}
+
 struct PhysicalKeyState<'a> {
 	key: &'a DeviceKey,
 	current_layer: &'a DeviceKeyLayer,
+	armed: Option<Duration>,
 }
 
 impl<'a> PhysicalKeyState<'a> {
@@ -189,6 +666,7 @@ impl<'a> PhysicalKeyState<'a> {
 		Self {
 			key,
 			current_layer: &key.layers.default_layer,
+			armed: None,
 		}
 	}
 }
@@ -198,6 +676,7 @@ struct VirtualKeyState<'a> {
 	id: usize,
 	key: &'a VirtualKey,
 	current_layer: &'a DeviceKeyLayer,
+	armed: Option<Duration>,
 }
 
 impl<'a> VirtualKeyState<'a> {
@@ -207,6 +686,7 @@ impl<'a> VirtualKeyState<'a> {
 			id,
 			key,
 			current_layer: &key.layers.default_layer,
+			armed: None,
 		}
 	}
 
@@ -223,7 +703,9 @@ trait KeyState<'a> {
 	fn key(&self) -> MacroSourceKey;
 	fn layers(&self) -> &'a DeviceLayers;
 	fn current_layer(&self) -> &'a DeviceKeyLayer;
-	fn update_current_layer(&mut self, tags: &TagList) -> Option<&'a DeviceKeyLayer>;
+	fn update_current_layer(&mut self, active_layers: LayerMask) -> Option<&'a DeviceKeyLayer>;
+	/// Time since a hold or double press trigger was armed.
+	fn armed(&mut self) -> &mut Option<Duration>;
 }
 
 impl<'a> KeyState<'a> for PhysicalKeyState<'a> {
@@ -239,8 +721,8 @@ impl<'a> KeyState<'a> for PhysicalKeyState<'a> {
 		self.current_layer
 	}
 
-	fn update_current_layer(&mut self, tags: &TagList) -> Option<&'a DeviceKeyLayer> {
-		let new_layer = self.key.layers.get_active_layer(tags);
+	fn update_current_layer(&mut self, active_layers: LayerMask) -> Option<&'a DeviceKeyLayer> {
+		let new_layer = self.key.layers.get_active_layer(active_layers);
 
 		if new_layer.id != self.current_layer.id {
 			self.current_layer = new_layer;
@@ -249,6 +731,10 @@ impl<'a> KeyState<'a> for PhysicalKeyState<'a> {
 			None
 		}
 	}
+
+	fn armed(&mut self) -> &mut Option<Duration> {
+		&mut self.armed
+	}
 }
 
 impl<'a> KeyState<'a> for VirtualKeyState<'a> {
@@ -264,8 +750,8 @@ impl<'a> KeyState<'a> for VirtualKeyState<'a> {
 		self.current_layer
 	}
 
-	fn update_current_layer(&mut self, tags: &TagList) -> Option<&'a DeviceKeyLayer> {
-		let new_layer = self.key.layers.get_active_layer(tags);
+	fn update_current_layer(&mut self, active_layers: LayerMask) -> Option<&'a DeviceKeyLayer> {
+		let new_layer = self.key.layers.get_active_layer(active_layers);
 
 		if new_layer.id != self.current_layer.id {
 			self.current_layer = new_layer;
@@ -274,17 +760,84 @@ impl<'a> KeyState<'a> for VirtualKeyState<'a> {
 			None
 		}
 	}
+
+	fn armed(&mut self) -> &mut Option<Duration> {
+		&mut self.armed
+	}
+}
+
+/// A set of keyboard keys, one bit per key code.
+#[derive(Clone, Copy, Default)]
+struct HeldKeys([u8; 32]);
+
+impl HeldKeys {
+	/// Follows the keys a macro presses and releases.
+	fn track(&mut self, event: &ActionEvent) {
+		match event {
+			ActionEvent::Keyboard(KeyboardEvent::KeyDown(key)) => {
+				self.0[*key as usize / 8] |= 1 << (*key as usize % 8)
+			}
+			ActionEvent::Keyboard(KeyboardEvent::KeyUp(key)) => {
+				self.0[*key as usize / 8] &= !(1 << (*key as usize % 8))
+			}
+			_ => {}
+		}
+	}
+
+	fn add(&mut self, other: &HeldKeys) {
+		for (byte, other) in self.0.iter_mut().zip(other.0) {
+			*byte |= other;
+		}
+	}
+
+	fn remove(&mut self, other: &HeldKeys) {
+		for (byte, other) in self.0.iter_mut().zip(other.0) {
+			*byte &= !other;
+		}
+	}
+
+	fn for_each(&self, f: impl FnMut(KeyboardKey)) {
+		(0..=u8::MAX)
+			.filter(|code| self.0[*code as usize / 8] & (1 << (code % 8)) != 0)
+			.filter_map(|code| KeyboardKey::try_from(code).ok())
+			.for_each(f);
+	}
 }
 
+/// How deep `RunMacro` calls can nest, so macros that call themselves can't recurse forever.
+const MAX_CALL_DEPTH: u8 = 4;
+
 struct MacroState<'a> {
 	macro_: &'a Macro,
 	current_sequence: CurrentSequence<'a>,
 	trigger: TriggerState,
 	source: MacroSource,
+	loops_played: u16,
+	/// Profile macros that `RunMacro` actions index into.
+	callable: &'a [Macro],
+	call: Option<Box<MacroState<'a>>>,
+	depth: u8,
+	/// How long to play on the first tick, when the key that started it was captured partway
+	/// through; the whole tick otherwise.
+	first_tick: Option<Duration>,
+	/// Keyboard keys pressed and not yet released by this macro and the macros it calls. Only
+	/// kept for running macros, not for calls.
+	held: HeldKeys,
 }
 
 impl<'a> MacroState<'a> {
-	pub fn from<K: KeyState<'a>>(macro_: &'a Macro, source: &K) -> Self {
+	pub fn from<K: KeyState<'a> + ?Sized>(macro_: &'a Macro, source: &K) -> Self {
+		Self::new(
+			macro_,
+			MacroSource {
+				key: source.key(),
+				layer: source.current_layer().id,
+			},
+			0,
+		)
+	}
+
+	fn new(macro_: &'a Macro, source: MacroSource, depth: u8) -> Self {
 		MacroState {
 			macro_,
 			current_sequence: CurrentSequence::Start(SequenceState::from(
@@ -292,24 +845,54 @@ impl<'a> MacroState<'a> {
 				0.millis(),
 			)),
 			trigger: TriggerState::Running,
-			source: MacroSource {
-				key: source.key(),
-				layer: source.current_layer().id,
-			},
+			source,
+			loops_played: 0,
+			callable: &[],
+			call: None,
+			depth,
+			first_tick: None,
+			held: HeldKeys::default(),
 		}
 	}
 
+	fn with_calls(mut self, callable: &'a [Macro]) -> Self {
+		self.callable = callable;
+		self
+	}
+
+	/// Heap held by the chain of macros this one is calling.
+	fn heap_size(&self) -> usize {
+		self.call.as_ref().map_or(0, |call| {
+			core::mem::size_of::<MacroState>() + call.heap_size()
+		})
+	}
+
 	pub fn tick(
 		&mut self,
 		mut elapsed: Duration,
+		rng: &mut Rng,
 		on_event: &mut impl FnMut(&'a ActionEvent),
 	) -> Duration {
 		while !self.is_finished() && !elapsed.is_zero() {
+			if let Some(call) = self.call.as_mut() {
+				elapsed = call.tick(elapsed, rng, on_event);
+				if !call.is_finished() {
+					break;
+				}
+				self.call = None;
+				continue;
+			}
+
 			if let CurrentSequence::Start(ref mut seq)
 			| CurrentSequence::Loop(ref mut seq)
 			| CurrentSequence::End(ref mut seq) = self.current_sequence
 			{
-				elapsed = seq.tick(elapsed, on_event);
+				elapsed = seq.tick(elapsed, rng, on_event);
+
+				if let Some(index) = seq.take_call() {
+					self.start_call(index);
+					continue;
+				}
 
 				if seq.is_finished() {
 					self.move_to_next_seq(elapsed);
@@ -332,18 +915,45 @@ impl<'a> MacroState<'a> {
 
 	fn stop(&mut self) {
 		self.trigger = TriggerState::Stopping;
+		if let Some(call) = self.call.as_mut() {
+			call.stop();
+		}
+	}
+
+	fn start_call(&mut self, index: MacroIndex) {
+		if self.depth >= MAX_CALL_DEPTH {
+			warn!("Macro calls nested too deep, skipping {:?}", index);
+			return;
+		}
+		let Some(macro_) = self.callable.get(index.get_index()) else {
+			warn!(
+				"Called macro index {:?} not found in profile macros.",
+				index
+			);
+			return;
+		};
+
+		let mut call =
+			MacroState::new(macro_, self.source, self.depth + 1).with_calls(self.callable);
+		call.trigger = self.trigger;
+		self.call = Some(Box::new(call));
 	}
 
 	fn move_to_next_seq(&mut self, elapsed: Duration) {
 		match self.current_sequence {
 			CurrentSequence::Start(_) => match self.trigger {
-				TriggerState::Running => self.move_to_loop(elapsed),
-				TriggerState::Stopping => self.move_to_end(elapsed),
-			},
-			CurrentSequence::Loop(_) => match self.trigger {
-				TriggerState::Running => self.move_to_loop(elapsed),
-				TriggerState::Stopping => self.move_to_end(elapsed),
+				TriggerState::Running if !self.loop_limit_reached() => self.move_to_loop(elapsed),
+				_ => self.move_to_end(elapsed),
 			},
+			CurrentSequence::Loop(_) => {
+				self.loops_played = self.loops_played.saturating_add(1);
+				match self.trigger {
+					TriggerState::Running if !self.loop_limit_reached() => {
+						self.move_to_loop(elapsed)
+					}
+					_ => self.move_to_end(elapsed),
+				}
+			}
 			CurrentSequence::End(_) => {
 				self.current_sequence = CurrentSequence::Finished;
 			}
@@ -351,6 +961,12 @@ impl<'a> MacroState<'a> {
 		}
 	}
 
+	fn loop_limit_reached(&self) -> bool {
+		self.macro_
+			.loop_limit
+			.is_some_and(|limit| self.loops_played >= limit)
+	}
+
 	fn move_to_loop(&mut self, elapsed: Duration) {
 		self.current_sequence =
 			CurrentSequence::Loop(SequenceState::from(&self.macro_.loop_sequence, elapsed));
@@ -362,6 +978,7 @@ impl<'a> MacroState<'a> {
 	}
 }
 
+#[derive(Clone, Copy)]
 struct MacroSource {
 	key: MacroSourceKey,
 	layer: LayerId,
@@ -374,33 +991,64 @@ enum MacroSourceKey {
 }
 
 struct SequenceState<'a> {
-	pending: Vec<&'a Action>,
+	pending: &'a [Action],
 	elapsed: Duration,
+	/// Delay picked for the next pending action, for actions with a delay range.
+	predelay_ms: Option<u64>,
+	typing: Option<TextTyping<'a>>,
+	call: Option<MacroIndex>,
 }
 
 impl<'a> SequenceState<'a> {
 	fn from(sequence: &'a Sequence, elapsed: Duration) -> Self {
 		SequenceState {
-			pending: sequence.actions.iter().rev().collect(),
+			pending: &sequence.actions,
 			elapsed,
+			predelay_ms: None,
+			typing: None,
+			call: None,
 		}
 	}
 
 	pub fn tick(
 		&mut self,
 		elapsed: Duration,
+		rng: &mut Rng,
 		on_event: &mut impl FnMut(&'a ActionEvent),
 	) -> Duration {
 		self.elapsed += elapsed;
 
-		while let Some(action) = self.pending.pop() {
-			if action.predelay_ms <= self.elapsed.to_millis() {
-				on_event(&action.action_event);
-				self.elapsed -= action.predelay_ms.millis();
+		while let Some((action, rest)) = self.pending.split_first() {
+			if self.typing.is_none() {
+				let predelay_ms = *self
+					.predelay_ms
+					.get_or_insert_with(|| action.sample_predelay_ms(rng));
+				if predelay_ms > self.elapsed.to_millis() {
+					return 0.millis();
+				}
+				self.elapsed -= predelay_ms.millis();
+				self.predelay_ms = None;
+			}
+
+			if let ActionEvent::Text(text) = &action.action_event {
+				let typing = self.typing.get_or_insert_with(|| TextTyping::new(text));
+				if typing.step(on_event) {
+					// typing takes the whole tick, and the next action's delay starts after it
+					self.elapsed = 0.millis();
+					return 0.millis();
+				}
+				self.typing = None;
+			} else if let ActionEvent::RunMacro(index) = &action.action_event {
+				// hand the rest of the tick to the macro state, which plays the call before this
+				// sequence carries on
+				self.call = Some(*index);
+				self.pending = rest;
+				return core::mem::replace(&mut self.elapsed, 0.millis());
 			} else {
-				self.pending.push(action);
-				return 0.millis();
+				on_event(&action.action_event);
 			}
+
+			self.pending = rest;
 		}
 
 		self.elapsed
@@ -409,6 +1057,10 @@ impl<'a> SequenceState<'a> {
 	pub fn is_finished(&self) -> bool {
 		self.pending.is_empty()
 	}
+
+	fn take_call(&mut self) -> Option<MacroIndex> {
+		self.call.take()
+	}
 }
 
 enum CurrentSequence<'a> {
@@ -429,15 +1081,143 @@ impl<'a> fmt::Debug for CurrentSequence<'a> {
 	}
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum TriggerState {
 	Running,
 	Stopping,
 }
 
+/// Owned copy of the tags active at one point in time, for handing to other tasks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ActiveTags {
+	pub internal: Vec<LayerTag>,
+	pub external: Vec<LayerTag>,
+	/// Profile layers those tags activate.
+	pub layers: LayerMask,
+}
+
+impl Writeable for ActiveTags {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_collection_u8(&self.internal).await?;
+		writer.write_collection_u8(&self.external).await?;
+		writer.write_u64(self.layers).await
+	}
+}
+
+/// Runtime details only the keypad task knows, snapshotted for the status command.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeypadStatus {
+	pub profile_name: String,
+	/// Physical keys down; keys the host injected aren't counted.
+	pub held_keys: u16,
+	pub timing: LoopTiming,
+}
+
+impl Writeable for KeypadStatus {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_string_u8(&self.profile_name).await?;
+		writer.write_u16(self.held_keys).await?;
+		self.timing.write_to(writer).await
+	}
+}
+
+/// Lifetime press count for one key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyPresses {
+	pub key: KeyId,
+	pub count: u32,
+}
+
+impl Readable for KeyPresses {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let key = KeyId::read_from(reader).await?;
+		let count = reader
+			.read_u32()
+			.await
+			.ok_or("Failed to read key presses")?;
+		Ok(Self { key, count })
+	}
+}
+
+impl Writeable for KeyPresses {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		self.key.write_to(writer).await?;
+		writer.write_u32(self.count).await
+	}
+}
+
+/// Press counts for every key seen, kept across profile changes and saved to flash so they
+/// survive reboots.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyStats {
+	/// Sorted by key.
+	keys: Vec<KeyPresses>,
+}
+
+impl KeyStats {
+	/// Injected keys can have any ID, so new keys stop being counted past this.
+	pub const MAX_KEYS: usize = 256;
+
+	pub fn count_press(&mut self, key: KeyId) {
+		match self.keys.binary_search_by_key(&key, |k| k.key) {
+			Ok(i) => self.keys[i].count = self.keys[i].count.saturating_add(1),
+			Err(i) if self.keys.len() < Self::MAX_KEYS => {
+				self.keys.insert(i, KeyPresses { key, count: 1 })
+			}
+			Err(_) => {}
+		}
+	}
+
+	pub fn presses(&self, key: KeyId) -> u32 {
+		match self.keys.binary_search_by_key(&key, |k| k.key) {
+			Ok(i) => self.keys[i].count,
+			Err(_) => 0,
+		}
+	}
+
+	pub fn keys(&self) -> &[KeyPresses] {
+		&self.keys
+	}
+
+	/// Presses of every counted key together.
+	pub fn total(&self) -> u32 {
+		self.keys
+			.iter()
+			.fold(0u32, |total, key| total.saturating_add(key.count))
+	}
+}
+
+impl Readable for KeyStats {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let mut keys: Vec<KeyPresses> = reader
+			.read_collection_u16()
+			.await
+			.ok_or("Failed to read key stats")?;
+		if keys.len() > Self::MAX_KEYS {
+			return Err("Too many keys in key stats");
+		}
+		keys.sort_by_key(|k| k.key);
+		Ok(Self { keys })
+	}
+}
+
+impl Writeable for KeyStats {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_collection_u16(&self.keys).await
+	}
+}
+
 pub struct TagList<'a> {
 	pub(crate) internal: Vec<&'a LayerTag>,
 	pub(crate) external: Vec<LayerTag>,
+	tag_ids: Option<&'a TagTable>,
+	active: TagMask,
 }
 
 impl<'a> TagList<'a> {
@@ -445,34 +1225,87 @@ impl<'a> TagList<'a> {
 		TagList {
 			internal: Vec::new(),
 			external: Vec::new(),
+			tag_ids: None,
+			active: 0,
+		}
+	}
+
+	/// Tracks the active tags as a bitmask too, so layers interned against `tag_ids` can be
+	/// matched without comparing strings.
+	pub fn with_tag_ids(tag_ids: &'a TagTable) -> Self {
+		TagList {
+			tag_ids: Some(tag_ids),
+			..Self::new()
 		}
 	}
 
 	pub fn add_internal(&mut self, tag: &'a LayerTag) {
 		self.internal.push(tag);
+		self.update_active();
 	}
 
 	pub fn remove_internal(&mut self, tag: &'a LayerTag) {
 		if let Some(index) = self.internal.iter().position(|t| *t == tag) {
 			self.internal.remove(index);
 		}
+		self.update_active();
+	}
+
+	/// Sets the tag if it isn't set internally, otherwise clears it however many times it was set.
+	pub fn toggle_internal(&mut self, tag: &'a LayerTag) {
+		if self.internal.contains(&tag) {
+			self.internal.retain(|t| *t != tag);
+		} else {
+			self.internal.push(tag);
+		}
+		self.update_active();
 	}
 
 	pub fn clear_internal(&mut self) {
 		self.internal.clear();
+		self.update_active();
 	}
 
 	pub fn set_external(&mut self, tags: Vec<LayerTag>) {
 		self.external = tags;
+		self.update_active();
 	}
 
-	pub fn matches(&self, tags: &[LayerTag], match_type: &TagMatchType) -> bool {
-		match match_type {
-			TagMatchType::All => tags.iter().all(|t| self.contains(t)),
+	fn update_active(&mut self) {
+		if let Some(tag_ids) = self.tag_ids {
+			self.active =
+				tag_ids.mask_of(self.internal.iter().copied().chain(self.external.iter()));
+		}
+	}
+
+	pub fn has_tag_ids(&self) -> bool {
+		self.tag_ids.is_some()
+	}
+
+	pub fn matches(&self, tags: &[LayerTag], match_type: &TagMatchType) -> bool {
+		match match_type {
+			TagMatchType::All => tags.iter().all(|t| self.contains(t)),
 			TagMatchType::Any => tags.iter().any(|t| self.contains(t)),
 		}
 	}
 
+	/// Same as `matches`, for a layer whose tags were interned into `mask`.
+	pub fn matches_mask(&self, mask: TagMask, match_type: &TagMatchType) -> bool {
+		match match_type {
+			TagMatchType::All => self.active & mask == mask,
+			TagMatchType::Any => self.active & mask != 0,
+		}
+	}
+
+	/// Returns true if a tag with this name is set, internally or externally.
+	pub fn contains_name(&self, name: &str) -> bool {
+		self.internal
+			.iter()
+			.copied()
+			.chain(self.external.iter())
+			.any(|tag| tag.as_str() == name)
+	}
+
 	fn contains(&self, value: &LayerTag) -> bool {
 		self.internal
 			.iter()
@@ -495,6 +1328,7 @@ pub(crate) fn to_bitset_index(vk_index: usize, total_bits: usize) -> Option<usiz
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::testing::*;
 	use crate::time::Duration;
 	use alloc::string::ToString;
 	use alloc::vec;
@@ -509,10 +1343,8 @@ mod tests {
 		MacroId::new(Uuid::from_u128_le(0x1326a82d_af4c_5e64_8619_ed6686415550));
 	static CHANNEL_ID: Channel = Channel::new(3);
 	static CHANNEL_ID2: Channel = Channel::new(4);
-	static LAYER_ID: LayerId =
-		LayerId::new(Uuid::from_u128_le(0x6e30c4c9_8e84_5e71_a303_6fc00ca31d68));
-	static LAYER_ID2: LayerId =
-		LayerId::new(Uuid::from_u128_le(0x2cb2145a_6fd1_59e3_8b2e_bd8160f9924c));
+	static LAYER_ID: LayerId = DEFAULT_LAYER_ID;
+	static LAYER_ID2: LayerId = TAGGED_LAYER_ID;
 
 	// ------- SEQUENCE TESTS --------
 
@@ -521,6 +1353,7 @@ mod tests {
 		let sequence = Sequence {
 			actions: vec![Action {
 				predelay_ms: 1000,
+				predelay_max_ms: None,
 				action_event: ActionEvent::None,
 			}],
 		};
@@ -528,13 +1361,13 @@ mod tests {
 		let mut state = SequenceState::from(&sequence, 0.millis());
 		assert_eq!(state.elapsed, 0.millis() as Duration);
 
-		state.tick(50.millis() as Duration, &mut |_| {});
+		state.tick(50.millis() as Duration, &mut Rng::default(), &mut |_| {});
 		assert_eq!(state.elapsed, 50.millis() as Duration);
 
-		state.tick(100.millis() as Duration, &mut |_| {});
+		state.tick(100.millis() as Duration, &mut Rng::default(), &mut |_| {});
 		assert_eq!(state.elapsed, 150.millis() as Duration);
 
-		state.tick(200.millis() as Duration, &mut |_| {});
+		state.tick(200.millis() as Duration, &mut Rng::default(), &mut |_| {});
 		assert_eq!(state.elapsed, 350.millis() as Duration);
 	}
 
@@ -543,6 +1376,7 @@ mod tests {
 		let sequence = Sequence {
 			actions: vec![Action {
 				predelay_ms: 1000,
+				predelay_max_ms: None,
 				action_event: ActionEvent::None,
 			}],
 		};
@@ -550,16 +1384,16 @@ mod tests {
 		let mut state = SequenceState::from(&sequence, 0.millis());
 		assert_eq!(state.pending.len(), 1);
 
-		state.tick(100.millis(), &mut |_| {});
+		state.tick(100.millis(), &mut Rng::default(), &mut |_| {});
 		assert_eq!(state.pending.len(), 1);
 
-		state.tick(100.millis(), &mut |_| {});
+		state.tick(100.millis(), &mut Rng::default(), &mut |_| {});
 		assert_eq!(state.pending.len(), 1);
 
-		state.tick(200.millis(), &mut |_| {});
+		state.tick(200.millis(), &mut Rng::default(), &mut |_| {});
 		assert_eq!(state.pending.len(), 1);
 
-		state.tick(599.millis(), &mut |_| {});
+		state.tick(599.millis(), &mut Rng::default(), &mut |_| {});
 		assert_eq!(state.pending.len(), 1);
 	}
 
@@ -569,10 +1403,12 @@ mod tests {
 			actions: vec![
 				Action {
 					predelay_ms: 100,
+					predelay_max_ms: None,
 					action_event: ActionEvent::None,
 				},
 				Action {
 					predelay_ms: 200,
+					predelay_max_ms: None,
 					action_event: ActionEvent::None,
 				},
 			],
@@ -581,10 +1417,10 @@ mod tests {
 		let mut state = SequenceState::from(&sequence, 0.millis());
 		assert_eq!(state.pending.len(), 2);
 
-		state.tick(99.millis(), &mut |_| {});
+		state.tick(99.millis(), &mut Rng::default(), &mut |_| {});
 		assert_eq!(state.pending.len(), 2);
 
-		state.tick(1.millis(), &mut |_| {});
+		state.tick(1.millis(), &mut Rng::default(), &mut |_| {});
 		assert_eq!(state.pending.len(), 1);
 	}
 
@@ -594,10 +1430,12 @@ mod tests {
 			actions: vec![
 				Action {
 					predelay_ms: 100,
+					predelay_max_ms: None,
 					action_event: ActionEvent::None,
 				},
 				Action {
 					predelay_ms: 200,
+					predelay_max_ms: None,
 					action_event: ActionEvent::None,
 				},
 			],
@@ -606,10 +1444,10 @@ mod tests {
 		let mut state = SequenceState::from(&sequence, 0.millis());
 		assert_eq!(state.is_finished(), false);
 
-		state.tick(299.millis(), &mut |_| {});
+		state.tick(299.millis(), &mut Rng::default(), &mut |_| {});
 		assert_eq!(state.is_finished(), false);
 
-		state.tick(1.millis(), &mut |_| {});
+		state.tick(1.millis(), &mut Rng::default(), &mut |_| {});
 		assert_eq!(state.is_finished(), true);
 	}
 
@@ -618,6 +1456,7 @@ mod tests {
 		let sequence = Sequence {
 			actions: vec![Action {
 				predelay_ms: 0,
+				predelay_max_ms: None,
 				action_event: ActionEvent::None,
 			}],
 		};
@@ -625,7 +1464,7 @@ mod tests {
 		let mut state = SequenceState::from(&sequence, 0.millis());
 		assert_eq!(state.pending.len(), 1);
 
-		state.tick(0.millis(), &mut |_| {});
+		state.tick(0.millis(), &mut Rng::default(), &mut |_| {});
 		assert_eq!(state.pending.len(), 0);
 	}
 
@@ -635,14 +1474,17 @@ mod tests {
 			actions: vec![
 				Action {
 					predelay_ms: 100,
+					predelay_max_ms: None,
 					action_event: ActionEvent::None,
 				},
 				Action {
 					predelay_ms: 200,
+					predelay_max_ms: None,
 					action_event: ActionEvent::None,
 				},
 				Action {
 					predelay_ms: 100,
+					predelay_max_ms: None,
 					action_event: ActionEvent::None,
 				},
 			],
@@ -651,7 +1493,7 @@ mod tests {
 		let mut state = SequenceState::from(&sequence, 0.millis());
 		assert_eq!(state.pending.len(), 3);
 
-		state.tick(400.millis(), &mut |_| {});
+		state.tick(400.millis(), &mut Rng::default(), &mut |_| {});
 		assert_eq!(state.pending.len(), 0);
 	}
 
@@ -661,14 +1503,17 @@ mod tests {
 			actions: vec![
 				Action {
 					predelay_ms: 100,
+					predelay_max_ms: None,
 					action_event: ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A)),
 				},
 				Action {
 					predelay_ms: 200,
+					predelay_max_ms: None,
 					action_event: ActionEvent::Mouse(MouseEvent::Move(MouseMove { x: 0, y: 0 })),
 				},
 				Action {
 					predelay_ms: 100,
+					predelay_max_ms: None,
 					action_event: ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A)),
 				},
 			],
@@ -677,7 +1522,7 @@ mod tests {
 		let mut state = SequenceState::from(&sequence, 0.millis());
 		let mut events = vec![];
 
-		state.tick(400.millis(), &mut |e| events.push(e));
+		state.tick(400.millis(), &mut Rng::default(), &mut |e| events.push(e));
 		assert_eq!(events.len(), 3);
 
 		assert!(matches!(
@@ -694,6 +1539,67 @@ mod tests {
 		));
 	}
 
+	#[test]
+	fn sequence_picks_delay_from_range() {
+		let sequence = Sequence {
+			actions: vec![Action {
+				predelay_ms: 100,
+				predelay_max_ms: Some(200),
+				action_event: ActionEvent::None,
+			}],
+		};
+
+		let mut rng = Rng::default();
+		let mut state = SequenceState::from(&sequence, 0.millis());
+
+		state.tick(99.millis(), &mut rng, &mut |_| {});
+		assert!(!state.is_finished());
+
+		// the delay is picked once, not again each tick
+		for _ in 0..101 {
+			state.tick(1.millis(), &mut rng, &mut |_| {});
+		}
+		assert!(state.is_finished());
+	}
+
+	#[test]
+	fn sequence_types_text_over_several_ticks() {
+		let sequence = Sequence {
+			actions: vec![
+				Action {
+					predelay_ms: 0,
+					predelay_max_ms: None,
+					action_event: ActionEvent::Text("hi".to_string()),
+				},
+				Action {
+					predelay_ms: 10,
+					predelay_max_ms: None,
+					action_event: ActionEvent::None,
+				},
+			],
+		};
+
+		let mut state = SequenceState::from(&sequence, 0.millis());
+		let mut events = vec![];
+
+		// h down, h up, i down, i up
+		for _ in 0..4 {
+			state.tick(1.millis(), &mut Rng::default(), &mut |e| events.push(e));
+		}
+		assert_eq!(events.len(), 4);
+		assert!(matches!(
+			events[2],
+			ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::I))
+		));
+		assert!(!state.is_finished());
+
+		// the next action's delay starts once typing is done
+		state.tick(9.millis(), &mut Rng::default(), &mut |e| events.push(e));
+		assert!(!state.is_finished());
+		state.tick(1.millis(), &mut Rng::default(), &mut |e| events.push(e));
+		assert!(state.is_finished());
+	}
+
 	// ------- MACRO TESTS --------
 	#[test]
 	fn macro_moves_to_loop_sequence() {
@@ -707,7 +1613,7 @@ mod tests {
 			CurrentSequence::Start(_)
 		));
 
-		macro_state.tick(100.millis(), &mut |_| {});
+		macro_state.tick(100.millis(), &mut Rng::default(), &mut |_| {});
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::Loop(_)
@@ -722,13 +1628,13 @@ mod tests {
 		let key_state = PhysicalKeyState::from(&device_key);
 		let mut macro_state = MacroState::from(&_macro, &key_state);
 
-		macro_state.tick(100.millis(), &mut |_| {});
+		macro_state.tick(100.millis(), &mut Rng::default(), &mut |_| {});
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::Loop(_)
 		));
 
-		macro_state.tick(200.millis(), &mut |_| {});
+		macro_state.tick(200.millis(), &mut Rng::default(), &mut |_| {});
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::Loop(_)
@@ -741,6 +1647,7 @@ mod tests {
 			start_sequence: Sequence {
 				actions: vec![Action {
 					predelay_ms: 100,
+					predelay_max_ms: None,
 					action_event: ActionEvent::None,
 				}],
 			},
@@ -748,6 +1655,7 @@ mod tests {
 			end_sequence: Sequence {
 				actions: vec![Action {
 					predelay_ms: 300,
+					predelay_max_ms: None,
 					action_event: ActionEvent::None,
 				}],
 			},
@@ -755,23 +1663,66 @@ mod tests {
 			id: MACRO_ID,
 			name: "Name".to_string(),
 			play_channel: Some(CHANNEL_ID),
+			loop_limit: None,
+			speed_percent: 100,
+			priority: 0,
+			channel_policy: ChannelPolicy::Share,
 		};
 		let device_key = new_test_device_key(KEY_ID, vec![MacroIndex::new(0)]);
 
 		let key_state = PhysicalKeyState::from(&device_key);
 		let mut macro_state = MacroState::from(&_macro, &key_state);
 
-		macro_state.tick(100.millis(), &mut |_| {});
+		macro_state.tick(100.millis(), &mut Rng::default(), &mut |_| {});
+		assert!(matches!(
+			macro_state.current_sequence,
+			CurrentSequence::Loop(_)
+		));
+
+		macro_state.tick(300.millis(), &mut Rng::default(), &mut |_| {});
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::Loop(_)
 		));
+	}
+
+	#[test]
+	fn macro_ends_after_loop_limit() {
+		let mut _macro = new_test_macro(MACRO_ID, Some(CHANNEL_ID), vec![CHANNEL_ID]);
+		_macro.loop_limit = Some(2);
+		let device_key = new_test_device_key(KEY_ID, vec![MacroIndex::new(0)]);
 
-		macro_state.tick(300.millis(), &mut |_| {});
+		let key_state = PhysicalKeyState::from(&device_key);
+		let mut macro_state = MacroState::from(&_macro, &key_state);
+
+		macro_state.tick(100.millis(), &mut Rng::default(), &mut |_| {});
+		macro_state.tick(200.millis(), &mut Rng::default(), &mut |_| {});
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::Loop(_)
 		));
+
+		macro_state.tick(200.millis(), &mut Rng::default(), &mut |_| {});
+		assert!(matches!(
+			macro_state.current_sequence,
+			CurrentSequence::End(_)
+		));
+	}
+
+	#[test]
+	fn macro_with_zero_loop_limit_skips_loop() {
+		let mut _macro = new_test_macro(MACRO_ID, Some(CHANNEL_ID), vec![CHANNEL_ID]);
+		_macro.loop_limit = Some(0);
+		let device_key = new_test_device_key(KEY_ID, vec![MacroIndex::new(0)]);
+
+		let key_state = PhysicalKeyState::from(&device_key);
+		let mut macro_state = MacroState::from(&_macro, &key_state);
+
+		macro_state.tick(100.millis(), &mut Rng::default(), &mut |_| {});
+		assert!(matches!(
+			macro_state.current_sequence,
+			CurrentSequence::End(_)
+		));
 	}
 
 	#[test]
@@ -782,7 +1733,7 @@ mod tests {
 		let key_state = PhysicalKeyState::from(&device_key);
 		let mut macro_state = MacroState::from(&_macro, &key_state);
 
-		macro_state.tick(100.millis(), &mut |_| {});
+		macro_state.tick(100.millis(), &mut Rng::default(), &mut |_| {});
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::Loop(_)
@@ -790,7 +1741,7 @@ mod tests {
 
 		macro_state.stop();
 
-		macro_state.tick(200.millis(), &mut |_| {});
+		macro_state.tick(200.millis(), &mut Rng::default(), &mut |_| {});
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::End(_)
@@ -805,7 +1756,7 @@ mod tests {
 		let key_state = PhysicalKeyState::from(&device_key);
 		let mut macro_state = MacroState::from(&_macro, &key_state);
 
-		macro_state.tick(100.millis(), &mut |_| {});
+		macro_state.tick(100.millis(), &mut Rng::default(), &mut |_| {});
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::Loop(_)
@@ -813,13 +1764,13 @@ mod tests {
 
 		macro_state.stop();
 
-		macro_state.tick(200.millis(), &mut |_| {});
+		macro_state.tick(200.millis(), &mut Rng::default(), &mut |_| {});
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::End(_)
 		));
 
-		macro_state.tick(300.millis(), &mut |_| {});
+		macro_state.tick(300.millis(), &mut Rng::default(), &mut |_| {});
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::Finished
@@ -836,7 +1787,7 @@ mod tests {
 
 		macro_state.stop();
 
-		macro_state.tick(100.millis(), &mut |_| {});
+		macro_state.tick(100.millis(), &mut Rng::default(), &mut |_| {});
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::End(_)
@@ -965,6 +1916,415 @@ mod tests {
 		));
 	}
 
+	#[test]
+	fn release_trigger_starts_macro_on_release() {
+		let _macro = new_test_macro(MACRO_ID, None, vec![]);
+		let mut device_key = new_test_device_key(KEY_ID, vec![MacroIndex::new(0)]);
+		device_key.layers.default_layer.trigger = MacroTrigger::Release;
+		let profile = new_test_profile(vec![device_key], vec![_macro]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KEY_ID);
+		assert_eq!(state.running.len(), 0);
+
+		state.release_key(KEY_ID);
+		assert_eq!(state.running.len(), 1);
+
+		// already released, so it skips the loop
+		state.tick(100.millis(), |_| {});
+		assert!(matches!(
+			state.running[0].current_sequence,
+			CurrentSequence::End(_)
+		));
+	}
+
+	#[test]
+	fn hold_trigger_starts_macro_after_threshold() {
+		let _macro = new_test_macro(MACRO_ID, None, vec![]);
+		let mut device_key = new_test_device_key(KEY_ID, vec![MacroIndex::new(0)]);
+		device_key.layers.default_layer.trigger = MacroTrigger::Hold { threshold_ms: 200 };
+		let profile = new_test_profile(vec![device_key], vec![_macro]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KEY_ID);
+		state.tick(100.millis(), |_| {});
+		assert_eq!(state.running.len(), 0);
+
+		state.tick(100.millis(), |_| {});
+		assert_eq!(state.running.len(), 1);
+	}
+
+	#[test]
+	fn captured_press_plays_from_capture_time() {
+		let _macro = new_test_macro(MACRO_ID, None, vec![]);
+		let device_key = new_test_device_key(KEY_ID, vec![MacroIndex::new(0)]);
+		let profile = new_test_profile(vec![device_key], vec![_macro]);
+		let mut state = KeyboardState::from(&profile);
+
+		// captured 40ms before the end of a late tick, so the tick only plays 40ms of the
+		// 100ms predelay
+		state.press_key_captured(KEY_ID, 40.millis());
+		state.tick(100.millis(), |_| {});
+		assert!(matches!(
+			state.running[0].current_sequence,
+			CurrentSequence::Start(_)
+		));
+
+		state.tick(60.millis(), |_| {});
+		assert!(matches!(
+			state.running[0].current_sequence,
+			CurrentSequence::Loop(_)
+		));
+	}
+
+	#[test]
+	fn captured_press_starts_hold_timer_from_capture_time() {
+		let _macro = new_test_macro(MACRO_ID, None, vec![]);
+		let mut device_key = new_test_device_key(KEY_ID, vec![MacroIndex::new(0)]);
+		device_key.layers.default_layer.trigger = MacroTrigger::Hold { threshold_ms: 200 };
+		let profile = new_test_profile(vec![device_key], vec![_macro]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key_captured(KEY_ID, 10.millis());
+		state.tick(100.millis(), |_| {});
+		state.tick(100.millis(), |_| {});
+		assert_eq!(state.running.len(), 0);
+
+		state.tick(90.millis(), |_| {});
+		assert_eq!(state.running.len(), 1);
+	}
+
+	#[test]
+	fn hold_trigger_doesnt_start_macro_when_released_early() {
+		let _macro = new_test_macro(MACRO_ID, None, vec![]);
+		let mut device_key = new_test_device_key(KEY_ID, vec![MacroIndex::new(0)]);
+		device_key.layers.default_layer.trigger = MacroTrigger::Hold { threshold_ms: 200 };
+		let profile = new_test_profile(vec![device_key], vec![_macro]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KEY_ID);
+		state.tick(100.millis(), |_| {});
+		state.release_key(KEY_ID);
+		state.tick(200.millis(), |_| {});
+
+		assert_eq!(state.running.len(), 0);
+	}
+
+	#[test]
+	fn double_press_trigger_starts_macro_on_second_press() {
+		let _macro = new_test_macro(MACRO_ID, None, vec![]);
+		let mut device_key = new_test_device_key(KEY_ID, vec![MacroIndex::new(0)]);
+		device_key.layers.default_layer.trigger = MacroTrigger::DoublePress { window_ms: 200 };
+		let profile = new_test_profile(vec![device_key], vec![_macro]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KEY_ID);
+		state.release_key(KEY_ID);
+		state.tick(100.millis(), |_| {});
+		assert_eq!(state.running.len(), 0);
+
+		state.press_key(KEY_ID);
+		assert_eq!(state.running.len(), 1);
+	}
+
+	#[test]
+	fn double_press_trigger_ignores_slow_presses() {
+		let _macro = new_test_macro(MACRO_ID, None, vec![]);
+		let mut device_key = new_test_device_key(KEY_ID, vec![MacroIndex::new(0)]);
+		device_key.layers.default_layer.trigger = MacroTrigger::DoublePress { window_ms: 200 };
+		let profile = new_test_profile(vec![device_key], vec![_macro]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KEY_ID);
+		state.release_key(KEY_ID);
+		state.tick(300.millis(), |_| {});
+		state.press_key(KEY_ID);
+
+		assert_eq!(state.running.len(), 0);
+	}
+
+	#[test]
+	fn run_macro_plays_call_before_continuing() {
+		let caller = new_test_sequence_macro(
+			MACRO_ID,
+			vec![
+				ActionEvent::RunMacro(MacroIndex::new(1)),
+				ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A)),
+			],
+		);
+		let callee = new_test_sequence_macro(
+			MACRO_ID2,
+			vec![ActionEvent::Keyboard(KeyboardEvent::KeyDown(
+				KeyboardKey::A,
+			))],
+		);
+		let profile = new_test_profile(
+			vec![new_test_device_key(KEY_ID, vec![MacroIndex::new(0)])],
+			vec![caller, callee],
+		);
+		let mut state = KeyboardState::from(&profile);
+		let mut events = vec![];
+
+		state.press_key(KEY_ID);
+		state.tick(1.millis(), |e| events.push(e));
+		assert_eq!(events.len(), 1);
+		assert!(matches!(
+			events[0],
+			ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A))
+		));
+
+		// the call keeps looping while the key is held, like any other macro
+		state.tick(100.millis(), |e| events.push(e));
+		assert_eq!(events.len(), 1);
+
+		state.release_key(KEY_ID);
+		state.tick(1.millis(), |e| events.push(e));
+		assert_eq!(events.len(), 2);
+		assert!(matches!(
+			events[1],
+			ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A))
+		));
+	}
+
+	#[test]
+	fn run_macro_stops_nesting_at_max_depth() {
+		let recursive =
+			new_test_sequence_macro(MACRO_ID, vec![ActionEvent::RunMacro(MacroIndex::new(0))]);
+		let profile = new_test_profile(
+			vec![new_test_device_key(KEY_ID, vec![MacroIndex::new(0)])],
+			vec![recursive],
+		);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KEY_ID);
+		state.tick(1.millis(), |_| {});
+
+		let mut depth = 0;
+		let mut macro_state = &state.running[0];
+		while let Some(call) = &macro_state.call {
+			depth += 1;
+			macro_state = call;
+		}
+		assert_eq!(depth, MAX_CALL_DEPTH);
+	}
+
+	#[test]
+	fn macro_and_global_speed_scale_delays() {
+		let mut _macro = new_test_sequence_macro(
+			MACRO_ID,
+			vec![ActionEvent::Keyboard(KeyboardEvent::KeyDown(
+				KeyboardKey::A,
+			))],
+		);
+		_macro.start_sequence.actions[0].predelay_ms = 100;
+		_macro.speed_percent = 200;
+		let profile = new_test_profile(
+			vec![new_test_device_key(KEY_ID, vec![MacroIndex::new(0)])],
+			vec![_macro],
+		);
+		let mut state = KeyboardState::from(&profile);
+		let mut events = 0;
+
+		state.press_key(KEY_ID);
+		state.tick(50.millis(), |_| events += 1);
+		assert_eq!(events, 1);
+
+		state.release_key(KEY_ID);
+		state.tick(1.millis(), |_| {});
+		state.set_speed_percent(50);
+		state.press_key(KEY_ID);
+		state.tick(50.millis(), |_| events += 1);
+		assert_eq!(events, 1);
+		state.tick(50.millis(), |_| events += 1);
+		assert_eq!(events, 2);
+	}
+
+	#[test]
+	fn preempting_macro_pauses_lower_priority_on_channel() {
+		let mut low = new_test_sequence_macro(
+			MACRO_ID,
+			vec![
+				ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A)),
+				ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A)),
+			],
+		);
+		low.start_sequence.actions[1].predelay_ms = 100;
+		low.play_channel = Some(CHANNEL_ID);
+		let mut high = new_test_sequence_macro(
+			MACRO_ID2,
+			vec![ActionEvent::Keyboard(KeyboardEvent::KeyDown(
+				KeyboardKey::B,
+			))],
+		);
+		high.play_channel = Some(CHANNEL_ID);
+		high.priority = 1;
+		high.channel_policy = ChannelPolicy::Preempt;
+		let profile = new_test_profile(
+			vec![
+				new_test_device_key(KEY_ID, vec![MacroIndex::new(0)]),
+				new_test_device_key(KEY_ID2, vec![MacroIndex::new(1)]),
+			],
+			vec![low, high],
+		);
+		let mut state = KeyboardState::from(&profile);
+		let mut events = vec![];
+
+		state.press_key(KEY_ID);
+		state.tick(1.millis(), |e| events.push(e));
+		state.press_key(KEY_ID2);
+		state.tick(200.millis(), |e| events.push(e));
+		assert_eq!(events.len(), 2);
+		assert!(matches!(
+			events[1],
+			ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::B))
+		));
+
+		// picks up where it left off, with the paused time not counting towards its delay
+		state.release_key(KEY_ID2);
+		state.tick(1.millis(), |e| events.push(e));
+		state.tick(50.millis(), |e| events.push(e));
+		assert_eq!(events.len(), 2);
+		state.tick(50.millis(), |e| events.push(e));
+		assert_eq!(events.len(), 3);
+		assert!(matches!(
+			events[2],
+			ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A))
+		));
+	}
+
+	#[test]
+	fn virtual_key_can_be_set_by_id() {
+		let id = VirtualKeyId::new(Uuid::from_u128(1));
+		let mut profile = new_test_profile(vec![], vec![new_test_macro(MACRO_ID, None, vec![])]);
+		profile.virtual_keys.push(VirtualKey {
+			id: Some(id),
+			layers: new_test_device_key(KEY_ID, vec![MacroIndex::new(0)]).layers,
+		});
+		let mut state = KeyboardState::from(&profile);
+
+		assert!(!state.set_virtual_key_by_id(VirtualKeyId::new(Uuid::from_u128(2)), true));
+		assert!(state.set_virtual_key_by_id(id, true));
+		assert_eq!(state.running.len(), 1);
+
+		// the bitfield still sees the key as held
+		state.set_virtual_key_state(&[0b1000_0000]);
+		assert_eq!(state.running.len(), 1);
+	}
+
+	#[test]
+	fn macro_limit_rejects_newest() {
+		let _macro = new_test_macro(MACRO_ID, None, vec![]);
+		let profile = new_test_profile(
+			vec![new_test_device_key(KEY_ID, vec![MacroIndex::new(0)])],
+			vec![_macro],
+		);
+		let mut state = KeyboardState::from(&profile);
+		state.set_macro_limit(MacroLimit {
+			max_running: 2,
+			overflow: MacroOverflowPolicy::RejectNewest,
+		});
+
+		state.press_key(KEY_ID);
+		state.press_key(KEY_ID);
+		assert!(!state.take_macro_overflow());
+
+		state.tick(100.millis(), |_| {});
+		state.press_key(KEY_ID);
+		assert_eq!(state.running.len(), 2);
+		assert!(state.take_macro_overflow());
+		assert!(!state.take_macro_overflow());
+
+		// the macros that were already running are untouched
+		assert!(matches!(
+			state.running[1].current_sequence,
+			CurrentSequence::Loop(_)
+		));
+	}
+
+	#[test]
+	fn macro_limit_cuts_oldest() {
+		let _macro = new_test_macro(MACRO_ID, None, vec![]);
+		let profile = new_test_profile(
+			vec![new_test_device_key(KEY_ID, vec![MacroIndex::new(0)])],
+			vec![_macro],
+		);
+		let mut state = KeyboardState::from(&profile);
+		state.set_macro_limit(MacroLimit {
+			max_running: 2,
+			overflow: MacroOverflowPolicy::CutOldest,
+		});
+
+		state.press_key(KEY_ID);
+		state.press_key(KEY_ID);
+		state.tick(100.millis(), |_| {});
+		state.press_key(KEY_ID);
+
+		assert_eq!(state.running.len(), 2);
+		assert!(state.take_macro_overflow());
+		assert!(matches!(
+			state.running[0].current_sequence,
+			CurrentSequence::Loop(_)
+		));
+		assert!(matches!(
+			state.running[1].current_sequence,
+			CurrentSequence::Start(_)
+		));
+	}
+
+	#[test]
+	fn macro_limit_releases_keys_the_cut_macros_held() {
+		let press_a = new_test_sequence_macro(
+			MACRO_ID,
+			vec![ActionEvent::Keyboard(KeyboardEvent::KeyDown(
+				KeyboardKey::A,
+			))],
+		);
+		let profile = new_test_profile(
+			vec![new_test_device_key(KEY_ID, vec![MacroIndex::new(0)])],
+			vec![press_a],
+		);
+		let mut state = KeyboardState::from(&profile);
+		state.set_macro_limit(MacroLimit {
+			max_running: 1,
+			overflow: MacroOverflowPolicy::CutOldest,
+		});
+
+		let mut released = Vec::new();
+		state.press_key(KEY_ID);
+		state.tick(10.millis(), |_| {});
+		state.take_cut_keys(|key| released.push(key as u8));
+		assert!(released.is_empty());
+
+		// the first macro is still holding A when the second press cuts it
+		state.press_key(KEY_ID);
+		state.take_cut_keys(|key| released.push(key as u8));
+		assert_eq!(released, vec![KeyboardKey::A as u8]);
+		state.take_cut_keys(|key| released.push(key as u8));
+		assert_eq!(released.len(), 1);
+	}
+
+	#[test]
+	fn macro_budget_counts_the_running_list() {
+		static BUDGET: MemoryBudget =
+			MemoryBudget::new("Macro", 2 * core::mem::size_of::<MacroState>());
+		let _macro = new_test_macro(MACRO_ID, None, vec![]);
+		let profile = new_test_profile(
+			vec![new_test_device_key(KEY_ID, vec![MacroIndex::new(0)])],
+			vec![_macro],
+		);
+		let mut state = KeyboardState::from(&profile);
+		state.set_macro_budget(&BUDGET);
+
+		state.press_key(KEY_ID);
+		state.press_key(KEY_ID);
+		assert_eq!(BUDGET.violations(), 0);
+
+		state.press_key(KEY_ID);
+		assert_eq!(state.running.len(), 2);
+		assert_eq!(state.running.capacity(), 2);
+		assert_eq!(BUDGET.violations(), 1);
+	}
+
 	// #[test]
 	// fn updating_profile_releases_macros() {
 	// 	let profile = new_test_profile(vec![new_test_device_key(
@@ -1004,21 +2364,28 @@ mod tests {
 			id: KEY_ID,
 			layers: DeviceLayers {
 				layers: vec![TaggedDeviceKeyLayer {
+					index: LayerIndex::new(0),
 					layer: DeviceKeyLayer {
 						id: LAYER_ID2,
 						macros: vec![MacroIndex::new(0)],
+						trigger: MacroTrigger::Press,
+						backlight: None,
 					},
-					tags: vec![tag.clone()],
-					match_type: TagMatchType::All,
 				}],
 				default_layer: DeviceKeyLayer {
 					id: LAYER_ID,
 					macros: vec![MacroIndex::new(1)],
+					trigger: MacroTrigger::Press,
+					backlight: None,
 				},
 			},
 		};
 
-		let profile = new_test_profile(vec![device_key], macros);
+		let profile = new_test_layered_profile(
+			vec![new_test_layer(vec![tag.clone()])],
+			vec![device_key],
+			macros,
+		);
 		let mut state = KeyboardState::from(&profile);
 
 		state.add_internal_tag(&tag);
@@ -1028,6 +2395,68 @@ mod tests {
 		assert_eq!(state.running[0].macro_.id, expected_macro_id);
 	}
 
+	#[test]
+	fn timed_tag_clears_itself() {
+		let profile = new_test_profile(vec![], vec![]);
+		let tag = LayerTag::new("leader".to_string());
+
+		let mut state = KeyboardState::from(&profile);
+		state.add_internal_tag_for(&tag, 100.millis());
+		state.tick(60.millis(), |_| {});
+		// setting it again restarts the timer
+		state.add_internal_tag_for(&tag, 100.millis());
+		state.tick(60.millis(), |_| {});
+		assert_eq!(state.active_tags().internal, vec![tag.clone()]);
+		assert!(!state.take_tags_changed());
+
+		state.tick(40.millis(), |_| {});
+		assert!(state.active_tags().internal.is_empty());
+		assert!(state.take_tags_changed());
+	}
+
+	#[test]
+	fn every_layer_event_in_a_tick_is_applied() {
+		let tags: Vec<LayerTag> = (0..20)
+			.map(|i| LayerTag::new(alloc::format!("tag{}", i)))
+			.collect();
+		let mut _macro = new_test_macro(MACRO_ID, None, vec![]);
+		_macro.start_sequence.actions = tags
+			.iter()
+			.map(|tag| Action {
+				predelay_ms: 0,
+				predelay_max_ms: None,
+				action_event: ActionEvent::Layer(LayerEvent::Set(tag.clone())),
+			})
+			.collect();
+		let profile = new_test_profile(
+			vec![new_test_device_key(KEY_ID, vec![MacroIndex::new(0)])],
+			vec![_macro],
+		);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KEY_ID);
+		state.tick(1.millis(), |event| {
+			assert!(!matches!(event, ActionEvent::Layer(_)));
+		});
+		assert_eq!(state.active_tags().internal, tags);
+		assert!(state.take_tags_changed());
+	}
+
+	#[test]
+	fn active_tags_snapshot_includes_internal_and_external() {
+		let profile = new_test_profile(vec![], vec![]);
+		let internal = LayerTag::new("fn".to_string());
+		let external = LayerTag::new("app:editor".to_string());
+
+		let mut state = KeyboardState::from(&profile);
+		state.add_internal_tag(&internal);
+		state.set_external_tags(vec![external.clone()]);
+
+		let tags = state.active_tags();
+		assert_eq!(tags.internal, vec![internal]);
+		assert_eq!(tags.external, vec![external]);
+	}
+
 	#[test]
 	fn external_tags_affect_macro_selection() {
 		let expected_macro_id = MACRO_ID2;
@@ -1042,21 +2471,28 @@ mod tests {
 			id: KEY_ID,
 			layers: DeviceLayers {
 				layers: vec![TaggedDeviceKeyLayer {
+					index: LayerIndex::new(0),
 					layer: DeviceKeyLayer {
 						id: LAYER_ID2,
 						macros: vec![MacroIndex::new(0)],
+						trigger: MacroTrigger::Press,
+						backlight: None,
 					},
-					tags: vec![tag.clone()],
-					match_type: TagMatchType::All,
 				}],
 				default_layer: DeviceKeyLayer {
 					id: LAYER_ID,
 					macros: vec![MacroIndex::new(1)],
+					trigger: MacroTrigger::Press,
+					backlight: None,
 				},
 			},
 		};
 
-		let profile = new_test_profile(vec![device_key], macros);
+		let profile = new_test_layered_profile(
+			vec![new_test_layer(vec![tag.clone()])],
+			vec![device_key],
+			macros,
+		);
 		let mut state = KeyboardState::from(&profile);
 
 		state.set_external_tags(vec![tag.clone()]);
@@ -1080,21 +2516,25 @@ mod tests {
 			id: KEY_ID,
 			layers: DeviceLayers {
 				layers: vec![TaggedDeviceKeyLayer {
+					index: LayerIndex::new(0),
 					layer: DeviceKeyLayer {
 						id: LAYER_ID2,
 						macros: vec![MacroIndex::new(1)],
+						trigger: MacroTrigger::Press,
+						backlight: None,
 					},
-					tags: vec![tag],
-					match_type: TagMatchType::All,
 				}],
 				default_layer: DeviceKeyLayer {
 					id: LAYER_ID,
 					macros: vec![MacroIndex::new(0)],
+					trigger: MacroTrigger::Press,
+					backlight: None,
 				},
 			},
 		};
 
-		let profile = new_test_profile(vec![device_key], macros);
+		let profile =
+			new_test_layered_profile(vec![new_test_layer(vec![tag])], vec![device_key], macros);
 		let mut state = KeyboardState::from(&profile);
 
 		state.press_key(KEY_ID);
@@ -1105,10 +2545,7 @@ mod tests {
 	#[test]
 	fn layers_with_empty_tags_never_match_for_any() {
 		let tag = LayerTag::new("".to_string());
-		let tag_list = TagList {
-			internal: vec![],
-			external: vec![],
-		};
+		let tag_list = TagList::new();
 
 		assert_eq!(tag_list.matches(&[tag], &TagMatchType::Any), false);
 	}
@@ -1116,10 +2553,7 @@ mod tests {
 	#[test]
 	fn layers_with_empty_tags_never_match_for_all() {
 		let tag = LayerTag::new("".to_string());
-		let tag_list = TagList {
-			internal: vec![],
-			external: vec![],
-		};
+		let tag_list = TagList::new();
 
 		assert_eq!(tag_list.matches(&[tag], &TagMatchType::All), false);
 	}
@@ -1138,54 +2572,145 @@ mod tests {
 		assert_eq!(tag_list.matches(&[tag1.clone()], &TagMatchType::All), true);
 	}
 
-	// ------- HELPERS --------
+	#[test]
+	fn toggling_internal_tag_flips_it() {
+		let tag1 = LayerTag::new("tag1".to_string());
 
-	fn new_test_profile(keys: Vec<DeviceKey>, macros: Vec<Macro>) -> KeyboardProfile {
-		KeyboardProfile {
-			name: "".to_string(),
-			keys,
-			virtual_keys: vec![],
-			macros,
-		}
+		let mut tag_list = TagList::new();
+
+		tag_list.toggle_internal(&tag1);
+		assert_eq!(tag_list.matches(&[tag1.clone()], &TagMatchType::All), true);
+
+		tag_list.add_internal(&tag1);
+		tag_list.toggle_internal(&tag1);
+		assert_eq!(tag_list.matches(&[tag1.clone()], &TagMatchType::All), false);
 	}
 
-	fn new_test_device_key(id: KeyId, macros: Vec<MacroIndex>) -> DeviceKey {
-		DeviceKey {
-			id,
-			layers: DeviceLayers {
-				layers: Vec::new(),
-				default_layer: DeviceKeyLayer {
-					id: LAYER_ID,
-					macros,
-				},
-			},
-		}
+	#[test]
+	fn interned_tags_get_a_mask_per_layer() {
+		let tags: Vec<LayerTag> = (0..3).map(|i| LayerTag::new(i.to_string())).collect();
+		let profile = new_test_layered_profile(
+			vec![
+				new_test_layer(vec![tags[0].clone(), tags[1].clone()]),
+				new_test_layer(vec![tags[1].clone(), tags[2].clone()]),
+			],
+			vec![],
+			vec![],
+		);
+
+		assert_eq!(profile.layers[0].tag_mask, Some(0b011));
+		assert_eq!(profile.layers[1].tag_mask, Some(0b110));
+		assert_eq!(profile.tags.mask_of(&tags), 0b111);
 	}
 
-	fn new_test_macro(id: MacroId, channel: Option<Channel>, cut: Vec<Channel>) -> Macro {
-		Macro {
-			start_sequence: Sequence {
-				actions: vec![Action {
-					predelay_ms: 100,
-					action_event: ActionEvent::None,
-				}],
-			},
-			loop_sequence: Sequence {
-				actions: vec![Action {
-					predelay_ms: 200,
-					action_event: ActionEvent::None,
-				}],
-			},
-			end_sequence: Sequence {
-				actions: vec![Action {
-					predelay_ms: 300,
-					action_event: ActionEvent::None,
-				}],
-			},
-			cut_channels: cut,
-			id,
-			name: "Name".to_string(),
-			play_channel: channel,
+	#[test]
+	fn active_tags_snapshot_includes_active_layers() {
+		let tags: Vec<LayerTag> = (0..2).map(|i| LayerTag::new(i.to_string())).collect();
+		let profile = new_test_layered_profile(
+			vec![
+				new_test_layer(vec![tags[0].clone()]),
+				new_test_layer(vec![tags[1].clone()]),
+				new_test_layer(tags.clone()),
+			],
+			vec![new_test_tagged_key(KEY_ID, 2)],
+			vec![],
+		);
+
+		let mut state = KeyboardState::from(&profile);
+		state.set_external_tags(vec![tags[1].clone()]);
+		assert_eq!(state.active_tags().layers, 0b010);
+		assert_eq!(state.keys[0].current_layer.id, LAYER_ID);
+
+		state.add_internal_tag(&tags[0]);
+		assert_eq!(state.active_tags().layers, 0b111);
+		assert_eq!(state.keys[0].current_layer.id, LAYER_ID2);
+	}
+
+	#[test]
+	fn layers_past_the_tag_id_limit_fall_back_to_strings() {
+		let expected_macro = new_test_macro(MACRO_ID2, None, vec![]);
+		let other_macro = new_test_macro(MACRO_ID, None, vec![]);
+
+		// fill up the tag IDs with another layer
+		let filler: Vec<LayerTag> = (0..TagTable::MAX_TAGS)
+			.map(|i| LayerTag::new(i.to_string()))
+			.collect();
+		let tag = LayerTag::new("overflow".to_string());
+		let mut device_key = new_test_tagged_key(KEY_ID, 1);
+		device_key.layers.layers[0].layer.macros = vec![MacroIndex::new(0)];
+		device_key.layers.default_layer.macros = vec![MacroIndex::new(1)];
+
+		let profile = new_test_layered_profile(
+			vec![new_test_layer(filler), new_test_layer(vec![tag.clone()])],
+			vec![new_test_tagged_key(KEY_ID2, 0), device_key],
+			vec![expected_macro, other_macro],
+		);
+		assert_eq!(profile.layers[1].tag_mask, None);
+
+		let mut state = KeyboardState::from(&profile);
+		state.set_external_tags(vec![tag]);
+		state.press_key(KEY_ID);
+
+		assert_eq!(state.running[0].macro_.id, MACRO_ID2);
+	}
+
+	#[test]
+	fn layer_changes_recolor_backlit_keys() {
+		let tag = LayerTag::new("".to_string());
+		let red = Rgb::new(255, 0, 0);
+		let mut device_key = new_test_tagged_key(KEY_ID, 0);
+		device_key.layers.layers[0].layer.backlight = Some(red);
+
+		let profile = new_test_layered_profile(
+			vec![new_test_layer(vec![tag.clone()])],
+			vec![device_key, new_test_tagged_key(KEY_ID2, 0)],
+			vec![],
+		);
+		let mut state = KeyboardState::from(&profile);
+		let mut colors = Vec::new();
+
+		state.take_backlight(|key, color| colors.push((key, color)));
+		assert_eq!(colors, vec![(KEY_ID, Rgb::OFF)]);
+
+		colors.clear();
+		state.take_backlight(|key, color| colors.push((key, color)));
+		assert!(colors.is_empty());
+
+		state.set_external_tags(vec![tag]);
+		state.take_backlight(|key, color| colors.push((key, color)));
+		assert_eq!(colors, vec![(KEY_ID, red)]);
+	}
+
+	// ------- KEY STATS TESTS --------
+
+	#[test]
+	fn key_presses_are_counted_even_for_keys_not_in_the_profile() {
+		let profile = new_test_profile(vec![new_test_device_key(KEY_ID, vec![])], vec![]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KEY_ID);
+		state.release_key(KEY_ID);
+		state.press_key(KEY_ID);
+		state.press_key(KEY_ID2);
+
+		assert_eq!(state.key_stats().presses(KEY_ID), 2);
+		assert_eq!(state.key_stats().presses(KEY_ID2), 1);
+		assert_eq!(state.key_stats().keys().len(), 2);
+	}
+
+	#[test]
+	fn key_stats_stop_adding_keys_when_full() {
+		let mut stats = KeyStats::default();
+		for i in 0..KeyStats::MAX_KEYS as u128 + 1 {
+			stats.count_press(KeyId::new(Uuid::from_u128(i)));
 		}
+		stats.count_press(KeyId::new(Uuid::from_u128(0)));
+
+		assert_eq!(stats.keys().len(), KeyStats::MAX_KEYS);
+		assert_eq!(stats.presses(KeyId::new(Uuid::from_u128(0))), 2);
+		assert_eq!(
+			stats.presses(KeyId::new(Uuid::from_u128(KeyStats::MAX_KEYS as u128))),
+			0
+		);
 	}
 }
@@ -0,0 +1,169 @@
+use core::cell::Cell;
+
+use crate::serialize::Writeable;
+use crate::stream::{WriteAsync, WriteAsyncExt};
+use crate::time::Duration;
+use critical_section::Mutex;
+
+/// Tracks how far each keypad loop iteration strays from the target interval. Jitter is the
+/// absolute difference between the measured and target interval, in microseconds.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoopTiming {
+	pub samples: u32,
+	pub min_jitter_us: u32,
+	pub max_jitter_us: u32,
+	total_jitter_us: u64,
+}
+
+impl LoopTiming {
+	pub const fn new() -> Self {
+		Self {
+			samples: 0,
+			min_jitter_us: 0,
+			max_jitter_us: 0,
+			total_jitter_us: 0,
+		}
+	}
+
+	pub fn record(&mut self, actual: Duration, target: Duration) {
+		let actual = actual.to_micros();
+		let target = target.to_micros();
+		let jitter = actual.abs_diff(target).min(u32::MAX as u64) as u32;
+
+		if self.samples == 0 || jitter < self.min_jitter_us {
+			self.min_jitter_us = jitter;
+		}
+		self.max_jitter_us = self.max_jitter_us.max(jitter);
+		self.total_jitter_us = self.total_jitter_us.saturating_add(jitter as u64);
+		self.samples = self.samples.saturating_add(1);
+	}
+
+	pub fn mean_jitter_us(&self) -> u32 {
+		if self.samples == 0 {
+			0
+		} else {
+			(self.total_jitter_us / self.samples as u64) as u32
+		}
+	}
+}
+
+impl Writeable for LoopTiming {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_u32(self.samples).await?;
+		writer.write_u32(self.min_jitter_us).await?;
+		writer.write_u32(self.max_jitter_us).await?;
+		writer.write_u32(self.mean_jitter_us()).await
+	}
+}
+
+/// Snapshot of the USB traffic counters, see `UsbStats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UsbCounters {
+	pub hid_reports_written: u32,
+	pub hid_write_errors: u32,
+	pub serial_packets_read: u32,
+	pub serial_packets_dropped: u32,
+	pub command_parse_failures: u32,
+}
+
+impl Writeable for UsbCounters {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer.write_u32(self.hid_reports_written).await?;
+		writer.write_u32(self.hid_write_errors).await?;
+		writer.write_u32(self.serial_packets_read).await?;
+		writer.write_u32(self.serial_packets_dropped).await?;
+		writer.write_u32(self.command_parse_failures).await
+	}
+}
+
+/// USB traffic counters shared between the HID, serial and command tasks.
+///
+/// Uses critical sections rather than atomics since the RP2040 has no atomic read-modify-write.
+pub struct UsbStats {
+	counters: Mutex<Cell<UsbCounters>>,
+}
+
+impl UsbStats {
+	pub const fn new() -> Self {
+		UsbStats {
+			counters: Mutex::new(Cell::new(UsbCounters {
+				hid_reports_written: 0,
+				hid_write_errors: 0,
+				serial_packets_read: 0,
+				serial_packets_dropped: 0,
+				command_parse_failures: 0,
+			})),
+		}
+	}
+
+	pub fn get(&self) -> UsbCounters {
+		critical_section::with(|cs| self.counters.borrow(cs).get())
+	}
+
+	fn update(&self, f: impl FnOnce(&mut UsbCounters)) {
+		critical_section::with(|cs| {
+			let cell = self.counters.borrow(cs);
+			let mut counters = cell.get();
+			f(&mut counters);
+			cell.set(counters);
+		});
+	}
+
+	pub fn hid_report_written(&self, ok: bool) {
+		self.update(|c| {
+			if ok {
+				c.hid_reports_written = c.hid_reports_written.wrapping_add(1);
+			} else {
+				c.hid_write_errors = c.hid_write_errors.wrapping_add(1);
+			}
+		});
+	}
+
+	pub fn serial_packet_read(&self) {
+		self.update(|c| c.serial_packets_read = c.serial_packets_read.wrapping_add(1));
+	}
+
+	pub fn serial_packet_dropped(&self) {
+		self.update(|c| c.serial_packets_dropped = c.serial_packets_dropped.wrapping_add(1));
+	}
+
+	pub fn command_parse_failed(&self) {
+		self.update(|c| c.command_parse_failures = c.command_parse_failures.wrapping_add(1));
+	}
+}
+
+impl Default for UsbStats {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use fugit::ExtU64;
+
+	#[test]
+	fn loop_timing_tracks_min_max_and_mean() {
+		let mut timing = LoopTiming::new();
+		assert_eq!(timing.mean_jitter_us(), 0);
+
+		timing.record(1_100.micros(), 1.millis());
+		timing.record(1_000.micros(), 1.millis());
+		timing.record(1_500.micros(), 1.millis());
+
+		assert_eq!(timing.samples, 3);
+		assert_eq!(timing.min_jitter_us, 0);
+		assert_eq!(timing.max_jitter_us, 500);
+		assert_eq!(timing.mean_jitter_us(), 200);
+	}
+
+	#[test]
+	fn loop_timing_counts_early_ticks_as_jitter() {
+		let mut timing = LoopTiming::new();
+		timing.record(900.micros(), 1.millis());
+
+		assert_eq!(timing.min_jitter_us, 100);
+		assert_eq!(timing.max_jitter_us, 100);
+	}
+}
@@ -0,0 +1,170 @@
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use cortex_m::peripheral::SCB;
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::flash::{Blocking, Error, FLASH_BASE, Flash, MAX_ERASE_SIZE, WRITE_SIZE};
+use embassy_stm32::gpio::{Input, Output};
+
+use crate::context::{Reboot, RebootToBootloader};
+use crate::indicator::Indicator;
+use crate::input::{ColPin, RowPin};
+use crate::output::OutputPin;
+use crate::power::PowerSourceSense;
+use crate::storage::BlockFlash;
+
+impl RowPin for Output<'_> {
+	fn set_high(&mut self) {
+		self.set_high();
+	}
+
+	fn set_low(&mut self) {
+		self.set_low();
+	}
+}
+
+impl Indicator for Output<'_> {
+	fn set(&mut self, on: bool) {
+		self.set_level(on.into());
+	}
+}
+
+impl OutputPin for Output<'_> {
+	fn set(&mut self, high: bool) {
+		self.set_level(high.into());
+	}
+}
+
+impl PowerSourceSense for Input<'_> {
+	fn on_external_power(&mut self) -> bool {
+		self.is_high()
+	}
+}
+
+// plain STM32 inputs can't wait for an edge, so columns need an EXTI line each
+impl ColPin for ExtiInput<'_> {
+	fn is_high(&self) -> bool {
+		ExtiInput::is_high(self)
+	}
+
+	fn wait_for_high(&mut self) -> Pin<Box<dyn Future<Output = ()> + '_>> {
+		Box::pin(ExtiInput::wait_for_high(self))
+	}
+}
+
+/// A partition of the internal flash. On chips with mixed sector sizes the partition has to
+/// sit in the largest sectors, since erases are done in blocks of `MAX_ERASE_SIZE`.
+pub struct Stm32FlashMemory<'d> {
+	storage_addr: *const u8,
+	length: usize,
+	flash: Flash<'d, Blocking>,
+}
+
+impl<'d> Stm32FlashMemory<'d> {
+	pub fn new(storage_addr: *const u8, length: usize, flash: Flash<'d, Blocking>) -> Self {
+		if (storage_addr as usize - FLASH_BASE) % MAX_ERASE_SIZE != 0 {
+			error!(
+				"Base address is not erase block aligned: {}",
+				storage_addr as usize
+			);
+			panic!("Base address is not erase block aligned");
+		}
+
+		if length % MAX_ERASE_SIZE != 0 {
+			error!("Length is not erase block aligned: {}", length);
+			panic!("Length is not erase block aligned");
+		}
+
+		Stm32FlashMemory {
+			storage_addr,
+			length,
+			flash,
+		}
+	}
+
+	fn get_flash_offset(&self) -> usize {
+		self.storage_addr as usize - FLASH_BASE
+	}
+}
+
+impl<'a> BlockFlash for Stm32FlashMemory<'a> {
+	fn as_slice(&self) -> &'static [u8] {
+		unsafe { core::slice::from_raw_parts(self.storage_addr, self.length) }
+	}
+
+	async fn erase(&mut self, offset: usize, length: usize) -> Result<(), &'static str> {
+		let start = offset + self.get_flash_offset();
+		let end = start + length;
+
+		self.flash
+			.blocking_erase(start as u32, end as u32)
+			.map_err(|e| {
+				error!("Error erasing flash memory: {:?}", e);
+				match e {
+					Error::Size => "Erase out of bounds",
+					Error::Unaligned => "Erase not block aligned",
+					_ => "Error erasing flash memory",
+				}
+			})
+	}
+
+	async fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), &'static str> {
+		self.flash
+			.blocking_write((self.get_flash_offset() + offset) as u32, data)
+			.map_err(|e| {
+				error!("Error writing to flash memory: {:?}", e);
+				match e {
+					Error::Size => "Write out of bounds",
+					Error::Unaligned => "Write not block aligned",
+					_ => "Error writing to flash memory",
+				}
+			})
+	}
+
+	fn length(&self) -> usize {
+		self.length
+	}
+
+	const ERASE_BLOCK_SIZE: usize = MAX_ERASE_SIZE;
+
+	const WRITE_BLOCK_SIZE: usize = WRITE_SIZE;
+}
+
+pub struct Stm32Reboot {}
+
+impl Reboot for Stm32Reboot {
+	fn reboot(&mut self) -> ! {
+		SCB::sys_reset()
+	}
+}
+
+const BOOTLOADER_MAGIC: u32 = 0xB007_10AD;
+
+// survives the reset, so the next boot can tell it was asked to enter the bootloader
+#[unsafe(link_section = ".uninit.BOOTLOADER_REQUEST")]
+static mut BOOTLOADER_REQUEST: u32 = 0;
+
+/// Reboots into the ROM bootloader. The ROM can't be entered directly from running
+/// firmware, so this leaves a flag for [`enter_bootloader_if_requested`] and resets.
+pub struct Stm32RebootToBootloader {}
+
+impl RebootToBootloader for Stm32RebootToBootloader {
+	fn reboot_to_bootloader(&self) -> ! {
+		unsafe { core::ptr::addr_of_mut!(BOOTLOADER_REQUEST).write_volatile(BOOTLOADER_MAGIC) };
+		SCB::sys_reset()
+	}
+}
+
+/// Jumps to the ROM bootloader at `system_memory` (0x1FFF0000 on most F4 parts, see AN2606)
+/// if the last reset came from [`Stm32RebootToBootloader`]. Call it first thing in main,
+/// before the clocks and peripherals are set up.
+pub fn enter_bootloader_if_requested(system_memory: u32) {
+	let request = core::ptr::addr_of_mut!(BOOTLOADER_REQUEST);
+	let requested = unsafe { request.read_volatile() } == BOOTLOADER_MAGIC;
+	if requested {
+		unsafe {
+			request.write_volatile(0);
+			cortex_m::asm::bootload(system_memory as *const u32)
+		}
+	}
+}
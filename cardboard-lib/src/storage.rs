@@ -1,9 +1,20 @@
-use crate::{profile::KeyboardProfile, serialize::Readable, stream::ReadAsyncExt};
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::task::Poll;
+
+use crate::{
+	crash::CrashReport,
+	crc::crc16,
+	profile::KeyboardProfile,
+	serialize::{Readable, Writeable},
+	state::KeyStats,
+	stream::ReadAsyncExt,
+};
 
 pub trait BlockFlash {
 	fn as_slice(&self) -> &'static [u8];
-	fn erase(&mut self, offset: usize, length: usize) -> Result<(), &'static str>;
-	fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), &'static str>;
+	async fn erase(&mut self, offset: usize, length: usize) -> Result<(), &'static str>;
+	async fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), &'static str>;
 	fn length(&self) -> usize;
 
 	const ERASE_BLOCK_SIZE: usize;
@@ -51,14 +62,14 @@ impl<'a, Flash: BlockFlash + ?Sized> BlockFlash for PartitionedFlashMemory<'a, F
 		&self.flash.as_slice()[start..end]
 	}
 
-	fn erase(&mut self, offset: usize, length: usize) -> Result<(), &'static str> {
+	async fn erase(&mut self, offset: usize, length: usize) -> Result<(), &'static str> {
 		let start = self.partition.start + offset;
-		self.flash.erase(start, length)
+		self.flash.erase(start, length).await
 	}
 
-	fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), &'static str> {
+	async fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), &'static str> {
 		let start = self.partition.start + offset;
-		self.flash.write(start, data)
+		self.flash.write(start, data).await
 	}
 
 	fn length(&self) -> usize {
@@ -78,32 +89,73 @@ pub trait BlockFlashExt: BlockFlash {
 		PartitionedFlashMemory::new(self, partition)
 	}
 
-	fn erase_all(&mut self) -> Result<(), &'static str> {
-		self.erase(0, self.length())
+	async fn erase_all(&mut self) -> Result<(), &'static str> {
+		self.erase(0, self.length()).await
+	}
+
+	async fn erase_at_least(&mut self, length: usize) -> Result<(), &'static str> {
+		self.erase_at_least_with_progress(length, |_, _| {}).await
 	}
 
-	fn erase_at_least(&mut self, length: usize) -> Result<(), &'static str> {
+	/// Erases the whole blocks covering `length` one at a time, yielding in between so a large
+	/// erase doesn't hold up other tasks. `progress` gets the bytes erased so far and the total
+	/// after each block.
+	async fn erase_at_least_with_progress(
+		&mut self,
+		length: usize,
+		mut progress: impl FnMut(usize, usize),
+	) -> Result<(), &'static str> {
 		let erase_block_size = Self::ERASE_BLOCK_SIZE;
 		let blocks_needed = (length + erase_block_size - 1) / erase_block_size;
 		let erase_length = blocks_needed * erase_block_size;
-		self.erase(0, erase_length)
+
+		let mut erased = 0;
+		while erased < erase_length {
+			self.erase(erased, erase_block_size).await?;
+			erased += erase_block_size;
+			progress(erased, erase_length);
+			yield_now().await;
+		}
+		Ok(())
 	}
 }
 
 impl<T: BlockFlash> BlockFlashExt for T {}
 
+// gives other tasks a turn, whatever executor we're running on
+pub(crate) async fn yield_now() {
+	let mut yielded = false;
+	poll_fn(|cx| {
+		if yielded {
+			Poll::Ready(())
+		} else {
+			yielded = true;
+			cx.waker().wake_by_ref();
+			Poll::Pending
+		}
+	})
+	.await
+}
+
 pub async fn load_settings_from_flash<F: BlockFlash, Settings>(
 	flash: &mut F,
 ) -> Result<Settings, &'static str>
 where
-	Settings: Readable,
+	Settings: Readable + Default,
 {
 	let mut data = flash.as_slice();
 	let length = data
 		.read_u16()
 		.await
-		.ok_or("Failed to read settings length")? as usize;
-	data = &data[..length];
+		.ok_or("Failed to read settings length")?;
+	// nothing saved yet, which isn't an error
+	if length == ERASED_LENGTH {
+		return Ok(Settings::default());
+	}
+	let length = length as usize;
+	data = data
+		.get(..length)
+		.ok_or("Settings length exceeds flash partition")?;
 	Settings::read_from(&mut data).await
 }
 
@@ -116,16 +168,20 @@ pub async fn save_settings_to_flash<F: BlockFlash>(
 	}
 
 	let length = settings.len();
-	flash.erase_at_least(length)?;
-	flash.write(0, &(length as u16).to_le_bytes())?;
-	flash.write(2, settings)?;
+	flash.erase_at_least(length + 2).await?;
+	flash.write(0, &(length as u16).to_le_bytes()).await?;
+	flash.write(2, settings).await?;
 	Ok(())
 }
 
 pub async fn load_profile_from_flash<F: BlockFlash>(
 	flash: &mut F,
 ) -> Result<KeyboardProfile, &'static str> {
-	let mut data = flash.as_slice();
+	read_profile_record(flash.as_slice()).await
+}
+
+/// Parses the contents of a profile partition, `[length u16][profile]`.
+pub async fn read_profile_record(mut data: &[u8]) -> Result<KeyboardProfile, &'static str> {
 	let length = data
 		.read_u16()
 		.await
@@ -138,11 +194,200 @@ pub async fn load_profile_from_flash<F: BlockFlash>(
 	KeyboardProfile::read_from(&mut data).await
 }
 
+/// Largest profile a profile partition of `partition_size` bytes can hold.
+pub const fn max_profile_size(partition_size: usize) -> usize {
+	let size = partition_size.saturating_sub(2); // the u16 length
+	if size < u16::MAX as usize {
+		size
+	} else {
+		u16::MAX as usize
+	}
+}
+
+// Key stats change all the time, so rather than erasing and rewriting the partition on every
+// save, records are appended until it fills up. Each record is `[length u16][crc u16][data]`,
+// padded to the write block size, and the last intact one is current.
+const KEY_STATS_HEADER_SIZE: usize = 4;
+const ERASED_LENGTH: u16 = 0xFFFF;
+
+/// Finds the newest intact key stats record and the offset just past it.
+fn find_key_stats<F: BlockFlash>(flash: &F) -> (Option<&'static [u8]>, usize) {
+	let data = flash.as_slice();
+	let mut latest = None;
+	let mut offset = 0;
+
+	while let Some(header) = data.get(offset..offset + KEY_STATS_HEADER_SIZE) {
+		let length = u16::from_le_bytes([header[0], header[1]]);
+		let crc = u16::from_le_bytes([header[2], header[3]]);
+		if length == ERASED_LENGTH {
+			break;
+		}
+		let start = offset + KEY_STATS_HEADER_SIZE;
+		let Some(record) = data.get(start..start + length as usize) else {
+			break;
+		};
+		if crc16(record) != crc {
+			break;
+		}
+		latest = Some(record);
+		offset = (start + record.len()).next_multiple_of(F::WRITE_BLOCK_SIZE);
+	}
+
+	(latest, offset)
+}
+
+/// Loads the saved key stats, or empty stats if none have been saved yet.
+pub async fn load_key_stats_from_flash<F: BlockFlash>(
+	flash: &mut F,
+) -> Result<KeyStats, &'static str> {
+	match find_key_stats(flash) {
+		(Some(mut record), _) => KeyStats::read_from(&mut record).await,
+		(None, _) => Ok(KeyStats::default()),
+	}
+}
+
+/// Appends the key stats after the last saved copy, only erasing the partition when there's
+/// no erased space left for them.
+pub async fn save_key_stats_to_flash<F: BlockFlash>(
+	flash: &mut F,
+	stats: &KeyStats,
+) -> Result<(), &'static str> {
+	let mut data = Vec::new();
+	stats.write_to(&mut data).await?;
+
+	let length = KEY_STATS_HEADER_SIZE + data.len();
+	if length > flash.length() || data.len() >= ERASED_LENGTH as usize {
+		return Err("Key stats exceed flash memory length");
+	}
+	let mut record = Vec::with_capacity(length.next_multiple_of(F::WRITE_BLOCK_SIZE));
+	record.extend_from_slice(&(data.len() as u16).to_le_bytes());
+	record.extend_from_slice(&crc16(&data).to_le_bytes());
+	record.extend_from_slice(&data);
+	record.resize(length.next_multiple_of(F::WRITE_BLOCK_SIZE), 0xFF);
+
+	let (_, mut offset) = find_key_stats(flash);
+	// a torn write leaves programmed bytes past the last intact record
+	let erased = flash
+		.as_slice()
+		.get(offset..offset + record.len())
+		.is_some_and(|space| space.iter().all(|b| *b == 0xFF));
+	if !erased {
+		flash.erase_all().await?;
+		offset = 0;
+	}
+
+	flash.write(offset, &record).await
+}
+
+// Data that's rewritten rarely, like the crash report and the lock PIN, is kept as a single
+// `[length u16][crc u16][data]` record at the start of its partition.
+fn load_record<F: BlockFlash>(flash: &F) -> Result<Option<&'static [u8]>, &'static str> {
+	let data = flash.as_slice();
+	let Some(header) = data.get(..KEY_STATS_HEADER_SIZE) else {
+		return Ok(None);
+	};
+	let length = u16::from_le_bytes([header[0], header[1]]);
+	let crc = u16::from_le_bytes([header[2], header[3]]);
+	if length == ERASED_LENGTH {
+		return Ok(None);
+	}
+	let record = data
+		.get(KEY_STATS_HEADER_SIZE..KEY_STATS_HEADER_SIZE + length as usize)
+		.ok_or("Record length exceeds flash partition")?;
+	if crc16(record) != crc {
+		return Err("Record failed CRC check");
+	}
+	Ok(Some(record))
+}
+
+async fn save_record<F: BlockFlash>(flash: &mut F, data: &[u8]) -> Result<(), &'static str> {
+	let length = KEY_STATS_HEADER_SIZE + data.len();
+	if length > flash.length() || data.len() >= ERASED_LENGTH as usize {
+		return Err("Record exceeds flash memory length");
+	}
+	let mut record = Vec::with_capacity(length.next_multiple_of(F::WRITE_BLOCK_SIZE));
+	record.extend_from_slice(&(data.len() as u16).to_le_bytes());
+	record.extend_from_slice(&crc16(data).to_le_bytes());
+	record.extend_from_slice(data);
+	record.resize(length.next_multiple_of(F::WRITE_BLOCK_SIZE), 0xFF);
+
+	flash.erase_at_least(record.len()).await?;
+	flash.write(0, &record).await
+}
+
+async fn clear_record<F: BlockFlash>(flash: &mut F) -> Result<(), &'static str> {
+	flash.erase_at_least(KEY_STATS_HEADER_SIZE).await
+}
+
+/// Loads the crash report saved by the last boot, if there is one.
+pub async fn load_crash_report_from_flash<F: BlockFlash>(
+	flash: &mut F,
+) -> Result<Option<CrashReport>, &'static str> {
+	match load_record(flash)? {
+		Some(mut record) => CrashReport::read_from(&mut record).await.map(Some),
+		None => Ok(None),
+	}
+}
+
+/// Replaces the saved crash report.
+pub async fn save_crash_report_to_flash<F: BlockFlash>(
+	flash: &mut F,
+	report: &CrashReport,
+) -> Result<(), &'static str> {
+	let mut data = Vec::new();
+	report.write_to(&mut data).await?;
+	save_record(flash, &data).await
+}
+
+/// Forgets the saved crash report.
+pub async fn clear_crash_report_in_flash<F: BlockFlash>(flash: &mut F) -> Result<(), &'static str> {
+	clear_record(flash).await
+}
+
+/// Loads the PIN the device is locked with, if it's locked. A record that fails its CRC is an
+/// error rather than no PIN, so a corrupted lock can't unlock the device; callers treat it as
+/// locked until it's cleared from safe mode.
+pub fn load_lock_pin_from_flash<F: BlockFlash>(
+	flash: &F,
+) -> Result<Option<&'static [u8]>, &'static str> {
+	load_record(flash)
+}
+
+pub async fn save_lock_pin_to_flash<F: BlockFlash>(
+	flash: &mut F,
+	pin: &[u8],
+) -> Result<(), &'static str> {
+	save_record(flash, pin).await
+}
+
+pub async fn clear_lock_pin_in_flash<F: BlockFlash>(flash: &mut F) -> Result<(), &'static str> {
+	clear_record(flash).await
+}
+
+/// Loads the secret hosts authenticate with, if one has been set. Like the lock PIN, a record
+/// that fails its CRC counts as no secret.
+pub fn load_auth_secret_from_flash<F: BlockFlash>(flash: &F) -> Option<&'static [u8]> {
+	load_record(flash).ok().flatten()
+}
+
+pub async fn save_auth_secret_to_flash<F: BlockFlash>(
+	flash: &mut F,
+	secret: &[u8],
+) -> Result<(), &'static str> {
+	save_record(flash, secret).await
+}
+
+pub async fn clear_auth_secret_in_flash<F: BlockFlash>(flash: &mut F) -> Result<(), &'static str> {
+	clear_record(flash).await
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
 
-	use crate::test::test::*;
+	use crate::input::KeyId;
+	use crate::testing::*;
+	use uuid::Uuid;
 
 	#[tokio::test]
 	async fn can_deserialize_cranky_profile() {
@@ -156,4 +401,126 @@ mod tests {
 			result.err().unwrap()
 		);
 	}
+
+	#[test]
+	fn max_profile_size_is_capped_by_the_length_prefix() {
+		assert_eq!(max_profile_size(0x1000), 0x0FFE);
+		assert_eq!(max_profile_size(0x40000), u16::MAX as usize);
+		assert_eq!(max_profile_size(1), 0);
+	}
+
+	#[tokio::test]
+	async fn key_stats_are_appended_until_the_partition_fills() {
+		let a = KeyId::new(Uuid::from_u128(1));
+		let mut flash = FakeNorFlash::new(64);
+		let mut stats = KeyStats::default();
+
+		assert_eq!(
+			load_key_stats_from_flash(&mut flash).await,
+			Ok(KeyStats::default())
+		);
+
+		// each record is 4 bytes of header, 2 of count and 20 per key
+		for presses in 1..=3 {
+			stats.count_press(a);
+			save_key_stats_to_flash(&mut flash, &stats).await.unwrap();
+			let loaded = load_key_stats_from_flash(&mut flash).await.unwrap();
+			assert_eq!(loaded.presses(a), presses);
+		}
+		assert_eq!(flash.erases, 1);
+	}
+
+	#[tokio::test]
+	async fn torn_key_stats_record_falls_back_to_the_previous_one() {
+		let a = KeyId::new(Uuid::from_u128(1));
+		let mut flash = FakeNorFlash::new(128);
+		let mut stats = KeyStats::default();
+		stats.count_press(a);
+		save_key_stats_to_flash(&mut flash, &stats).await.unwrap();
+		stats.count_press(a);
+		save_key_stats_to_flash(&mut flash, &stats).await.unwrap();
+
+		// corrupt the second record
+		flash.data[26 + 10] ^= 0xFF;
+		let loaded = load_key_stats_from_flash(&mut flash).await.unwrap();
+		assert_eq!(loaded.presses(a), 1);
+
+		// the next save can't append over it, so it starts the partition over
+		stats.count_press(a);
+		save_key_stats_to_flash(&mut flash, &stats).await.unwrap();
+		let loaded = load_key_stats_from_flash(&mut flash).await.unwrap();
+		assert_eq!(loaded.presses(a), 3);
+		assert_eq!(flash.erases, 1);
+	}
+
+	#[tokio::test]
+	async fn only_unsaved_settings_load_as_defaults() {
+		// any `Readable + Default` stands in for the firmware's settings
+		let mut flash = FakeNorFlash::new(8);
+		assert_eq!(
+			load_settings_from_flash::<_, KeyStats>(&mut flash).await,
+			Ok(KeyStats::default())
+		);
+
+		flash.data[..2].copy_from_slice(&16u16.to_le_bytes());
+		assert_eq!(
+			load_settings_from_flash::<_, KeyStats>(&mut flash).await,
+			Err("Settings length exceeds flash partition")
+		);
+	}
+
+	#[tokio::test]
+	async fn erase_at_least_erases_one_block_at_a_time() {
+		let mut flash = FakeNorFlash::new(8);
+		flash.data.fill(0);
+		let mut progress = Vec::new();
+
+		flash
+			.erase_at_least_with_progress(3, |erased, total| progress.push((erased, total)))
+			.await
+			.unwrap();
+
+		assert_eq!(flash.erases, 3);
+		assert_eq!(progress, [(1, 3), (2, 3), (3, 3)]);
+		assert_eq!(&flash.data[..4], &[0xFF, 0xFF, 0xFF, 0]);
+	}
+
+	#[tokio::test]
+	async fn crash_report_is_saved_and_cleared() {
+		let mut flash = FakeNorFlash::new(64);
+		assert_eq!(load_crash_report_from_flash(&mut flash).await, Ok(None));
+
+		let report = CrashReport {
+			uptime_ms: 5000,
+			core: 1,
+			stack_pointer: 0x2004_0F00,
+			message: "panicked at src/tasks.rs:10:5".into(),
+		};
+		save_crash_report_to_flash(&mut flash, &report)
+			.await
+			.unwrap();
+		assert_eq!(
+			load_crash_report_from_flash(&mut flash).await,
+			Ok(Some(report))
+		);
+
+		clear_crash_report_in_flash(&mut flash).await.unwrap();
+		assert_eq!(load_crash_report_from_flash(&mut flash).await, Ok(None));
+	}
+
+	#[tokio::test]
+	async fn lock_pin_is_saved_and_cleared() {
+		let mut flash = FakeNorFlash::new(64);
+		assert_eq!(load_lock_pin_from_flash(&flash), Ok(None));
+
+		save_lock_pin_to_flash(&mut flash, b"1234").await.unwrap();
+		assert_eq!(load_lock_pin_from_flash(&flash), Ok(Some(&b"1234"[..])));
+
+		// a torn write is reported, never mistaken for an unlocked device
+		flash.data[5] = 0;
+		assert!(load_lock_pin_from_flash(&flash).is_err());
+
+		clear_lock_pin_in_flash(&mut flash).await.unwrap();
+		assert_eq!(load_lock_pin_from_flash(&flash), Ok(None));
+	}
 }
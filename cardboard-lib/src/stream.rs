@@ -1,16 +1,60 @@
 use crate::serialize::{Readable, Writeable};
+use crate::time::{Clock, Duration, with_deadline};
 use alloc::string::String;
-use alloc::vec;
 use alloc::vec::Vec;
-use defmt::error;
 use uuid::Uuid;
 
 pub trait ReadAsync {
 	async fn read_exact(&mut self, to_fill: &mut [u8]) -> Result<(), &'static str>;
+
+	/// Most bytes left to read, if the reader knows. Collection reads use it to bound how much
+	/// they reserve for a length prefix.
+	fn remaining_hint(&self) -> Option<usize> {
+		None
+	}
+
+	/// Reads whatever is available, at least one byte unless `buf` is empty.
+	async fn read_up_to(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+		// readers that don't know what they have buffered go a byte at a time
+		if buf.is_empty() {
+			return Ok(0);
+		}
+		self.read_exact(&mut buf[..1]).await?;
+		Ok(1)
+	}
+
+	/// `read_exact`, giving up once `timeout` has passed on `clock`. Bytes read before the
+	/// deadline are kept in `to_fill`, but a framed source may lose a partly read frame, so treat
+	/// a timeout as a failed transfer.
+	async fn read_exact_timeout(
+		&mut self,
+		to_fill: &mut [u8],
+		clock: &impl Clock,
+		timeout: Duration,
+	) -> Result<(), &'static str> {
+		let deadline = clock.now() + timeout;
+		let mut filled = 0;
+		while filled < to_fill.len() {
+			filled += with_deadline(clock, deadline, self.read_up_to(&mut to_fill[filled..]))
+				.await
+				.ok_or("Read timeout")??;
+		}
+		Ok(())
+	}
+
+	/// Whether collection and string length prefixes are LEB128 varints rather than fixed width.
+	fn varint_lengths(&self) -> bool {
+		false
+	}
 }
 
 pub trait WriteAsync {
 	async fn write_exact(&mut self, data: &[u8]) -> Result<(), &'static str>;
+
+	/// Whether collection and string length prefixes are written as LEB128 varints.
+	fn varint_lengths(&self) -> bool {
+		false
+	}
 }
 
 pub trait ReadAsyncExt: ReadAsync {
@@ -19,6 +63,17 @@ pub trait ReadAsyncExt: ReadAsync {
 	async fn read_u16(&mut self) -> Option<u16>;
 	async fn read_u32(&mut self) -> Option<u32>;
 	async fn read_u64(&mut self) -> Option<u64>;
+	async fn read_i8(&mut self) -> Option<i8>;
+	async fn read_i16(&mut self) -> Option<i16>;
+	async fn read_i32(&mut self) -> Option<i32>;
+	async fn read_i64(&mut self) -> Option<i64>;
+	async fn read_f32(&mut self) -> Option<f32>;
+	async fn read_varint_u32(&mut self) -> Option<u32>;
+	async fn read_varint_u64(&mut self) -> Option<u64>;
+	/// A length prefix, fixed width or a varint no larger than the fixed width could hold.
+	async fn read_length_u8(&mut self) -> Option<usize>;
+	async fn read_length_u16(&mut self) -> Option<usize>;
+	async fn read_length_u32(&mut self) -> Option<usize>;
 
 	async fn read_utf8<'a>(&mut self, buf: &'a mut [u8]) -> Option<&'a str>;
 
@@ -38,6 +93,16 @@ pub trait WriteAsyncExt: WriteAsync {
 	async fn write_u16(&mut self, value: u16) -> Result<(), &'static str>;
 	async fn write_u32(&mut self, value: u32) -> Result<(), &'static str>;
 	async fn write_u64(&mut self, value: u64) -> Result<(), &'static str>;
+	async fn write_i8(&mut self, value: i8) -> Result<(), &'static str>;
+	async fn write_i16(&mut self, value: i16) -> Result<(), &'static str>;
+	async fn write_i32(&mut self, value: i32) -> Result<(), &'static str>;
+	async fn write_i64(&mut self, value: i64) -> Result<(), &'static str>;
+	async fn write_f32(&mut self, value: f32) -> Result<(), &'static str>;
+	async fn write_varint_u32(&mut self, value: u32) -> Result<(), &'static str>;
+	async fn write_varint_u64(&mut self, value: u64) -> Result<(), &'static str>;
+	async fn write_length_u8(&mut self, len: usize) -> Result<(), &'static str>;
+	async fn write_length_u16(&mut self, len: usize) -> Result<(), &'static str>;
+	async fn write_length_u32(&mut self, len: usize) -> Result<(), &'static str>;
 
 	async fn write_utf8(&mut self, value: &str) -> Result<(), &'static str>;
 	async fn write_uuid(&mut self, value: Uuid) -> Result<(), &'static str>;
@@ -80,6 +145,83 @@ impl<T: ReadAsync> ReadAsyncExt for T {
 		Some(u64::from_le_bytes(buf))
 	}
 
+	async fn read_i8(&mut self) -> Option<i8> {
+		let mut buf = [0; 1];
+		self.read_exact(&mut buf).await.ok()?;
+		Some(i8::from_le_bytes(buf))
+	}
+
+	async fn read_i16(&mut self) -> Option<i16> {
+		let mut buf = [0; 2];
+		self.read_exact(&mut buf).await.ok()?;
+		Some(i16::from_le_bytes(buf))
+	}
+
+	async fn read_i32(&mut self) -> Option<i32> {
+		let mut buf = [0; 4];
+		self.read_exact(&mut buf).await.ok()?;
+		Some(i32::from_le_bytes(buf))
+	}
+
+	async fn read_i64(&mut self) -> Option<i64> {
+		let mut buf = [0; 8];
+		self.read_exact(&mut buf).await.ok()?;
+		Some(i64::from_le_bytes(buf))
+	}
+
+	async fn read_f32(&mut self) -> Option<f32> {
+		let mut buf = [0; 4];
+		self.read_exact(&mut buf).await.ok()?;
+		Some(f32::from_le_bytes(buf))
+	}
+
+	async fn read_varint_u32(&mut self) -> Option<u32> {
+		u32::try_from(self.read_varint_u64().await?).ok()
+	}
+
+	async fn read_varint_u64(&mut self) -> Option<u64> {
+		let mut value = 0u64;
+		for shift in (0..u64::BITS).step_by(7) {
+			let byte = self.read_u8().await?;
+			let bits = (byte & 0x7F) as u64;
+			// the tenth byte only has room for the top bit
+			if bits << shift >> shift != bits {
+				return None;
+			}
+			value |= bits << shift;
+			if byte & 0x80 == 0 {
+				return Some(value);
+			}
+		}
+		None
+	}
+
+	async fn read_length_u8(&mut self) -> Option<usize> {
+		if self.varint_lengths() {
+			let len = self.read_varint_u32().await?;
+			(len <= u8::MAX as u32).then_some(len as usize)
+		} else {
+			Some(self.read_u8().await? as usize)
+		}
+	}
+
+	async fn read_length_u16(&mut self) -> Option<usize> {
+		if self.varint_lengths() {
+			let len = self.read_varint_u32().await?;
+			(len <= u16::MAX as u32).then_some(len as usize)
+		} else {
+			Some(self.read_u16().await? as usize)
+		}
+	}
+
+	async fn read_length_u32(&mut self) -> Option<usize> {
+		if self.varint_lengths() {
+			Some(self.read_varint_u32().await? as usize)
+		} else {
+			Some(self.read_u32().await? as usize)
+		}
+	}
+
 	async fn read_utf8<'a>(&mut self, buf: &'a mut [u8]) -> Option<&'a str> {
 		self.read_exact(buf).await.ok()?;
 		core::str::from_utf8(buf).ok()
@@ -92,8 +234,8 @@ impl<T: ReadAsync> ReadAsyncExt for T {
 	}
 
 	async fn read_collection_u8<R: Readable>(&mut self) -> Option<Vec<R>> {
-		let num_items = self.read_u8().await? as usize;
-		let mut items = Vec::with_capacity(num_items);
+		let num_items = self.read_length_u8().await?;
+		let mut items = try_vec_with_capacity(collection_capacity(self, num_items))?;
 		for _ in 0..num_items {
 			let item = match R::read_from(self).await {
 				Ok(item) => item,
@@ -108,8 +250,8 @@ impl<T: ReadAsync> ReadAsyncExt for T {
 	}
 
 	async fn read_collection_u16<R: Readable>(&mut self) -> Option<Vec<R>> {
-		let num_items = self.read_u16().await? as usize;
-		let mut items = Vec::with_capacity(num_items);
+		let num_items = self.read_length_u16().await?;
+		let mut items = try_vec_with_capacity(collection_capacity(self, num_items))?;
 		for _ in 0..num_items {
 			let item = R::read_from(self).await.ok()?;
 			items.push(item);
@@ -118,8 +260,8 @@ impl<T: ReadAsync> ReadAsyncExt for T {
 	}
 
 	async fn read_collection_u32<R: Readable>(&mut self) -> Option<Vec<R>> {
-		let num_items = self.read_u32().await? as usize;
-		let mut items = Vec::with_capacity(num_items);
+		let num_items = self.read_length_u32().await?;
+		let mut items = try_vec_with_capacity(collection_capacity(self, num_items))?;
 		for _ in 0..num_items {
 			let item = R::read_from(self).await.ok()?;
 			items.push(item);
@@ -128,8 +270,9 @@ impl<T: ReadAsync> ReadAsyncExt for T {
 	}
 
 	async fn read_string_u8(&mut self) -> Option<String> {
-		let length = self.read_u8().await?;
-		let mut buf = vec![0; length as usize];
+		let length = self.read_length_u8().await?;
+		let mut buf = try_vec_with_capacity(length)?;
+		buf.resize(length, 0);
 		self.read_exact(&mut buf).await.ok()?;
 		let str = String::from_utf8(buf).ok()?;
 		Some(str)
@@ -157,6 +300,26 @@ impl<T: ReadAsync> ReadAsyncExt for T {
 	}
 }
 
+/// Collection lengths come straight off the wire, so allocate fallibly: a corrupt or oversized
+/// profile should fail to load, not take the keyboard down with an out-of-memory abort.
+pub(crate) fn try_vec_with_capacity<T>(capacity: usize) -> Option<Vec<T>> {
+	let mut items = Vec::new();
+	if items.try_reserve_exact(capacity).is_err() {
+		error!("Out of memory reading {} items", capacity);
+		return None;
+	}
+	Some(items)
+}
+
+/// Every item takes at least a byte, so a length prefix claiming more items than there are
+/// bytes left is bogus; reserve for what could actually be there and let the read fail.
+pub(crate) fn collection_capacity<R: ReadAsync>(reader: &R, num_items: usize) -> usize {
+	match reader.remaining_hint() {
+		Some(remaining) => num_items.min(remaining),
+		None => num_items,
+	}
+}
+
 impl Readable for u8 {
 	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
 	where
@@ -189,6 +352,78 @@ impl<T: WriteAsync> WriteAsyncExt for T {
 		self.write_exact(&data).await
 	}
 
+	async fn write_i8(&mut self, value: i8) -> Result<(), &'static str> {
+		self.write_exact(&value.to_le_bytes()).await
+	}
+
+	async fn write_i16(&mut self, value: i16) -> Result<(), &'static str> {
+		let data: [u8; 2] = value.to_le_bytes();
+		self.write_exact(&data).await
+	}
+
+	async fn write_i32(&mut self, value: i32) -> Result<(), &'static str> {
+		let data: [u8; 4] = value.to_le_bytes();
+		self.write_exact(&data).await
+	}
+
+	async fn write_i64(&mut self, value: i64) -> Result<(), &'static str> {
+		let data: [u8; 8] = value.to_le_bytes();
+		self.write_exact(&data).await
+	}
+
+	async fn write_f32(&mut self, value: f32) -> Result<(), &'static str> {
+		let data: [u8; 4] = value.to_le_bytes();
+		self.write_exact(&data).await
+	}
+
+	async fn write_varint_u32(&mut self, value: u32) -> Result<(), &'static str> {
+		self.write_varint_u64(value as u64).await
+	}
+
+	async fn write_varint_u64(&mut self, mut value: u64) -> Result<(), &'static str> {
+		let mut buf = [0u8; 10];
+		let mut len = 0;
+		loop {
+			let byte = (value & 0x7F) as u8;
+			value >>= 7;
+			if value == 0 {
+				buf[len] = byte;
+				len += 1;
+				break;
+			}
+			buf[len] = byte | 0x80;
+			len += 1;
+		}
+		self.write_exact(&buf[..len]).await
+	}
+
+	async fn write_length_u8(&mut self, len: usize) -> Result<(), &'static str> {
+		let len = u8::try_from(len).map_err(|_| "Length too long")?;
+		if self.varint_lengths() {
+			self.write_varint_u32(len as u32).await
+		} else {
+			self.write_u8(len).await
+		}
+	}
+
+	async fn write_length_u16(&mut self, len: usize) -> Result<(), &'static str> {
+		let len = u16::try_from(len).map_err(|_| "Length too long")?;
+		if self.varint_lengths() {
+			self.write_varint_u32(len as u32).await
+		} else {
+			self.write_u16(len).await
+		}
+	}
+
+	async fn write_length_u32(&mut self, len: usize) -> Result<(), &'static str> {
+		let len = u32::try_from(len).map_err(|_| "Length too long")?;
+		if self.varint_lengths() {
+			self.write_varint_u32(len).await
+		} else {
+			self.write_u32(len).await
+		}
+	}
+
 	async fn write_utf8(&mut self, value: &str) -> Result<(), &'static str> {
 		self.write_exact(value.as_bytes()).await
 	}
@@ -199,7 +434,7 @@ impl<T: WriteAsync> WriteAsyncExt for T {
 	}
 
 	async fn write_collection_u8<R: Writeable>(&mut self, value: &[R]) -> Result<(), &'static str> {
-		self.write_u8(value.len() as u8).await?;
+		self.write_length_u8(value.len()).await?;
 		for item in value {
 			item.write_to(self).await?;
 		}
@@ -210,7 +445,7 @@ impl<T: WriteAsync> WriteAsyncExt for T {
 		&mut self,
 		value: &[R],
 	) -> Result<(), &'static str> {
-		self.write_u16(value.len() as u16).await?;
+		self.write_length_u16(value.len()).await?;
 		for item in value {
 			item.write_to(self).await?;
 		}
@@ -221,7 +456,7 @@ impl<T: WriteAsync> WriteAsyncExt for T {
 		&mut self,
 		value: &[R],
 	) -> Result<(), &'static str> {
-		self.write_u32(value.len() as u32).await?;
+		self.write_length_u32(value.len()).await?;
 		for item in value {
 			item.write_to(self).await?;
 		}
@@ -230,19 +465,19 @@ impl<T: WriteAsync> WriteAsyncExt for T {
 
 	async fn write_string_u8(&mut self, value: &str) -> Result<(), &'static str> {
 		let bytes = value.as_bytes();
-		self.write_u8(bytes.len() as u8).await?;
+		self.write_length_u8(bytes.len()).await?;
 		self.write_exact(bytes).await
 	}
 
 	async fn write_string_u16(&mut self, value: &str) -> Result<(), &'static str> {
 		let bytes = value.as_bytes();
-		self.write_u16(bytes.len() as u16).await?;
+		self.write_length_u16(bytes.len()).await?;
 		self.write_exact(bytes).await
 	}
 
 	async fn write_string_u32(&mut self, value: &str) -> Result<(), &'static str> {
 		let bytes = value.as_bytes();
-		self.write_u32(bytes.len() as u32).await?;
+		self.write_length_u32(bytes.len()).await?;
 		self.write_exact(bytes).await
 	}
 
@@ -266,6 +501,28 @@ impl ReadAsync for &[u8] {
 		*self = &self[to_fill.len()..];
 		Ok(())
 	}
+
+	async fn read_up_to(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+		if self.is_empty() && !buf.is_empty() {
+			return Err("Not enough data to read");
+		}
+
+		let len = buf.len().min(self.len());
+		buf[..len].copy_from_slice(&self[..len]);
+		*self = &self[len..];
+		Ok(len)
+	}
+
+	fn remaining_hint(&self) -> Option<usize> {
+		Some(self.len())
+	}
+}
+
+impl WriteAsync for Vec<u8> {
+	async fn write_exact(&mut self, data: &[u8]) -> Result<(), &'static str> {
+		self.extend_from_slice(data);
+		Ok(())
+	}
 }
 
 impl<'a> WriteAsync for &'a mut [u8] {
@@ -280,3 +537,168 @@ impl<'a> WriteAsync for &'a mut [u8] {
 		Ok(())
 	}
 }
+
+/// Reads or writes through to another stream, with collection and string lengths as varints.
+pub struct VarintLengths<'a, T>(pub &'a mut T);
+
+impl<T: ReadAsync> ReadAsync for VarintLengths<'_, T> {
+	async fn read_exact(&mut self, to_fill: &mut [u8]) -> Result<(), &'static str> {
+		self.0.read_exact(to_fill).await
+	}
+
+	async fn read_up_to(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+		self.0.read_up_to(buf).await
+	}
+
+	fn remaining_hint(&self) -> Option<usize> {
+		self.0.remaining_hint()
+	}
+
+	fn varint_lengths(&self) -> bool {
+		true
+	}
+}
+
+impl<T: WriteAsync> WriteAsync for VarintLengths<'_, T> {
+	async fn write_exact(&mut self, data: &[u8]) -> Result<(), &'static str> {
+		self.0.write_exact(data).await
+	}
+
+	fn varint_lengths(&self) -> bool {
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use alloc::vec;
+	use fugit::ExtU64;
+
+	#[test]
+	fn oversized_collection_fails_instead_of_aborting() {
+		assert!(try_vec_with_capacity::<u8>(usize::MAX).is_none());
+		assert!(try_vec_with_capacity::<u8>(16).is_some());
+	}
+
+	#[tokio::test]
+	async fn truncated_collection_is_rejected() {
+		let mut data: &[u8] = &[0xFF, 0xFF, 1, 2, 3];
+		assert_eq!(data.read_collection_u16::<u8>().await, None);
+	}
+
+	#[tokio::test]
+	async fn signed_and_float_values_round_trip() {
+		let mut data = Vec::new();
+		data.write_i8(-2).await.unwrap();
+		data.write_i16(-300).await.unwrap();
+		data.write_i32(i32::MIN).await.unwrap();
+		data.write_i64(-1).await.unwrap();
+		data.write_f32(-1.5).await.unwrap();
+		assert_eq!(data[..3], [0xFE, 0xD4, 0xFE]);
+
+		let mut reader = data.as_slice();
+		assert_eq!(reader.read_i8().await, Some(-2));
+		assert_eq!(reader.read_i16().await, Some(-300));
+		assert_eq!(reader.read_i32().await, Some(i32::MIN));
+		assert_eq!(reader.read_i64().await, Some(-1));
+		assert_eq!(reader.read_f32().await, Some(-1.5));
+		assert!(reader.is_empty());
+	}
+
+	#[tokio::test]
+	async fn collection_reserves_no_more_than_the_data_holds() {
+		let mut data: &[u8] = &[0xFF, 0xFF, 0xFF, 0xFF, 1, 2, 3];
+		assert_eq!(collection_capacity(&data, u32::MAX as usize), 7);
+		assert_eq!(data.read_collection_u32::<u8>().await, None);
+	}
+
+	#[tokio::test]
+	async fn varints_round_trip() {
+		let mut data = Vec::new();
+		data.write_varint_u32(0).await.unwrap();
+		data.write_varint_u32(300).await.unwrap();
+		data.write_varint_u64(u64::MAX).await.unwrap();
+		assert_eq!(data[..3], [0x00, 0xAC, 0x02]);
+		assert_eq!(data.len(), 13);
+
+		let mut reader = data.as_slice();
+		assert_eq!(reader.read_varint_u32().await, Some(0));
+		assert_eq!(reader.read_varint_u32().await, Some(300));
+		assert_eq!(reader.read_varint_u64().await, Some(u64::MAX));
+		assert!(reader.is_empty());
+	}
+
+	#[tokio::test]
+	async fn overlong_varint_is_rejected() {
+		let mut data: &[u8] = &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02];
+		assert_eq!(data.read_varint_u64().await, None);
+
+		let mut data: &[u8] = &[0x80, 0x80, 0x80, 0x80, 0x10];
+		assert_eq!(data.read_varint_u32().await, None);
+	}
+
+	#[tokio::test]
+	async fn varint_lengths_keep_the_fixed_width_limit() {
+		let mut data = Vec::new();
+		let mut writer = VarintLengths(&mut data);
+		writer.write_string_u8("hi").await.unwrap();
+		writer.write_collection_u16(&[7u8; 200]).await.unwrap();
+		assert_eq!(data[..3], [2, b'h', b'i']);
+		assert_eq!(data[3..5], [0xC8, 0x01]);
+
+		let mut slice = data.as_slice();
+		let mut reader = VarintLengths(&mut slice);
+		assert_eq!(reader.read_string_u8().await.as_deref(), Some("hi"));
+		assert_eq!(reader.read_collection_u16::<u8>().await, Some(vec![7; 200]));
+
+		let mut slice: &[u8] = &[0x80, 0x02, 0];
+		assert_eq!(VarintLengths(&mut slice).read_string_u8().await, None);
+	}
+
+	// every deadline has already passed
+	struct ExpiredClock;
+
+	impl Clock for ExpiredClock {
+		fn now(&self) -> crate::time::Instant {
+			crate::time::Instant::from_ticks(0)
+		}
+
+		async fn after(&self, _duration: Duration) {}
+
+		async fn at(&self, _instant: crate::time::Instant) {}
+	}
+
+	struct StalledReader;
+
+	impl ReadAsync for StalledReader {
+		async fn read_exact(&mut self, _to_fill: &mut [u8]) -> Result<(), &'static str> {
+			core::future::pending().await
+		}
+	}
+
+	#[tokio::test]
+	async fn read_up_to_returns_what_is_there() {
+		let mut data: &[u8] = &[1, 2, 3];
+		let mut buf = [0; 8];
+		assert_eq!(data.read_up_to(&mut buf).await, Ok(3));
+		assert_eq!(buf[..3], [1, 2, 3]);
+		assert!(data.read_up_to(&mut buf).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn read_exact_timeout_gives_up_on_a_stalled_reader() {
+		let mut buf = [0; 4];
+		let result = StalledReader
+			.read_exact_timeout(&mut buf, &ExpiredClock, 10.millis())
+			.await;
+		assert_eq!(result, Err("Read timeout"));
+
+		// data that is already there wins over the deadline
+		let mut data: &[u8] = &[1, 2, 3, 4];
+		data.read_exact_timeout(&mut buf, &ExpiredClock, 10.millis())
+			.await
+			.unwrap();
+		assert_eq!(buf, [1, 2, 3, 4]);
+	}
+}
@@ -0,0 +1,86 @@
+use core::cell::Cell;
+
+use critical_section::Mutex;
+
+use crate::time::{Duration, Instant};
+
+/// The hardware watchdog, which resets the device unless it's fed in time.
+pub trait HardwareWatchdog {
+	fn feed(&mut self);
+}
+
+/// Proof of life from a task. The supervisor only feeds the watchdog while every task's
+/// heartbeat is fresh, so a hung task resets the device.
+pub struct Heartbeat {
+	pub name: &'static str,
+	timeout: Duration,
+	last: Mutex<Cell<Option<Instant>>>,
+}
+
+impl Heartbeat {
+	pub const fn new(name: &'static str, timeout: Duration) -> Self {
+		Self {
+			name,
+			timeout,
+			last: Mutex::new(Cell::new(None)),
+		}
+	}
+
+	pub fn beat(&self, now: Instant) {
+		critical_section::with(|cs| self.last.borrow(cs).set(Some(now)));
+	}
+
+	/// Whether the task beat within its timeout. One that never beat isn't, so the supervisor
+	/// beats every heartbeat once when it starts.
+	pub fn is_fresh(&self, now: Instant) -> bool {
+		let last = critical_section::with(|cs| self.last.borrow(cs).get());
+		// another core can beat after `now` was taken
+		last.is_some_and(|last| {
+			now.checked_duration_since(last)
+				.is_none_or(|elapsed| elapsed <= self.timeout)
+		})
+	}
+}
+
+/// The first heartbeat that's gone stale, if any.
+pub fn stale_heartbeat<'a>(heartbeats: &[&'a Heartbeat], now: Instant) -> Option<&'a Heartbeat> {
+	heartbeats
+		.iter()
+		.find(|heartbeat| !heartbeat.is_fresh(now))
+		.copied()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use fugit::ExtU64;
+
+	#[test]
+	fn heartbeat_goes_stale_after_its_timeout() {
+		let heartbeat = Heartbeat::new("keypad", 100.millis());
+		let start = Instant::from_ticks(1_000_000);
+		assert!(!heartbeat.is_fresh(start));
+
+		heartbeat.beat(start);
+		assert!(heartbeat.is_fresh(start + 100.millis()));
+		assert!(!heartbeat.is_fresh(start + 101.millis()));
+		// beat from another core after the supervisor read the clock
+		assert!(heartbeat.is_fresh(start - 1.millis()));
+	}
+
+	#[test]
+	fn stale_heartbeat_finds_the_task_that_stopped() {
+		let keypad = Heartbeat::new("keypad", 100.millis());
+		let cmd = Heartbeat::new("cmd", 1.secs());
+		let start = Instant::from_ticks(1_000_000);
+		keypad.beat(start);
+		cmd.beat(start);
+
+		let heartbeats = [&keypad, &cmd];
+		assert!(stale_heartbeat(&heartbeats, start + 50.millis()).is_none());
+
+		cmd.beat(start + 500.millis());
+		let stale = stale_heartbeat(&heartbeats, start + 500.millis());
+		assert_eq!(stale.map(|heartbeat| heartbeat.name), Some("keypad"));
+	}
+}
@@ -1,150 +1,1068 @@
-use crate::command::Command;
+use crate::battery::{BatteryAdc, BatteryConfig, BatteryStatus};
+use crate::budget::MemoryBudget;
+use crate::buzzer::{Buzzer, Tone};
+use crate::command::{Command, check_policy, return_credit};
 use crate::context::{
-	ContextErrorLog, ContextSerialRx, ExternalTagsSignalRx, RebootToBootloader,
-	UpdateProfileSignalRx, VirtualKeySignalRx,
+	ActiveTagsSignalTx, ContextAllocator, ContextAuth, ContextErrorLog, ContextKeyEvents,
+	ContextKeyStats, ContextKeyStatsFlash, ContextLockFlash, ContextMemoryBudgets, ContextSerialRx,
+	ContextSerialTx, ContextSyncMarkers, ContextUsbStats, DisplaySignalRx, DisplaySignalTx,
+	ExternalTagsSignalRx, HapticSignalRx, HapticSignalTx, HostEventSignalRx, HostEventSignalTx,
+	InjectKeySignalRx, KeyEventSignalTx, KeyStatsSignalTx, KeypadErrorSignalRx,
+	KeypadErrorSignalTx, KeypadStatusSignalTx, LightingSignalRx, LightingSignalTx, LowPower,
+	MacroSpeedSignalRx, MatrixScanSignalTx, RebootToBootloader, ToneSignalRx, ToneSignalTx,
+	UpdateProfileSignalRx, VirtualKeyIdSignalRx, VirtualKeySignalRx,
 };
+use crate::display::{DisplayStatus, DisplayWidget, FrameBuffer, I2cBus, OledDisplay};
+use crate::encoder::{EncoderPins, Encoders};
 use crate::error::{Error, ErrorLog};
-use crate::hid::ReportHid;
-use crate::input::{KeyId, KeyState, UpdateMatrix};
-use crate::profile::{ActionEvent, DebugEvent, KeyboardProfile, LayerEvent};
-use crate::serial::SerialDrain;
-use crate::state::KeyboardState;
-use crate::stream::ReadAsyncExt;
-use crate::time::Duration;
+use crate::event::{HostEvents, KeyEvent, send_event};
+use crate::haptic::HapticMotor;
+use crate::hid::{HostLocks, ReportHid};
+use crate::indicator::{BoundIndicator, Indicator, IndicatorStatus};
+use crate::input::{Chord, KeyId, KeyState, KeyboardAction, UpdateMatrix};
+use crate::lighting::{Effects, LedDriver, LedFrame, LightingEffect, LightingEvent, Rgb};
+use crate::lock::SafeMode;
+use crate::module::{ModuleDebounce, ModuleDetect, ModulePort, ModuleStatus};
+use crate::output::{AuxOutputs, OutputPin, PwmOutputs, PwmPin};
+use crate::pointer::{PointerConfig, PointerMotion, PointerTransform, PointingSensor};
+use crate::power::{PowerPolicy, PowerSource, PowerSourceSense, PowerState};
+use crate::profile::{ActionEvent, DebugEvent, KeyboardEvent, KeyboardProfile, MouseEvent};
+use crate::serial::{SerialDrain, SerialEventSender, SerialSession, read_sync_marker};
+use crate::serialize::Writeable;
+use crate::slider::{SliderPosition, SliderSensor, SliderStep, SliderSteps};
+use crate::state::{KeyStats, KeyboardState, KeypadStatus, MacroLimit};
+use crate::stats::LoopTiming;
+use crate::storage::save_key_stats_to_flash;
+use crate::stream::{ReadAsyncExt, WriteAsyncExt};
+use crate::supervisor::{HardwareWatchdog, Heartbeat, stale_heartbeat};
+use crate::time::{Duration, Instant, MissedTickPolicy, Ticker};
 use alloc::boxed::Box;
 use alloc::vec::Vec;
-use defmt::{debug, info, warn};
 use fugit::ExtU64;
 
 pub async fn keypad_task<
 	Clock: crate::time::Clock,
 	Matrix: UpdateMatrix,
 	Report: ReportHid,
-	ProfileChanged: UpdateProfileSignalRx + 'static,
-	ExternalTagsChanged: ExternalTagsSignalRx + 'static,
+	Bootloader: RebootToBootloader + 'static,
+	Power: LowPower + 'static,
+	Ind: Indicator,
+	Out: OutputPin,
+	PwmOut: PwmPin,
+	Enc: EncoderPins,
+	Links: KeypadLinks<VIRTUAL_KEY_BITFIELD_BYTES>,
 	const VIRTUAL_KEY_BITFIELD_BYTES: usize,
-	VirtualKeysChanged: VirtualKeySignalRx<VIRTUAL_KEY_BITFIELD_BYTES> + 'static,
-	Bootloader: RebootToBootloader,
 >(
 	clock: &Clock,
-	mut matrix: Matrix,
 	mut profile: KeyboardProfile,
-	mut hid: Report,
-	profile_changed: &'static ProfileChanged,
-	tags_changed: &'static ExternalTagsChanged,
-	virtual_keys_changed: &'static VirtualKeysChanged,
-	bootloader_key: Option<KeyId>,
-	bootloader: &'static Bootloader,
-	interval: Duration,
+	hardware: KeypadHardware<Matrix, Report, Bootloader, Power, Ind, Out, PwmOut, Enc>,
+	channels: KeypadChannels<Links, VIRTUAL_KEY_BITFIELD_BYTES>,
+	config: KeypadConfig<'_>,
 ) {
+	let KeypadHardware {
+		mut matrix,
+		mut hid,
+		bootloader,
+		power,
+		mut indicators,
+		mut outputs,
+		mut pwm_outputs,
+		mut encoders,
+	} = hardware;
+	let KeypadChannels {
+		profile_changed,
+		tags_changed,
+		virtual_keys_changed,
+		virtual_keys_by_id,
+		macro_speed_changed,
+		power_state,
+		host_events,
+		key_events,
+		matrix_scan,
+		injected_keys,
+		active_tags,
+		keypad_status,
+		key_stats,
+		lighting,
+		tones,
+		haptics,
+		pointer_motion,
+		slider_position,
+		modules,
+		safe_mode,
+		indicator_status,
+		host_locks,
+		display,
+		macro_budget,
+		errors,
+		heartbeat,
+	} = channels;
+	let KeypadConfig {
+		boot_keys,
+		mut bootloader_chord,
+		idle_timeout,
+		saved_key_stats,
+		key_stats_save_interval,
+		macro_limit,
+		interval,
+	} = config;
+
 	info!("Keypad task started.");
 
+	let mut key_actions = Vec::with_capacity(Matrix::SIZE);
+
+	// check for boot keys held at startup
+	if !boot_keys.is_empty() {
+		matrix.update(clock.now(), 0.millis(), &mut key_actions);
+		let held = boot_keys
+			.iter()
+			.find(|boot_key| key_actions.iter().any(|k| k.key_id == boot_key.key));
+		match held.map(|boot_key| boot_key.action) {
+			Some(BootAction::Bootloader) => {
+				info!("Rebooting into bootloader");
+				bootloader.reboot_to_bootloader();
+			}
+			Some(BootAction::SafeMode) => {
+				warn!("Safe mode key held, ignoring stored profile");
+				profile = KeyboardProfile::default();
+				safe_mode.set();
+				errors.report_error(Error {
+					timestamp: clock.now(),
+					message: "Started in safe mode",
+				});
+			}
+			None => {}
+		}
+	}
+
 	let mut state = KeyboardState::from(&profile);
+	state.set_macro_budget(macro_budget);
+	state.set_macro_limit(macro_limit);
+	state.seed_rng(clock.now().ticks() as u32);
+	state.set_key_stats(saved_key_stats);
 
-	let mut key_actions = Vec::with_capacity(Matrix::SIZE);
+	// set over serial, so it outlives profile changes
+	let mut macro_speed = 100;
 
 	let mut previous_tick = clock.now();
+	let mut ticker = Ticker::new(previous_tick, interval, MissedTickPolicy::Skip);
 
-	// check if bootloader key is pressed at startup
-	if let Some(bootloader_key) = bootloader_key {
-		matrix.update(0.millis(), &mut key_actions);
-		if key_actions.iter().any(|k| k.key_id == bootloader_key) {
-			info!("Rebooting into bootloader");
-			bootloader.reboot_to_bootloader();
-		}
-	}
+	let mut held_keys: u16 = 0;
+	let mut unsaved_presses = false;
+	let mut idle_for: Duration = 0.millis();
+	let mut asleep = false;
+	let mut key_stats_saved_at = previous_tick;
+	let mut timing = LoopTiming::new();
+	// the display is only sent a new status when the profile, tags, locks or counted presses
+	// change
+	let mut display_stale = true;
+	let mut display_locks = host_locks.get();
+	let mut slider = SliderSteps::new();
+	let mut slider_taps = Vec::new();
 
 	loop {
 		// check for profile change
 		if let Some(new_profile) = profile_changed.try_get_changed_profile() {
-			// hang onto the old external tags to apply them to the new profile
+			// hang onto the old external tags and key stats to apply them to the new profile
+			let old_key_stats = state.take_key_stats();
 			let old_external_tags = state.to_external_tags();
 			profile = new_profile;
 			state = KeyboardState::from(&profile);
+			state.set_macro_budget(macro_budget);
+			state.set_macro_limit(macro_limit);
+			state.seed_rng(clock.now().ticks() as u32);
+			state.set_speed_percent(macro_speed);
 			state.set_external_tags(old_external_tags);
+			state.set_key_stats(old_key_stats);
 
 			hid.reset();
+			encoders.reset();
+			slider.reset();
+			slider_taps.clear();
+			lighting.send_lighting_event(LightingEvent::Fill(Rgb::OFF));
+			display_stale = true;
 			info!("Profile updated");
+			host_events.notify_host(HostEvents::PROFILE_CHANGED);
 		}
 
 		// check for external tags change
 		if let Some(tags) = tags_changed.try_get_external_tags() {
 			state.set_external_tags(tags);
+			host_events.notify_host(HostEvents::TAGS_CHANGED);
+			display_stale = true;
+		}
+
+		// the matrix already picked up the change, the host just needs telling
+		if modules.take_changed() {
+			host_events.notify_host(HostEvents::MODULES_CHANGED);
 		}
 
 		// check for virtual keys
 		if let Some(virtual_keys) = virtual_keys_changed.try_get_virtual_keys() {
 			state.set_virtual_key_state(&virtual_keys);
 		}
+		while let Some(action) = virtual_keys_by_id.try_take_virtual_key() {
+			let pressed = action.action == KeyState::Pressed;
+			if !state.set_virtual_key_by_id(action.id, pressed) {
+				warn!("Virtual key not found in profile");
+			}
+		}
 
-		let next_tick = previous_tick + interval;
-		clock.at(next_tick).await;
-		let now = clock.now();
+		// check for macro speed change
+		if let Some(speed) = macro_speed_changed.try_get_macro_speed() {
+			macro_speed = speed;
+			state.set_speed_percent(macro_speed);
+		}
+
+		// check for raw matrix scan request
+		if matrix_scan.matrix_scan_requested() {
+			matrix_scan.send_matrix_scan(matrix.scan_raw());
+		}
+
+		// check for active tags request
+		if active_tags.active_tags_requested() {
+			active_tags.send_active_tags(state.active_tags());
+		}
+
+		// check for status request
+		if keypad_status.keypad_status_requested() {
+			keypad_status.send_keypad_status(KeypadStatus {
+				profile_name: profile.name.clone(),
+				held_keys,
+				timing: timing.clone(),
+			});
+		}
+
+		// check for key stats request
+		if key_stats.key_stats_requested() {
+			key_stats.send_key_stats(state.key_stats().clone());
+		}
+
+		// the board's power policy, once it has run, overrides the scan interval and idle timeout
+		let power = power_state.get();
+		let scan_interval = power.map_or(interval, |mode| mode.scan_interval);
+		let sleep_after = power.map_or(idle_timeout, |mode| mode.idle_timeout);
+
+		ticker.set_interval(scan_interval);
+		let now = if asleep {
+			// wake up now and then anyway so requests from the host still get answered
+			let _ = clock
+				.timeout(SLEEP_WAKE_INTERVAL_MS.millis(), matrix.wait_for_key())
+				.await;
+			let now = clock.now();
+			ticker.reset(now);
+			now
+		} else {
+			ticker.next(clock).await
+		};
 		let dt = now - previous_tick;
 		previous_tick = now;
+		timing.record(dt, scan_interval);
+		heartbeat.beat(now);
 
 		// read key matrix and update macro state with results
 		key_actions.clear();
-		matrix.update(dt, &mut key_actions);
-		for key in key_actions.iter() {
-			match key.action {
+		matrix.update(now, dt, &mut key_actions);
+		// synthetic presses from the host are handled exactly like physical ones, except that
+		// they aren't counted as held
+		let physical_actions = key_actions.len();
+		while let Some(action) = injected_keys.try_take_injected_key() {
+			key_actions.push(action);
+		}
+		if let Some(chord) = bootloader_chord.as_mut() {
+			if chord.update(&key_actions, dt) {
+				info!("Bootloader chord held, rebooting into bootloader");
+				bootloader.reboot_to_bootloader();
+			}
+		}
+		apply_key_actions(
+			&mut state,
+			&key_actions,
+			physical_actions,
+			now,
+			dt,
+			key_events,
+			|key, physical| match key.action {
 				KeyState::Pressed => {
-					state.press_key(key.key_id);
+					// only physical presses are counted, so only they need saving or redrawing
+					if physical {
+						held_keys = held_keys.saturating_add(1);
+						unsaved_presses = true;
+						display_stale |= profile
+							.display
+							.iter()
+							.any(|widget| matches!(widget, DisplayWidget::KeyCounter { .. }));
+					}
+					for mapping in profile.leds.iter().filter(|m| m.key == key.key_id) {
+						lighting.send_lighting_event(LightingEvent::Pressed { led: mapping.led });
+					}
 					info!("Key pressed: {:?}", key.key_id);
 				}
 				KeyState::Released => {
-					state.release_key(key.key_id);
+					if physical {
+						held_keys = held_keys.saturating_sub(1);
+					}
 				}
-			}
+			},
+		);
+
+		// encoders scroll without going through the macro engine, so turning one adds no latency
+		let encoder_turned =
+			encoders.scroll(dt, &profile.encoders, state.active_layers(), |scroll| {
+				hid.report_mouse(&MouseEvent::Scroll(scroll))
+			});
+
+		let (motion_x, motion_y) = pointer_motion.take();
+		let pointer_moved = (motion_x, motion_y) != (0, 0);
+		if pointer_moved {
+			hid.report_motion(motion_x, motion_y);
 		}
 
-		// tick macros and process events
-		let mut layer_events: Vec<&LayerEvent> = Vec::new();
+		// taps are released a tick after they're pressed, so everything sampled per tick sees
+		// the key down
+		for id in slider_taps.drain(..) {
+			state.set_virtual_key_by_id(id, false);
+		}
+		let slider_touched = slider.update(
+			slider_position.get(),
+			&profile.sliders,
+			state.active_layers(),
+			|step| match step {
+				SliderStep::Scroll(scroll) => hid.report_mouse(&MouseEvent::Scroll(scroll)),
+				SliderStep::Consumer(event) => hid.report_consumer(&event),
+				SliderStep::TapVirtualKey(id) => {
+					if !state.set_virtual_key_by_id(id, true) {
+						warn!("Slider virtual key not found in profile");
+						return;
+					}
+					slider_taps.push(id);
+				}
+			},
+		);
+
+		// saved at most once per interval, and only after a press, to spare the flash
+		if unsaved_presses && now - key_stats_saved_at >= key_stats_save_interval {
+			key_stats.save_key_stats(state.key_stats().clone());
+			key_stats_saved_at = now;
+			unsaved_presses = false;
+		}
+
+		if state.take_macro_overflow() {
+			errors.report_error(Error {
+				timestamp: now,
+				message: "Too many macros running",
+			});
+		}
+
+		// tick macros and process events; events are borrowed from the profile, so nothing in
+		// here touches the heap
 		state.tick(dt, |event| match event {
 			ActionEvent::DebugAction(event) => match event {
 				DebugEvent::Log(msg) => {
 					info!("Debug event: {:?}", msg.as_str())
 				}
 			},
-			ActionEvent::None => {}
+			// handled by the macro engine
+			ActionEvent::None
+			| ActionEvent::Text(_)
+			| ActionEvent::RunMacro(_)
+			| ActionEvent::Layer(_) => {}
+			ActionEvent::Lighting(event) => {
+				event.resolve(&profile.leds, |event| lighting.send_lighting_event(event))
+			}
+			ActionEvent::Tone {
+				freq_hz,
+				duration_ms,
+			} => tones.send_tone(Tone {
+				freq_hz: *freq_hz,
+				duration_ms: *duration_ms,
+			}),
+			ActionEvent::Haptic(pattern) => haptics.send_haptic(*pattern),
+			ActionEvent::Output(event) => outputs.apply(event),
+			ActionEvent::Pwm(event) => pwm_outputs.apply(event),
 			ActionEvent::Keyboard(event) => hid.report_keyboard(event),
 			ActionEvent::Mouse(event) => hid.report_mouse(event),
 			ActionEvent::ConsumerControl(event) => {
 				hid.report_consumer(event);
 			}
-			ActionEvent::Layer(event) => {
-				layer_events.push(event);
-			}
 		});
+		state.take_cut_keys(|key| hid.report_keyboard(&KeyboardEvent::KeyUp(key)));
 
-		// process layer events after tick completes (can't borrow state during tick)
-		for event in layer_events {
-			match event {
-				LayerEvent::Clear(layer) => state.remove_internal_tag(layer),
-				LayerEvent::Set(layer) => state.add_internal_tag(layer),
-			}
+		outputs.advance(dt);
+
+		if state.take_tags_changed() {
+			host_events.notify_host(HostEvents::TAGS_CHANGED);
+			display_stale = true;
+		}
+
+		state.take_backlight(|key, color| {
+			LightingEvent::SetKey { key, color }
+				.resolve(&profile.leds, |event| lighting.send_lighting_event(event))
+		});
+		let locks = host_locks.get();
+		if display_stale || locks != display_locks {
+			let tags = state.active_tags();
+			let widgets = if profile.display.is_empty() {
+				DisplayWidget::default_layout()
+			} else {
+				profile.display.clone()
+			};
+			let key_counts = widgets
+				.iter()
+				.filter_map(|widget| match widget {
+					DisplayWidget::KeyCounter { key, .. } => Some(*key),
+					_ => None,
+				})
+				.map(|key| {
+					let stats = state.key_stats();
+					(key, key.map_or(stats.total(), |key| stats.presses(key)))
+				})
+				.collect();
+			display.send_display_status(DisplayStatus {
+				profile_name: profile.name.clone(),
+				tags: tags.internal.into_iter().chain(tags.external).collect(),
+				locks,
+				widgets,
+				key_counts,
+			});
+			display_stale = false;
+			display_locks = locks;
+		}
+
+		for indicator in indicators.iter_mut() {
+			indicator
+				.update(indicator_status.is_met(indicator.condition, |tag| state.has_tag(tag)));
 		}
 
 		hid.flush();
+
+		if !key_actions.is_empty()
+			|| encoder_turned
+			|| pointer_moved
+			|| slider_touched
+			|| held_keys > 0
+			|| !state.is_idle()
+			|| outputs.is_pulsing()
+		{
+			idle_for = 0.millis();
+			if asleep {
+				power.exit_low_power();
+				asleep = false;
+				info!("Woke up");
+			}
+		} else if !asleep {
+			idle_for += dt;
+			if sleep_after.is_some_and(|timeout| idle_for >= timeout) {
+				info!("Idle, going to sleep");
+				power.enter_low_power();
+				asleep = true;
+			}
+		}
+	}
+}
+
+/// Feeds a tick's key changes to the macro engine, then hands each to `changed` along with
+/// whether it's physical. Only the first `physical` changes came off the matrix; the rest were
+/// injected by the host, so they play their macros like any other key but aren't streamed to the
+/// host or counted in the key stats.
+fn apply_key_actions<Events: KeyEventSignalTx>(
+	state: &mut KeyboardState,
+	key_actions: &[KeyboardAction],
+	physical: usize,
+	now: Instant,
+	dt: Duration,
+	key_events: &Events,
+	mut changed: impl FnMut(&KeyboardAction, bool),
+) {
+	let stream_key_events = key_events.key_events_subscribed();
+	for (i, key) in key_actions.iter().enumerate() {
+		let physical = i < physical;
+		// how long before this tick the change was captured, so macros play from then
+		let age = now
+			.checked_duration_since(key.timestamp)
+			.map_or(0.millis(), |age| age.min(dt));
+		if physical && stream_key_events {
+			key_events.send_key_event(KeyEvent {
+				key_id: key.key_id,
+				state: key.action,
+				timestamp: key.timestamp,
+			});
+		}
+
+		match key.action {
+			KeyState::Pressed if physical => state.press_key_captured(key.key_id, age),
+			KeyState::Pressed => state.press_injected_key(key.key_id, age),
+			KeyState::Released => state.release_key_captured(key.key_id, age),
+		}
+		changed(key, physical);
 	}
 }
 
-pub async fn cmd_task<Clock: crate::time::Clock, Context: ContextErrorLog + ContextSerialRx>(
+/// Drives the LED chain from lighting events and the current effect, only writing to it when a
+/// color may have changed.
+pub async fn lighting_task<
+	Clock: crate::time::Clock,
+	Driver: LedDriver,
+	Events: LightingSignalRx + 'static,
+>(
+	clock: &Clock,
+	mut driver: Driver,
+	events: &'static Events,
+	led_count: usize,
+	effect: LightingEffect,
+	effect_speed_percent: u16,
+	power_state: &'static PowerState,
+	interval: Duration,
+) {
+	info!("Lighting task started.");
+
+	let mut frame = LedFrame::new(led_count);
+	let mut effects = Effects::new(led_count, effect, effect_speed_percent);
+	let mut output = Vec::with_capacity(led_count);
+	effects.render(&frame, &mut output);
+	driver.write(&output).await;
+	let mut previous_tick = clock.now();
+	let mut brightness_percent = 100;
+
+	loop {
+		let now = clock.now();
+		let dt = now - previous_tick;
+		previous_tick = now;
+
+		let mut changed = false;
+		while let Some(event) = events.try_take_lighting_event() {
+			changed |= frame.apply(&event);
+			changed |= effects.apply(&event);
+		}
+		// checked before advancing, so the last frame of a fade is still drawn
+		changed |= effects.is_animated();
+		effects.advance(dt);
+		let brightness = power_state
+			.get()
+			.map_or(100, |mode| mode.brightness_percent.min(100));
+		changed |= brightness != brightness_percent;
+		brightness_percent = brightness;
+		if changed {
+			effects.render(&frame, &mut output);
+			if brightness_percent < 100 {
+				let level = (brightness_percent as u16 * u8::MAX as u16 / 100) as u8;
+				output
+					.iter_mut()
+					.for_each(|color| *color = color.scale(level));
+			}
+			driver.write(&output).await;
+		}
+
+		clock.after(interval).await;
+	}
+}
+
+/// Plays tones from macros one after another, checking for more every interval.
+pub async fn buzzer_task<Clock: crate::time::Clock, Buzz: Buzzer, Tones: ToneSignalRx + 'static>(
+	clock: &Clock,
+	mut buzzer: Buzz,
+	tones: &'static Tones,
+	interval: Duration,
+) {
+	info!("Buzzer task started.");
+
+	loop {
+		while let Some(tone) = tones.try_take_tone() {
+			if tone.freq_hz > 0 {
+				buzzer.start(tone.freq_hz);
+			}
+			clock.after((tone.duration_ms as u64).millis()).await;
+			buzzer.stop();
+		}
+
+		clock.after(interval).await;
+	}
+}
+
+/// Plays vibration patterns from macros one after another, checking for more every interval.
+pub async fn haptic_task<
+	Clock: crate::time::Clock,
+	Motor: HapticMotor,
+	Haptics: HapticSignalRx + 'static,
+>(
+	clock: &Clock,
+	mut motor: Motor,
+	haptics: &'static Haptics,
+	interval: Duration,
+) {
+	info!("Haptic task started.");
+
+	loop {
+		while let Some(pattern) = haptics.try_take_haptic() {
+			for step in pattern.steps() {
+				motor.set(step.strength_percent);
+				clock.after((step.duration_ms as u64).millis()).await;
+			}
+			motor.set(0);
+		}
+
+		clock.after(interval).await;
+	}
+}
+
+/// Reads a pointing sensor every interval and queues its motion, turned and scaled by `config`,
+/// for the keypad task to report. Gives up if the sensor doesn't come up.
+pub async fn pointer_task<Clock: crate::time::Clock, Sensor: PointingSensor>(
+	clock: &Clock,
+	mut sensor: Sensor,
+	config: PointerConfig,
+	motion: &'static PointerMotion,
+	interval: Duration,
+) {
+	info!("Pointer task started.");
+
+	if let Err(e) = sensor.init().await {
+		error!("Pointing sensor failed to start: {}", e);
+		return;
+	}
+
+	let mut transform = PointerTransform::new(config);
+	let mut ticker = Ticker::new(clock.now(), interval, MissedTickPolicy::Skip);
+	loop {
+		match sensor.read_motion().await {
+			Ok((0, 0)) => {}
+			Ok((x, y)) => {
+				let (x, y) = transform.apply(x, y);
+				motion.add(x, y);
+			}
+			Err(e) => warn!("Pointer read failed: {}", e),
+		}
+
+		ticker.next(clock).await;
+	}
+}
+
+/// Reads the touch slider every interval and publishes where it's touched for the keypad task.
+/// Gives up if the sensor doesn't come up.
+pub async fn slider_task<Clock: crate::time::Clock, Sensor: SliderSensor>(
+	clock: &Clock,
+	mut sensor: Sensor,
+	position: &'static SliderPosition,
+	interval: Duration,
+) {
+	info!("Slider task started.");
+
+	if let Err(e) = sensor.init().await {
+		error!("Touch slider failed to start: {}", e);
+		return;
+	}
+
+	let mut ticker = Ticker::new(clock.now(), interval, MissedTickPolicy::Skip);
+	loop {
+		match sensor.read_position().await {
+			Ok(touched) => position.set(touched),
+			Err(e) => {
+				warn!("Slider read failed: {}", e);
+				position.set(None);
+			}
+		}
+
+		ticker.next(clock).await;
+	}
+}
+
+/// Probes each key module port every interval and records what's plugged in, for the matrix
+/// to scan and the host to be told about.
+pub async fn module_task<Clock: crate::time::Clock, Detect: ModuleDetect>(
+	clock: &Clock,
+	mut ports: Vec<ModulePort<Detect>>,
+	status: &'static ModuleStatus,
+	interval: Duration,
+) {
+	info!("Module task started.");
+
+	for port in ports.iter() {
+		status.register(port.name);
+	}
+	let mut debounce = alloc::vec![ModuleDebounce::default(); ports.len()];
+
+	let mut ticker = Ticker::new(clock.now(), interval, MissedTickPolicy::Skip);
+	loop {
+		for (index, (port, debounce)) in ports.iter_mut().zip(debounce.iter_mut()).enumerate() {
+			let present = port.detect.is_present().await;
+			if let Some(attached) = debounce.update(present) {
+				if attached {
+					info!("Key module {} attached", port.name);
+				} else {
+					info!("Key module {} detached", port.name);
+				}
+				status.set_attached(index, attached);
+			}
+		}
+
+		ticker.next(clock).await;
+	}
+}
+
+/// Readings averaged for each battery measurement, to smooth out ADC noise and load spikes.
+const BATTERY_SAMPLES: u32 = 8;
+
+/// Measures the battery every interval for `GetStatus` and the host's battery indicator, then
+/// has the power policy pick a mode for the new level.
+pub async fn battery_task<Clock: crate::time::Clock, Adc: BatteryAdc, Sense: PowerSourceSense>(
+	clock: &Clock,
+	mut adc: Adc,
+	config: BatteryConfig,
+	status: &'static BatteryStatus,
+	mut sense: Sense,
+	policy: PowerPolicy,
+	power_state: &'static PowerState,
+	interval: Duration,
+) {
+	info!("Battery task started.");
+
+	loop {
+		let mut total = 0;
+		let mut samples = 0;
+		for _ in 0..BATTERY_SAMPLES {
+			match adc.read().await {
+				Ok(raw) => {
+					total += raw as u32;
+					samples += 1;
+				}
+				Err(e) => warn!("Battery read failed: {}", e),
+			}
+		}
+
+		if samples > 0 {
+			let level = config.level((total / samples) as u16);
+			debug!("Battery at {} mV ({}%)", level.voltage_mv, level.percent);
+			status.set(level);
+		}
+
+		let source = if sense.on_external_power() {
+			PowerSource::External
+		} else {
+			PowerSource::Battery
+		};
+		let mode = policy.mode(source, status.get());
+		if power_state.get() != Some(mode) {
+			info!(
+				"Power mode: {} ms scan interval, {}% brightness",
+				mode.scan_interval.to_millis(),
+				mode.brightness_percent
+			);
+			power_state.set(mode);
+		}
+
+		clock.after(interval).await;
+	}
+}
+
+/// Feeds the hardware watchdog for as long as every task's heartbeat stays fresh. Once one goes
+/// stale the watchdog is left to reset the device.
+pub async fn supervisor_task<Clock: crate::time::Clock, Watchdog: HardwareWatchdog>(
+	clock: &Clock,
+	mut watchdog: Watchdog,
+	heartbeats: &[&'static Heartbeat],
+	interval: Duration,
+) {
+	info!("Supervisor task started.");
+
+	// tasks get a full timeout to beat for the first time
+	let start = clock.now();
+	for heartbeat in heartbeats {
+		heartbeat.beat(start);
+	}
+
+	loop {
+		match stale_heartbeat(heartbeats, clock.now()) {
+			None => watchdog.feed(),
+			Some(heartbeat) => {
+				error!(
+					"Task {} stopped responding, waiting for the watchdog",
+					heartbeat.name
+				);
+			}
+		}
+
+		clock.after(interval).await;
+	}
+}
+
+/// Redraws the status display when the keypad task sends a new status. A panel that stops
+/// answering is set up again before the next draw.
+pub async fn display_task<
+	Clock: crate::time::Clock,
+	Bus: I2cBus,
+	Status: DisplaySignalRx + 'static,
+>(
+	clock: &Clock,
+	mut display: OledDisplay<Bus>,
+	status: &'static Status,
+	interval: Duration,
+) {
+	info!("Display task started.");
+
+	let mut frame = FrameBuffer::new();
+	let mut ready = false;
+	let mut pending = false;
+
+	loop {
+		if let Some(status) = status.try_take_display_status() {
+			status.render(&mut frame);
+			pending = true;
+		}
+		if pending && !ready {
+			match display.init().await {
+				Ok(()) => ready = true,
+				Err(e) => warn!("Failed to set up display: {}", e),
+			}
+		}
+		if pending && ready {
+			match display.draw(&frame).await {
+				Ok(()) => pending = false,
+				Err(e) => {
+					warn!("Failed to draw display: {}", e);
+					ready = false;
+				}
+			}
+		}
+
+		clock.after(interval).await;
+	}
+}
+
+/// While asleep the keypad task ticks this often, instead of every interval, unless a key wakes
+/// it first. Keeps it under the command task's keypad response timeout.
+const SLEEP_WAKE_INTERVAL_MS: u64 = 50;
+
+/// What holding a key at power-up does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootAction {
+	/// Reboot into the USB bootloader.
+	Bootloader,
+	/// Start with an empty profile instead of the stored one, for when it makes the keypad
+	/// unusable.
+	SafeMode,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BootKey {
+	pub key: KeyId,
+	pub action: BootAction,
+}
+
+/// The signal and channel types the keypad task talks to the rest of the firmware through. A
+/// firmware names them once here instead of as a type parameter each on `keypad_task`.
+pub trait KeypadLinks<const VIRTUAL_KEY_BITFIELD_BYTES: usize> {
+	type ProfileChanged: UpdateProfileSignalRx + 'static;
+	type ExternalTagsChanged: ExternalTagsSignalRx + 'static;
+	type VirtualKeysChanged: VirtualKeySignalRx<VIRTUAL_KEY_BITFIELD_BYTES> + 'static;
+	type VirtualKeysById: VirtualKeyIdSignalRx + 'static;
+	type MacroSpeedChanged: MacroSpeedSignalRx + 'static;
+	type HostEvents: HostEventSignalTx + 'static;
+	type KeyEvents: KeyEventSignalTx + 'static;
+	type MatrixScan: MatrixScanSignalTx + 'static;
+	type InjectedKeys: InjectKeySignalRx + 'static;
+	type ActiveTags: ActiveTagsSignalTx + 'static;
+	type KeypadStatus: KeypadStatusSignalTx + 'static;
+	type KeyStats: KeyStatsSignalTx + 'static;
+	type Lighting: LightingSignalTx + 'static;
+	type Tones: ToneSignalTx + 'static;
+	type Haptics: HapticSignalTx + 'static;
+	type Display: DisplaySignalTx + 'static;
+	type Errors: KeypadErrorSignalTx + 'static;
+}
+
+/// The hardware the keypad task drives directly.
+pub struct KeypadHardware<
+	Matrix,
+	Report,
+	Bootloader: 'static,
+	Power: 'static,
+	Ind,
+	Out,
+	PwmOut,
+	Enc,
+> {
+	pub matrix: Matrix,
+	pub hid: Report,
+	pub bootloader: &'static Bootloader,
+	pub power: &'static Power,
+	pub indicators: Vec<BoundIndicator<Ind>>,
+	pub outputs: AuxOutputs<Out>,
+	pub pwm_outputs: PwmOutputs<PwmOut>,
+	pub encoders: Encoders<Enc>,
+}
+
+/// The state the keypad task shares with the other tasks.
+pub struct KeypadChannels<
+	Links: KeypadLinks<VIRTUAL_KEY_BITFIELD_BYTES>,
+	const VIRTUAL_KEY_BITFIELD_BYTES: usize,
+> {
+	pub profile_changed: &'static Links::ProfileChanged,
+	pub tags_changed: &'static Links::ExternalTagsChanged,
+	pub virtual_keys_changed: &'static Links::VirtualKeysChanged,
+	pub virtual_keys_by_id: &'static Links::VirtualKeysById,
+	pub macro_speed_changed: &'static Links::MacroSpeedChanged,
+	pub power_state: &'static PowerState,
+	pub host_events: &'static Links::HostEvents,
+	pub key_events: &'static Links::KeyEvents,
+	pub matrix_scan: &'static Links::MatrixScan,
+	pub injected_keys: &'static Links::InjectedKeys,
+	pub active_tags: &'static Links::ActiveTags,
+	pub keypad_status: &'static Links::KeypadStatus,
+	pub key_stats: &'static Links::KeyStats,
+	pub lighting: &'static Links::Lighting,
+	pub tones: &'static Links::Tones,
+	pub haptics: &'static Links::Haptics,
+	pub pointer_motion: &'static PointerMotion,
+	pub slider_position: &'static SliderPosition,
+	pub modules: &'static ModuleStatus,
+	pub safe_mode: &'static SafeMode,
+	pub indicator_status: &'static IndicatorStatus,
+	pub host_locks: &'static HostLocks,
+	pub display: &'static Links::Display,
+	pub macro_budget: &'static MemoryBudget,
+	pub errors: &'static Links::Errors,
+	pub heartbeat: &'static Heartbeat,
+}
+
+/// How the keypad task behaves, fixed when it's spawned.
+pub struct KeypadConfig<'a> {
+	/// Keys checked once at power-up.
+	pub boot_keys: &'a [BootKey],
+	/// Held at runtime to reboot into the bootloader.
+	pub bootloader_chord: Option<Chord>,
+	/// Overridden by the power policy once it has run.
+	pub idle_timeout: Option<Duration>,
+	/// Counts to carry on from, as last saved to flash.
+	pub saved_key_stats: KeyStats,
+	pub key_stats_save_interval: Duration,
+	pub macro_limit: MacroLimit,
+	/// The scan interval, also overridden by the power policy.
+	pub interval: Duration,
+}
+
+/// Set on the command byte when it's followed by a u16 correlation ID. The ID is echoed back
+/// ahead of the command's response so the host can pipeline requests.
+pub const CORRELATED_COMMAND_FLAG: u8 = 0x80;
+
+pub async fn cmd_task<
+	Clock: crate::time::Clock,
+	Context: ContextErrorLog
+		+ ContextSerialRx
+		+ ContextSerialTx
+		+ ContextKeyEvents
+		+ ContextUsbStats
+		+ ContextAllocator
+		+ ContextMemoryBudgets
+		+ ContextKeyStats
+		+ ContextKeyStatsFlash
+		+ ContextAuth
+		+ ContextLockFlash
+		+ ContextSyncMarkers,
+	Events: HostEventSignalRx + 'static,
+	KeypadErrors: KeypadErrorSignalRx + 'static,
+>(
 	clock: &Clock,
 	mut cmds: Vec<Box<dyn Command<Context>>>,
 	mut ctx: Context,
-	serial_reset_timeout: Duration,
-) {
+	host_events: &'static Events,
+	keypad_errors: &'static KeypadErrors,
+	indicator_status: &'static IndicatorStatus,
+	heartbeat: &'static Heartbeat,
+) where
+	Context::SerialTx: SerialEventSender,
+{
 	info!("Serial task started.");
 
+	let mut pending_events = HostEvents::empty();
+
 	loop {
+		// serial reads time out, so this comes around even with no host attached
+		heartbeat.beat(clock.now());
+
+		if ctx.serial_rx().take_session_ended() {
+			info!("Host disconnected");
+			// whoever connects next has to authenticate and turn on sync markers for themselves,
+			// and nothing the last host left half sent is read as a command
+			ctx.auth_session().reset();
+			*ctx.sync_markers() = false;
+			ctx.serial_rx().drop_buffered();
+		}
+
+		// events go out between commands so they never interleave with a response
+		if let Some(events) = host_events.try_take_host_events() {
+			pending_events |= events;
+		}
+		while let Some(error) = keypad_errors.try_take_keypad_error() {
+			ctx.errors().push(error);
+			pending_events |= HostEvents::ERROR_LOGGED;
+		}
+		if let Some(stats) = ctx.try_take_key_stats_to_save() {
+			if let Err(e) = save_key_stats_to_flash(&mut ctx.key_stats_flash(), &stats).await {
+				ctx.errors().push(Error {
+					timestamp: clock.now(),
+					message: e,
+				});
+				pending_events |= HostEvents::ERROR_LOGGED;
+			}
+		}
+		if !pending_events.is_empty() {
+			// a failed send means nobody is listening; drop the events rather than retry
+			if let Err(e) = send_event(ctx.serial_tx(), &pending_events).await {
+				warn!("Failed to send host events: {}", e);
+			}
+			pending_events = HostEvents::empty();
+		}
+		while let Some(event) = ctx.try_take_key_event() {
+			if let Err(e) = send_event(ctx.serial_tx(), &event).await {
+				warn!("Failed to send key event: {}", e);
+				break;
+			}
+		}
+		// hand back whatever the last command (or a read that timed out) took off the wire
+		return_credit(&mut ctx).await;
+		indicator_status.set_error_present(ctx.errors().get_errors().next().is_some());
+
+		// a command that failed may have left part of itself unread; with sync markers on,
+		// anything before the next marker is skipped, so the device always picks up at the start
+		// of a command. Hosts that never turn them on send commands as they always have.
+		if *ctx.sync_markers() {
+			match read_sync_marker(ctx.serial_rx()).await {
+				Ok(0) => {}
+				Ok(skipped) => {
+					warn!("Skipped {} bytes before sync marker", skipped);
+				}
+				Err(_) => {
+					continue;
+				}
+			}
+		}
+
 		let cmd_id = match ctx.serial_rx().read_u8().await {
 			Some(cmd_id) => cmd_id,
 			None => {
 				continue;
 			}
 		};
-		match read_cmd(cmd_id, &mut cmds, &mut ctx).await {
+
+		let heap_before = ctx.allocator().start_window();
+		let result = read_cmd(cmd_id, &mut cmds, &mut ctx).await;
+
+		// anything the command allocated and freed again was buffering; what it kept (a new
+		// profile, say) is accounted for by that subsystem's own budget
+		let heap_after = ctx.allocator().current();
+		let transient = ctx
+			.allocator()
+			.window_max()
+			.saturating_sub(heap_before.max(heap_after));
+		if ctx.budgets().serial.check(transient).is_err() {
+			ctx.errors().push(Error {
+				timestamp: clock.now(),
+				message: "Command exceeded serial memory budget",
+			});
+			pending_events |= HostEvents::ERROR_LOGGED;
+		}
+
+		match result {
 			Ok(_) => {
 				info!("Command {} executed successfully", cmd_id);
 			}
@@ -154,33 +1072,291 @@ pub async fn cmd_task<Clock: crate::time::Clock, Context: ContextErrorLog + Cont
 					message: e,
 				};
 				ctx.errors().push(error);
+				pending_events |= HostEvents::ERROR_LOGGED;
 
 				warn!("Error: {}", e);
 
-				let timeout_start = clock.now();
-				while clock.now() - timeout_start < serial_reset_timeout {
-					if !ctx.serial_rx().drop_packet().await {
-						break;
-					}
-				}
+				ctx.serial_rx().drop_buffered();
 			}
 		}
 	}
 }
 
-async fn read_cmd<Context: ContextSerialRx>(
+async fn read_cmd<
+	Context: ContextSerialRx + ContextSerialTx + ContextUsbStats + ContextAuth + ContextLockFlash,
+>(
 	cmd_id: u8,
 	cmds: &mut Vec<Box<dyn Command<Context>>>,
 	ctx: &mut Context,
 ) -> Result<(), &'static str> {
+	let (cmd_id, correlation_id) = if cmd_id & CORRELATED_COMMAND_FLAG != 0 {
+		match ctx.serial_rx().read_u16().await {
+			Some(correlation_id) => (cmd_id & !CORRELATED_COMMAND_FLAG, Some(correlation_id)),
+			None => {
+				ctx.usb_stats().command_parse_failed();
+				return Err("Missing correlation ID");
+			}
+		}
+	} else {
+		(cmd_id, None)
+	};
+
 	debug!("Serial message {} received", cmd_id);
 
 	let cmd = match cmds.get_mut(cmd_id as usize) {
 		Some(cmd) => cmd,
 		None => {
+			ctx.usb_stats().command_parse_failed();
 			return Err("Invalid command ID")?;
 		}
 	};
 
+	if let Some(correlation_id) = correlation_id {
+		ctx.serial_tx().write_u16(correlation_id).await?;
+	}
+
+	if let Err((code, msg)) = check_policy(ctx, &cmd.info()) {
+		ctx.serial_tx().write_u8(code).await?;
+		return Err(msg);
+	}
+
 	cmd.execute(ctx).await
 }
+
+#[cfg(test)]
+mod tests {
+	use core::cell::RefCell;
+
+	use super::*;
+	use crate::auth::{AuthSession, NONCE_SIZE};
+	use crate::command::{CommandFlags, CommandInfo};
+	use crate::device::CommandId;
+	use crate::profile::{KeyboardKey, MacroId, MacroIndex};
+	use crate::stats::UsbStats;
+	use crate::storage::{FlashPartition, PartitionedFlashMemory, save_lock_pin_to_flash};
+	use crate::testing::*;
+	use alloc::vec;
+	use async_trait::async_trait;
+	use uuid::{Uuid, uuid};
+
+	static KEY_ID: KeyId = KeyId::new(Uuid::from_u128_le(0xd1472104_1c37_560f_a39b_1737983559fc));
+	static INJECTED_KEY_ID: KeyId =
+		KeyId::new(Uuid::from_u128_le(0x5661275b_eba1_5c7b_b7cc_f8f8dd08d3b7));
+	static MACRO_ID: MacroId =
+		MacroId::new(Uuid::from_u128_le(0x140acba7_4971_5b36_af21_ce478b891606));
+
+	#[derive(Default)]
+	struct FakeKeyEvents {
+		sent: RefCell<Vec<KeyEvent>>,
+	}
+
+	impl KeyEventSignalTx for FakeKeyEvents {
+		fn key_events_subscribed(&self) -> bool {
+			true
+		}
+
+		fn send_key_event(&self, event: KeyEvent) {
+			self.sent.borrow_mut().push(event);
+		}
+	}
+
+	#[test]
+	fn injected_keys_play_macros_without_being_streamed() {
+		let profile = new_test_profile(
+			vec![
+				new_test_device_key(KEY_ID, vec![]),
+				new_test_device_key(INJECTED_KEY_ID, vec![MacroIndex::new(0)]),
+			],
+			vec![new_test_sequence_macro(
+				MACRO_ID,
+				vec![ActionEvent::Keyboard(KeyboardEvent::KeyDown(
+					KeyboardKey::A,
+				))],
+			)],
+		);
+		let mut state = KeyboardState::from(&profile);
+		let key_events = FakeKeyEvents::default();
+		let now = Instant::from_ticks(10_000);
+		let key_actions = [
+			KeyboardAction::pressed(KEY_ID, now),
+			KeyboardAction::pressed(INJECTED_KEY_ID, now),
+		];
+
+		let mut changed = Vec::new();
+		apply_key_actions(
+			&mut state,
+			&key_actions,
+			1,
+			now,
+			1.millis(),
+			&key_events,
+			|key, physical| changed.push((key.key_id, physical)),
+		);
+
+		let sent = key_events.sent.borrow();
+		assert_eq!(sent.len(), 1);
+		assert_eq!(sent[0].key_id, KEY_ID);
+		assert_eq!(changed, [(KEY_ID, true), (INJECTED_KEY_ID, false)]);
+
+		let mut typed = false;
+		state.tick(1.millis(), |event| {
+			typed |= matches!(
+				event,
+				ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A))
+			);
+		});
+		assert!(typed);
+	}
+
+	#[test]
+	fn injected_keys_arent_counted_in_key_stats() {
+		let profile = new_test_profile(
+			vec![
+				new_test_device_key(KEY_ID, vec![]),
+				new_test_device_key(INJECTED_KEY_ID, vec![]),
+			],
+			vec![],
+		);
+		let mut state = KeyboardState::from(&profile);
+		let now = Instant::from_ticks(10_000);
+		let key_actions = [
+			KeyboardAction::pressed(KEY_ID, now),
+			KeyboardAction::pressed(INJECTED_KEY_ID, now),
+		];
+
+		apply_key_actions(
+			&mut state,
+			&key_actions,
+			1,
+			now,
+			1.millis(),
+			&FakeKeyEvents::default(),
+			|_, _| {},
+		);
+
+		assert_eq!(state.key_stats().presses(KEY_ID), 1);
+		assert_eq!(state.key_stats().presses(INJECTED_KEY_ID), 0);
+		assert_eq!(state.key_stats().total(), 1);
+	}
+
+	struct CmdContext {
+		flash: FakeNorFlash,
+		lock_partition: FlashPartition<FakeNorFlash>,
+		auth_partition: FlashPartition<FakeNorFlash>,
+		auth_session: AuthSession,
+		usb_stats: UsbStats,
+		serial_rx: FakeSerialRx,
+		serial_tx: FakeSerialTx,
+	}
+
+	impl CmdContext {
+		fn new(input: &[u8]) -> Self {
+			Self {
+				flash: FakeNorFlash::new(128),
+				lock_partition: FlashPartition::new(0, 64),
+				auth_partition: FlashPartition::new(64, 64),
+				auth_session: AuthSession::new(),
+				usb_stats: UsbStats::new(),
+				serial_rx: FakeSerialRx::new(input.to_vec()),
+				serial_tx: FakeSerialTx::new(),
+			}
+		}
+	}
+
+	impl ContextLockFlash for CmdContext {
+		type Flash = FakeNorFlash;
+		fn lock_flash(&mut self) -> PartitionedFlashMemory<Self::Flash> {
+			PartitionedFlashMemory::new(&mut self.flash, &self.lock_partition)
+		}
+	}
+
+	impl ContextAuth for CmdContext {
+		type Flash = FakeNorFlash;
+		fn auth_flash(&mut self) -> PartitionedFlashMemory<Self::Flash> {
+			PartitionedFlashMemory::new(&mut self.flash, &self.auth_partition)
+		}
+
+		fn auth_session(&mut self) -> &mut AuthSession {
+			&mut self.auth_session
+		}
+
+		fn auth_challenge(&mut self) -> [u8; NONCE_SIZE] {
+			[0; NONCE_SIZE]
+		}
+	}
+
+	impl ContextUsbStats for CmdContext {
+		fn usb_stats(&self) -> &UsbStats {
+			&self.usb_stats
+		}
+	}
+
+	impl ContextSerialTx for CmdContext {
+		type SerialTx = FakeSerialTx;
+
+		fn serial_tx(&mut self) -> &mut Self::SerialTx {
+			&mut self.serial_tx
+		}
+	}
+
+	impl ContextSerialRx for CmdContext {
+		type SerialRx = FakeSerialRx;
+
+		fn serial_rx(&mut self) -> &mut Self::SerialRx {
+			&mut self.serial_rx
+		}
+	}
+
+	// answers 0xFF without reading anything
+	struct OkCommand;
+
+	#[async_trait(?Send)]
+	impl<Context: ContextSerialTx> Command<Context> for OkCommand {
+		fn info(&self) -> CommandInfo {
+			CommandInfo {
+				id: CommandId(uuid!("0b7f8b9e-6d2a-5c55-9a57-2f0a4c1d8e63")),
+				name: "Ok",
+				flags: CommandFlags::MUTATING,
+				schema: 1,
+			}
+		}
+
+		async fn execute(&self, ctx: &mut Context) -> Result<(), &'static str> {
+			ctx.serial_tx().write_u8(0xFF).await
+		}
+	}
+
+	#[tokio::test]
+	async fn correlated_command_echoes_its_id_ahead_of_the_response() {
+		let mut cmds: Vec<Box<dyn Command<CmdContext>>> = vec![Box::new(OkCommand)];
+		let mut ctx = CmdContext::new(&[0x34, 0x12]);
+
+		let result = read_cmd(CORRELATED_COMMAND_FLAG, &mut cmds, &mut ctx).await;
+		assert_eq!(result, Ok(()));
+		assert_eq!(ctx.serial_tx.written, [0x34, 0x12, 0xFF]);
+	}
+
+	#[tokio::test]
+	async fn correlated_command_echoes_its_id_when_policy_rejects_it() {
+		let mut cmds: Vec<Box<dyn Command<CmdContext>>> = vec![Box::new(OkCommand)];
+		let mut ctx = CmdContext::new(&[0x34, 0x12]);
+		save_lock_pin_to_flash(&mut ctx.lock_flash(), b"1234")
+			.await
+			.unwrap();
+
+		let result = read_cmd(CORRELATED_COMMAND_FLAG, &mut cmds, &mut ctx).await;
+		assert_eq!(result, Err("Device is locked"));
+		assert_eq!(ctx.serial_tx.written, [0x34, 0x12, 0x40]);
+	}
+
+	#[tokio::test]
+	async fn correlated_command_without_an_id_is_a_parse_failure() {
+		let mut cmds: Vec<Box<dyn Command<CmdContext>>> = vec![Box::new(OkCommand)];
+		let mut ctx = CmdContext::new(&[]);
+
+		let result = read_cmd(CORRELATED_COMMAND_FLAG, &mut cmds, &mut ctx).await;
+		assert_eq!(result, Err("Missing correlation ID"));
+		assert!(ctx.serial_tx.written.is_empty());
+		assert_eq!(ctx.usb_stats.get().command_parse_failures, 1);
+	}
+}
@@ -1,273 +1,4 @@
-#[cfg(test)]
-pub mod test {
-	use crate::storage::BlockFlash;
-	use alloc::boxed::Box;
-	use alloc::vec::Vec;
-
-	pub struct FakeFlashMemory {
-		pub read_buf: &'static [u8],
-		pub write_buf: &'static mut [u8],
-	}
-
-	impl FakeFlashMemory {
-		pub fn new(
-			read_buf: Option<&'static [u8]>,
-			write_buf: Option<&'static mut [u8]>,
-		) -> FakeFlashMemory {
-			let read_buf = match read_buf {
-				Some(buf) => buf,
-				None => &[0u8; 0],
-			};
-			let write_buf = match write_buf {
-				Some(buf) => buf,
-				None => &mut [0u8; 0],
-			};
-			FakeFlashMemory {
-				read_buf,
-				write_buf,
-			}
-		}
-	}
-
-	impl BlockFlash for FakeFlashMemory {
-		fn as_slice(&self) -> &'static [u8] {
-			self.read_buf
-		}
-
-		fn erase(&mut self, offset: usize, length: usize) -> Result<(), &'static str> {
-			for i in &mut self.write_buf[offset..offset + length].iter_mut() {
-				*i = 0;
-			}
-			Ok(())
-		}
-
-		fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), &'static str> {
-			if offset + data.len() > self.write_buf.len() {
-				return Err("Write out of bounds");
-			}
-			let mut new_data = self.write_buf.to_vec();
-			new_data[offset..offset + data.len()].copy_from_slice(data);
-			self.write_buf = Box::leak(new_data.into_boxed_slice());
-			Ok(())
-		}
-
-		fn length(&self) -> usize {
-			self.read_buf.len()
-		}
-
-		const ERASE_BLOCK_SIZE: usize = 1;
-
-		const WRITE_BLOCK_SIZE: usize = 1;
-	}
-
-	pub fn get_cranky_profile_data() -> &'static mut [u8] {
-		Box::leak(
-			Vec::from([
-				0xD0, 0x0A, 0x01, 0x00, 0x00, 0x00, 0x04, 0x54, 0x65, 0x73, 0x74, 0x1E, 0x85, 0xEE,
-				0x61, 0x06, 0x8B, 0x34, 0x93, 0x5D, 0xB5, 0xE2, 0xAC, 0x11, 0xCF, 0xA5, 0x34, 0x4B,
-				0x00, 0xFF, 0x08, 0x1C, 0xF0, 0xD6, 0x71, 0xB0, 0x89, 0x15, 0x7D, 0x37, 0xC4, 0xC7,
-				0x4C, 0x76, 0xB0, 0x01, 0x00, 0x00, 0x79, 0xFD, 0xC4, 0x87, 0x3B, 0x14, 0x6B, 0x57,
-				0xAF, 0xA2, 0xBE, 0xA5, 0x9E, 0x4C, 0xD0, 0x2C, 0x00, 0xD3, 0xDC, 0x5A, 0xFC, 0x05,
-				0x24, 0x8E, 0xAD, 0x3D, 0x1B, 0x15, 0x52, 0x1E, 0x82, 0xDE, 0x96, 0x01, 0x01, 0x00,
-				0x94, 0x27, 0x65, 0x1D, 0xA4, 0x96, 0x59, 0x5C, 0x99, 0x48, 0xAF, 0xD4, 0x41, 0x28,
-				0x93, 0x17, 0x00, 0xF3, 0x57, 0xF5, 0xD1, 0x34, 0xA0, 0xB6, 0x28, 0xF7, 0xCB, 0x7F,
-				0xF6, 0x06, 0x84, 0xB7, 0xC8, 0x01, 0x02, 0x00, 0x7C, 0x73, 0x57, 0xDE, 0xC1, 0xE6,
-				0x18, 0x58, 0xBF, 0x94, 0xD1, 0x26, 0xFF, 0x53, 0x04, 0xA3, 0x00, 0xC7, 0x9A, 0x42,
-				0x84, 0x0E, 0xDE, 0x98, 0xDB, 0x79, 0x6F, 0xB2, 0xBB, 0x5B, 0x5D, 0x6B, 0x92, 0x01,
-				0x03, 0x00, 0x88, 0x05, 0xC2, 0x85, 0x48, 0x81, 0x85, 0x57, 0x9E, 0x9F, 0x44, 0x97,
-				0x6E, 0x8D, 0xFE, 0xF8, 0x00, 0x9E, 0xA0, 0xD1, 0x70, 0x57, 0xA4, 0x7B, 0x33, 0x26,
-				0x32, 0x04, 0x3A, 0xA4, 0x98, 0xA7, 0xB9, 0x01, 0x04, 0x00, 0x4A, 0x97, 0xEE, 0xB6,
-				0x05, 0xB4, 0x67, 0x53, 0x8C, 0x9F, 0xE7, 0x0A, 0x75, 0x04, 0x5C, 0x37, 0x01, 0x01,
-				0x02, 0x66, 0x6E, 0x00, 0x63, 0x4D, 0x8A, 0x84, 0x40, 0x59, 0x88, 0x14, 0xBE, 0x19,
-				0x57, 0xC5, 0xED, 0xFB, 0xCD, 0x99, 0x01, 0x1E, 0x00, 0x60, 0xED, 0x57, 0x65, 0xB3,
-				0x46, 0x71, 0x22, 0x1E, 0x0E, 0x5E, 0xDB, 0xDA, 0x5D, 0x36, 0xE0, 0x01, 0x05, 0x00,
-				0xBE, 0x52, 0x10, 0x8A, 0x65, 0x81, 0x76, 0x59, 0x84, 0x9B, 0x51, 0x1C, 0xE9, 0x2F,
-				0x99, 0x56, 0x00, 0xC8, 0xF9, 0x57, 0x0D, 0xD3, 0xE7, 0x51, 0x44, 0x17, 0xBD, 0xB1,
-				0x3D, 0x4D, 0xB5, 0x45, 0x2A, 0x01, 0x06, 0x00, 0x06, 0x6D, 0x20, 0x91, 0xD4, 0x70,
-				0x75, 0x5B, 0x9F, 0xDF, 0xAA, 0xD7, 0xF3, 0x67, 0xFF, 0xF5, 0x00, 0x64, 0x6A, 0xDA,
-				0xB7, 0x41, 0x41, 0x05, 0x1C, 0xDB, 0x67, 0x65, 0x31, 0xFB, 0xAC, 0x39, 0x61, 0x01,
-				0x07, 0x00, 0xDF, 0x3E, 0xBD, 0x7A, 0x4C, 0xF9, 0x2E, 0x52, 0xB2, 0xBE, 0x06, 0xA8,
-				0x8B, 0xDB, 0x1C, 0xC9, 0x00, 0xE2, 0x77, 0xFD, 0xF4, 0xA3, 0xBF, 0xC6, 0x79, 0x9B,
-				0x12, 0x4D, 0xF6, 0x95, 0x34, 0xD5, 0x69, 0x01, 0x08, 0x00, 0x9A, 0xA6, 0x2D, 0xA3,
-				0x91, 0x7F, 0x5A, 0x5F, 0x87, 0xD2, 0xDD, 0x5E, 0x47, 0x76, 0xB1, 0xC4, 0x00, 0xC9,
-				0x34, 0x77, 0x6F, 0x83, 0x7F, 0x9F, 0x86, 0xF3, 0x3B, 0x32, 0x6C, 0x81, 0x1F, 0x49,
-				0x6B, 0x01, 0x09, 0x00, 0x21, 0x1A, 0x80, 0x3A, 0xF7, 0x1E, 0x03, 0x58, 0xBF, 0x42,
-				0xEC, 0xD1, 0xE8, 0x44, 0x46, 0x56, 0x00, 0x83, 0x50, 0x5A, 0xD5, 0xB7, 0x51, 0x86,
-				0xA5, 0xA4, 0x60, 0x1C, 0x2F, 0xF3, 0x0F, 0x87, 0xA7, 0x01, 0x0A, 0x00, 0x1F, 0xC3,
-				0x4E, 0xC5, 0x81, 0x23, 0x36, 0x56, 0xB0, 0xA5, 0xED, 0xD4, 0x48, 0x29, 0x4B, 0x88,
-				0x00, 0x91, 0xFC, 0x68, 0xC7, 0x35, 0xCA, 0xDD, 0x63, 0xD8, 0x77, 0x00, 0x68, 0x8D,
-				0x5A, 0xF7, 0x7B, 0x01, 0x0B, 0x00, 0xAF, 0x3D, 0xAD, 0x16, 0x00, 0xBD, 0x68, 0x51,
-				0x88, 0x5A, 0x74, 0x00, 0x8C, 0xE8, 0xDE, 0x35, 0x00, 0xC8, 0xE2, 0x73, 0x44, 0xD9,
-				0xFF, 0xB1, 0x77, 0xAD, 0xD1, 0xCA, 0x22, 0x63, 0x6F, 0x92, 0xE4, 0x01, 0x0C, 0x00,
-				0xC5, 0x0F, 0x39, 0xDA, 0x61, 0x53, 0xF9, 0x5A, 0x93, 0x98, 0xD3, 0x82, 0x3B, 0x81,
-				0xEC, 0xBA, 0x00, 0xB3, 0x8A, 0xDA, 0x85, 0xB6, 0x82, 0x35, 0xB8, 0x72, 0xF6, 0xFD,
-				0x40, 0x50, 0x1E, 0x17, 0x4A, 0x01, 0x0D, 0x00, 0x65, 0x9B, 0x54, 0x1A, 0xD5, 0x43,
-				0x68, 0x50, 0xA3, 0xF5, 0x59, 0x42, 0x99, 0x46, 0xE4, 0x04, 0x00, 0x69, 0xA0, 0x66,
-				0x11, 0x9B, 0x42, 0xCE, 0x92, 0x4A, 0xF0, 0x1A, 0x63, 0xFF, 0xBB, 0x00, 0x68, 0x01,
-				0x0E, 0x00, 0xA0, 0xB9, 0x06, 0xEC, 0x13, 0x07, 0xB1, 0x5D, 0x86, 0x2C, 0x20, 0xFA,
-				0xFD, 0x2B, 0x07, 0x64, 0x00, 0xE4, 0x1C, 0xB5, 0x03, 0x7F, 0x71, 0xC5, 0x46, 0xAC,
-				0xBA, 0xD1, 0xE2, 0x01, 0x70, 0x27, 0x8F, 0x01, 0x0F, 0x00, 0x60, 0xF2, 0xFE, 0xCB,
-				0x98, 0xA4, 0x9F, 0x59, 0xA6, 0xC0, 0x8A, 0x6A, 0x51, 0x00, 0x2B, 0x76, 0x00, 0xB9,
-				0x80, 0xB8, 0xC3, 0x09, 0x81, 0x48, 0x70, 0xBC, 0x48, 0x3A, 0xC5, 0x68, 0xD1, 0xB8,
-				0xB7, 0x01, 0x10, 0x00, 0xF2, 0xAF, 0x2C, 0x85, 0xF9, 0x9E, 0xA3, 0x59, 0xAE, 0x41,
-				0xE5, 0xEE, 0xC3, 0xFA, 0x0D, 0x21, 0x00, 0xF6, 0x95, 0x5B, 0x33, 0x68, 0x14, 0xCA,
-				0xF8, 0xDA, 0x1F, 0x64, 0xE0, 0x70, 0xEB, 0x90, 0xDA, 0x01, 0x11, 0x00, 0x43, 0x80,
-				0x14, 0x96, 0x90, 0x98, 0x67, 0x57, 0xA4, 0x64, 0x1B, 0x12, 0xF1, 0x26, 0xDA, 0x14,
-				0x00, 0xC9, 0x8A, 0xA9, 0x7F, 0x02, 0x3D, 0x5E, 0x63, 0xD3, 0x44, 0x46, 0x37, 0x36,
-				0xFF, 0xDE, 0xDB, 0x01, 0x12, 0x00, 0xB5, 0xB4, 0x30, 0x7A, 0xB1, 0xF6, 0xAE, 0x5A,
-				0x8C, 0xF5, 0xF2, 0x8B, 0xCA, 0x7C, 0x1C, 0x13, 0x00, 0x02, 0x1A, 0xEC, 0x6C, 0x14,
-				0xF3, 0x26, 0xA4, 0xB5, 0xC0, 0xE8, 0xEA, 0x1C, 0x68, 0xFF, 0xD0, 0x01, 0x13, 0x00,
-				0xE8, 0x39, 0x60, 0xAB, 0xDC, 0x38, 0x91, 0x5F, 0xB1, 0x5C, 0x66, 0x78, 0xDE, 0xF8,
-				0x7C, 0xEA, 0x00, 0xB8, 0x60, 0xFC, 0xDD, 0x27, 0x38, 0xB5, 0xB9, 0x36, 0xF8, 0x60,
-				0x24, 0xCE, 0x93, 0xC1, 0xDB, 0x01, 0x14, 0x00, 0xA7, 0x9F, 0xF2, 0x0E, 0xFB, 0x07,
-				0x95, 0x54, 0xBB, 0x6F, 0x33, 0xD1, 0x64, 0xED, 0xA9, 0x94, 0x00, 0x41, 0x89, 0xDE,
-				0x56, 0xFF, 0x23, 0xC5, 0xD5, 0x64, 0x04, 0xCA, 0x41, 0x55, 0x74, 0x47, 0x6B, 0x01,
-				0x15, 0x00, 0x6C, 0xAA, 0x8C, 0xE1, 0x22, 0xD9, 0x8E, 0x55, 0xB1, 0x46, 0x02, 0x62,
-				0x17, 0x3A, 0x28, 0xBD, 0x01, 0x01, 0x03, 0x74, 0x65, 0x73, 0x00, 0x44, 0x75, 0x7F,
-				0xD5, 0x02, 0x00, 0xB3, 0xC9, 0x65, 0x32, 0x35, 0x1C, 0x93, 0x01, 0xD6, 0x21, 0x01,
-				0x1F, 0x00, 0xFF, 0x0C, 0xA8, 0x93, 0x89, 0xA9, 0xD1, 0x94, 0x9B, 0x27, 0x78, 0x22,
-				0x6E, 0x74, 0x64, 0xC2, 0x01, 0x16, 0x00, 0xEA, 0x85, 0x32, 0x7B, 0xE6, 0x4B, 0xAE,
-				0x5E, 0x91, 0x25, 0xCE, 0xC5, 0x47, 0xFA, 0x3F, 0xB1, 0x00, 0xB1, 0xF5, 0xDF, 0x58,
-				0x14, 0xEB, 0xD8, 0x40, 0xD5, 0x15, 0xF1, 0xCC, 0xEE, 0xA9, 0x41, 0x62, 0x01, 0x1D,
-				0x00, 0xBA, 0x2C, 0xDE, 0x4A, 0xD3, 0x18, 0xD0, 0x5F, 0xA6, 0xD4, 0xBA, 0x92, 0x8B,
-				0xB4, 0x70, 0x09, 0x00, 0x2E, 0xA8, 0x41, 0x48, 0xCC, 0xB3, 0xE2, 0x9B, 0xA4, 0xEA,
-				0x3A, 0xDB, 0x87, 0x90, 0x5A, 0xF0, 0x01, 0x18, 0x00, 0x39, 0x0B, 0x4D, 0x47, 0x65,
-				0x61, 0xE0, 0x58, 0x97, 0x45, 0x2C, 0xA7, 0x94, 0x93, 0xA9, 0xE8, 0x00, 0xCE, 0x73,
-				0x6A, 0x9E, 0x22, 0x7E, 0x0D, 0x13, 0x33, 0x67, 0xBD, 0x73, 0xB8, 0x05, 0xA6, 0xF1,
-				0x01, 0x19, 0x00, 0x39, 0xBC, 0xFB, 0x67, 0x40, 0x85, 0x1C, 0x57, 0xA8, 0xE7, 0x0A,
-				0x8B, 0xFF, 0xBD, 0xC4, 0xC0, 0x00, 0xE5, 0x16, 0xF4, 0xC5, 0x32, 0x1B, 0xF6, 0xFD,
-				0x13, 0x44, 0xB3, 0x29, 0xB1, 0xFD, 0xB8, 0x45, 0x01, 0x1A, 0x00, 0x79, 0x81, 0xA6,
-				0x00, 0x85, 0x75, 0x08, 0x5F, 0x89, 0xFD, 0xC6, 0x34, 0x64, 0x76, 0x05, 0x75, 0x00,
-				0xBA, 0x00, 0xFD, 0x7B, 0x3E, 0xA3, 0x40, 0xAF, 0x9B, 0x2E, 0x15, 0x64, 0xF6, 0x6E,
-				0xE3, 0x9E, 0x01, 0x1B, 0x00, 0x81, 0x3C, 0x74, 0x7B, 0x60, 0x72, 0xE3, 0x5A, 0x8C,
-				0x7E, 0xFC, 0x45, 0x17, 0x51, 0xA2, 0xC7, 0x00, 0x44, 0x48, 0xF5, 0x71, 0x9B, 0x95,
-				0xBE, 0xB3, 0x7C, 0x6D, 0x8B, 0xDE, 0xCA, 0xE3, 0x62, 0x35, 0x01, 0x20, 0x00, 0x3D,
-				0x6A, 0xC5, 0x15, 0x31, 0x0F, 0xBD, 0x5E, 0xBC, 0xF1, 0x63, 0xAA, 0x96, 0x8B, 0xE4,
-				0x9A, 0x00, 0xAF, 0x77, 0xB9, 0xB7, 0x08, 0x2B, 0xB3, 0x41, 0xD5, 0x45, 0x29, 0x03,
-				0x06, 0x92, 0x07, 0xA4, 0x01, 0x1C, 0x00, 0x01, 0x00, 0x60, 0x14, 0xE5, 0x68, 0x08,
-				0x46, 0xA9, 0x24, 0x41, 0x49, 0xFE, 0xD1, 0x18, 0x62, 0xE4, 0xB7, 0x01, 0x01, 0x00,
-				0x21, 0x00, 0x0D, 0x8A, 0xB1, 0xAF, 0x99, 0x73, 0x4A, 0xB0, 0x61, 0x72, 0xCE, 0xCC,
-				0x5E, 0x32, 0xA9, 0x3C, 0x03, 0x45, 0x73, 0x63, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x29, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x29, 0x7F, 0xAC, 0x47, 0x1B, 0x9E, 0x0E, 0x8A,
-				0x56, 0x15, 0xBC, 0x3D, 0xA9, 0xB8, 0x8B, 0xC8, 0xD2, 0x01, 0x31, 0x00, 0x00, 0x01,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x1E, 0x00, 0x01, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x1E, 0x9E, 0x4C, 0x8F, 0xA3,
-				0x81, 0x0A, 0x91, 0x1C, 0x76, 0xC5, 0xA0, 0x21, 0x12, 0xC4, 0xD1, 0x8A, 0x01, 0x32,
-				0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x1F,
-				0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x1F, 0xBE,
-				0x19, 0xA0, 0x16, 0xAA, 0xCA, 0x01, 0xFE, 0x00, 0x5A, 0x9A, 0xDC, 0x35, 0xA1, 0xF8,
-				0xD6, 0x01, 0x33, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x01, 0x01, 0x20, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
-				0x00, 0x20, 0xA9, 0xFC, 0x2E, 0x12, 0xA3, 0x05, 0xBA, 0x75, 0x1B, 0x2B, 0xA5, 0xD9,
-				0x6D, 0x68, 0x44, 0x50, 0x01, 0x34, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x01, 0x01, 0x21, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x01, 0x00, 0x21, 0xEB, 0xB3, 0xEA, 0x9F, 0xEA, 0xB2, 0x27, 0xD4, 0x4A,
-				0x90, 0xA3, 0x27, 0x6F, 0xB4, 0xE6, 0xD5, 0x01, 0x35, 0x00, 0x00, 0x01, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x22, 0x00, 0x01, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x22, 0xCD, 0xCA, 0x78, 0x16, 0x96, 0xBE,
-				0xE6, 0xB8, 0x5E, 0x2A, 0xFB, 0x7E, 0x24, 0x27, 0x91, 0x97, 0x01, 0x60, 0x00, 0x00,
-				0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x35, 0x00, 0x01,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x35, 0x16, 0x4A, 0xF1,
-				0x82, 0xB6, 0x58, 0xEB, 0xF7, 0xD4, 0x59, 0xC7, 0x12, 0x62, 0x93, 0x05, 0x66, 0x01,
-				0x51, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01,
-				0x14, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x14,
-				0x45, 0xD0, 0x0C, 0xA3, 0xF0, 0x2D, 0xB9, 0x2C, 0xCC, 0x32, 0x40, 0xBF, 0xB6, 0xA8,
-				0xB0, 0x23, 0x01, 0x57, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x01, 0x01, 0x1A, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x01, 0x00, 0x1A, 0xFF, 0x0C, 0x10, 0x25, 0xC2, 0x63, 0x1E, 0x74, 0xDE, 0x81, 0x41,
-				0x3C, 0xF6, 0xD3, 0x56, 0xCB, 0x01, 0x45, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x08, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x01, 0x00, 0x08, 0x25, 0x94, 0x39, 0x53, 0x1A, 0xED, 0xA6, 0xE9,
-				0x90, 0xC5, 0xB5, 0x84, 0x17, 0x2C, 0x40, 0x2B, 0x01, 0x52, 0x00, 0x00, 0x01, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x15, 0x00, 0x01, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x15, 0xA9, 0xA4, 0x12, 0x56, 0x82,
-				0xE0, 0xA3, 0xE1, 0xB1, 0x97, 0xC5, 0x6F, 0xEB, 0x7D, 0x35, 0xF8, 0x01, 0x54, 0x00,
-				0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x17, 0x00,
-				0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x17, 0xDB, 0x74,
-				0x9A, 0x22, 0x6F, 0x5C, 0x08, 0x12, 0x65, 0x27, 0x95, 0xFD, 0xAE, 0x9A, 0x5D, 0xB2,
-				0x03, 0x54, 0x61, 0x62, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x01, 0x01, 0x2B, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x01, 0x00, 0x2B, 0x40, 0x16, 0x93, 0x52, 0xC0, 0x00, 0x48, 0x44, 0xF0, 0x58, 0xA1,
-				0x82, 0x28, 0x37, 0xFD, 0x13, 0x01, 0x41, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x01, 0x00, 0x04, 0x8F, 0xBC, 0xA8, 0xA2, 0x91, 0x1E, 0xBC, 0x4D,
-				0x9B, 0x00, 0xF1, 0x5B, 0x4A, 0xA5, 0x33, 0x17, 0x01, 0x53, 0x00, 0x00, 0x01, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x16, 0x00, 0x01, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x16, 0x3A, 0x13, 0x2E, 0xD5, 0xE9,
-				0xE2, 0x38, 0xDA, 0x2B, 0x56, 0x31, 0x16, 0xBA, 0x86, 0xEA, 0x2D, 0x01, 0x44, 0x00,
-				0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x07, 0x00,
-				0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x07, 0x2A, 0x82,
-				0x6E, 0xF1, 0xF2, 0xBA, 0x20, 0x22, 0x59, 0x45, 0xB7, 0xC0, 0x0F, 0xED, 0xF9, 0x4A,
-				0x01, 0x46, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
-				0x01, 0x09, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
-				0x09, 0x25, 0x8A, 0x47, 0x15, 0x08, 0x27, 0x29, 0xAC, 0x84, 0xCD, 0x76, 0x3B, 0xEC,
-				0xA6, 0x93, 0x15, 0x01, 0x47, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x01, 0x01, 0x0A, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x01, 0x00, 0x0A, 0x16, 0xC3, 0x31, 0xC8, 0x37, 0x41, 0x24, 0x80, 0xF2, 0x0C,
-				0x7C, 0xA4, 0x2B, 0x77, 0xCB, 0x3E, 0x05, 0x53, 0x68, 0x69, 0x66, 0x74, 0x00, 0x00,
-				0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0xE1, 0x00, 0x01,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0xE1, 0x5C, 0xE6, 0xF1,
-				0x2F, 0x3E, 0xD4, 0x25, 0x02, 0x58, 0xC1, 0x6C, 0x24, 0x43, 0xE5, 0x35, 0xB3, 0x01,
-				0x5A, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01,
-				0x1D, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x1D,
-				0xB2, 0xA1, 0xA9, 0x4C, 0xBB, 0x31, 0x13, 0xE3, 0x66, 0xC7, 0x23, 0xFB, 0xAB, 0x85,
-				0x80, 0x0C, 0x01, 0x58, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x01, 0x01, 0x1B, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x01, 0x00, 0x1B, 0x02, 0x38, 0xDF, 0x05, 0xB5, 0x63, 0xD6, 0x41, 0x9D, 0x2E, 0x6F,
-				0x75, 0xD4, 0x00, 0x3D, 0x0C, 0x01, 0x4D, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x10, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x01, 0x00, 0x10, 0xD6, 0xC8, 0xF1, 0x89, 0xCE, 0x7E, 0x83, 0x2A,
-				0xD3, 0x0D, 0xC9, 0x77, 0xB1, 0x29, 0x57, 0xD3, 0x01, 0x56, 0x00, 0x00, 0x01, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x19, 0x00, 0x01, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x19, 0x41, 0x60, 0x21, 0x0B, 0x06,
-				0x2F, 0xFE, 0x4E, 0xCA, 0x68, 0xAE, 0x8C, 0xC1, 0x9E, 0x0E, 0x65, 0x01, 0x42, 0x00,
-				0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x05, 0x00,
-				0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x05, 0x89, 0x16,
-				0x25, 0xE2, 0x93, 0xF6, 0x3E, 0x83, 0xB4, 0xB4, 0x32, 0xA7, 0x9A, 0x5F, 0x8F, 0x73,
-				0x04, 0x43, 0x74, 0x72, 0x6C, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x01, 0x01, 0xE0, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x01, 0x00, 0xE0, 0xD7, 0x9B, 0x34, 0x74, 0xDE, 0x32, 0x05, 0xA6, 0xCA, 0xA2,
-				0xBE, 0x66, 0xCF, 0xF6, 0x68, 0x34, 0x03, 0x41, 0x6C, 0x74, 0x00, 0x00, 0x01, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0xE2, 0x00, 0x01, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0xE2, 0xFE, 0x63, 0xED, 0x9B, 0x04,
-				0x74, 0x3F, 0x4A, 0x22, 0x9E, 0x56, 0x52, 0xFD, 0x56, 0xB4, 0x6B, 0x01, 0x36, 0x00,
-				0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x23, 0x00,
-				0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x23, 0xD8, 0x3B,
-				0xB5, 0x25, 0x6A, 0x9A, 0x86, 0x57, 0x11, 0x7E, 0x18, 0xF1, 0x37, 0xD7, 0xB3, 0xBD,
-				0x01, 0x37, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
-				0x01, 0x24, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
-				0x24, 0x4B, 0xFD, 0xA9, 0x36, 0x72, 0xF4, 0x6F, 0x62, 0x5C, 0xE9, 0x52, 0x40, 0xDB,
-				0xB3, 0xA9, 0x73, 0x05, 0x53, 0x70, 0x61, 0x63, 0x65, 0x00, 0x00, 0x01, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x2C, 0x00, 0x01, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x2C, 0xEE, 0x53, 0x8D, 0x94, 0x41, 0x43,
-				0xCA, 0x63, 0xDD, 0x96, 0x89, 0x85, 0x5C, 0x4E, 0x12, 0x4A, 0x02, 0x46, 0x6E, 0x00,
-				0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x01, 0x02, 0x66,
-				0x6E, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00, 0x02,
-				0x66, 0x6E, 0xEB, 0x98, 0x07, 0xFF, 0xAD, 0x7D, 0xF9, 0xE4, 0xCB, 0x53, 0xC9, 0x80,
-				0x47, 0x46, 0x7C, 0x5E, 0x12, 0x52, 0x65, 0x63, 0x6F, 0x72, 0x64, 0x20, 0x4C, 0x61,
-				0x73, 0x74, 0x20, 0x4D, 0x69, 0x6E, 0x75, 0x74, 0x65, 0x00, 0x00, 0x02, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0xE2, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x01, 0x01, 0x43, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x01, 0x00, 0xE2, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
-				0x00, 0x43, 0xD7, 0x08, 0x55, 0x42, 0xAB, 0xA9, 0xC6, 0x70, 0x5A, 0xA9, 0xC8, 0xEE,
-				0x79, 0x4A, 0x1C, 0x96, 0x07, 0x52, 0x61, 0x70, 0x69, 0x64, 0x20, 0x46, 0x00, 0x00,
-				0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x09, 0x02, 0x0A,
-				0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x09, 0x0A, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x09, 0x01, 0x0A, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x01, 0x00, 0x09, 0xD5, 0xA4, 0x03, 0xE4, 0x49, 0x1E, 0x7C, 0x08, 0x32,
-				0x45, 0x59, 0xDC, 0x6A, 0xEA, 0x05, 0x0D, 0x0B, 0x52, 0x61, 0x70, 0x69, 0x64, 0x20,
-				0x53, 0x70, 0x61, 0x63, 0x65, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x00, 0x00, 0x01, 0x01, 0x2C, 0x02, 0x32, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-				0x01, 0x00, 0x2C, 0x32, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x2C,
-				0x01, 0x32, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x2C,
-			])
-			.into_boxed_slice(),
-		)
-	}
-}
-
-#[cfg(test)]
+#[cfg(all(test, feature = "defmt"))]
 mod defmt_mock {
 	use std::sync::Mutex;
 	use std::time::{SystemTime, UNIX_EPOCH};
@@ -0,0 +1,249 @@
+use crate::profile::{ActionEvent, KeyboardEvent, KeyboardKey};
+
+/// Returns the key that types `byte` on a US ASCII layout, and whether shift has to be held.
+pub fn ascii_key(byte: u8) -> Option<(KeyboardKey, bool)> {
+	use KeyboardKey::*;
+
+	let (code, shift) = match byte {
+		b'a'..=b'z' => (A as u8 + (byte - b'a'), false),
+		b'A'..=b'Z' => (A as u8 + (byte - b'A'), true),
+		b'1'..=b'9' => (ONE as u8 + (byte - b'1'), false),
+		_ => {
+			let (key, shift) = match byte {
+				b'0' => (ZERO, false),
+				b'!' => (ONE, true),
+				b'@' => (TWO, true),
+				b'#' => (THREE, true),
+				b'$' => (FOUR, true),
+				b'%' => (FIVE, true),
+				b'^' => (SIX, true),
+				b'&' => (SEVEN, true),
+				b'*' => (EIGHT, true),
+				b'(' => (NINE, true),
+				b')' => (ZERO, true),
+				b'\n' => (ENTER, false),
+				b'\t' => (TAB, false),
+				b' ' => (SPACEBAR, false),
+				b'-' => (MINUS, false),
+				b'_' => (MINUS, true),
+				b'=' => (EQUALS, false),
+				b'+' => (EQUALS, true),
+				b'[' => (LEFT_BRACKET, false),
+				b'{' => (LEFT_BRACKET, true),
+				b']' => (RIGHT_BRACKET, false),
+				b'}' => (RIGHT_BRACKET, true),
+				b'\\' => (BACKSLASH, false),
+				b'|' => (BACKSLASH, true),
+				b';' => (SEMICOLON, false),
+				b':' => (SEMICOLON, true),
+				b'\'' => (QUOTE, false),
+				b'"' => (QUOTE, true),
+				b'`' => (GRAVE_ACCENT, false),
+				b'~' => (GRAVE_ACCENT, true),
+				b',' => (COMMA, false),
+				b'<' => (COMMA, true),
+				b'.' => (PERIOD, false),
+				b'>' => (PERIOD, true),
+				b'/' => (FORWARD_SLASH, false),
+				b'?' => (FORWARD_SLASH, true),
+				_ => return None,
+			};
+			(key as u8, shift)
+		}
+	};
+
+	KeyboardKey::try_from(code).ok().map(|key| (key, shift))
+}
+
+pub fn is_typeable(text: &str) -> bool {
+	text.bytes().all(|byte| ascii_key(byte).is_some())
+}
+
+macro_rules! key_events {
+	($($key:ident),* $(,)?) => {
+		[$((
+			ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::$key)),
+			ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::$key)),
+		)),*]
+	};
+}
+
+// down and up events for every key `ascii_key` can return, indexed from `KeyboardKey::A`. The
+// macro engine hands out references to profile events, so typed keys need events that live
+// somewhere too.
+static KEY_EVENTS: [(ActionEvent, ActionEvent); 53] = key_events![
+	A,
+	B,
+	C,
+	D,
+	E,
+	F,
+	G,
+	H,
+	I,
+	J,
+	K,
+	L,
+	M,
+	N,
+	O,
+	P,
+	Q,
+	R,
+	S,
+	T,
+	U,
+	V,
+	W,
+	X,
+	Y,
+	Z,
+	ONE,
+	TWO,
+	THREE,
+	FOUR,
+	FIVE,
+	SIX,
+	SEVEN,
+	EIGHT,
+	NINE,
+	ZERO,
+	ENTER,
+	ESCAPE,
+	BACKSPACE,
+	TAB,
+	SPACEBAR,
+	MINUS,
+	EQUALS,
+	LEFT_BRACKET,
+	RIGHT_BRACKET,
+	BACKSLASH,
+	POUND,
+	SEMICOLON,
+	QUOTE,
+	GRAVE_ACCENT,
+	COMMA,
+	PERIOD,
+	FORWARD_SLASH,
+];
+
+static SHIFT_DOWN: ActionEvent =
+	ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::LEFT_SHIFT));
+static SHIFT_UP: ActionEvent = ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::LEFT_SHIFT));
+
+fn key_events(key: KeyboardKey) -> Option<&'static (ActionEvent, ActionEvent)> {
+	KEY_EVENTS.get((key as usize).checked_sub(KeyboardKey::A as usize)?)
+}
+
+/// Types a string one step per tick: a key goes down on one tick and comes back up on the next,
+/// so repeated characters don't merge into a single HID report.
+pub(crate) struct TextTyping<'a> {
+	remaining: &'a [u8],
+	held: Option<(KeyboardKey, bool)>,
+}
+
+impl<'a> TextTyping<'a> {
+	pub fn new(text: &'a str) -> Self {
+		Self {
+			remaining: text.as_bytes(),
+			held: None,
+		}
+	}
+
+	/// Emits the next step's events. Returns false once everything has been typed and released.
+	pub fn step(&mut self, on_event: &mut impl FnMut(&'a ActionEvent)) -> bool {
+		if let Some((key, shift)) = self.held.take() {
+			if let Some((_, up)) = key_events(key) {
+				on_event(up);
+			}
+			if shift {
+				on_event(&SHIFT_UP);
+			}
+			return true;
+		}
+
+		while let Some((byte, rest)) = self.remaining.split_first() {
+			self.remaining = rest;
+
+			// checked when the profile is loaded, so nothing should get skipped here
+			let Some((key, shift)) = ascii_key(*byte) else {
+				continue;
+			};
+			let Some((down, _)) = key_events(key) else {
+				continue;
+			};
+
+			if shift {
+				on_event(&SHIFT_DOWN);
+			}
+			on_event(down);
+			self.held = Some((key, shift));
+			return true;
+		}
+
+		false
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use alloc::vec::Vec;
+
+	fn key_code(event: &ActionEvent) -> (bool, u8) {
+		match event {
+			ActionEvent::Keyboard(KeyboardEvent::KeyDown(key)) => (true, *key as u8),
+			ActionEvent::Keyboard(KeyboardEvent::KeyUp(key)) => (false, *key as u8),
+			_ => panic!("Not a keyboard event"),
+		}
+	}
+
+	#[test]
+	fn ascii_maps_to_us_layout() {
+		assert!(matches!(ascii_key(b'c'), Some((KeyboardKey::C, false))));
+		assert!(matches!(ascii_key(b'C'), Some((KeyboardKey::C, true))));
+		assert!(matches!(ascii_key(b'0'), Some((KeyboardKey::ZERO, false))));
+		assert!(matches!(
+			ascii_key(b'?'),
+			Some((KeyboardKey::FORWARD_SLASH, true))
+		));
+		assert!(ascii_key(0x7F).is_none());
+		assert!(!is_typeable("caf\u{e9}"));
+	}
+
+	#[test]
+	fn every_mapped_key_has_events() {
+		for byte in 0..=u8::MAX {
+			if let Some((key, _)) = ascii_key(byte) {
+				let (down, up) = key_events(key).unwrap();
+				assert_eq!(key_code(down), (true, key as u8));
+				assert_eq!(key_code(up), (false, key as u8));
+			}
+		}
+	}
+
+	#[test]
+	fn typing_presses_and_releases_on_separate_steps() {
+		let mut typing = TextTyping::new("aA");
+		let mut steps: Vec<Vec<(bool, u8)>> = Vec::new();
+		loop {
+			let mut events = Vec::new();
+			if !typing.step(&mut |event| events.push(key_code(event))) {
+				break;
+			}
+			steps.push(events);
+		}
+
+		let a = KeyboardKey::A as u8;
+		let shift = KeyboardKey::LEFT_SHIFT as u8;
+		assert_eq!(
+			steps,
+			[
+				vec![(true, a)],
+				vec![(false, a)],
+				vec![(true, shift), (true, a)],
+				vec![(false, a), (false, shift)],
+			]
+		);
+	}
+}
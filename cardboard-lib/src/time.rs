@@ -1,3 +1,9 @@
+use core::cell::Cell;
+use core::future::{Future, poll_fn};
+use core::pin::pin;
+use core::task::Poll;
+use critical_section::Mutex;
+
 pub type Instant = fugit::Instant<u64, 1, 1_000_000>;
 pub type Duration = fugit::Duration<u64, 1, 1_000_000>;
 
@@ -6,5 +12,223 @@ pub trait Clock {
 	async fn after(&self, duration: Duration);
 	async fn at(&self, instant: Instant);
 
+	/// Runs `future` for at most `duration`, failing with `TIMED_OUT` if it takes longer.
+	async fn timeout<F: Future>(
+		&self,
+		duration: Duration,
+		future: F,
+	) -> Result<F::Output, &'static str>
+	where
+		Self: Sized,
+	{
+		with_deadline(self, self.now() + duration, future)
+			.await
+			.ok_or(TIMED_OUT)
+	}
+
 	// todo: output Instant and Duration types?
 }
+
+/// Error from `Clock::timeout`.
+pub const TIMED_OUT: &str = "Timed out";
+
+/// Runs `future` until `deadline` on `clock`; `None` if the deadline passes first.
+pub async fn with_deadline<F: Future>(
+	clock: &impl Clock,
+	deadline: Instant,
+	future: F,
+) -> Option<F::Output> {
+	let mut future = pin!(future);
+	let mut timer = pin!(clock.at(deadline));
+	poll_fn(|cx| {
+		if let Poll::Ready(output) = future.as_mut().poll(cx) {
+			return Poll::Ready(Some(output));
+		}
+		timer.as_mut().poll(cx).map(|_| None)
+	})
+	.await
+}
+
+/// What a `Ticker` does after falling behind by more than an interval.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissedTickPolicy {
+	/// Fires the missed ticks back to back until it's on schedule again.
+	CatchUp,
+	/// Drops the missed ticks and carries on at the next deadline on the original schedule.
+	Skip,
+	/// Starts a new schedule an interval after the late tick.
+	Delay,
+}
+
+/// Ticks every `interval` on a `Clock`, keeping track of the next deadline.
+pub struct Ticker {
+	interval: Duration,
+	next: Instant,
+	policy: MissedTickPolicy,
+}
+
+impl Ticker {
+	/// The first tick is an interval after `start`.
+	pub fn new(start: Instant, interval: Duration, policy: MissedTickPolicy) -> Self {
+		Self {
+			interval,
+			next: start + interval,
+			policy,
+		}
+	}
+
+	/// Takes effect after the next tick, which is already scheduled.
+	pub fn set_interval(&mut self, interval: Duration) {
+		self.interval = interval;
+	}
+
+	/// Schedules the next tick an interval after `now`, e.g. after waking up off schedule.
+	pub fn reset(&mut self, now: Instant) {
+		self.next = now + self.interval;
+	}
+
+	/// Waits for the next tick and returns when it happened.
+	pub async fn next(&mut self, clock: &impl Clock) -> Instant {
+		clock.at(self.next).await;
+		let now = clock.now();
+		self.advance(now);
+		now
+	}
+
+	fn advance(&mut self, now: Instant) {
+		let deadline = self.next;
+		let interval = self.interval.ticks();
+		self.next = match self.policy {
+			MissedTickPolicy::CatchUp => deadline + self.interval,
+			MissedTickPolicy::Delay => now + self.interval,
+			MissedTickPolicy::Skip if interval == 0 => now,
+			MissedTickPolicy::Skip => {
+				let behind = now
+					.checked_duration_since(deadline)
+					.map_or(0, |behind| behind.ticks());
+				deadline + Duration::from_ticks((behind / interval + 1) * interval)
+			}
+		};
+	}
+}
+
+/// A clock chip that keeps real time, e.g. on a coin cell across power loss.
+pub trait Rtc {
+	/// Milliseconds since the Unix epoch.
+	async fn read_unix_ms(&mut self) -> Result<u64, &'static str>;
+}
+
+/// Real time, once the host or an RTC has said what it is. Shared between tasks, so the
+/// offset from the monotonic clock lives behind a critical section.
+pub struct WallClock {
+	offset_ms: Mutex<Cell<Option<u64>>>,
+}
+
+impl WallClock {
+	pub const fn new() -> Self {
+		Self {
+			offset_ms: Mutex::new(Cell::new(None)),
+		}
+	}
+
+	/// Records that `now` on the monotonic clock is `unix_ms` in real time.
+	pub fn sync(&self, now: Instant, unix_ms: u64) {
+		let offset = unix_ms.saturating_sub(now.duration_since_epoch().to_millis());
+		critical_section::with(|cs| self.offset_ms.borrow(cs).set(Some(offset)));
+	}
+
+	pub async fn sync_from_rtc(
+		&self,
+		clock: &impl Clock,
+		rtc: &mut impl Rtc,
+	) -> Result<(), &'static str> {
+		let unix_ms = rtc.read_unix_ms().await?;
+		self.sync(clock.now(), unix_ms);
+		Ok(())
+	}
+
+	/// `instant` in milliseconds since the Unix epoch; None until synced.
+	pub fn unix_ms(&self, instant: Instant) -> Option<u64> {
+		let offset = critical_section::with(|cs| self.offset_ms.borrow(cs).get())?;
+		Some(offset + instant.duration_since_epoch().to_millis())
+	}
+}
+
+impl Default for WallClock {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use fugit::ExtU64;
+
+	fn at_ms(ms: u64) -> Instant {
+		Instant::from_ticks(ms * 1000)
+	}
+
+	#[test]
+	fn on_time_ticks_keep_the_schedule() {
+		for policy in [
+			MissedTickPolicy::CatchUp,
+			MissedTickPolicy::Skip,
+			MissedTickPolicy::Delay,
+		] {
+			let mut ticker = Ticker::new(at_ms(0), 10.millis(), policy);
+			ticker.advance(at_ms(10));
+			assert_eq!(ticker.next, at_ms(20));
+		}
+	}
+
+	#[test]
+	fn late_ticks_follow_the_policy() {
+		let mut catch_up = Ticker::new(at_ms(0), 10.millis(), MissedTickPolicy::CatchUp);
+		catch_up.advance(at_ms(35));
+		assert_eq!(catch_up.next, at_ms(20));
+
+		let mut skip = Ticker::new(at_ms(0), 10.millis(), MissedTickPolicy::Skip);
+		skip.advance(at_ms(35));
+		assert_eq!(skip.next, at_ms(40));
+
+		let mut delay = Ticker::new(at_ms(0), 10.millis(), MissedTickPolicy::Delay);
+		delay.advance(at_ms(35));
+		assert_eq!(delay.next, at_ms(45));
+	}
+
+	// every deadline has already passed
+	struct ExpiredClock;
+
+	impl Clock for ExpiredClock {
+		fn now(&self) -> Instant {
+			Instant::from_ticks(0)
+		}
+
+		async fn after(&self, _duration: Duration) {}
+
+		async fn at(&self, _instant: Instant) {}
+	}
+
+	#[tokio::test]
+	async fn timeout_gives_up_unless_the_future_is_ready() {
+		let clock = ExpiredClock;
+		assert_eq!(clock.timeout(1.millis(), async { 7 }).await, Ok(7));
+		assert_eq!(
+			clock
+				.timeout(1.millis(), core::future::pending::<()>())
+				.await,
+			Err(TIMED_OUT)
+		);
+	}
+
+	#[test]
+	fn wall_clock_maps_instants_once_synced() {
+		let wall_clock = WallClock::new();
+		assert_eq!(wall_clock.unix_ms(Instant::from_ticks(0)), None);
+
+		wall_clock.sync(at_ms(2_000), 1_700_000_000_000);
+		assert_eq!(wall_clock.unix_ms(at_ms(500)), Some(1_699_999_998_500));
+		assert_eq!(wall_clock.unix_ms(at_ms(3_000)), Some(1_700_000_001_000));
+	}
+}
@@ -0,0 +1,124 @@
+use core::cell::Cell;
+use critical_section::Mutex;
+
+use crate::hid::HidReport;
+
+/// Somewhere HID reports can go: USB endpoints, a BLE link or a UART bridge to another
+/// board.
+pub trait HidTransport<const SIZE_K: usize, const SIZE_M: usize, const SIZE_C: usize> {
+	async fn send(
+		&mut self,
+		report: &HidReport<SIZE_K, SIZE_M, SIZE_C>,
+	) -> Result<(), &'static str>;
+}
+
+/// Which side of a [`Routed`] pair gets reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+	First,
+	Second,
+	Both,
+}
+
+/// The route reports take, switchable at runtime by whatever picks the transport, such as a
+/// macro or the power source. Shared the same way as `UsbStats`.
+pub struct TransportSelect {
+	route: Mutex<Cell<Route>>,
+}
+
+impl TransportSelect {
+	pub const fn new(route: Route) -> Self {
+		Self {
+			route: Mutex::new(Cell::new(route)),
+		}
+	}
+
+	pub fn set(&self, route: Route) {
+		critical_section::with(|cs| self.route.borrow(cs).set(route));
+	}
+
+	pub fn get(&self) -> Route {
+		critical_section::with(|cs| self.route.borrow(cs).get())
+	}
+}
+
+/// Two transports with reports routed between them; nest them for more than two.
+pub struct Routed<A, B> {
+	pub first: A,
+	pub second: B,
+	select: &'static TransportSelect,
+}
+
+impl<A, B> Routed<A, B> {
+	pub fn new(first: A, second: B, select: &'static TransportSelect) -> Self {
+		Self {
+			first,
+			second,
+			select,
+		}
+	}
+}
+
+impl<A, B, const SIZE_K: usize, const SIZE_M: usize, const SIZE_C: usize>
+	HidTransport<SIZE_K, SIZE_M, SIZE_C> for Routed<A, B>
+where
+	A: HidTransport<SIZE_K, SIZE_M, SIZE_C>,
+	B: HidTransport<SIZE_K, SIZE_M, SIZE_C>,
+{
+	async fn send(
+		&mut self,
+		report: &HidReport<SIZE_K, SIZE_M, SIZE_C>,
+	) -> Result<(), &'static str> {
+		match self.select.get() {
+			Route::First => self.first.send(report).await,
+			Route::Second => self.second.send(report).await,
+			Route::Both => {
+				// one transport failing shouldn't keep reports from the other
+				let first = self.first.send(report).await;
+				let second = self.second.send(report).await;
+				first.and(second)
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use alloc::vec::Vec;
+
+	#[derive(Default)]
+	struct MockTransport {
+		sent: Vec<[u8; 1]>,
+	}
+
+	impl HidTransport<1, 1, 1> for MockTransport {
+		async fn send(&mut self, report: &HidReport<1, 1, 1>) -> Result<(), &'static str> {
+			self.sent.extend(report.keyboard);
+			Ok(())
+		}
+	}
+
+	fn key(code: u8) -> HidReport<1, 1, 1> {
+		HidReport {
+			keyboard: Some([code]),
+			mouse: None,
+			consumer: None,
+		}
+	}
+
+	#[tokio::test]
+	async fn reports_follow_the_selected_route() {
+		static SELECT: TransportSelect = TransportSelect::new(Route::First);
+		let mut routed = Routed::new(MockTransport::default(), MockTransport::default(), &SELECT);
+
+		routed.send(&key(1)).await.unwrap();
+		SELECT.set(Route::Second);
+		routed.send(&key(2)).await.unwrap();
+		SELECT.set(Route::Both);
+		routed.send(&key(3)).await.unwrap();
+
+		assert_eq!(routed.first.sent, [[1], [3]]);
+		assert_eq!(routed.second.sent, [[2], [3]]);
+	}
+}
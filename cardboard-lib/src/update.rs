@@ -0,0 +1,346 @@
+use alloc::vec;
+use core::ops::RangeInclusive;
+
+use sha2::{Digest, Sha256};
+
+use crate::crc::crc32_mpeg2;
+use crate::storage::{BlockFlash, BlockFlashExt};
+
+// The staging partition starts with `[length u32][sha256 [u8; 32]]`, written when an update
+// begins, followed by a commit marker written once the image has been verified. The image
+// itself starts on the next erase block, so the flasher can clear the header without touching
+// it.
+const UPDATE_INFO_SIZE: usize = 4 + DIGEST_SIZE;
+const COMMIT_MARKER_SIZE: usize = 4;
+const COMMIT_MARKER: u32 = 0xC0DE_F1A5;
+const BOOT2_SIZE: usize = 256;
+
+/// Size of the SHA-256 digest an update is started with.
+pub const DIGEST_SIZE: usize = 32;
+
+fn commit_marker_offset<F: BlockFlash + ?Sized>() -> usize {
+	UPDATE_INFO_SIZE.next_multiple_of(F::WRITE_BLOCK_SIZE)
+}
+
+/// Where the image starts in the staging partition.
+pub fn image_offset<F: BlockFlash + ?Sized>() -> usize {
+	(commit_marker_offset::<F>() + COMMIT_MARKER_SIZE)
+		.next_multiple_of(F::WRITE_BLOCK_SIZE)
+		.next_multiple_of(F::ERASE_BLOCK_SIZE)
+}
+
+/// Largest image the staging partition can hold.
+pub fn max_image_length<F: BlockFlash + ?Sized>(flash: &F) -> usize {
+	flash.length().saturating_sub(image_offset::<F>())
+}
+
+/// What a chip's boot ROM expects at the start of a firmware image, so that an image built for
+/// something else is never committed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageLayout {
+	/// Address the image runs from.
+	pub flash_base: u32,
+	/// Whether the image starts with an RP2040 boot2 stage, whose last word is a CRC-32 of the
+	/// rest of it.
+	pub boot2: bool,
+	/// Offset of the vector table in the image.
+	pub vector_table: usize,
+	/// Where the initial stack pointer may point. It's usually the end of RAM, so the range is
+	/// inclusive.
+	pub ram: RangeInclusive<u32>,
+}
+
+/// Checks that the image starts the way `layout` says it should: with an intact boot2 stage if
+/// there is one, then a vector table whose stack pointer is in RAM and whose reset handler is
+/// Thumb code inside the image.
+pub fn check_image(image: &[u8], layout: &ImageLayout) -> Result<(), &'static str> {
+	if layout.boot2 {
+		let boot2 = image
+			.get(..BOOT2_SIZE)
+			.ok_or("Firmware image is too short for boot2")?;
+		let (code, checksum) = boot2.split_at(BOOT2_SIZE - 4);
+		if crc32_mpeg2(code).to_le_bytes() != checksum {
+			return Err("Firmware image doesn't start with boot2");
+		}
+	}
+
+	let vectors = image
+		.get(layout.vector_table..layout.vector_table + 8)
+		.ok_or("Firmware image is too short for a vector table")?;
+	let stack_pointer = u32::from_le_bytes([vectors[0], vectors[1], vectors[2], vectors[3]]);
+	let reset = u32::from_le_bytes([vectors[4], vectors[5], vectors[6], vectors[7]]);
+	if !layout.ram.contains(&stack_pointer) {
+		return Err("Firmware image's initial stack pointer is not in RAM");
+	}
+	let code = layout.flash_base as u64 + layout.vector_table as u64 + 8
+		..layout.flash_base as u64 + image.len() as u64;
+	if reset & 1 == 0 || !code.contains(&((reset & !1) as u64)) {
+		return Err("Firmware image's reset vector is not in the image");
+	}
+	Ok(())
+}
+
+/// A firmware image that has been, or is being, written to the staging partition.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StagedUpdate {
+	pub length: usize,
+	pub digest: [u8; DIGEST_SIZE],
+	pub committed: bool,
+}
+
+/// Reads the staging header, if an update has been started.
+pub fn staged_update<F: BlockFlash + ?Sized>(flash: &F) -> Option<StagedUpdate> {
+	let data = flash.as_slice();
+	let info = data.get(..UPDATE_INFO_SIZE)?;
+	let length = u32::from_le_bytes([info[0], info[1], info[2], info[3]]);
+	if length == u32::MAX {
+		return None;
+	}
+	let mut digest = [0; DIGEST_SIZE];
+	digest.copy_from_slice(&info[4..UPDATE_INFO_SIZE]);
+	let marker_offset = commit_marker_offset::<F>();
+	let marker = data.get(marker_offset..marker_offset + COMMIT_MARKER_SIZE)?;
+	let committed =
+		u32::from_le_bytes([marker[0], marker[1], marker[2], marker[3]]) == COMMIT_MARKER;
+
+	Some(StagedUpdate {
+		length: length as usize,
+		digest,
+		committed,
+	})
+}
+
+/// Erases enough of the staging partition for an image of `length` bytes and records the
+/// length and SHA-256 digest it's expected to have.
+pub async fn begin_update<F: BlockFlash>(
+	flash: &mut F,
+	length: usize,
+	digest: &[u8; DIGEST_SIZE],
+	progress: impl FnMut(usize, usize),
+) -> Result<(), &'static str> {
+	if length == 0 || length > max_image_length(flash) {
+		return Err("Firmware image doesn't fit in the update partition");
+	}
+
+	flash
+		.erase_at_least_with_progress(update_erase_length::<F>(length), progress)
+		.await?;
+	record_update(flash, length, digest).await
+}
+
+/// How much of the staging partition has to be erased for an image of `length` bytes.
+pub fn update_erase_length<F: BlockFlash + ?Sized>(length: usize) -> usize {
+	image_offset::<F>() + length
+}
+
+/// Records the length and SHA-256 digest of an image about to be written to a staging
+/// partition that has already been erased.
+pub async fn record_update<F: BlockFlash>(
+	flash: &mut F,
+	length: usize,
+	digest: &[u8; DIGEST_SIZE],
+) -> Result<(), &'static str> {
+	let mut info = vec![0xFF; UPDATE_INFO_SIZE.next_multiple_of(F::WRITE_BLOCK_SIZE)];
+	info[..4].copy_from_slice(&(length as u32).to_le_bytes());
+	info[4..UPDATE_INFO_SIZE].copy_from_slice(digest);
+	flash.write(0, &info).await
+}
+
+/// Checks that the update is in range for an image chunk written at `offset`.
+pub fn check_chunk<F: BlockFlash + ?Sized>(
+	flash: &F,
+	offset: usize,
+	length: usize,
+) -> Result<(), &'static str> {
+	let update = staged_update(flash).ok_or("No firmware update in progress")?;
+	if update.committed {
+		return Err("Firmware update is already committed");
+	}
+	if offset % F::WRITE_BLOCK_SIZE != 0 {
+		return Err("Firmware chunk is not write block aligned");
+	}
+	if offset + length > update.length {
+		return Err("Firmware chunk is past the end of the image");
+	}
+	Ok(())
+}
+
+/// The image an update was started for, as far as it has been written.
+pub fn staged_image<F: BlockFlash + ?Sized>(
+	flash: &F,
+	update: &StagedUpdate,
+) -> Result<&'static [u8], &'static str> {
+	let start = image_offset::<F>();
+	flash
+		.as_slice()
+		.get(start..start + update.length)
+		.ok_or("Firmware image length exceeds the update partition")
+}
+
+/// Checks the staged image against the digest it was started with.
+pub fn verify_update<F: BlockFlash + ?Sized>(flash: &F) -> Result<StagedUpdate, &'static str> {
+	let update = staged_update(flash).ok_or("No firmware update in progress")?;
+	if Sha256::digest(staged_image(flash, &update)?).as_slice() != update.digest {
+		return Err("Firmware image doesn't match its SHA-256 digest");
+	}
+	Ok(update)
+}
+
+/// Marks a verified image as ready for the flasher to install on the next boot, if it looks
+/// like firmware for `layout`.
+pub async fn commit_update<F: BlockFlash>(
+	flash: &mut F,
+	layout: &ImageLayout,
+) -> Result<(), &'static str> {
+	let update = verify_update(flash)?;
+	check_image(staged_image(flash, &update)?, layout)?;
+	if update.committed {
+		return Ok(());
+	}
+
+	let mut marker = vec![0xFF; COMMIT_MARKER_SIZE.next_multiple_of(F::WRITE_BLOCK_SIZE)];
+	marker[..COMMIT_MARKER_SIZE].copy_from_slice(&COMMIT_MARKER.to_le_bytes());
+	flash.write(commit_marker_offset::<F>(), &marker).await
+}
+
+/// The committed image for the flasher to install, if there is one and it's still intact.
+pub fn committed_image<F: BlockFlash + ?Sized>(
+	flash: &F,
+	layout: &ImageLayout,
+) -> Option<&'static [u8]> {
+	// checked before hashing, so a staged but uncommitted update doesn't slow down every boot
+	if !staged_update(flash)?.committed {
+		return None;
+	}
+	let update = verify_update(flash).ok()?;
+	let image = staged_image(flash, &update).ok()?;
+	check_image(image, layout).ok()?;
+	Some(image)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::*;
+
+	const LAYOUT: ImageLayout = ImageLayout {
+		flash_base: 0x1000_0000,
+		boot2: false,
+		vector_table: 0,
+		ram: 0x2000_0000..=0x2004_2000,
+	};
+
+	// a vector table with the stack at the top of RAM and the reset handler right after it
+	fn firmware_image() -> Vec<u8> {
+		let mut image = Vec::new();
+		image.extend_from_slice(&0x2004_2000u32.to_le_bytes());
+		image.extend_from_slice(&0x1000_0009u32.to_le_bytes());
+		image.extend_from_slice(&[0x00, 0xBE, 0xFE, 0xE7]);
+		image
+	}
+
+	async fn stage(flash: &mut FakeNorFlash, image: &[u8]) {
+		begin_update(flash, image.len(), &Sha256::digest(image).into(), |_, _| {})
+			.await
+			.unwrap();
+		check_chunk(flash, 0, image.len()).unwrap();
+		let offset = image_offset::<FakeNorFlash>();
+		flash.write(offset, image).await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn committed_image_is_available_to_the_flasher() {
+		let mut flash = FakeNorFlash::new(64);
+		let image = firmware_image();
+		assert_eq!(staged_update(&flash), None);
+
+		stage(&mut flash, &image).await;
+		assert_eq!(committed_image(&flash, &LAYOUT), None);
+
+		commit_update(&mut flash, &LAYOUT).await.unwrap();
+		assert_eq!(
+			staged_update(&flash),
+			Some(StagedUpdate {
+				length: image.len(),
+				digest: Sha256::digest(&image).into(),
+				committed: true,
+			})
+		);
+		assert_eq!(committed_image(&flash, &LAYOUT), Some(&image[..]));
+	}
+
+	#[tokio::test]
+	async fn corrupted_image_is_not_committed() {
+		let mut flash = FakeNorFlash::new(64);
+		stage(&mut flash, &firmware_image()).await;
+		flash.data[image_offset::<FakeNorFlash>() + 10] = 0;
+
+		assert!(verify_update(&flash).is_err());
+		assert!(commit_update(&mut flash, &LAYOUT).await.is_err());
+		assert_eq!(committed_image(&flash, &LAYOUT), None);
+	}
+
+	#[tokio::test]
+	async fn image_without_a_vector_table_is_not_committed() {
+		let mut flash = FakeNorFlash::new(64);
+		stage(&mut flash, &[1, 2, 3, 4, 5, 6, 7, 8, 9]).await;
+
+		assert!(verify_update(&flash).is_ok());
+		assert!(commit_update(&mut flash, &LAYOUT).await.is_err());
+		assert_eq!(committed_image(&flash, &LAYOUT), None);
+	}
+
+	#[test]
+	fn vector_table_must_point_into_ram_and_the_image() {
+		let image = firmware_image();
+		assert!(check_image(&image, &LAYOUT).is_ok());
+
+		let mut stack_in_flash = image.clone();
+		stack_in_flash[..4].copy_from_slice(&0x1000_0000u32.to_le_bytes());
+		assert!(check_image(&stack_in_flash, &LAYOUT).is_err());
+
+		let mut reset_past_the_end = image.clone();
+		reset_past_the_end[4..8].copy_from_slice(&0x1000_0101u32.to_le_bytes());
+		assert!(check_image(&reset_past_the_end, &LAYOUT).is_err());
+
+		let mut reset_not_thumb = image.clone();
+		reset_not_thumb[4..8].copy_from_slice(&0x1000_0008u32.to_le_bytes());
+		assert!(check_image(&reset_not_thumb, &LAYOUT).is_err());
+	}
+
+	#[test]
+	fn boot2_must_have_its_checksum() {
+		let layout = ImageLayout {
+			boot2: true,
+			vector_table: BOOT2_SIZE,
+			..LAYOUT
+		};
+		let mut image = vec![0; BOOT2_SIZE];
+		let checksum = crc32_mpeg2(&image[..BOOT2_SIZE - 4]);
+		image[BOOT2_SIZE - 4..].copy_from_slice(&checksum.to_le_bytes());
+		image.extend_from_slice(&0x2004_2000u32.to_le_bytes());
+		image.extend_from_slice(&0x1000_0109u32.to_le_bytes());
+		image.extend_from_slice(&[0x00, 0xBE, 0xFE, 0xE7]);
+		assert!(check_image(&image, &layout).is_ok());
+
+		image[0] = 1;
+		assert!(check_image(&image, &layout).is_err());
+	}
+
+	#[tokio::test]
+	async fn chunks_must_fit_in_the_image() {
+		let mut flash = FakeNorFlash::new(64);
+		assert!(check_chunk(&flash, 0, 1).is_err());
+		assert!(
+			begin_update(&mut flash, 64, &[0; DIGEST_SIZE], |_, _| {})
+				.await
+				.is_err()
+		);
+
+		begin_update(&mut flash, 8, &[0; DIGEST_SIZE], |_, _| {})
+			.await
+			.unwrap();
+		assert!(check_chunk(&flash, 4, 4).is_ok());
+		assert!(check_chunk(&flash, 4, 5).is_err());
+	}
+}
@@ -8,24 +8,222 @@
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
 
+use std::collections::HashSet;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    // the RP2350 has no boot2 stage and needs room for its image definition block
+    let memory_x: &[u8] = if env::var_os("CARGO_FEATURE_RP2350").is_some() {
+        include_bytes!("memory_rp2350.x")
+    } else {
+        include_bytes!("memory.x")
+    };
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory_x)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
     // By default, Cargo will re-run a build script whenever
     // any file in the project changes. By specifying `memory.x`
     // here, we ensure the build script is only re-run when
-    // `memory.x` is changed.
+    // a memory layout is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory_rp2350.x");
+
+    // the firmware update flasher relies on inlining, so opt-level 0 builds (the `debug-probe`
+    // profile) refuse updates
+    println!("cargo:rustc-check-cfg=cfg(unoptimized)");
+    if env::var("OPT_LEVEL").as_deref() == Ok("0") {
+        println!("cargo:rustc-cfg=unoptimized");
+    }
+
+    write_build_info(out);
+    write_board_layouts(out);
+}
+
+/// Generates `build_info.rs` in the output directory with the git revision, build time
+/// and enabled cargo features, for the firmware to report over the command protocol.
+fn write_build_info(out: &PathBuf) {
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    // reproducible builds pin the time through SOURCE_DATE_EPOCH
+    let timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        });
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(str::to_string))
+        .map(|feature| feature.to_lowercase().replace('_', "-"))
+        .collect();
+    features.sort();
+
+    let mut file = File::create(out.join("build_info.rs")).unwrap();
+    writeln!(file, "pub const GIT_HASH: &str = {:?};", git_hash).unwrap();
+    writeln!(file, "pub const BUILD_TIMESTAMP: u64 = {};", timestamp).unwrap();
+    writeln!(file, "pub const FEATURES: &[&str] = &{:?};", features).unwrap();
+
+    // pick up checkouts and new commits, including refs git has packed; without
+    // SOURCE_DATE_EPOCH the timestamp only changes when something else triggers a rebuild
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs/heads");
+    println!("cargo:rerun-if-changed=../.git/packed-refs");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+/// Generates `<board>_layout.rs` in the output directory for every `src/<board>/board.layout`,
+/// with the matrix size, row and column pins and key IDs the board's `BoardConfig` is built
+/// from.
+fn write_board_layouts(out: &Path) {
+    // a board directory added or removed changes which layouts there are
+    println!("cargo:rerun-if-changed=src");
+    for entry in fs::read_dir("src").unwrap() {
+        let dir = entry.unwrap().path();
+        let layout = dir.join("board.layout");
+        if !layout.is_file() {
+            continue;
+        }
+        println!("cargo:rerun-if-changed={}", layout.display());
+
+        let source = fs::read_to_string(&layout).unwrap();
+        let generated = generate_board_layout(&source)
+            .unwrap_or_else(|e| panic!("{}: {}", layout.display(), e));
+        let board = dir.file_name().unwrap().to_str().unwrap();
+        fs::write(out.join(format!("{}_layout.rs", board)), generated).unwrap();
+    }
+}
+
+fn generate_board_layout(source: &str) -> Result<String, String> {
+    let mut namespace = None;
+    let mut col_pins: Option<Vec<u8>> = None;
+    let mut rows: Vec<(u8, Vec<&str>)> = Vec::new();
+
+    let mut words = source
+        .lines()
+        .map(|line| line.split('#').next().unwrap())
+        .flat_map(str::split_whitespace)
+        .peekable();
+    let is_directive = |word: &&str| matches!(*word, "namespace" | "cols" | "row");
+    while let Some(word) = words.next() {
+        match word {
+            "namespace" => {
+                let id = words.next().ok_or("`namespace` needs a UUID")?;
+                namespace = Some(parse_uuid(id)?);
+            }
+            "cols" => {
+                let mut pins = Vec::new();
+                while let Some(pin) = words.next_if(|word| !is_directive(word)) {
+                    pins.push(parse_pin(pin)?);
+                }
+                col_pins = Some(pins);
+            }
+            "row" => {
+                let pin = parse_pin(words.next().ok_or("`row` needs a pin")?)?;
+                let mut keys = Vec::new();
+                while let Some(key) = words.next_if(|word| !is_directive(word)) {
+                    keys.push(key);
+                }
+                rows.push((pin, keys));
+            }
+            _ => {
+                return Err(format!(
+                    "expected `namespace`, `cols` or `row`, found `{}`",
+                    word
+                ))
+            }
+        }
+    }
+
+    let col_pins = col_pins.ok_or("no `cols` given")?;
+    if col_pins.is_empty() || rows.is_empty() {
+        return Err("the matrix needs at least one row and column".to_string());
+    }
+
+    let mut pins = HashSet::new();
+    for pin in rows.iter().map(|(pin, _)| pin).chain(&col_pins) {
+        if !pins.insert(*pin) {
+            return Err(format!("GPIO {} is used more than once", pin));
+        }
+    }
+
+    let mut key_ids = Vec::new();
+    let mut seen = HashSet::new();
+    for (row, (pin, keys)) in rows.iter().enumerate() {
+        if keys.len() != col_pins.len() {
+            return Err(format!(
+                "row {} (GPIO {}) has {} keys, but there are {} columns",
+                row,
+                pin,
+                keys.len(),
+                col_pins.len()
+            ));
+        }
+        let mut ids = Vec::new();
+        for (col, key) in keys.iter().enumerate() {
+            let id = if *key == "*" {
+                let namespace = namespace.ok_or("`*` keys need a `namespace`")?;
+                uuid::Uuid::new_v5(&namespace, format!("{},{}", row, col).as_bytes())
+            } else {
+                parse_uuid(key)?
+            };
+            if !seen.insert(id) {
+                return Err(format!("key ID {} is used more than once", id));
+            }
+            ids.push(id);
+        }
+        key_ids.push(ids);
+    }
+
+    let list = |items: Vec<String>| items.join(", ");
+    let mut generated = String::new();
+    generated += &format!("pub const ROWS: usize = {};\n", rows.len());
+    generated += &format!("pub const COLS: usize = {};\n", col_pins.len());
+    generated += &format!(
+        "pub const ROW_PINS: [u8; ROWS] = [{}];\n",
+        list(rows.iter().map(|(pin, _)| pin.to_string()).collect())
+    );
+    generated += &format!(
+        "pub const COL_PINS: [u8; COLS] = [{}];\n",
+        list(col_pins.iter().map(u8::to_string).collect())
+    );
+    generated += "pub const KEY_IDS: [[::uuid::Uuid; COLS]; ROWS] = [\n";
+    for ids in key_ids {
+        let ids = ids
+            .iter()
+            .map(|id| format!("::uuid::Uuid::from_u128({:#034x})", id.as_u128()))
+            .collect();
+        generated += &format!("    [{}],\n", list(ids));
+    }
+    generated += "];\n";
+    Ok(generated)
+}
+
+fn parse_pin(word: &str) -> Result<u8, String> {
+    word.parse()
+        .map_err(|_| format!("`{}` is not a GPIO number", word))
+}
+
+fn parse_uuid(word: &str) -> Result<uuid::Uuid, String> {
+    uuid::Uuid::parse_str(word).map_err(|_| format!("`{}` is not a UUID", word))
 }
@@ -1,7 +1,8 @@
 MEMORY {
     BOOT2   : ORIGIN = 0x10000000, LENGTH = 256
-    FLASH   : ORIGIN = 0x10000100, LENGTH = 1500K - 256
-	PROFILE : ORIGIN = 0x10180000, LENGTH = 500K
+    FLASH   : ORIGIN = 0x10000100, LENGTH = 768K - 256
+	/* firmware update staging (768K), then profile data from 0x10180000 (500K) */
+	PROFILE : ORIGIN = 0x100C0000, LENGTH = 1268K
     RAM     : ORIGIN = 0x20000000, LENGTH = 256K
 }
 
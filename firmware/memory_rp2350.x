@@ -0,0 +1,51 @@
+MEMORY {
+    FLASH   : ORIGIN = 0x10000000, LENGTH = 768K
+	/* firmware update staging (768K), then profile data from 0x10180000 (500K) */
+	PROFILE : ORIGIN = 0x100C0000, LENGTH = 1268K
+    RAM     : ORIGIN = 0x20000000, LENGTH = 512K
+    SRAM8   : ORIGIN = 0x20080000, LENGTH = 4K
+    SRAM9   : ORIGIN = 0x20081000, LENGTH = 4K
+}
+
+SECTIONS {
+    /* ### Boot ROM info, must be within the first 4K of flash */
+    .start_block : ALIGN(4)
+    {
+        __start_block_addr = .;
+        KEEP(*(.start_block));
+        KEEP(*(.boot_info));
+    } > FLASH
+} INSERT AFTER .vector_table;
+
+/* move .text to start after the boot info */
+_stext = ADDR(.start_block) + SIZEOF(.start_block);
+
+SECTIONS {
+    /* ### Picotool 'Binary Info' entries */
+    .bi_entries : ALIGN(4)
+    {
+        __bi_entries_start = .;
+        KEEP(*(.bi_entries));
+        . = ALIGN(4);
+        __bi_entries_end = .;
+    } > FLASH
+} INSERT AFTER .text;
+
+SECTIONS {
+    /* ### Boot ROM extra info, must be within the last 4K of flash */
+    .end_block : ALIGN(4)
+    {
+        __end_block_addr = .;
+        KEEP(*(.end_block));
+    } > FLASH
+} INSERT AFTER .uninit;
+
+SECTIONS {
+	/* profile data */
+	.profile : {
+		*(.profile);
+	} > PROFILE
+} INSERT BEFORE .text;
+
+PROVIDE(start_to_end = __end_block_addr - __start_block_addr);
+PROVIDE(end_to_start = __start_block_addr - __end_block_addr);
@@ -0,0 +1,198 @@
+use alloc::boxed::Box;
+
+use cardboard_lib::{
+	device::{DeviceTypeId, PartitionSizes},
+	input::{ColPin, KeyId, KeyMatrix, RowPin},
+	rp::Rp2040ColPort,
+	state::MacroLimit,
+	storage::{BlockFlash, FlashPartition},
+	tasks::{BootAction, BootKey},
+	time::Duration,
+};
+use embassy_rp::gpio::{AnyPin, Input, Level, Output, Pull};
+use uuid::Uuid;
+
+/// Everything that differs between keypads built on this firmware. Each board binary defines
+/// one as a `const` and hands it to the shared setup code.
+pub struct BoardConfig<const ROWS: usize, const COLS: usize> {
+	/// Shown to the host until the user renames the device.
+	pub name: &'static str,
+	pub manufacturer: &'static str,
+	pub device_type: DeviceTypeId,
+	pub heap_size: usize,
+	/// Ceilings for the parts of the heap the host controls, which have to fit in `heap_size`.
+	pub memory_budgets: MemoryBudgetSizes,
+	pub macro_limit: MacroLimit,
+	/// How many virtual keys the host can set at once.
+	pub virtual_keys: VirtualKeyCount,
+	pub flash: FlashLayout,
+	/// GPIO numbers of the row outputs, top to bottom.
+	pub row_pins: [u8; ROWS],
+	/// GPIO numbers of the column inputs, left to right.
+	pub col_pins: [u8; COLS],
+	pub debounce_ms: u64,
+	/// Profiles refer to keys by these IDs, so they can't change once a board has shipped.
+	pub key_ids: [[Uuid; COLS]; ROWS],
+	/// Held at power-up to enter the bootloader, as `(row, col)`.
+	pub bootloader_key: (usize, usize),
+	/// Held at power-up to start with an empty profile, as `(row, col)`.
+	pub safe_mode_key: (usize, usize),
+	pub command_channel: CommandChannel,
+}
+
+/// Heap sizes in bytes, handed to `MemoryBudgets`.
+#[derive(Clone, Copy)]
+pub struct MemoryBudgetSizes {
+	pub profile: usize,
+	pub macros: usize,
+	pub serial: usize,
+}
+
+/// The sizes Set Virtual Keys comes in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VirtualKeyCount {
+	Keys8,
+	Keys32,
+	Keys128,
+}
+
+impl VirtualKeyCount {
+	/// Reported to the host in Identify.
+	pub const fn keys(self) -> u16 {
+		match self {
+			Self::Keys8 => 8,
+			Self::Keys32 => 32,
+			Self::Keys128 => 128,
+		}
+	}
+}
+
+/// The link the host sends commands over. HID always goes over USB.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandChannel {
+	/// The CDC-ACM interface next to the HID ones.
+	Usb,
+	/// The UART handed to `RuntimeBuilder::command_uart`, for boards wired to a bridge or to
+	/// the other half of a split keypad.
+	Uart,
+	/// The I2C target handed to `RuntimeBuilder::command_i2c`, for an embedded host.
+	I2c,
+	/// The SPI target handed to `RuntimeBuilder::command_spi`, for a keypad built into a larger
+	/// product.
+	Spi,
+}
+
+impl<const ROWS: usize, const COLS: usize> BoardConfig<ROWS, COLS>
+where
+	[(); ROWS * COLS]:,
+{
+	/// Key IDs in the row-major order the matrix scans them.
+	pub fn key_ids(&self) -> [KeyId; ROWS * COLS] {
+		core::array::from_fn(|i| KeyId::new(self.key_ids[i / COLS][i % COLS]))
+	}
+
+	pub fn boot_keys(&self) -> [BootKey; 2] {
+		let (row, col) = self.bootloader_key;
+		let (safe_row, safe_col) = self.safe_mode_key;
+		[
+			BootKey {
+				key: KeyId::new(self.key_ids[row][col]),
+				action: BootAction::Bootloader,
+			},
+			BootKey {
+				key: KeyId::new(self.key_ids[safe_row][safe_col]),
+				action: BootAction::SafeMode,
+			},
+		]
+	}
+
+	/// Sets up the matrix pins by number.
+	///
+	/// # Safety
+	///
+	/// Must only be called once, and none of the row or column pins may be used elsewhere.
+	pub unsafe fn key_matrix(&self) -> KeyMatrix<ROWS, COLS> {
+		let rows = self.row_pins.map(|pin| {
+			Box::new(Output::new(unsafe { AnyPin::steal(pin) }, Level::Low)) as Box<dyn RowPin>
+		});
+		let cols = self.col_pins.map(|pin| {
+			Box::new(Input::new(unsafe { AnyPin::steal(pin) }, Pull::Down)) as Box<dyn ColPin>
+		});
+
+		KeyMatrix::new(
+			self.key_ids(),
+			rows,
+			cols,
+			Duration::millis(self.debounce_ms),
+		)
+		.with_col_port(Box::new(Rp2040ColPort::new(&self.col_pins)))
+	}
+}
+
+/// Sizes of the flash partitions, laid out in this order from the start of the `PROFILE`
+/// region in `memory.x`. The profile gets whatever the others leave.
+#[derive(Clone, Copy)]
+pub struct FlashLayout {
+	/// The whole region, which must match `memory.x`.
+	pub total: usize,
+	pub firmware_update: usize,
+	pub settings: usize,
+	pub auth: usize,
+	pub lock: usize,
+	pub crash_report: usize,
+	pub key_stats: usize,
+}
+
+impl FlashLayout {
+	pub const fn profile_size(&self) -> usize {
+		self.total
+			- self.firmware_update
+			- self.settings
+			- self.auth
+			- self.lock
+			- self.crash_report
+			- self.key_stats
+	}
+
+	/// Reported to the host in Identify.
+	pub const fn partition_sizes(&self) -> PartitionSizes {
+		PartitionSizes {
+			firmware_update: self.firmware_update as u32,
+			settings: self.settings as u32,
+			profile: self.profile_size() as u32,
+			auth: self.auth as u32,
+			lock: self.lock as u32,
+			crash_report: self.crash_report as u32,
+			key_stats: self.key_stats as u32,
+		}
+	}
+
+	pub fn partitions<F: BlockFlash>(&self) -> FlashPartitions<F> {
+		let mut offset = 0;
+		let mut next = |length| {
+			let partition = FlashPartition::new(offset, length);
+			offset += length;
+			partition
+		};
+
+		FlashPartitions {
+			firmware_update: next(self.firmware_update),
+			settings: next(self.settings),
+			profile: next(self.profile_size()),
+			auth: next(self.auth),
+			lock: next(self.lock),
+			crash_report: next(self.crash_report),
+			key_stats: next(self.key_stats),
+		}
+	}
+}
+
+pub struct FlashPartitions<F: BlockFlash> {
+	pub firmware_update: FlashPartition<F>,
+	pub settings: FlashPartition<F>,
+	pub profile: FlashPartition<F>,
+	pub auth: FlashPartition<F>,
+	pub lock: FlashPartition<F>,
+	pub crash_report: FlashPartition<F>,
+	pub key_stats: FlashPartition<F>,
+}
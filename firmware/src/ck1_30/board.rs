@@ -0,0 +1,50 @@
+use cardboard::board::{
+	BoardConfig, CommandChannel, FlashLayout, MemoryBudgetSizes, VirtualKeyCount,
+};
+use cardboard_lib::{
+	device::DeviceTypeId,
+	state::{MacroLimit, MacroOverflowPolicy},
+};
+use uuid::Uuid;
+
+// generated by build.rs from board.layout
+mod layout {
+	include!(concat!(env!("OUT_DIR"), "/ck1_30_layout.rs"));
+}
+pub use layout::{COLS, ROWS};
+
+pub const BOARD: BoardConfig<ROWS, COLS> = BoardConfig {
+	name: "Cardboard",
+	manufacturer: "cranky",
+	device_type: DeviceTypeId::new(Uuid::from_u128(0x0407db48_ca74_5783_9b11_489637b7c615)),
+	heap_size: 96 * 1024,
+	memory_budgets: MemoryBudgetSizes {
+		profile: 48 * 1024,
+		macros: 8 * 1024,
+		serial: 16 * 1024,
+	},
+	macro_limit: MacroLimit {
+		max_running: 32,
+		overflow: MacroOverflowPolicy::RejectNewest,
+	},
+	virtual_keys: VirtualKeyCount::Keys128,
+	flash: FlashLayout {
+		// right after the 768 KB firmware region, as in memory.x
+		total: 1268 * 1024,
+		// taken from the old firmware region, so everything after it stays where it was
+		firmware_update: 768 * 1024,
+		settings: 4 * 1024,
+		auth: 4 * 1024,
+		lock: 4 * 1024,
+		crash_report: 4 * 1024,
+		// room for many saves between erases
+		key_stats: 16 * 1024,
+	},
+	row_pins: layout::ROW_PINS,
+	col_pins: layout::COL_PINS,
+	debounce_ms: 10,
+	key_ids: layout::KEY_IDS,
+	bootloader_key: (0, 0),
+	safe_mode_key: (0, 1),
+	command_channel: CommandChannel::Usb,
+};
@@ -0,0 +1,42 @@
+use core::{cell::UnsafeCell, mem::MaybeUninit};
+
+use cardboard_lib::crash::{CrashRecord, CrashReport};
+use embassy_rp::pac;
+
+struct CrashRecordCell(UnsafeCell<MaybeUninit<CrashRecord>>);
+
+// only touched by the panic handler and once at boot, before anything else is running
+unsafe impl Sync for CrashRecordCell {}
+
+// in .uninit so it survives the reset that follows a panic
+#[link_section = ".uninit.CRASH_RECORD"]
+static CRASH_RECORD: CrashRecordCell = CrashRecordCell(UnsafeCell::new(MaybeUninit::uninit()));
+
+/// Takes the crash recorded by the panic handler before the last reset, if there was one.
+pub fn take_crash_report() -> Option<CrashReport> {
+	// every field is plain data, so whatever the RAM held at power-on is a valid record
+	unsafe { (*CRASH_RECORD.0.get()).assume_init_mut() }.take()
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+	cortex_m::interrupt::disable();
+
+	let record = unsafe { (*CRASH_RECORD.0.get()).assume_init_mut() };
+	record.capture(
+		format_args!("{}", info),
+		embassy_time::Instant::now().as_millis(),
+		pac::SIO.cpuid().read() as u8,
+		cortex_m::register::msp::read(),
+	);
+	defmt::error!("{}", defmt::Display2Format(info));
+
+	// otherwise the watchdog resets us once the supervisor notices the stalled task
+	#[cfg(feature = "reboot-on-panic")]
+	pac::WATCHDOG.ctrl().write(|w| w.set_trigger(true));
+
+	loop {
+		cortex_m::asm::wfi();
+	}
+}
@@ -10,7 +10,16 @@ use cardboard_lib::device::DeviceId;
 
 pub use static_cell::StaticCell;
 
+#[cfg(all(feature = "rp2040", feature = "rp2350"))]
+compile_error!("the rp2040 and rp2350 features are mutually exclusive");
+
+pub mod board;
+pub mod crash;
 pub mod rp2040;
+#[cfg(feature = "rp2350")]
+pub mod rp2350;
+pub mod runtime;
+pub mod settings;
 
 static SERIAL_NUMBER: StaticCell<String> = StaticCell::new();
 
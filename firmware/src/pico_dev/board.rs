@@ -0,0 +1,48 @@
+use cardboard::board::{
+	BoardConfig, CommandChannel, FlashLayout, MemoryBudgetSizes, VirtualKeyCount,
+};
+use cardboard_lib::{
+	device::DeviceTypeId,
+	state::{MacroLimit, MacroOverflowPolicy},
+};
+use uuid::Uuid;
+
+// generated by build.rs from board.layout
+mod layout {
+	include!(concat!(env!("OUT_DIR"), "/pico_dev_layout.rs"));
+}
+pub use layout::{COLS, ROWS};
+
+pub const BOARD: BoardConfig<ROWS, COLS> = BoardConfig {
+	name: "Cardboard Dev",
+	manufacturer: "cranky",
+	device_type: DeviceTypeId::new(Uuid::from_u128(0x3584d22b_7b93_5e81_8ff2_9d1cbf2c531a)),
+	heap_size: 96 * 1024,
+	memory_budgets: MemoryBudgetSizes {
+		profile: 48 * 1024,
+		macros: 8 * 1024,
+		serial: 16 * 1024,
+	},
+	macro_limit: MacroLimit {
+		max_running: 32,
+		overflow: MacroOverflowPolicy::RejectNewest,
+	},
+	virtual_keys: VirtualKeyCount::Keys128,
+	// the same memory.x as the CK1-30, so the same partitions
+	flash: FlashLayout {
+		total: 1268 * 1024,
+		firmware_update: 768 * 1024,
+		settings: 4 * 1024,
+		auth: 4 * 1024,
+		lock: 4 * 1024,
+		crash_report: 4 * 1024,
+		key_stats: 16 * 1024,
+	},
+	row_pins: layout::ROW_PINS,
+	col_pins: layout::COL_PINS,
+	debounce_ms: 10,
+	key_ids: layout::KEY_IDS,
+	bootloader_key: (0, 0),
+	safe_mode_key: (0, 1),
+	command_channel: CommandChannel::Usb,
+};
@@ -0,0 +1,118 @@
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+#![feature(impl_trait_in_assoc_type)]
+#![feature(generic_const_exprs)]
+
+extern crate alloc;
+extern crate cortex_m;
+extern crate usbd_human_interface_device;
+
+use core::mem::MaybeUninit;
+
+#[cfg(feature = "rp2040")]
+use cardboard::rp2040::flash::init_flash;
+#[cfg(feature = "rp2350")]
+use cardboard::rp2350::flash::init_flash;
+use cardboard::{
+	rp2040::{
+		battery::init_battery_adc, buzzer::init_buzzer, display::init_i2c,
+		haptic::init_haptic_motor, pwm::init_pwm_output, ws2812::init_ws2812,
+	},
+	runtime::{CardboardRuntime, Heap},
+};
+use cardboard_lib::{
+	battery::BatteryConfig,
+	display::{DisplayController, OledDisplay},
+	indicator::{BoundIndicator, IndicatorCondition},
+	output::{AuxOutput, PwmOutput},
+	TrackingAllocator,
+};
+use embassy_executor::Spawner;
+use embassy_rp::gpio::{Input, Level, Output, Pull};
+
+use defmt_rtt as _;
+
+// tells the RP2350 bootrom how to boot this image
+#[cfg(feature = "rp2350")]
+#[link_section = ".start_block"]
+#[used]
+pub static IMAGE_DEF: embassy_rp::block::ImageDef = embassy_rp::block::ImageDef::secure_exe();
+
+mod board;
+use board::{BOARD, COLS, ROWS};
+
+const HEAP_SIZE: usize = BOARD.heap_size;
+static mut HEAP: [u8; HEAP_SIZE] = [0; HEAP_SIZE];
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator<Heap> = TrackingAllocator::new(Heap::empty());
+
+const LED_COUNT: usize = ROWS * COLS; // one WS2812 per key
+const DISPLAY_CONTROLLER: DisplayController = DisplayController::Ssd1306;
+const DISPLAY_ADDRESS: u8 = 0x3C;
+// the Pico measures VSYS on GPIO 29 through a 200k/100k divider; a LiPo on VSYS reads 3.3-4.2 V
+const BATTERY_CONFIG: BatteryConfig = BatteryConfig {
+	reference_mv: 3300,
+	adc_max: 4095,
+	divider_top_ohms: 200_000,
+	divider_bottom_ohms: 100_000,
+	empty_mv: 3300,
+	full_mv: 4200,
+};
+
+// firmware update staging and profile flash storage
+#[link_section = ".profile"]
+static mut FLASH_DATA: MaybeUninit<[u8; FLASH_DATA_SIZE]> = MaybeUninit::uninit();
+const FLASH_DATA_SIZE: usize = BOARD.flash.total;
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) -> () {
+	unsafe { ALLOCATOR.inner.init(HEAP.as_ptr() as usize, HEAP_SIZE) };
+	ALLOCATOR.set_heap_size(HEAP_SIZE);
+
+	let p = embassy_rp::init(Default::default());
+
+	let flash =
+		init_flash::<FLASH_DATA_SIZE>(unsafe { FLASH_DATA.as_ptr() }, p.FLASH, p.DMA_CH0).await;
+
+	// a Pico on a breadboard with one of everything the runtime can drive, for trying them out;
+	// a real board only passes the peripherals it has
+	CardboardRuntime::builder()
+		.board(&BOARD)
+		.allocator(&ALLOCATOR)
+		.flash(flash)
+		// the matrix pins aren't taken from `p` anywhere else
+		.matrix(unsafe { BOARD.key_matrix() })
+		.lighting(
+			init_ws2812::<LED_COUNT>(p.PIO0, p.PIN_14, p.DMA_CH1),
+			LED_COUNT,
+		)
+		.display(OledDisplay::new(
+			init_i2c(p.I2C1, p.PIN_3, p.PIN_2),
+			DISPLAY_ADDRESS,
+			DISPLAY_CONTROLLER,
+		))
+		.buzzer(init_buzzer(p.PWM_SLICE7, p.PIN_15))
+		.haptics(init_haptic_motor(p.PWM_SLICE6, p.PIN_13))
+		// the Pico senses VBUS on GPIO 24; powered over USB, so the host isn't shown a battery icon
+		.battery(
+			init_battery_adc(p.ADC, p.PIN_29),
+			BATTERY_CONFIG,
+			Input::new(p.PIN_24, Pull::None),
+		)
+		// the Pico's own LED shows logged errors
+		.indicator(BoundIndicator::new(
+			IndicatorCondition::ErrorPresent,
+			Output::new(p.PIN_25, Level::Low),
+		))
+		// spare pins for macros to drive external hardware
+		.output(AuxOutput::new("aux0", Output::new(p.PIN_10, Level::Low)))
+		.output(AuxOutput::new("aux1", Output::new(p.PIN_11, Level::Low)))
+		.pwm_output(PwmOutput::new(
+			"pwm0",
+			init_pwm_output(p.PWM_SLICE3, p.PIN_6),
+		))
+		.run(spawner, p.USB, p.CORE1, p.WATCHDOG)
+		.await;
+}
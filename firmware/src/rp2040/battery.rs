@@ -0,0 +1,37 @@
+use cardboard_lib::battery::BatteryAdc;
+use embassy_rp::{
+	adc::{self, Adc, AdcPin, Async, Channel},
+	bind_interrupts,
+	gpio::Pull,
+	peripherals::ADC,
+	Peripheral,
+};
+
+bind_interrupts!(struct Irqs {
+	ADC_IRQ_FIFO => adc::InterruptHandler;
+});
+
+/// An ADC pin measuring the battery through the board's divider.
+pub struct Rp2040BatteryAdc {
+	adc: Adc<'static, Async>,
+	channel: Channel<'static>,
+}
+
+pub fn init_battery_adc(
+	adc: ADC,
+	pin: impl Peripheral<P = impl AdcPin> + 'static,
+) -> Rp2040BatteryAdc {
+	Rp2040BatteryAdc {
+		adc: Adc::new(adc, Irqs, adc::Config::default()),
+		channel: Channel::new_pin(pin, Pull::None),
+	}
+}
+
+impl BatteryAdc for Rp2040BatteryAdc {
+	async fn read(&mut self) -> Result<u16, &'static str> {
+		self.adc
+			.read(&mut self.channel)
+			.await
+			.map_err(|_| "ADC read failed")
+	}
+}
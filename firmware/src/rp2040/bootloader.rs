@@ -1,15 +1,14 @@
 use cardboard_lib::context::{Reboot, RebootToBootloader};
-use embassy_rp::{rom_data::reset_to_usb_boot, watchdog::Watchdog};
+use embassy_rp::{pac, rom_data::reset_to_usb_boot};
 
-pub struct EmbassyRp2040Reboot {
-	pub watchdog: Watchdog,
-}
+pub struct EmbassyRp2040Reboot {}
 
 pub struct EmbassyRp2040RebootToBootloader {}
 
 impl Reboot for EmbassyRp2040Reboot {
 	fn reboot(&mut self) -> ! {
-		self.watchdog.trigger_reset();
+		// the supervisor owns the watchdog, so trigger it through the registers
+		pac::WATCHDOG.ctrl().write(|w| w.set_trigger(true));
 		halt()
 	}
 }
@@ -0,0 +1,41 @@
+use cardboard_lib::buzzer::{Buzzer, PwmTiming};
+use embassy_rp::{
+	clocks::clk_sys_freq,
+	peripherals::PWM_SLICE7,
+	pwm::{self, ChannelBPin, Pwm},
+	Peripheral,
+};
+
+/// A piezo buzzer on channel B of PWM slice 7, driven at half duty.
+pub struct Rp2040Buzzer {
+	pwm: Pwm<'static>,
+	config: pwm::Config,
+}
+
+pub fn init_buzzer(
+	slice: PWM_SLICE7,
+	pin: impl Peripheral<P = impl ChannelBPin<PWM_SLICE7>> + 'static,
+) -> Rp2040Buzzer {
+	// silent until the first tone
+	let config = pwm::Config::default();
+
+	Rp2040Buzzer {
+		pwm: Pwm::new_output_b(slice, pin, config.clone()),
+		config,
+	}
+}
+
+impl Buzzer for Rp2040Buzzer {
+	fn start(&mut self, freq_hz: u16) {
+		let timing = PwmTiming::for_frequency(clk_sys_freq(), freq_hz);
+		self.config.divider = timing.divider.into();
+		self.config.top = timing.top;
+		self.config.compare_b = timing.top / 2;
+		self.pwm.set_config(&self.config);
+	}
+
+	fn stop(&mut self) {
+		self.config.compare_b = 0;
+		self.pwm.set_config(&self.config);
+	}
+}
@@ -0,0 +1,39 @@
+use cardboard_lib::display::I2cBus;
+use embassy_rp::{
+	bind_interrupts,
+	i2c::{self, Async, I2c, SclPin, SdaPin},
+	peripherals::I2C1,
+	Peripheral,
+};
+
+bind_interrupts!(struct Irqs {
+	I2C1_IRQ => i2c::InterruptHandler<I2C1>;
+});
+
+/// The I2C1 bus, which the status display sits on.
+pub struct Rp2040I2c {
+	i2c: I2c<'static, I2C1, Async>,
+}
+
+pub fn init_i2c(
+	i2c: I2C1,
+	scl: impl Peripheral<P = impl SclPin<I2C1>> + 'static,
+	sda: impl Peripheral<P = impl SdaPin<I2C1>> + 'static,
+) -> Rp2040I2c {
+	let mut config = i2c::Config::default();
+	// fast mode, so a whole frame takes about 25 ms
+	config.frequency = 400_000;
+
+	Rp2040I2c {
+		i2c: I2c::new_async(i2c, scl, sda, Irqs, config),
+	}
+}
+
+impl I2cBus for Rp2040I2c {
+	async fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), &'static str> {
+		self.i2c
+			.write_async(address, bytes.iter().copied())
+			.await
+			.map_err(|_| "I2C write failed")
+	}
+}
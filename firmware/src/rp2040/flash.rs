@@ -1,4 +1,4 @@
-use cardboard_lib::{device::DeviceId, embassy::EmbassyFlashMemory};
+use cardboard_lib::{device::DeviceId, rp::EmbassyFlashMemory};
 use defmt::error;
 use embassy_rp::{
 	flash::{Async, Flash},
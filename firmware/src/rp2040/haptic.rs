@@ -0,0 +1,37 @@
+use cardboard_lib::haptic::HapticMotor;
+use embassy_rp::{
+	peripherals::PWM_SLICE6,
+	pwm::{self, ChannelBPin, Pwm},
+	Peripheral,
+};
+
+/// 25 kHz at the default 125 MHz clock, above hearing so the motor doesn't whine.
+const TOP: u16 = 4999;
+
+/// A vibration motor switched by a transistor on channel B of PWM slice 6.
+pub struct Rp2040HapticMotor {
+	pwm: Pwm<'static>,
+	config: pwm::Config,
+}
+
+pub fn init_haptic_motor(
+	slice: PWM_SLICE6,
+	pin: impl Peripheral<P = impl ChannelBPin<PWM_SLICE6>> + 'static,
+) -> Rp2040HapticMotor {
+	let mut config = pwm::Config::default();
+	config.top = TOP;
+	config.compare_b = 0;
+
+	Rp2040HapticMotor {
+		pwm: Pwm::new_output_b(slice, pin, config.clone()),
+		config,
+	}
+}
+
+impl HapticMotor for Rp2040HapticMotor {
+	fn set(&mut self, strength_percent: u8) {
+		let strength_percent = strength_percent.min(100) as u32;
+		self.config.compare_b = ((TOP as u32 + 1) * strength_percent / 100) as u16;
+		self.pwm.set_config(&self.config);
+	}
+}
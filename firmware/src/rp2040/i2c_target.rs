@@ -0,0 +1,106 @@
+use cardboard_lib::serial::FRAME_DELIMITER;
+use defmt::warn;
+use embassy_rp::{
+	bind_interrupts,
+	i2c::{self, SclPin, SdaPin},
+	i2c_slave::{self, Command, I2cSlave},
+	peripherals::I2C0,
+	Peripheral,
+};
+
+use crate::rp2040::pipe::{CommandPipe, PipePacketReader, PipePacketWriter, PIPE_PACKET_SIZE};
+
+// bytes written by the host, waiting for the command task
+static RX_PIPE: CommandPipe = CommandPipe::new();
+// responses and events, waiting for the host to read them
+static TX_PIPE: CommandPipe = CommandPipe::new();
+
+bind_interrupts!(struct Irqs {
+	I2C0_IRQ => i2c::InterruptHandler<I2C0>;
+});
+
+/// I2C0 as a target, carrying the command protocol for an embedded host that has no USB to
+/// spare, such as a Raspberry Pi with the keypad on a hat.
+pub struct Rp2040CommandI2c {
+	target: I2cSlave<'static, I2C0>,
+}
+
+pub fn init_command_i2c(
+	i2c: I2C0,
+	scl: impl Peripheral<P = impl SclPin<I2C0>> + 'static,
+	sda: impl Peripheral<P = impl SdaPin<I2C0>> + 'static,
+	address: u8,
+) -> Rp2040CommandI2c {
+	let mut config = i2c_slave::Config::default();
+	config.addr = address as u16;
+
+	Rp2040CommandI2c {
+		target: I2cSlave::new(i2c, scl, sda, Irqs, config),
+	}
+}
+
+impl Rp2040CommandI2c {
+	/// Splits into the command task's ends of the link and the loop that serves the bus.
+	pub fn split(
+		self,
+		read_timeout: cardboard_lib::time::Duration,
+		write_timeout: cardboard_lib::time::Duration,
+	) -> (PipePacketReader, PipePacketWriter, I2cTarget) {
+		(
+			PipePacketReader::new(&RX_PIPE, read_timeout),
+			PipePacketWriter::new(&TX_PIPE, write_timeout),
+			I2cTarget {
+				target: self.target,
+			},
+		)
+	}
+}
+
+/// Answers the host's transactions. The device can't start one itself, so the host polls
+/// with reads to pick up responses and events.
+pub struct I2cTarget {
+	target: I2cSlave<'static, I2C0>,
+}
+
+impl I2cTarget {
+	pub async fn run(mut self) {
+		let mut buf = [0u8; PIPE_PACKET_SIZE];
+
+		loop {
+			match self.target.listen(&mut buf).await {
+				Ok(Command::Write(len)) => Self::receive(&buf[..len]),
+				Ok(Command::WriteRead(len)) => {
+					Self::receive(&buf[..len]);
+					self.respond().await;
+				}
+				Ok(Command::Read) => self.respond().await,
+				Ok(Command::GeneralCall(_)) => {}
+				Err(e) => warn!("I2C target error: {:?}", e),
+			}
+		}
+	}
+
+	fn receive(data: &[u8]) {
+		// the host has overrun its receive window; the frame checksums will catch the gap
+		if RX_PIPE
+			.try_write(data)
+			.map_or(true, |written| written < data.len())
+		{
+			warn!("I2C receive pipe full, dropped bytes");
+		}
+	}
+
+	// reads past what's queued get frame delimiters, which the host's decoder skips
+	async fn respond(&mut self) {
+		let mut buf = [0u8; PIPE_PACKET_SIZE];
+		let len = TX_PIPE.try_read(&mut buf).unwrap_or(0);
+
+		if let Err(e) = self
+			.target
+			.respond_and_fill(&buf[..len], FRAME_DELIMITER)
+			.await
+		{
+			warn!("I2C target read failed: {:?}", e);
+		}
+	}
+}
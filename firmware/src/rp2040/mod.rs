@@ -1,4 +1,21 @@
+pub mod battery;
+#[cfg(feature = "rp2040")]
 pub mod bootloader;
+pub mod buzzer;
+pub mod display;
+#[cfg(feature = "rp2040")]
 pub mod flash;
-pub mod hid;
+pub mod haptic;
+pub mod i2c_target;
+pub mod module;
+pub mod pipe;
+pub mod pmw3360;
+pub mod power;
+pub mod pwm;
+pub mod spi_target;
+pub mod touch;
+pub mod uart;
+#[cfg(feature = "rp2040")]
+pub mod update;
 pub mod usb;
+pub mod ws2812;
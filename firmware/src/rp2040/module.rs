@@ -0,0 +1,21 @@
+use crate::rp2040::display::Rp2040I2c;
+use cardboard_lib::module::{I2cProbe, ModuleDetect};
+use embassy_rp::gpio::Input;
+
+/// How a key module port finds out a module is plugged in.
+pub enum Rp2040ModuleDetect {
+	/// A pin pulled up on the keypad, which the module ties to ground through the connector.
+	PresencePin(Input<'static>),
+	/// A chip on the module answering at its I2C address. The bus can't be shared with the
+	/// display.
+	I2c(I2cProbe<Rp2040I2c>),
+}
+
+impl ModuleDetect for Rp2040ModuleDetect {
+	async fn is_present(&mut self) -> bool {
+		match self {
+			Self::PresencePin(pin) => pin.is_low(),
+			Self::I2c(probe) => probe.is_present().await,
+		}
+	}
+}
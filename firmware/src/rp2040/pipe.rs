@@ -0,0 +1,70 @@
+use cardboard_lib::serial::{SerialPacketReader, SerialPacketSender, SerialSession};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pipe::Pipe};
+use embassy_time::{Duration, Timer};
+
+/// Bytes handed to the command protocol per read.
+pub const PIPE_PACKET_SIZE: usize = 64;
+
+// room for a whole receive window, so the host never has to wait on the bus
+const PIPE_SIZE: usize = 512;
+
+/// Bytes passed between a bus the host drives and the command task, for links where the host
+/// clocks every transfer and the device can only queue its side.
+pub type CommandPipe = Pipe<CriticalSectionRawMutex, PIPE_SIZE>;
+
+/// The command task's end of a pipe filled by a bus target.
+pub struct PipePacketReader {
+	pipe: &'static CommandPipe,
+	timeout: Duration,
+}
+
+/// The command task's end of a pipe drained by a bus target.
+pub struct PipePacketWriter {
+	pipe: &'static CommandPipe,
+	timeout: Duration,
+}
+
+impl PipePacketReader {
+	pub fn new(pipe: &'static CommandPipe, timeout: cardboard_lib::time::Duration) -> Self {
+		Self {
+			pipe,
+			timeout: Duration::from_millis(timeout.to_millis()),
+		}
+	}
+}
+
+impl PipePacketWriter {
+	pub fn new(pipe: &'static CommandPipe, timeout: cardboard_lib::time::Duration) -> Self {
+		Self {
+			pipe,
+			timeout: Duration::from_millis(timeout.to_millis()),
+		}
+	}
+}
+
+impl SerialPacketReader for PipePacketReader {
+	async fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+		match select(self.pipe.read(buf), Timer::after(self.timeout)).await {
+			Either::First(len) => Ok(len),
+			Either::Second(_) => Err("Read timeout"),
+		}
+	}
+
+	const SIZE: usize = PIPE_PACKET_SIZE;
+}
+
+// a bus has no connection to lose; a new host starts over with Identify
+impl SerialSession for PipePacketReader {}
+
+impl SerialPacketSender for PipePacketWriter {
+	async fn write_packet(&mut self, data: &[u8]) -> Result<(), &'static str> {
+		// times out when the host stops polling, rather than blocking the command task
+		match select(self.pipe.write_all(data), Timer::after(self.timeout)).await {
+			Either::First(_) => Ok(()),
+			Either::Second(_) => Err("Write timeout"),
+		}
+	}
+
+	const SIZE: usize = PIPE_PACKET_SIZE;
+}
@@ -0,0 +1,154 @@
+use cardboard_lib::pointer::PointingSensor;
+use embassy_rp::{
+	gpio::{Level, Output, Pin},
+	peripherals::SPI1,
+	spi::{self, Blocking, ClkPin, MisoPin, MosiPin, Spi},
+	Peripheral,
+};
+use embassy_time::Timer;
+
+// registers
+const PRODUCT_ID: u8 = 0x00;
+const CONFIG2: u8 = 0x10;
+const SROM_ENABLE: u8 = 0x13;
+const SROM_ID: u8 = 0x2A;
+const POWER_UP_RESET: u8 = 0x3A;
+const MOTION_BURST: u8 = 0x50;
+const SROM_LOAD_BURST: u8 = 0x62;
+
+const PMW3360_PRODUCT_ID: u8 = 0x42;
+
+// the sensor tops out at 2 MHz
+const SPI_FREQUENCY: u32 = 2_000_000;
+
+// datasheet timings, in microseconds: address to data on reads, then the gaps the sensor needs
+// after a read or write before the next access
+const T_SRAD: u64 = 160;
+const T_SRW: u64 = 20;
+const T_SWW: u64 = 180;
+const T_SCLK_NCS: u64 = 35;
+const T_SROM_BYTE: u64 = 15;
+
+/// A PixArt PMW3360 optical sensor on SPI1, for trackballs. PixArt only ships the sensor's
+/// firmware (SROM) under NDA, so it's left to the board to supply; without it the sensor runs
+/// on its ROM and tracks less reliably.
+pub struct Rp2040Pmw3360 {
+	spi: Spi<'static, SPI1, Blocking>,
+	cs: Output<'static>,
+	srom: Option<&'static [u8]>,
+}
+
+pub fn init_pmw3360(
+	spi: SPI1,
+	clk: impl Peripheral<P = impl ClkPin<SPI1>> + 'static,
+	mosi: impl Peripheral<P = impl MosiPin<SPI1>> + 'static,
+	miso: impl Peripheral<P = impl MisoPin<SPI1>> + 'static,
+	cs: impl Peripheral<P = impl Pin> + 'static,
+	srom: Option<&'static [u8]>,
+) -> Rp2040Pmw3360 {
+	// mode 3
+	let mut config = spi::Config::default();
+	config.frequency = SPI_FREQUENCY;
+	config.phase = spi::Phase::CaptureOnSecondTransition;
+	config.polarity = spi::Polarity::IdleHigh;
+
+	Rp2040Pmw3360 {
+		spi: Spi::new_blocking(spi, clk, mosi, miso, config),
+		cs: Output::new(cs, Level::High),
+		srom,
+	}
+}
+
+impl Rp2040Pmw3360 {
+	async fn read_register(&mut self, register: u8) -> Result<u8, &'static str> {
+		self.cs.set_low();
+		let result = self.spi.blocking_write(&[register & 0x7F]);
+		Timer::after_micros(T_SRAD).await;
+		let mut value = [0];
+		let result = result.and_then(|_| self.spi.blocking_read(&mut value));
+		self.cs.set_high();
+		Timer::after_micros(T_SRW).await;
+
+		result.map_err(|_| "Pointing sensor read failed")?;
+		Ok(value[0])
+	}
+
+	async fn write_register(&mut self, register: u8, value: u8) -> Result<(), &'static str> {
+		self.cs.set_low();
+		let result = self.spi.blocking_write(&[register | 0x80, value]);
+		Timer::after_micros(T_SCLK_NCS).await;
+		self.cs.set_high();
+		Timer::after_micros(T_SWW).await;
+
+		result.map_err(|_| "Pointing sensor write failed")
+	}
+
+	async fn upload_srom(&mut self, srom: &[u8]) -> Result<(), &'static str> {
+		self.write_register(CONFIG2, 0x00).await?;
+		self.write_register(SROM_ENABLE, 0x1D).await?;
+		Timer::after_millis(10).await;
+		self.write_register(SROM_ENABLE, 0x18).await?;
+
+		self.cs.set_low();
+		let mut result = self.spi.blocking_write(&[SROM_LOAD_BURST | 0x80]);
+		for byte in srom {
+			Timer::after_micros(T_SROM_BYTE).await;
+			result = result.and_then(|_| self.spi.blocking_write(&[*byte]));
+		}
+		Timer::after_micros(T_SROM_BYTE).await;
+		self.cs.set_high();
+		Timer::after_micros(200).await;
+		result.map_err(|_| "Pointing sensor firmware upload failed")?;
+
+		if self.read_register(SROM_ID).await? == 0 {
+			return Err("Pointing sensor rejected its firmware");
+		}
+		self.write_register(CONFIG2, 0x00).await
+	}
+}
+
+impl PointingSensor for Rp2040Pmw3360 {
+	async fn init(&mut self) -> Result<(), &'static str> {
+		// toggling NCS resets the sensor's SPI port
+		self.cs.set_low();
+		self.cs.set_high();
+		self.write_register(POWER_UP_RESET, 0x5A).await?;
+		Timer::after_millis(50).await;
+
+		if self.read_register(PRODUCT_ID).await? != PMW3360_PRODUCT_ID {
+			return Err("No PMW3360 found");
+		}
+
+		// the motion registers have to be read once after a reset
+		for register in 0x02..=0x06 {
+			self.read_register(register).await?;
+		}
+
+		if let Some(srom) = self.srom {
+			self.upload_srom(srom).await?;
+		}
+		Ok(())
+	}
+
+	async fn read_motion(&mut self) -> Result<(i16, i16), &'static str> {
+		// any write latches the motion counters for the burst
+		self.write_register(MOTION_BURST, 0x00).await?;
+
+		self.cs.set_low();
+		let result = self.spi.blocking_write(&[MOTION_BURST]);
+		Timer::after_micros(T_SCLK_NCS).await;
+		// motion, observation, then X and Y, low byte first
+		let mut burst = [0; 6];
+		let result = result.and_then(|_| self.spi.blocking_read(&mut burst));
+		self.cs.set_high();
+		result.map_err(|_| "Pointing sensor read failed")?;
+
+		if burst[0] & 0x80 == 0 {
+			return Ok((0, 0));
+		}
+		Ok((
+			i16::from_le_bytes([burst[2], burst[3]]),
+			i16::from_le_bytes([burst[4], burst[5]]),
+		))
+	}
+}
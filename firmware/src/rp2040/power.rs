@@ -0,0 +1,12 @@
+use cardboard_lib::context::LowPower;
+
+/// Leaves the clocks alone while asleep. PIO, PWM and the UART all run off clk_sys, so slowing
+/// it would retime the LEDs, buzzer and serial link along with it. Sleep saves power by not
+/// scanning instead: the keypad task waits on column interrupts, so the cores idle in WFI.
+pub struct EmbassyRp2040LowPower {}
+
+impl LowPower for EmbassyRp2040LowPower {
+	fn enter_low_power(&self) {}
+
+	fn exit_low_power(&self) {}
+}
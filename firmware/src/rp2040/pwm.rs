@@ -0,0 +1,36 @@
+use cardboard_lib::output::PwmPin;
+use embassy_rp::{
+	pwm::{self, ChannelAPin, Pwm, Slice},
+	Peripheral,
+};
+
+/// 25 kHz at the default 125 MHz clock, quiet for fans and flicker-free for lamps.
+const TOP: u16 = 4999;
+
+/// A PWM output on channel A of a slice, for macros to dim lamps or run fans.
+pub struct Rp2040PwmOutput {
+	pwm: Pwm<'static>,
+	config: pwm::Config,
+}
+
+pub fn init_pwm_output<T: Slice>(
+	slice: impl Peripheral<P = T> + 'static,
+	pin: impl Peripheral<P = impl ChannelAPin<T>> + 'static,
+) -> Rp2040PwmOutput {
+	let mut config = pwm::Config::default();
+	config.top = TOP;
+	config.compare_a = 0;
+
+	Rp2040PwmOutput {
+		pwm: Pwm::new_output_a(slice, pin, config.clone()),
+		config,
+	}
+}
+
+impl PwmPin for Rp2040PwmOutput {
+	fn set_duty(&mut self, percent: u8) {
+		let percent = percent.min(100) as u32;
+		self.config.compare_a = ((TOP as u32 + 1) * percent / 100) as u16;
+		self.pwm.set_config(&self.config);
+	}
+}
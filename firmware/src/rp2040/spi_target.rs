@@ -0,0 +1,124 @@
+use cardboard_lib::serial::FRAME_DELIMITER;
+use embassy_rp::{
+	bind_interrupts,
+	gpio::Pin,
+	interrupt::typelevel::{Binding, Handler, Interrupt, SPI0_IRQ},
+	pac,
+	peripherals::SPI0,
+	spi::{ClkPin, CsPin, MisoPin, MosiPin},
+	Peripheral,
+};
+
+use crate::rp2040::pipe::{CommandPipe, PipePacketReader, PipePacketWriter};
+
+// bytes clocked in by the host, waiting for the command task
+static RX_PIPE: CommandPipe = CommandPipe::new();
+// responses and events, waiting to be clocked out
+static TX_PIPE: CommandPipe = CommandPipe::new();
+
+// GPIO function select for the SPI blocks
+const FUNCSEL_SPI: u8 = 1;
+
+/// Moves bytes between the SPI FIFOs and the pipes. embassy-rp only drives SPI as a
+/// controller, so the target side is done on the registers.
+pub struct SpiTargetInterruptHandler;
+
+impl Handler<SPI0_IRQ> for SpiTargetInterruptHandler {
+	unsafe fn on_interrupt() {
+		let spi = pac::SPI0;
+
+		while spi.sr().read().rne() {
+			let byte = spi.dr().read().data() as u8;
+			// the host has overrun its receive window; the frame checksums will catch the gap
+			let _ = RX_PIPE.try_write(&[byte]);
+		}
+
+		// the host clocks out whatever is in the FIFO, so keep it topped up with delimiters
+		// when nothing is queued
+		while spi.sr().read().tnf() {
+			let mut byte = [FRAME_DELIMITER];
+			let _ = TX_PIPE.try_read(&mut byte);
+			spi.dr().write(|w| w.set_data(byte[0] as u16));
+		}
+
+		spi.icr().write(|w| w.set_rtic(true));
+	}
+}
+
+bind_interrupts!(struct Irqs {
+	SPI0_IRQ => SpiTargetInterruptHandler;
+});
+
+/// SPI0 as a target in mode 0, carrying the command protocol for a keypad built into a larger
+/// product. Every transfer is full duplex: the host's bytes go to the command task, and it
+/// gets back responses and events, or `0x00` when there's nothing to send.
+pub struct Rp2040CommandSpi {
+	_spi: SPI0,
+}
+
+/// embassy-rp names the pins for a controller, so the host's MOSI line goes to what it calls
+/// a MISO pin (the block's RX) and the host's MISO to a MOSI pin (the block's TX).
+pub fn init_command_spi(
+	spi: SPI0,
+	clk: impl Peripheral<P = impl ClkPin<SPI0>> + 'static,
+	rx: impl Peripheral<P = impl MisoPin<SPI0>> + 'static,
+	tx: impl Peripheral<P = impl MosiPin<SPI0>> + 'static,
+	cs: impl Peripheral<P = impl CsPin<SPI0>> + 'static,
+) -> Rp2040CommandSpi {
+	let pins = [
+		clk.into_ref().pin(),
+		rx.into_ref().pin(),
+		tx.into_ref().pin(),
+		cs.into_ref().pin(),
+	];
+	for pin in pins {
+		pac::IO_BANK0
+			.gpio(pin as usize)
+			.ctrl()
+			.write(|w| w.set_funcsel(FUNCSEL_SPI));
+		pac::PADS_BANK0.gpio(pin as usize).modify(|w| {
+			w.set_ie(true);
+			w.set_od(false);
+		});
+	}
+
+	let regs = pac::SPI0;
+	// 8-bit Motorola frames, mode 0; the host sets the clock
+	regs.cr0().write(|w| {
+		w.set_dss(0b0111);
+		w.set_frf(0);
+		w.set_spo(false);
+		w.set_sph(false);
+	});
+	regs.cr1().write(|w| w.set_ms(true));
+	regs.imsc().write(|w| {
+		w.set_rxim(true);
+		w.set_rtim(true);
+		w.set_txim(true);
+	});
+	regs.cr1().modify(|w| w.set_sse(true));
+
+	enable_interrupt(Irqs);
+
+	Rp2040CommandSpi { _spi: spi }
+}
+
+// taking the binding makes sure the handler is the one wired to the interrupt
+fn enable_interrupt(_irqs: impl Binding<SPI0_IRQ, SpiTargetInterruptHandler>) {
+	SPI0_IRQ::unpend();
+	unsafe { SPI0_IRQ::enable() };
+}
+
+impl Rp2040CommandSpi {
+	/// The command task's ends of the link; the interrupt handler serves the bus.
+	pub fn split(
+		self,
+		read_timeout: cardboard_lib::time::Duration,
+		write_timeout: cardboard_lib::time::Duration,
+	) -> (PipePacketReader, PipePacketWriter) {
+		(
+			PipePacketReader::new(&RX_PIPE, read_timeout),
+			PipePacketWriter::new(&TX_PIPE, write_timeout),
+		)
+	}
+}
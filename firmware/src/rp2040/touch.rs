@@ -0,0 +1,55 @@
+use alloc::vec::Vec;
+use cardboard_lib::slider::TouchPads;
+use embassy_rp::gpio::{Flex, Pull};
+use embassy_time::Timer;
+
+/// Long enough to empty a pad through the pin before timing it again.
+const DISCHARGE_US: u64 = 10;
+
+/// Loop iterations a pad gets to charge before it's read as covered as it can be.
+const MAX_COUNT: u16 = 4000;
+
+/// Capacitive pads for a touch slider, each a GPIO wired to a copper pad with a high-value
+/// resistor (around 1 MΩ) to 3.3 V. A pad is emptied by driving it low, then timed as it
+/// charges back up through the resistor; a finger adds capacitance and slows it down.
+pub struct Rp2040TouchPads {
+	pins: Vec<Flex<'static>>,
+}
+
+impl Rp2040TouchPads {
+	/// Pads in order along the strip.
+	pub fn new(mut pins: Vec<Flex<'static>>) -> Self {
+		for pin in pins.iter_mut() {
+			pin.set_pull(Pull::None);
+			pin.set_low();
+			pin.set_as_output();
+		}
+		Self { pins }
+	}
+}
+
+impl TouchPads for Rp2040TouchPads {
+	fn len(&self) -> usize {
+		self.pins.len()
+	}
+
+	async fn measure(&mut self, counts: &mut [u16]) -> Result<(), &'static str> {
+		for (pin, count) in self.pins.iter_mut().zip(counts.iter_mut()) {
+			Timer::after_micros(DISCHARGE_US).await;
+
+			// with interrupts off on this core so one firing mid-count doesn't read as a touch
+			*count = cortex_m::interrupt::free(|_| {
+				pin.set_as_input();
+				let mut count = 0;
+				while pin.is_low() && count < MAX_COUNT {
+					count += 1;
+				}
+				count
+			});
+
+			// left driven low, so it's empty for the next measurement
+			pin.set_as_output();
+		}
+		Ok(())
+	}
+}
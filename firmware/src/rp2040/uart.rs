@@ -0,0 +1,118 @@
+use cardboard_lib::serial::{SerialPacketReader, SerialPacketSender, SerialSession};
+use embassy_futures::select::{select, Either};
+use embassy_rp::{
+	bind_interrupts,
+	peripherals::UART0,
+	uart::{
+		self, BufferedInterruptHandler, BufferedUart, BufferedUartRx, BufferedUartTx, RxPin, TxPin,
+	},
+	Peripheral,
+};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::{Read, Write};
+
+use crate::StaticCell;
+
+/// Bytes handed to the command protocol per read, matching the USB serial packet size so
+/// either channel fits the same reader.
+pub const UART_PACKET_SIZE: usize = 64;
+
+// enough to ride out a flash erase at 115200 baud without dropping bytes
+const UART_RX_BUFFER_SIZE: usize = 1024;
+const UART_TX_BUFFER_SIZE: usize = 256;
+
+bind_interrupts!(struct Irqs {
+	UART0_IRQ => BufferedInterruptHandler<UART0>;
+});
+
+/// UART0, carrying the command protocol for boards whose host talks to them over a wire
+/// rather than USB, such as a bridge or the other half of a split keypad.
+pub struct Rp2040CommandUart {
+	tx: BufferedUartTx<'static, UART0>,
+	rx: BufferedUartRx<'static, UART0>,
+}
+
+pub fn init_command_uart(
+	uart: UART0,
+	tx: impl Peripheral<P = impl TxPin<UART0>> + 'static,
+	rx: impl Peripheral<P = impl RxPin<UART0>> + 'static,
+	baud_rate: u32,
+) -> Rp2040CommandUart {
+	static TX_BUF: StaticCell<[u8; UART_TX_BUFFER_SIZE]> = StaticCell::new();
+	static RX_BUF: StaticCell<[u8; UART_RX_BUFFER_SIZE]> = StaticCell::new();
+
+	let mut config = uart::Config::default();
+	config.baudrate = baud_rate;
+
+	let uart = BufferedUart::new(
+		uart,
+		Irqs,
+		tx,
+		rx,
+		TX_BUF.init([0; UART_TX_BUFFER_SIZE]),
+		RX_BUF.init([0; UART_RX_BUFFER_SIZE]),
+		config,
+	);
+	let (tx, rx) = uart.split();
+
+	Rp2040CommandUart { tx, rx }
+}
+
+impl Rp2040CommandUart {
+	pub fn split(
+		self,
+		read_timeout: cardboard_lib::time::Duration,
+		write_timeout: cardboard_lib::time::Duration,
+	) -> (UartPacketReader, UartPacketWriter) {
+		(
+			UartPacketReader {
+				rx: self.rx,
+				timeout: Duration::from_millis(read_timeout.to_millis()),
+			},
+			UartPacketWriter {
+				tx: self.tx,
+				timeout: Duration::from_millis(write_timeout.to_millis()),
+			},
+		)
+	}
+}
+
+/// Reads whatever has arrived on the UART, up to a packet's worth, as one packet.
+pub struct UartPacketReader {
+	rx: BufferedUartRx<'static, UART0>,
+	timeout: Duration,
+}
+
+pub struct UartPacketWriter {
+	tx: BufferedUartTx<'static, UART0>,
+	timeout: Duration,
+}
+
+impl SerialPacketReader for UartPacketReader {
+	async fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+		let result = select(self.rx.read(buf), Timer::after(self.timeout)).await;
+
+		match result {
+			Either::First(result) => result.map_err(|_| "UART read error"),
+			Either::Second(_) => Err("Read timeout"),
+		}
+	}
+
+	const SIZE: usize = UART_PACKET_SIZE;
+}
+
+// a bus has no connection to lose; a new host starts over with Identify
+impl SerialSession for UartPacketReader {}
+
+impl SerialPacketSender for UartPacketWriter {
+	async fn write_packet(&mut self, data: &[u8]) -> Result<(), &'static str> {
+		let result = select(self.tx.write_all(data), Timer::after(self.timeout)).await;
+
+		match result {
+			Either::First(result) => result.map_err(|_| "UART write error"),
+			Either::Second(_) => Err("Write timeout"),
+		}
+	}
+
+	const SIZE: usize = UART_PACKET_SIZE;
+}
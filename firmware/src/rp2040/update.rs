@@ -0,0 +1,117 @@
+use core::ptr;
+
+use cardboard_lib::update::ImageLayout;
+use embassy_rp::{pac, rom_data};
+
+const FLASH_BASE: usize = 0x1000_0000;
+const SECTOR_SIZE: usize = 4096;
+const BLOCK_SIZE: u32 = 1 << 16;
+const BLOCK_ERASE_CMD: u8 = 0xD8;
+
+struct RomFunctions {
+	connect_internal_flash: unsafe extern "C" fn(),
+	flash_exit_xip: unsafe extern "C" fn(),
+	flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+	flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+	flash_flush_cache: unsafe extern "C" fn(),
+	flash_enter_cmd_xip: unsafe extern "C" fn(),
+}
+
+/// What an RP2040 image starts with: boot2, then the vector table, with the stack at the top of
+/// the 264 KB of SRAM at most. `None` when this build can't install updates, because
+/// `copy_image` isn't safe to run unoptimized.
+pub fn image_layout() -> Option<ImageLayout> {
+	if cfg!(unoptimized) {
+		return None;
+	}
+	Some(ImageLayout {
+		flash_base: FLASH_BASE as u32,
+		boot2: true,
+		vector_table: 0x100,
+		ram: 0x2000_0000..=0x2004_2000,
+	})
+}
+
+/// Copies a committed update from the staging partition over the running firmware, clears the
+/// staging header so it only happens once, and resets into the new image. Must be called
+/// before the second core or any DMA is started.
+pub fn install_update(image: &'static [u8], staging: &'static [u8]) -> ! {
+	defmt::info!("Installing firmware update: {} bytes", image.len());
+
+	cortex_m::interrupt::disable();
+	pac::WATCHDOG.ctrl().modify(|w| w.set_enable(false));
+
+	// looked up now, since the lookup code lives in the flash we're about to overwrite
+	let rom = RomFunctions {
+		connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+		flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+		flash_range_erase: rom_data::flash_range_erase::ptr(),
+		flash_range_program: rom_data::flash_range_program::ptr(),
+		flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+		flash_enter_cmd_xip: rom_data::flash_enter_cmd_xip::ptr(),
+	};
+	let mut buffer = [0u8; SECTOR_SIZE];
+
+	unsafe {
+		copy_image(
+			&rom,
+			image.as_ptr(),
+			image.len(),
+			staging.as_ptr() as usize - FLASH_BASE,
+			buffer.as_mut_ptr(),
+		)
+	}
+}
+
+// Runs entirely from RAM, so nothing in here may call into flash. It sticks to raw pointer
+// reads and writes, which are only inlined when optimized; at opt-level 0 (the `debug-probe`
+// profile) `image_layout` refuses updates, so this never runs.
+#[link_section = ".data.ram_func"]
+#[inline(never)]
+unsafe fn copy_image(
+	rom: &RomFunctions,
+	image: *const u8,
+	length: usize,
+	staging_offset: usize,
+	buffer: *mut u8,
+) -> ! {
+	let mut offset = 0;
+	while offset < length {
+		// XIP is still on here, so the staged sector can be read
+		let mut i = 0;
+		while i < SECTOR_SIZE {
+			let byte = if offset + i < length {
+				ptr::read_volatile(image.add(offset + i))
+			} else {
+				0xFF
+			};
+			ptr::write_volatile(buffer.add(i), byte);
+			i += 1;
+		}
+
+		(rom.connect_internal_flash)();
+		(rom.flash_exit_xip)();
+		(rom.flash_range_erase)(offset as u32, SECTOR_SIZE, BLOCK_SIZE, BLOCK_ERASE_CMD);
+		(rom.flash_range_program)(offset as u32, buffer, SECTOR_SIZE);
+		(rom.flash_flush_cache)();
+		(rom.flash_enter_cmd_xip)();
+
+		offset += SECTOR_SIZE;
+	}
+
+	// the image starts on the sector after the header, so this leaves it alone
+	(rom.connect_internal_flash)();
+	(rom.flash_exit_xip)();
+	(rom.flash_range_erase)(
+		staging_offset as u32,
+		SECTOR_SIZE,
+		BLOCK_SIZE,
+		BLOCK_ERASE_CMD,
+	);
+	(rom.flash_flush_cache)();
+	(rom.flash_enter_cmd_xip)();
+
+	// SYSRESETREQ through the AIRCR, since the reset helpers are in flash too
+	ptr::write_volatile(0xE000_ED0C as *mut u32, 0x05FA_0004);
+	loop {}
+}
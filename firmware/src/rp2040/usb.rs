@@ -1,20 +1,31 @@
+use core::future::Future;
+use core::pin::{pin, Pin};
+use core::task::{Context, Poll};
+
 use cardboard_lib::{
+	battery::BatteryStatus,
 	device::DeviceInfo,
-	hid::{HidDevice},
+	hid::{HidDevice, HostLocks, BATTERY_REPORT_DESCRIPTOR},
+	indicator::IndicatorStatus,
 	profile::{ConsumerControlEvent, KeyboardEvent, MouseEvent},
+	supervisor::Heartbeat,
+	time::Instant,
 };
 use defmt::info;
+use embassy_futures::select::select;
 use embassy_rp::{
 	bind_interrupts,
 	peripherals::USB,
 	usb::{Driver, InterruptHandler},
 };
+use embassy_time::Timer;
 use embassy_usb::{
 	class::{
 		cdc_acm::{CdcAcmClass, Receiver},
-		hid::HidWriter,
+		hid::{HidWriter, ReportId, RequestHandler},
 	},
-	Builder, Config, UsbDevice,
+	control::OutResponse,
+	Builder, Config, Handler, UsbDevice,
 };
 
 use embassy_usb::class::cdc_acm::State as CdcAcmState;
@@ -31,10 +42,44 @@ bind_interrupts!(struct Irqs {
 	USBCTRL_IRQ => InterruptHandler<USB>;
 });
 
+/// How often the USB task polls the stack when the bus is quiet, so it can beat.
+const USB_HEARTBEAT_INTERVAL_MS: u64 = 500;
+
+/// Polls the USB stack, beating each time a poll of it returns. A poll only returns once the
+/// stack has handled whatever the bus had for it, so a stack wedged in a request stops beating
+/// even though the rest of core 0 carries on.
+struct Beating<'a, F> {
+	run: Pin<&'a mut F>,
+	heartbeat: &'static Heartbeat,
+}
+
+impl<F: Future> Future for Beating<'_, F> {
+	type Output = F::Output;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+		let result = self.run.as_mut().poll(cx);
+		self.heartbeat.beat(Instant::from_ticks(
+			embassy_time::Instant::now().as_micros(),
+		));
+		result
+	}
+}
+
 #[embassy_executor::task]
-pub async fn usb_task(mut usb: UsbDevice<'static, Driver<'static, USB>>) {
+pub async fn usb_task(
+	mut usb: UsbDevice<'static, Driver<'static, USB>>,
+	heartbeat: &'static Heartbeat,
+) {
 	info!("USB task started.");
-	usb.run().await;
+	let run = pin!(usb.run());
+	// `select` polls both sides whenever either wakes, so the timer only makes sure the stack
+	// gets polled on a quiet bus; the beat comes from the stack's own poll
+	select(Beating { run, heartbeat }, async {
+		loop {
+			Timer::after_millis(USB_HEARTBEAT_INTERVAL_MS).await;
+		}
+	})
+	.await;
 }
 
 pub struct UsbDevices<
@@ -58,12 +103,18 @@ pub fn init_usb<
 	usb: USB,
 	device_info: &DeviceInfo,
 	serial_number: &'static str,
+	indicator_status: &'static IndicatorStatus,
+	host_locks: &'static HostLocks,
+	battery: Option<&'static BatteryStatus>,
 ) -> UsbDevices<{ KeyboardImpl::SIZE }, { MouseImpl::SIZE }, { ConsumerImpl::SIZE }> {
-	let mut usb_builder = get_usb_builder(usb, device_info, serial_number);
+	let mut usb_builder = get_usb_builder(usb, device_info, serial_number, indicator_status);
 
-	let keyboard_writer = get_keyboard_writer::<KeyboardImpl>(&mut usb_builder);
+	let keyboard_writer = get_keyboard_writer::<KeyboardImpl>(&mut usb_builder, host_locks);
 	let mouse_writer = get_mouse_writer::<MouseImpl>(&mut usb_builder);
 	let consumer_writer = get_consumer_writer::<ConsumerImpl>(&mut usb_builder);
+	if let Some(battery) = battery {
+		add_battery_interface(&mut usb_builder, battery);
+	}
 	let serial_class = get_serial_class(&mut usb_builder);
 	let (serial_writer, serial_reader) = serial_class.split();
 
@@ -86,11 +137,17 @@ pub fn init_usb_no_mouse<
 	usb: USB,
 	device_info: &DeviceInfo,
 	serial_number: &'static str,
+	indicator_status: &'static IndicatorStatus,
+	host_locks: &'static HostLocks,
+	battery: Option<&'static BatteryStatus>,
 ) -> UsbDevicesNoMouse<{ KeyboardImpl::SIZE }, { ConsumerImpl::SIZE }> {
-	let mut usb_builder = get_usb_builder(usb, device_info, serial_number);
+	let mut usb_builder = get_usb_builder(usb, device_info, serial_number, indicator_status);
 
-	let keyboard_writer = get_keyboard_writer::<KeyboardImpl>(&mut usb_builder);
+	let keyboard_writer = get_keyboard_writer::<KeyboardImpl>(&mut usb_builder, host_locks);
 	let consumer_writer = get_consumer_writer::<ConsumerImpl>(&mut usb_builder);
+	if let Some(battery) = battery {
+		add_battery_interface(&mut usb_builder, battery);
+	}
 	let serial_class = get_serial_class(&mut usb_builder);
 	let (serial_writer, serial_reader) = serial_class.split();
 
@@ -105,10 +162,22 @@ pub fn init_usb_no_mouse<
 	}
 }
 
+/// Reports bus suspend and resume for indicators.
+struct SuspendHandler {
+	indicator_status: &'static IndicatorStatus,
+}
+
+impl Handler for SuspendHandler {
+	fn suspended(&mut self, suspended: bool) {
+		self.indicator_status.set_usb_suspended(suspended);
+	}
+}
+
 fn get_usb_builder(
 	usb: USB,
 	device_info: &DeviceInfo,
 	serial_number: &'static str,
+	indicator_status: &'static IndicatorStatus,
 ) -> Builder<'static, Driver<'static, USB>> {
 	let mut config = Config::new(0xF055, 0x6969);
 	config.manufacturer = Some(device_info.manufacturer);
@@ -134,22 +203,46 @@ fn get_usb_builder(
 
 	let driver = Driver::new(usb, Irqs);
 
-	Builder::new(
+	let mut builder = Builder::new(
 		driver,
 		config,
 		config_descriptor,
 		bos_descriptor,
 		msos_descriptor,
 		control_buf,
-	)
+	);
+
+	static SUSPEND_HANDLER: StaticCell<SuspendHandler> = StaticCell::new();
+	builder.handler(SUSPEND_HANDLER.init(SuspendHandler { indicator_status }));
+
+	builder
+}
+
+/// Takes the lock LED output report the host sends over the control pipe.
+struct LockHandler {
+	host_locks: &'static HostLocks,
+}
+
+impl RequestHandler for LockHandler {
+	fn set_report(&mut self, _id: ReportId, data: &[u8]) -> OutResponse {
+		match data.first() {
+			Some(report) => {
+				self.host_locks.set_report(*report);
+				OutResponse::Accepted
+			}
+			None => OutResponse::Rejected,
+		}
+	}
 }
 
 fn get_keyboard_writer<KeyboardImpl: HidDevice<KeyboardEvent>>(
 	usb_builder: &mut Builder<'static, Driver<'static, USB>>,
+	host_locks: &'static HostLocks,
 ) -> HidWriter<'static, Driver<'static, USB>, { KeyboardImpl::SIZE }> {
+	static LOCK_HANDLER: StaticCell<LockHandler> = StaticCell::new();
 	let keyboard_hid_config = embassy_usb::class::hid::Config {
 		report_descriptor: KeyboardImpl::report_descriptor(),
-		request_handler: None,
+		request_handler: Some(LOCK_HANDLER.init(LockHandler { host_locks })),
 		poll_ms: 1,
 		max_packet_size: USB_HID_KEYBOARD_PACKET_SIZE as u16,
 	};
@@ -189,6 +282,38 @@ fn get_consumer_writer<ConsumerImpl: HidDevice<ConsumerControlEvent>>(
 	HidWriter::new(usb_builder, state, consumer_hid_config)
 }
 
+/// Answers the host's requests for the battery feature report.
+struct BatteryHandler {
+	battery: &'static BatteryStatus,
+}
+
+impl RequestHandler for BatteryHandler {
+	fn get_report(&mut self, _id: ReportId, buf: &mut [u8]) -> Option<usize> {
+		let level = self.battery.get()?;
+		*buf.first_mut()? = level.percent;
+		Some(1)
+	}
+}
+
+/// Adds an interface the host only reads the battery feature report from; its input
+/// endpoint is never written.
+fn add_battery_interface(
+	usb_builder: &mut Builder<'static, Driver<'static, USB>>,
+	battery: &'static BatteryStatus,
+) {
+	static BATTERY_HANDLER: StaticCell<BatteryHandler> = StaticCell::new();
+	let battery_hid_config = embassy_usb::class::hid::Config {
+		report_descriptor: BATTERY_REPORT_DESCRIPTOR,
+		request_handler: Some(BATTERY_HANDLER.init(BatteryHandler { battery })),
+		poll_ms: 255,
+		max_packet_size: 8,
+	};
+
+	static STATE: StaticCell<HidState> = StaticCell::new();
+	let state = STATE.init(HidState::new());
+	let _ = HidWriter::<_, 1>::new(usb_builder, state, battery_hid_config);
+}
+
 fn get_serial_class(
 	usb_builder: &mut Builder<'static, Driver<'static, USB>>,
 ) -> CdcAcmClass<'static, Driver<'static, USB>> {
@@ -0,0 +1,44 @@
+use cardboard_lib::lighting::{LedDriver, Rgb};
+use embassy_rp::{
+	bind_interrupts,
+	dma::Channel,
+	peripherals::PIO0,
+	pio::{InterruptHandler, Pio, PioPin},
+	pio_programs::ws2812::{PioWs2812, PioWs2812Program},
+	Peripheral,
+};
+use smart_leds::RGB8;
+
+bind_interrupts!(struct Irqs {
+	PIO0_IRQ_0 => InterruptHandler<PIO0>;
+});
+
+/// A chain of `N` WS2812 LEDs driven by a PIO0 state machine.
+pub struct Rp2040Ws2812<const N: usize> {
+	leds: PioWs2812<'static, PIO0, 0, N>,
+}
+
+pub fn init_ws2812<const N: usize>(
+	pio: PIO0,
+	pin: impl PioPin,
+	dma: impl Peripheral<P = impl Channel> + 'static,
+) -> Rp2040Ws2812<N> {
+	let Pio {
+		mut common, sm0, ..
+	} = Pio::new(pio, Irqs);
+	let program = PioWs2812Program::new(&mut common);
+	let leds = PioWs2812::new(&mut common, sm0, dma, pin, &program);
+
+	Rp2040Ws2812 { leds }
+}
+
+impl<const N: usize> LedDriver for Rp2040Ws2812<N> {
+	async fn write(&mut self, colors: &[Rgb]) {
+		// LEDs past the end of the frame stay off
+		let mut out = [RGB8::default(); N];
+		for (out, color) in out.iter_mut().zip(colors) {
+			*out = RGB8::new(color.r, color.g, color.b);
+		}
+		self.leds.write(&out).await;
+	}
+}
@@ -0,0 +1,31 @@
+use cardboard_lib::context::{Reboot, RebootToBootloader};
+use embassy_rp::{pac, rom_data::reboot};
+
+// flags for the bootrom's reboot function
+const REBOOT_TYPE_BOOTSEL: u32 = 0x0002;
+const NO_RETURN_ON_SUCCESS: u32 = 0x0100;
+
+pub struct EmbassyRp2350Reboot {}
+
+pub struct EmbassyRp2350RebootToBootloader {}
+
+impl Reboot for EmbassyRp2350Reboot {
+	fn reboot(&mut self) -> ! {
+		// the supervisor owns the watchdog, so trigger it through the registers
+		pac::WATCHDOG.ctrl().write(|w| w.set_trigger(true));
+		halt()
+	}
+}
+
+impl RebootToBootloader for EmbassyRp2350RebootToBootloader {
+	fn reboot_to_bootloader(&self) -> ! {
+		reboot(REBOOT_TYPE_BOOTSEL | NO_RETURN_ON_SUCCESS, 10, 0, 0);
+		halt()
+	}
+}
+
+fn halt() -> ! {
+	loop {
+		cortex_m::asm::wfi();
+	}
+}
@@ -0,0 +1,43 @@
+use cardboard_lib::{device::DeviceId, rp::EmbassyFlashMemory};
+use defmt::error;
+use embassy_rp::{
+	flash::{Async, Flash},
+	otp,
+	peripherals::{DMA_CH0, FLASH},
+};
+use embassy_time::Timer;
+use uuid::Uuid;
+
+const FLASH_ADDR: *const u8 = 0x10000000 as *const u8;
+pub const FLASH_SIZE: usize = 4 * 1024 * 1024; // 4 MB
+
+pub async fn init_flash<const DATA_SIZE: usize>(
+	flash_data: *const [u8; DATA_SIZE],
+	flash: FLASH,
+	dma_ch0: DMA_CH0,
+) -> FlashStorage {
+	// wait to initialize flash
+	Timer::after_millis(10).await;
+	let flash_memory = Flash::<_, Async, FLASH_SIZE>::new(flash, dma_ch0);
+	let device_id = get_device_id().unwrap();
+	let flash =
+		EmbassyFlashMemory::new(FLASH_ADDR, flash_data as *const u8, DATA_SIZE, flash_memory);
+
+	FlashStorage { device_id, flash }
+}
+
+// the RP2350's flash has no unique ID command, but the chip ID in OTP is unique per device
+fn get_device_id() -> Result<DeviceId, &'static str> {
+	let chip_id = otp::get_chipid().map_err(|e| {
+		error!("Failed to read chip ID from OTP: {}", e);
+		"Failed to read chip ID from OTP"
+	})?;
+
+	let uuid = Uuid::new_v5(&Uuid::NAMESPACE_OID, &chip_id.to_le_bytes());
+	Ok(DeviceId::new(uuid))
+}
+
+pub struct FlashStorage {
+	pub device_id: DeviceId,
+	pub flash: EmbassyFlashMemory<'static, FLASH_SIZE>,
+}
@@ -0,0 +1,2 @@
+pub mod bootloader;
+pub mod flash;
@@ -0,0 +1,1388 @@
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::{future::Future, pin::Pin};
+
+#[cfg(feature = "rp2040")]
+use crate::rp2040::{
+	bootloader::{
+		EmbassyRp2040Reboot as EmbassyReboot,
+		EmbassyRp2040RebootToBootloader as EmbassyRebootToBootloader,
+	},
+	flash::{FlashStorage, FLASH_SIZE},
+	update::{image_layout, install_update},
+};
+#[cfg(feature = "rp2350")]
+use crate::rp2350::{
+	bootloader::{
+		EmbassyRp2350Reboot as EmbassyReboot,
+		EmbassyRp2350RebootToBootloader as EmbassyRebootToBootloader,
+	},
+	flash::{FlashStorage, FLASH_SIZE},
+};
+use crate::{
+	board::{
+		BoardConfig, CommandChannel, FlashLayout, FlashPartitions, MemoryBudgetSizes,
+		VirtualKeyCount,
+	},
+	crash::take_crash_report,
+	get_serial_number,
+	rp2040::{
+		battery::Rp2040BatteryAdc,
+		buzzer::Rp2040Buzzer,
+		display::Rp2040I2c,
+		haptic::Rp2040HapticMotor,
+		i2c_target::{I2cTarget, Rp2040CommandI2c},
+		module::Rp2040ModuleDetect,
+		pipe::{PipePacketReader, PipePacketWriter},
+		pmw3360::Rp2040Pmw3360,
+		power::EmbassyRp2040LowPower,
+		pwm::Rp2040PwmOutput,
+		spi_target::Rp2040CommandSpi,
+		touch::Rp2040TouchPads,
+		uart::{Rp2040CommandUart, UartPacketReader, UartPacketWriter},
+		usb::{init_usb, init_usb_no_mouse, usb_task, USB_SERIAL_PACKET_SIZE},
+	},
+	settings::Settings,
+	StaticCell,
+};
+use cardboard_lib::{
+	battery::{BatteryConfig, BatteryStatus},
+	budget::MemoryBudgets,
+	buzzer::Tone,
+	command::{
+		AuthenticateCommand, BeginFirmwareUpdateCommand, ClearCrashReportCommand,
+		ClearErrorsCommand, Command, CommitFirmwareUpdateCommand, GetActiveTagsCommand,
+		GetAuthChallengeCommand, GetBuildInfoCommand, GetCrashReportCommand, GetKeyStatsCommand,
+		GetModulesCommand, GetProfileCommand, GetProfileHashCommand, GetProfileUploadStatusCommand,
+		GetRawMatrixCommand, GetSettingsCommand, GetStatusCommand, IdentifyCommand,
+		InjectKeyCommand, LockDeviceCommand, PingCommand, RebootCommand,
+		ResetAllocatorStatsCommand, ResumeProfileUploadCommand, SetAuthSecretCommand,
+		SetDeviceNameCommand, SetExternalTagsCommand, SetKeyBindingCommand, SetMacroCommand,
+		SetMacroSpeedCommand, SetSettingCommand, SetSyncMarkersCommand, SetTimeCommand,
+		SetVirtualKeysByIdCommand, SetVirtualKeysCommand, SubscribeKeyEventsCommand,
+		UnlockDeviceCommand, UpdateProfileCommand, UpdateSettingsCommand,
+		VerifyFirmwareUpdateCommand, WriteFirmwareChunkCommand,
+	},
+	context::Context,
+	device::{
+		BuildInfo, DeviceCapabilities, DeviceInfo, DeviceTypeId, DeviceVersion, HidInterfaces,
+	},
+	display::{DisplayStatus, OledDisplay},
+	embassy::{
+		EmbassyKeyEventChannel, EmbassyKeyStatsSignal, EmbassyKeypadHid, EmbassyRequestSignal,
+		EmbassySerialPacketReader, EmbassySerialPacketWriter, EmbassyTickClock, UsbHidTransport,
+	},
+	encoder::{Encoder, Encoders},
+	error::{Error, ErrorLog, HeaplessSpscErrorLog},
+	event::PendingHostEvents,
+	haptic::HapticPattern,
+	hid::{HidDevice, HidReport, HostLocks},
+	indicator::{BoundIndicator, IndicatorStatus},
+	input::{Chord, KeyboardAction, RawMatrixScan, UpdateMatrix, VirtualKeyAction},
+	lighting::{LedDriver, LightingEffect, LightingEvent, Rgb},
+	lock::SafeMode,
+	module::{ModularMatrix, ModuleKeys, ModulePort, ModuleStatus},
+	output::{AuxOutput, AuxOutputs, PwmOutput, PwmOutputs},
+	pointer::{PointerConfig, PointerMotion},
+	power::{PowerMode, PowerPolicy, PowerState},
+	profile::{KeyboardProfile, LayerTag},
+	rp::{EmbassyFlashMemory, RoscNonceSource},
+	serial::{
+		BufferedReader, FramedReader, FramedWriter, SerialPacketReader, SerialPacketSender,
+		SerialSession,
+	},
+	slider::{PadSlider, SliderPosition},
+	state::{ActiveTags, KeyStats, KeypadStatus, MacroLimit},
+	stats::UsbStats,
+	storage::{
+		load_key_stats_from_flash, load_profile_from_flash, load_settings_from_flash,
+		max_profile_size, save_crash_report_to_flash, BlockFlashExt,
+	},
+	supervisor::Heartbeat,
+	tasks::{BootKey, KeypadChannels, KeypadConfig, KeypadHardware, KeypadLinks},
+	time::{Clock, Duration, Instant, WallClock},
+	TrackingAllocator,
+};
+#[cfg(feature = "rp2040")]
+use cardboard_lib::{storage::BlockFlash, update::committed_image};
+use defmt::{info, warn};
+use embassy_executor::{Executor, Spawner};
+use embassy_rp::{
+	gpio::{Input, Output},
+	multicore::{spawn_core1, Stack},
+	peripherals::{CORE1, USB, WATCHDOG},
+	usb::Driver,
+	watchdog::Watchdog,
+};
+use embedded_alloc::LlffHeap;
+use fugit::ExtU64;
+
+mod build_info {
+	include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+}
+
+pub type Heap = LlffHeap;
+
+// sized from the board's `MemoryBudgetSizes`
+static MEMORY_BUDGETS: StaticCell<MemoryBudgets> = StaticCell::new();
+
+// room for the most virtual keys a board can have; boards with fewer leave the rest released
+const VIRTUAL_KEY_BITFIELD_SIZE: usize = 16; // 128 bits
+
+const SERIAL_FRAME_SIZE: usize = 256; // decoded bytes per COBS frame
+
+// bytes the host may have in flight; room for any command that isn't sent in chunks
+const SERIAL_RECEIVE_WINDOW: u16 = SERIAL_FRAME_SIZE as u16;
+
+// key presses are saved at most this often, to spare the flash
+const KEY_STATS_SAVE_INTERVAL_MINS: u64 = 10;
+
+// a low battery sleeps at least this soon, whatever the settings say
+const LOW_BATTERY_IDLE_TIMEOUT_SECS: u64 = 30;
+
+// hid
+type KeyboardImpl = cardboard_lib::hid::NKROKeyboard;
+type MouseImpl = cardboard_lib::hid::Mouse;
+type ConsumerImpl = cardboard_lib::hid::ConsumerControl;
+
+// shared between the keypad on core 1 and everything else on core 0
+type Mutex = embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+type Signal<T> = embassy_sync::signal::Signal<Mutex, T>;
+type Channel<T, const N: usize> = embassy_sync::channel::Channel<Mutex, T, N>;
+type RequestSignal<T> = EmbassyRequestSignal<Mutex, T>;
+static HID_SIGNAL: Signal<
+	HidReport<{ KeyboardImpl::SIZE }, { MouseImpl::SIZE }, { ConsumerImpl::SIZE }>,
+> = Signal::new();
+static PROFILE_CHANGED_SIGNAL: Signal<KeyboardProfile> = Signal::new();
+static EXTERNAL_TAGS_CHANGED_SIGNAL: Signal<Vec<LayerTag>> = Signal::new();
+static VIRTUAL_KEY_SIGNAL: Signal<[u8; VIRTUAL_KEY_BITFIELD_SIZE]> = Signal::new();
+static MACRO_SPEED_SIGNAL: Signal<u16> = Signal::new();
+static HOST_EVENTS: PendingHostEvents = PendingHostEvents::new();
+static USB_STATS: UsbStats = UsbStats::new();
+static INDICATOR_STATUS: IndicatorStatus = IndicatorStatus::new();
+static HOST_LOCKS: HostLocks = HostLocks::new();
+static KEY_EVENT_CHANNEL: KeyEventChannel = KeyEventChannel::new();
+static MATRIX_SCAN_SIGNAL: RequestSignal<RawMatrixScan> = RequestSignal::new();
+static ACTIVE_TAGS_SIGNAL: RequestSignal<ActiveTags> = RequestSignal::new();
+static KEYPAD_STATUS_SIGNAL: RequestSignal<KeypadStatus> = RequestSignal::new();
+static KEY_STATS_SIGNAL: EmbassyKeyStatsSignal<Mutex> = EmbassyKeyStatsSignal::new();
+static INJECTED_KEY_CHANNEL: Channel<KeyboardAction, 16> = Channel::new();
+static VIRTUAL_KEY_ID_CHANNEL: Channel<VirtualKeyAction, 16> = Channel::new();
+static KEYPAD_ERROR_CHANNEL: Channel<Error, 4> = Channel::new();
+static LIGHTING_CHANNEL: Channel<LightingEvent, 32> = Channel::new();
+static DISPLAY_SIGNAL: Signal<DisplayStatus> = Signal::new();
+static TONE_CHANNEL: Channel<Tone, 8> = Channel::new();
+static BATTERY_STATUS: BatteryStatus = BatteryStatus::new();
+static WALL_CLOCK: WallClock = WallClock::new();
+static POWER_STATE: PowerState = PowerState::new();
+static HAPTIC_CHANNEL: Channel<HapticPattern, 4> = Channel::new();
+static POINTER_MOTION: PointerMotion = PointerMotion::new();
+static SLIDER_POSITION: SliderPosition = SliderPosition::new();
+static MODULE_STATUS: ModuleStatus = ModuleStatus::new();
+static SAFE_MODE: SafeMode = SafeMode::new();
+
+// how long each supervised task may go without a heartbeat; the command task's is long enough
+// to erase and rewrite the whole profile partition
+static KEYPAD_HEARTBEAT: Heartbeat = Heartbeat::new("keypad", Duration::millis(1000));
+static HID_HEARTBEAT: Heartbeat = Heartbeat::new("hid", Duration::millis(10_000));
+static CMD_HEARTBEAT: Heartbeat = Heartbeat::new("cmd", Duration::millis(30_000));
+static USB_HEARTBEAT: Heartbeat = Heartbeat::new("usb", Duration::millis(2000));
+static HEARTBEATS: [&Heartbeat; 4] = [
+	&KEYPAD_HEARTBEAT,
+	&HID_HEARTBEAT,
+	&CMD_HEARTBEAT,
+	&USB_HEARTBEAT,
+];
+/// Resets the device if the supervisor stops feeding the watchdog for this long.
+const WATCHDOG_TIMEOUT_MS: u64 = 2000;
+
+type KeyEventChannel = EmbassyKeyEventChannel<Mutex, 16>;
+
+const CORE1_STACK_SIZE: usize = 16 * 1024; // 16 KB
+static mut CORE1_STACK: Stack<CORE1_STACK_SIZE> = Stack::new();
+static CORE1_EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+type ContextFlashMemory = EmbassyFlashMemory<'static, FLASH_SIZE>;
+type ContextSerialReader = FramedReader<BufferedReader<CommandPacketReader>, SERIAL_FRAME_SIZE>;
+type ContextSerialWriter = FramedWriter<CommandPacketWriter, SERIAL_FRAME_SIZE>;
+
+/// Whichever link the board's `CommandChannel` picked, so the command context has one type.
+enum CommandPacketReader {
+	Usb(EmbassySerialPacketReader<'static, Driver<'static, USB>, USB_SERIAL_PACKET_SIZE>),
+	Uart(UartPacketReader),
+	Pipe(PipePacketReader),
+}
+
+impl SerialPacketReader for CommandPacketReader {
+	async fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+		match self {
+			Self::Usb(reader) => reader.read_packet(buf).await,
+			Self::Uart(reader) => reader.read_packet(buf).await,
+			Self::Pipe(reader) => reader.read_packet(buf).await,
+		}
+	}
+
+	const SIZE: usize = USB_SERIAL_PACKET_SIZE;
+}
+
+impl SerialSession for CommandPacketReader {
+	fn take_session_ended(&mut self) -> bool {
+		match self {
+			Self::Usb(reader) => reader.take_session_ended(),
+			Self::Uart(reader) => reader.take_session_ended(),
+			Self::Pipe(reader) => reader.take_session_ended(),
+		}
+	}
+}
+
+enum CommandPacketWriter {
+	Usb(EmbassySerialPacketWriter<'static, Driver<'static, USB>, USB_SERIAL_PACKET_SIZE>),
+	Uart(UartPacketWriter),
+	Pipe(PipePacketWriter),
+}
+
+impl SerialPacketSender for CommandPacketWriter {
+	async fn write_packet(&mut self, data: &[u8]) -> Result<(), &'static str> {
+		match self {
+			Self::Usb(writer) => writer.write_packet(data).await,
+			Self::Uart(writer) => writer.write_packet(data).await,
+			Self::Pipe(writer) => writer.write_packet(data).await,
+		}
+	}
+
+	const SIZE: usize = USB_SERIAL_PACKET_SIZE;
+}
+
+type CommandContext = Context<
+	ContextFlashMemory,
+	ContextSerialReader,
+	ContextSerialWriter,
+	VIRTUAL_KEY_BITFIELD_SIZE,
+	Heap,
+	HeaplessSpscErrorLog<32>,
+	EmbassyTickClock,
+>;
+
+/// The A and B phase pins of a rotary encoder.
+pub type EncoderInputs = (Input<'static>, Input<'static>);
+
+/// A key matrix of any size, so the keypad task doesn't depend on the board.
+pub struct DynMatrix(Box<dyn ErasedMatrix>);
+
+trait ErasedMatrix {
+	fn update(&mut self, now: Instant, dt: Duration, output: &mut Vec<KeyboardAction>);
+	fn scan_raw(&mut self) -> RawMatrixScan;
+	fn wait_for_key(&mut self) -> Pin<Box<dyn Future<Output = ()> + '_>>;
+}
+
+impl<M: UpdateMatrix> ErasedMatrix for M {
+	fn update(&mut self, now: Instant, dt: Duration, output: &mut Vec<KeyboardAction>) {
+		UpdateMatrix::update(self, now, dt, output);
+	}
+
+	fn scan_raw(&mut self) -> RawMatrixScan {
+		UpdateMatrix::scan_raw(self)
+	}
+
+	fn wait_for_key(&mut self) -> Pin<Box<dyn Future<Output = ()> + '_>> {
+		Box::pin(UpdateMatrix::wait_for_key(self))
+	}
+}
+
+impl DynMatrix {
+	pub fn new(matrix: impl UpdateMatrix + 'static) -> Self {
+		Self(Box::new(matrix))
+	}
+}
+
+impl UpdateMatrix for DynMatrix {
+	fn update(&mut self, now: Instant, dt: Duration, output: &mut Vec<KeyboardAction>) {
+		self.0.update(now, dt, output);
+	}
+
+	fn scan_raw(&mut self) -> RawMatrixScan {
+		self.0.scan_raw()
+	}
+
+	async fn wait_for_key(&mut self) {
+		self.0.wait_for_key().await;
+	}
+
+	// only sizes the keypad's key action buffer, which grows for bigger boards
+	const SIZE: usize = 64;
+}
+
+/// An LED chain of any length, so the lighting task doesn't depend on the board.
+pub struct DynLeds {
+	driver: Box<dyn ErasedLeds>,
+	count: usize,
+}
+
+trait ErasedLeds {
+	fn write<'a>(&'a mut self, colors: &'a [Rgb]) -> Pin<Box<dyn Future<Output = ()> + 'a>>;
+}
+
+impl<L: LedDriver> ErasedLeds for L {
+	fn write<'a>(&'a mut self, colors: &'a [Rgb]) -> Pin<Box<dyn Future<Output = ()> + 'a>> {
+		Box::pin(LedDriver::write(self, colors))
+	}
+}
+
+impl DynLeds {
+	pub fn new(driver: impl LedDriver + 'static, count: usize) -> Self {
+		Self {
+			driver: Box::new(driver),
+			count,
+		}
+	}
+}
+
+impl LedDriver for DynLeds {
+	async fn write(&mut self, colors: &[Rgb]) {
+		self.driver.write(colors).await;
+	}
+}
+
+/// What the runtime needs to know about the board, taken from its `BoardConfig`.
+struct BoardInfo {
+	name: &'static str,
+	manufacturer: &'static str,
+	device_type: DeviceTypeId,
+	rows: u8,
+	cols: u8,
+	flash: FlashLayout,
+	boot_keys: [BootKey; 2],
+	command_channel: CommandChannel,
+	memory_budgets: MemoryBudgetSizes,
+	macro_limit: MacroLimit,
+	virtual_keys: VirtualKeyCount,
+}
+
+struct Battery {
+	adc: Rp2040BatteryAdc,
+	config: BatteryConfig,
+	vbus: Input<'static>,
+}
+
+/// Sets up the shared keypad firmware and spawns its tasks, so a board binary only has to
+/// initialise its own peripherals:
+///
+/// ```ignore
+/// CardboardRuntime::builder()
+///     .board(&BOARD)
+///     .allocator(&ALLOCATOR)
+///     .flash(flash)
+///     .matrix(unsafe { BOARD.key_matrix() })
+///     .lighting(leds, LED_COUNT)
+///     .run(spawner, p.USB, p.CORE1, p.WATCHDOG)
+///     .await;
+/// ```
+pub struct CardboardRuntime;
+
+impl CardboardRuntime {
+	/// Real time as the host last set it. Boards with an RTC chip can sync it with
+	/// `WallClock::sync_from_rtc` from a task of their own.
+	pub fn wall_clock() -> &'static WallClock {
+		&WALL_CLOCK
+	}
+
+	pub fn builder() -> RuntimeBuilder {
+		RuntimeBuilder {
+			board: None,
+			allocator: None,
+			flash: None,
+			matrix: None,
+			leds: None,
+			display: None,
+			buzzer: None,
+			haptics: None,
+			pointer: None,
+			slider: None,
+			modules: Vec::new(),
+			battery: None,
+			host_battery: false,
+			command_uart: None,
+			command_i2c: None,
+			command_spi: None,
+			indicators: Vec::new(),
+			outputs: Vec::new(),
+			pwm_outputs: Vec::new(),
+			encoders: Vec::new(),
+		}
+	}
+}
+
+/// Collects the board and its optional peripherals for `CardboardRuntime`. The board,
+/// allocator, flash and matrix are required; anything else the board doesn't have is left out.
+pub struct RuntimeBuilder {
+	board: Option<BoardInfo>,
+	allocator: Option<&'static TrackingAllocator<Heap>>,
+	flash: Option<FlashStorage>,
+	matrix: Option<DynMatrix>,
+	leds: Option<DynLeds>,
+	display: Option<OledDisplay<Rp2040I2c>>,
+	buzzer: Option<Rp2040Buzzer>,
+	haptics: Option<Rp2040HapticMotor>,
+	pointer: Option<Rp2040Pmw3360>,
+	slider: Option<PadSlider<Rp2040TouchPads>>,
+	modules: Vec<(ModulePort<Rp2040ModuleDetect>, DynMatrix)>,
+	battery: Option<Battery>,
+	host_battery: bool,
+	command_uart: Option<Rp2040CommandUart>,
+	command_i2c: Option<Rp2040CommandI2c>,
+	command_spi: Option<Rp2040CommandSpi>,
+	indicators: Vec<BoundIndicator<Output<'static>>>,
+	outputs: Vec<AuxOutput<Output<'static>>>,
+	pwm_outputs: Vec<PwmOutput<Rp2040PwmOutput>>,
+	encoders: Vec<Encoder<EncoderInputs>>,
+}
+
+impl RuntimeBuilder {
+	pub fn board<const ROWS: usize, const COLS: usize>(
+		mut self,
+		board: &BoardConfig<ROWS, COLS>,
+	) -> Self
+	where
+		[(); ROWS * COLS]:,
+	{
+		self.board = Some(BoardInfo {
+			name: board.name,
+			manufacturer: board.manufacturer,
+			device_type: board.device_type,
+			rows: ROWS as u8,
+			cols: COLS as u8,
+			flash: board.flash,
+			boot_keys: board.boot_keys(),
+			command_channel: board.command_channel,
+			memory_budgets: board.memory_budgets,
+			macro_limit: board.macro_limit,
+			virtual_keys: board.virtual_keys,
+		});
+		self
+	}
+
+	/// The global allocator, with its heap already initialised.
+	pub fn allocator(mut self, allocator: &'static TrackingAllocator<Heap>) -> Self {
+		self.allocator = Some(allocator);
+		self
+	}
+
+	/// The flash window laid out by the board's `FlashLayout`.
+	pub fn flash(mut self, flash: FlashStorage) -> Self {
+		self.flash = Some(flash);
+		self
+	}
+
+	pub fn matrix(mut self, matrix: impl UpdateMatrix + 'static) -> Self {
+		self.matrix = Some(DynMatrix::new(matrix));
+		self
+	}
+
+	pub fn lighting(mut self, leds: impl LedDriver + 'static, count: usize) -> Self {
+		self.leds = Some(DynLeds::new(leds, count));
+		self
+	}
+
+	pub fn display(mut self, display: OledDisplay<Rp2040I2c>) -> Self {
+		self.display = Some(display);
+		self
+	}
+
+	pub fn buzzer(mut self, buzzer: Rp2040Buzzer) -> Self {
+		self.buzzer = Some(buzzer);
+		self
+	}
+
+	pub fn haptics(mut self, motor: Rp2040HapticMotor) -> Self {
+		self.haptics = Some(motor);
+		self
+	}
+
+	/// A trackball sensor, whose motion is sent through the mouse interface.
+	pub fn pointer(mut self, sensor: Rp2040Pmw3360) -> Self {
+		self.pointer = Some(sensor);
+		self
+	}
+
+	/// A capacitive touch slider, which does what the profile's slider mappings say.
+	pub fn slider(mut self, slider: PadSlider<Rp2040TouchPads>) -> Self {
+		self.slider = Some(slider);
+		self
+	}
+
+	/// A key module that can be plugged in while running, whose `keys` are scanned along with
+	/// the matrix while `detect` finds it.
+	pub fn module(
+		mut self,
+		name: &'static str,
+		keys: impl UpdateMatrix + 'static,
+		detect: Rp2040ModuleDetect,
+	) -> Self {
+		self.modules
+			.push((ModulePort { name, detect }, DynMatrix::new(keys)));
+		self
+	}
+
+	/// Measures the battery, with `vbus` high while on external power.
+	pub fn battery(
+		mut self,
+		adc: Rp2040BatteryAdc,
+		config: BatteryConfig,
+		vbus: Input<'static>,
+	) -> Self {
+		self.battery = Some(Battery { adc, config, vbus });
+		self
+	}
+
+	/// Reports the battery level to the host over USB. Off by default, so keypads powered over
+	/// USB don't show the host a battery icon.
+	pub fn host_battery(mut self, enabled: bool) -> Self {
+		self.host_battery = enabled;
+		self
+	}
+
+	/// Carries the command protocol instead of USB serial, for boards whose `BoardConfig` picks
+	/// `CommandChannel::Uart`.
+	pub fn command_uart(mut self, uart: Rp2040CommandUart) -> Self {
+		self.command_uart = Some(uart);
+		self
+	}
+
+	/// Carries the command protocol instead of USB serial, for boards whose `BoardConfig` picks
+	/// `CommandChannel::I2c`.
+	pub fn command_i2c(mut self, i2c: Rp2040CommandI2c) -> Self {
+		self.command_i2c = Some(i2c);
+		self
+	}
+
+	/// Carries the command protocol instead of USB serial, for boards whose `BoardConfig` picks
+	/// `CommandChannel::Spi`.
+	pub fn command_spi(mut self, spi: Rp2040CommandSpi) -> Self {
+		self.command_spi = Some(spi);
+		self
+	}
+
+	pub fn indicator(mut self, indicator: BoundIndicator<Output<'static>>) -> Self {
+		self.indicators.push(indicator);
+		self
+	}
+
+	/// A spare pin for macros to drive, named as profiles refer to it.
+	pub fn output(mut self, output: AuxOutput<Output<'static>>) -> Self {
+		self.outputs.push(output);
+		self
+	}
+
+	pub fn pwm_output(mut self, output: PwmOutput<Rp2040PwmOutput>) -> Self {
+		self.pwm_outputs.push(output);
+		self
+	}
+
+	/// A rotary encoder on two input pins, named as profiles refer to it.
+	pub fn encoder(mut self, encoder: Encoder<EncoderInputs>) -> Self {
+		self.encoders.push(encoder);
+		self
+	}
+
+	/// Loads the stored state and spawns every task, running the keypad on core 1. Never
+	/// returns if a committed firmware update is waiting to be installed.
+	pub async fn run(self, spawner: Spawner, usb: USB, core1: CORE1, watchdog: WATCHDOG) {
+		let board = self.board.expect("Runtime needs a board");
+		let allocator = self.allocator.expect("Runtime needs an allocator");
+		let flash = self.flash.expect("Runtime needs flash storage");
+		let mut matrix = self.matrix.expect("Runtime needs a key matrix");
+		let (module_ports, module_keys): (Vec<_>, Vec<_>) = self.modules.into_iter().unzip();
+		if !module_keys.is_empty() {
+			let module_keys = module_keys.into_iter().map(ModuleKeys::new).collect();
+			matrix = DynMatrix::new(ModularMatrix::new(matrix, module_keys, &MODULE_STATUS));
+		}
+
+		let budgets: &'static MemoryBudgets = MEMORY_BUDGETS.init(MemoryBudgets::new(
+			board.memory_budgets.profile,
+			board.memory_budgets.macros,
+			board.memory_budgets.serial,
+		));
+
+		let cmds: Vec<Box<dyn Command<CommandContext>>> = vec![
+			// identify MUST be first
+			/* 0x00 */ Box::new(IdentifyCommand {}),
+			/* 0x01 */ Box::new(UpdateProfileCommand {}),
+			/* 0x02 */ Box::new(GetProfileCommand {}),
+			/* 0x03 */ Box::new(SetExternalTagsCommand {}),
+			/* 0x04 */ Box::new(RebootCommand {}),
+			/* 0x05 */ Box::new(GetStatusCommand {}),
+			/* 0x06 */ set_virtual_keys_command(board.virtual_keys),
+			/* 0x07 */ Box::new(UpdateSettingsCommand {}),
+			/* 0x08 */ Box::new(GetSettingsCommand {}),
+			/* 0x09 */ Box::new(SubscribeKeyEventsCommand {}),
+			/* 0x0A */ Box::new(GetRawMatrixCommand {}),
+			/* 0x0B */ Box::new(InjectKeyCommand {}),
+			/* 0x0C */ Box::new(GetActiveTagsCommand {}),
+			/* 0x0D */ Box::new(ClearErrorsCommand {}),
+			/* 0x0E */ Box::new(PingCommand {}),
+			/* 0x0F */ Box::new(GetBuildInfoCommand {}),
+			/* 0x10 */ Box::new(SetDeviceNameCommand::<Settings>::new()),
+			/* 0x11 */ Box::new(SetSettingCommand::<Settings>::new()),
+			/* 0x12 */ Box::new(ResetAllocatorStatsCommand {}),
+			/* 0x13 */ Box::new(SetMacroSpeedCommand {}),
+			/* 0x14 */ Box::new(SetVirtualKeysByIdCommand {}),
+			/* 0x15 */ Box::new(GetKeyStatsCommand {}),
+			/* 0x16 */ Box::new(GetCrashReportCommand {}),
+			/* 0x17 */ Box::new(ClearCrashReportCommand {}),
+			/* 0x18 */ Box::new(BeginFirmwareUpdateCommand {}),
+			/* 0x19 */ Box::new(WriteFirmwareChunkCommand {}),
+			/* 0x1A */ Box::new(VerifyFirmwareUpdateCommand {}),
+			/* 0x1B */ Box::new(CommitFirmwareUpdateCommand {}),
+			/* 0x1C */ Box::new(LockDeviceCommand {}),
+			/* 0x1D */ Box::new(UnlockDeviceCommand::new()),
+			/* 0x1E */ Box::new(GetAuthChallengeCommand {}),
+			/* 0x1F */ Box::new(AuthenticateCommand {}),
+			/* 0x20 */ Box::new(SetAuthSecretCommand {}),
+			/* 0x21 */ Box::new(GetProfileUploadStatusCommand {}),
+			/* 0x22 */ Box::new(ResumeProfileUploadCommand {}),
+			/* 0x23 */ Box::new(SetTimeCommand {}),
+			/* 0x24 */ Box::new(GetModulesCommand {}),
+			/* 0x25 */ Box::new(SetKeyBindingCommand {}),
+			/* 0x26 */ Box::new(SetMacroCommand {}),
+			/* 0x27 */ Box::new(GetProfileHashCommand {}),
+			/* 0x28 */ Box::new(SetSyncMarkersCommand {}),
+		];
+
+		let device_id = flash.device_id;
+		let mut flash = flash.flash;
+
+		let FlashPartitions {
+			firmware_update: firmware_update_partition,
+			settings: settings_partition,
+			profile: profile_partition,
+			auth: auth_partition,
+			lock: lock_partition,
+			crash_report: crash_report_partition,
+			key_stats: key_stats_partition,
+		} = board.flash.partitions();
+
+		// the panic handler can't write flash, so move its report there before anything else runs
+		if let Some(report) = take_crash_report() {
+			warn!("Recovered from a crash: {}", report.message.as_str());
+			if let Err(err) =
+				save_crash_report_to_flash(&mut flash.partition(&crash_report_partition), &report)
+					.await
+			{
+				warn!(
+					"Failed to save crash report to flash storage. Error: {}",
+					err
+				);
+			}
+		}
+
+		// only the RP2040 has a flasher, so other chips refuse updates
+		#[cfg(feature = "rp2040")]
+		let firmware_image_layout = image_layout();
+		#[cfg(not(feature = "rp2040"))]
+		let firmware_image_layout = None;
+
+		// a committed update is installed before the second core or USB are started
+		#[cfg(feature = "rp2040")]
+		if let Some(layout) = &firmware_image_layout {
+			let firmware_update_flash = flash.partition(&firmware_update_partition);
+			if let Some(image) = committed_image(&firmware_update_flash, layout) {
+				install_update(image, firmware_update_flash.as_slice());
+			}
+		}
+
+		// settings that fail to load are logged for the host, like the profile below
+		let mut settings_error = None;
+		let settings: Settings =
+			match load_settings_from_flash(&mut flash.partition(&settings_partition)).await {
+				Ok(settings) => settings,
+				Err(err) => {
+					warn!(
+						"Failed to load settings from flash storage. Using defaults. Error: {}",
+						err
+					);
+					settings_error = Some(err);
+					Settings::default()
+				}
+			};
+
+		let mut hid = HidInterfaces::KEYBOARD | HidInterfaces::CONSUMER_CONTROL;
+		hid.set(HidInterfaces::MOUSE, settings.mouse_enabled);
+		hid.set(HidInterfaces::BATTERY, self.host_battery);
+
+		static DEVICE_INFO: StaticCell<DeviceInfo> = StaticCell::new();
+		let device_info = DEVICE_INFO.init(DeviceInfo {
+			id: device_id,
+			name: match settings.device_name.clone() {
+				Some(name) => name.leak(),
+				None => board.name,
+			},
+			manufacturer: board.manufacturer,
+			r#type: board.device_type,
+			variant: None,
+			version: DeviceVersion::new(0x00000001),
+			commands: cmds.iter().map(|cmd| cmd.info()).collect(),
+			build: BuildInfo {
+				version: env!("CARGO_PKG_VERSION"),
+				git_hash: build_info::GIT_HASH,
+				timestamp: build_info::BUILD_TIMESTAMP,
+				features: build_info::FEATURES,
+			},
+			mouse_enabled: settings.mouse_enabled,
+			capabilities: DeviceCapabilities {
+				virtual_keys: board.virtual_keys.keys(),
+				matrix_rows: board.rows,
+				matrix_cols: board.cols,
+				hid,
+				partitions: board.flash.partition_sizes(),
+				max_profile_size: max_profile_size(board.flash.profile_size()) as u32,
+				receive_window: SERIAL_RECEIVE_WINDOW,
+			},
+		});
+
+		static CLOCK: StaticCell<EmbassyTickClock> = StaticCell::new();
+		let clock = CLOCK.init(EmbassyTickClock {});
+
+		let tick_interval = 1.millis();
+		let lighting_interval = 10.millis();
+		let display_interval = 50.millis();
+		let buzzer_interval = 10.millis();
+		let haptic_interval = 10.millis();
+		let pointer_interval = 1.millis();
+		let slider_interval = 10.millis();
+		let module_interval = 50.millis();
+		let battery_interval = 10.secs();
+		let supervisor_interval = 100.millis();
+
+		// held at runtime, if configured
+		let bootloader_chord = if settings.bootloader_chord.is_empty() {
+			None
+		} else {
+			Chord::new(
+				settings.bootloader_chord.clone(),
+				(settings.bootloader_chord_hold_ms as u64).millis(),
+			)
+			.ok()
+		};
+
+		// a profile that fails to load (including running out of memory) is logged for the host
+		let mut profile_error = None;
+		let heap_before = allocator.current();
+		let profile = match load_profile_from_flash(&mut flash.partition(&profile_partition))
+			.await
+			.and_then(|profile| {
+				budgets
+					.profile
+					.check(allocator.current().saturating_sub(heap_before))
+					.map(|_| profile)
+					.map_err(|_| "Profile exceeds memory budget")
+			}) {
+			Ok(profile) => {
+				info!("Profile loaded from flash storage");
+				profile
+			}
+			Err(err) => {
+				warn!(
+					"Failed to load profile from flash storage. \
+					 Falling back to empty profile. Error: {}",
+					err
+				);
+				profile_error = Some(err);
+				KeyboardProfile::default()
+			}
+		};
+
+		let mut key_stats_error = None;
+		let key_stats =
+			match load_key_stats_from_flash(&mut flash.partition(&key_stats_partition)).await {
+				Ok(stats) => stats,
+				Err(err) => {
+					warn!(
+						"Failed to load key stats from flash storage. \
+						 Starting from zero. Error: {}",
+						err
+					);
+					key_stats_error = Some(err);
+					KeyStats::default()
+				}
+			};
+
+		let hid = EmbassyKeypadHid {
+			keyboard: KeyboardImpl::new(),
+			mouse: MouseImpl::new(),
+			consumer: ConsumerImpl::new(),
+			signal: &HID_SIGNAL,
+		};
+
+		static REBOOT: StaticCell<EmbassyReboot> = StaticCell::new();
+		let reboot = REBOOT.init(EmbassyReboot {});
+
+		static BOOTLOADER: StaticCell<EmbassyRebootToBootloader> = StaticCell::new();
+		let bootloader = BOOTLOADER.init(EmbassyRebootToBootloader {});
+
+		static NONCE_SOURCE: StaticCell<RoscNonceSource> = StaticCell::new();
+		let nonce_source = NONCE_SOURCE.init(RoscNonceSource);
+
+		static POWER: StaticCell<EmbassyRp2040LowPower> = StaticCell::new();
+		let power = POWER.init(EmbassyRp2040LowPower {});
+
+		let idle_timeout =
+			(settings.idle_timeout_secs != 0).then(|| (settings.idle_timeout_secs as u64).secs());
+		let battery_idle_timeout = (settings.battery_idle_timeout_secs != 0)
+			.then(|| (settings.battery_idle_timeout_secs as u64).secs());
+		let battery_scan_interval = (settings.battery_scan_interval_ms.max(1) as u64).millis();
+		let power_policy = PowerPolicy {
+			external: PowerMode {
+				scan_interval: tick_interval,
+				brightness_percent: 100,
+				idle_timeout,
+			},
+			battery: PowerMode {
+				scan_interval: battery_scan_interval,
+				brightness_percent: settings.battery_brightness_percent,
+				idle_timeout: battery_idle_timeout,
+			},
+			// lights off and the shorter of the two timeouts to stretch what's left
+			low_battery: PowerMode {
+				scan_interval: battery_scan_interval,
+				brightness_percent: 0,
+				idle_timeout: Some(
+					battery_idle_timeout.map_or(LOW_BATTERY_IDLE_TIMEOUT_SECS.secs(), |timeout| {
+						timeout.min(LOW_BATTERY_IDLE_TIMEOUT_SECS.secs())
+					}),
+				),
+			},
+			low_battery_percent: settings.low_battery_percent,
+		};
+
+		let serial_number = get_serial_number(&device_id);
+
+		let serial_read_timeout = 100.millis();
+		let serial_write_timeout = 1.secs();
+
+		let host_battery = self.host_battery.then_some(&BATTERY_STATUS);
+		let (serial_reader, serial_writer, usb_device, hid_transport) = if settings.mouse_enabled {
+			let usb = init_usb::<KeyboardImpl, MouseImpl, ConsumerImpl>(
+				usb,
+				&device_info,
+				serial_number,
+				&INDICATOR_STATUS,
+				&HOST_LOCKS,
+				host_battery,
+			);
+			let hid = UsbHidTransport::new(
+				usb.keyboard_writer,
+				Some(usb.mouse_writer),
+				usb.consumer_writer,
+				&USB_STATS,
+			);
+			(usb.serial_reader, usb.serial_writer, usb.device, hid)
+		} else {
+			let usb = init_usb_no_mouse::<KeyboardImpl, ConsumerImpl>(
+				usb,
+				&device_info,
+				serial_number,
+				&INDICATOR_STATUS,
+				&HOST_LOCKS,
+				host_battery,
+			);
+			let hid =
+				UsbHidTransport::new(usb.keyboard_writer, None, usb.consumer_writer, &USB_STATS);
+			(usb.serial_reader, usb.serial_writer, usb.device, hid)
+		};
+		spawner
+			.spawn(hid_task(&HID_SIGNAL, hid_transport, &HID_HEARTBEAT))
+			.unwrap();
+
+		// the USB serial interface is still there with another channel, it just goes unanswered
+		let (serial_rx, serial_tx) = match board.command_channel {
+			CommandChannel::Usb => (
+				CommandPacketReader::Usb(EmbassySerialPacketReader::new(
+					serial_reader,
+					serial_read_timeout,
+				)),
+				CommandPacketWriter::Usb(EmbassySerialPacketWriter::new(
+					serial_writer,
+					serial_write_timeout,
+				)),
+			),
+			CommandChannel::Uart => {
+				info!("Commands are on the UART");
+				let uart = self
+					.command_uart
+					.expect("Board's command channel is a UART, but none was given");
+				let (reader, writer) = uart.split(serial_read_timeout, serial_write_timeout);
+				(
+					CommandPacketReader::Uart(reader),
+					CommandPacketWriter::Uart(writer),
+				)
+			}
+			CommandChannel::I2c => {
+				info!("Commands are on the I2C target");
+				let i2c = self
+					.command_i2c
+					.expect("Board's command channel is I2C, but no I2C target was given");
+				let (reader, writer, target) = i2c.split(serial_read_timeout, serial_write_timeout);
+				spawner.spawn(i2c_target_task(target)).unwrap();
+				(
+					CommandPacketReader::Pipe(reader),
+					CommandPacketWriter::Pipe(writer),
+				)
+			}
+			CommandChannel::Spi => {
+				info!("Commands are on the SPI target");
+				let spi = self
+					.command_spi
+					.expect("Board's command channel is SPI, but no SPI target was given");
+				let (reader, writer) = spi.split(serial_read_timeout, serial_write_timeout);
+				(
+					CommandPacketReader::Pipe(reader),
+					CommandPacketWriter::Pipe(writer),
+				)
+			}
+		};
+		let serial_rx = FramedReader::new(BufferedReader::new(serial_rx)).with_stats(&USB_STATS);
+		let serial_tx = FramedWriter::new(serial_tx);
+
+		let mut error_log = HeaplessSpscErrorLog::new();
+		for message in [settings_error, profile_error, key_stats_error]
+			.into_iter()
+			.flatten()
+		{
+			error_log.push(Error {
+				timestamp: clock.now(),
+				message,
+			});
+		}
+
+		let ctx = CommandContext::new(
+			device_info,
+			flash,
+			settings_partition,
+			profile_partition,
+			key_stats_partition,
+			crash_report_partition,
+			firmware_update_partition,
+			firmware_image_layout,
+			lock_partition,
+			auth_partition,
+			&PROFILE_CHANGED_SIGNAL,
+			serial_rx,
+			serial_tx,
+			&EXTERNAL_TAGS_CHANGED_SIGNAL,
+			&VIRTUAL_KEY_SIGNAL,
+			&VIRTUAL_KEY_ID_CHANNEL,
+			&MACRO_SPEED_SIGNAL,
+			&KEY_EVENT_CHANNEL,
+			&MATRIX_SCAN_SIGNAL,
+			&INJECTED_KEY_CHANNEL,
+			&ACTIVE_TAGS_SIGNAL,
+			&KEYPAD_STATUS_SIGNAL,
+			&KEY_STATS_SIGNAL,
+			allocator,
+			&USB_STATS,
+			&BATTERY_STATUS,
+			&MODULE_STATUS,
+			&SAFE_MODE,
+			budgets,
+			reboot,
+			bootloader,
+			nonce_source,
+			error_log,
+			clock,
+			&WALL_CLOCK,
+		);
+
+		spawner.spawn(usb_task(usb_device, &USB_HEARTBEAT)).unwrap();
+
+		if let Some(leds) = self.leds {
+			spawner
+				.spawn(lighting_task(
+					clock,
+					leds,
+					&LIGHTING_CHANNEL,
+					settings.lighting_effect,
+					settings.lighting_effect_speed,
+					&POWER_STATE,
+					lighting_interval,
+				))
+				.unwrap();
+		}
+
+		if let Some(display) = self.display {
+			spawner
+				.spawn(display_task(
+					clock,
+					display,
+					&DISPLAY_SIGNAL,
+					display_interval,
+				))
+				.unwrap();
+		}
+
+		if let Some(buzzer) = self.buzzer {
+			spawner
+				.spawn(buzzer_task(clock, buzzer, &TONE_CHANNEL, buzzer_interval))
+				.unwrap();
+		}
+
+		if let Some(battery) = self.battery {
+			spawner
+				.spawn(battery_task(
+					clock,
+					battery.adc,
+					battery.config,
+					&BATTERY_STATUS,
+					battery.vbus,
+					power_policy,
+					&POWER_STATE,
+					battery_interval,
+				))
+				.unwrap();
+		}
+
+		if let Some(motor) = self.haptics {
+			spawner
+				.spawn(haptic_task(clock, motor, &HAPTIC_CHANNEL, haptic_interval))
+				.unwrap();
+		}
+
+		if let Some(sensor) = self.pointer {
+			spawner
+				.spawn(pointer_task(
+					clock,
+					sensor,
+					settings.pointer,
+					&POINTER_MOTION,
+					pointer_interval,
+				))
+				.unwrap();
+		}
+
+		if let Some(slider) = self.slider {
+			spawner
+				.spawn(slider_task(
+					clock,
+					slider,
+					&SLIDER_POSITION,
+					slider_interval,
+				))
+				.unwrap();
+		}
+
+		if !module_ports.is_empty() {
+			spawner
+				.spawn(module_task(
+					clock,
+					module_ports,
+					&MODULE_STATUS,
+					module_interval,
+				))
+				.unwrap();
+		}
+
+		static BOOT_KEYS: StaticCell<[BootKey; 2]> = StaticCell::new();
+		let hardware = KeypadHardware {
+			matrix,
+			hid,
+			bootloader,
+			power,
+			indicators: self.indicators,
+			outputs: AuxOutputs::new(self.outputs),
+			pwm_outputs: PwmOutputs::new(self.pwm_outputs),
+			encoders: Encoders::new(self.encoders),
+		};
+		let channels = KeypadChannels::<RuntimeLinks, VIRTUAL_KEY_BITFIELD_SIZE> {
+			profile_changed: &PROFILE_CHANGED_SIGNAL,
+			tags_changed: &EXTERNAL_TAGS_CHANGED_SIGNAL,
+			virtual_keys_changed: &VIRTUAL_KEY_SIGNAL,
+			virtual_keys_by_id: &VIRTUAL_KEY_ID_CHANNEL,
+			macro_speed_changed: &MACRO_SPEED_SIGNAL,
+			power_state: &POWER_STATE,
+			host_events: &HOST_EVENTS,
+			key_events: &KEY_EVENT_CHANNEL,
+			matrix_scan: &MATRIX_SCAN_SIGNAL,
+			injected_keys: &INJECTED_KEY_CHANNEL,
+			active_tags: &ACTIVE_TAGS_SIGNAL,
+			keypad_status: &KEYPAD_STATUS_SIGNAL,
+			key_stats: &KEY_STATS_SIGNAL,
+			lighting: &LIGHTING_CHANNEL,
+			tones: &TONE_CHANNEL,
+			haptics: &HAPTIC_CHANNEL,
+			pointer_motion: &POINTER_MOTION,
+			slider_position: &SLIDER_POSITION,
+			modules: &MODULE_STATUS,
+			safe_mode: &SAFE_MODE,
+			indicator_status: &INDICATOR_STATUS,
+			host_locks: &HOST_LOCKS,
+			display: &DISPLAY_SIGNAL,
+			macro_budget: &budgets.macros,
+			errors: &KEYPAD_ERROR_CHANNEL,
+			heartbeat: &KEYPAD_HEARTBEAT,
+		};
+		let config = KeypadConfig {
+			boot_keys: BOOT_KEYS.init(board.boot_keys),
+			bootloader_chord,
+			idle_timeout,
+			saved_key_stats: key_stats,
+			key_stats_save_interval: KEY_STATS_SAVE_INTERVAL_MINS.minutes(),
+			macro_limit: board.macro_limit,
+			interval: tick_interval,
+		};
+
+		// the keypad engine gets core 1 to itself so USB, commands and lighting on core 0 can't
+		// delay a scan. It runs from flash, so core 0 pauses it for every flash write and
+		// erase: scans stop for as long as that takes, which for an erase of a whole
+		// partition runs to hundreds of milliseconds
+		spawn_core1(
+			core1,
+			unsafe { &mut *core::ptr::addr_of_mut!(CORE1_STACK) },
+			move || {
+				let executor = CORE1_EXECUTOR.init(Executor::new());
+				executor.run(|spawner| {
+					spawner
+						.spawn(keypad_task(clock, profile, hardware, channels, config))
+						.unwrap();
+				});
+			},
+		);
+
+		spawner
+			.spawn(cmd_task(
+				clock,
+				cmds,
+				ctx,
+				&HOST_EVENTS,
+				&KEYPAD_ERROR_CHANNEL,
+				&INDICATOR_STATUS,
+				&CMD_HEARTBEAT,
+			))
+			.unwrap();
+
+		let mut watchdog = Watchdog::new(watchdog);
+		watchdog.pause_on_debug(true);
+		watchdog.start(embassy_time::Duration::from_millis(WATCHDOG_TIMEOUT_MS));
+		spawner
+			.spawn(supervisor_task(
+				clock,
+				watchdog,
+				&HEARTBEATS,
+				supervisor_interval,
+			))
+			.unwrap();
+	}
+}
+
+/// The signals and channels above, as the keypad task sees them.
+struct RuntimeLinks;
+
+impl KeypadLinks<VIRTUAL_KEY_BITFIELD_SIZE> for RuntimeLinks {
+	type ProfileChanged = Signal<KeyboardProfile>;
+	type ExternalTagsChanged = Signal<Vec<LayerTag>>;
+	type VirtualKeysChanged = Signal<[u8; VIRTUAL_KEY_BITFIELD_SIZE]>;
+	type VirtualKeysById = Channel<VirtualKeyAction, 16>;
+	type MacroSpeedChanged = Signal<u16>;
+	type HostEvents = PendingHostEvents;
+	type KeyEvents = KeyEventChannel;
+	type MatrixScan = RequestSignal<RawMatrixScan>;
+	type InjectedKeys = Channel<KeyboardAction, 16>;
+	type ActiveTags = RequestSignal<ActiveTags>;
+	type KeypadStatus = RequestSignal<KeypadStatus>;
+	type KeyStats = EmbassyKeyStatsSignal<Mutex>;
+	type Lighting = Channel<LightingEvent, 32>;
+	type Tones = Channel<Tone, 8>;
+	type Haptics = Channel<HapticPattern, 4>;
+	type Display = Signal<DisplayStatus>;
+	type Errors = Channel<Error, 4>;
+}
+
+type RuntimeKeypadHardware = KeypadHardware<
+	DynMatrix,
+	EmbassyKeypadHid<KeyboardImpl, MouseImpl, ConsumerImpl, Mutex>,
+	EmbassyRebootToBootloader,
+	EmbassyRp2040LowPower,
+	Output<'static>,
+	Output<'static>,
+	Rp2040PwmOutput,
+	EncoderInputs,
+>;
+
+#[embassy_executor::task]
+async fn keypad_task(
+	clock: &'static EmbassyTickClock,
+	profile: KeyboardProfile,
+	hardware: RuntimeKeypadHardware,
+	channels: KeypadChannels<RuntimeLinks, VIRTUAL_KEY_BITFIELD_SIZE>,
+	config: KeypadConfig<'static>,
+) {
+	cardboard_lib::tasks::keypad_task(clock, profile, hardware, channels, config).await
+}
+
+/// Set Virtual Keys in the size the board has; the keys past it stay released.
+fn set_virtual_keys_command(count: VirtualKeyCount) -> Box<dyn Command<CommandContext>> {
+	match count {
+		VirtualKeyCount::Keys8 => Box::new(SetVirtualKeysCommand::<1> {}),
+		VirtualKeyCount::Keys32 => Box::new(SetVirtualKeysCommand::<4> {}),
+		VirtualKeyCount::Keys128 => Box::new(SetVirtualKeysCommand::<16> {}),
+	}
+}
+
+#[embassy_executor::task]
+async fn lighting_task(
+	clock: &'static EmbassyTickClock,
+	leds: DynLeds,
+	events: &'static Channel<LightingEvent, 32>,
+	effect: LightingEffect,
+	effect_speed_percent: u16,
+	power_state: &'static PowerState,
+	interval: Duration,
+) {
+	let count = leds.count;
+	cardboard_lib::tasks::lighting_task(
+		clock,
+		leds,
+		events,
+		count,
+		effect,
+		effect_speed_percent,
+		power_state,
+		interval,
+	)
+	.await;
+}
+
+#[embassy_executor::task]
+async fn display_task(
+	clock: &'static EmbassyTickClock,
+	display: OledDisplay<Rp2040I2c>,
+	status: &'static Signal<DisplayStatus>,
+	interval: Duration,
+) {
+	cardboard_lib::tasks::display_task(clock, display, status, interval).await;
+}
+
+#[embassy_executor::task]
+async fn buzzer_task(
+	clock: &'static EmbassyTickClock,
+	buzzer: Rp2040Buzzer,
+	tones: &'static Channel<Tone, 8>,
+	interval: Duration,
+) {
+	cardboard_lib::tasks::buzzer_task(clock, buzzer, tones, interval).await;
+}
+
+#[embassy_executor::task]
+async fn haptic_task(
+	clock: &'static EmbassyTickClock,
+	motor: Rp2040HapticMotor,
+	haptics: &'static Channel<HapticPattern, 4>,
+	interval: Duration,
+) {
+	cardboard_lib::tasks::haptic_task(clock, motor, haptics, interval).await;
+}
+
+#[embassy_executor::task]
+async fn pointer_task(
+	clock: &'static EmbassyTickClock,
+	sensor: Rp2040Pmw3360,
+	config: PointerConfig,
+	motion: &'static PointerMotion,
+	interval: Duration,
+) {
+	cardboard_lib::tasks::pointer_task(clock, sensor, config, motion, interval).await;
+}
+
+#[embassy_executor::task]
+async fn slider_task(
+	clock: &'static EmbassyTickClock,
+	slider: PadSlider<Rp2040TouchPads>,
+	position: &'static SliderPosition,
+	interval: Duration,
+) {
+	cardboard_lib::tasks::slider_task(clock, slider, position, interval).await;
+}
+
+#[embassy_executor::task]
+async fn module_task(
+	clock: &'static EmbassyTickClock,
+	ports: Vec<ModulePort<Rp2040ModuleDetect>>,
+	status: &'static ModuleStatus,
+	interval: Duration,
+) {
+	cardboard_lib::tasks::module_task(clock, ports, status, interval).await;
+}
+
+#[embassy_executor::task]
+async fn battery_task(
+	clock: &'static EmbassyTickClock,
+	adc: Rp2040BatteryAdc,
+	config: BatteryConfig,
+	status: &'static BatteryStatus,
+	vbus: Input<'static>,
+	policy: PowerPolicy,
+	power_state: &'static PowerState,
+	interval: Duration,
+) {
+	cardboard_lib::tasks::battery_task(
+		clock,
+		adc,
+		config,
+		status,
+		vbus,
+		policy,
+		power_state,
+		interval,
+	)
+	.await;
+}
+
+#[embassy_executor::task]
+async fn cmd_task(
+	clock: &'static EmbassyTickClock,
+	cmds: Vec<Box<dyn Command<CommandContext>>>,
+	ctx: CommandContext,
+	host_events: &'static PendingHostEvents,
+	keypad_errors: &'static Channel<Error, 4>,
+	indicator_status: &'static IndicatorStatus,
+	heartbeat: &'static Heartbeat,
+) {
+	cardboard_lib::tasks::cmd_task(
+		clock,
+		cmds,
+		ctx,
+		host_events,
+		keypad_errors,
+		indicator_status,
+		heartbeat,
+	)
+	.await;
+}
+
+#[embassy_executor::task]
+async fn i2c_target_task(target: I2cTarget) {
+	target.run().await;
+}
+
+#[embassy_executor::task]
+async fn hid_task(
+	signal: &'static Signal<
+		HidReport<{ KeyboardImpl::SIZE }, { MouseImpl::SIZE }, { ConsumerImpl::SIZE }>,
+	>,
+	transport: UsbHidTransport<
+		Driver<'static, USB>,
+		{ KeyboardImpl::SIZE },
+		{ MouseImpl::SIZE },
+		{ ConsumerImpl::SIZE },
+	>,
+	heartbeat: &'static Heartbeat,
+) {
+	cardboard_lib::embassy::hid_task(signal, transport, heartbeat).await;
+}
+
+#[embassy_executor::task]
+async fn supervisor_task(
+	clock: &'static EmbassyTickClock,
+	watchdog: Watchdog,
+	heartbeats: &'static [&'static Heartbeat],
+	interval: Duration,
+) {
+	cardboard_lib::tasks::supervisor_task(clock, watchdog, heartbeats, interval).await;
+}
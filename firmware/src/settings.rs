@@ -0,0 +1,393 @@
+use alloc::{string::String, vec::Vec};
+
+use cardboard_lib::{
+	input::{Chord, KeyId},
+	lighting::LightingEffect,
+	pointer::PointerConfig,
+	serialize::{Readable, Writeable},
+	settings::{validate_device_name, DeviceSettings, SettingValue},
+	stream::{ReadAsync, ReadAsyncExt, WriteAsync, WriteAsyncExt},
+};
+
+const SETTINGS_VERSION: u32 = 7;
+
+// keys for SetSettingCommand
+const SETTING_MOUSE_ENABLED: u8 = 0x00;
+const SETTING_DEVICE_NAME: u8 = 0x01;
+const SETTING_BOOTLOADER_CHORD: u8 = 0x02;
+const SETTING_BOOTLOADER_CHORD_HOLD_MS: u8 = 0x03;
+const SETTING_IDLE_TIMEOUT_SECS: u8 = 0x04;
+const SETTING_LIGHTING_EFFECT: u8 = 0x05;
+const SETTING_LIGHTING_EFFECT_SPEED: u8 = 0x06;
+const SETTING_BATTERY_BRIGHTNESS: u8 = 0x07;
+const SETTING_BATTERY_IDLE_TIMEOUT_SECS: u8 = 0x08;
+const SETTING_BATTERY_SCAN_INTERVAL_MS: u8 = 0x09;
+const SETTING_LOW_BATTERY_PERCENT: u8 = 0x0A;
+const SETTING_POINTER_SENSITIVITY: u8 = 0x0B;
+const SETTING_POINTER_ROTATION: u8 = 0x0C;
+
+/// Device settings kept in the settings partition and changed over the command protocol.
+pub struct Settings {
+	pub mouse_enabled: bool,
+	pub device_name: Option<String>,
+	/// Keys held together at runtime to enter the bootloader; empty disables the chord
+	pub bootloader_chord: Vec<KeyId>,
+	pub bootloader_chord_hold_ms: u32,
+	/// How long without key activity before the keypad sleeps; 0 never sleeps
+	pub idle_timeout_secs: u32,
+	/// Effect the LEDs start with; macros can switch it until the next reboot
+	pub lighting_effect: LightingEffect,
+	/// Effect speed in percent, where 100 is normal speed
+	pub lighting_effect_speed: u16,
+	/// LED brightness in percent while on battery
+	pub battery_brightness_percent: u8,
+	/// Idle timeout while on battery; 0 never sleeps
+	pub battery_idle_timeout_secs: u32,
+	/// Matrix scan interval while on battery
+	pub battery_scan_interval_ms: u32,
+	/// Charge at which the LEDs go off and the keypad sleeps sooner
+	pub low_battery_percent: u8,
+	/// Sensitivity and mounting angle of the pointing sensor, on boards with one
+	pub pointer: PointerConfig,
+}
+
+impl Default for Settings {
+	fn default() -> Self {
+		Self {
+			mouse_enabled: true,
+			device_name: None,
+			bootloader_chord: Vec::new(),
+			bootloader_chord_hold_ms: 3000,
+			idle_timeout_secs: 0,
+			lighting_effect: LightingEffect::Static,
+			lighting_effect_speed: 100,
+			battery_brightness_percent: 50,
+			battery_idle_timeout_secs: 0,
+			battery_scan_interval_ms: 2,
+			low_battery_percent: 15,
+			pointer: PointerConfig::default(),
+		}
+	}
+}
+
+impl DeviceSettings for Settings {
+	fn set_device_name(&mut self, name: String) {
+		self.device_name = Some(name);
+	}
+
+	fn set_setting(&mut self, key: u8, value: SettingValue) -> Result<(), &'static str> {
+		match (key, value) {
+			(SETTING_MOUSE_ENABLED, SettingValue::Bool(enabled)) => {
+				self.mouse_enabled = enabled;
+				Ok(())
+			}
+			(SETTING_DEVICE_NAME, SettingValue::String(name)) => {
+				validate_device_name(&name)?;
+				self.set_device_name(name);
+				Ok(())
+			}
+			(SETTING_BOOTLOADER_CHORD, SettingValue::Keys(keys)) => {
+				if keys.len() > Chord::MAX_KEYS {
+					return Err("Too many chord keys");
+				}
+				self.bootloader_chord = keys;
+				Ok(())
+			}
+			(SETTING_BOOTLOADER_CHORD_HOLD_MS, SettingValue::U32(hold_ms)) => {
+				self.bootloader_chord_hold_ms = hold_ms;
+				Ok(())
+			}
+			(SETTING_IDLE_TIMEOUT_SECS, SettingValue::U32(secs)) => {
+				self.idle_timeout_secs = secs;
+				Ok(())
+			}
+			(SETTING_LIGHTING_EFFECT, SettingValue::U32(effect)) => {
+				self.lighting_effect = u8::try_from(effect)
+					.ok()
+					.and_then(|effect| LightingEffect::try_from(effect).ok())
+					.ok_or("Unknown lighting effect")?;
+				Ok(())
+			}
+			(SETTING_LIGHTING_EFFECT_SPEED, SettingValue::U32(speed)) => {
+				self.lighting_effect_speed =
+					u16::try_from(speed).map_err(|_| "Lighting effect speed too high")?;
+				Ok(())
+			}
+			(SETTING_BATTERY_BRIGHTNESS, SettingValue::U32(percent)) => {
+				self.battery_brightness_percent = percent_setting(percent)?;
+				Ok(())
+			}
+			(SETTING_BATTERY_IDLE_TIMEOUT_SECS, SettingValue::U32(secs)) => {
+				self.battery_idle_timeout_secs = secs;
+				Ok(())
+			}
+			(SETTING_BATTERY_SCAN_INTERVAL_MS, SettingValue::U32(interval_ms)) => {
+				if !(1..=MAX_SCAN_INTERVAL_MS).contains(&interval_ms) {
+					return Err("Scan interval out of range");
+				}
+				self.battery_scan_interval_ms = interval_ms;
+				Ok(())
+			}
+			(SETTING_LOW_BATTERY_PERCENT, SettingValue::U32(percent)) => {
+				self.low_battery_percent = percent_setting(percent)?;
+				Ok(())
+			}
+			(SETTING_POINTER_SENSITIVITY, SettingValue::U32(percent)) => {
+				if !(1..=MAX_POINTER_SENSITIVITY_PERCENT).contains(&percent) {
+					return Err("Pointer sensitivity out of range");
+				}
+				self.pointer.sensitivity_percent = percent as u16;
+				Ok(())
+			}
+			(SETTING_POINTER_ROTATION, SettingValue::U32(degrees)) => {
+				if degrees >= 360 {
+					return Err("Pointer rotation out of range");
+				}
+				self.pointer.rotation_degrees = degrees as u16;
+				Ok(())
+			}
+			(
+				SETTING_MOUSE_ENABLED
+				| SETTING_DEVICE_NAME
+				| SETTING_BOOTLOADER_CHORD
+				| SETTING_BOOTLOADER_CHORD_HOLD_MS
+				| SETTING_IDLE_TIMEOUT_SECS
+				| SETTING_LIGHTING_EFFECT
+				| SETTING_LIGHTING_EFFECT_SPEED
+				| SETTING_BATTERY_BRIGHTNESS
+				| SETTING_BATTERY_IDLE_TIMEOUT_SECS
+				| SETTING_BATTERY_SCAN_INTERVAL_MS
+				| SETTING_LOW_BATTERY_PERCENT
+				| SETTING_POINTER_SENSITIVITY
+				| SETTING_POINTER_ROTATION,
+				_,
+			) => Err("Wrong setting type"),
+			_ => Err("Unknown setting key"),
+		}
+	}
+}
+
+/// Slower scans than this would make typing feel laggy.
+const MAX_SCAN_INTERVAL_MS: u32 = 20;
+
+/// Past this a single count from the sensor jumps the cursor too far to aim.
+const MAX_POINTER_SENSITIVITY_PERCENT: u32 = 1000;
+
+fn percent_setting(value: u32) -> Result<u8, &'static str> {
+	u8::try_from(value)
+		.ok()
+		.filter(|percent| *percent <= 100)
+		.ok_or("Percent out of range")
+}
+
+impl Readable for Settings {
+	async fn read_from<R: ReadAsync>(reader: &mut R) -> Result<Self, &'static str>
+	where
+		Self: Sized,
+	{
+		let version = reader
+			.read_u32()
+			.await
+			.ok_or("Could not read settings version")?;
+
+		if version == 0 || version > SETTINGS_VERSION {
+			return Err("Unsupported settings version");
+		}
+
+		let mouse_enabled = reader
+			.read_bool()
+			.await
+			.ok_or("Could not read mouse enabled")?;
+
+		// version 1 settings predate the device name
+		let device_name = if version >= 2 {
+			let has_name = reader
+				.read_bool()
+				.await
+				.ok_or("Could not read device name")?;
+			if has_name {
+				Some(
+					reader
+						.read_string_u8()
+						.await
+						.ok_or("Could not read device name")?,
+				)
+			} else {
+				None
+			}
+		} else {
+			None
+		};
+
+		// version 2 settings predate the bootloader chord
+		let (bootloader_chord, bootloader_chord_hold_ms) = if version >= 3 {
+			let keys = reader
+				.read_collection_u8()
+				.await
+				.ok_or("Could not read bootloader chord")?;
+			let hold_ms = reader
+				.read_u32()
+				.await
+				.ok_or("Could not read bootloader chord hold")?;
+			(keys, hold_ms)
+		} else {
+			let defaults = Self::default();
+			(defaults.bootloader_chord, defaults.bootloader_chord_hold_ms)
+		};
+
+		// version 3 settings predate the idle timeout
+		let idle_timeout_secs = if version >= 4 {
+			reader
+				.read_u32()
+				.await
+				.ok_or("Could not read idle timeout")?
+		} else {
+			Self::default().idle_timeout_secs
+		};
+
+		// version 4 settings predate lighting effects
+		let (lighting_effect, lighting_effect_speed) = if version >= 5 {
+			let effect = LightingEffect::read_from(reader).await?;
+			let speed = reader
+				.read_u16()
+				.await
+				.ok_or("Could not read lighting effect speed")?;
+			(effect, speed)
+		} else {
+			let defaults = Self::default();
+			(defaults.lighting_effect, defaults.lighting_effect_speed)
+		};
+
+		// version 5 settings predate the power policy
+		let defaults = Self::default();
+		let (
+			battery_brightness_percent,
+			battery_idle_timeout_secs,
+			battery_scan_interval_ms,
+			low_battery_percent,
+		) = if version >= 6 {
+			let brightness = reader
+				.read_u8()
+				.await
+				.ok_or("Could not read battery brightness")?;
+			let idle_timeout = reader
+				.read_u32()
+				.await
+				.ok_or("Could not read battery idle timeout")?;
+			let scan_interval = reader
+				.read_u32()
+				.await
+				.ok_or("Could not read battery scan interval")?;
+			let low_battery = reader
+				.read_u8()
+				.await
+				.ok_or("Could not read low battery level")?;
+			(brightness, idle_timeout, scan_interval, low_battery)
+		} else {
+			(
+				defaults.battery_brightness_percent,
+				defaults.battery_idle_timeout_secs,
+				defaults.battery_scan_interval_ms,
+				defaults.low_battery_percent,
+			)
+		};
+
+		// version 6 settings predate pointing sensors
+		let pointer = if version >= 7 {
+			let sensitivity_percent = reader
+				.read_u16()
+				.await
+				.ok_or("Could not read pointer sensitivity")?;
+			let rotation_degrees = reader
+				.read_u16()
+				.await
+				.ok_or("Could not read pointer rotation")?;
+			PointerConfig {
+				sensitivity_percent,
+				rotation_degrees,
+			}
+		} else {
+			defaults.pointer
+		};
+
+		Ok(Self {
+			mouse_enabled,
+			device_name,
+			bootloader_chord,
+			bootloader_chord_hold_ms,
+			idle_timeout_secs,
+			lighting_effect,
+			lighting_effect_speed,
+			battery_brightness_percent,
+			battery_idle_timeout_secs,
+			battery_scan_interval_ms,
+			low_battery_percent,
+			pointer,
+		})
+	}
+}
+
+impl Writeable for Settings {
+	async fn write_to<W: WriteAsync>(&self, writer: &mut W) -> Result<(), &'static str> {
+		writer
+			.write_u32(SETTINGS_VERSION)
+			.await
+			.map_err(|_| "Could not write settings version")?;
+		writer
+			.write_bool(self.mouse_enabled)
+			.await
+			.map_err(|_| "Could not write mouse enabled")?;
+		match &self.device_name {
+			Some(name) => {
+				writer.write_bool(true).await?;
+				writer.write_string_u8(name).await
+			}
+			None => writer.write_bool(false).await,
+		}
+		.map_err(|_| "Could not write device name")?;
+		writer
+			.write_collection_u8(&self.bootloader_chord)
+			.await
+			.map_err(|_| "Could not write bootloader chord")?;
+		writer
+			.write_u32(self.bootloader_chord_hold_ms)
+			.await
+			.map_err(|_| "Could not write bootloader chord hold")?;
+		writer
+			.write_u32(self.idle_timeout_secs)
+			.await
+			.map_err(|_| "Could not write idle timeout")?;
+		writer
+			.write_u8(self.lighting_effect as u8)
+			.await
+			.map_err(|_| "Could not write lighting effect")?;
+		writer
+			.write_u16(self.lighting_effect_speed)
+			.await
+			.map_err(|_| "Could not write lighting effect speed")?;
+		writer
+			.write_u8(self.battery_brightness_percent)
+			.await
+			.map_err(|_| "Could not write battery brightness")?;
+		writer
+			.write_u32(self.battery_idle_timeout_secs)
+			.await
+			.map_err(|_| "Could not write battery idle timeout")?;
+		writer
+			.write_u32(self.battery_scan_interval_ms)
+			.await
+			.map_err(|_| "Could not write battery scan interval")?;
+		writer
+			.write_u8(self.low_battery_percent)
+			.await
+			.map_err(|_| "Could not write low battery level")?;
+		writer
+			.write_u16(self.pointer.sensitivity_percent)
+			.await
+			.map_err(|_| "Could not write pointer sensitivity")?;
+		writer
+			.write_u16(self.pointer.rotation_degrees)
+			.await
+			.map_err(|_| "Could not write pointer rotation")
+	}
+}